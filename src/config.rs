@@ -0,0 +1,460 @@
+use crate::vfs::{self, VfsClientError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Errors from loading or saving a [`Config`].
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The file is not valid JSON or TOML, or doesn't fit the config type. line and
+    /// column are 1-based.
+    #[error("{path}:{line}:{column}: {message}")]
+    Parse {
+        path: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("unsupported config format for {path}: use .json, or .toml with the `toml` feature")]
+    UnsupportedFormat { path: String },
+    #[error("failed to serialize config for {path}: {error}")]
+    Serialize { path: String, error: String },
+    #[error(transparent)]
+    Vfs(#[from] VfsClientError),
+}
+
+/// The format of a config file, from its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    #[cfg(feature = "toml")]
+    Toml,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Option<Self> {
+        match path.rsplit_once('.')?.1 {
+            "json" => Some(Format::Json),
+            #[cfg(feature = "toml")]
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    /// Parse bytes as T, along with everything in them as a JSON value, to find
+    /// fields T doesn't know in.
+    fn parse<T: DeserializeOwned>(
+        self,
+        path: &str,
+        bytes: &[u8],
+    ) -> Result<(T, Value), ConfigError> {
+        match self {
+            Format::Json => {
+                let parse_error = |e: serde_json::Error| ConfigError::Parse {
+                    path: path.to_string(),
+                    line: e.line(),
+                    column: e.column(),
+                    message: e.to_string(),
+                };
+                let value = serde_json::from_slice(bytes).map_err(parse_error)?;
+                let raw = serde_json::from_slice(bytes).map_err(parse_error)?;
+                Ok((value, raw))
+            }
+            #[cfg(feature = "toml")]
+            Format::Toml => {
+                let text = std::str::from_utf8(bytes)
+                    .map_err(|e| parse_error_at(path, bytes, e.valid_up_to(), "invalid UTF-8"))?;
+                let parse_error = |e: toml::de::Error| {
+                    let offset = e.span().map_or(0, |span| span.start);
+                    parse_error_at(path, bytes, offset, e.message())
+                };
+                let value = toml::from_str(text).map_err(parse_error)?;
+                let raw: toml::Table = toml::from_str(text).map_err(parse_error)?;
+                // tables and keys map one to one onto JSON objects
+                Ok((value, serde_json::to_value(raw).unwrap_or_default()))
+            }
+        }
+    }
+
+    fn serialize<T: Serialize>(self, path: &str, value: &T) -> Result<Vec<u8>, ConfigError> {
+        let serialize_error = |error: String| ConfigError::Serialize {
+            path: path.to_string(),
+            error,
+        };
+        match self {
+            Format::Json => {
+                serde_json::to_vec_pretty(value).map_err(|e| serialize_error(e.to_string()))
+            }
+            #[cfg(feature = "toml")]
+            Format::Toml => toml::to_string_pretty(value)
+                .map(String::into_bytes)
+                .map_err(|e| serialize_error(e.to_string())),
+        }
+    }
+}
+
+/// A [`ConfigError::Parse`] at byte offset in bytes.
+#[cfg(feature = "toml")]
+fn parse_error_at(path: &str, bytes: &[u8], offset: usize, message: &str) -> ConfigError {
+    let before = &bytes[..offset.min(bytes.len())];
+    let line_start = before
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    ConfigError::Parse {
+        path: path.to_string(),
+        line: before.iter().filter(|b| **b == b'\n').count() + 1,
+        column: String::from_utf8_lossy(&before[line_start..])
+            .chars()
+            .count()
+            + 1,
+        message: message.to_string(),
+    }
+}
+
+/// The dotted paths of the object keys in raw that known doesn't have.
+fn unknown_fields(raw: &Value, known: &Value, prefix: &str, found: &mut Vec<String>) {
+    let (Value::Object(raw), Value::Object(known)) = (raw, known) else {
+        return;
+    };
+    for (key, raw_value) in raw {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        match known.get(key) {
+            Some(known_value) => unknown_fields(raw_value, known_value, &path, found),
+            None => found.push(path),
+        }
+    }
+}
+
+/// What a config needs from the runtime, so that it can be exercised without one.
+trait Io {
+    /// `None` if there is no file at path.
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>, VfsClientError>;
+    fn write(&mut self, path: &str, bytes: &[u8]) -> Result<(), VfsClientError>;
+    /// `None` if there is no file at path, or the runtime doesn't report it.
+    fn modified(&mut self, path: &str) -> Result<Option<u64>, VfsClientError>;
+    fn warn(&mut self, message: &str);
+}
+
+struct Kernel;
+
+/// `None` for errors that mean there is nothing at path.
+fn unless_not_found<T>(result: Result<T, VfsClientError>) -> Result<Option<T>, VfsClientError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(VfsClientError::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl Io for Kernel {
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>, VfsClientError> {
+        match unless_not_found(vfs::open_file(path, false, None))? {
+            Some(file) => Ok(Some(file.read()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&mut self, path: &str, bytes: &[u8]) -> Result<(), VfsClientError> {
+        vfs::write_atomic(path, bytes, None)
+    }
+
+    fn modified(&mut self, path: &str) -> Result<Option<u64>, VfsClientError> {
+        Ok(unless_not_found(vfs::metadata(path, None))?.and_then(|m| m.modified()))
+    }
+
+    fn warn(&mut self, message: &str) {
+        crate::warn!("{message}");
+    }
+}
+
+/// A config file in the vfs, parsed as T: JSON for `.json` paths and, with the
+/// `toml` feature, TOML for `.toml` ones.
+///
+/// Fields in the file that T doesn't have are warned about and ignored, unless T
+/// is `#[serde(deny_unknown_fields)]`, in which case they fail the parse.
+///
+/// ```no_run
+/// use hyperware_process_lib::config::Config;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Default, Serialize, Deserialize)]
+/// #[serde(default)]
+/// struct Settings {
+///     greeting: String,
+///     max_peers: u32,
+/// }
+///
+/// let mut config = Config::<Settings>::load("/my-app:publisher.os/config.json").unwrap();
+/// config.update(|settings| settings.max_peers = 8).unwrap();
+/// // later, e.g. on a timer
+/// if config.reload_if_changed().unwrap() {
+///     println!("now greeting with {}", config.get().greeting);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Config<T> {
+    path: String,
+    format: Format,
+    value: T,
+    /// The modified time of the file as last read or written.
+    modified: Option<u64>,
+    /// The digest of the file as last read or written, for when the runtime
+    /// doesn't report modified times.
+    digest: [u8; 32],
+}
+
+impl<T: DeserializeOwned + Serialize + Default> Config<T> {
+    /// Parse the file at path or, if there is none, write `T::default()` to it.
+    /// path may be a file of a [`crate::vfs::DrivePath`], such as
+    /// `data_dir()?.file("config.toml")?`.
+    pub fn load(path: impl AsRef<str>) -> Result<Self, ConfigError> {
+        Self::load_with(&mut Kernel, path.as_ref())
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Write the config to its file.
+    pub fn save(&mut self) -> Result<(), ConfigError> {
+        self.save_with(&mut Kernel)
+    }
+
+    /// Change the config with f, then [`Config::save()`] it.
+    pub fn update<F: FnOnce(&mut T)>(&mut self, f: F) -> Result<(), ConfigError> {
+        f(&mut self.value);
+        self.save()
+    }
+
+    /// Parse the file again if it changed since it was last read or written.
+    /// Returns whether it did. If the file is gone, the config is left as is.
+    pub fn reload_if_changed(&mut self) -> Result<bool, ConfigError> {
+        self.reload_if_changed_with(&mut Kernel)
+    }
+
+    fn load_with<I: Io>(io: &mut I, path: &str) -> Result<Self, ConfigError> {
+        let format = Format::from_path(path).ok_or_else(|| ConfigError::UnsupportedFormat {
+            path: path.to_string(),
+        })?;
+        let (value, bytes) = match io.read(path)? {
+            Some(bytes) => (Self::parse(io, format, path, &bytes)?, bytes),
+            None => {
+                let value = T::default();
+                let bytes = format.serialize(path, &value)?;
+                io.write(path, &bytes)?;
+                (value, bytes)
+            }
+        };
+        Ok(Config {
+            path: path.to_string(),
+            format,
+            value,
+            modified: io.modified(path)?,
+            digest: Sha256::digest(&bytes).into(),
+        })
+    }
+
+    fn parse<I: Io>(
+        io: &mut I,
+        format: Format,
+        path: &str,
+        bytes: &[u8],
+    ) -> Result<T, ConfigError> {
+        let (value, raw) = format.parse::<T>(path, bytes)?;
+        let known = serde_json::to_value(&value).unwrap_or_default();
+        let mut unknown = vec![];
+        unknown_fields(&raw, &known, "", &mut unknown);
+        if !unknown.is_empty() {
+            io.warn(&format!(
+                "{path}: ignoring unknown fields {}",
+                unknown.join(", ")
+            ));
+        }
+        Ok(value)
+    }
+
+    fn save_with<I: Io>(&mut self, io: &mut I) -> Result<(), ConfigError> {
+        let bytes = self.format.serialize(&self.path, &self.value)?;
+        io.write(&self.path, &bytes)?;
+        self.modified = io.modified(&self.path)?;
+        self.digest = Sha256::digest(&bytes).into();
+        Ok(())
+    }
+
+    fn reload_if_changed_with<I: Io>(&mut self, io: &mut I) -> Result<bool, ConfigError> {
+        let modified = io.modified(&self.path)?;
+        if modified.is_some() && modified == self.modified {
+            return Ok(false);
+        }
+        let Some(bytes) = io.read(&self.path)? else {
+            return Ok(false);
+        };
+        let digest: [u8; 32] = Sha256::digest(&bytes).into();
+        if digest == self.digest {
+            self.modified = modified;
+            return Ok(false);
+        }
+        // if the new file doesn't parse, the old config and modified time are kept,
+        // so the next call tries again
+        self.value = Self::parse(io, self.format, &self.path, &bytes)?;
+        self.digest = digest;
+        self.modified = modified;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Settings {
+        greeting: String,
+        max_peers: u32,
+        limits: Limits,
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Limits {
+        per_minute: u32,
+    }
+
+    #[derive(Default)]
+    struct MockIo {
+        file: Option<Vec<u8>>,
+        modified: Option<u64>,
+        reads: usize,
+        warnings: Vec<String>,
+    }
+
+    impl Io for MockIo {
+        fn read(&mut self, _: &str) -> Result<Option<Vec<u8>>, VfsClientError> {
+            self.reads += 1;
+            Ok(self.file.clone())
+        }
+
+        fn write(&mut self, _: &str, bytes: &[u8]) -> Result<(), VfsClientError> {
+            self.file = Some(bytes.to_vec());
+            self.modified = self.modified.map(|m| m + 1);
+            Ok(())
+        }
+
+        fn modified(&mut self, _: &str) -> Result<Option<u64>, VfsClientError> {
+            Ok(self.file.as_ref().and(self.modified))
+        }
+
+        fn warn(&mut self, message: &str) {
+            self.warnings.push(message.to_string());
+        }
+    }
+
+    const PATH: &str = "/app:pub.os/config.json";
+
+    #[test]
+    fn test_creates_defaults() {
+        let mut io = MockIo {
+            modified: Some(1),
+            ..MockIo::default()
+        };
+        let mut config = Config::<Settings>::load_with(&mut io, PATH).unwrap();
+        assert_eq!(config.get(), &Settings::default());
+        let written: Settings = serde_json::from_slice(io.file.as_ref().unwrap()).unwrap();
+        assert_eq!(written, Settings::default());
+
+        config.value.max_peers = 8;
+        config.save_with(&mut io).unwrap();
+        let reloaded = Config::<Settings>::load_with(&mut io, PATH).unwrap();
+        assert_eq!(reloaded.get().max_peers, 8);
+        assert!(io.warnings.is_empty());
+
+        assert!(matches!(
+            Config::<Settings>::load_with(&mut io, "/app:pub.os/config.yaml"),
+            Err(ConfigError::UnsupportedFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let mut io = MockIo {
+            file: Some(b"{\n  \"greeting\": \"hi\",\n  \"max_peers\": \"eight\"\n}".to_vec()),
+            ..MockIo::default()
+        };
+        match Config::<Settings>::load_with(&mut io, PATH) {
+            Err(ConfigError::Parse { line, column, .. }) => assert_eq!((line, column), (3, 22)),
+            other => panic!("unexpected {other:?}"),
+        }
+        io.file = Some(b"{\n  \"greeting\": \"hi\",,\n}".to_vec());
+        match Config::<Settings>::load_with(&mut io, PATH) {
+            Err(e @ ConfigError::Parse { .. }) => {
+                assert!(e.to_string().starts_with(&format!("{PATH}:2:")));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_fields_warn() {
+        let mut io = MockIo {
+            file: Some(
+                br#"{"greeting": "hi", "colour": "red", "limits": {"per_hour": 1}}"#.to_vec(),
+            ),
+            ..MockIo::default()
+        };
+        let config = Config::<Settings>::load_with(&mut io, PATH).unwrap();
+        assert_eq!(config.get().greeting, "hi");
+        assert_eq!(io.warnings.len(), 1);
+        assert!(io.warnings[0].ends_with("colour, limits.per_hour"));
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let mut io = MockIo {
+            modified: Some(1),
+            ..MockIo::default()
+        };
+        let mut config = Config::<Settings>::load_with(&mut io, PATH).unwrap();
+        // our own writes don't count as changes
+        config.save_with(&mut io).unwrap();
+        let reads = io.reads;
+        assert!(!config.reload_if_changed_with(&mut io).unwrap());
+        assert_eq!(io.reads, reads);
+
+        io.file = Some(br#"{"greeting": "hello"}"#.to_vec());
+        io.modified = Some(10);
+        assert!(config.reload_if_changed_with(&mut io).unwrap());
+        assert_eq!(config.get().greeting, "hello");
+        assert!(!config.reload_if_changed_with(&mut io).unwrap());
+
+        // touched but not changed
+        io.modified = Some(11);
+        assert!(!config.reload_if_changed_with(&mut io).unwrap());
+
+        // a broken edit keeps the old config, and is reported until fixed
+        io.file = Some(b"{".to_vec());
+        io.modified = Some(12);
+        assert!(config.reload_if_changed_with(&mut io).is_err());
+        assert!(config.reload_if_changed_with(&mut io).is_err());
+        assert_eq!(config.get().greeting, "hello");
+
+        // without modified times, contents are compared
+        io.modified = None;
+        io.file = Some(br#"{"greeting": "hello"}"#.to_vec());
+        assert!(!config.reload_if_changed_with(&mut io).unwrap());
+        io.file = Some(br#"{"greeting": "hey"}"#.to_vec());
+        assert!(config.reload_if_changed_with(&mut io).unwrap());
+        assert_eq!(config.get().greeting, "hey");
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{trace::USER_METADATA_KEY, types::message::metadata_object, Address, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// The metadata key [`crate::Request::signed()`] puts the [`Signature`] of the
+/// body under.
+pub const SIGNATURE_KEY: &str = "__signature";
+
+/// How far a signature's timestamp may be from our clock, either way, for
+/// [`verify()`] to accept it. Also how long its nonce is remembered to turn
+/// away replays.
+pub const MAX_SKEW_MS: u64 = 5 * 60 * 1000;
+
+/// Prefixed to what is signed, so that a signature over a body can't be passed
+/// off as one made with [`crate::net::sign()`] for some other purpose.
+const DOMAIN: &[u8] = b"hyperware-signed-body-v1";
+
+/// An attestation by a process that it produced a body, made with its node's
+/// networking key by [`sign_body()`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Signature {
+    pub timestamp_ms: u64,
+    pub nonce: u64,
+    pub signature: Vec<u8>,
+}
+
+/// The process a [`Message`] was shown by [`verify()`] to have been signed by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedSource {
+    pub address: Address,
+    pub signed_at_ms: u64,
+}
+
+/// Why [`verify()`] rejected a message.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum SignatureError {
+    #[error("message from {0} is not signed")]
+    Missing(Address),
+    #[error("signature of message from {sender} is malformed: {error}")]
+    Malformed { sender: Address, error: String },
+    #[error("signature of message from {sender} was made {age_ms}ms ago, longer than allowed")]
+    Stale { sender: Address, age_ms: u64 },
+    #[error("signature of message from {sender} is dated {ahead_ms}ms in the future")]
+    FromFuture { sender: Address, ahead_ms: u64 },
+    #[error("signature of message from {0} was already used")]
+    Replayed(Address),
+    #[error("signature of message from {0} does not match its body")]
+    Invalid(Address),
+}
+
+thread_local! {
+    /// The nonces of signatures verified in the last [`MAX_SKEW_MS`], with their
+    /// timestamps.
+    static SEEN: RefCell<HashMap<(Address, u64), u64>> = RefCell::new(HashMap::new());
+}
+
+/// What signing and verifying need from the runtime, so that they can be
+/// exercised without one.
+trait Signer {
+    /// payload signed with our node's key, bound to our address.
+    fn sign(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>>;
+    /// Whether signature is from's signature of payload, per the PKI.
+    fn verify(&self, from: &Address, payload: &[u8], signature: &[u8]) -> anyhow::Result<bool>;
+}
+
+struct Net;
+
+impl Signer for Net {
+    fn sign(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(crate::net::sign(payload)?)
+    }
+
+    fn verify(&self, from: &Address, payload: &[u8], signature: &[u8]) -> anyhow::Result<bool> {
+        Ok(crate::net::verify(from.clone(), payload, signature)?)
+    }
+}
+
+/// Sign body as coming from this process, with our node's networking key and
+/// the current time and a random nonce, so that the signature can't be
+/// replayed. `net:distro:sys` binds the signature to our [`Address`], so that
+/// no other process can sign as us.
+pub fn sign_body(body: &[u8]) -> anyhow::Result<Signature> {
+    sign_with(&Net, body, crate::timer::now_ms()?, rand::random())
+}
+
+/// Check that message was signed by its source with [`crate::Request::signed()`]:
+/// that the signature matches its body and source by the PKI, was made within
+/// [`MAX_SKEW_MS`] of now, and has not been seen before. Fails with a
+/// [`SignatureError`] if not, or with whatever error reaching `net:distro:sys`
+/// ran into.
+pub fn verify(message: &Message) -> anyhow::Result<VerifiedSource> {
+    verify_with(&Net, message, crate::timer::now_ms()?)
+}
+
+/// metadata with signature set as its [`SIGNATURE_KEY`], kept apart from
+/// non-object metadata as [`crate::guard::with_via()`] does.
+pub fn with_signature(metadata: Option<&str>, signature: &Signature) -> String {
+    let mut object = match metadata_object(metadata) {
+        Ok(object) if !object.contains_key(USER_METADATA_KEY) => object,
+        _ => {
+            let mut object = serde_json::Map::new();
+            object.insert(
+                USER_METADATA_KEY.to_string(),
+                Value::String(metadata.unwrap_or_default().to_string()),
+            );
+            object
+        }
+    };
+    object.insert(
+        SIGNATURE_KEY.to_string(),
+        serde_json::to_value(signature).unwrap(),
+    );
+    Value::Object(object).to_string()
+}
+
+fn signed_payload(body: &[u8], timestamp_ms: u64, nonce: u64) -> Vec<u8> {
+    let mut payload = DOMAIN.to_vec();
+    payload.extend_from_slice(&timestamp_ms.to_be_bytes());
+    payload.extend_from_slice(&nonce.to_be_bytes());
+    payload.extend_from_slice(&Sha256::digest(body));
+    payload
+}
+
+fn sign_with<S: Signer>(
+    signer: &S,
+    body: &[u8],
+    timestamp_ms: u64,
+    nonce: u64,
+) -> anyhow::Result<Signature> {
+    Ok(Signature {
+        timestamp_ms,
+        nonce,
+        signature: signer.sign(&signed_payload(body, timestamp_ms, nonce))?,
+    })
+}
+
+fn verify_with<S: Signer>(
+    signer: &S,
+    message: &Message,
+    now_ms: u64,
+) -> anyhow::Result<VerifiedSource> {
+    let source = message.source();
+    let malformed = |error: String| SignatureError::Malformed {
+        sender: source.clone(),
+        error,
+    };
+    let signature: Signature = match metadata_object(message.metadata()) {
+        Ok(mut object) => match object.remove(SIGNATURE_KEY) {
+            Some(value) => serde_json::from_value(value).map_err(|e| malformed(e.to_string()))?,
+            None => return Err(SignatureError::Missing(source.clone()).into()),
+        },
+        Err(_) => return Err(SignatureError::Missing(source.clone()).into()),
+    };
+    if signature.timestamp_ms > now_ms + MAX_SKEW_MS {
+        return Err(SignatureError::FromFuture {
+            sender: source.clone(),
+            ahead_ms: signature.timestamp_ms - now_ms,
+        }
+        .into());
+    }
+    if now_ms > signature.timestamp_ms + MAX_SKEW_MS {
+        return Err(SignatureError::Stale {
+            sender: source.clone(),
+            age_ms: now_ms - signature.timestamp_ms,
+        }
+        .into());
+    }
+    let nonce = (source.clone(), signature.nonce);
+    if SEEN.with_borrow(|seen| seen.contains_key(&nonce)) {
+        return Err(SignatureError::Replayed(source.clone()).into());
+    }
+    let payload = signed_payload(message.body(), signature.timestamp_ms, signature.nonce);
+    if !signer.verify(source, &payload, &signature.signature)? {
+        return Err(SignatureError::Invalid(source.clone()).into());
+    }
+    SEEN.with_borrow_mut(|seen| {
+        // a nonce need only be remembered while its timestamp would pass
+        seen.retain(|_, timestamp_ms| *timestamp_ms + MAX_SKEW_MS >= now_ms);
+        seen.insert(nonce, signature.timestamp_ms);
+    });
+    Ok(VerifiedSource {
+        address: source.clone(),
+        signed_at_ms: signature.timestamp_ms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MessageBuilder;
+
+    /// Signs with a fixed key per node, standing in for the networking keys
+    /// and the PKI.
+    struct Fixture {
+        our: Address,
+    }
+
+    fn key(node: &str) -> Option<[u8; 32]> {
+        match node {
+            "alice.os" => Some([1; 32]),
+            "bob.os" => Some([2; 32]),
+            _ => None,
+        }
+    }
+
+    fn fixture_sign(key: [u8; 32], from: &Address, payload: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        hasher.update(from.to_string());
+        hasher.update(payload);
+        hasher.finalize().to_vec()
+    }
+
+    impl Signer for Fixture {
+        fn sign(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(fixture_sign(
+                key(self.our.node()).unwrap(),
+                &self.our,
+                payload,
+            ))
+        }
+
+        fn verify(&self, from: &Address, payload: &[u8], signature: &[u8]) -> anyhow::Result<bool> {
+            Ok(key(from.node()).is_some_and(|key| fixture_sign(key, from, payload) == signature))
+        }
+    }
+
+    const NOW: u64 = 1_700_000_000_000;
+    const ALICE: &str = "alice.os@signer:app:pub.os";
+
+    fn signed(from: &str, body: &[u8], signature: &Signature) -> Message {
+        MessageBuilder::request()
+            .from(from)
+            .body(body.to_vec())
+            .metadata(&with_signature(Some("user data"), signature))
+            .build()
+    }
+
+    fn alice_signs(body: &[u8], timestamp_ms: u64, nonce: u64) -> Signature {
+        let alice = Fixture {
+            our: ALICE.parse().unwrap(),
+        };
+        sign_with(&alice, body, timestamp_ms, nonce).unwrap()
+    }
+
+    fn rejection(message: &Message, now_ms: u64) -> SignatureError {
+        let verifier = Fixture {
+            our: "bob.os@app:app:pub.os".parse().unwrap(),
+        };
+        verify_with(&verifier, message, now_ms)
+            .unwrap_err()
+            .downcast()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify_accepts_once() {
+        let verifier = Fixture {
+            our: "bob.os@app:app:pub.os".parse().unwrap(),
+        };
+        let signature = alice_signs(b"payload", NOW, 1);
+        let message = signed(ALICE, b"payload", &signature);
+        let verified = verify_with(&verifier, &message, NOW + 1000).unwrap();
+        assert_eq!(verified.address, ALICE.parse().unwrap());
+        assert_eq!(verified.signed_at_ms, NOW);
+        // the sender's own metadata is kept alongside
+        assert_eq!(
+            metadata_object(message.metadata()).unwrap()[USER_METADATA_KEY],
+            "user data"
+        );
+
+        assert_eq!(
+            rejection(&message, NOW + 2000),
+            SignatureError::Replayed(ALICE.parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_tampered_body_rejected() {
+        let signature = alice_signs(b"pay alice 1", NOW, 2);
+        let tampered = signed(ALICE, b"pay alice 1000", &signature);
+        assert_eq!(
+            rejection(&tampered, NOW),
+            SignatureError::Invalid(ALICE.parse().unwrap())
+        );
+
+        // nor can another process pass alice's signature off as its own
+        let impostor = "alice.os@impostor:app:pub.os";
+        let stolen = signed(impostor, b"pay alice 1", &signature);
+        assert_eq!(
+            rejection(&stolen, NOW),
+            SignatureError::Invalid(impostor.parse().unwrap())
+        );
+
+        // or change the timestamp to dodge replay protection
+        let mut redated = signature.clone();
+        redated.timestamp_ms += 1;
+        let redated = signed(ALICE, b"pay alice 1", &redated);
+        assert_eq!(
+            rejection(&redated, NOW),
+            SignatureError::Invalid(ALICE.parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_stale_timestamp_rejected() {
+        let message = signed(ALICE, b"payload", &alice_signs(b"payload", NOW, 3));
+        assert_eq!(
+            rejection(&message, NOW + MAX_SKEW_MS + 1),
+            SignatureError::Stale {
+                sender: ALICE.parse().unwrap(),
+                age_ms: MAX_SKEW_MS + 1
+            }
+        );
+        assert_eq!(
+            rejection(&message, NOW - MAX_SKEW_MS - 1),
+            SignatureError::FromFuture {
+                sender: ALICE.parse().unwrap(),
+                ahead_ms: MAX_SKEW_MS + 1
+            }
+        );
+
+        let unsigned = MessageBuilder::request()
+            .from(ALICE)
+            .body("payload")
+            .build();
+        assert_eq!(
+            rejection(&unsigned, NOW),
+            SignatureError::Missing(ALICE.parse().unwrap())
+        );
+        let garbled = MessageBuilder::request()
+            .from(ALICE)
+            .body("payload")
+            .metadata(r#"{"__signature": 5}"#)
+            .build();
+        assert!(matches!(
+            rejection(&garbled, NOW),
+            SignatureError::Malformed { .. }
+        ));
+    }
+}
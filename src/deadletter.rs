@@ -0,0 +1,575 @@
+use crate::timer::wall_clock_ms;
+use crate::{kernel_types, trace::USER_METADATA_KEY, types::message::metadata_object};
+use crate::{Address, LazyLoadBlob, Message, Request, SendError, SendErrorKind};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// The metadata key a retried dead letter carries its retry count under, so the
+/// count survives the retry failing in turn. [`handle()`] strips it off again.
+pub const RETRY_KEY: &str = "__dead_letter";
+
+/// When, and how often, [`DeadLetterQueue::retry_all()`] retries a dead letter:
+/// the nth retry is due `initial_delay_ms * multiplier^(n - 1)` after the latest
+/// failure, at most `max_delay_ms`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    /// Retries before giving up, not counting the original send.
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub multiplier: u32,
+    pub max_delay_ms: u64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            max_attempts: 5,
+            initial_delay_ms: 1_000,
+            multiplier: 2,
+            max_delay_ms: 5 * 60 * 1000,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// How long after failing a letter already retried attempts times is retried.
+    pub fn delay_ms(&self, attempts: u32) -> u64 {
+        let factor = (self.multiplier as u64).saturating_pow(attempts);
+        self.initial_delay_ms
+            .saturating_mul(factor)
+            .min(self.max_delay_ms)
+    }
+
+    /// When letter is due to be retried, in ms since the Unix epoch, or `None` if
+    /// it is out of attempts.
+    pub fn next_retry_at_ms(&self, letter: &DeadLetter) -> Option<u64> {
+        (letter.attempts < self.max_attempts).then(|| {
+            letter
+                .failed_at_ms
+                .saturating_add(self.delay_ms(letter.attempts))
+        })
+    }
+}
+
+/// A request that could not be delivered, as reconstructed from its [`SendError`]
+/// by [`handle()`]: everything needed to send it again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub target: Address,
+    pub kind: SendErrorKind,
+    pub body: Vec<u8>,
+    /// The metadata the request was sent with, without the retry count.
+    pub metadata: Option<String>,
+    pub blob: Option<kernel_types::LazyLoadBlob>,
+    pub context: Option<Vec<u8>>,
+    /// The timeout the request awaited a response for, in seconds, if it expected one.
+    pub expects_response: Option<u64>,
+    /// How often the letter was retried before this failure.
+    pub attempts: u32,
+    /// When the request first failed, in ms since the Unix epoch.
+    pub first_failed_at_ms: u64,
+    /// When it last failed.
+    pub failed_at_ms: u64,
+}
+
+impl DeadLetter {
+    pub fn blob(&self) -> Option<LazyLoadBlob> {
+        self.blob.clone().map(LazyLoadBlob::from)
+    }
+
+    /// The context the request was sent with, deserialized as T from JSON.
+    pub fn context_as<T: DeserializeOwned>(&self) -> anyhow::Result<Option<T>> {
+        match &self.context {
+            Some(context) => Ok(Some(serde_json::from_slice(context)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The request as originally sent, to send again.
+    pub fn request(&self) -> Request {
+        let mut request = Request::to(&self.target).body(self.body.clone());
+        if let Some(metadata) = &self.metadata {
+            request = request.metadata(metadata);
+        }
+        if let Some(context) = &self.context {
+            request = request.context(context.clone());
+        }
+        if let Some(blob) = self.blob() {
+            request = request.blob(blob);
+        }
+        if let Some(timeout) = self.expects_response {
+            request = request.expects_response(timeout);
+        }
+        request
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RetryCount {
+    attempts: u32,
+    first_failed_at_ms: u64,
+}
+
+/// Reconstruct the request error failed to deliver. Returns `None` if error is
+/// not for a Request, which the runtime doesn't issue.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, deadletter::{self, DeadLetterQueue}};
+///
+/// let mut queue = DeadLetterQueue::open("/my-app:publisher.os/data/dead.json").unwrap();
+/// loop {
+///     match await_message() {
+///         Ok(message) => { /* handle it */ }
+///         Err(error) => {
+///             if let Some(letter) = deadletter::handle(&error) {
+///                 queue.push(letter).unwrap();
+///             }
+///         }
+///     }
+/// }
+/// ```
+pub fn handle(error: &SendError) -> Option<DeadLetter> {
+    handle_at(error, wall_clock_ms())
+}
+
+fn handle_at(error: &SendError, now_ms: u64) -> Option<DeadLetter> {
+    let Message::Request {
+        expects_response,
+        body,
+        metadata,
+        ..
+    } = error.message()
+    else {
+        return None;
+    };
+    let (count, metadata) = extract_retry_count(metadata.as_deref());
+    let (attempts, first_failed_at_ms) = count.map_or((0, now_ms), |count| {
+        (count.attempts, count.first_failed_at_ms)
+    });
+    Some(DeadLetter {
+        target: error.target().clone(),
+        kind: error.kind().clone(),
+        body: body.clone(),
+        metadata,
+        blob: error.blob().cloned().map(kernel_types::LazyLoadBlob::from),
+        context: error.context.clone(),
+        expects_response: *expects_response,
+        attempts,
+        first_failed_at_ms,
+        failed_at_ms: now_ms,
+    })
+}
+
+/// metadata with count under [`RETRY_KEY`], kept verbatim under
+/// [`USER_METADATA_KEY`] if it is not a JSON object, as
+/// [`crate::trace::merge_trace()`] does.
+fn with_retry_count(metadata: Option<&str>, count: &RetryCount) -> String {
+    let mut object = match metadata.map(|m| metadata_object(Some(m))) {
+        // an empty object is kept verbatim, so that it doesn't come back as None
+        Some(Ok(object))
+            if !object.is_empty()
+                && !object.contains_key(RETRY_KEY)
+                && !object.contains_key(USER_METADATA_KEY) =>
+        {
+            object
+        }
+        None => Map::new(),
+        _ => {
+            let mut object = Map::new();
+            object.insert(
+                USER_METADATA_KEY.to_string(),
+                Value::String(metadata.unwrap_or_default().to_string()),
+            );
+            object
+        }
+    };
+    object.insert(RETRY_KEY.to_string(), serde_json::to_value(count).unwrap());
+    Value::Object(object).to_string()
+}
+
+/// Undo [`with_retry_count()`]: metadata without a count is returned unchanged.
+fn extract_retry_count(metadata: Option<&str>) -> (Option<RetryCount>, Option<String>) {
+    let Some(text) = metadata else {
+        return (None, None);
+    };
+    let Ok(mut object) = metadata_object(Some(text)) else {
+        return (None, Some(text.to_string()));
+    };
+    let Some(count) = object
+        .get(RETRY_KEY)
+        .and_then(|count| RetryCount::deserialize(count).ok())
+    else {
+        return (None, Some(text.to_string()));
+    };
+    object.remove(RETRY_KEY);
+    let user = match object.remove(USER_METADATA_KEY) {
+        Some(Value::String(user)) if object.is_empty() => Some(user),
+        _ if object.is_empty() => None,
+        wrapped => {
+            if let Some(wrapped) = wrapped {
+                object.insert(USER_METADATA_KEY.to_string(), wrapped);
+            }
+            Some(Value::Object(object).to_string())
+        }
+    };
+    (Some(count), user)
+}
+
+/// What [`DeadLetterQueue::retry_all()`] did with the letters in the queue, by id.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetryReport {
+    /// Sent again and dropped from the queue: if they fail again, their
+    /// [`SendError`]s come back through [`handle()`] with the retry counted.
+    pub resent: Vec<u64>,
+    /// Not due yet, left in the queue.
+    pub waiting: Vec<u64>,
+    /// Out of attempts and dropped from the queue.
+    pub gave_up: Vec<u64>,
+}
+
+/// What the queue needs from the runtime, so that it can be exercised without one.
+trait Io {
+    fn load(&mut self, path: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn save(&mut self, path: &str, bytes: &[u8]) -> anyhow::Result<()>;
+    fn send(&mut self, request: Request) -> anyhow::Result<()>;
+    fn now_ms(&mut self) -> u64;
+}
+
+struct Kernel;
+
+impl Io for Kernel {
+    fn load(&mut self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        use crate::vfs::VfsClientError;
+        match crate::vfs::open_file(path, false, None) {
+            Ok(file) => Ok(Some(file.read()?)),
+            // anything but a missing file must not start an empty queue over it
+            Err(VfsClientError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&mut self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        Ok(crate::vfs::write_atomic(path, bytes, None)?)
+    }
+
+    fn send(&mut self, request: Request) -> anyhow::Result<()> {
+        request.send()?;
+        Ok(())
+    }
+
+    fn now_ms(&mut self) -> u64 {
+        wall_clock_ms()
+    }
+}
+
+/// Requests that failed to deliver, kept in a vfs file until they are retried or
+/// purged. Every change is written to the file before returning.
+#[derive(Debug)]
+pub struct DeadLetterQueue {
+    path: String,
+    state: QueueState,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueState {
+    next_id: u64,
+    letters: BTreeMap<u64, DeadLetter>,
+}
+
+impl DeadLetterQueue {
+    /// Load the queue kept in the vfs file at path, or start an empty one if
+    /// there is no such file.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        Self::open_with(&mut Kernel, path)
+    }
+
+    /// Save letter, returning its id in the queue.
+    pub fn push(&mut self, letter: DeadLetter) -> anyhow::Result<u64> {
+        self.push_with(&mut Kernel, letter)
+    }
+
+    /// Send every letter that is due again under policy, and drop those out of
+    /// attempts.
+    pub fn retry_all(&mut self, policy: &BackoffPolicy) -> anyhow::Result<RetryReport> {
+        self.retry_all_with(&mut Kernel, policy)
+    }
+
+    /// Drop the letters that first failed over ms ago. Returns how many.
+    pub fn purge_older_than(&mut self, ms: u64) -> anyhow::Result<usize> {
+        self.purge_older_than_with(&mut Kernel, ms)
+    }
+
+    /// The letters in the queue, by id.
+    pub fn letters(&self) -> impl Iterator<Item = (u64, &DeadLetter)> {
+        self.state.letters.iter().map(|(id, letter)| (*id, letter))
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.letters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.letters.is_empty()
+    }
+
+    fn open_with<I: Io>(io: &mut I, path: &str) -> anyhow::Result<Self> {
+        let state = match io.load(path)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => QueueState::default(),
+        };
+        Ok(DeadLetterQueue {
+            path: path.to_string(),
+            state,
+        })
+    }
+
+    fn save<I: Io>(&self, io: &mut I) -> anyhow::Result<()> {
+        io.save(&self.path, &serde_json::to_vec(&self.state)?)
+    }
+
+    fn push_with<I: Io>(&mut self, io: &mut I, letter: DeadLetter) -> anyhow::Result<u64> {
+        let id = self.state.next_id;
+        self.state.next_id += 1;
+        self.state.letters.insert(id, letter);
+        self.save(io)?;
+        Ok(id)
+    }
+
+    fn retry_all_with<I: Io>(
+        &mut self,
+        io: &mut I,
+        policy: &BackoffPolicy,
+    ) -> anyhow::Result<RetryReport> {
+        let now_ms = io.now_ms();
+        let mut report = RetryReport::default();
+        for (id, letter) in &self.state.letters {
+            match policy.next_retry_at_ms(letter) {
+                None => report.gave_up.push(*id),
+                Some(due_ms) if due_ms > now_ms => report.waiting.push(*id),
+                Some(_) => report.resent.push(*id),
+            }
+        }
+        if report.resent.is_empty() && report.gave_up.is_empty() {
+            return Ok(report);
+        }
+        let mut resent = Vec::with_capacity(report.resent.len());
+        for id in report.resent.iter().chain(&report.gave_up) {
+            let letter = self.state.letters.remove(id).unwrap();
+            if report.resent.contains(id) {
+                resent.push(letter);
+            }
+        }
+        // saved first: a letter is better lost in a crash than sent twice forever
+        self.save(io)?;
+        for letter in resent {
+            let count = RetryCount {
+                attempts: letter.attempts + 1,
+                first_failed_at_ms: letter.first_failed_at_ms,
+            };
+            let metadata = with_retry_count(letter.metadata.as_deref(), &count);
+            io.send(letter.request().metadata(&metadata))?;
+        }
+        Ok(report)
+    }
+
+    fn purge_older_than_with<I: Io>(&mut self, io: &mut I, ms: u64) -> anyhow::Result<usize> {
+        let cutoff_ms = io.now_ms().saturating_sub(ms);
+        let before = self.state.letters.len();
+        self.state
+            .letters
+            .retain(|_, letter| letter.first_failed_at_ms >= cutoff_ms);
+        let purged = before - self.state.letters.len();
+        if purged > 0 {
+            self.save(io)?;
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockIo {
+        files: BTreeMap<String, Vec<u8>>,
+        sent: Vec<Request>,
+        now_ms: u64,
+    }
+
+    impl Io for MockIo {
+        fn load(&mut self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            Ok(self.files.get(path).cloned())
+        }
+
+        fn save(&mut self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+            self.files.insert(path.to_string(), bytes.to_vec());
+            Ok(())
+        }
+
+        fn send(&mut self, request: Request) -> anyhow::Result<()> {
+            self.sent.push(request);
+            Ok(())
+        }
+
+        fn now_ms(&mut self) -> u64 {
+            self.now_ms
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Notification {
+        user: String,
+        attempt_id: u32,
+    }
+
+    fn target() -> Address {
+        "friend.os@notify:app:pub.os".parse().unwrap()
+    }
+
+    /// The SendError the runtime would issue for request.
+    fn send_error(request: &Request) -> SendError {
+        SendError {
+            kind: SendErrorKind::Offline,
+            target: request.target.clone().unwrap(),
+            message: Message::Request {
+                source: "our.os@app:app:pub.os".parse().unwrap(),
+                expects_response: request.timeout,
+                body: request.body.clone().unwrap(),
+                metadata: request.metadata.clone(),
+                capabilities: vec![],
+            },
+            lazy_load_blob: request.blob.clone(),
+            context: request.context.clone(),
+        }
+    }
+
+    #[test]
+    fn test_reconstruction() {
+        let notification = Notification {
+            user: "alice".to_string(),
+            attempt_id: 7,
+        };
+        let original = Request::to(target())
+            .body(b"ping".to_vec())
+            .metadata("plain text")
+            .context(serde_json::to_vec(&notification).unwrap())
+            .blob(LazyLoadBlob {
+                mime: Some("image/png".to_string()),
+                bytes: vec![1, 2, 3],
+            })
+            .expects_response(30);
+        let letter = handle_at(&send_error(&original), 1_000).unwrap();
+        assert_eq!(letter.target, target());
+        assert!(letter.kind.is_offline());
+        assert_eq!(letter.body, b"ping");
+        assert_eq!(letter.metadata.as_deref(), Some("plain text"));
+        assert_eq!(
+            letter.context_as::<Notification>().unwrap(),
+            Some(notification)
+        );
+        let blob = letter.blob().unwrap();
+        assert_eq!(blob.mime.as_deref(), Some("image/png"));
+        assert_eq!(blob.bytes, [1, 2, 3]);
+        assert_eq!(letter.expects_response, Some(30));
+        assert_eq!((letter.attempts, letter.first_failed_at_ms), (0, 1_000));
+
+        // rebuilt as sent, and the retry count comes back off a retry's metadata
+        let rebuilt = letter.request();
+        assert_eq!(rebuilt.target, original.target);
+        assert_eq!(rebuilt.body, original.body);
+        assert_eq!(rebuilt.metadata, original.metadata);
+        assert_eq!(rebuilt.context, original.context);
+        assert_eq!(rebuilt.timeout, original.timeout);
+        for metadata in [None, Some("plain text"), Some("{}"), Some(r#"{"a":1}"#)] {
+            let count = RetryCount {
+                attempts: 2,
+                first_failed_at_ms: 1_000,
+            };
+            let mut retry = original.clone();
+            retry.metadata = Some(with_retry_count(metadata, &count));
+            let letter = handle_at(&send_error(&retry), 5_000).unwrap();
+            assert_eq!(letter.metadata.as_deref(), metadata);
+            assert_eq!(
+                (
+                    letter.attempts,
+                    letter.first_failed_at_ms,
+                    letter.failed_at_ms
+                ),
+                (2, 1_000, 5_000)
+            );
+        }
+
+        // and survives the queue being persisted
+        let mut io = MockIo::default();
+        let mut queue = DeadLetterQueue::open_with(&mut io, "/dead.json").unwrap();
+        queue.push_with(&mut io, letter).unwrap();
+        let queue = DeadLetterQueue::open_with(&mut io, "/dead.json").unwrap();
+        let (_, letter) = queue.letters().next().unwrap();
+        assert_eq!(letter.blob().unwrap().bytes, [1, 2, 3]);
+        assert_eq!(letter.request().context, original.context);
+    }
+
+    #[test]
+    fn test_backoff_schedule() {
+        let policy = BackoffPolicy {
+            max_attempts: 3,
+            initial_delay_ms: 1_000,
+            multiplier: 3,
+            max_delay_ms: 5_000,
+        };
+        assert_eq!(
+            (0..4).map(|n| policy.delay_ms(n)).collect::<Vec<_>>(),
+            [1_000, 3_000, 5_000, 5_000]
+        );
+
+        let mut io = MockIo::default();
+        let mut queue = DeadLetterQueue::open_with(&mut io, "/dead.json").unwrap();
+        let original = Request::to(target()).body(b"ping".to_vec());
+        let mut letter = handle_at(&send_error(&original), 0).unwrap();
+        let mut due_ms = 0;
+        for (attempt, delay_ms) in [(1, 1_000), (2, 3_000), (3, 5_000)] {
+            let id = queue.push_with(&mut io, letter).unwrap();
+            due_ms += delay_ms;
+            io.now_ms = due_ms - 1;
+            let report = queue.retry_all_with(&mut io, &policy).unwrap();
+            assert_eq!(report.waiting, [id]);
+            assert!(io.sent.is_empty());
+
+            io.now_ms = due_ms;
+            let report = queue.retry_all_with(&mut io, &policy).unwrap();
+            assert_eq!(report.resent, [id]);
+            assert!(queue.is_empty());
+            // the retry fails in turn
+            let retry = io.sent.pop().unwrap();
+            letter = handle_at(&send_error(&retry), due_ms).unwrap();
+            assert_eq!(letter.attempts, attempt);
+            assert_eq!(letter.first_failed_at_ms, 0);
+            assert_eq!(letter.metadata, None);
+        }
+        let id = queue.push_with(&mut io, letter).unwrap();
+        let report = queue.retry_all_with(&mut io, &policy).unwrap();
+        assert_eq!(report.gave_up, [id]);
+        assert!(io.sent.is_empty() && queue.is_empty());
+    }
+
+    #[test]
+    fn test_purge_older_than() {
+        let mut io = MockIo::default();
+        let mut queue = DeadLetterQueue::open_with(&mut io, "/dead.json").unwrap();
+        let original = Request::to(target()).body(b"ping".to_vec());
+        for failed_at_ms in [1_000, 2_000, 3_000] {
+            let letter = handle_at(&send_error(&original), failed_at_ms).unwrap();
+            queue.push_with(&mut io, letter).unwrap();
+        }
+        io.now_ms = 4_000;
+        assert_eq!(queue.purge_older_than_with(&mut io, 2_000).unwrap(), 1);
+        assert_eq!(queue.purge_older_than_with(&mut io, 2_000).unwrap(), 0);
+        let queue = DeadLetterQueue::open_with(&mut io, "/dead.json").unwrap();
+        assert_eq!(
+            queue.letters().map(|(id, _)| id).collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+}
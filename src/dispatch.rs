@@ -0,0 +1,204 @@
+use crate::{Message, Response};
+use serde::de::DeserializeOwned;
+
+/// What each arm of [`handle!`], and the macro itself, evaluates to.
+pub type HandlerResult = anyhow::Result<()>;
+
+/// Deserialize the JSON body of a message as T and hand it to handler.
+pub fn dispatch_json<T, F, R>(message: &Message, handler: F) -> Result<R, serde_json::Error>
+where
+    T: DeserializeOwned,
+    F: FnOnce(T) -> R,
+{
+    Ok(handler(parse(message)?))
+}
+
+/// Deserialize a request body, route it to a handler by variant, and answer with
+/// an error Response if handling fails. Takes a `&Message`, the type of the
+/// body, a match arm per variant, and a closure-like `else` arm called with the
+/// [`serde_json::Error`] when the body does not deserialize. Every arm must
+/// evaluate to a [`HandlerResult`], and so does the whole macro. Each arm runs
+/// in a closure, so `?` and `return` in an arm end that arm rather than the
+/// enclosing function. As in any macro, arms are separated by commas, even
+/// after blocks.
+///
+/// If an arm returns `Err` and the message is a request that expects a response,
+/// this responds with the error's chain as `{"Err": "..."}` before returning it.
+/// Handlers that succeed send their own responses.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, handle, Response};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum CounterRequest {
+///     Add(u64),
+///     Get,
+/// }
+///
+/// let mut count = 0;
+/// let message = await_message().unwrap();
+/// let result = handle!(&message => CounterRequest {
+///     CounterRequest::Add(n) => {
+///         count += n;
+///         Ok(())
+///     },
+///     CounterRequest::Get => {
+///         Response::new().body(serde_json::to_vec(&count)?).send()?;
+///         Ok(())
+///     },
+/// } else |error| Err(anyhow::anyhow!("unknown request: {error}")));
+/// ```
+#[macro_export]
+macro_rules! handle {
+    ($message:expr => $body:ty {
+        $($pattern:pat $(if $guard:expr)? => $handler:expr),+ $(,)?
+    } else |$error:ident| $fallback:expr) => {{
+        let message: &$crate::Message = $message;
+        // each arm runs in its own closure so that `?` leaves the arm, not the caller
+        #[allow(clippy::redundant_closure_call)]
+        let result: $crate::dispatch::HandlerResult = match $crate::dispatch::parse::<$body>(message) {
+            Ok(body) => match body {
+                $($pattern $(if $guard)? => (|| -> $crate::dispatch::HandlerResult { $handler })()),+
+            },
+            Err($error) => (|| -> $crate::dispatch::HandlerResult { $fallback })(),
+        };
+        $crate::dispatch::respond_on_error(message, result)
+    }};
+}
+
+#[doc(hidden)]
+pub fn parse<T: DeserializeOwned>(message: &Message) -> Result<T, serde_json::Error> {
+    serde_json::from_slice(message.body())
+}
+
+#[doc(hidden)]
+pub fn respond_on_error(message: &Message, result: HandlerResult) -> HandlerResult {
+    if let Err(error) = &result {
+        if let Some(body) = error_response_body(message, error) {
+            let _ = Response::new().body(body).send();
+        }
+    }
+    result
+}
+
+/// The body of the error Response [`handle!`] sends, if message expects one.
+fn error_response_body(message: &Message, error: &anyhow::Error) -> Option<Vec<u8>> {
+    match message {
+        Message::Request {
+            expects_response: Some(_),
+            ..
+        } => serde_json::to_vec(&Err::<(), String>(format!("{error:#}"))).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    enum KvRequest {
+        Set(String, u64),
+        Get(String),
+        Delete { key: String },
+    }
+
+    fn request(body: &[u8], expects_response: Option<u64>) -> Message {
+        Message::Request {
+            source: "our.os@client:app:pub.os".parse().unwrap(),
+            expects_response,
+            body: body.to_vec(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    fn route(message: &Message, log: &mut Vec<String>) -> anyhow::Result<()> {
+        crate::handle!(message => KvRequest {
+            KvRequest::Set(key, _) if key == "bad" => {
+                let value: u64 = key.parse()?;
+                log.push(format!("unreachable {value}"));
+                Ok(())
+            },
+            KvRequest::Set(key, value) => {
+                log.push(format!("set {key}={value}"));
+                Ok(())
+            },
+            KvRequest::Get(key) if key.is_empty() => Err(anyhow::anyhow!("empty key")),
+            KvRequest::Get(key) => {
+                log.push(format!("get {key}"));
+                Ok(())
+            },
+            KvRequest::Delete { key } => {
+                log.push(format!("delete {key}"));
+                Ok(())
+            },
+        } else |error| {
+            log.push("malformed".to_string());
+            Err(error.into())
+        })
+    }
+
+    #[test]
+    fn test_handle_routes_by_variant() {
+        let mut log = vec![];
+        let body = |request: KvRequest| serde_json::to_vec(&request).unwrap();
+        route(
+            &request(&body(KvRequest::Set("a".into(), 1)), None),
+            &mut log,
+        )
+        .unwrap();
+        route(&request(&body(KvRequest::Get("a".into())), None), &mut log).unwrap();
+        route(
+            &request(&body(KvRequest::Delete { key: "a".into() }), None),
+            &mut log,
+        )
+        .unwrap();
+        assert_eq!(log, vec!["set a=1", "get a", "delete a"]);
+
+        // failures come back out, whether from an arm or from deserializing
+        let error = route(&request(&body(KvRequest::Get("".into())), None), &mut log);
+        assert_eq!(error.unwrap_err().to_string(), "empty key");
+        // `?` in an arm is caught by the macro, so the error response is still sent
+        let error = route(
+            &request(&body(KvRequest::Set("bad".into(), 1)), None),
+            &mut log,
+        );
+        assert!(error.unwrap_err().is::<std::num::ParseIntError>());
+        assert!(route(&request(b"{\"Unknown\":1}", None), &mut log).is_err());
+        assert_eq!(log.last().unwrap(), "malformed");
+    }
+
+    #[test]
+    fn test_dispatch_json() {
+        let message = request(b"{\"Get\":\"k\"}", None);
+        assert_eq!(
+            dispatch_json(&message, |request: KvRequest| request).unwrap(),
+            KvRequest::Get("k".into())
+        );
+        assert!(dispatch_json(&request(b"nope", None), |_: KvRequest| ()).is_err());
+    }
+
+    #[test]
+    fn test_error_response_body() {
+        let error = anyhow::anyhow!("disk full").context("failed to set");
+        let body = error_response_body(&request(b"", Some(5)), &error).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"Err": "failed to set: disk full"})
+        );
+
+        // nobody is waiting for a response to a fire-and-forget request or a response
+        assert!(error_response_body(&request(b"", None), &error).is_none());
+        let response = Message::Response {
+            source: "our.os@client:app:pub.os".parse().unwrap(),
+            body: vec![],
+            metadata: None,
+            context: None,
+            capabilities: vec![],
+        };
+        assert!(error_response_body(&response, &error).is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A serialization format for bodies and state. JSON is the crate's default and
+/// the one to use when other processes need to read the bytes; bincode and
+/// MessagePack are smaller and faster to encode and decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Json,
+    Bincode,
+    MsgPack,
+}
+
+impl Encoding {
+    /// The first byte of an [`encoded()`] value.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Encoding::Json => 0x01,
+            Encoding::Bincode => 0x02,
+            Encoding::MsgPack => 0x03,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0x01 => Some(Encoding::Json),
+            0x02 => Some(Encoding::Bincode),
+            0x03 => Some(Encoding::MsgPack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Json => serde_json::to_vec(value)?,
+            Encoding::Bincode => bincode::serialize(value)?,
+            Encoding::MsgPack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    /// Decode bytes, reporting a failure with their length and a preview as in
+    /// [`crate::try_get_typed_blob()`]. what names the bytes in the error.
+    pub fn decode<T: DeserializeOwned>(&self, what: &str, bytes: &[u8]) -> anyhow::Result<T> {
+        match self {
+            Encoding::Json => crate::deserialize_bytes(what, bytes, |b| serde_json::from_slice(b)),
+            Encoding::Bincode => crate::deserialize_bytes(what, bytes, |b| bincode::deserialize(b)),
+            Encoding::MsgPack => {
+                crate::deserialize_bytes(what, bytes, |b| rmp_serde::from_slice(b))
+            }
+        }
+    }
+}
+
+/// Encode value prefixed with the one-byte [`Encoding::tag()`], so that a receiver
+/// can [`decode_encoded()`] it without knowing which encoding the sender chose.
+pub fn encoded<T: Serialize>(encoding: Encoding, value: &T) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![encoding.tag()];
+    bytes.extend(encoding.encode(value)?);
+    Ok(bytes)
+}
+
+/// Decode bytes made by [`encoded()`]. Bytes without a tag are decoded as plain
+/// JSON, which never starts with a tag byte, so senders that don't use [`encoded()`]
+/// are understood too.
+pub fn decode_encoded<T: DeserializeOwned>(what: &str, bytes: &[u8]) -> anyhow::Result<T> {
+    match bytes.first().and_then(|tag| Encoding::from_tag(*tag)) {
+        Some(encoding) => encoding.decode(what, &bytes[1..]),
+        None => Encoding::Json.decode(what, bytes),
+    }
+}
+
+/// Save value as the process state, encoded with encoding. Read it back with
+/// [`try_get_typed_state_as()`].
+pub fn set_typed_state_as<T: Serialize>(encoding: Encoding, value: &T) -> anyhow::Result<()> {
+    crate::set_state(&encoding.encode(value)?);
+    Ok(())
+}
+
+/// Read the process state saved by [`set_typed_state_as()`] with the same encoding.
+/// Returns `Ok(None)` if there is no state.
+pub fn try_get_typed_state_as<T: DeserializeOwned>(
+    encoding: Encoding,
+) -> anyhow::Result<Option<T>> {
+    crate::get_state()
+        .map(|bytes| encoding.decode("state", &bytes))
+        .transpose()
+}
+
+/// [`set_typed_state_as()`] with [`Encoding::Bincode`].
+pub fn set_typed_state_bincode<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    set_typed_state_as(Encoding::Bincode, value)
+}
+
+/// [`try_get_typed_state_as()`] with [`Encoding::Bincode`].
+pub fn try_get_typed_state_bincode<T: DeserializeOwned>() -> anyhow::Result<Option<T>> {
+    try_get_typed_state_as(Encoding::Bincode)
+}
+
+/// [`set_typed_state_as()`] with [`Encoding::MsgPack`].
+pub fn set_typed_state_msgpack<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    set_typed_state_as(Encoding::MsgPack, value)
+}
+
+/// [`try_get_typed_state_as()`] with [`Encoding::MsgPack`].
+pub fn try_get_typed_state_msgpack<T: DeserializeOwned>() -> anyhow::Result<Option<T>> {
+    try_get_typed_state_as(Encoding::MsgPack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Job {
+        Idle,
+        Resize { width: u32, height: u32 },
+        Fetch(String, Option<u64>),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Batch {
+        id: u64,
+        owner: Option<String>,
+        jobs: Vec<Job>,
+        retries: BTreeMap<String, Option<u8>>,
+        inner: Option<Box<Batch>>,
+    }
+
+    fn batch() -> Batch {
+        Batch {
+            id: 7,
+            owner: None,
+            jobs: vec![
+                Job::Idle,
+                Job::Resize {
+                    width: 640,
+                    height: 480,
+                },
+                Job::Fetch("https://example.com".to_string(), Some(3)),
+                Job::Fetch(String::new(), None),
+            ],
+            retries: [("a".to_string(), Some(2)), ("b".to_string(), None)]
+                .into_iter()
+                .collect(),
+            inner: Some(Box::new(Batch {
+                id: 8,
+                owner: Some("alice".to_string()),
+                jobs: vec![],
+                retries: BTreeMap::new(),
+                inner: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn test_round_trips() {
+        for encoding in [Encoding::Json, Encoding::Bincode, Encoding::MsgPack] {
+            let bytes = encoding.encode(&batch()).unwrap();
+            assert_eq!(encoding.decode::<Batch>("body", &bytes).unwrap(), batch());
+
+            let tagged = encoded(encoding, &batch()).unwrap();
+            assert_eq!(tagged[0], encoding.tag());
+            assert_eq!(Encoding::from_tag(tagged[0]), Some(encoding));
+            assert_eq!(decode_encoded::<Batch>("body", &tagged).unwrap(), batch());
+        }
+    }
+
+    #[test]
+    fn test_message_body_as() {
+        let message = |body: Vec<u8>| crate::Message::Request {
+            source: "our.os@worker:app:pub.os".parse().unwrap(),
+            expects_response: None,
+            body,
+            metadata: None,
+            capabilities: vec![],
+        };
+        let bincode = message(Encoding::Bincode.encode(&batch()).unwrap());
+        assert_eq!(bincode.body_as_bincode::<Batch>().unwrap(), batch());
+        assert!(bincode.body_as_msgpack::<Batch>().is_err());
+        let msgpack = message(Encoding::MsgPack.encode(&batch()).unwrap());
+        assert_eq!(msgpack.body_as_msgpack::<Batch>().unwrap(), batch());
+        let tagged = message(encoded(Encoding::Bincode, &batch()).unwrap());
+        assert_eq!(tagged.body_as_encoded::<Batch>().unwrap(), batch());
+    }
+
+    #[test]
+    fn test_decode_encoded_accepts_plain_json() {
+        let plain = serde_json::to_vec(&batch()).unwrap();
+        assert_eq!(decode_encoded::<Batch>("body", &plain).unwrap(), batch());
+        assert!(decode_encoded::<Batch>("body", b"").is_err());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = Encoding::MsgPack.encode(&batch()).unwrap();
+        let error = Encoding::Bincode
+            .decode::<Batch>("body", &bytes)
+            .unwrap_err();
+        assert!(format!("{error:#}").starts_with(&format!(
+            "failed to deserialize {}-byte body starting with",
+            bytes.len()
+        )));
+        assert!(error.downcast_ref::<bincode::Error>().is_some());
+
+        let tagged = encoded(Encoding::MsgPack, &Job::Idle).unwrap();
+        assert!(decode_encoded::<Batch>("body", &tagged).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+/// A body as sent by [`wrap()`]: the protocol version of the sender, and the
+/// value itself.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Versioned<T> {
+    #[serde(rename = "v")]
+    pub version: u32,
+    pub body: T,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The sender speaks a version of the protocol with a variant we don't know,
+    /// most likely a newer one: ask it to fall back, or its user to upgrade us.
+    #[error("unknown variant `{variant}` from protocol version {version_seen}")]
+    UnknownVariant { version_seen: u32, variant: String },
+    /// A well-formed envelope whose body doesn't parse as the expected type.
+    #[error("bad body from protocol version {version_seen}: {error}")]
+    BadBody { version_seen: u32, error: String },
+    /// Not JSON, or missing the `v` or `body` field.
+    #[error("malformed envelope: {0}")]
+    Malformed(String),
+}
+
+impl EnvelopeError {
+    /// The protocol version of the sender, if the envelope was readable.
+    pub fn version_seen(&self) -> Option<u32> {
+        match self {
+            EnvelopeError::UnknownVariant { version_seen, .. }
+            | EnvelopeError::BadBody { version_seen, .. } => Some(*version_seen),
+            EnvelopeError::Malformed(_) => None,
+        }
+    }
+}
+
+/// value as JSON, tagged with the protocol version: `{"v": version, "body": value}`.
+pub fn wrap<T: Serialize>(version: u32, value: &T) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&Versioned {
+        version,
+        body: value,
+    })
+}
+
+/// Parse bytes made by [`wrap()`]. A body naming a variant T doesn't have fails
+/// with [`EnvelopeError::UnknownVariant`], distinct from other parse failures, so
+/// a handler can answer with something better than silence.
+///
+/// ```
+/// use hyperware_process_lib::envelope::{self, EnvelopeError};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// enum Old { Ping }
+/// #[derive(Serialize, Deserialize)]
+/// enum New { Ping, Pong }
+///
+/// let bytes = envelope::wrap(2, &New::Pong).unwrap();
+/// assert!(matches!(
+///     envelope::unwrap::<Old>(&bytes),
+///     Err(EnvelopeError::UnknownVariant { version_seen: 2, .. })
+/// ));
+/// ```
+pub fn unwrap<T: DeserializeOwned>(bytes: &[u8]) -> Result<Versioned<T>, EnvelopeError> {
+    let Versioned { version, body } = serde_json::from_slice::<Versioned<serde_json::Value>>(bytes)
+        .map_err(|e| EnvelopeError::Malformed(e.to_string()))?;
+    T::deserialize(body)
+        .map(|body| Versioned { version, body })
+        .map_err(|e| match unknown_variant(&e.to_string()) {
+            Some(variant) => EnvelopeError::UnknownVariant {
+                version_seen: version,
+                variant,
+            },
+            None => EnvelopeError::BadBody {
+                version_seen: version,
+                error: e.to_string(),
+            },
+        })
+}
+
+/// The variant named by a serde "unknown variant `X`, expected ..." error.
+fn unknown_variant(error: &str) -> Option<String> {
+    let rest = error.strip_prefix("unknown variant `")?;
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    /// Version 1 of a protocol.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum V1 {
+        Get { key: String },
+        Delete { key: String },
+    }
+
+    /// Version 2 adds a variant.
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum V2 {
+        Get { key: String },
+        Delete { key: String },
+        Rename { from: String, to: String },
+    }
+
+    fn message(body: Vec<u8>) -> Message {
+        Message::Request {
+            source: "our.os@app:app:pub.os".parse().unwrap(),
+            expects_response: None,
+            body,
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    #[test]
+    fn test_cross_version_decode() {
+        // old to new: every old variant is still understood
+        let bytes = wrap(
+            1,
+            &V1::Delete {
+                key: "a".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            unwrap::<V2>(&bytes).unwrap(),
+            Versioned {
+                version: 1,
+                body: V2::Delete {
+                    key: "a".to_string()
+                }
+            }
+        );
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            r#"{"v":1,"body":{"Delete":{"key":"a"}}}"#
+        );
+
+        // new to old: shared variants parse, the new one is reported as such
+        let bytes = wrap(
+            2,
+            &V2::Get {
+                key: "a".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(unwrap::<V1>(&bytes).unwrap().version, 2);
+        let bytes = wrap(
+            2,
+            &V2::Rename {
+                from: "a".to_string(),
+                to: "b".to_string(),
+            },
+        )
+        .unwrap();
+        let error = unwrap::<V1>(&bytes).unwrap_err();
+        assert_eq!(
+            error,
+            EnvelopeError::UnknownVariant {
+                version_seen: 2,
+                variant: "Rename".to_string()
+            }
+        );
+        assert_eq!(error.version_seen(), Some(2));
+        assert_eq!(message(bytes).body_as_versioned::<V1>().unwrap_err(), error);
+    }
+
+    #[test]
+    fn test_malformed() {
+        // a known variant with the wrong fields is a bad body, not an unknown variant
+        let error = unwrap::<V1>(br#"{"v":3,"body":{"Get":{"name":"a"}}}"#).unwrap_err();
+        assert!(matches!(
+            error,
+            EnvelopeError::BadBody {
+                version_seen: 3,
+                ..
+            }
+        ));
+        for bytes in [
+            &b"not json"[..],
+            br#"{"Get":{"key":"a"}}"#,
+            br#"{"v":"1","body":{"Get":{"key":"a"}}}"#,
+        ] {
+            let error = unwrap::<V1>(bytes).unwrap_err();
+            assert!(matches!(error, EnvelopeError::Malformed(_)), "{error}");
+            assert_eq!(error.version_seen(), None);
+        }
+    }
+}
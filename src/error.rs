@@ -0,0 +1,387 @@
+use crate::{
+    config::ConfigError,
+    crypto::SignatureError,
+    envelope::EnvelopeError,
+    eth::EthError,
+    guard::Denied,
+    http::{client::HttpClientError, multipart::MultipartError, server::HttpServerError},
+    hypermap::DecodeLogError,
+    kv::KvError,
+    net::NetError,
+    rpc::{CallError, RpcError},
+    sqlite::{MigrationError, SqliteError},
+    sync::SyncError,
+    util::UidParseError,
+    version::VersionError,
+    vfs::{VfsClientError, VfsError},
+    AddressParseError, AwaitError, BuildError, ProcessIdParseError, RemoteError, SendError,
+    SendErrorKind,
+};
+
+/// What went wrong, broadly, for callers that handle failures by category
+/// rather than by the error type of each module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// An id, address or other string did not parse.
+    Parse,
+    /// A message could not be built or delivered.
+    Send,
+    /// Something did not answer in time, or a deadline passed.
+    Timeout,
+    /// A body, blob or file did not deserialize as expected.
+    Deserialize,
+    /// `vfs:distro:sys` refused or failed the request.
+    Vfs,
+    /// The http client or server refused or failed the request.
+    Http,
+    /// A kv or sqlite database refused or failed the request.
+    Kv,
+    /// The sender or we lack a capability, or the sender is otherwise not allowed.
+    Capability,
+    Other,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+/// An error from this crate, of any module, along with its [`ErrorKind`].
+///
+/// Like [`std::io::Error`], it displays as the error it wraps, which can be
+/// had back with [`Error::get_ref()`] or [`Error::downcast_ref()`]. Every module
+/// error converts into it with `?`, and so does an [`anyhow::Error`], whose
+/// chain is searched for a module error to take the kind from: see
+/// [`Error::kind_of()`].
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    error: Box<dyn std::error::Error + Send + Sync + 'static>,
+}
+
+/// A `Result` whose error is an [`Error`]. Functions that return
+/// [`anyhow::Result`] keep doing so; convert their errors with `?` or
+/// [`Error::from()`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Wrap error as of kind.
+    pub fn new<E>(kind: ErrorKind, error: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Error {
+            kind,
+            error: error.into(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The error this wraps.
+    pub fn get_ref(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self.error.as_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn std::error::Error + Send + Sync> {
+        self.error
+    }
+
+    /// The error this wraps, if it is an E, as a module error or anywhere in
+    /// the chain of a wrapped [`anyhow::Error`].
+    pub fn downcast_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        let mut next: Option<&(dyn std::error::Error + 'static)> = Some(self.error.as_ref());
+        while let Some(error) = next {
+            if let Some(found) = error.downcast_ref::<E>() {
+                return Some(found);
+            }
+            next = error.source();
+        }
+        None
+    }
+
+    /// The [`ErrorKind`] of error: that of the first error in its chain that
+    /// this module knows, or [`ErrorKind::Other`] if none.
+    pub fn kind_of(error: &anyhow::Error) -> ErrorKind {
+        error
+            .chain()
+            .find_map(known_kind)
+            .unwrap_or(ErrorKind::Other)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // the wrapped error is displayed as this one, so not repeated as its source
+        self.error.source()
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<Error>() {
+            Ok(error) => error,
+            Err(error) => Error {
+                kind: Error::kind_of(&error),
+                error: error.into(),
+            },
+        }
+    }
+}
+
+/// The kind of error, if it is one of the module errors this knows.
+fn known_kind(error: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+    macro_rules! try_kinds {
+        ($($t:ty),* $(,)?) => {
+            $(if let Some(error) = error.downcast_ref::<$t>() {
+                return Some(Kind::kind(error));
+            })*
+        };
+    }
+    try_kinds!(
+        Error,
+        AddressParseError,
+        ProcessIdParseError,
+        UidParseError,
+        BuildError,
+        SendError,
+        AwaitError,
+        RemoteError,
+        VfsError,
+        VfsClientError,
+        HttpClientError,
+        HttpServerError,
+        MultipartError,
+        KvError,
+        NetError,
+        SqliteError,
+        MigrationError,
+        EthError,
+        RpcError,
+        CallError,
+        ConfigError,
+        EnvelopeError,
+        DecodeLogError,
+        Denied,
+        SignatureError,
+        SyncError,
+        VersionError,
+        serde_json::Error,
+    );
+    None
+}
+
+/// The [`ErrorKind`] of a module error.
+trait Kind {
+    fn kind(&self) -> ErrorKind;
+}
+
+macro_rules! impl_kind {
+    ($($t:ty => |$error:ident| $kind:expr),* $(,)?) => {
+        $(
+            impl Kind for $t {
+                #[allow(unused_variables)]
+                fn kind(&self) -> ErrorKind {
+                    let $error = self;
+                    $kind
+                }
+            }
+
+            impl From<$t> for Error {
+                fn from(error: $t) -> Self {
+                    Error {
+                        kind: Kind::kind(&error),
+                        error: Box::new(error),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl Kind for Error {
+    fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+fn send_kind(kind: &SendErrorKind) -> ErrorKind {
+    match kind {
+        SendErrorKind::Offline => ErrorKind::Send,
+        SendErrorKind::Timeout => ErrorKind::Timeout,
+    }
+}
+
+impl_kind!(
+    AddressParseError => |e| ErrorKind::Parse,
+    ProcessIdParseError => |e| ErrorKind::Parse,
+    UidParseError => |e| ErrorKind::Parse,
+    BuildError => |e| match e {
+        BuildError::DeadlineExceeded => ErrorKind::Timeout,
+        _ => ErrorKind::Send,
+    },
+    SendError => |e| send_kind(&e.kind),
+    AwaitError => |e| match e {
+        AwaitError::Build(e) => Kind::kind(e),
+        AwaitError::Transport { kind, .. } => send_kind(kind),
+        AwaitError::Timeout { .. } => ErrorKind::Timeout,
+        AwaitError::Remote(e) => Kind::kind(e),
+        AwaitError::Deserialize { .. } | AwaitError::MissingBlob { .. } => ErrorKind::Deserialize,
+    },
+    RemoteError => |e| ErrorKind::Other,
+    VfsError => |e| match e {
+        VfsError::NoReadCap | VfsError::NoWriteCap => ErrorKind::Capability,
+        VfsError::SendError(kind) => send_kind(kind),
+        _ => ErrorKind::Vfs,
+    },
+    VfsClientError => |e| match e {
+        VfsClientError::NoCapability { .. } => ErrorKind::Capability,
+        VfsClientError::Timeout { .. }
+        | VfsClientError::DeadlineExceeded
+        | VfsClientError::LockTimeout { .. } => ErrorKind::Timeout,
+        VfsClientError::Json { .. } => ErrorKind::Deserialize,
+        VfsClientError::Build(e) => Kind::kind(e),
+        VfsClientError::Runtime(e) => Kind::kind(e),
+        _ => ErrorKind::Vfs,
+    },
+    HttpClientError => |e| match e {
+        HttpClientError::Timeout => ErrorKind::Timeout,
+        HttpClientError::Vfs(e) => Kind::kind(e),
+        HttpClientError::Build(e) => Kind::kind(e),
+        _ => ErrorKind::Http,
+    },
+    HttpServerError => |e| match e {
+        HttpServerError::Timeout => ErrorKind::Timeout,
+        HttpServerError::Build(e) => Kind::kind(e),
+        _ => ErrorKind::Http,
+    },
+    MultipartError => |e| ErrorKind::Http,
+    KvError => |e| match e {
+        KvError::NoReadCap | KvError::NoWriteCap => ErrorKind::Capability,
+        _ => ErrorKind::Kv,
+    },
+    SqliteError => |e| match e {
+        SqliteError::NoReadCap | SqliteError::NoWriteCap => ErrorKind::Capability,
+        _ => ErrorKind::Kv,
+    },
+    MigrationError => |e| ErrorKind::Kv,
+    NetError => |e| match e {
+        NetError::Build(e) => Kind::kind(e),
+        NetError::Send(e) => Kind::kind(e.as_ref()),
+        NetError::NoSignature => ErrorKind::Deserialize,
+    },
+    EthError => |e| match e {
+        EthError::RpcTimeout => ErrorKind::Timeout,
+        EthError::PermissionDenied => ErrorKind::Capability,
+        EthError::RpcMalformedResponse => ErrorKind::Deserialize,
+        _ => ErrorKind::Other,
+    },
+    RpcError => |e| ErrorKind::Other,
+    CallError => |e| match e {
+        CallError::Encode(_) | CallError::BadResponse(_) => ErrorKind::Deserialize,
+        CallError::Send(e) => Kind::kind(e.as_ref()),
+        CallError::Build(e) => Kind::kind(e),
+        CallError::Rpc(e) => Kind::kind(e),
+    },
+    ConfigError => |e| match e {
+        ConfigError::Parse { .. } => ErrorKind::Parse,
+        ConfigError::Vfs(e) => Kind::kind(e),
+        _ => ErrorKind::Other,
+    },
+    EnvelopeError => |e| ErrorKind::Deserialize,
+    DecodeLogError => |e| ErrorKind::Deserialize,
+    Denied => |e| ErrorKind::Capability,
+    SignatureError => |e| ErrorKind::Capability,
+    SyncError => |e| ErrorKind::Other,
+    VersionError => |e| ErrorKind::Parse,
+    serde_json::Error => |e| ErrorKind::Deserialize,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, ProcessId};
+    use anyhow::Context;
+
+    fn kind(error: impl Into<Error>) -> ErrorKind {
+        error.into().kind()
+    }
+
+    #[test]
+    fn test_module_errors_classified() {
+        let parse = "no-at-sign".parse::<Address>().unwrap_err();
+        assert_eq!(kind(parse), ErrorKind::Parse);
+        assert_eq!(
+            kind("a:b".parse::<ProcessId>().unwrap_err()),
+            ErrorKind::Parse
+        );
+        assert_eq!(kind(BuildError::NoBody), ErrorKind::Send);
+        assert_eq!(kind(BuildError::DeadlineExceeded), ErrorKind::Timeout);
+        assert_eq!(
+            kind(VfsError::IOError("No such file".into())),
+            ErrorKind::Vfs
+        );
+        assert_eq!(kind(VfsError::NoReadCap), ErrorKind::Capability);
+        assert_eq!(
+            kind(VfsError::SendError(SendErrorKind::Timeout)),
+            ErrorKind::Timeout
+        );
+        assert_eq!(kind(HttpClientError::BadStatus(502)), ErrorKind::Http);
+        assert_eq!(kind(HttpServerError::Timeout), ErrorKind::Timeout);
+        assert_eq!(kind(KvError::KeyNotFound), ErrorKind::Kv);
+        assert_eq!(kind(SqliteError::NoWriteCap), ErrorKind::Capability);
+        assert_eq!(
+            kind(serde_json::from_str::<u8>("x").unwrap_err()),
+            ErrorKind::Deserialize
+        );
+        assert_eq!(
+            kind(Denied::NotLocal {
+                sender: "them.os@app:app:pub.os".parse().unwrap()
+            }),
+            ErrorKind::Capability
+        );
+        assert_eq!(
+            kind(CallError::Build(BuildError::DeadlineExceeded)),
+            ErrorKind::Timeout
+        );
+    }
+
+    #[test]
+    fn test_anyhow_chain_classified() {
+        let error = Err::<(), _>(KvError::NoTx(3))
+            .context("committing")
+            .context("saving order")
+            .unwrap_err();
+        let error = Error::from(error);
+        assert_eq!(error.kind(), ErrorKind::Kv);
+        assert_eq!(error.to_string(), "saving order");
+        assert!(matches!(
+            error.downcast_ref::<KvError>(),
+            Some(KvError::NoTx(3))
+        ));
+        assert_eq!(
+            Error::from(anyhow::anyhow!("something else")).kind(),
+            ErrorKind::Other
+        );
+
+        // converting to anyhow and back keeps the kind, and can be downcast
+        let error: anyhow::Error = Error::from(VfsError::NoWriteCap).into();
+        assert_eq!(
+            error.downcast_ref::<Error>().map(Error::kind),
+            Some(ErrorKind::Capability)
+        );
+        assert_eq!(
+            Error::kind_of(&error.context("writing")),
+            ErrorKind::Capability
+        );
+    }
+}
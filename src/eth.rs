@@ -10,7 +10,7 @@ pub use alloy::rpc::types::{
 pub use alloy::transports::Authorization as AlloyAuthorization;
 pub use alloy_primitives::{Address, BlockHash, BlockNumber, Bytes, TxHash, U128, U256, U64, U8};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 
@@ -131,6 +131,9 @@ pub enum EthError {
     RpcTimeout,
     /// RPC gave garbage back
     RpcMalformedResponse,
+    /// Not actually issued by `eth:distro:sys`, just this library:
+    /// the request was never sent
+    Build(crate::BuildError),
 }
 
 impl fmt::Display for EthError {
@@ -145,12 +148,29 @@ impl fmt::Display for EthError {
             EthError::PermissionDenied => write!(f, "Permission denied"),
             EthError::RpcTimeout => write!(f, "RPC request timed out"),
             EthError::RpcMalformedResponse => write!(f, "RPC returned malformed response"),
+            EthError::Build(e) => write!(f, "Failed to send: {}", e),
         }
     }
 }
 
 impl Error for EthError {}
 
+impl EthError {
+    /// The code and message of an error returned by the RPC provider itself, as
+    /// opposed to one from the runtime or transport. `None` for any other error.
+    pub fn rpc_error(&self) -> Option<ErrorPayload> {
+        match self {
+            EthError::RpcError(value) => serde_json::from_value(value.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an error returned by the RPC provider itself.
+    pub fn is_rpc_error(&self) -> bool {
+        matches!(self, EthError::RpcError(_))
+    }
+}
+
 /// The action type used for configuring eth:distro:sys. Only processes which have the "root"
 /// [`crate::Capability`] from eth:distro:sys can successfully send this action.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -329,24 +349,41 @@ impl Provider {
         action: EthAction,
     ) -> Result<T, EthError> {
         let resp = KiRequest::new()
-            .target(("our", "eth", "distro", "sys"))
+            .target(crate::SystemProcess::Eth.address("our"))
             .body(serde_json::to_vec(&action).unwrap())
             .send_and_await_response(self.request_timeout)
-            .unwrap()
+            .map_err(EthError::Build)?
             .map_err(|_| EthError::RpcTimeout)?;
 
         match resp {
-            Message::Response { body, .. } => match serde_json::from_slice::<EthResponse>(&body) {
-                Ok(EthResponse::Response(value)) => {
-                    serde_json::from_value::<T>(value).map_err(|_| EthError::RpcMalformedResponse)
-                }
-                Ok(EthResponse::Err(e)) => Err(e),
-                _ => Err(EthError::RpcMalformedResponse),
-            },
+            Message::Response { body, .. } => parse_eth_response(&body),
             _ => Err(EthError::RpcMalformedResponse),
         }
     }
 
+    /// Make any JSON-RPC call: an escape hatch for methods without a dedicated function.
+    /// params is usually a tuple or array of the method's positional parameters,
+    /// and the result is deserialized as T (use [`serde_json::Value`] to inspect it).
+    ///
+    /// ```no_run
+    /// use hyperware_process_lib::eth::{Provider, U64};
+    ///
+    /// let provider = Provider::new(1, 30);
+    /// let chain_id: U64 = provider.request("eth_chainId", ()).unwrap();
+    /// ```
+    pub fn request<T, P>(&self, method: &str, params: P) -> Result<T, EthError>
+    where
+        T: serde::de::DeserializeOwned,
+        P: Serialize,
+    {
+        let action = EthAction::Request {
+            chain_id: self.chain_id,
+            method: method.to_string(),
+            params: serde_json::to_value(params).map_err(|_| EthError::InvalidParams)?,
+        };
+        self.send_request_and_parse_response(action)
+    }
+
     /// Retrieves the current block number.
     ///
     /// # Returns
@@ -729,10 +766,10 @@ impl Provider {
         };
 
         let resp = KiRequest::new()
-            .target(("our", "eth", "distro", "sys"))
+            .target(crate::SystemProcess::Eth.address("our"))
             .body(body)
             .send_and_await_response(self.request_timeout)
-            .unwrap()
+            .map_err(EthError::Build)?
             .map_err(|_| EthError::RpcTimeout)?;
 
         match resp {
@@ -783,10 +820,10 @@ impl Provider {
         let action = EthAction::UnsubscribeLogs(sub_id);
 
         let resp = KiRequest::new()
-            .target(("our", "eth", "distro", "sys"))
+            .target(crate::SystemProcess::Eth.address("our"))
             .body(serde_json::to_vec(&action).map_err(|_| EthError::MalformedRequest)?)
             .send_and_await_response(self.request_timeout)
-            .unwrap()
+            .map_err(EthError::Build)?
             .map_err(|_| EthError::RpcTimeout)?;
 
         match resp {
@@ -797,4 +834,399 @@ impl Provider {
             _ => Err(EthError::RpcMalformedResponse),
         }
     }
+
+    /// Subscribe to logs matching filter under a newly picked, random subscription ID.
+    /// Updates arrive as requests from eth:distro:sys: see [`parse_subscription()`].
+    /// To resubscribe automatically, use a [`SubscriptionManager`] instead.
+    pub fn subscribe_logs(&self, filter: Filter) -> anyhow::Result<SubscriptionId> {
+        let sub_id = rand::random();
+        self.subscribe(sub_id, filter)?;
+        Ok(sub_id)
+    }
+}
+
+/// ID of a log subscription, chosen by the subscribing process.
+pub type SubscriptionId = u64;
+
+/// An update on a subscription, parsed by [`parse_subscription()`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubEvent {
+    /// A log matching the subscription's filter.
+    Log { id: SubscriptionId, log: Box<Log> },
+    /// The subscription was closed, and will send no more updates unless resubscribed.
+    Error { id: SubscriptionId, error: String },
+}
+
+impl SubEvent {
+    pub fn id(&self) -> SubscriptionId {
+        match self {
+            SubEvent::Log { id, .. } | SubEvent::Error { id, .. } => *id,
+        }
+    }
+}
+
+/// Parse a subscription update pushed by our node's eth:distro:sys. Returns `None` for
+/// any other message, including responses from eth:distro:sys, so it can be tried on
+/// every incoming message before handling it otherwise.
+pub fn parse_subscription(message: &Message) -> Option<SubEvent> {
+    parse_subscription_from(message, &crate::our().node)
+}
+
+fn parse_subscription_from(message: &Message, our_node: &str) -> Option<SubEvent> {
+    if !message.is_request()
+        || message.source().node != our_node
+        || !message.is_process(crate::SystemProcess::Eth)
+    {
+        return None;
+    }
+    match serde_json::from_slice::<EthSubResult>(message.body()).ok()? {
+        Ok(EthSub { id, result }) => Some(SubEvent::Log {
+            id,
+            log: serde_json::from_value(result).ok()?,
+        }),
+        Err(EthSubError { id, error }) => Some(SubEvent::Error { id, error }),
+    }
+}
+
+/// Log subscriptions that remember their filters, so they can be recreated under the
+/// same IDs after a provider closes them.
+///
+/// ```no_run
+/// use hyperware_process_lib::eth::{parse_subscription, Filter, Provider, SubEvent, SubscriptionManager};
+/// use hyperware_process_lib::await_message;
+///
+/// let mut subscriptions = SubscriptionManager::new(Provider::new(1, 30));
+/// subscriptions.subscribe(Filter::new().event("Transfer(address,address,uint256)")).unwrap();
+/// loop {
+///     let Ok(message) = await_message() else { continue };
+///     match parse_subscription(&message) {
+///         Some(SubEvent::Log { log, .. }) => { /* handle log */ }
+///         Some(SubEvent::Error { .. }) => subscriptions.resubscribe_all().unwrap(),
+///         None => { /* handle other messages */ }
+///     }
+/// }
+/// ```
+pub struct SubscriptionManager {
+    provider: Provider,
+    filters: BTreeMap<SubscriptionId, Filter>,
+}
+
+impl SubscriptionManager {
+    pub fn new(provider: Provider) -> Self {
+        SubscriptionManager {
+            provider,
+            filters: BTreeMap::new(),
+        }
+    }
+
+    pub fn provider(&self) -> &Provider {
+        &self.provider
+    }
+
+    /// Subscribe to logs matching filter and track the subscription.
+    pub fn subscribe(&mut self, filter: Filter) -> anyhow::Result<SubscriptionId> {
+        let provider = self.provider.clone();
+        self.subscribe_with(filter, |id, filter| provider.subscribe(id, filter.clone()))
+    }
+
+    /// Unsubscribe and stop tracking the subscription. Errors if it is not tracked.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> anyhow::Result<()> {
+        let provider = self.provider.clone();
+        self.unsubscribe_with(id, |id| provider.unsubscribe(id))
+    }
+
+    /// Recreate every tracked subscription under its ID, e.g. after a
+    /// [`SubEvent::Error`]. All are attempted; errors if any could not be recreated,
+    /// in which case they stay tracked so they can be retried.
+    pub fn resubscribe_all(&self) -> anyhow::Result<()> {
+        let provider = &self.provider;
+        self.resubscribe_all_with(|id, filter| provider.subscribe(id, filter.clone()))
+    }
+
+    /// The filter of a tracked subscription.
+    pub fn filter(&self, id: SubscriptionId) -> Option<&Filter> {
+        self.filters.get(&id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = SubscriptionId> + '_ {
+        self.filters.keys().copied()
+    }
+
+    pub fn contains(&self, id: SubscriptionId) -> bool {
+        self.filters.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    fn subscribe_with<F>(&mut self, filter: Filter, subscribe: F) -> anyhow::Result<SubscriptionId>
+    where
+        F: FnOnce(SubscriptionId, &Filter) -> Result<(), EthError>,
+    {
+        let id = loop {
+            let id = rand::random();
+            if !self.filters.contains_key(&id) {
+                break id;
+            }
+        };
+        subscribe(id, &filter)?;
+        self.filters.insert(id, filter);
+        Ok(id)
+    }
+
+    fn unsubscribe_with<F>(&mut self, id: SubscriptionId, unsubscribe: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(SubscriptionId) -> Result<(), EthError>,
+    {
+        if !self.filters.contains_key(&id) {
+            return Err(anyhow::anyhow!("not tracking subscription {id}"));
+        }
+        unsubscribe(id)?;
+        self.filters.remove(&id);
+        Ok(())
+    }
+
+    fn resubscribe_all_with<F>(&self, mut subscribe: F) -> anyhow::Result<()>
+    where
+        F: FnMut(SubscriptionId, &Filter) -> Result<(), EthError>,
+    {
+        let failed: Vec<String> = self
+            .filters
+            .iter()
+            .filter_map(|(id, filter)| {
+                subscribe(*id, filter)
+                    .err()
+                    .map(|error| format!("{id}: {error}"))
+            })
+            .collect();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "failed to resubscribe {}",
+                failed.join(", ")
+            ))
+        }
+    }
+}
+
+/// Parse the body of an [`EthResponse`] to a [`EthAction::Request`] into T.
+fn parse_eth_response<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, EthError> {
+    match serde_json::from_slice::<EthResponse>(body) {
+        Ok(EthResponse::Response(value)) => {
+            serde_json::from_value::<T>(value).map_err(|_| EthError::RpcMalformedResponse)
+        }
+        Ok(EthResponse::Err(e)) => Err(e),
+        _ => Err(EthError::RpcMalformedResponse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_eth_action_envelope() {
+        let action = EthAction::Request {
+            chain_id: 8453,
+            method: "eth_getBalance".to_string(),
+            params: serde_json::to_value((
+                Address::ZERO,
+                BlockId::Number(BlockNumberOrTag::Latest),
+            ))
+            .unwrap(),
+        };
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            json!({"Request": {
+                "chain_id": 8453,
+                "method": "eth_getBalance",
+                "params": ["0x0000000000000000000000000000000000000000", "latest"],
+            }})
+        );
+        assert_eq!(
+            serde_json::to_value(EthAction::UnsubscribeLogs(3)).unwrap(),
+            json!({"UnsubscribeLogs": 3})
+        );
+    }
+
+    #[test]
+    fn test_parse_block_number_and_balance() {
+        let body = serde_json::to_vec(&json!({"Response": "0x12a05f2"})).unwrap();
+        assert_eq!(
+            parse_eth_response::<U64>(&body).unwrap().to::<u64>(),
+            19_531_250
+        );
+
+        let body = serde_json::to_vec(&json!({"Response": "0xde0b6b3a7640000"})).unwrap();
+        assert_eq!(
+            parse_eth_response::<U256>(&body).unwrap(),
+            U256::from(1_000_000_000_000_000_000u64)
+        );
+
+        // right envelope, wrong result type
+        let body = serde_json::to_vec(&json!({"Response": {"not": "a number"}})).unwrap();
+        assert!(matches!(
+            parse_eth_response::<U64>(&body),
+            Err(EthError::RpcMalformedResponse)
+        ));
+        assert!(matches!(
+            parse_eth_response::<U64>(b"garbage"),
+            Err(EthError::RpcMalformedResponse)
+        ));
+    }
+
+    #[test]
+    fn test_rpc_errors_are_distinguished() {
+        let body = serde_json::to_vec(&json!({"Err": {"RpcError": {
+            "code": -32000,
+            "message": "execution reverted",
+        }}}))
+        .unwrap();
+        let error = parse_eth_response::<Bytes>(&body).unwrap_err();
+        assert!(error.is_rpc_error());
+        let payload = error.rpc_error().unwrap();
+        assert_eq!(payload.code, -32000);
+        assert_eq!(payload.message, "execution reverted");
+
+        let body = serde_json::to_vec(&json!({"Err": "NoRpcForChain"})).unwrap();
+        let error = parse_eth_response::<Bytes>(&body).unwrap_err();
+        assert!(matches!(error, EthError::NoRpcForChain));
+        assert!(!error.is_rpc_error());
+        assert!(error.rpc_error().is_none());
+    }
+
+    fn log_json() -> serde_json::Value {
+        json!({
+            "address": "0x000000000044c6b8cb4d8f0f889a3e47664eaeda",
+            "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+            "data": "0x",
+            "blockHash": "0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber": "0x10",
+            "transactionHash": "0x0000000000000000000000000000000000000000000000000000000000000002",
+            "transactionIndex": "0x0",
+            "logIndex": "0x1",
+            "removed": false,
+        })
+    }
+
+    fn push(source: &str, body: serde_json::Value) -> Message {
+        Message::Request {
+            source: source.parse().unwrap(),
+            expects_response: None,
+            body: serde_json::to_vec(&body).unwrap(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    #[test]
+    fn test_parse_subscription() {
+        let event = parse_subscription_from(
+            &push(
+                "our.os@eth:distro:sys",
+                json!({"Ok": {"id": 7, "result": log_json()}}),
+            ),
+            "our.os",
+        )
+        .unwrap();
+        let SubEvent::Log { id, log } = &event else {
+            panic!("expected a log, got {event:?}");
+        };
+        assert_eq!(*id, 7);
+        assert_eq!(log.block_number, Some(16));
+        assert_eq!(log.log_index, Some(1));
+        assert_eq!(log.topics().len(), 1);
+
+        assert_eq!(
+            parse_subscription_from(
+                &push(
+                    "our.os@eth:distro:sys",
+                    json!({"Err": {"id": 7, "error": "subscription closed"}}),
+                ),
+                "our.os",
+            ),
+            Some(SubEvent::Error {
+                id: 7,
+                error: "subscription closed".to_string()
+            })
+        );
+
+        // responses, other nodes, other processes, and other bodies are not updates
+        let ok = json!({"Ok": {"id": 7, "result": log_json()}});
+        let response = Message::Response {
+            source: "our.os@eth:distro:sys".parse().unwrap(),
+            body: serde_json::to_vec(&json!("Ok")).unwrap(),
+            metadata: None,
+            context: None,
+            capabilities: vec![],
+        };
+        assert!(parse_subscription_from(&response, "our.os").is_none());
+        assert!(
+            parse_subscription_from(&push("them.os@eth:distro:sys", ok.clone()), "our.os")
+                .is_none()
+        );
+        assert!(parse_subscription_from(&push("our.os@eth:app:sys", ok), "our.os").is_none());
+        assert!(parse_subscription_from(
+            &push("our.os@eth:distro:sys", json!({"Request": {}})),
+            "our.os"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_subscription_manager_bookkeeping() {
+        let mut manager = SubscriptionManager::new(Provider::new(1, 5));
+        let transfers = Filter::new().address(Address::ZERO);
+        let id = manager
+            .subscribe_with(transfers.clone(), |_, _| Ok(()))
+            .unwrap();
+        let other = manager
+            .subscribe_with(Filter::new(), |_, _| Ok(()))
+            .unwrap();
+        assert_ne!(id, other);
+        assert_eq!(manager.len(), 2);
+        assert_eq!(manager.filter(id), Some(&transfers));
+
+        // a failed subscribe is not tracked
+        assert!(manager
+            .subscribe_with(Filter::new(), |_, _| Err(EthError::RpcTimeout))
+            .is_err());
+        assert_eq!(manager.len(), 2);
+
+        // every subscription is recreated under its ID and filter, even after a failure
+        let mut seen = vec![];
+        let result = manager.resubscribe_all_with(|id, filter| {
+            seen.push((id, filter.clone()));
+            if id == other {
+                Err(EthError::NoRpcForChain)
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.unwrap_err().to_string().contains(&other.to_string()));
+        seen.sort_by_key(|(id, _)| *id);
+        let mut expected = vec![(id, transfers), (other, Filter::new())];
+        expected.sort_by_key(|(id, _)| *id);
+        assert_eq!(seen, expected);
+        assert_eq!(manager.len(), 2);
+
+        // unsubscribing untracked IDs or failing to unsubscribe keeps the bookkeeping
+        assert!(manager
+            .unsubscribe_with(id ^ other ^ 1, |_| Ok(()))
+            .is_err());
+        assert!(manager
+            .unsubscribe_with(id, |_| Err(EthError::RpcTimeout))
+            .is_err());
+        assert!(manager.contains(id));
+        manager.unsubscribe_with(id, |_| Ok(())).unwrap();
+        assert!(!manager.contains(id));
+        assert_eq!(manager.ids().collect::<Vec<_>>(), vec![other]);
+    }
 }
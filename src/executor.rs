@@ -0,0 +1,302 @@
+use crate::{util::Uid, LazyLoadBlob, Message, SendError};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+/// What the context of a request sent with [`crate::Request::send_async()`]
+/// starts with, followed by its correlation id.
+pub const CONTEXT_PREFIX: &[u8] = b"__async:";
+
+/// What a [`ResponseFuture`] resolves to: the response and the blob it
+/// carried, or the request's [`SendError`].
+///
+/// The blob is taken when the response arrives, since by the time the future
+/// is polled other messages may have arrived, and [`crate::get_blob()`] only
+/// sees the last.
+pub type AsyncResponse = Result<(Message, Option<LazyLoadBlob>), SendError>;
+
+#[derive(Default)]
+struct Slot {
+    waker: Option<Waker>,
+    result: Option<AsyncResponse>,
+}
+
+thread_local! {
+    /// The requests whose futures are still alive, by correlation id.
+    static IN_FLIGHT: RefCell<HashMap<Uid, Slot>> = RefCell::new(HashMap::new());
+}
+
+/// The response to a request sent with [`crate::Request::send_async()`], ready
+/// once [`run()`] receives it. Dropping it forgets the request: its response is
+/// discarded when it arrives.
+#[must_use = "futures do nothing unless awaited"]
+pub struct ResponseFuture {
+    id: Uid,
+}
+
+impl ResponseFuture {
+    /// Expect the response to the request sent with context [`context()`] of id.
+    pub(crate) fn new(id: Uid) -> Self {
+        IN_FLIGHT.with_borrow_mut(|in_flight| in_flight.insert(id, Slot::default()));
+        ResponseFuture { id }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = AsyncResponse;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<AsyncResponse> {
+        IN_FLIGHT.with_borrow_mut(|in_flight| {
+            let slot = in_flight.entry(self.id).or_default();
+            match slot.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    slot.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        IN_FLIGHT.with_borrow_mut(|in_flight| in_flight.remove(&self.id));
+    }
+}
+
+/// The context that marks a request as id's.
+pub(crate) fn context(id: Uid) -> Vec<u8> {
+    let mut context = CONTEXT_PREFIX.to_vec();
+    context.extend_from_slice(id.to_string().as_bytes());
+    context
+}
+
+fn correlation_id(context: Option<&[u8]>) -> Option<Uid> {
+    let id = context?.strip_prefix(CONTEXT_PREFIX)?;
+    std::str::from_utf8(id).ok()?.parse().ok()
+}
+
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Drive future to completion on this thread, receiving messages while it
+/// waits and handing each response to the [`ResponseFuture`] it answers, so
+/// that any number of requests can be in flight at once, e.g. joined with
+/// `futures::join!`. Call it from the main loop, or a handler, around the
+/// async part of the work; it does not nest.
+///
+/// Messages that arrive meanwhile and answer none of them, including requests
+/// from other processes, are kept in the [`crate::queue`] for
+/// [`crate::await_message()`] to return next, in order, unless its
+/// [`crate::queue::Backpressure`] sheds them.
+///
+/// Panics if future waits while no request of its is in flight, as nothing
+/// could ever wake it.
+pub fn run<F: Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let flag = Arc::new(Flag(AtomicBool::new(true)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if flag.0.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            continue;
+        }
+        let waiting =
+            IN_FLIGHT.with_borrow(|in_flight| in_flight.values().any(|slot| slot.result.is_none()));
+        if !waiting {
+            panic!("executor::run: the future is waiting, but not on any request in flight");
+        }
+        deliver(crate::receive_message());
+    }
+}
+
+/// Hand received to the future it answers, if one is waiting for it, and
+/// otherwise queue it.
+fn deliver(received: Result<Message, SendError>) {
+    let context = match &received {
+        Ok(message @ Message::Response { .. }) => message.context(),
+        Ok(Message::Request { .. }) => None,
+        Err(error) => error.context(),
+    };
+    let Some(id) = correlation_id(context) else {
+        // the host forgets the blob once the next message is received
+        crate::queue::push(received, crate::get_blob());
+        return;
+    };
+    let result = received.map(|message| (message, crate::get_blob()));
+    let waker = IN_FLIGHT.with_borrow_mut(|in_flight| {
+        let slot = in_flight.get_mut(&id)?;
+        slot.result = Some(result);
+        Some(slot.waker.take())
+    });
+    match waker {
+        Some(waker) => waker.into_iter().for_each(Waker::wake),
+        None => crate::debug!("executor: discarding response {id} no future waits for"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+    use crate::test_utils::MessageBuilder;
+    use crate::{Request, SendErrorKind};
+
+    fn sent_contexts(host: &MockHost) -> Vec<Vec<u8>> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendRequest { context, .. } => context,
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn response(from: &str, body: &str, context: &[u8]) -> Message {
+        MessageBuilder::response()
+            .from(from)
+            .body(body)
+            .context(context.to_vec())
+            .build()
+    }
+
+    #[test]
+    fn test_concurrent_requests_resolve_out_of_order() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let first = Request::to(("our", "a", "app", "pub.os"))
+            .body("first")
+            .send_async(5)
+            .unwrap();
+        let second = Request::to(("our", "b", "app", "pub.os"))
+            .body("second")
+            .send_async(5)
+            .unwrap();
+        // both are sent before either is answered
+        let contexts = sent_contexts(&host);
+        assert_eq!(contexts.len(), 2);
+        let blob = LazyLoadBlob::new(None::<String>, b"second blob".to_vec());
+        host.push_message(
+            response("our@b:app:pub.os", "second done", &contexts[1]),
+            Some(blob.clone()),
+        );
+        let request = MessageBuilder::request()
+            .from("other.os@c:app:pub.os")
+            .body("unrelated")
+            .build();
+        host.push_message(request, None);
+        host.push_message(
+            response("our@a:app:pub.os", "first done", &contexts[0]),
+            None,
+        );
+
+        let order = RefCell::new(vec![]);
+        let (first, second) = run(async {
+            futures::join!(
+                async {
+                    let result = first.await;
+                    order.borrow_mut().push("first");
+                    result
+                },
+                async {
+                    let result = second.await;
+                    order.borrow_mut().push("second");
+                    result
+                },
+            )
+        });
+        assert_eq!(order.into_inner(), ["second", "first"]);
+        let (message, no_blob) = first.unwrap();
+        assert_eq!((message.body(), no_blob), (&b"first done"[..], None));
+        let (message, second_blob) = second.unwrap();
+        assert_eq!(
+            (message.body(), second_blob),
+            (&b"second done"[..], Some(blob))
+        );
+
+        // the request that arrived meanwhile is handed out next
+        let parked = crate::await_message().unwrap();
+        assert_eq!(parked.body(), b"unrelated");
+    }
+
+    #[test]
+    fn test_parked_request_keeps_its_blob() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let pending = Request::to(("our", "a", "app", "pub.os"))
+            .body("work")
+            .send_async(5)
+            .unwrap();
+        let contexts = sent_contexts(&host);
+        let blob = LazyLoadBlob::new(Some("text/plain"), b"parked blob".to_vec());
+        let request = MessageBuilder::request()
+            .from("other.os@c:app:pub.os")
+            .body("upload")
+            .build();
+        host.push_message(request, Some(blob.clone()));
+        host.push_message(response("our@a:app:pub.os", "done", &contexts[0]), None);
+
+        let (_, no_blob) = run(pending).unwrap();
+        assert_eq!(no_blob, None);
+
+        // the host has moved on to the response, but the request gets its own
+        let parked = crate::await_message().unwrap();
+        assert_eq!(parked.body(), b"upload");
+        assert!(crate::has_blob());
+        assert_eq!(crate::get_blob(), Some(blob));
+        assert_eq!(crate::get_blob_len(), Some(11));
+    }
+
+    #[test]
+    fn test_send_errors_and_dropped_futures() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let dropped = Request::to(("our", "a", "app", "pub.os"))
+            .body("never mind")
+            .send_async(5)
+            .unwrap();
+        let offline = Request::to(("gone.os", "b", "app", "pub.os"))
+            .body("hello?")
+            .send_async(5)
+            .unwrap();
+        let contexts = sent_contexts(&host);
+        drop(dropped);
+
+        host.push_message(response("our@a:app:pub.os", "late", &contexts[0]), None);
+        let bounced = MessageBuilder::request()
+            .from("our@test:app:pub.os")
+            .body("hello?")
+            .build();
+        host.push_send_error(SendError {
+            kind: SendErrorKind::Offline,
+            target: "gone.os@b:app:pub.os".parse().unwrap(),
+            message: bounced,
+            lazy_load_blob: None,
+            context: Some(contexts[1].clone()),
+        });
+        let error = run(offline).unwrap_err();
+        assert!(error.kind.is_offline());
+        // the late response was neither delivered nor queued
+        assert!(crate::queue::pop().is_none());
+        assert!(IN_FLIGHT.with_borrow(HashMap::is_empty));
+    }
+
+    #[test]
+    #[should_panic(expected = "not on any request in flight")]
+    fn test_waiting_on_nothing_panics() {
+        run(std::future::pending::<()>());
+    }
+}
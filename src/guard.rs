@@ -0,0 +1,463 @@
+use crate::{
+    trace::USER_METADATA_KEY, types::message::metadata_object, Address, Capability, Message,
+    Response,
+};
+use serde_json::Value;
+use std::borrow::Cow;
+use thiserror::Error;
+
+/// The metadata key a relay puts the address of the process it forwards a
+/// request for under. See [`crate::Request::via()`].
+pub const VIA_KEY: &str = "__via";
+
+/// Why [`require()`] and friends turned a message away.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum Denied {
+    #[error("permission denied: {sender} did not attach capability {capability}")]
+    MissingCapability {
+        sender: Address,
+        capability: Box<Capability>,
+    },
+    #[error("permission denied: {sender} is not on this node")]
+    NotLocal { sender: Address },
+    /// A response was held back by [`crate::Response::only_if()`]: sender is who
+    /// it would have gone to, if it was answering a request at all.
+    #[error("permission denied: response withheld from {}", withheld_from(.sender))]
+    Withheld { sender: Option<Address> },
+}
+
+fn withheld_from(sender: &Option<Address>) -> String {
+    sender
+        .as_ref()
+        .map_or_else(|| "unknown sender".to_string(), ToString::to_string)
+}
+
+/// Check that message carries capability, compared as [`Capability`]s are: by
+/// issuer, and by params as JSON. If it doesn't, answer it with a permission
+/// denied error Response if it expects one, as [`crate::handle!`] answers
+/// errors: `{"Err": "..."}`.
+pub fn require(message: &Message, capability: &Capability) -> Result<(), Denied> {
+    if message.capabilities().contains(capability) {
+        return Ok(());
+    }
+    deny(
+        message,
+        Denied::MissingCapability {
+            sender: message.source().clone(),
+            capability: Box::new(capability.clone()),
+        },
+    )
+}
+
+/// [`require()`] the capability to message issuer, e.g. to only serve processes
+/// that were granted it.
+pub fn require_messaging(message: &Message, issuer: &Address) -> Result<(), Denied> {
+    require(message, &Capability::new(issuer, "\"messaging\""))
+}
+
+/// Check that message comes from a process on our node, answering it as
+/// [`require()`] does if not.
+pub fn require_local(message: &Message, our: &Address) -> Result<(), Denied> {
+    if message.source().node == our.node {
+        return Ok(());
+    }
+    deny(
+        message,
+        Denied::NotLocal {
+            sender: message.source().clone(),
+        },
+    )
+}
+
+fn deny(message: &Message, denied: Denied) -> Result<(), Denied> {
+    crate::debug!("{denied}");
+    if let Message::Request {
+        expects_response: Some(_),
+        ..
+    } = message
+    {
+        let _ = Response::new().body(denied_body(&denied)).send();
+    }
+    Err(denied)
+}
+
+/// The body of the response to a denied request, as [`crate::handle!`] answers
+/// errors: `{"Err": "..."}`.
+pub(crate) fn denied_body(denied: &Denied) -> Vec<u8> {
+    serde_json::to_vec(&Err::<(), String>(denied.to_string())).unwrap()
+}
+
+/// A predicate for [`crate::Response::only_if()`] letting through processes on
+/// our node.
+pub fn only_local(our: &Address) -> impl Fn(&Address) -> bool {
+    let node = our.node().to_string();
+    move |source| source.node() == node
+}
+
+/// A predicate for [`crate::Response::only_if()`] letting through processes of
+/// our package on our node.
+pub fn only_package(our: &Address) -> impl Fn(&Address) -> bool {
+    let our = our.clone();
+    move |source| source.node() == our.node() && source.package_id() == our.package_id()
+}
+
+/// metadata with origin set as its [`VIA_KEY`]. Metadata that is a JSON object
+/// gets the entry added, replacing any there was; anything else is kept under
+/// [`USER_METADATA_KEY`], as [`crate::trace::merge_trace()`] does, so that trace
+/// context can be merged in alongside it when the request is sent.
+pub fn with_via(metadata: Option<&str>, origin: &Address) -> String {
+    let mut object = match metadata_object(metadata) {
+        Ok(object) if !object.contains_key(USER_METADATA_KEY) => object,
+        _ => {
+            let mut object = serde_json::Map::new();
+            object.insert(
+                USER_METADATA_KEY.to_string(),
+                Value::String(metadata.unwrap_or_default().to_string()),
+            );
+            object
+        }
+    };
+    object.insert(VIA_KEY.to_string(), Value::String(origin.to_string()));
+    Value::Object(object).to_string()
+}
+
+/// The address under [`VIA_KEY`] in metadata, if it has one. Fails if it has
+/// one that is not an address.
+///
+/// Anyone can put an address there: see [`effective_source()`] for one that can
+/// be trusted.
+pub fn forwarded_from(metadata: Option<&str>) -> anyhow::Result<Option<Address>> {
+    let Ok(mut object) = metadata_object(metadata) else {
+        return Ok(None);
+    };
+    match object.remove(VIA_KEY) {
+        None => Ok(None),
+        Some(Value::String(origin)) => origin
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("metadata field {VIA_KEY}: {e}")),
+        Some(other) => Err(anyhow::anyhow!(
+            "metadata field {VIA_KEY} is not an address: {other}"
+        )),
+    }
+}
+
+/// Who message is from, for authorization: the address it was forwarded on
+/// behalf of if its source is one of trusted_relays, and its source otherwise.
+///
+/// A forwarded-from address claimed by any other process is ignored, as is one
+/// that doesn't parse, since only a trusted relay can vouch for it.
+pub fn effective_source<'a>(message: &'a Message, trusted_relays: &[Address]) -> Cow<'a, Address> {
+    let source = message.source();
+    if !trusted_relays.contains(source) {
+        return Cow::Borrowed(source);
+    }
+    match forwarded_from(message.metadata()) {
+        Ok(Some(origin)) => Cow::Owned(origin),
+        Ok(None) => Cow::Borrowed(source),
+        Err(e) => {
+            crate::debug!("ignoring forwarded-from of message from relay {source}: {e}");
+            Cow::Borrowed(source)
+        }
+    }
+}
+
+/// Return early with a [`guard::Denied`](crate::guard::Denied), converted with
+/// `Into`, unless the message carries the capability. See [`guard::require()`](crate::guard::require).
+///
+/// ```no_run
+/// use hyperware_process_lib::{require_capability, Capability, Message};
+///
+/// fn handle_admin(message: &Message, admin: &Capability) -> anyhow::Result<()> {
+///     require_capability!(message, admin);
+///     // only reached if the sender attached the admin capability
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! require_capability {
+    ($message:expr, $capability:expr) => {
+        if let Err(denied) = $crate::guard::require($message, &$capability) {
+            return Err(denied.into());
+        }
+    };
+}
+
+/// Return early with a [`guard::Denied`](crate::guard::Denied), converted with
+/// `Into`, unless the message is from our node. See
+/// [`guard::require_local()`](crate::guard::require_local).
+#[macro_export]
+macro_rules! require_local {
+    ($message:expr, $our:expr) => {
+        if let Err(denied) = $crate::guard::require_local($message, &$our) {
+            return Err(denied.into());
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+
+    const OUR: &str = "our.os@server:app:pub.os";
+    const ADMIN: &str = "our.os@admin:app:pub.os";
+
+    fn request(source: &str, capabilities: Vec<Capability>, expects_response: bool) -> Message {
+        Message::Request {
+            source: source.parse().unwrap(),
+            expects_response: expects_response.then_some(5),
+            body: b"delete everything".to_vec(),
+            metadata: None,
+            capabilities,
+        }
+    }
+
+    fn admin() -> Capability {
+        Capability::new(ADMIN.parse::<Address>().unwrap(), r#"{"admin": true}"#)
+    }
+
+    fn handle(message: &Message, log: &mut Vec<&'static str>) -> anyhow::Result<()> {
+        crate::require_local!(message, OUR.parse::<Address>().unwrap());
+        crate::require_capability!(message, admin());
+        log.push("handled");
+        Ok(())
+    }
+
+    fn response_bodies(host: &MockHost) -> Vec<String> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendResponse { response, .. } => {
+                    Some(String::from_utf8(response.body).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_authorized_pass_through() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut log = vec![];
+        // params compare as JSON, so whitespace doesn't matter
+        let capability = Capability::new(ADMIN.parse::<Address>().unwrap(), r#"{ "admin":true }"#);
+        let message = request("our.os@client:app:pub.os", vec![capability], true);
+        handle(&message, &mut log).unwrap();
+        assert_eq!(log, ["handled"]);
+        assert!(host.take_calls().is_empty());
+    }
+
+    #[test]
+    fn test_denial_sent_once() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut log = vec![];
+
+        let missing = request("our.os@client:app:pub.os", vec![], true);
+        let error = handle(&missing, &mut log).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Denied>(),
+            Some(Denied::MissingCapability { .. })
+        ));
+        let bodies = response_bodies(&host);
+        assert_eq!(bodies.len(), 1);
+        let body: Result<(), String> = serde_json::from_str(&bodies[0]).unwrap();
+        assert!(body.unwrap_err().starts_with("permission denied"));
+
+        let remote = request("them.os@client:app:pub.os", vec![admin()], true);
+        let error = handle(&remote, &mut log).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "permission denied: them.os@client:app:pub.os is not on this node"
+        );
+        assert_eq!(response_bodies(&host).len(), 1);
+
+        // nothing is sent when no response is expected
+        let fire_and_forget = request("our.os@client:app:pub.os", vec![], false);
+        assert!(handle(&fire_and_forget, &mut log).is_err());
+        assert!(response_bodies(&host).is_empty());
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn test_require_messaging() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let issuer: Address = OUR.parse().unwrap();
+        let messaging = Capability::new(&issuer, "\"messaging\"");
+        let message = request("our.os@client:app:pub.os", vec![messaging], false);
+        assert_eq!(require_messaging(&message, &issuer), Ok(()));
+        let other: Address = ADMIN.parse().unwrap();
+        assert!(require_messaging(&message, &other).is_err());
+    }
+
+    const RELAY: &str = "our.os@relay:app:pub.os";
+    const CLIENT: &str = "client.os@app:app:pub.os";
+
+    fn forwarded(source: &str, metadata: Option<String>) -> Message {
+        Message::Request {
+            source: source.parse().unwrap(),
+            expects_response: None,
+            body: vec![],
+            metadata,
+            capabilities: vec![],
+        }
+    }
+
+    fn via(origin: &str) -> Option<String> {
+        crate::Request::new()
+            .via(&origin.parse().unwrap())
+            .metadata_to_send()
+    }
+
+    #[test]
+    fn test_effective_source_trusts_only_relays() {
+        let relays: Vec<Address> = vec![RELAY.parse().unwrap()];
+        let from_relay = forwarded(RELAY, via(CLIENT));
+        assert_eq!(
+            from_relay.forwarded_from().unwrap(),
+            Some(CLIENT.parse().unwrap())
+        );
+        assert_eq!(from_relay.effective_source(&relays).to_string(), CLIENT);
+        // the same claim from anyone else is ignored
+        let impostor = forwarded("mallory.os@relay:app:pub.os", via(CLIENT));
+        assert_eq!(
+            impostor.effective_source(&relays).to_string(),
+            "mallory.os@relay:app:pub.os"
+        );
+        assert_eq!(
+            from_relay.effective_source(&[]).to_string(),
+            RELAY,
+            "no relays are trusted"
+        );
+        // a relay sending on its own behalf is itself
+        let direct = forwarded(RELAY, None);
+        assert_eq!(direct.forwarded_from().unwrap(), None);
+        assert_eq!(direct.effective_source(&relays).to_string(), RELAY);
+    }
+
+    #[test]
+    fn test_malformed_via_falls_back_to_source() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let relays: Vec<Address> = vec![RELAY.parse().unwrap()];
+        for metadata in [r#"{"__via": "not an address"}"#, r#"{"__via": 5}"#] {
+            let message = forwarded(RELAY, Some(metadata.to_string()));
+            assert!(message.forwarded_from().is_err(), "{metadata}");
+            assert_eq!(message.effective_source(&relays).to_string(), RELAY);
+        }
+        // metadata that is not ours to read isn't an error
+        let text = forwarded(RELAY, Some("plain text".to_string()));
+        assert_eq!(text.forwarded_from().unwrap(), None);
+    }
+
+    #[test]
+    fn test_via_coexists_with_other_metadata() {
+        let client: Address = CLIENT.parse().unwrap();
+        let merged = crate::Request::new()
+            .metadata(r#"{"app": 1}"#)
+            .via(&"old.os@app:app:pub.os".parse().unwrap())
+            .via(&client)
+            .metadata_to_send();
+        let message = forwarded(RELAY, merged);
+        assert_eq!(message.metadata_field_as::<u8>("app").unwrap(), Some(1));
+        assert_eq!(message.forwarded_from().unwrap(), Some(client.clone()));
+
+        // non-object metadata is kept aside, and trace context merges in after
+        let wrapped = with_via(Some("plain text"), &client);
+        let context = crate::trace::TraceContext::new_root();
+        let traced = crate::trace::merge_trace(Some(&wrapped), &context);
+        let (extracted, user) = crate::trace::extract_trace(Some(&traced));
+        assert_eq!(extracted, Some(context));
+        let message = forwarded(RELAY, user);
+        assert_eq!(message.forwarded_from().unwrap(), Some(client));
+        assert_eq!(
+            message
+                .metadata_field_as::<String>(USER_METADATA_KEY)
+                .unwrap(),
+            Some("plain text".to_string())
+        );
+    }
+
+    fn receive(host: &MockHost, message: Message) {
+        host.push_message(message, None);
+        crate::await_message().unwrap();
+    }
+
+    fn answer(host: &MockHost, predicate: impl FnOnce(&Address) -> bool) -> Call {
+        crate::Response::new()
+            .metadata("secret metadata")
+            .only_if(predicate)
+            .body("secret")
+            .blob_bytes("secret blob")
+            .send()
+            .unwrap();
+        host.take_calls().pop().unwrap()
+    }
+
+    #[test]
+    fn test_only_if_withholds_response() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let our: Address = OUR.parse().unwrap();
+
+        receive(&host, request("our.os@debug:other:pub.os", vec![], true));
+        let Call::SendResponse { response, blob } = answer(&host, only_local(&our)) else {
+            panic!("no response sent");
+        };
+        assert_eq!(response.body, b"secret");
+        assert!(blob.is_some());
+        let Call::SendResponse { response, blob } = answer(&host, only_package(&our)) else {
+            panic!("no response sent");
+        };
+        let body: Result<(), String> = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(
+            body.unwrap_err(),
+            "permission denied: response withheld from our.os@debug:other:pub.os"
+        );
+        assert_eq!((response.metadata, blob), (None, None));
+
+        receive(&host, request("them.os@server:app:pub.os", vec![], true));
+        let Call::SendResponse { response, .. } = answer(&host, only_local(&our)) else {
+            panic!("no response sent");
+        };
+        assert_ne!(response.body, b"secret");
+        let Call::SendResponse { response, .. } = answer(&host, |_| true) else {
+            panic!("no response sent");
+        };
+        assert_eq!(response.body, b"secret");
+    }
+
+    #[test]
+    fn test_current_source_resets_between_messages() {
+        let host = MockHost::new();
+        let installed = host.install();
+        assert_eq!(crate::current_source(), None);
+
+        receive(&host, request(CLIENT, vec![], true));
+        assert_eq!(crate::current_source(), Some(CLIENT.parse().unwrap()));
+
+        // a response is not answered, so there is no one to let through
+        let response = crate::test_utils::MessageBuilder::response()
+            .from(CLIENT)
+            .body("ok")
+            .build();
+        receive(&host, response);
+        assert_eq!(crate::current_source(), None);
+        let Call::SendResponse { response, .. } = answer(&host, |_| true) else {
+            panic!("no response sent");
+        };
+        let body: Result<(), String> = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(
+            body.unwrap_err(),
+            "permission denied: response withheld from unknown sender"
+        );
+
+        receive(&host, request(RELAY, vec![], true));
+        assert_eq!(crate::current_source(), Some(RELAY.parse().unwrap()));
+        drop(installed);
+        assert_eq!(crate::current_source(), None);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::timer::wall_clock_ms;
+use crate::{Address, Message, Request, Response};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::{Cell, RefCell};
+use std::sync::OnceLock;
+
+/// The body of a health check, as JSON: `"Ping"`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HealthRequest {
+    Ping,
+}
+
+/// The answer to a [`HealthRequest`], as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum HealthResponse {
+    Pong {
+        /// Since the process called [`crate::set_our()`] in `init`.
+        uptime_ms: u64,
+        /// Work the process has queued, as last reported with [`set_queue_depth()`].
+        queue_depth: u64,
+        /// The size of the state last saved with [`crate::set_state()`], or 0 if
+        /// there is none.
+        state_bytes: u64,
+        /// As set with [`set_version()`].
+        version: Option<String>,
+        /// Whatever the [`on_ping()`] callback adds.
+        #[serde(default, skip_serializing_if = "Map::is_empty")]
+        custom: Map<String, Value>,
+    },
+}
+
+static STARTED_MS: OnceLock<u64> = OnceLock::new();
+
+type Custom = Box<dyn Fn() -> Map<String, Value>>;
+
+thread_local! {
+    static QUEUE_DEPTH: Cell<u64> = const { Cell::new(0) };
+    static VERSION: RefCell<Option<String>> = const { RefCell::new(None) };
+    static CUSTOM: RefCell<Option<Custom>> = const { RefCell::new(None) };
+}
+
+/// Record now as when the process started. Only the first call has an effect.
+pub(crate) fn mark_started() {
+    STARTED_MS.get_or_init(wall_clock_ms);
+}
+
+/// Report how much work the process has queued, e.g. the length of its
+/// [`crate::outbox::Outbox::pending()`], for answers to pings from now on.
+pub fn set_queue_depth(depth: u64) {
+    QUEUE_DEPTH.set(depth);
+}
+
+/// Report the version of the process, typically `env!("CARGO_PKG_VERSION")`.
+pub fn set_version(version: &str) {
+    VERSION.set(Some(version.to_string()));
+}
+
+/// Have custom called for every ping, adding the fields it returns to the
+/// answer's `custom`. Replaces any callback set before.
+pub fn on_ping<F>(custom: F)
+where
+    F: Fn() -> Map<String, Value> + 'static,
+{
+    CUSTOM.set(Some(Box::new(custom)));
+}
+
+/// Answer message if it is a [`HealthRequest::Ping`], returning `Some(())` if it
+/// was one, so that a handler can start with
+///
+/// ```no_run
+/// # fn handle(message: &hyperware_process_lib::Message) -> anyhow::Result<()> {
+/// if hyperware_process_lib::health::responder(message).is_some() {
+///     return Ok(());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A failure to answer is logged rather than returned.
+pub fn responder(message: &Message) -> Option<()> {
+    let Message::Request {
+        expects_response,
+        body,
+        ..
+    } = message
+    else {
+        return None;
+    };
+    let HealthRequest::Ping = serde_json::from_slice(body).ok()?;
+    if expects_response.is_some() {
+        let answer = serde_json::to_vec(&pong(wall_clock_ms()))
+            .map_err(anyhow::Error::from)
+            .and_then(|body| Ok(Response::new().body(body).send()?));
+        if let Err(e) = answer {
+            crate::log_error_chain(&e.context("failed to answer ping"));
+        }
+    }
+    Some(())
+}
+
+/// Ping target, waiting up to timeout seconds for its answer.
+pub fn check(target: &Address, timeout: u64) -> anyhow::Result<HealthResponse> {
+    let response = Request::to(target)
+        .body(serde_json::to_vec(&HealthRequest::Ping)?)
+        .send_and_await_response(timeout)??;
+    serde_json::from_slice(response.body())
+        .with_context(|| format!("{target} answered ping with something else"))
+}
+
+fn pong(now_ms: u64) -> HealthResponse {
+    HealthResponse::Pong {
+        uptime_ms: STARTED_MS
+            .get()
+            .map_or(0, |started| now_ms.saturating_sub(*started)),
+        queue_depth: QUEUE_DEPTH.get(),
+        state_bytes: crate::get_state().map_or(0, |state| state.len() as u64),
+        version: VERSION.with_borrow(Clone::clone),
+        custom: CUSTOM.with_borrow(|custom| custom.as_ref().map(|f| f()).unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+
+    fn request(body: &[u8], expects_response: Option<u64>) -> Message {
+        Message::Request {
+            source: "monitor.os@monitor:monitor:sys".parse().unwrap(),
+            expects_response,
+            body: body.to_vec(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    #[test]
+    fn test_protocol_shapes() {
+        assert_eq!(serde_json::to_value(HealthRequest::Ping).unwrap(), "Ping");
+        let pong = HealthResponse::Pong {
+            uptime_ms: 1_500,
+            queue_depth: 2,
+            state_bytes: 64,
+            version: Some("0.3.1".to_string()),
+            custom: Map::new(),
+        };
+        let json = serde_json::json!({"Pong": {
+            "uptime_ms": 1_500,
+            "queue_depth": 2,
+            "state_bytes": 64,
+            "version": "0.3.1",
+        }});
+        assert_eq!(serde_json::to_value(&pong).unwrap(), json);
+        assert_eq!(
+            serde_json::from_value::<HealthResponse>(json).unwrap(),
+            pong
+        );
+    }
+
+    #[test]
+    fn test_responder_short_circuits_pings() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        crate::set_state(b"four");
+        set_queue_depth(3);
+        set_version("1.2.3");
+        on_ping(|| {
+            let mut custom = Map::new();
+            custom.insert("peers".to_string(), 5.into());
+            custom
+        });
+        host.take_calls();
+
+        // other messages are left to the handler
+        assert_eq!(responder(&request(br#"{"Get": 1}"#, Some(5))), None);
+        assert_eq!(responder(&request(b"not json", Some(5))), None);
+        assert!(host.take_calls().is_empty());
+
+        assert_eq!(responder(&request(br#""Ping""#, None)), Some(()));
+        assert!(host.take_calls().is_empty());
+        assert_eq!(responder(&request(br#""Ping""#, Some(5))), Some(()));
+        let calls = host.take_calls();
+        let [Call::SendResponse { response, .. }] = &calls[..] else {
+            panic!("got {calls:?}");
+        };
+        let HealthResponse::Pong {
+            queue_depth,
+            state_bytes,
+            version,
+            custom,
+            ..
+        } = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!((queue_depth, state_bytes), (3, 4));
+        assert_eq!(version.as_deref(), Some("1.2.3"));
+        assert_eq!(custom["peers"], 5);
+    }
+
+    #[test]
+    fn test_uptime() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        mark_started();
+        let started = *STARTED_MS.get().unwrap();
+        let HealthResponse::Pong { uptime_ms, .. } = pong(started + 250);
+        assert_eq!(uptime_ms, 250);
+        // an earlier clock reading doesn't underflow
+        let HealthResponse::Pong { uptime_ms, .. } = pong(started.saturating_sub(1));
+        assert_eq!(uptime_ms, 0);
+    }
+}
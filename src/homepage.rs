@@ -1,4 +1,4 @@
-use crate::Request;
+use crate::{BuildError, Request};
 
 /// Add a new icon and/or widget to the Hyperware homepage. Note that the process calling this
 /// function must have the `homepage:homepage:sys` messaging [`crate::Capability`].
@@ -12,7 +12,12 @@ use crate::Request;
 /// will be `my:process:pkg/mypath`.
 ///
 /// A widget should be HTML: it will be displayed in an iframe.
-pub fn add_to_homepage(label: &str, icon: Option<&str>, path: Option<&str>, widget: Option<&str>) {
+pub fn add_to_homepage(
+    label: &str,
+    icon: Option<&str>,
+    path: Option<&str>,
+    widget: Option<&str>,
+) -> Result<(), BuildError> {
     Request::to(("our", "homepage", "homepage", "sys"))
         .body(
             serde_json::json!({
@@ -26,16 +31,14 @@ pub fn add_to_homepage(label: &str, icon: Option<&str>, path: Option<&str>, widg
             .to_string(),
         )
         .send()
-        .unwrap();
 }
 
 /// Remove the caller process from the Hyperware homepage. Note that the process calling this function
 /// must have the `homepage:homepage:sys` messaging [`crate::Capability`].
 ///
 /// This usually isn't necessary as processes are not persisted on homepage between boots.
-pub fn remove_from_homepage() {
+pub fn remove_from_homepage() -> Result<(), BuildError> {
     Request::to(("our", "homepage", "homepage", "sys"))
         .body("\"Remove\"")
         .send()
-        .unwrap();
 }
@@ -0,0 +1,219 @@
+use crate::{types::message::BuildError, Address, Message};
+use std::cell::RefCell;
+
+/// Called by [`crate::Request::send()`] and
+/// [`crate::Request::send_and_await_response()`] before the request goes out.
+/// An error aborts the send with [`BuildError::Hook`].
+pub type SendHook = fn(&mut RequestSnapshot) -> anyhow::Result<()>;
+
+/// Called with the source of every message received by
+/// [`crate::await_message()`], and of responses awaited by
+/// [`crate::Request::send_and_await_response()`].
+pub type ReceiveHook = fn(&Address, &Message);
+
+/// What a [`SendHook`] sees of a request about to be sent.
+pub struct RequestSnapshot<'a> {
+    target: &'a Address,
+    body_len: usize,
+    metadata: &'a mut Option<String>,
+}
+
+impl RequestSnapshot<'_> {
+    pub fn target(&self) -> &Address {
+        self.target
+    }
+
+    pub fn body_len(&self) -> usize {
+        self.body_len
+    }
+
+    pub fn metadata(&self) -> Option<&str> {
+        self.metadata.as_deref()
+    }
+
+    /// The metadata the request will be sent with, for hooks to change. Later
+    /// hooks see the change.
+    pub fn metadata_mut(&mut self) -> &mut Option<String> {
+        self.metadata
+    }
+}
+
+thread_local! {
+    static SEND_HOOKS: RefCell<Vec<SendHook>> = const { RefCell::new(vec![]) };
+    static RECEIVE_HOOKS: RefCell<Vec<ReceiveHook>> = const { RefCell::new(vec![]) };
+}
+
+/// Run hook before every request is sent, after the hooks registered before it.
+pub fn on_send(hook: SendHook) {
+    SEND_HOOKS.with_borrow_mut(|hooks| hooks.push(hook));
+}
+
+/// Run hook on every message received, after the hooks registered before it.
+pub fn on_receive(hook: ReceiveHook) {
+    RECEIVE_HOOKS.with_borrow_mut(|hooks| hooks.push(hook));
+}
+
+/// Unregister all hooks.
+pub fn clear() {
+    SEND_HOOKS.with_borrow_mut(Vec::clear);
+    RECEIVE_HOOKS.with_borrow_mut(Vec::clear);
+}
+
+/// Run the send hooks on a request, stopping at the first to fail.
+pub(crate) fn before_send(
+    target: &Address,
+    body_len: usize,
+    metadata: &mut Option<String>,
+) -> Result<(), BuildError> {
+    // copied out so that hooks can register hooks
+    let Some(hooks) = SEND_HOOKS.with_borrow(|hooks| (!hooks.is_empty()).then(|| hooks.clone()))
+    else {
+        return Ok(());
+    };
+    let mut snapshot = RequestSnapshot {
+        target,
+        body_len,
+        metadata,
+    };
+    for hook in hooks {
+        hook(&mut snapshot).map_err(|e| BuildError::Hook(format!("{e:#}")))?;
+    }
+    Ok(())
+}
+
+/// Run the receive hooks on a message.
+pub(crate) fn after_receive(message: &Message) {
+    let Some(hooks) = RECEIVE_HOOKS.with_borrow(|hooks| (!hooks.is_empty()).then(|| hooks.clone()))
+    else {
+        return;
+    };
+    for hook in hooks {
+        hook(message.source(), message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::Request;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static LOG: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    }
+
+    fn log(entry: String) {
+        LOG.with_borrow_mut(|log| log.push(entry));
+    }
+
+    fn tag_first(request: &mut RequestSnapshot) -> anyhow::Result<()> {
+        log(format!("first saw {:?}", request.metadata()));
+        *request.metadata_mut() = Some("tagged".to_string());
+        Ok(())
+    }
+
+    fn log_second(request: &mut RequestSnapshot) -> anyhow::Result<()> {
+        log(format!(
+            "second saw {:?}, {} bytes to {}",
+            request.metadata(),
+            request.body_len(),
+            request.target()
+        ));
+        Ok(())
+    }
+
+    fn only_kv(request: &mut RequestSnapshot) -> anyhow::Result<()> {
+        if request.target().process() != "kv" {
+            anyhow::bail!("{} is not whitelisted", request.target());
+        }
+        Ok(())
+    }
+
+    fn log_receive(source: &Address, message: &Message) {
+        log(format!("received {:?} from {source}", message.body()));
+    }
+
+    fn sent_metadata(host: &MockHost) -> Vec<Option<String>> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendRequest { request, .. } | Call::SendAndAwaitResponse { request, .. } => {
+                    Some(request.metadata)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    const TARGET: &str = "tester.os@kv:distro:sys";
+
+    #[test]
+    fn test_order_and_mutation() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        on_send(tag_first);
+        on_send(log_second);
+        on_receive(log_receive);
+
+        Request::to(TARGET.parse::<Address>().unwrap())
+            .body("four")
+            .send()
+            .unwrap();
+        host.reply(Reply::body("pong"));
+        let response = Request::to(TARGET.parse::<Address>().unwrap())
+            .body("ping")
+            .metadata("mine")
+            .send_and_await_response(5)
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.body(), b"pong");
+
+        assert_eq!(
+            LOG.take(),
+            [
+                "first saw None".to_string(),
+                format!("second saw Some(\"tagged\"), 4 bytes to {TARGET}"),
+                "first saw Some(\"mine\")".to_string(),
+                format!("second saw Some(\"tagged\"), 4 bytes to {TARGET}"),
+                format!("received [112, 111, 110, 103] from {TARGET}"),
+            ]
+        );
+        assert_eq!(
+            sent_metadata(&host),
+            [Some("tagged".to_string()), Some("tagged".to_string())]
+        );
+        clear();
+    }
+
+    #[test]
+    fn test_abort() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        on_send(only_kv);
+        on_send(log_second);
+
+        let error = Request::to(("tester.os", "vfs", "distro", "sys"))
+            .body("read")
+            .send()
+            .unwrap_err();
+        assert!(
+            matches!(&error, BuildError::Hook(message) if message.contains("not whitelisted")),
+            "{error:?}"
+        );
+        // later hooks don't run, and nothing is sent
+        assert!(LOG.take().is_empty());
+        assert!(sent_metadata(&host).is_empty());
+
+        Request::to(TARGET.parse::<Address>().unwrap())
+            .body("read")
+            .send()
+            .unwrap();
+        assert_eq!(sent_metadata(&host), [None]);
+        clear();
+        Request::to(("tester.os", "vfs", "distro", "sys"))
+            .body("read")
+            .send()
+            .unwrap();
+    }
+}
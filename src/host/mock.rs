@@ -0,0 +1,539 @@
+use super::{set_host, Host, HostGuard, Received};
+use crate::hyperware::process::standard as wit;
+use crate::{Address, Capability, LazyLoadBlob, Message, SendError, SendErrorKind};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// A call a [`MockHost`] recorded. Reads, e.g. of the blob or state, are not
+/// recorded.
+#[derive(Clone, Debug)]
+pub enum Call {
+    SendRequest {
+        target: Address,
+        request: wit::Request,
+        context: Option<Vec<u8>>,
+        blob: Option<LazyLoadBlob>,
+    },
+    SendAndAwaitResponse {
+        target: Address,
+        request: wit::Request,
+        blob: Option<LazyLoadBlob>,
+    },
+    SendResponse {
+        response: wit::Response,
+        blob: Option<LazyLoadBlob>,
+    },
+    SetState(Vec<u8>),
+    ClearState,
+    SaveCapabilities(Vec<Capability>),
+    DropCapabilities(Vec<Capability>),
+    Print {
+        verbosity: u8,
+        message: String,
+    },
+}
+
+/// A scripted answer to [`Host::send_and_await_response()`].
+#[derive(Clone, Debug)]
+pub enum Reply {
+    /// A response from the target, with a body and optionally a blob and metadata.
+    Response {
+        body: Vec<u8>,
+        blob: Option<LazyLoadBlob>,
+        metadata: Option<String>,
+    },
+    /// The request bounces back with this kind of error.
+    Error(SendErrorKind),
+}
+
+impl Reply {
+    pub fn body<T: Into<Vec<u8>>>(body: T) -> Self {
+        Reply::Response {
+            body: body.into(),
+            blob: None,
+            metadata: None,
+        }
+    }
+
+    /// Answer with body serialized as JSON. Panics if it doesn't serialize.
+    pub fn json<T: serde::Serialize>(body: &T) -> Self {
+        Reply::body(serde_json::to_vec(body).expect("reply serializes to JSON"))
+    }
+
+    pub fn with_blob(body: Vec<u8>, blob: LazyLoadBlob) -> Self {
+        Reply::Response {
+            body,
+            blob: Some(blob),
+            metadata: None,
+        }
+    }
+
+    /// The same response with metadata. Panics on an [`Reply::Error`].
+    pub fn metadata(self, metadata: &str) -> Self {
+        match self {
+            Reply::Response { body, blob, .. } => Reply::Response {
+                body,
+                blob,
+                metadata: Some(metadata.to_string()),
+            },
+            Reply::Error(_) => panic!("MockHost: an error reply has no metadata"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    incoming: VecDeque<(Received, Option<LazyLoadBlob>)>,
+    replies: VecDeque<Reply>,
+    blob: Option<LazyLoadBlob>,
+    blob_reads: usize,
+    state: Option<Vec<u8>>,
+    capabilities: Vec<Capability>,
+}
+
+/// A [`Host`] that records what is sent and printed, keeps process state and
+/// capabilities in memory, and hands out scripted messages and replies.
+///
+/// Receiving with nothing scripted, or awaiting a response with no
+/// [`Reply`] scripted, panics.
+///
+/// ```
+/// use hyperware_process_lib::host::{Call, MockHost, Reply};
+/// use hyperware_process_lib::Request;
+///
+/// let host = MockHost::new();
+/// let _installed = host.install();
+/// host.reply(Reply::body("pong"));
+///
+/// let response = Request::to(("our", "pinger", "app", "pub.os"))
+///     .body("ping")
+///     .send_and_await_response(5)
+///     .unwrap()
+///     .unwrap();
+/// assert_eq!(response.body(), b"pong");
+/// assert!(matches!(host.calls()[0], Call::SendAndAwaitResponse { .. }));
+/// ```
+#[derive(Clone, Default)]
+pub struct MockHost {
+    state: Rc<RefCell<State>>,
+}
+
+impl MockHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make this the host of the current thread until the guard is dropped.
+    pub fn install(&self) -> HostGuard {
+        set_host(Rc::new(self.clone()))
+    }
+
+    /// Queue message, and the blob it carries, for [`crate::await_message()`].
+    pub fn push_message(&self, message: Message, blob: Option<LazyLoadBlob>) {
+        let received = Ok(to_wit_message(message));
+        self.state.borrow_mut().incoming.push_back((received, blob));
+    }
+
+    /// Queue error for [`crate::await_message()`].
+    pub fn push_send_error(&self, error: SendError) {
+        let (_, message) = to_wit_message(error.message);
+        let received = Err((
+            wit::SendError {
+                kind: to_wit_kind(&error.kind),
+                target: error.target,
+                message,
+                lazy_load_blob: error.lazy_load_blob.clone(),
+            },
+            error.context,
+        ));
+        self.state
+            .borrow_mut()
+            .incoming
+            .push_back((received, error.lazy_load_blob));
+    }
+
+    /// Queue reply as the answer to the next request awaiting a response.
+    pub fn reply(&self, reply: Reply) {
+        self.state.borrow_mut().replies.push_back(reply);
+    }
+
+    /// Set the blob of the message being handled.
+    pub fn set_blob(&self, blob: Option<LazyLoadBlob>) {
+        super::clear_blob_cache();
+        self.state.borrow_mut().blob = blob;
+    }
+
+    /// How many times the blob has been fetched, i.e. copied out of the host.
+    pub fn blob_reads(&self) -> usize {
+        self.state.borrow().blob_reads
+    }
+
+    /// The process state, as last set.
+    pub fn state(&self) -> Option<Vec<u8>> {
+        self.state.borrow().state.clone()
+    }
+
+    pub fn set_capabilities(&self, capabilities: Vec<Capability>) {
+        self.state.borrow_mut().capabilities = capabilities;
+    }
+
+    /// The calls made so far, oldest first.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.borrow().calls.clone()
+    }
+
+    /// Like [`MockHost::calls()`], but forgets them.
+    pub fn take_calls(&self) -> Vec<Call> {
+        std::mem::take(&mut self.state.borrow_mut().calls)
+    }
+
+    /// The messages printed so far, with their verbosity levels.
+    pub fn prints(&self) -> Vec<(u8, String)> {
+        self.state
+            .borrow()
+            .calls
+            .iter()
+            .filter_map(|call| match call {
+                Call::Print { verbosity, message } => Some((*verbosity, message.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn record(&self, call: Call) {
+        self.state.borrow_mut().calls.push(call);
+    }
+}
+
+fn to_wit_kind(kind: &SendErrorKind) -> wit::SendErrorKind {
+    match kind {
+        SendErrorKind::Offline => wit::SendErrorKind::Offline,
+        SendErrorKind::Timeout => wit::SendErrorKind::Timeout,
+    }
+}
+
+/// The source and wit form of message, as the kernel would deliver it.
+fn to_wit_message(message: Message) -> (Address, wit::Message) {
+    match message {
+        Message::Request {
+            source,
+            expects_response,
+            body,
+            metadata,
+            capabilities,
+        } => (
+            source,
+            wit::Message::Request(wit::Request {
+                inherit: false,
+                expects_response,
+                body,
+                metadata,
+                capabilities,
+            }),
+        ),
+        Message::Response {
+            source,
+            body,
+            metadata,
+            context,
+            capabilities,
+        } => (
+            source,
+            wit::Message::Response((
+                wit::Response {
+                    inherit: false,
+                    body,
+                    metadata,
+                    capabilities,
+                },
+                context,
+            )),
+        ),
+    }
+}
+
+impl Host for MockHost {
+    fn receive(&self) -> Received {
+        let mut state = self.state.borrow_mut();
+        let (received, blob) = state
+            .incoming
+            .pop_front()
+            .expect("MockHost: nothing queued to receive");
+        state.blob = blob;
+        received
+    }
+
+    fn send_request(
+        &self,
+        target: &Address,
+        request: &wit::Request,
+        context: Option<&Vec<u8>>,
+        blob: Option<&LazyLoadBlob>,
+    ) {
+        self.record(Call::SendRequest {
+            target: target.clone(),
+            request: request.clone(),
+            context: context.cloned(),
+            blob: blob.cloned(),
+        });
+    }
+
+    fn send_and_await_response(
+        &self,
+        target: &Address,
+        request: &wit::Request,
+        blob: Option<&LazyLoadBlob>,
+    ) -> Result<(Address, wit::Message), wit::SendError> {
+        self.record(Call::SendAndAwaitResponse {
+            target: target.clone(),
+            request: request.clone(),
+            blob: blob.cloned(),
+        });
+        let mut state = self.state.borrow_mut();
+        let reply = state
+            .replies
+            .pop_front()
+            .unwrap_or_else(|| panic!("MockHost: no reply scripted for request to {target}"));
+        match reply {
+            Reply::Response {
+                body,
+                blob,
+                metadata,
+            } => {
+                state.blob = blob;
+                let response = wit::Response {
+                    inherit: false,
+                    body,
+                    metadata,
+                    capabilities: vec![],
+                };
+                Ok((target.clone(), wit::Message::Response((response, None))))
+            }
+            Reply::Error(kind) => Err(wit::SendError {
+                kind: to_wit_kind(&kind),
+                target: target.clone(),
+                message: wit::Message::Request(request.clone()),
+                lazy_load_blob: blob.cloned(),
+            }),
+        }
+    }
+
+    fn send_response(&self, response: &wit::Response, blob: Option<&LazyLoadBlob>) {
+        self.record(Call::SendResponse {
+            response: response.clone(),
+            blob: blob.cloned(),
+        });
+    }
+
+    fn get_blob(&self) -> Option<LazyLoadBlob> {
+        let mut state = self.state.borrow_mut();
+        state.blob_reads += 1;
+        state.blob.clone()
+    }
+
+    fn has_blob(&self) -> bool {
+        self.state.borrow().blob.is_some()
+    }
+
+    fn get_state(&self) -> Option<Vec<u8>> {
+        self.state.borrow().state.clone()
+    }
+
+    fn set_state(&self, bytes: &[u8]) {
+        self.record(Call::SetState(bytes.to_vec()));
+        self.state.borrow_mut().state = Some(bytes.to_vec());
+    }
+
+    fn clear_state(&self) {
+        self.record(Call::ClearState);
+        self.state.borrow_mut().state = None;
+    }
+
+    fn our_capabilities(&self) -> Vec<Capability> {
+        self.state.borrow().capabilities.clone()
+    }
+
+    fn save_capabilities(&self, capabilities: &[Capability]) {
+        self.record(Call::SaveCapabilities(capabilities.to_vec()));
+        let mut state = self.state.borrow_mut();
+        for capability in capabilities {
+            if !state.capabilities.contains(capability) {
+                state.capabilities.push(capability.clone());
+            }
+        }
+    }
+
+    fn drop_capabilities(&self, capabilities: &[Capability]) {
+        self.record(Call::DropCapabilities(capabilities.to_vec()));
+        self.state
+            .borrow_mut()
+            .capabilities
+            .retain(|capability| !capabilities.contains(capability));
+    }
+
+    fn print_to_terminal(&self, verbosity: u8, message: &str) {
+        self.record(Call::Print {
+            verbosity,
+            message: message.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{self, FileMetadata, FileType, VfsClientError, VfsResponse};
+    use crate::{await_message, get_blob, Request, Response};
+
+    fn our_address() -> Address {
+        "our.os@tester:app:sys".parse().unwrap()
+    }
+
+    #[test]
+    fn test_requests_and_replies() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        host.reply(Reply::json(&VfsResponse::Metadata(FileMetadata {
+            file_type: FileType::File,
+            len: 3,
+            created: None,
+            modified: Some(7),
+        })));
+        host.reply(Reply::Error(SendErrorKind::Timeout));
+
+        // the vfs module goes through the host too
+        let metadata = vfs::metadata("/app:sys/file", None).unwrap();
+        assert_eq!((metadata.len, metadata.modified), (3, Some(7)));
+        assert!(matches!(
+            vfs::metadata("/app:sys/file", None),
+            Err(VfsClientError::Timeout { .. })
+        ));
+
+        Request::to(our_address())
+            .body("fire and forget")
+            .context(b"ctx".to_vec())
+            .send()
+            .unwrap();
+        // the timeout against vfs also prints a warning
+        let calls: Vec<Call> = host
+            .take_calls()
+            .into_iter()
+            .filter(|call| !matches!(call, Call::Print { .. }))
+            .collect();
+        assert_eq!(calls.len(), 3);
+        let Call::SendAndAwaitResponse { target, .. } = &calls[0] else {
+            panic!("unexpected {calls:?}");
+        };
+        assert_eq!(target.process(), "vfs");
+        let Call::SendRequest {
+            request, context, ..
+        } = &calls[2]
+        else {
+            panic!("unexpected {calls:?}");
+        };
+        assert_eq!(request.body, b"fire and forget");
+        assert_eq!(context.as_deref(), Some(&b"ctx"[..]));
+    }
+
+    #[test]
+    fn test_incoming_and_state() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let request = Message::Request {
+            source: our_address(),
+            expects_response: Some(5),
+            body: b"hello".to_vec(),
+            metadata: None,
+            capabilities: vec![],
+        };
+        host.push_message(request, Some(LazyLoadBlob::new(None::<String>, b"blob")));
+        let message = await_message().unwrap();
+        assert_eq!(message.body(), b"hello");
+        assert_eq!(get_blob().unwrap().bytes, b"blob");
+        Response::new().body("hi").inherit(true).send().unwrap();
+        assert!(matches!(
+            &host.calls()[..],
+            [Call::SendResponse { response, .. }] if response.body == b"hi"
+        ));
+
+        host.push_send_error(SendError {
+            kind: SendErrorKind::Offline,
+            target: our_address(),
+            message: message.clone(),
+            lazy_load_blob: None,
+            context: Some(b"ctx".to_vec()),
+        });
+        let error = await_message().unwrap_err();
+        assert!(matches!(error.kind, SendErrorKind::Offline));
+        assert_eq!(error.context(), Some(&b"ctx"[..]));
+        assert_eq!(get_blob(), None);
+
+        assert_eq!(crate::get_state(), None);
+        crate::set_state(b"saved");
+        assert_eq!(host.state().as_deref(), Some(&b"saved"[..]));
+        crate::clear_state();
+        assert_eq!(crate::get_state(), None);
+
+        let capability = Capability::new(our_address(), "{}");
+        crate::save_capabilities(std::slice::from_ref(&capability));
+        crate::save_capabilities(std::slice::from_ref(&capability));
+        assert_eq!(crate::our_capabilities(), vec![capability.clone()]);
+        crate::drop_capabilities(&[capability]);
+        assert!(crate::our_capabilities().is_empty());
+
+        crate::print_to_terminal(2, "printed");
+        assert_eq!(host.prints(), [(2, "printed".to_string())]);
+    }
+
+    #[test]
+    fn test_blob_accessors_copy_once_per_message() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let message = Message::Request {
+            source: our_address(),
+            expects_response: None,
+            body: vec![],
+            metadata: None,
+            capabilities: vec![],
+        };
+        let blob = LazyLoadBlob::new(Some("text/plain"), b"header:body".to_vec());
+        host.push_message(message.clone(), Some(blob));
+        host.push_message(message, None);
+
+        let message = await_message().unwrap();
+        assert_eq!(crate::get_blob_len(), Some(11));
+        assert_eq!(message.blob_len(), Some(11));
+        assert_eq!(crate::get_blob_mime().as_deref(), Some("text/plain"));
+        assert_eq!(crate::get_blob_range(0, 6).unwrap(), b"header");
+        assert_eq!(crate::get_blob_range(7, 100).unwrap(), b"body");
+        assert_eq!(crate::get_blob_range(20, 5).unwrap(), b"");
+        assert_eq!(host.blob_reads(), 1);
+
+        // the next message has its own blob, fetched anew
+        await_message().unwrap();
+        assert_eq!(crate::get_blob_len(), None);
+        assert_eq!(crate::get_blob_mime(), None);
+        assert_eq!(crate::get_blob_range(0, 1), None);
+        assert_eq!(host.blob_reads(), 2);
+
+        // as does the response to a request awaited
+        host.reply(Reply::with_blob(
+            vec![],
+            LazyLoadBlob::new(None::<String>, b"abc"),
+        ));
+        Request::to(our_address())
+            .body("get")
+            .send_and_await_response(5)
+            .unwrap()
+            .unwrap();
+        assert_eq!(crate::get_blob_len(), Some(3));
+        assert_eq!(crate::get_blob_mime(), None);
+        assert_eq!(host.blob_reads(), 3);
+        host.set_blob(Some(LazyLoadBlob::new(None::<String>, b"abcd")));
+        assert_eq!(crate::get_blob_len(), Some(4));
+        assert_eq!(host.blob_reads(), 4);
+    }
+}
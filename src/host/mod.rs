@@ -0,0 +1,299 @@
+//! The host functions the rest of the crate calls, behind a trait so that a
+//! stand-in can take the kernel's place, e.g. in tests off-node.
+//!
+//! Every thread starts out calling the wit bindings directly. [`set_host()`]
+//! replaces them on the current thread until the returned guard is dropped. The
+//! functions here are what the crate root exports under the wit names, so the
+//! [`crate::Request`] and [`crate::Response`] builders, and the modules built on
+//! them, all go through the current host.
+use crate::hyperware::process::standard as wit;
+use crate::{Address, Capability, LazyLoadBlob};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+#[cfg(any(test, feature = "mock"))]
+pub use mock::{Call, MockHost, Reply};
+
+/// What [`Host::receive()`] returns: the next message and its source, or a
+/// message that could not be delivered along with its context.
+pub type Received = Result<(Address, wit::Message), (wit::SendError, Option<Vec<u8>>)>;
+
+/// Functions the kernel provides. Each defaults to calling the kernel, so that a
+/// stand-in need only implement those it replaces.
+///
+/// Signatures are those of the bindings, large `Err` variants included.
+#[allow(clippy::result_large_err)]
+pub trait Host {
+    fn receive(&self) -> Received {
+        wit::receive()
+    }
+
+    fn send_request(
+        &self,
+        target: &Address,
+        request: &wit::Request,
+        context: Option<&Vec<u8>>,
+        blob: Option<&LazyLoadBlob>,
+    ) {
+        wit::send_request(target, request, context, blob)
+    }
+
+    fn send_and_await_response(
+        &self,
+        target: &Address,
+        request: &wit::Request,
+        blob: Option<&LazyLoadBlob>,
+    ) -> Result<(Address, wit::Message), wit::SendError> {
+        wit::send_and_await_response(target, request, blob)
+    }
+
+    /// Answer the request being handled.
+    fn send_response(&self, response: &wit::Response, blob: Option<&LazyLoadBlob>) {
+        wit::send_response(response, blob)
+    }
+
+    /// The blob of the message being handled, if any.
+    fn get_blob(&self) -> Option<LazyLoadBlob> {
+        wit::get_blob()
+    }
+
+    fn has_blob(&self) -> bool {
+        wit::has_blob()
+    }
+
+    fn get_state(&self) -> Option<Vec<u8>> {
+        wit::get_state()
+    }
+
+    fn set_state(&self, bytes: &[u8]) {
+        wit::set_state(bytes)
+    }
+
+    fn clear_state(&self) {
+        wit::clear_state()
+    }
+
+    fn our_capabilities(&self) -> Vec<Capability> {
+        wit::our_capabilities()
+    }
+
+    fn save_capabilities(&self, capabilities: &[Capability]) {
+        wit::save_capabilities(capabilities)
+    }
+
+    fn drop_capabilities(&self, capabilities: &[Capability]) {
+        wit::drop_capabilities(capabilities)
+    }
+
+    fn print_to_terminal(&self, verbosity: u8, message: &str) {
+        wit::print_to_terminal(verbosity, message)
+    }
+}
+
+/// The kernel, through the wit bindings.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Wit;
+
+impl Host for Wit {}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<dyn Host>>> = const { RefCell::new(None) };
+    /// The blob of the message being handled, once fetched by one of the
+    /// `get_blob_*()` accessors, or restored with the message from the queue:
+    /// `Some(None)` if it has none.
+    static BLOB: RefCell<Option<Option<LazyLoadBlob>>> = const { RefCell::new(None) };
+    /// The source of the message last received, if it was a request.
+    static SOURCE: RefCell<Option<Address>> = const { RefCell::new(None) };
+}
+
+/// Restores the host that was current before [`set_host()`] when dropped.
+#[must_use = "the host is replaced only until the guard is dropped"]
+pub struct HostGuard {
+    previous: Option<Rc<dyn Host>>,
+}
+
+impl Drop for HostGuard {
+    fn drop(&mut self) {
+        clear_blob_cache();
+        SOURCE.set(None);
+        CURRENT.set(self.previous.take());
+    }
+}
+
+/// Make host the current host of this thread, until the guard is dropped.
+pub fn set_host(host: Rc<dyn Host>) -> HostGuard {
+    clear_blob_cache();
+    SOURCE.set(None);
+    HostGuard {
+        previous: CURRENT.replace(Some(host)),
+    }
+}
+
+/// Call f with the current host: [`Wit`], unless [`set_host()`] replaced it.
+#[inline]
+pub fn with_host<R>(f: impl FnOnce(&dyn Host) -> R) -> R {
+    // cloned out so that f may itself call through the current host
+    match CURRENT.with_borrow(|current| current.clone()) {
+        Some(host) => f(host.as_ref()),
+        None => f(&Wit),
+    }
+}
+
+/// Ingest next message when it arrives along with its source.
+#[inline]
+#[allow(clippy::result_large_err)]
+pub fn receive() -> Received {
+    clear_blob_cache();
+    let received = with_host(|host| host.receive());
+    SOURCE.set(match &received {
+        Ok((source, wit::Message::Request(_))) => Some(source.clone()),
+        _ => None,
+    });
+    received
+}
+
+/// Who a [`crate::Response`] sent now would go to: the source of the message
+/// last received, if it was a request. `None` once anything else is received,
+/// so that a check against it fails closed. Responses awaited with
+/// [`send_and_await_response()`] leave it as it was.
+pub fn current_source() -> Option<Address> {
+    SOURCE.with_borrow(Clone::clone)
+}
+
+/// Send request to target.
+#[inline]
+pub fn send_request(
+    target: &Address,
+    request: &wit::Request,
+    context: Option<&Vec<u8>>,
+    blob: Option<&LazyLoadBlob>,
+) {
+    with_host(|host| host.send_request(target, request, context, blob))
+}
+
+/// Send a single request, then block until its response.
+#[inline]
+#[allow(clippy::result_large_err)]
+pub fn send_and_await_response(
+    target: &Address,
+    request: &wit::Request,
+    blob: Option<&LazyLoadBlob>,
+) -> Result<(Address, wit::Message), wit::SendError> {
+    clear_blob_cache();
+    with_host(|host| host.send_and_await_response(target, request, blob))
+}
+
+/// Send response to the request currently being handled.
+#[inline]
+pub fn send_response(response: &wit::Response, blob: Option<&LazyLoadBlob>) {
+    with_host(|host| host.send_response(response, blob))
+}
+
+/// Returns the blob of the current message, if any.
+#[inline]
+pub fn get_blob() -> Option<LazyLoadBlob> {
+    match BLOB.with_borrow(Clone::clone) {
+        Some(cached) => cached,
+        None => with_host(|host| host.get_blob()),
+    }
+}
+
+/// The length in bytes of the blob of the current message, if any.
+///
+/// This and the other `get_blob_*()` accessors fetch the blob from the host
+/// once per message, on first use, and serve from that copy until the next
+/// message is received.
+pub fn get_blob_len() -> Option<u64> {
+    with_cached_blob(|blob| blob.bytes.len() as u64)
+}
+
+/// The MIME type of the blob of the current message, if it has a blob with one.
+pub fn get_blob_mime() -> Option<String> {
+    with_cached_blob(|blob| blob.mime.clone()).flatten()
+}
+
+/// Up to len bytes of the blob of the current message, starting at byte
+/// start: fewer if the blob ends first, and none if it ends before start.
+/// `None` if there is no blob.
+pub fn get_blob_range(start: u64, len: u64) -> Option<Vec<u8>> {
+    with_cached_blob(|blob| {
+        let size = blob.bytes.len() as u64;
+        let start = start.min(size);
+        let end = start.saturating_add(len).min(size);
+        blob.bytes[start as usize..end as usize].to_vec()
+    })
+}
+
+fn with_cached_blob<R>(f: impl FnOnce(&LazyLoadBlob) -> R) -> Option<R> {
+    BLOB.with_borrow_mut(|cached| {
+        cached
+            .get_or_insert_with(|| with_host(|host| host.get_blob()))
+            .as_ref()
+            .map(f)
+    })
+}
+
+/// Forget the blob cached by the `get_blob_*()` accessors, when the message
+/// being handled changes.
+pub(crate) fn clear_blob_cache() {
+    BLOB.set(None);
+}
+
+/// Make blob the blob of the message being handled, as for a message taken
+/// from the [`crate::queue`], whose blob the host has since forgotten.
+pub(crate) fn restore_blob(blob: Option<LazyLoadBlob>) {
+    BLOB.set(Some(blob));
+}
+
+/// Returns whether or not the current message has a blob.
+#[inline]
+pub fn has_blob() -> bool {
+    match BLOB.with_borrow(|cached| cached.as_ref().map(Option::is_some)) {
+        Some(cached) => cached,
+        None => with_host(|host| host.has_blob()),
+    }
+}
+
+/// The persisted state of this process, if any.
+#[inline]
+pub fn get_state() -> Option<Vec<u8>> {
+    with_host(|host| host.get_state())
+}
+
+/// Persist bytes as the state of this process.
+#[inline]
+pub fn set_state(bytes: &[u8]) {
+    with_host(|host| host.set_state(bytes))
+}
+
+/// Delete the persisted state of this process.
+#[inline]
+pub fn clear_state() {
+    with_host(|host| host.clear_state())
+}
+
+/// Gets all capabilities from persisted process state.
+#[inline]
+pub fn our_capabilities() -> Vec<Capability> {
+    with_host(|host| host.our_capabilities())
+}
+
+/// Saves the capabilities to persisted process state.
+#[inline]
+pub fn save_capabilities(capabilities: &[Capability]) {
+    with_host(|host| host.save_capabilities(capabilities))
+}
+
+/// Deletes the capabilities from persisted process state.
+#[inline]
+pub fn drop_capabilities(capabilities: &[Capability]) {
+    with_host(|host| host.drop_capabilities(capabilities))
+}
+
+/// Prints to the terminal at a given verbosity level. Level 0 is always printed.
+#[inline]
+pub fn print_to_terminal(verbosity: u8, message: &str) {
+    with_host(|host| host.print_to_terminal(verbosity, message))
+}
@@ -0,0 +1,543 @@
+use super::client::{to_http_response, ClientRequestBuilder, HttpClientError, HttpResponse};
+use crate::timer::wall_clock_ms;
+use crate::vfs::{self, File, VfsClientError, VfsError};
+use http::{HeaderMap, Method};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Default byte budget of a [`CachingClient`]: 16 MiB of cached bodies.
+pub const DEFAULT_CACHE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How a [`CachingClient`] answered a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Served from the cache without contacting the server, per `Cache-Control: max-age`.
+    Fresh,
+    /// The server answered `304 Not Modified` and the cached body was served.
+    Revalidated,
+    /// The body was downloaded from the server.
+    Fetched,
+}
+
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub status: CacheStatus,
+    pub response: http::Response<Vec<u8>>,
+}
+
+/// An HTTP client that keeps GET response bodies in a vfs directory and revalidates
+/// them with `If-None-Match` / `If-Modified-Since`, so unchanged resources are not
+/// downloaded again.
+///
+/// Only successful GET responses carrying an `ETag`, a `Last-Modified`, or a
+/// `Cache-Control: max-age` are stored, and never ones marked `no-store`.
+/// Once the stored bodies exceed the byte budget, the least recently used are evicted.
+///
+/// ```no_run
+/// use hyperware_process_lib::http::client::{CacheStatus, CachingClient};
+///
+/// let mut client = CachingClient::new("/my-package:publisher.os/http-cache").unwrap();
+/// let cached = client.get("https://example.com/prices.json").unwrap();
+/// if cached.status != CacheStatus::Revalidated {
+///     // the data may have changed
+/// }
+/// ```
+pub struct CachingClient {
+    pub dir: String,
+    pub max_bytes: u64,
+    pub timeout: u64,
+    index: CacheIndex,
+}
+
+impl CachingClient {
+    /// Open (creating if needed) a cache in the vfs directory at drive_path,
+    /// picking up any entries already stored there.
+    pub fn new(drive_path: &str) -> Result<Self, HttpClientError> {
+        let dir = drive_path.trim_end_matches('/').to_string();
+        vfs::open_dir(&dir, true, None).map_err(HttpClientError::Vfs)?;
+        let index = match vfs::read_json(&index_path(&dir), None) {
+            Ok(index) => index,
+            Err(VfsClientError::NotFound { .. })
+            | Err(VfsClientError::Runtime(VfsError::IOError(_)))
+            | Err(VfsClientError::Json { .. }) => CacheIndex::default(),
+            Err(e) => return Err(HttpClientError::Vfs(e)),
+        };
+        Ok(CachingClient {
+            dir,
+            max_bytes: DEFAULT_CACHE_BYTES,
+            timeout: super::client::DEFAULT_CLIENT_TIMEOUT,
+            index,
+        })
+    }
+
+    /// Set the byte budget for cached bodies. Defaults to [`DEFAULT_CACHE_BYTES`].
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Set the request timeout in seconds.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// GET url through the cache.
+    pub fn get(&mut self, url: &str) -> Result<CachedResponse, HttpClientError> {
+        let request = ClientRequestBuilder::get(url).timeout(self.timeout);
+        self.send(request)
+    }
+
+    /// Send request through the cache. Requests other than GET are sent as-is
+    /// and reported as [`CacheStatus::Fetched`].
+    pub fn send(
+        &mut self,
+        request: ClientRequestBuilder,
+    ) -> Result<CachedResponse, HttpClientError> {
+        let outgoing = request.build()?;
+        if outgoing.method != Method::GET.as_str() {
+            return Ok(CachedResponse {
+                status: CacheStatus::Fetched,
+                response: request.send()?,
+            });
+        }
+        let url = outgoing.url;
+        let now = wall_clock_ms();
+
+        if let Some(entry) = self.index.touch(&url, now) {
+            if entry.is_fresh(now) {
+                if let Ok(response) = self.read_cached(&entry) {
+                    self.save_index()?;
+                    return Ok(CachedResponse {
+                        status: CacheStatus::Fresh,
+                        response,
+                    });
+                }
+            }
+        }
+
+        let conditional = match self.index.entries.get(&url) {
+            Some(entry) => conditional_headers(entry)
+                .into_iter()
+                .fold(request.clone(), |request, (key, value)| {
+                    request.header(key, value)
+                }),
+            None => request.clone(),
+        };
+        let response = conditional.send()?;
+
+        match self.index.on_response(&url, &response, now) {
+            ResponseAction::UseCached(entry) => match self.read_cached(&entry) {
+                Ok(cached) => {
+                    self.save_index()?;
+                    Ok(CachedResponse {
+                        status: CacheStatus::Revalidated,
+                        response: cached,
+                    })
+                }
+                // the body went missing: forget it and fetch unconditionally
+                Err(_) => {
+                    self.index.entries.remove(&url);
+                    self.save_index()?;
+                    Ok(CachedResponse {
+                        status: CacheStatus::Fetched,
+                        response: request.send()?,
+                    })
+                }
+            },
+            ResponseAction::Store(entry) => {
+                if entry.size <= self.max_bytes {
+                    vfs::write_atomic(&self.body_path(&entry.file), response.body(), None)
+                        .map_err(HttpClientError::Vfs)?;
+                    self.index.entries.insert(url.clone(), entry);
+                    for evicted in self.index.evict(self.max_bytes, &url) {
+                        let _ = vfs::remove_file(&self.body_path(&evicted.file), None);
+                    }
+                    self.save_index()?;
+                }
+                Ok(CachedResponse {
+                    status: CacheStatus::Fetched,
+                    response,
+                })
+            }
+            ResponseAction::Uncacheable(evicted) => {
+                if let Some(evicted) = evicted {
+                    let _ = vfs::remove_file(&self.body_path(&evicted.file), None);
+                    self.save_index()?;
+                }
+                Ok(CachedResponse {
+                    status: CacheStatus::Fetched,
+                    response,
+                })
+            }
+        }
+    }
+
+    /// Total size of the cached bodies, in bytes.
+    pub fn cached_bytes(&self) -> u64 {
+        self.index.total_bytes()
+    }
+
+    /// Remove every cached body.
+    pub fn clear(&mut self) -> Result<(), HttpClientError> {
+        for (_, entry) in self.index.entries.drain() {
+            let _ = vfs::remove_file(&body_path(&self.dir, &entry.file), None);
+        }
+        self.save_index()
+    }
+
+    fn read_cached(&self, entry: &CacheEntry) -> Result<http::Response<Vec<u8>>, VfsClientError> {
+        let body = File::new(self.body_path(&entry.file), 5).read()?;
+        let response = HttpResponse {
+            status: entry.status,
+            headers: entry.headers.clone(),
+        };
+        Ok(to_http_response(response, body))
+    }
+
+    fn body_path(&self, file: &str) -> String {
+        body_path(&self.dir, file)
+    }
+
+    fn save_index(&self) -> Result<(), HttpClientError> {
+        File::new(index_path(&self.dir), 5)
+            .save_json(&self.index)
+            .map_err(HttpClientError::Vfs)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Name of the body file within the cache directory.
+    file: String,
+    /// Status and headers of the response the body came with.
+    status: u16,
+    headers: HashMap<String, String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// When the body stops being fresh without revalidation, in ms since the UNIX epoch.
+    fresh_until_ms: Option<u64>,
+    size: u64,
+    last_used_ms: u64,
+}
+
+impl CacheEntry {
+    fn is_fresh(&self, now: u64) -> bool {
+        self.fresh_until_ms.is_some_and(|until| now < until)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// What to do with a response received for a cached (or cacheable) URL.
+#[derive(Debug, PartialEq, Eq)]
+enum ResponseAction {
+    /// `304 Not Modified`: serve this stored entry.
+    UseCached(CacheEntry),
+    /// Store the response body under this entry.
+    Store(CacheEntry),
+    /// Pass the response through, dropping any previous entry for the URL.
+    Uncacheable(Option<CacheEntry>),
+}
+
+impl CacheIndex {
+    fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+
+    /// Mark the entry for url as used now, returning a copy of it.
+    fn touch(&mut self, url: &str, now: u64) -> Option<CacheEntry> {
+        let entry = self.entries.get_mut(url)?;
+        entry.last_used_ms = now;
+        Some(entry.clone())
+    }
+
+    fn on_response(
+        &mut self,
+        url: &str,
+        response: &http::Response<Vec<u8>>,
+        now: u64,
+    ) -> ResponseAction {
+        let headers = response.headers();
+        let status = response.status().as_u16();
+
+        if status == 304 {
+            if let Some(entry) = self.entries.get_mut(url) {
+                // a 304 may carry updated validators and freshness
+                if let Some(etag) = header(headers, http::header::ETAG) {
+                    entry.etag = Some(etag);
+                }
+                if let Some(last_modified) = header(headers, http::header::LAST_MODIFIED) {
+                    entry.last_modified = Some(last_modified);
+                }
+                if let Some(max_age) = max_age(headers) {
+                    entry.fresh_until_ms = Some(now + max_age * 1000);
+                }
+                entry.last_used_ms = now;
+                return ResponseAction::UseCached(entry.clone());
+            }
+        }
+
+        let previous = self.entries.remove(url);
+        if !(200..300).contains(&status) {
+            return ResponseAction::Uncacheable(previous);
+        }
+        match entry_for_response(url, response, now) {
+            Some(entry) => ResponseAction::Store(entry),
+            None => ResponseAction::Uncacheable(previous),
+        }
+    }
+
+    /// Drop least-recently-used entries, never keep, until the total fits in max_bytes.
+    /// Returns the dropped entries.
+    fn evict(&mut self, max_bytes: u64, keep: &str) -> Vec<CacheEntry> {
+        let mut total = self.total_bytes();
+        let mut by_age: Vec<(String, u64)> = self
+            .entries
+            .iter()
+            .filter(|(url, _)| url.as_str() != keep)
+            .map(|(url, entry)| (url.clone(), entry.last_used_ms))
+            .collect();
+        by_age.sort_by_key(|(_, last_used)| *last_used);
+
+        let mut evicted = vec![];
+        for (url, _) in by_age {
+            if total <= max_bytes {
+                break;
+            }
+            if let Some(entry) = self.entries.remove(&url) {
+                total -= entry.size;
+                evicted.push(entry);
+            }
+        }
+        evicted
+    }
+}
+
+/// The cache entry for a successful response, if it may be stored.
+fn entry_for_response(
+    url: &str,
+    response: &http::Response<Vec<u8>>,
+    now: u64,
+) -> Option<CacheEntry> {
+    let headers = response.headers();
+    let cache_control = header(headers, http::header::CACHE_CONTROL).unwrap_or_default();
+    if cache_directives(&cache_control).any(|(name, _)| name == "no-store") {
+        return None;
+    }
+    let etag = header(headers, http::header::ETAG);
+    let last_modified = header(headers, http::header::LAST_MODIFIED);
+    let fresh_until_ms = max_age(headers).map(|secs| now + secs * 1000);
+    if etag.is_none() && last_modified.is_none() && fresh_until_ms.is_none() {
+        return None;
+    }
+    Some(CacheEntry {
+        file: alloy_primitives::hex::encode(Sha256::digest(url.as_bytes())),
+        status: response.status().as_u16(),
+        headers: headers
+            .iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        etag,
+        last_modified,
+        fresh_until_ms,
+        size: response.body().len() as u64,
+        last_used_ms: now,
+    })
+}
+
+/// The validators to send when revalidating entry.
+fn conditional_headers(entry: &CacheEntry) -> Vec<(&'static str, String)> {
+    let mut headers = vec![];
+    if let Some(etag) = &entry.etag {
+        headers.push(("If-None-Match", etag.clone()));
+    }
+    if let Some(last_modified) = &entry.last_modified {
+        headers.push(("If-Modified-Since", last_modified.clone()));
+    }
+    headers
+}
+
+/// `max-age` from `Cache-Control`, in seconds. `no-cache` means always revalidate.
+fn max_age(headers: &HeaderMap) -> Option<u64> {
+    let cache_control = header(headers, http::header::CACHE_CONTROL)?;
+    let mut max_age = None;
+    for (name, value) in cache_directives(&cache_control) {
+        match name.as_str() {
+            "no-cache" => return None,
+            "max-age" => max_age = value.and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+    }
+    max_age
+}
+
+fn cache_directives(cache_control: &str) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    cache_control.split(',').map(|directive| {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name, Some(value.trim().trim_matches('"').to_string())),
+            None => (directive, None),
+        };
+        (name.trim().to_ascii_lowercase(), value)
+    })
+}
+
+fn header(headers: &HeaderMap, name: http::header::HeaderName) -> Option<String> {
+    Some(headers.get(name)?.to_str().ok()?.to_string())
+}
+
+fn index_path(dir: &str) -> String {
+    format!("{dir}/index.json")
+}
+
+fn body_path(dir: &str, file: &str) -> String {
+    format!("{dir}/{file}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/data.json";
+
+    fn response(status: u16, headers: &[(&str, &str)], body: &[u8]) -> http::Response<Vec<u8>> {
+        let mut builder = http::Response::builder().status(status);
+        for (key, value) in headers {
+            builder = builder.header(*key, *value);
+        }
+        builder.body(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_conditional_headers() {
+        let ok = response(
+            200,
+            &[
+                ("ETag", "\"v1\""),
+                ("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ],
+            b"{}",
+        );
+        let entry = entry_for_response(URL, &ok, 0).unwrap();
+        assert_eq!(
+            conditional_headers(&entry),
+            vec![
+                ("If-None-Match", "\"v1\"".to_string()),
+                (
+                    "If-Modified-Since",
+                    "Wed, 21 Oct 2015 07:28:00 GMT".to_string()
+                ),
+            ]
+        );
+
+        let etag_only = entry_for_response(URL, &response(200, &[("ETag", "W/\"x\"")], b""), 0);
+        assert_eq!(
+            conditional_headers(&etag_only.unwrap()),
+            vec![("If-None-Match", "W/\"x\"".to_string())]
+        );
+
+        // max-age alone is cacheable, but has nothing to revalidate with
+        let fresh_only = entry_for_response(
+            URL,
+            &response(200, &[("Cache-Control", "max-age=60")], b""),
+            0,
+        )
+        .unwrap();
+        assert!(conditional_headers(&fresh_only).is_empty());
+        assert!(fresh_only.is_fresh(59_999));
+        assert!(!fresh_only.is_fresh(60_000));
+    }
+
+    #[test]
+    fn test_uncacheable_responses() {
+        assert!(entry_for_response(URL, &response(200, &[], b"x"), 0).is_none());
+        let no_store = response(
+            200,
+            &[("ETag", "\"v1\""), ("Cache-Control", "private, no-store")],
+            b"x",
+        );
+        assert!(entry_for_response(URL, &no_store, 0).is_none());
+        let no_cache = response(
+            200,
+            &[
+                ("ETag", "\"v1\""),
+                ("Cache-Control", "no-cache, max-age=60"),
+            ],
+            b"x",
+        );
+        let entry = entry_for_response(URL, &no_cache, 0).unwrap();
+        assert!(!entry.is_fresh(1));
+    }
+
+    #[test]
+    fn test_not_modified_path() {
+        let mut index = CacheIndex::default();
+        let ok = response(200, &[("ETag", "\"v1\"")], b"{\"price\":1}");
+        let ResponseAction::Store(entry) = index.on_response(URL, &ok, 1_000) else {
+            panic!("expected Store");
+        };
+        assert_eq!(entry.size, 11);
+        assert_eq!(entry.status, 200);
+        index.entries.insert(URL.to_string(), entry.clone());
+
+        // 304 with a new validator: serve the stored entry, updated
+        let not_modified = response(304, &[("ETag", "\"v2\"")], b"");
+        let ResponseAction::UseCached(cached) = index.on_response(URL, &not_modified, 2_000) else {
+            panic!("expected UseCached");
+        };
+        assert_eq!(cached.file, entry.file);
+        assert_eq!(cached.etag.as_deref(), Some("\"v2\""));
+        assert_eq!(cached.last_used_ms, 2_000);
+        assert_eq!(index.entries[URL].etag.as_deref(), Some("\"v2\""));
+
+        // 304 for something we never stored is passed through
+        assert_eq!(
+            index.on_response("https://example.com/other", &not_modified, 3_000),
+            ResponseAction::Uncacheable(None)
+        );
+
+        // a changed resource replaces the entry
+        let changed = response(200, &[("ETag", "\"v3\"")], b"{\"price\":2}");
+        let ResponseAction::Store(replaced) = index.on_response(URL, &changed, 4_000) else {
+            panic!("expected Store");
+        };
+        assert_eq!(replaced.etag.as_deref(), Some("\"v3\""));
+
+        // an error drops the old entry so its body can be removed
+        index.entries.insert(URL.to_string(), replaced.clone());
+        assert_eq!(
+            index.on_response(URL, &response(500, &[], b""), 5_000),
+            ResponseAction::Uncacheable(Some(replaced))
+        );
+        assert!(index.entries.is_empty());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut index = CacheIndex::default();
+        for (i, last_used) in [(0, 30), (1, 10), (2, 20)] {
+            let url = format!("https://example.com/{i}");
+            let mut entry =
+                entry_for_response(&url, &response(200, &[("ETag", "\"e\"")], &[0; 40]), 0)
+                    .unwrap();
+            entry.last_used_ms = last_used;
+            index.entries.insert(url, entry);
+        }
+        assert_eq!(index.total_bytes(), 120);
+        assert!(index.evict(120, "").is_empty());
+
+        // the least recently used go first; keep is never evicted
+        let evicted = index.evict(50, "https://example.com/1");
+        assert_eq!(evicted.len(), 2);
+        assert!(index.entries.contains_key("https://example.com/1"));
+        assert_eq!(index.total_bytes(), 40);
+
+        assert!(index.touch("https://example.com/1", 99).is_some());
+        assert_eq!(index.entries["https://example.com/1"].last_used_ms, 99);
+        assert!(index.touch("https://example.com/0", 99).is_none());
+    }
+}
@@ -1,7 +1,8 @@
+pub use super::cache::{CacheStatus, CachedResponse, CachingClient, DEFAULT_CACHE_BYTES};
 pub use super::server::{HttpResponse, WsMessageType};
 use crate::{get_blob, LazyLoadBlob as KiBlob, Message, Request as KiRequest};
 use http::Method;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
 use thiserror::Error;
@@ -83,6 +84,24 @@ pub enum HttpClientError {
     BuildRequestFailed(String),
     #[error("client failed to execute request: {0}")]
     ExecuteRequestFailed(String),
+    /// Not actually issued by `http-client:distro:sys`, just this library
+    #[error("http-client did not respond in time")]
+    Timeout,
+    /// Not actually issued by `http-client:distro:sys`, just this library
+    #[error("http-client gave an invalid response: {0}")]
+    BadResponse(String),
+    /// Not actually issued by `http-client:distro:sys`, just this library
+    #[error("request failed with status {0}")]
+    BadStatus(u16),
+    /// Not actually issued by `http-client:distro:sys`, just this library
+    #[error("downloaded file has SHA-256 {got}, expected {expected}")]
+    HashMismatch { expected: String, got: String },
+    /// Not actually issued by `http-client:distro:sys`, just this library
+    #[error("failed to write download: {0}")]
+    Vfs(crate::vfs::VfsClientError),
+    /// Not actually issued by `http-client:distro:sys`, just this library
+    #[error("failed to send: {0}")]
+    Build(#[from] crate::BuildError),
 
     // WebSocket errors
     #[error("could not open connection to {url}")]
@@ -109,8 +128,8 @@ pub fn send_request(
     headers: Option<HashMap<String, String>>,
     timeout: Option<u64>,
     body: Vec<u8>,
-) {
-    let req = KiRequest::to(("our", "http-client", "distro", "sys"))
+) -> Result<(), HttpClientError> {
+    let req = KiRequest::to(crate::SystemProcess::HttpClient.address(crate::our_node()))
         .body(
             serde_json::to_vec(&HttpClientAction::Http(OutgoingHttpRequest {
                 method: method.to_string(),
@@ -122,10 +141,11 @@ pub fn send_request(
         )
         .blob_bytes(body);
     if let Some(timeout) = timeout {
-        req.expects_response(timeout).send().unwrap()
+        req.expects_response(timeout).send()?;
     } else {
-        req.send().unwrap()
+        req.send()?;
     }
+    Ok(())
 }
 
 /// Make an HTTP request using http-client and await its response.
@@ -138,7 +158,7 @@ pub fn send_request_await_response(
     timeout: u64,
     body: Vec<u8>,
 ) -> std::result::Result<http::Response<Vec<u8>>, HttpClientError> {
-    let res = KiRequest::to(("our", "http-client", "distro", "sys"))
+    let res = KiRequest::to(crate::SystemProcess::HttpClient.address(crate::our_node()))
         .body(
             serde_json::to_vec(&HttpClientAction::Http(OutgoingHttpRequest {
                 method: method.to_string(),
@@ -149,30 +169,31 @@ pub fn send_request_await_response(
             .map_err(|_| HttpClientError::MalformedRequest)?,
         )
         .blob_bytes(body)
-        .send_and_await_response(timeout)
-        .unwrap();
+        .send_and_await_response(timeout)?;
     let Ok(Message::Response { body, .. }) = res else {
-        return Err(HttpClientError::ExecuteRequestFailed(
-            "http-client timed out".to_string(),
-        ));
-    };
-    let resp = match serde_json::from_slice::<
-        std::result::Result<HttpClientResponse, HttpClientError>,
-    >(&body)
-    {
-        Ok(Ok(HttpClientResponse::Http(resp))) => resp,
-        Ok(Ok(HttpClientResponse::WebSocketAck)) => {
-            return Err(HttpClientError::ExecuteRequestFailed(
-                "http-client gave unexpected response".to_string(),
-            ))
-        }
-        Ok(Err(e)) => return Err(e),
-        Err(e) => {
-            return Err(HttpClientError::ExecuteRequestFailed(format!(
-                "http-client gave invalid response: {e:?}"
-            )))
-        }
+        return Err(HttpClientError::Timeout);
     };
+    let resp = parse_http_response(&body)?;
+    Ok(to_http_response(resp, get_blob().unwrap_or_default().bytes))
+}
+
+/// Parse the body of a [`crate::Response`] from `http-client:distro:sys` to an
+/// [`HttpClientAction::Http`] request.
+/// Errors the runtime reports (such as a failure to connect) are returned as-is,
+/// while a body that doesn't parse gives [`HttpClientError::BadResponse`].
+/// Non-2xx statuses are not errors.
+pub fn parse_http_response(body: &[u8]) -> std::result::Result<HttpResponse, HttpClientError> {
+    match serde_json::from_slice::<std::result::Result<HttpClientResponse, HttpClientError>>(body) {
+        Ok(Ok(HttpClientResponse::Http(resp))) => Ok(resp),
+        Ok(Ok(HttpClientResponse::WebSocketAck)) => Err(HttpClientError::BadResponse(
+            "expected Http, got WebSocketAck".to_string(),
+        )),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(HttpClientError::BadResponse(e.to_string())),
+    }
+}
+
+pub(super) fn to_http_response(resp: HttpResponse, body: Vec<u8>) -> http::Response<Vec<u8>> {
     let mut http_response = http::Response::builder()
         .status(http::StatusCode::from_u16(resp.status).unwrap_or_default());
     let headers = http_response.headers_mut().unwrap();
@@ -185,9 +206,32 @@ pub fn send_request_await_response(
         };
         headers.insert(key, value);
     }
-    Ok(http_response
-        .body(get_blob().unwrap_or_default().bytes)
-        .unwrap())
+    http_response.body(body).unwrap()
+}
+
+/// Accessors for the body of an [`http::Response`] as returned by
+/// [`send_request_await_response()`].
+pub trait ResponseBodyExt {
+    /// The raw body bytes.
+    fn bytes(&self) -> &[u8];
+    /// The body as a UTF-8 string.
+    fn text(&self) -> std::result::Result<&str, std::str::Utf8Error>;
+    /// The body deserialized from JSON.
+    fn json<T: DeserializeOwned>(&self) -> std::result::Result<T, serde_json::Error>;
+}
+
+impl ResponseBodyExt for http::Response<Vec<u8>> {
+    fn bytes(&self) -> &[u8] {
+        self.body()
+    }
+
+    fn text(&self) -> std::result::Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self.body())
+    }
+
+    fn json<T: DeserializeOwned>(&self) -> std::result::Result<T, serde_json::Error> {
+        serde_json::from_slice(self.body())
+    }
 }
 
 pub fn open_ws_connection(
@@ -196,7 +240,7 @@ pub fn open_ws_connection(
     channel_id: u32,
 ) -> std::result::Result<(), HttpClientError> {
     let Ok(Ok(Message::Response { body, .. })) =
-        KiRequest::to(("our", "http-client", "distro", "sys"))
+        KiRequest::to(crate::SystemProcess::HttpClient.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&HttpClientAction::WebSocketOpen {
                     url: url.clone(),
@@ -218,7 +262,7 @@ pub fn open_ws_connection(
 
 /// Send a WebSocket push message on an open WebSocket channel.
 pub fn send_ws_client_push(channel_id: u32, message_type: WsMessageType, blob: KiBlob) {
-    KiRequest::to(("our", "http-client", "distro", "sys"))
+    KiRequest::to(crate::SystemProcess::HttpClient.address(crate::our_node()))
         .body(
             serde_json::to_vec(&HttpClientAction::WebSocketPush {
                 channel_id,
@@ -234,7 +278,7 @@ pub fn send_ws_client_push(channel_id: u32, message_type: WsMessageType, blob: K
 /// Close a WebSocket connection.
 pub fn close_ws_connection(channel_id: u32) -> std::result::Result<(), HttpClientError> {
     let Ok(Ok(Message::Response { body, .. })) =
-        KiRequest::to(("our", "http-client", "distro", "sys"))
+        KiRequest::to(crate::SystemProcess::HttpClient.address(crate::our_node()))
             .body(
                 serde_json::json!(HttpClientAction::WebSocketClose { channel_id })
                     .to_string()
@@ -251,3 +295,590 @@ pub fn close_ws_connection(channel_id: u32) -> std::result::Result<(), HttpClien
         _ => Err(HttpClientError::WsCloseFailed { channel_id }),
     }
 }
+
+/// An outbound WebSocket connection opened through `http-client:distro:sys` with [`open_ws()`].
+///
+/// Incoming frames arrive in the main loop as requests from `http-client:distro:sys`;
+/// decode them with [`parse_ws_message()`] and match on [`WsConnection::channel_id`].
+/// Connections are not reopened automatically when closed, see [`WsConnection::reopen()`].
+#[derive(Clone, Debug)]
+pub struct WsConnection {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+    pub channel_id: u32,
+}
+
+impl WsConnection {
+    pub fn send_text(&self, text: &str) {
+        self.send(WsMessageType::Text, text.as_bytes().to_vec());
+    }
+
+    pub fn send_binary(&self, bytes: Vec<u8>) {
+        self.send(WsMessageType::Binary, bytes);
+    }
+
+    pub fn send(&self, message_type: WsMessageType, bytes: Vec<u8>) {
+        send_ws_client_push(self.channel_id, message_type, KiBlob { mime: None, bytes });
+    }
+
+    pub fn close(&self) -> std::result::Result<(), HttpClientError> {
+        close_ws_connection(self.channel_id)
+    }
+
+    /// Open the connection again with the same URL, headers, and channel ID,
+    /// e.g. after receiving [`WsClientEvent::Closed`].
+    pub fn reopen(&self) -> std::result::Result<(), HttpClientError> {
+        open_ws_connection(
+            self.url.clone(),
+            Some(self.headers.clone()),
+            self.channel_id,
+        )
+    }
+}
+
+/// Open a WebSocket connection to url on a new random channel ID.
+pub fn open_ws(
+    url: &str,
+    headers: Option<HashMap<String, String>>,
+) -> std::result::Result<WsConnection, HttpClientError> {
+    let connection = WsConnection {
+        url: url.to_string(),
+        headers: headers.unwrap_or_default(),
+        channel_id: rand::random(),
+    };
+    connection.reopen()?;
+    Ok(connection)
+}
+
+/// A frame received on a [`WsConnection`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum WsFrame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+}
+
+/// An event on a [`WsConnection`], as decoded by [`parse_ws_message()`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum WsClientEvent {
+    Frame {
+        channel_id: u32,
+        frame: WsFrame,
+    },
+    /// `code` and `reason` are given if the remote end sent them in a close frame.
+    Closed {
+        channel_id: u32,
+        code: Option<u16>,
+        reason: Option<String>,
+    },
+}
+
+impl WsClientEvent {
+    pub fn channel_id(&self) -> u32 {
+        match self {
+            WsClientEvent::Frame { channel_id, .. } | WsClientEvent::Closed { channel_id, .. } => {
+                *channel_id
+            }
+        }
+    }
+}
+
+/// Decode a message from `http-client:distro:sys` about an open WebSocket
+/// connection. Returns `None` for any other message.
+pub fn parse_ws_message(message: &Message) -> Option<WsClientEvent> {
+    if !message.is_request() || !message.is_process(crate::SystemProcess::HttpClient) {
+        return None;
+    }
+    parse_ws_request(message.body(), || {
+        message.blob().map(|blob| blob.bytes).unwrap_or_default()
+    })
+}
+
+fn parse_ws_request(body: &[u8], blob: impl FnOnce() -> Vec<u8>) -> Option<WsClientEvent> {
+    match serde_json::from_slice::<HttpClientRequest>(body).ok()? {
+        HttpClientRequest::WebSocketPush {
+            channel_id,
+            message_type,
+        } => {
+            let bytes = blob();
+            Some(match message_type {
+                WsMessageType::Text => WsClientEvent::Frame {
+                    channel_id,
+                    frame: WsFrame::Text(String::from_utf8_lossy(&bytes).to_string()),
+                },
+                WsMessageType::Binary => WsClientEvent::Frame {
+                    channel_id,
+                    frame: WsFrame::Binary(bytes),
+                },
+                WsMessageType::Ping => WsClientEvent::Frame {
+                    channel_id,
+                    frame: WsFrame::Ping(bytes),
+                },
+                WsMessageType::Pong => WsClientEvent::Frame {
+                    channel_id,
+                    frame: WsFrame::Pong(bytes),
+                },
+                // close frame payload: big-endian status code, then a UTF-8 reason
+                WsMessageType::Close => WsClientEvent::Closed {
+                    channel_id,
+                    code: bytes
+                        .get(..2)
+                        .map(|code| u16::from_be_bytes([code[0], code[1]])),
+                    reason: bytes
+                        .get(2..)
+                        .filter(|reason| !reason.is_empty())
+                        .map(|reason| String::from_utf8_lossy(reason).to_string()),
+                },
+            })
+        }
+        HttpClientRequest::WebSocketClose { channel_id } => Some(WsClientEvent::Closed {
+            channel_id,
+            code: None,
+            reason: None,
+        }),
+    }
+}
+
+/// Default timeout in seconds for requests made with [`ClientRequestBuilder`].
+pub const DEFAULT_CLIENT_TIMEOUT: u64 = 30;
+
+/// Builder for an HTTP request sent through `http-client:distro:sys`, awaiting its response.
+///
+/// ```no_run
+/// use hyperware_process_lib::http::client::ClientRequestBuilder;
+///
+/// let response = ClientRequestBuilder::get("https://example.com/api/items")
+///     .query("q", "two words")
+///     .bearer_auth("token")
+///     .timeout(10)
+///     .send();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientRequestBuilder {
+    method: Method,
+    url: String,
+    headers: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+    timeout: u64,
+    follow_redirects: u32,
+    error: Option<HttpClientError>,
+}
+
+impl ClientRequestBuilder {
+    pub fn new<T: Into<String>>(method: Method, url: T) -> Self {
+        ClientRequestBuilder {
+            method,
+            url: url.into(),
+            headers: HashMap::new(),
+            query: Vec::new(),
+            body: Vec::new(),
+            timeout: DEFAULT_CLIENT_TIMEOUT,
+            follow_redirects: 0,
+            error: None,
+        }
+    }
+
+    pub fn get<T: Into<String>>(url: T) -> Self {
+        Self::new(Method::GET, url)
+    }
+
+    pub fn post<T: Into<String>>(url: T) -> Self {
+        Self::new(Method::POST, url)
+    }
+
+    pub fn put<T: Into<String>>(url: T) -> Self {
+        Self::new(Method::PUT, url)
+    }
+
+    pub fn delete<T: Into<String>>(url: T) -> Self {
+        Self::new(Method::DELETE, url)
+    }
+
+    pub fn header<T, U>(mut self, key: T, value: U) -> Self
+    where
+        T: Into<String>,
+        U: Into<String>,
+    {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+
+    /// Set an `Authorization: Bearer` header.
+    pub fn bearer_auth<T: std::fmt::Display>(self, token: T) -> Self {
+        self.header("Authorization", format!("Bearer {token}"))
+    }
+
+    /// Add a query parameter. Keys and values are URL-encoded when the request is built.
+    pub fn query<T, U>(mut self, key: T, value: U) -> Self
+    where
+        T: Into<String>,
+        U: Into<String>,
+    {
+        self.query.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn body<T: Into<Vec<u8>>>(mut self, body: T) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Set the body to value serialized as JSON, and the `Content-Type` to match.
+    pub fn json<T: Serialize>(mut self, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => self.body = body,
+            Err(e) => self.error = Some(HttpClientError::BuildRequestFailed(e.to_string())),
+        }
+        self.header("Content-Type", "application/json")
+    }
+
+    /// Set the timeout in seconds. Defaults to [`DEFAULT_CLIENT_TIMEOUT`].
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Follow up to n redirects handed back by http-client. Defaults to 0, so any
+    /// 3xx response is returned as-is. A 303, or a 301/302 to a POST, is followed
+    /// with a bodiless GET, as browsers do.
+    pub fn follow_redirects(mut self, n: u32) -> Self {
+        self.follow_redirects = n;
+        self
+    }
+
+    /// Resolve the URL with query parameters and build the IPC request without sending it.
+    pub fn build(&self) -> std::result::Result<OutgoingHttpRequest, HttpClientError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+        let mut url = url::Url::parse(&self.url).map_err(|_| HttpClientError::BadUrl {
+            url: self.url.clone(),
+        })?;
+        if !self.query.is_empty() {
+            let pairs: Vec<(&str, &str)> = self
+                .query
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect();
+            let encoded = super::encode_query(&pairs);
+            let query = match url.query() {
+                Some(existing) if !existing.is_empty() => format!("{existing}&{encoded}"),
+                _ => encoded,
+            };
+            url.set_query(Some(&query));
+        }
+        Ok(OutgoingHttpRequest {
+            method: self.method.to_string(),
+            version: None,
+            url: url.to_string(),
+            headers: self.headers.clone(),
+        })
+    }
+
+    /// Send the request and await the response. Non-2xx statuses are not errors.
+    pub fn send(self) -> std::result::Result<http::Response<Vec<u8>>, HttpClientError> {
+        let resp = self.send_inner()?;
+        Ok(to_http_response(resp, get_blob().unwrap_or_default().bytes))
+    }
+
+    /// Send the request and write the response body to the file at vfs_path,
+    /// returning its length. The body is handed to vfs without being copied into
+    /// this process. Fails with [`HttpClientError::BadStatus`] on a non-2xx status,
+    /// and with [`HttpClientError::HashMismatch`] if `expected_sha256` is given and
+    /// does not match the written file.
+    ///
+    /// http-client delivers the body in one piece, so `progress` is called once with
+    /// the bytes written and the `Content-Length`, if any, after the file is written.
+    pub fn download_to_file(
+        self,
+        vfs_path: &str,
+        expected_sha256: Option<[u8; 32]>,
+        progress: Option<&mut dyn FnMut(u64, Option<u64>)>,
+    ) -> std::result::Result<u64, HttpClientError> {
+        let timeout = self.timeout;
+        let resp = self.send_inner()?;
+        if !(200..300).contains(&resp.status) {
+            return Err(HttpClientError::BadStatus(resp.status));
+        }
+
+        let action = crate::vfs::VfsAction::Write;
+        let sent = crate::vfs::vfs_request(vfs_path, action.clone())
+            .inherit(true)
+            .send_and_await_response(timeout);
+        match crate::vfs::classified(vfs_path, &action, sent).map_err(HttpClientError::Vfs)? {
+            crate::vfs::VfsResponse::Ok => {}
+            response => return Err(HttpClientError::Vfs(crate::vfs::bad_response(&response))),
+        }
+
+        let len = crate::vfs::metadata(vfs_path, Some(timeout))
+            .map_err(HttpClientError::Vfs)?
+            .len();
+        if let Some(progress) = progress {
+            let total = resp
+                .headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.parse().ok());
+            progress(len, total);
+        }
+        if let Some(expected) = expected_sha256 {
+            let got =
+                crate::vfs::hash_file(vfs_path, Some(timeout)).map_err(HttpClientError::Vfs)?;
+            if got != expected {
+                return Err(HttpClientError::HashMismatch {
+                    expected: alloy_primitives::hex::encode(expected),
+                    got: alloy_primitives::hex::encode(got),
+                });
+            }
+        }
+        Ok(len)
+    }
+
+    /// Send, following redirects as configured, leaving the final body in the blob.
+    fn send_inner(mut self) -> std::result::Result<HttpResponse, HttpClientError> {
+        let mut redirects_left = self.follow_redirects;
+        loop {
+            let request = self.build()?;
+            let res = KiRequest::to(crate::SystemProcess::HttpClient.address(crate::our_node()))
+                .body(
+                    serde_json::to_vec(&HttpClientAction::Http(request.clone()))
+                        .map_err(|_| HttpClientError::MalformedRequest)?,
+                )
+                .blob_bytes(self.body.clone())
+                .send_and_await_response(self.timeout)?;
+            let Ok(Message::Response { body, .. }) = res else {
+                return Err(HttpClientError::Timeout);
+            };
+            let resp = parse_http_response(&body)?;
+
+            if redirects_left == 0 || !(300..400).contains(&resp.status) {
+                return Ok(resp);
+            }
+            let Some(next) = redirect_target(&request.url, &resp) else {
+                return Ok(resp);
+            };
+            redirects_left -= 1;
+            let to_get = resp.status == 303
+                || (matches!(resp.status, 301 | 302) && self.method == Method::POST);
+            if to_get {
+                self.method = Method::GET;
+                self.body = Vec::new();
+            }
+            // the query is already part of the resolved location
+            self.url = next;
+            self.query.clear();
+        }
+    }
+}
+
+/// The absolute URL a redirect response points to, if it has a usable `Location`.
+fn redirect_target(url: &str, resp: &HttpResponse) -> Option<String> {
+    let location = resp
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("location"))?
+        .1;
+    Some(url::Url::parse(url).ok()?.join(location).ok()?.to_string())
+}
+
+/// GET url and await the response.
+pub fn get(url: &str) -> std::result::Result<http::Response<Vec<u8>>, HttpClientError> {
+    ClientRequestBuilder::get(url).send()
+}
+
+/// POST body serialized as JSON to url and await the response.
+pub fn post_json<T: Serialize>(
+    url: &str,
+    body: &T,
+) -> std::result::Result<http::Response<Vec<u8>>, HttpClientError> {
+    ClientRequestBuilder::post(url).json(body).send()
+}
+
+/// PUT body to url and await the response.
+pub fn put<T: Into<Vec<u8>>>(
+    url: &str,
+    body: T,
+) -> std::result::Result<http::Response<Vec<u8>>, HttpClientError> {
+    ClientRequestBuilder::put(url).body(body).send()
+}
+
+/// DELETE url and await the response.
+pub fn delete(url: &str) -> std::result::Result<http::Response<Vec<u8>>, HttpClientError> {
+    ClientRequestBuilder::delete(url).send()
+}
+
+/// GET url and write the response body to the file at vfs_path, returning its length.
+/// See [`ClientRequestBuilder::download_to_file()`] to add progress reporting or
+/// hash verification.
+pub fn download_to_file(url: &str, vfs_path: &str) -> std::result::Result<u64, HttpClientError> {
+    ClientRequestBuilder::get(url).download_to_file(vfs_path, None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_action_json() {
+        let action = HttpClientAction::Http(OutgoingHttpRequest {
+            method: "GET".to_string(),
+            version: None,
+            url: "https://example.com/".to_string(),
+            headers: HashMap::from([("accept".to_string(), "*/*".to_string())]),
+        });
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            serde_json::json!({"Http": {
+                "method": "GET",
+                "version": null,
+                "url": "https://example.com/",
+                "headers": {"accept": "*/*"},
+            }})
+        );
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let resp = parse_http_response(
+            br#"{"Ok":{"Http":{"status":404,"headers":{"content-type":"application/json"}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.status, 404);
+
+        let resp = to_http_response(resp, br#"{"error":"missing"}"#.to_vec());
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        assert_eq!(resp.text().unwrap(), r#"{"error":"missing"}"#);
+        let body: serde_json::Value = resp.json().unwrap();
+        assert_eq!(body["error"], "missing");
+
+        assert!(matches!(
+            parse_http_response(br#"{"Err":{"ExecuteRequestFailed":"dns error"}}"#),
+            Err(HttpClientError::ExecuteRequestFailed(e)) if e == "dns error"
+        ));
+        assert!(matches!(
+            parse_http_response(br#"{"Ok":"WebSocketAck"}"#),
+            Err(HttpClientError::BadResponse(_))
+        ));
+        assert!(matches!(
+            parse_http_response(b"not json"),
+            Err(HttpClientError::BadResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_ws_request() {
+        let push = |message_type: &str| {
+            format!(r#"{{"WebSocketPush":{{"channel_id":9,"message_type":"{message_type}"}}}}"#)
+        };
+        assert_eq!(
+            parse_ws_request(push("Text").as_bytes(), || br#"{"px":1}"#.to_vec()),
+            Some(WsClientEvent::Frame {
+                channel_id: 9,
+                frame: WsFrame::Text(r#"{"px":1}"#.to_string())
+            })
+        );
+        assert_eq!(
+            parse_ws_request(push("Binary").as_bytes(), || vec![0, 1, 2]),
+            Some(WsClientEvent::Frame {
+                channel_id: 9,
+                frame: WsFrame::Binary(vec![0, 1, 2])
+            })
+        );
+        assert_eq!(
+            parse_ws_request(push("Ping").as_bytes(), Vec::new),
+            Some(WsClientEvent::Frame {
+                channel_id: 9,
+                frame: WsFrame::Ping(vec![])
+            })
+        );
+
+        let mut close = 1001u16.to_be_bytes().to_vec();
+        close.extend(b"going away");
+        assert_eq!(
+            parse_ws_request(push("Close").as_bytes(), || close),
+            Some(WsClientEvent::Closed {
+                channel_id: 9,
+                code: Some(1001),
+                reason: Some("going away".to_string())
+            })
+        );
+        assert_eq!(
+            parse_ws_request(br#"{"WebSocketClose":{"channel_id":9}}"#, || {
+                panic!("close has no blob")
+            })
+            .map(|event| event.channel_id()),
+            Some(9)
+        );
+        assert_eq!(
+            parse_ws_request(br#"{"Ok":"WebSocketAck"}"#, Vec::new),
+            None
+        );
+    }
+
+    #[test]
+    fn test_builder_query_encoding() {
+        let request = ClientRequestBuilder::get("https://example.com/search?page=2")
+            .query("q", "two words & more")
+            .query("tag", "ü/é")
+            .build()
+            .unwrap();
+        assert_eq!(
+            request.url,
+            "https://example.com/search?page=2&q=two+words+%26+more&tag=%C3%BC%2F%C3%A9"
+        );
+
+        assert!(matches!(
+            ClientRequestBuilder::get("not a url").build(),
+            Err(HttpClientError::BadUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn test_builder_ipc() {
+        #[derive(Serialize)]
+        struct Item {
+            name: &'static str,
+        }
+        let builder = ClientRequestBuilder::post("https://example.com/items")
+            .json(&Item { name: "a" })
+            .bearer_auth("secret")
+            .header("X-Trace", "1")
+            .timeout(7);
+        assert_eq!(builder.body, br#"{"name":"a"}"#);
+        assert_eq!(builder.timeout, 7);
+        assert_eq!(
+            serde_json::to_value(HttpClientAction::Http(builder.build().unwrap())).unwrap(),
+            serde_json::json!({"Http": {
+                "method": "POST",
+                "version": null,
+                "url": "https://example.com/items",
+                "headers": {
+                    "Content-Type": "application/json",
+                    "Authorization": "Bearer secret",
+                    "X-Trace": "1",
+                },
+            }})
+        );
+    }
+
+    #[test]
+    fn test_redirect_target() {
+        let resp = HttpResponse::new(302u16).header("Location", "/next?x=1");
+        assert_eq!(
+            redirect_target("https://example.com/a/b", &resp).as_deref(),
+            Some("https://example.com/next?x=1")
+        );
+        let resp = HttpResponse::new(301u16).header("location", "https://other.org/");
+        assert_eq!(
+            redirect_target("https://example.com/", &resp).as_deref(),
+            Some("https://other.org/")
+        );
+        assert_eq!(
+            redirect_target("https://example.com/", &HttpResponse::new(302u16)),
+            None
+        );
+    }
+}
@@ -1,3 +1,10 @@
+pub mod cache;
 pub mod client;
+pub mod multipart;
+pub mod query;
+pub mod router;
 pub mod server;
+pub mod session;
+pub use client::{delete, download_to_file, get, post_json, put, ClientRequestBuilder};
 pub use http::{HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode};
+pub use query::*;
@@ -0,0 +1,358 @@
+use super::server::IncomingHttpRequest;
+use crate::vfs::{create_file, VfsClientError};
+use thiserror::Error;
+
+/// A part of a `multipart/form-data` body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// The form field name, from `Content-Disposition`.
+    pub name: String,
+    /// The uploaded file's name, if this part is a file.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum MultipartError {
+    #[error("request is not multipart or has no boundary")]
+    NoBoundary,
+    #[error("malformed multipart body: {0}")]
+    Malformed(&'static str),
+    #[error("unsafe upload filename: {0}")]
+    BadFilename(String),
+    #[error("failed to write upload: {0}")]
+    Vfs(#[from] VfsClientError),
+}
+
+/// Parse the `multipart/form-data` body of an incoming request, which must be
+/// the message currently being handled.
+pub fn parse_multipart(incoming: &IncomingHttpRequest) -> Result<Vec<Part>, MultipartError> {
+    let content_type = request_content_type(incoming)?;
+    parse_multipart_body(&content_type, &incoming.body_bytes())
+}
+
+/// Parse a `multipart/form-data` body given the request's `Content-Type` header.
+pub fn parse_multipart_body(content_type: &str, body: &[u8]) -> Result<Vec<Part>, MultipartError> {
+    let boundary = boundary(content_type).ok_or(MultipartError::NoBoundary)?;
+    Ok(parse_parts(&boundary, body)?
+        .into_iter()
+        .map(|part| Part {
+            name: part.name,
+            filename: part.filename,
+            content_type: part.content_type,
+            bytes: part.bytes.to_vec(),
+        })
+        .collect())
+}
+
+/// Write every file part of the `multipart/form-data` body of an incoming request
+/// into dest_dir, named after the uploaded filename. Returns the path and size of
+/// each file written; non-file fields are skipped.
+///
+/// Parts are written straight out of the request body rather than copied into
+/// [`Part`]s first. Filenames that are empty or `..` are rejected, and any
+/// directories in filenames are dropped.
+pub fn multipart_to_vfs(
+    incoming: &IncomingHttpRequest,
+    dest_dir: &str,
+) -> Result<Vec<(String, u64)>, MultipartError> {
+    let content_type = request_content_type(incoming)?;
+    let boundary = boundary(&content_type).ok_or(MultipartError::NoBoundary)?;
+    let body = incoming.body_bytes();
+
+    let mut written = vec![];
+    for part in parse_parts(&boundary, &body)? {
+        let Some(filename) = part.filename else {
+            continue;
+        };
+        let Some(name) = safe_filename(&filename) else {
+            return Err(MultipartError::BadFilename(filename));
+        };
+        let path = format!("{}/{}", dest_dir.trim_end_matches('/'), name);
+        create_file(&path, None).and_then(|file| file.write(part.bytes))?;
+        written.push((path, part.bytes.len() as u64));
+    }
+    Ok(written)
+}
+
+struct PartRef<'a> {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    bytes: &'a [u8],
+}
+
+fn request_content_type(incoming: &IncomingHttpRequest) -> Result<String, MultipartError> {
+    incoming
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .ok_or(MultipartError::NoBoundary)
+}
+
+/// The boundary parameter of a `multipart/*` content type.
+fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+    header_params(params)
+        .into_iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value)
+        .filter(|boundary| !boundary.is_empty())
+}
+
+fn parse_parts<'a>(boundary: &str, body: &'a [u8]) -> Result<Vec<PartRef<'a>>, MultipartError> {
+    let delimiter = format!("--{boundary}");
+    let delimiter = delimiter.as_bytes();
+
+    // skip any preamble before the first delimiter
+    let mut pos = if body.starts_with(delimiter) {
+        0
+    } else {
+        find_delimiter(body, delimiter, 0).ok_or(MultipartError::Malformed("no boundary"))? + 2
+    };
+
+    let mut parts = vec![];
+    loop {
+        pos += delimiter.len();
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        pos += line_end(rest).ok_or(MultipartError::Malformed("bad boundary line"))?;
+
+        let headers_end = find(body, b"\r\n\r\n", pos)
+            .ok_or(MultipartError::Malformed("unterminated headers"))?;
+        let headers = std::str::from_utf8(&body[pos..headers_end])
+            .map_err(|_| MultipartError::Malformed("non-UTF-8 headers"))?;
+        let content_start = headers_end + 4;
+        let content_end = find_delimiter(body, delimiter, content_start - 2)
+            .ok_or(MultipartError::Malformed("unterminated part"))?;
+
+        let mut part = PartRef {
+            name: String::new(),
+            filename: None,
+            content_type: None,
+            bytes: &body[content_start.min(content_end)..content_end],
+        };
+        let mut has_disposition = false;
+        for header in headers.split("\r\n").filter(|h| !h.is_empty()) {
+            let (key, value) = header
+                .split_once(':')
+                .ok_or(MultipartError::Malformed("bad part header"))?;
+            if key.trim().eq_ignore_ascii_case("content-disposition") {
+                has_disposition = true;
+                let params = value.split_once(';').map(|(_, p)| p).unwrap_or_default();
+                for (key, value) in header_params(params) {
+                    match key.to_ascii_lowercase().as_str() {
+                        "name" => part.name = value,
+                        "filename" => part.filename = Some(value),
+                        _ => {}
+                    }
+                }
+            } else if key.trim().eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+        if !has_disposition {
+            return Err(MultipartError::Malformed(
+                "part without Content-Disposition",
+            ));
+        }
+        parts.push(part);
+        // the CRLF before the delimiter belongs to the delimiter
+        pos = content_end + 2;
+    }
+}
+
+/// Find `\r\n` followed by the delimiter and then `--`, whitespace, or a line end,
+/// so content that merely starts like the delimiter is not cut short.
+/// Returns the position of the `\r\n`.
+fn find_delimiter(body: &[u8], delimiter: &[u8], from: usize) -> Option<usize> {
+    let mut from = from;
+    loop {
+        let at = find(body, b"\r\n", from)?;
+        let after = &body[at + 2..];
+        if after.starts_with(delimiter) {
+            let tail = &after[delimiter.len()..];
+            if tail.starts_with(b"--") || line_end(tail).is_some() {
+                return Some(at);
+            }
+        }
+        from = at + 1;
+    }
+}
+
+/// Length of optional transport padding followed by `\r\n` at the start of bytes.
+fn line_end(bytes: &[u8]) -> Option<usize> {
+    let padding = bytes
+        .iter()
+        .take_while(|&&b| b == b' ' || b == b'\t')
+        .count();
+    bytes[padding..].starts_with(b"\r\n").then_some(padding + 2)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| i + from)
+}
+
+/// Parse `; key=value; key="quoted \"value\""` header parameters.
+fn header_params(params: &str) -> Vec<(String, String)> {
+    let mut result = vec![];
+    let mut chars = params.chars().peekable();
+    loop {
+        while matches!(chars.peek(), Some(';') | Some(' ') | Some('\t')) {
+            chars.next();
+        }
+        let key: String = chars
+            .by_ref()
+            .take_while(|&c| c != '=')
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if key.is_empty() {
+            return result;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            // drop anything between the closing quote and the next parameter
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars
+                .by_ref()
+                .take_while(|&c| c != ';')
+                .collect::<String>()
+                .trim()
+                .to_string();
+        }
+        result.push((key, value));
+    }
+}
+
+/// The last path component of an uploaded filename, if it is safe to write.
+fn safe_filename(filename: &str) -> Option<&str> {
+    let name = filename.rsplit(['/', '\\']).next()?;
+    (!name.is_empty() && name != "." && name != ".." && !name.contains('\0')).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARY: &str = "----WebKitFormBoundary7MA4YWxkTrZu0gW";
+
+    fn fixture() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend(b"preamble to ignore\r\n");
+        body.extend(format!("--{BOUNDARY}\r\n").as_bytes());
+        body.extend(b"Content-Disposition: form-data; name=\"title\"\r\n\r\n");
+        body.extend(b"hello world\r\n");
+        body.extend(format!("--{BOUNDARY}\r\n").as_bytes());
+        body.extend(
+            b"Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"quoted\\\"; name.bin\"\r\n",
+        );
+        body.extend(b"Content-Type: application/octet-stream\r\n\r\n");
+        body.extend([0x00, 0xff, b'\r', b'\n', 0x10, b'-', b'-']);
+        body.extend(b"\r\n");
+        body.extend(format!("--{BOUNDARY}\r\n").as_bytes());
+        body.extend(b"Content-Disposition: form-data; name=\"notes\"\r\n");
+        body.extend(b"Content-Type: text/plain\r\n\r\n");
+        body.extend(format!("line one\r\n--{BOUNDARY}-not-really\r\nline three").as_bytes());
+        body.extend(format!("\r\n--{BOUNDARY}--\r\nepilogue").as_bytes());
+        body
+    }
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(
+            boundary(&format!("multipart/form-data; boundary={BOUNDARY}")).as_deref(),
+            Some(BOUNDARY)
+        );
+        assert_eq!(
+            boundary("multipart/form-data; charset=utf-8; boundary=\"a b;c\"").as_deref(),
+            Some("a b;c")
+        );
+        assert_eq!(boundary("application/json; boundary=x"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+    }
+
+    #[test]
+    fn test_parse_multipart_body() {
+        let content_type = format!("multipart/form-data; boundary=\"{BOUNDARY}\"");
+        let parts = parse_multipart_body(&content_type, &fixture()).unwrap();
+        assert_eq!(parts.len(), 3);
+
+        assert_eq!(parts[0].name, "title");
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].content_type, None);
+        assert_eq!(parts[0].bytes, b"hello world");
+
+        assert_eq!(parts[1].name, "upload");
+        assert_eq!(parts[1].filename.as_deref(), Some("a \"quoted\"; name.bin"));
+        assert_eq!(
+            parts[1].content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(parts[1].bytes, [0x00, 0xff, b'\r', b'\n', 0x10, b'-', b'-']);
+
+        assert_eq!(parts[2].name, "notes");
+        assert_eq!(
+            parts[2].bytes,
+            format!("line one\r\n--{BOUNDARY}-not-really\r\nline three").as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_parse_multipart_body_errors() {
+        let content_type = format!("multipart/form-data; boundary={BOUNDARY}");
+        assert!(matches!(
+            parse_multipart_body("text/plain", b""),
+            Err(MultipartError::NoBoundary)
+        ));
+        assert!(matches!(
+            parse_multipart_body(&content_type, b"no delimiters here"),
+            Err(MultipartError::Malformed(_))
+        ));
+        let unterminated =
+            format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nnever ends");
+        assert!(matches!(
+            parse_multipart_body(&content_type, unterminated.as_bytes()),
+            Err(MultipartError::Malformed(_))
+        ));
+        let empty = format!("--{BOUNDARY}--\r\n");
+        assert!(parse_multipart_body(&content_type, empty.as_bytes())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_safe_filename() {
+        assert_eq!(safe_filename("photo.png"), Some("photo.png"));
+        assert_eq!(safe_filename("C:\\Users\\me\\photo.png"), Some("photo.png"));
+        assert_eq!(safe_filename("../../etc/passwd"), Some("passwd"));
+        assert_eq!(safe_filename("dir/.."), None);
+        assert_eq!(safe_filename(""), None);
+        assert_eq!(safe_filename("dir/"), None);
+    }
+}
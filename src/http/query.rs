@@ -0,0 +1,158 @@
+use super::server::IncomingHttpRequest;
+
+/// `Content-Type` of a body built with [`form_urlencoded_body()`].
+pub const FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+
+/// Encode key-value pairs as a query string (without the leading `?`), in order,
+/// using the `application/x-www-form-urlencoded` rules: spaces become `+` and
+/// everything except ASCII alphanumerics and `*-._` is percent-encoded.
+pub fn encode_query(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| format!("{}={}", encode_component(key), encode_component(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Parse a query string (with or without the leading `?`) into key-value pairs, in order.
+/// Repeated keys are all kept, `+` decodes to a space, and a key without `=` has an
+/// empty value. Malformed percent-escapes are kept as-is, and invalid UTF-8 is replaced.
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .strip_prefix('?')
+        .unwrap_or(query)
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_component(key), decode_component(value))
+        })
+        .collect()
+}
+
+/// Build a form body from key-value pairs. Returns the body and its `Content-Type`.
+pub fn form_urlencoded_body(pairs: &[(&str, &str)]) -> (Vec<u8>, &'static str) {
+    (encode_query(pairs).into_bytes(), FORM_URLENCODED)
+}
+
+/// Parse the `application/x-www-form-urlencoded` body of an incoming request,
+/// which must be the message currently being handled. See [`parse_query()`].
+pub fn parse_form_body(request: &IncomingHttpRequest) -> Vec<(String, String)> {
+    parse_query(&String::from_utf8_lossy(&request.body_bytes()))
+}
+
+fn encode_component(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn decode_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(encode_query(&[]), "");
+        assert_eq!(encode_query(&[("a", "1"), ("b", "2")]), "a=1&b=2");
+        assert_eq!(encode_query(&[("q", "two words")]), "q=two+words");
+        assert_eq!(encode_query(&[("empty", "")]), "empty=");
+        assert_eq!(
+            encode_query(&[("reserved", "&=?#/:;+,%")]),
+            "reserved=%26%3D%3F%23%2F%3A%3B%2B%2C%25"
+        );
+        assert_eq!(encode_query(&[("safe", "aZ09*-._")]), "safe=aZ09*-._");
+        assert_eq!(encode_query(&[("ü", "日本")]), "%C3%BC=%E6%97%A5%E6%9C%AC");
+        assert_eq!(encode_query(&[("k", "v"), ("k", "w")]), "k=v&k=w");
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(parse_query(""), vec![]);
+        assert_eq!(parse_query("?"), vec![]);
+        assert_eq!(parse_query("?a=1&b=2"), owned(&[("a", "1"), ("b", "2")]));
+        assert_eq!(parse_query("q=two+words"), owned(&[("q", "two words")]));
+        assert_eq!(
+            parse_query("q=two%20words%2Bplus"),
+            owned(&[("q", "two words+plus")])
+        );
+        assert_eq!(
+            parse_query("k=v&k=w&k"),
+            owned(&[("k", "v"), ("k", "w"), ("k", "")])
+        );
+        assert_eq!(parse_query("a=&=b&&"), owned(&[("a", ""), ("", "b")]));
+        assert_eq!(parse_query("eq=a=b"), owned(&[("eq", "a=b")]));
+        assert_eq!(
+            parse_query("%C3%BC=%E6%97%A5%E6%9C%AC"),
+            owned(&[("ü", "日本")])
+        );
+        assert_eq!(parse_query("u=ü"), owned(&[("u", "ü")]));
+        // malformed escapes are kept literally, invalid UTF-8 is replaced
+        assert_eq!(
+            parse_query("bad=%zz%4&trail=%"),
+            owned(&[("bad", "%zz%4"), ("trail", "%")])
+        );
+        assert_eq!(parse_query("x=%FF"), owned(&[("x", "\u{FFFD}")]));
+    }
+
+    #[test]
+    fn test_query_round_trip() {
+        let pairs = [
+            ("name", "Ada Lovelace"),
+            ("tags", "a&b=c"),
+            ("path", "/x/y?z#w"),
+            ("emoji", "🦀 + 🐍"),
+            ("", ""),
+        ];
+        assert_eq!(parse_query(&encode_query(&pairs)), owned(&pairs));
+    }
+
+    #[test]
+    fn test_form_urlencoded_body() {
+        let (body, content_type) = form_urlencoded_body(&[("user", "a b"), ("pw", "p&ss")]);
+        assert_eq!(body, b"user=a+b&pw=p%26ss");
+        assert_eq!(content_type, "application/x-www-form-urlencoded");
+    }
+}
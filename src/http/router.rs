@@ -0,0 +1,384 @@
+use super::server::{
+    error_response, HttpBindingConfig, HttpResponse, HttpServer, HttpServerError,
+    IncomingHttpRequest, StatusCode,
+};
+use crate::{LazyLoadBlob as KiBlob, Response as KiResponse};
+use std::collections::HashMap;
+
+/// What a [`Router`] handler is given besides the request: the values of the
+/// route's `:param` and `*rest` segments, percent-decoded, by name.
+pub type RouteParams = HashMap<String, String>;
+
+type RouteHandler =
+    Box<dyn FnMut(IncomingHttpRequest, RouteParams) -> (HttpResponse, Option<KiBlob>)>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+impl Segment {
+    /// How general the segment is: when several routes match a path, the one
+    /// whose segments are least general, from the left, wins.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Rest(_) => 2,
+        }
+    }
+}
+
+struct Route {
+    method: String,
+    path: String,
+    segments: Vec<Segment>,
+    handler: RouteHandler,
+}
+
+/// How a request's method and path matched the routes of a [`Router`].
+#[derive(Debug, PartialEq)]
+enum RouteMatch {
+    Found {
+        index: usize,
+        params: RouteParams,
+    },
+    /// Some route has the path, but none the method; these methods have it.
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+    /// The path has a percent-escape that is not valid UTF-8.
+    BadPath,
+}
+
+/// Routes incoming HTTP requests to handlers by method and path, e.g.
+/// `("GET", "/api/items/:id")`, so a process need not match on raw paths.
+///
+/// Paths are relative to the process, as [`IncomingHttpRequest::path()`]
+/// returns them. A `:name` segment matches any one segment, and a `*name`
+/// segment, which must come last, matches the rest of the path, possibly
+/// nothing. Segments are percent-decoded before matching, so an encoded `/`
+/// stays within its segment. Empty segments, as from a trailing slash, are
+/// ignored: `/api/items/` is `/api/items`.
+///
+/// Where several routes match, literal segments beat `:name`, which beats
+/// `*name`, from the left, whatever the order the routes were added in. A path
+/// some route matches under another method gets a 405 with an `Allow` header,
+/// and one no route matches a 404.
+///
+/// ```no_run
+/// use hyperware_process_lib::http::server::{HttpBindingConfig, HttpResponse, HttpServer, Router, StatusCode};
+/// use hyperware_process_lib::LazyLoadBlob;
+///
+/// let mut router = Router::new()
+///     .route("GET", "/api/items/:id", |_request, params| {
+///         let body = format!("item {}", params["id"]).into_bytes();
+///         (HttpResponse::new(StatusCode::OK), Some(LazyLoadBlob::new(None::<String>, body)))
+///     })
+///     .route("POST", "/api/items", |_request, _params| {
+///         (HttpResponse::new(StatusCode::CREATED), None)
+///     });
+/// let mut server = HttpServer::new(5);
+/// router.bind(&mut server, HttpBindingConfig::default()).unwrap();
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router { routes: vec![] }
+    }
+
+    /// Add a route for method (case-insensitive) and path, with `:name` and
+    /// `*name` segments as described on [`Router`].
+    ///
+    /// Panics if a `*name` segment is not last, or a segment has no name.
+    pub fn route<F>(mut self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: FnMut(IncomingHttpRequest, RouteParams) -> (HttpResponse, Option<KiBlob>) + 'static,
+    {
+        let segments = parse_route(path).unwrap_or_else(|e| panic!("Router: route {path}: {e}"));
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            path: format!("/{}", path.trim_matches('/')),
+            segments,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// The distinct paths of the routes, in the order they were added.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = vec![];
+        for route in &self.routes {
+            if !paths.contains(&route.path) {
+                paths.push(route.path.clone());
+            }
+        }
+        paths
+    }
+
+    /// Bind every path of the routes with server, all with config. Call once at
+    /// startup, after adding the routes. As with
+    /// [`HttpServer::bind_multiple_http_paths()`], either all paths are bound
+    /// or none are.
+    pub fn bind(
+        &self,
+        server: &mut HttpServer,
+        config: HttpBindingConfig,
+    ) -> Result<(), HttpServerError> {
+        server.bind_multiple_http_paths(self.paths(), config)
+    }
+
+    /// Run the handler of the route request matches and return its response,
+    /// or a 404, 405 or 400 response. Suits [`HttpServer::handle_request()`]:
+    /// `server.handle_request(request, |request| router.respond(request), ...)`.
+    pub fn respond(&mut self, request: IncomingHttpRequest) -> (HttpResponse, Option<KiBlob>) {
+        let (Ok(method), Ok(path)) = (request.method(), request.path()) else {
+            return error_response(StatusCode::BAD_REQUEST);
+        };
+        match self.find(method.as_str(), &path) {
+            RouteMatch::Found { index, params } => (self.routes[index].handler)(request, params),
+            RouteMatch::MethodNotAllowed(allowed) => {
+                let (response, blob) = error_response(StatusCode::METHOD_NOT_ALLOWED);
+                (response.header("Allow", allowed.join(", ")), blob)
+            }
+            RouteMatch::NotFound => error_response(StatusCode::NOT_FOUND),
+            RouteMatch::BadPath => error_response(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    /// Respond to request, which must be the message currently being handled,
+    /// as [`Router::respond()`] does.
+    pub fn handle(&mut self, request: IncomingHttpRequest) -> anyhow::Result<()> {
+        let (response, blob) = self.respond(request);
+        let response = KiResponse::new().body(serde_json::to_vec(&response)?);
+        match blob {
+            Some(blob) => response.blob(blob).send()?,
+            None => response.send()?,
+        }
+        Ok(())
+    }
+
+    fn find(&self, method: &str, path: &str) -> RouteMatch {
+        let Some(segments) = split_path(path) else {
+            return RouteMatch::BadPath;
+        };
+        let mut best: Option<(Vec<u8>, usize, RouteParams)> = None;
+        let mut allowed: Vec<String> = vec![];
+        for (index, route) in self.routes.iter().enumerate() {
+            let Some(params) = match_segments(&route.segments, &segments) else {
+                continue;
+            };
+            if route.method != method {
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+                continue;
+            }
+            let rank: Vec<u8> = route.segments.iter().map(Segment::rank).collect();
+            if best.as_ref().is_none_or(|(best, _, _)| rank < *best) {
+                best = Some((rank, index, params));
+            }
+        }
+        match best {
+            Some((_, index, params)) => RouteMatch::Found { index, params },
+            None if !allowed.is_empty() => RouteMatch::MethodNotAllowed(allowed),
+            None => RouteMatch::NotFound,
+        }
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+fn parse_route(path: &str) -> Result<Vec<Segment>, String> {
+    let raw: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut segments = vec![];
+    for (i, segment) in raw.iter().enumerate() {
+        let segment = if let Some(name) = segment.strip_prefix(':') {
+            Segment::Param(name.to_string())
+        } else if let Some(name) = segment.strip_prefix('*') {
+            if i + 1 != raw.len() {
+                return Err(format!("*{name} must be the last segment"));
+            }
+            Segment::Rest(name.to_string())
+        } else {
+            Segment::Literal(segment.to_string())
+        };
+        if let Segment::Param(name) | Segment::Rest(name) = &segment {
+            if name.is_empty() {
+                return Err("segment without a name".to_string());
+            }
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+/// The percent-decoded, non-empty segments of path, or `None` if one does not
+/// decode to UTF-8.
+fn split_path(path: &str) -> Option<Vec<String>> {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            percent_encoding::percent_decode_str(s)
+                .decode_utf8()
+                .ok()
+                .map(|s| s.into_owned())
+        })
+        .collect()
+}
+
+fn match_segments(route: &[Segment], path: &[String]) -> Option<RouteParams> {
+    let mut params = RouteParams::new();
+    for (i, segment) in route.iter().enumerate() {
+        match segment {
+            Segment::Rest(name) => {
+                params.insert(name.clone(), path[i..].join("/"));
+                return Some(params);
+            }
+            Segment::Literal(literal) if path.get(i) != Some(literal) => return None,
+            Segment::Literal(_) => {}
+            Segment::Param(name) => {
+                params.insert(name.clone(), path.get(i)?.clone());
+            }
+        }
+    }
+    (route.len() == path.len()).then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(routes: &[(&str, &str)]) -> Router {
+        routes.iter().fold(Router::new(), |router, (method, path)| {
+            router.route(method, path, |_, _| {
+                (HttpResponse::new(StatusCode::OK), None)
+            })
+        })
+    }
+
+    fn found(index: usize, params: &[(&str, &str)]) -> RouteMatch {
+        RouteMatch::Found {
+            index,
+            params: params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_overlapping_routes() {
+        let router = router(&[
+            ("GET", "/static/*path"),
+            ("GET", "/api/items/:id"),
+            ("GET", "/api/items/new"),
+            ("GET", "/api/:kind/:id"),
+            ("POST", "/api/items"),
+        ]);
+        // the literal wins although added after the parameter
+        assert_eq!(router.find("GET", "/api/items/new"), found(2, &[]));
+        assert_eq!(router.find("GET", "/api/items/7"), found(1, &[("id", "7")]));
+        assert_eq!(
+            router.find("GET", "/api/users/7"),
+            found(3, &[("kind", "users"), ("id", "7")])
+        );
+        assert_eq!(
+            router.find("GET", "/static/js/app.js"),
+            found(0, &[("path", "js/app.js")])
+        );
+        assert_eq!(router.find("GET", "/static"), found(0, &[("path", "")]));
+        assert_eq!(router.find("POST", "/api/items"), found(4, &[]));
+        assert_eq!(
+            router.find("DELETE", "/api/items/7"),
+            RouteMatch::MethodNotAllowed(vec!["GET".to_string()])
+        );
+        assert_eq!(
+            router.find("GET", "/api/items"),
+            RouteMatch::MethodNotAllowed(vec!["POST".to_string()])
+        );
+        assert_eq!(
+            router.find("GET", "/api/items/7/extra"),
+            RouteMatch::NotFound
+        );
+        assert_eq!(router.find("GET", "/"), RouteMatch::NotFound);
+        assert_eq!(
+            router.paths(),
+            [
+                "/static/*path",
+                "/api/items/:id",
+                "/api/items/new",
+                "/api/:kind/:id",
+                "/api/items"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trailing_slashes() {
+        let router = router(&[("GET", "/api/items/"), ("GET", "/"), ("GET", "/a/:b")]);
+        assert_eq!(router.find("GET", "/api/items"), found(0, &[]));
+        assert_eq!(router.find("GET", "/api/items/"), found(0, &[]));
+        assert_eq!(router.find("GET", "/"), found(1, &[]));
+        assert_eq!(router.find("GET", ""), found(1, &[]));
+        assert_eq!(router.find("GET", "/a/x/"), found(2, &[("b", "x")]));
+        // a parameter never matches an empty segment
+        assert_eq!(router.find("GET", "/a/"), RouteMatch::NotFound);
+        assert_eq!(router.paths(), ["/api/items", "/", "/a/:b"]);
+    }
+
+    #[test]
+    fn test_percent_encoded_segments() {
+        let router = router(&[("GET", "/files/:name"), ("GET", "/my dir/*rest")]);
+        assert_eq!(
+            router.find("GET", "/files/a%2Fb%20c"),
+            found(0, &[("name", "a/b c")])
+        );
+        assert_eq!(
+            router.find("GET", "/my%20dir/x%3Fy/z"),
+            found(1, &[("rest", "x?y/z")])
+        );
+        assert_eq!(router.find("GET", "/files/%ff"), RouteMatch::BadPath);
+    }
+
+    #[test]
+    fn test_respond() {
+        let mut router = Router::new()
+            .route("get", "/api/items/:id", |request, params| {
+                assert_eq!(request.query_params()["limit"], "5");
+                let body = params["id"].clone().into_bytes();
+                (
+                    HttpResponse::new(StatusCode::OK),
+                    Some(KiBlob::new(None::<String>, body)),
+                )
+            })
+            .route("DELETE", "/api/items/:id", |_, _| {
+                (HttpResponse::new(StatusCode::NO_CONTENT), None)
+            });
+        let request = |method: &str, path: &str| {
+            let json = format!(
+                r#"{{"source_socket_addr":null,"method":"{method}","url":"http://localhost:8080/app:app:sys{path}?limit=5","bound_path":"/app:app:sys/api/items/:id","headers":{{}},"url_params":{{}},"query_params":{{"limit":"5"}}}}"#
+            );
+            serde_json::from_str::<IncomingHttpRequest>(&json).unwrap()
+        };
+
+        let (response, blob) = router.respond(request("GET", "/api/items/42"));
+        assert_eq!(
+            (response.status, blob.unwrap().bytes),
+            (200, b"42".to_vec())
+        );
+        let (response, _) = router.respond(request("POST", "/api/items/42"));
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers["Allow"], "GET, DELETE");
+        let (response, _) = router.respond(request("GET", "/nope"));
+        assert_eq!(response.status, 404);
+    }
+}
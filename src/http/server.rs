@@ -1,3 +1,6 @@
+pub use super::multipart::{multipart_to_vfs, parse_multipart, MultipartError, Part};
+pub use super::router::{RouteParams, Router};
+pub use super::session::{Cookies, SameSite, SessionData, SessionStore, SetCookie};
 use crate::vfs::{FileType, VfsAction, VfsRequest, VfsResponse};
 use crate::{
     get_blob, last_blob, LazyLoadBlob as KiBlob, Message, Request as KiRequest,
@@ -129,6 +132,12 @@ impl IncomingHttpRequest {
     pub fn query_params(&self) -> &HashMap<String, String> {
         &self.query_params
     }
+
+    /// The request body, from the blob of the message currently being handled.
+    /// Empty if the request had no body.
+    pub fn body_bytes(&self) -> Vec<u8> {
+        get_blob().unwrap_or_default().bytes
+    }
 }
 
 /// The possible message types for [`HttpServerRequest::WebSocketPush`].
@@ -284,6 +293,9 @@ pub enum HttpServerError {
     /// Not actually issued by `http-server:distro:sys`, just this library
     #[error("unexpected response from http-server")]
     UnexpectedResponse,
+    /// Not actually issued by `http-server:distro:sys`, just this library
+    #[error("failed to send: {0}")]
+    Build(#[from] crate::BuildError),
 }
 
 /// Whether the [`HttpServerAction::WebSocketPush`] is [`crate::Request`] or [`crate::Response`].
@@ -466,7 +478,7 @@ impl HttpServer {
     {
         let path: String = path.into();
         let cache = config.static_content.is_some();
-        let req = KiRequest::to(("our", "http-server", "distro", "sys")).body(
+        let req = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node())).body(
             serde_json::to_vec(&if config.secure_subdomain {
                 HttpServerAction::SecureBind {
                     path: path.clone(),
@@ -488,7 +500,7 @@ impl HttpServer {
                 .send_and_await_response(self.timeout),
             None => req.send_and_await_response(self.timeout),
         };
-        let Ok(Message::Response { body, .. }) = res.unwrap() else {
+        let Ok(Message::Response { body, .. }) = res? else {
             return Err(HttpServerError::Timeout);
         };
         let Ok(resp) = serde_json::from_slice::<Result<(), HttpServerError>>(&body) else {
@@ -510,7 +522,7 @@ impl HttpServer {
         T: Into<String>,
     {
         let path: String = path.into();
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(if config.secure_subdomain {
                 serde_json::to_vec(&HttpServerAction::WebSocketSecureBind {
                     path: path.clone(),
@@ -526,7 +538,7 @@ impl HttpServer {
                 .unwrap()
             })
             .send_and_await_response(self.timeout);
-        let Ok(Message::Response { body, .. }) = res.unwrap() else {
+        let Ok(Message::Response { body, .. }) = res? else {
             return Err(HttpServerError::Timeout);
         };
         let Ok(resp) = serde_json::from_slice::<Result<(), HttpServerError>>(&body) else {
@@ -552,7 +564,7 @@ impl HttpServer {
         T: Into<String>,
     {
         let path: String = path.into();
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&HttpServerAction::Bind {
                     path: path.clone(),
@@ -566,8 +578,7 @@ impl HttpServer {
                 mime: content_type.clone(),
                 bytes: content.clone(),
             })
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
         let Ok(Message::Response { body, .. }) = res else {
             return Err(HttpServerError::Timeout);
         };
@@ -603,7 +614,7 @@ impl HttpServer {
         T: Into<String>,
     {
         let path: String = path.into();
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&HttpServerAction::SecureBind {
                     path: path.clone(),
@@ -611,8 +622,7 @@ impl HttpServer {
                 })
                 .unwrap(),
             )
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
         let Ok(Message::Response { body, .. }) = res else {
             return Err(HttpServerError::Timeout);
         };
@@ -645,7 +655,7 @@ impl HttpServer {
         T: Into<String>,
     {
         let path: String = path.into();
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&HttpServerAction::WebSocketSecureBind {
                     path: path.clone(),
@@ -654,7 +664,7 @@ impl HttpServer {
                 .unwrap(),
             )
             .send_and_await_response(self.timeout);
-        let Ok(Message::Response { body, .. }) = res.unwrap() else {
+        let Ok(Message::Response { body, .. }) = res? else {
             return Err(HttpServerError::Timeout);
         };
         let Ok(resp) = serde_json::from_slice::<Result<(), HttpServerError>>(&body) else {
@@ -686,7 +696,7 @@ impl HttpServer {
             .http_paths
             .get_mut(path)
             .ok_or(HttpServerError::MalformedRequest)?;
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&HttpServerAction::Bind {
                     path: path.to_string(),
@@ -696,8 +706,7 @@ impl HttpServer {
                 })
                 .unwrap(),
             )
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
         let Ok(Message::Response { body, .. }) = res else {
             return Err(HttpServerError::Timeout);
         };
@@ -723,7 +732,7 @@ impl HttpServer {
             .ws_paths
             .get_mut(path)
             .ok_or(HttpServerError::MalformedRequest)?;
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(if entry.secure_subdomain {
                 serde_json::to_vec(&HttpServerAction::WebSocketSecureBind {
                     path: path.to_string(),
@@ -738,8 +747,7 @@ impl HttpServer {
                 })
                 .unwrap()
             })
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
         let Ok(Message::Response { body, .. }) = res else {
             return Err(HttpServerError::Timeout);
         };
@@ -760,10 +768,9 @@ impl HttpServer {
         T: Into<String>,
     {
         let path: String = path.into();
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(serde_json::to_vec(&HttpServerAction::Unbind { path: path.clone() }).unwrap())
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
         let Ok(Message::Response { body, .. }) = res else {
             return Err(HttpServerError::Timeout);
         };
@@ -782,13 +789,12 @@ impl HttpServer {
         T: Into<String>,
     {
         let path: String = path.into();
-        let res = KiRequest::to(("our", "http-server", "distro", "sys"))
+        let res = KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&HttpServerAction::WebSocketUnbind { path: path.clone() })
                     .unwrap(),
             )
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
         let Ok(Message::Response { body, .. }) = res else {
             return Err(HttpServerError::Timeout);
         };
@@ -814,7 +820,7 @@ impl HttpServer {
         config: HttpBindingConfig,
     ) -> Result<(), HttpServerError> {
         let our = crate::our();
-        let _res = KiRequest::to(("our", "vfs", "distro", "sys"))
+        let _res = KiRequest::to(crate::SystemProcess::Vfs.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&VfsRequest {
                     path: format!(
@@ -826,8 +832,7 @@ impl HttpServer {
                 })
                 .map_err(|_| HttpServerError::MalformedRequest)?,
             )
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
 
         let Some(mut blob) = get_blob() else {
             return Err(HttpServerError::NoBlob);
@@ -853,7 +858,7 @@ impl HttpServer {
         paths: Vec<&str>,
         config: HttpBindingConfig,
     ) -> Result<(), HttpServerError> {
-        let _res = KiRequest::to(("our", "vfs", "distro", "sys"))
+        let _res = KiRequest::to(crate::SystemProcess::Vfs.address(crate::our_node()))
             .body(
                 serde_json::to_vec(&VfsRequest {
                     path: file_path.to_string(),
@@ -861,8 +866,7 @@ impl HttpServer {
                 })
                 .map_err(|_| HttpServerError::MalformedRequest)?,
             )
-            .send_and_await_response(self.timeout)
-            .unwrap();
+            .send_and_await_response(self.timeout)?;
 
         let Some(mut blob) = get_blob() else {
             return Err(HttpServerError::NoBlob);
@@ -898,16 +902,16 @@ impl HttpServer {
         queue.push_back(initial_path.clone());
 
         while let Some(path) = queue.pop_front() {
-            let Ok(directory_response) = KiRequest::to(("our", "vfs", "distro", "sys"))
-                .body(
-                    serde_json::to_vec(&VfsRequest {
-                        path,
-                        action: VfsAction::ReadDir,
-                    })
-                    .unwrap(),
-                )
-                .send_and_await_response(self.timeout)
-                .unwrap()
+            let Ok(directory_response) =
+                KiRequest::to(crate::SystemProcess::Vfs.address(crate::our_node()))
+                    .body(
+                        serde_json::to_vec(&VfsRequest {
+                            path,
+                            action: VfsAction::ReadDir,
+                        })
+                        .unwrap(),
+                    )
+                    .send_and_await_response(self.timeout)?
             else {
                 return Err(HttpServerError::MalformedRequest);
             };
@@ -982,15 +986,15 @@ impl HttpServer {
         server_request: HttpServerRequest,
         mut http_handler: impl FnMut(IncomingHttpRequest) -> (HttpResponse, Option<KiBlob>),
         mut ws_handler: impl FnMut(u32, WsMessageType, KiBlob),
-    ) {
+    ) -> Result<(), HttpServerError> {
         match server_request {
             HttpServerRequest::Http(http_request) => {
                 let (response, blob) = http_handler(http_request);
                 let response = KiResponse::new().body(serde_json::to_vec(&response).unwrap());
                 if let Some(blob) = blob {
-                    response.blob(blob).send().unwrap();
+                    response.blob(blob).send()?;
                 } else {
-                    response.send().unwrap();
+                    response.send()?;
                 }
             }
             HttpServerRequest::WebSocketPush {
@@ -1004,11 +1008,17 @@ impl HttpServer {
                 self.handle_websocket_close(channel_id);
             }
         }
+        Ok(())
     }
 
     /// Push a WebSocket message to all channels on a given path.
-    pub fn ws_push_all_channels(&self, path: &str, message_type: WsMessageType, blob: KiBlob) {
-        ws_push_all_channels(&self.ws_channels, path, message_type, blob);
+    pub fn ws_push_all_channels(
+        &self,
+        path: &str,
+        message_type: WsMessageType,
+        blob: KiBlob,
+    ) -> Result<(), HttpServerError> {
+        ws_push_all_channels(&self.ws_channels, path, message_type, blob)
     }
 
     pub fn get_ws_channels(&self) -> HashMap<String, HashSet<u32>> {
@@ -1051,8 +1061,33 @@ impl HttpServer {
     }
 }
 
+/// Bind a path with `http-server:distro:sys` without keeping an [`HttpServer`] around.
+/// Requests on the path will be forwarded to this process as [`HttpServerRequest::Http`].
+pub fn bind_path<T>(path: T, authenticated: bool, local_only: bool) -> Result<(), HttpServerError>
+where
+    T: Into<String>,
+{
+    HttpServer::new(5).bind_http_path(
+        path,
+        HttpBindingConfig::new(authenticated, local_only, false, None),
+    )
+}
+
+/// Bind a WebSocket path with `http-server:distro:sys` without keeping an [`HttpServer`] around.
+/// Connections will arrive as [`HttpServerRequest::WebSocketOpen`].
+pub fn bind_ws_path<T>(path: T, authenticated: bool, extension: bool) -> Result<(), HttpServerError>
+where
+    T: Into<String>,
+{
+    HttpServer::new(5).bind_ws_path(path, WsBindingConfig::new(authenticated, false, extension))
+}
+
 /// Send an HTTP response to an incoming HTTP request ([`HttpServerRequest::Http`]).
-pub fn send_response(status: StatusCode, headers: Option<HashMap<String, String>>, body: Vec<u8>) {
+pub fn send_response(
+    status: StatusCode,
+    headers: Option<HashMap<String, String>>,
+    body: Vec<u8>,
+) -> Result<(), HttpServerError> {
     KiResponse::new()
         .body(
             serde_json::to_vec(&HttpResponse {
@@ -1062,13 +1097,17 @@ pub fn send_response(status: StatusCode, headers: Option<HashMap<String, String>
             .unwrap(),
         )
         .blob_bytes(body)
-        .send()
-        .unwrap()
+        .send()?;
+    Ok(())
 }
 
 /// Send a WebSocket push message on an open WebSocket channel.
-pub fn send_ws_push(channel_id: u32, message_type: WsMessageType, blob: KiBlob) {
-    KiRequest::to(("our", "http-server", "distro", "sys"))
+pub fn send_ws_push(
+    channel_id: u32,
+    message_type: WsMessageType,
+    blob: KiBlob,
+) -> Result<(), HttpServerError> {
+    KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
         .body(
             serde_json::to_vec(&HttpServerRequest::WebSocketPush {
                 channel_id,
@@ -1077,8 +1116,8 @@ pub fn send_ws_push(channel_id: u32, message_type: WsMessageType, blob: KiBlob)
             .unwrap(),
         )
         .blob(blob)
-        .send()
-        .unwrap()
+        .send()?;
+    Ok(())
 }
 
 pub fn ws_push_all_channels(
@@ -1086,12 +1125,514 @@ pub fn ws_push_all_channels(
     path: &str,
     message_type: WsMessageType,
     blob: KiBlob,
-) {
+) -> Result<(), HttpServerError> {
     if let Some(channels) = ws_channels.get(path) {
-        channels.iter().for_each(|channel_id| {
-            send_ws_push(*channel_id, message_type, blob.clone());
-        });
+        for channel_id in channels {
+            send_ws_push(*channel_id, message_type, blob.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// Tracks the WebSocket channels open on this process's bound WebSocket paths,
+/// for processes that don't use [`HttpServer`] (which tracks its own).
+///
+/// Feed every [`crate::Request`] from `http-server:distro:sys` to [`WsChannels::handle()`]
+/// to keep it up to date.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WsChannels {
+    channels: HashMap<String, HashSet<u32>>,
+}
+
+/// A WebSocket event from `http-server:distro:sys`, as returned by [`WsChannels::handle()`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum WsEvent {
+    Open {
+        path: String,
+        channel_id: u32,
+    },
+    /// The message bytes are in the blob of the message being handled.
+    Push {
+        channel_id: u32,
+        message_type: WsMessageType,
+    },
+    /// `path` is `None` if the channel was not being tracked.
+    Close {
+        channel_id: u32,
+        path: Option<String>,
+    },
+}
+
+impl WsChannels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the body of a request from `http-server:distro:sys` and track any
+    /// channel it opens or closes. Returns `None` for HTTP requests, and for
+    /// anything this version of the library can't parse, such as messages added
+    /// by newer runtimes.
+    pub fn handle(&mut self, body: &[u8]) -> Option<WsEvent> {
+        self.handle_request(HttpServerRequest::from_bytes(body).ok()?)
+    }
+
+    /// Like [`WsChannels::handle()`], for an already-parsed request.
+    pub fn handle_request(&mut self, request: HttpServerRequest) -> Option<WsEvent> {
+        match request {
+            HttpServerRequest::Http(_) => None,
+            HttpServerRequest::WebSocketOpen { path, channel_id } => {
+                self.channels
+                    .entry(path.clone())
+                    .or_default()
+                    .insert(channel_id);
+                Some(WsEvent::Open { path, channel_id })
+            }
+            HttpServerRequest::WebSocketPush {
+                channel_id,
+                message_type,
+            } => Some(WsEvent::Push {
+                channel_id,
+                message_type,
+            }),
+            HttpServerRequest::WebSocketClose(channel_id) => Some(WsEvent::Close {
+                channel_id,
+                path: self.forget(channel_id),
+            }),
+        }
+    }
+
+    /// The open channels on path, in ascending order.
+    pub fn channels(&self, path: &str) -> Vec<u32> {
+        let mut channels: Vec<u32> = self
+            .channels
+            .get(path)
+            .map(|channels| channels.iter().copied().collect())
+            .unwrap_or_default();
+        channels.sort();
+        channels
+    }
+
+    /// The path a channel was opened on, if it is open.
+    pub fn path_of(&self, channel_id: u32) -> Option<&str> {
+        self.channels
+            .iter()
+            .find(|(_, channels)| channels.contains(&channel_id))
+            .map(|(path, _)| path.as_str())
+    }
+
+    /// Push a message on a single channel.
+    pub fn push(
+        &self,
+        channel_id: u32,
+        message_type: WsMessageType,
+        blob: KiBlob,
+    ) -> Result<(), HttpServerError> {
+        send_ws_push(channel_id, message_type, blob)
+    }
+
+    /// Push a message to every channel open on path. Returns how many channels it
+    /// went to, or the first error, leaving the channels after it unpushed.
+    pub fn broadcast(
+        &self,
+        path: &str,
+        message_type: WsMessageType,
+        blob: KiBlob,
+    ) -> Result<usize, HttpServerError> {
+        let channels = self.channels(path);
+        for channel_id in &channels {
+            send_ws_push(*channel_id, message_type, blob.clone())?;
+        }
+        Ok(channels.len())
+    }
+
+    /// Close a channel from the server side and stop tracking it.
+    pub fn close(&mut self, channel_id: u32) -> Result<(), HttpServerError> {
+        self.forget(channel_id);
+        KiRequest::to(crate::SystemProcess::HttpServer.address(crate::our_node()))
+            .body(serde_json::to_vec(&HttpServerAction::WebSocketClose(channel_id)).unwrap())
+            .send()?;
+        Ok(())
+    }
+
+    fn forget(&mut self, channel_id: u32) -> Option<String> {
+        let path = self.path_of(channel_id)?.to_string();
+        if let Some(channels) = self.channels.get_mut(&path) {
+            channels.remove(&channel_id);
+            if channels.is_empty() {
+                self.channels.remove(&path);
+            }
+        }
+        Some(path)
+    }
+}
+
+/// Bind paths_to_bind so that requests on them are forwarded to this process,
+/// to be answered with [`serve_file()`] (or [`StaticFileCache::serve()`]).
+/// Returns the base directory to serve from: `ui_dir` within the `pkg` folder
+/// of package_id's drive.
+///
+/// Unlike [`HttpServer::serve_ui()`], which binds each file statically, this serves
+/// files as they are on disk at request time, and paths may end in a wildcard.
+pub fn serve_ui(
+    package_id: &crate::PackageId,
+    ui_dir: &str,
+    paths_to_bind: Vec<&str>,
+) -> Result<String, HttpServerError> {
+    for path in paths_to_bind {
+        bind_path(path, true, false)?;
+    }
+    Ok(format!("/{}/pkg/{}", package_id, ui_dir.trim_matches('/')))
+}
+
+/// Answer incoming with the file its path maps to under base_dir in vfs.
+/// Rejects paths that try to leave base_dir with 400, serves `index.html` for
+/// directory paths, and answers 404 if there is no such file.
+/// The response carries a `Content-Type` guessed from the extension and an `ETag`;
+/// an `If-None-Match` that matches gives a bodiless 304.
+///
+/// base_dir may be a [`crate::vfs::DrivePath`], such as a directory of
+/// [`crate::vfs::pkg_dir()`]. The result can be returned straight from the
+/// handler given to [`HttpServer::handle_request()`].
+pub fn serve_file(
+    incoming: &IncomingHttpRequest,
+    base_dir: impl AsRef<str>,
+) -> (HttpResponse, Option<KiBlob>) {
+    let file_path = match static_file_path(incoming, base_dir.as_ref()) {
+        Ok(file_path) => file_path,
+        Err(response) => return response,
+    };
+    match read_static_file(&file_path) {
+        Ok(bytes) => {
+            let etag = etag(&bytes);
+            static_file_response(incoming, &file_path, &etag, bytes)
+        }
+        Err(response) => response,
+    }
+}
+
+/// In-process cache of files served by [`StaticFileCache::serve()`], keyed by vfs path.
+/// Files are read from vfs once and then served from memory until [`StaticFileCache::clear()`].
+#[derive(Clone, Debug, Default)]
+pub struct StaticFileCache {
+    files: HashMap<String, (String, Vec<u8>)>,
+}
+
+impl StaticFileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like [`serve_file()`], but reads each file from vfs only the first time it is requested.
+    pub fn serve(
+        &mut self,
+        incoming: &IncomingHttpRequest,
+        base_dir: impl AsRef<str>,
+    ) -> (HttpResponse, Option<KiBlob>) {
+        let file_path = match static_file_path(incoming, base_dir.as_ref()) {
+            Ok(file_path) => file_path,
+            Err(response) => return response,
+        };
+        if !self.files.contains_key(&file_path) {
+            match read_static_file(&file_path) {
+                Ok(bytes) => {
+                    self.files.insert(file_path.clone(), (etag(&bytes), bytes));
+                }
+                Err(response) => return response,
+            }
+        }
+        let (etag, bytes) = &self.files[&file_path];
+        static_file_response(incoming, &file_path, etag, bytes.clone())
+    }
+
+    /// Drop a single cached file, e.g. after it changed on disk.
+    pub fn invalidate(&mut self, file_path: &str) {
+        self.files.remove(file_path);
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// The most bytes [`file_response()`] puts in one response, unless told otherwise.
+pub const FILE_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// What part of a file a request's `Range` header asks for, as parsed by
+/// [`parse_range()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No `Range`, or one this does not serve: the whole file.
+    Whole,
+    /// Bytes start to end, inclusive.
+    Partial { start: u64, end: u64 },
+    /// Nothing of a file of this length is in the range: a 416.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header against a file of len bytes: `bytes=a-b`,
+/// `bytes=a-` (to the end) or `bytes=-n` (the last n bytes), with the end
+/// clamped to the file. Other units, malformed headers and requests for
+/// several ranges are ignored, which the HTTP spec allows, giving
+/// [`ByteRange::Whole`].
+pub fn parse_range(header: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Whole;
+    };
+    // several ranges would take a multipart response
+    if spec.contains(',') {
+        return ByteRange::Whole;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Whole;
+    };
+    let parse = |n: &str| n.parse::<u64>().ok();
+    match (start.trim(), end.trim()) {
+        ("", "") => ByteRange::Whole,
+        ("", suffix) => match parse(suffix) {
+            None => ByteRange::Whole,
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Partial {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            },
+        },
+        (start, end) => {
+            let Some(start) = parse(start) else {
+                return ByteRange::Whole;
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match parse(end) {
+                    Some(end) if end >= start => end,
+                    _ => return ByteRange::Whole,
+                },
+            };
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial {
+                    start,
+                    end: end.min(len - 1),
+                }
+            }
+        }
+    }
+}
+
+/// Cap a range of a file of len bytes at chunk_size bytes: a client asking for
+/// more, e.g. with `bytes=0-`, gets the first chunk and asks again from where
+/// it ends. The whole file is only served if it fits in one chunk; otherwise
+/// a request for all of it gets the first chunk too.
+fn cap_range(range: ByteRange, len: u64, chunk_size: u64) -> ByteRange {
+    let range = match range {
+        ByteRange::Whole if len > chunk_size => ByteRange::Partial {
+            start: 0,
+            end: len - 1,
+        },
+        range => range,
+    };
+    match range {
+        ByteRange::Partial { start, end } if end - start >= chunk_size => ByteRange::Partial {
+            start,
+            end: start + chunk_size.max(1) - 1,
+        },
+        range => range,
+    }
+}
+
+/// Answer incoming with the file at vfs_path, honouring a `Range` header:
+/// a 206 with `Content-Range` for the part asked for, at most chunk_size
+/// bytes of it (default [`FILE_CHUNK_SIZE`]), or a 416 if none of the file is
+/// in the range. Responses carry `Accept-Ranges`, `Content-Length` and a
+/// `Content-Type` guessed from the extension; a missing file gives a 404.
+///
+/// http-server can not stream a response, so no response carries more than
+/// chunk_size bytes: a request without a `Range` gets the whole file in a 200
+/// only if it fits, and otherwise a 206 of its first chunk, whose
+/// `Content-Range` tells the client how much more there is to ask for.
+pub fn file_response(
+    incoming: &IncomingHttpRequest,
+    vfs_path: impl AsRef<str>,
+    chunk_size: Option<u64>,
+) -> (HttpResponse, Option<KiBlob>) {
+    let vfs_path = vfs_path.as_ref();
+    let chunk_size = chunk_size.unwrap_or(FILE_CHUNK_SIZE);
+    let not_found = |e: crate::vfs::VfsClientError| match e {
+        crate::vfs::VfsClientError::NotFound { .. } => error_response(StatusCode::NOT_FOUND),
+        _ => error_response(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let mut file = crate::vfs::File::new(vfs_path, 5);
+    let len = match file.metadata() {
+        Ok(metadata) if metadata.file_type == FileType::File => metadata.len,
+        Ok(_) => return error_response(StatusCode::NOT_FOUND),
+        Err(e) => return not_found(e),
+    };
+    let range = incoming
+        .headers()
+        .get(http::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mime = get_mime_type(vfs_path);
+    let response = HttpResponse::new(StatusCode::OK)
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", &mime);
+    match cap_range(parse_range(range.as_deref(), len), len, chunk_size) {
+        ByteRange::Whole => match file.read() {
+            Ok(bytes) => (
+                response.header("Content-Length", bytes.len().to_string()),
+                Some(KiBlob {
+                    mime: Some(mime),
+                    bytes,
+                }),
+            ),
+            Err(e) => not_found(e),
+        },
+        ByteRange::Partial { start, end } => {
+            let mut bytes = vec![0; (end - start + 1) as usize];
+            let read = file
+                .seek(crate::vfs::SeekFrom::Start(start))
+                .and_then(|_| file.read_at(&mut bytes));
+            match read {
+                Ok(read) => {
+                    bytes.truncate(read);
+                    (
+                        response
+                            .set_status(StatusCode::PARTIAL_CONTENT.as_u16())
+                            .header("Content-Range", format!("bytes {start}-{end}/{len}"))
+                            .header("Content-Length", bytes.len().to_string()),
+                        Some(KiBlob {
+                            mime: Some(mime),
+                            bytes,
+                        }),
+                    )
+                }
+                Err(e) => not_found(e),
+            }
+        }
+        ByteRange::Unsatisfiable => (
+            HttpResponse::new(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{len}"))
+                .header("Content-Length", "0"),
+            None,
+        ),
+    }
+}
+
+/// Send [`file_response()`] for incoming, which must be the message currently
+/// being handled, with at most chunk_size bytes of the file (default
+/// [`FILE_CHUNK_SIZE`]).
+pub fn send_file_response(
+    incoming: &IncomingHttpRequest,
+    vfs_path: impl AsRef<str>,
+    chunk_size: Option<u64>,
+) -> anyhow::Result<()> {
+    let (response, blob) = file_response(incoming, vfs_path, chunk_size);
+    let response = KiResponse::new().body(serde_json::to_vec(&response)?);
+    match blob {
+        Some(blob) => response.blob(blob).send()?,
+        None => response.send()?,
+    }
+    Ok(())
+}
+
+fn static_file_path(
+    incoming: &IncomingHttpRequest,
+    base_dir: &str,
+) -> Result<String, (HttpResponse, Option<KiBlob>)> {
+    let relative = incoming
+        .path()
+        .ok()
+        .and_then(|path| sanitize_request_path(&path))
+        .ok_or_else(|| error_response(StatusCode::BAD_REQUEST))?;
+    Ok(format!("{}/{}", base_dir.trim_end_matches('/'), relative))
+}
+
+fn read_static_file(file_path: &str) -> Result<Vec<u8>, (HttpResponse, Option<KiBlob>)> {
+    crate::vfs::open_file(file_path, false, None)
+        .and_then(|file| file.read())
+        .map_err(|e| match e {
+            crate::vfs::VfsClientError::NotFound { .. } => error_response(StatusCode::NOT_FOUND),
+            // most likely a directory without a trailing slash in the request
+            crate::vfs::VfsClientError::Runtime(crate::vfs::VfsError::IOError(_)) => {
+                error_response(StatusCode::NOT_FOUND)
+            }
+            _ => error_response(StatusCode::INTERNAL_SERVER_ERROR),
+        })
+}
+
+fn static_file_response(
+    incoming: &IncomingHttpRequest,
+    file_path: &str,
+    etag: &str,
+    bytes: Vec<u8>,
+) -> (HttpResponse, Option<KiBlob>) {
+    let response = HttpResponse::new(StatusCode::OK).header("ETag", etag);
+    if etag_matches(incoming.headers().get(http::header::IF_NONE_MATCH), etag) {
+        return (response.set_status(StatusCode::NOT_MODIFIED.as_u16()), None);
+    }
+    let mime = get_mime_type(file_path);
+    (
+        response.header("Content-Type", &mime),
+        Some(KiBlob {
+            mime: Some(mime),
+            bytes,
+        }),
+    )
+}
+
+pub(super) fn error_response(status: StatusCode) -> (HttpResponse, Option<KiBlob>) {
+    let body = status
+        .canonical_reason()
+        .unwrap_or_default()
+        .as_bytes()
+        .to_vec();
+    (
+        HttpResponse::new(status).header("Content-Type", "text/plain"),
+        Some(KiBlob {
+            mime: Some("text/plain".to_string()),
+            bytes: body,
+        }),
+    )
+}
+
+/// Turn a request path into a path relative to the served directory, or `None`
+/// if it is not safe to serve. Directory paths map to their `index.html`.
+fn sanitize_request_path(path: &str) -> Option<String> {
+    let decoded = percent_encoding::percent_decode_str(path)
+        .decode_utf8()
+        .ok()?;
+    if decoded.contains(['\\', '\0']) {
+        return None;
+    }
+    let mut segments = vec![];
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment => segments.push(segment),
+        }
     }
+    if segments.is_empty() || decoded.ends_with('/') {
+        segments.push("index.html");
+    }
+    Some(segments.join("/"))
+}
+
+fn etag(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    let hash = sha2::Sha256::digest(bytes);
+    format!("\"{}\"", alloy_primitives::hex::encode(&hash[..16]))
+}
+
+fn etag_matches(if_none_match: Option<&HeaderValue>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
 }
 
 /// Guess the MIME type of a file from its extension.
@@ -1107,3 +1648,288 @@ pub fn get_mime_type(filename: &str) -> String {
         .first_or_octet_stream()
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An HTTP request as forwarded by `http-server:distro:sys` to a bound process.
+    const HTTP_REQUEST_FIXTURE: &str = r#"{"Http":{"source_socket_addr":"127.0.0.1:51234","method":"POST","url":"http://localhost:8080/app:app:sys/api/items/42?limit=5","bound_path":"/app:app:sys/api/items/:id","headers":{"content-type":"application/json","host":"localhost:8080"},"url_params":{"id":"42"},"query_params":{"limit":"5"}}}"#;
+
+    #[test]
+    fn test_parse_incoming_http_request() {
+        let request = HttpServerRequest::from_bytes(HTTP_REQUEST_FIXTURE.as_bytes())
+            .unwrap()
+            .request()
+            .unwrap();
+        assert_eq!(request.method().unwrap(), http::Method::POST);
+        assert_eq!(request.path().unwrap(), "/api/items/42");
+        assert_eq!(request.bound_path(Some("app:app:sys")), "/api/items/:id");
+        assert_eq!(request.url_params()["id"], "42");
+        assert_eq!(request.query_params()["limit"], "5");
+        assert_eq!(request.headers()["content-type"], "application/json");
+        assert_eq!(
+            request.source_socket_addr().unwrap(),
+            "127.0.0.1:51234".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_websocket_requests() {
+        assert!(matches!(
+            HttpServerRequest::from_bytes(br#"{"WebSocketOpen":{"path":"/app:app:sys/ws","channel_id":7}}"#).unwrap(),
+            HttpServerRequest::WebSocketOpen { path, channel_id: 7 } if path == "/app:app:sys/ws"
+        ));
+        assert!(matches!(
+            HttpServerRequest::from_bytes(
+                br#"{"WebSocketPush":{"channel_id":7,"message_type":"Text"}}"#
+            )
+            .unwrap(),
+            HttpServerRequest::WebSocketPush {
+                channel_id: 7,
+                message_type: WsMessageType::Text
+            }
+        ));
+        assert!(matches!(
+            HttpServerRequest::from_bytes(br#"{"WebSocketClose":7}"#).unwrap(),
+            HttpServerRequest::WebSocketClose(7)
+        ));
+    }
+
+    #[test]
+    fn test_bind_and_response_json() {
+        assert_eq!(
+            serde_json::to_value(HttpServerAction::Bind {
+                path: "/api".to_string(),
+                authenticated: true,
+                local_only: false,
+                cache: false,
+            })
+            .unwrap(),
+            serde_json::json!({"Bind": {
+                "path": "/api",
+                "authenticated": true,
+                "local_only": false,
+                "cache": false,
+            }})
+        );
+        assert_eq!(
+            serde_json::to_value(
+                HttpResponse::new(StatusCode::CREATED).header("Content-Type", "application/json")
+            )
+            .unwrap(),
+            serde_json::json!({"status": 201, "headers": {"Content-Type": "application/json"}})
+        );
+        let resp: Result<(), HttpServerError> = Ok(());
+        assert_eq!(serde_json::to_string(&resp).unwrap(), r#"{"Ok":null}"#);
+    }
+
+    #[test]
+    fn test_ws_channels_sequencing() {
+        let mut ws = WsChannels::new();
+        assert_eq!(
+            ws.handle(br#"{"WebSocketOpen":{"path":"/feed","channel_id":1}}"#),
+            Some(WsEvent::Open {
+                path: "/feed".to_string(),
+                channel_id: 1
+            })
+        );
+        ws.handle(br#"{"WebSocketOpen":{"path":"/feed","channel_id":3}}"#);
+        ws.handle(br#"{"WebSocketOpen":{"path":"/admin","channel_id":2}}"#);
+        assert_eq!(ws.channels("/feed"), vec![1, 3]);
+        assert_eq!(ws.path_of(2), Some("/admin"));
+
+        assert_eq!(
+            ws.handle(br#"{"WebSocketPush":{"channel_id":3,"message_type":"Binary"}}"#),
+            Some(WsEvent::Push {
+                channel_id: 3,
+                message_type: WsMessageType::Binary
+            })
+        );
+
+        assert_eq!(
+            ws.handle(br#"{"WebSocketClose":1}"#),
+            Some(WsEvent::Close {
+                channel_id: 1,
+                path: Some("/feed".to_string())
+            })
+        );
+        assert_eq!(ws.channels("/feed"), vec![3]);
+        ws.handle(br#"{"WebSocketClose":3}"#);
+        assert!(ws.channels("/feed").is_empty());
+        assert_eq!(
+            ws.handle(br#"{"WebSocketClose":3}"#),
+            Some(WsEvent::Close {
+                channel_id: 3,
+                path: None
+            })
+        );
+        assert_eq!(ws.channels("/admin"), vec![2]);
+    }
+
+    #[test]
+    fn test_ws_channels_ignores_http_and_unknown() {
+        let mut ws = WsChannels::new();
+        assert_eq!(ws.handle(HTTP_REQUEST_FIXTURE.as_bytes()), None);
+        assert_eq!(
+            ws.handle(br#"{"WebSocketSomethingNew":{"channel_id":1}}"#),
+            None
+        );
+        assert_eq!(ws.handle(b"garbage"), None);
+        assert!(ws.path_of(1).is_none());
+    }
+
+    #[test]
+    fn test_sanitize_request_path() {
+        assert_eq!(sanitize_request_path("/").as_deref(), Some("index.html"));
+        assert_eq!(sanitize_request_path("").as_deref(), Some("index.html"));
+        assert_eq!(
+            sanitize_request_path("/assets/app.js").as_deref(),
+            Some("assets/app.js")
+        );
+        assert_eq!(
+            sanitize_request_path("/docs/").as_deref(),
+            Some("docs/index.html")
+        );
+        assert_eq!(
+            sanitize_request_path("//a/./b.css").as_deref(),
+            Some("a/b.css")
+        );
+        assert_eq!(
+            sanitize_request_path("/my%20file.txt").as_deref(),
+            Some("my file.txt")
+        );
+        for bad in [
+            "/../secret",
+            "/a/../../secret",
+            "/%2e%2e/secret",
+            "/a%2f..%2f..%2fsecret",
+            "/a\\..\\b",
+            "/a%00.js",
+            "/%ff",
+        ] {
+            assert_eq!(sanitize_request_path(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_mime_types() {
+        assert_eq!(get_mime_type("index.html"), "text/html");
+        assert_eq!(get_mime_type("assets/app.js"), "text/javascript");
+        assert_eq!(get_mime_type("style.css"), "text/css");
+        assert_eq!(get_mime_type("logo.svg"), "image/svg+xml");
+        assert_eq!(get_mime_type("app.wasm"), "application/wasm");
+        assert_eq!(get_mime_type("data.json"), "application/json");
+        assert_eq!(get_mime_type("LICENSE"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let tag = etag(b"hello");
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_ne!(tag, etag(b"hello!"));
+        let header = |v: &str| HeaderValue::from_str(v).unwrap();
+        assert!(etag_matches(Some(&header(&tag)), &tag));
+        assert!(etag_matches(
+            Some(&header(&format!("\"x\", W/{tag}"))),
+            &tag
+        ));
+        assert!(etag_matches(Some(&header("*")), &tag));
+        assert!(!etag_matches(Some(&header("\"x\"")), &tag));
+        assert!(!etag_matches(None, &tag));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = |header: &str| parse_range(Some(header), 1000);
+        let partial = |start, end| ByteRange::Partial { start, end };
+        assert_eq!(range("bytes=0-499"), partial(0, 499));
+        assert_eq!(range("bytes=500-5000"), partial(500, 999));
+        // open-ended
+        assert_eq!(range("bytes=900-"), partial(900, 999));
+        // suffix
+        assert_eq!(range("bytes=-100"), partial(900, 999));
+        assert_eq!(range("bytes=-5000"), partial(0, 999));
+        assert_eq!(range("bytes=1000-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-10"), 0), ByteRange::Unsatisfiable);
+        // several ranges, other units and nonsense are ignored, not refused
+        for ignored in [
+            "bytes=0-1,5-6",
+            "bytes=-5,0-1",
+            "items=0-1",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=7",
+        ] {
+            assert_eq!(range(ignored), ByteRange::Whole, "{ignored}");
+        }
+        assert_eq!(parse_range(None, 1000), ByteRange::Whole);
+    }
+
+    #[test]
+    fn test_range_capped_at_chunk_size() {
+        let partial = |start, end| ByteRange::Partial { start, end };
+        // small ranges are served as asked
+        assert_eq!(cap_range(partial(0, 99), 1000, 100), partial(0, 99));
+        // larger ranges get one chunk, for the client to ask again after
+        assert_eq!(cap_range(partial(0, 100), 1000, 100), partial(0, 99));
+        assert_eq!(
+            cap_range(parse_range(Some("bytes=250-"), 1000), 1000, 100),
+            partial(250, 349)
+        );
+        assert_eq!(
+            cap_range(ByteRange::Unsatisfiable, 1000, 100),
+            ByteRange::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn test_chunk_size_fallback() {
+        let partial = |start, end| ByteRange::Partial { start, end };
+        // without a range, a file that fits in a chunk is sent whole
+        assert_eq!(cap_range(ByteRange::Whole, 0, 100), ByteRange::Whole);
+        assert_eq!(cap_range(ByteRange::Whole, 100, 100), ByteRange::Whole);
+        // and a larger one is not read whole: its first chunk is sent instead
+        assert_eq!(cap_range(ByteRange::Whole, 101, 100), partial(0, 99));
+        assert_eq!(
+            cap_range(parse_range(None, 1 << 30), 1 << 30, FILE_CHUNK_SIZE),
+            partial(0, FILE_CHUNK_SIZE - 1)
+        );
+        // ignored ranges fall back the same way
+        assert_eq!(
+            cap_range(parse_range(Some("bytes=0-1,5-6"), 1000), 1000, 100),
+            partial(0, 99)
+        );
+    }
+
+    #[test]
+    fn test_refused_sends_are_errors() {
+        fn refuse(_: &mut crate::hooks::RequestSnapshot) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("refused"))
+        }
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        // requests are refused by the hook, responses by the blob limit
+        crate::hooks::on_send(refuse);
+        crate::limits::set_max_blob(4);
+        let blob = || KiBlob::new(None::<String>, vec![0; 8]);
+
+        let refused = |result| matches!(result, Err(HttpServerError::Build(_)));
+        assert!(refused(send_response(StatusCode::OK, None, vec![0; 8])));
+        assert!(refused(send_ws_push(7, WsMessageType::Binary, blob())));
+        let mut channels = WsChannels::new();
+        assert!(refused(channels.close(7)));
+        let request = HttpServerRequest::from_bytes(HTTP_REQUEST_FIXTURE.as_bytes()).unwrap();
+        assert!(refused(HttpServer::new(5).handle_request(
+            request,
+            |_| (HttpResponse::new(StatusCode::OK), Some(blob())),
+            |_, _, _| {}
+        )));
+        assert!(host.take_calls().is_empty());
+        crate::limits::set_max_blob(crate::limits::DEFAULT_MAX_BLOB);
+        crate::hooks::clear();
+    }
+}
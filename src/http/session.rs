@@ -0,0 +1,357 @@
+use super::server::IncomingHttpRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Cookies sent by the client in the `Cookie` header of a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cookies(Vec<(String, String)>);
+
+impl Cookies {
+    /// Read the cookies of an incoming request. A request without a `Cookie`
+    /// header has no cookies.
+    pub fn parse(request: &IncomingHttpRequest) -> Self {
+        request
+            .headers()
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .fold(Cookies::default(), |mut cookies, header| {
+                cookies.0.extend(Cookies::from_header(header).0);
+                cookies
+            })
+    }
+
+    /// Parse a `Cookie` header value such as `a=1; b=2`. Whitespace around names
+    /// and values is trimmed, surrounding double quotes are removed from values,
+    /// and a cookie without `=` has an empty value.
+    pub fn from_header(header: &str) -> Self {
+        Cookies(
+            header
+                .split(';')
+                .filter_map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    let name = name.trim();
+                    let value = value.trim();
+                    let value = value
+                        .strip_prefix('"')
+                        .and_then(|v| v.strip_suffix('"'))
+                        .unwrap_or(value);
+                    (!name.is_empty()).then(|| (name.to_string(), value.to_string()))
+                })
+                .collect(),
+        )
+    }
+
+    /// The value of the first cookie called name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this on cookies that are also [`SetCookie::secure()`].
+    None,
+}
+
+/// Builder for a `Set-Cookie` header. Render it with [`SetCookie::header()`] and
+/// add it to the headers passed to [`super::server::send_response()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub max_age: Option<u64>,
+    pub http_only: bool,
+    pub secure: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new<T, U>(name: T, value: U) -> Self
+    where
+        T: Into<String>,
+        U: Into<String>,
+    {
+        SetCookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that tells the client to delete the cookie called name
+    /// (set on the same path).
+    pub fn expired<T>(name: T) -> Self
+    where
+        T: Into<String>,
+    {
+        SetCookie::new(name, "").max_age(0)
+    }
+
+    pub fn path<T>(mut self, path: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Set how long the cookie lives, in seconds.
+    pub fn max_age(mut self, secs: u64) -> Self {
+        self.max_age = Some(secs);
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /// The `("Set-Cookie", value)` header pair.
+    pub fn header(&self) -> (String, String) {
+        ("Set-Cookie".to_string(), self.to_string())
+    }
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={max_age}")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A session in a [`SessionStore`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionData {
+    /// When the session was created, in seconds since the UNIX epoch.
+    pub created: u64,
+    pub values: HashMap<String, String>,
+}
+
+/// Sessions keyed by a random token, typically handed to the client in a cookie.
+///
+/// The store is serializable so it can be kept inside a process's own state type.
+/// Processes that keep nothing else in their state can instead use
+/// [`SessionStore::load()`] and [`SessionStore::save()`], which read and write the
+/// **whole** process state.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SessionStore {
+    sessions: HashMap<String, SessionData>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        SessionStore::default()
+    }
+
+    /// Load the store saved in the process state by [`SessionStore::save()`],
+    /// or an empty store if there is none. Errors if the state is not a store.
+    pub fn load() -> anyhow::Result<Self> {
+        Ok(crate::try_get_typed_state(|bytes| serde_json::from_slice(bytes))?.unwrap_or_default())
+    }
+
+    /// Save the store as the process state, replacing whatever was there.
+    pub fn save(&self) {
+        crate::set_state(&serde_json::to_vec(self).unwrap());
+    }
+
+    /// Start a new, empty session. Returns its token.
+    pub fn create(&mut self) -> String {
+        self.create_at(now_secs())
+    }
+
+    pub fn get(&self, token: &str) -> Option<&SessionData> {
+        self.sessions.get(token)
+    }
+
+    pub fn get_mut(&mut self, token: &str) -> Option<&mut SessionData> {
+        self.sessions.get_mut(token)
+    }
+
+    /// The session named by the cookie called cookie_name, if any.
+    pub fn get_from_cookies(&self, cookies: &Cookies, cookie_name: &str) -> Option<&SessionData> {
+        self.get(cookies.get(cookie_name)?)
+    }
+
+    pub fn remove(&mut self, token: &str) -> Option<SessionData> {
+        self.sessions.remove(token)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Remove sessions created more than secs seconds ago. Returns how many were removed.
+    pub fn expire_older_than(&mut self, secs: u64) -> usize {
+        self.expire_older_than_at(secs, now_secs())
+    }
+
+    fn create_at(&mut self, now: u64) -> String {
+        let token = alloy_primitives::hex::encode(rand::random::<[u8; 32]>());
+        self.sessions.insert(
+            token.clone(),
+            SessionData {
+                created: now,
+                values: HashMap::new(),
+            },
+        );
+        token
+    }
+
+    fn expire_older_than_at(&mut self, secs: u64, now: u64) -> usize {
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|_, session| now.saturating_sub(session.created) <= secs);
+        before - self.sessions.len()
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookies_from_header() {
+        let cookies = Cookies::from_header("a=1; b=2;c=3");
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies.get("a"), Some("1"));
+        assert_eq!(cookies.get("b"), Some("2"));
+        assert_eq!(cookies.get("c"), Some("3"));
+        assert_eq!(cookies.get("d"), None);
+
+        let cookies = Cookies::from_header("  spaced  =  out value ;quoted=\"q v\"");
+        assert_eq!(cookies.get("spaced"), Some("out value"));
+        assert_eq!(cookies.get("quoted"), Some("q v"));
+
+        // missing values, repeated names, empty segments
+        let cookies = Cookies::from_header("empty=; flag; dup=first; dup=second; ;=orphan");
+        assert_eq!(cookies.get("empty"), Some(""));
+        assert_eq!(cookies.get("flag"), Some(""));
+        assert_eq!(cookies.get("dup"), Some("first"));
+        assert_eq!(cookies.len(), 4);
+
+        // values may contain `=`
+        assert_eq!(
+            Cookies::from_header("token=abc==").get("token"),
+            Some("abc==")
+        );
+        assert!(Cookies::from_header("").is_empty());
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        assert_eq!(SetCookie::new("a", "1").to_string(), "a=1");
+        let cookie = SetCookie::new("session", "tok")
+            .path("/app:app:sys")
+            .max_age(3600)
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict);
+        assert_eq!(
+            cookie.header(),
+            (
+                "Set-Cookie".to_string(),
+                "session=tok; Path=/app:app:sys; Max-Age=3600; HttpOnly; Secure; SameSite=Strict"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            SetCookie::expired("session").path("/").to_string(),
+            "session=; Path=/; Max-Age=0"
+        );
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let mut store = SessionStore::new();
+        let old = store.create_at(1_000);
+        let new = store.create_at(2_000);
+        assert_ne!(old, new);
+        assert_eq!(old.len(), 64);
+        assert_eq!(store.get(&old).unwrap().created, 1_000);
+
+        store
+            .get_mut(&new)
+            .unwrap()
+            .values
+            .insert("user".to_string(), "alice".to_string());
+
+        // exactly secs old is kept
+        assert_eq!(store.expire_older_than_at(1_000, 2_000), 0);
+        assert_eq!(store.expire_older_than_at(999, 2_000), 1);
+        assert!(store.get(&old).is_none());
+        assert_eq!(store.get(&new).unwrap().values["user"], "alice");
+
+        let cookies = Cookies::from_header(&format!("other=x; session={new}"));
+        assert!(store.get_from_cookies(&cookies, "session").is_some());
+        assert!(store.get_from_cookies(&cookies, "other").is_none());
+
+        // round-trips through the serialized state
+        let bytes = serde_json::to_vec(&store).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<SessionStore>(&bytes).unwrap(),
+            store
+        );
+    }
+}
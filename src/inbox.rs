@@ -0,0 +1,324 @@
+use crate::timer::wall_clock_ms;
+use crate::{trace::USER_METADATA_KEY, types::message::metadata_object, Message, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// The metadata key a sender puts its idempotency key under: requests from the
+/// same source with the same key are retries of one request.
+pub const IDEMPOTENCY_KEY: &str = "__idempotency_key";
+
+/// How long a [`Deduper`] remembers a request it hasn't seen again, by default.
+pub const DEFAULT_MAX_AGE_MS: u64 = 10 * 60 * 1000;
+
+/// metadata with key set as its [`IDEMPOTENCY_KEY`]. Metadata that is a JSON object
+/// gets the entry added; anything else is kept under
+/// [`USER_METADATA_KEY`](crate::trace::USER_METADATA_KEY), as
+/// [`crate::trace::merge_trace()`] does.
+pub fn with_idempotency_key(metadata: Option<&str>, key: &str) -> String {
+    let mut object = match metadata_object(metadata) {
+        Ok(object)
+            if !object.contains_key(IDEMPOTENCY_KEY) && !object.contains_key(USER_METADATA_KEY) =>
+        {
+            object
+        }
+        _ => {
+            let mut object = serde_json::Map::new();
+            object.insert(
+                USER_METADATA_KEY.to_string(),
+                Value::String(metadata.unwrap_or_default().to_string()),
+            );
+            object
+        }
+    };
+    object.insert(IDEMPOTENCY_KEY.to_string(), Value::String(key.to_string()));
+    Value::Object(object).to_string()
+}
+
+/// The [`IDEMPOTENCY_KEY`] in metadata, if it has one.
+pub fn idempotency_key(metadata: Option<&str>) -> Option<String> {
+    match metadata_object(Some(metadata?))
+        .ok()?
+        .remove(IDEMPOTENCY_KEY)?
+    {
+        Value::String(key) => Some(key),
+        _ => None,
+    }
+}
+
+/// What [`Deduper::check()`] makes of a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DedupDecision {
+    /// Not seen before, or carrying no idempotency key: handle it.
+    Fresh,
+    /// A retry of a request already handled: skip the side effects, and answer
+    /// with cached_response if there is one. It is `None` if no response was
+    /// recorded, e.g. because the first copy is still being handled.
+    Duplicate { cached_response: Option<Vec<u8>> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Seen {
+    last_seen_ms: u64,
+    /// Position in [`Deduper::by_recency`].
+    tick: u64,
+    response: Option<Vec<u8>>,
+}
+
+/// Remembers the idempotency keys of recent requests, and the responses sent to
+/// them, so that retries are handled at most once.
+///
+/// At most window_size requests are remembered, least recently seen forgotten
+/// first, and none longer than max_age_ms after it was last seen. Serialize it
+/// into process state to keep it across restarts.
+///
+/// ```no_run
+/// use hyperware_process_lib::inbox::{DedupDecision, Deduper};
+/// use hyperware_process_lib::{await_message, Response};
+///
+/// let mut deduper = Deduper::new(1_000);
+/// let message = await_message().unwrap();
+/// match deduper.check(&message) {
+///     DedupDecision::Duplicate { cached_response: Some(body) } => {
+///         Response::new().body(body).send().unwrap();
+///     }
+///     DedupDecision::Duplicate { cached_response: None } => {}
+///     DedupDecision::Fresh => {
+///         // side effects go here
+///         deduper.respond(&message, b"done".to_vec()).unwrap();
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Deduper {
+    window_size: usize,
+    max_age_ms: u64,
+    next_tick: u64,
+    /// Keyed by source and idempotency key.
+    seen: HashMap<String, Seen>,
+    by_recency: BTreeMap<u64, String>,
+}
+
+impl Deduper {
+    pub fn new(window_size: usize) -> Self {
+        Deduper {
+            window_size,
+            max_age_ms: DEFAULT_MAX_AGE_MS,
+            next_tick: 0,
+            seen: HashMap::new(),
+            by_recency: BTreeMap::new(),
+        }
+    }
+
+    /// Forget requests not seen for max_age_ms. Defaults to [`DEFAULT_MAX_AGE_MS`].
+    pub fn max_age_ms(mut self, max_age_ms: u64) -> Self {
+        self.max_age_ms = max_age_ms;
+        self
+    }
+
+    /// Consult before doing a request's side effects. A request not seen before is
+    /// remembered, so that its retries are recognized from now on.
+    pub fn check(&mut self, message: &Message) -> DedupDecision {
+        self.check_at(message, wall_clock_ms())
+    }
+
+    /// Remember body as the response to message, to answer its retries with.
+    /// Does nothing if message carries no idempotency key or has been forgotten.
+    pub fn record_response(&mut self, message: &Message, body: Vec<u8>) {
+        if let Some(seen) = dedup_key(message).and_then(|key| self.seen.get_mut(&key)) {
+            seen.response = Some(body);
+        }
+    }
+
+    /// [`Deduper::record_response()`], then send body as the response.
+    pub fn respond(&mut self, message: &Message, body: Vec<u8>) -> anyhow::Result<()> {
+        self.record_response(message, body.clone());
+        Response::new().body(body).send()?;
+        Ok(())
+    }
+
+    /// How many requests are remembered.
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    fn check_at(&mut self, message: &Message, now_ms: u64) -> DedupDecision {
+        self.evict_expired(now_ms);
+        let Some(key) = dedup_key(message) else {
+            return DedupDecision::Fresh;
+        };
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.by_recency.insert(tick, key.clone());
+        if let Some(seen) = self.seen.get_mut(&key) {
+            self.by_recency.remove(&seen.tick);
+            seen.tick = tick;
+            seen.last_seen_ms = now_ms;
+            return DedupDecision::Duplicate {
+                cached_response: seen.response.clone(),
+            };
+        }
+        self.seen.insert(
+            key,
+            Seen {
+                last_seen_ms: now_ms,
+                tick,
+                response: None,
+            },
+        );
+        while self.seen.len() > self.window_size {
+            self.evict_oldest();
+        }
+        DedupDecision::Fresh
+    }
+
+    fn evict_expired(&mut self, now_ms: u64) {
+        // least recently seen is also longest unseen, so expired entries come first
+        while let Some((_, key)) = self.by_recency.first_key_value() {
+            if now_ms.saturating_sub(self.seen[key].last_seen_ms) < self.max_age_ms {
+                break;
+            }
+            self.evict_oldest();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.by_recency.pop_first() {
+            self.seen.remove(&key);
+        }
+    }
+}
+
+/// Requests are deduplicated per source, so that senders needn't coordinate keys.
+fn dedup_key(message: &Message) -> Option<String> {
+    let Message::Request {
+        source, metadata, ..
+    } = message
+    else {
+        return None;
+    };
+    Some(format!(
+        "{source} {}",
+        idempotency_key(metadata.as_deref())?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(source: &str, key: Option<&str>) -> Message {
+        Message::Request {
+            source: source.parse().unwrap(),
+            expects_response: Some(5),
+            body: b"charge".to_vec(),
+            metadata: key.map(|key| with_idempotency_key(None, key)),
+            capabilities: vec![],
+        }
+    }
+
+    const ALICE: &str = "alice.os@shop:app:pub.os";
+    const BOB: &str = "bob.os@shop:app:pub.os";
+
+    #[test]
+    fn test_metadata_key() {
+        let merged = with_idempotency_key(Some(r#"{"a":1}"#), "k1");
+        assert_eq!(idempotency_key(Some(&merged)).as_deref(), Some("k1"));
+        assert_eq!(serde_json::from_str::<Value>(&merged).unwrap()["a"], 1);
+        let wrapped = with_idempotency_key(Some("plain"), "k2");
+        let value: Value = serde_json::from_str(&wrapped).unwrap();
+        assert_eq!(value[USER_METADATA_KEY], "plain");
+        assert_eq!(idempotency_key(Some(&wrapped)).as_deref(), Some("k2"));
+        assert_eq!(idempotency_key(Some("plain")), None);
+        assert_eq!(idempotency_key(None), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut deduper = Deduper::new(10);
+        let first = request(ALICE, Some("k1"));
+        assert_eq!(deduper.check_at(&first, 0), DedupDecision::Fresh);
+        // a retry arriving while the first copy is being handled
+        assert_eq!(
+            deduper.check_at(&first, 1),
+            DedupDecision::Duplicate {
+                cached_response: None
+            }
+        );
+        deduper.record_response(&first, b"receipt".to_vec());
+        assert_eq!(
+            deduper.check_at(&first, 2),
+            DedupDecision::Duplicate {
+                cached_response: Some(b"receipt".to_vec())
+            }
+        );
+        // keys are per source, and requests without one are always fresh
+        assert_eq!(
+            deduper.check_at(&request(BOB, Some("k1")), 3),
+            DedupDecision::Fresh
+        );
+        assert_eq!(
+            deduper.check_at(&request(ALICE, None), 4),
+            DedupDecision::Fresh
+        );
+        assert_eq!(
+            deduper.check_at(&request(ALICE, None), 5),
+            DedupDecision::Fresh
+        );
+        assert_eq!(deduper.len(), 2);
+
+        // survives a restart
+        let persisted = serde_json::to_vec(&deduper).unwrap();
+        let mut restored: Deduper = serde_json::from_slice(&persisted).unwrap();
+        assert_eq!(
+            restored.check_at(&first, 6),
+            DedupDecision::Duplicate {
+                cached_response: Some(b"receipt".to_vec())
+            }
+        );
+    }
+
+    #[test]
+    fn test_eviction_by_count_and_age() {
+        let mut deduper = Deduper::new(2).max_age_ms(100);
+        let (a, b, c) = (
+            request(ALICE, Some("a")),
+            request(ALICE, Some("b")),
+            request(ALICE, Some("c")),
+        );
+        deduper.check_at(&a, 0);
+        deduper.check_at(&b, 10);
+        // seeing a again makes b the least recently seen
+        deduper.check_at(&a, 20);
+        deduper.check_at(&c, 30);
+        assert_eq!(deduper.len(), 2);
+        assert!(matches!(
+            deduper.check_at(&a, 40),
+            DedupDecision::Duplicate { .. }
+        ));
+        assert!(matches!(
+            deduper.check_at(&c, 41),
+            DedupDecision::Duplicate { .. }
+        ));
+        assert_eq!(deduper.check_at(&b, 42), DedupDecision::Fresh);
+
+        // b pushed a out, leaving c last seen at 41 and b at 42
+        assert_eq!(deduper.len(), 2);
+        assert!(matches!(
+            deduper.check_at(&b, 141),
+            DedupDecision::Duplicate { .. }
+        ));
+        // c expired at 141, before this check
+        assert_eq!(deduper.len(), 1);
+        assert_eq!(deduper.check_at(&c, 142), DedupDecision::Fresh);
+        assert_eq!(
+            deduper.check_at(&request(ALICE, None), 10_000),
+            DedupDecision::Fresh
+        );
+        assert!(deduper.is_empty());
+    }
+}
@@ -0,0 +1,478 @@
+use crate::vfs::logger::{log_path, rotation_renames};
+use crate::vfs::{self, File, VfsClientError};
+use crate::{Address, LazyLoadBlob, Message};
+use serde::{Deserialize, Serialize};
+
+/// Name of the active journal file in its directory; rotated files get `.1`,
+/// `.2` and so on appended, `.1` being the newest.
+pub const JOURNAL_FILE: &str = "journal";
+/// Number of rotated journal files kept by default.
+pub const DEFAULT_MAX_FILES: u32 = 5;
+
+/// Called on a copy of each message and its blob before they are written, to
+/// blank out what should not end up in a journal, e.g. tokens in a body.
+pub type Redactor = fn(&mut Message, &mut Option<LazyLoadBlob>);
+
+/// What is journaled of a message: the message, which holds its source, and
+/// its blob as mime and bytes.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    message: Message,
+    blob: Option<(Option<String>, Vec<u8>)>,
+}
+
+/// Records the messages a process receives to a journal in the vfs, to replay
+/// them later with a [`Replayer`], e.g. against a handler under
+/// [`crate::host::MockHost`] to reproduce a bug report.
+///
+/// Each message is appended as a record as soon as it is recorded: its length as
+/// a little-endian `u32`, then the message and blob in bincode. Once the active
+/// file would grow past max_bytes, it is rotated as a [`vfs::Logger`] rotates.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, journal::Recorder};
+///
+/// let mut recorder = Recorder::new("/my-app:publisher.os/journal", 1024 * 1024)?;
+/// loop {
+///     let Ok(message) = await_message() else { continue };
+///     recorder.record(&message)?;
+///     // handle message
+/// }
+/// # anyhow::Ok(())
+/// ```
+pub struct Recorder {
+    pub dir: String,
+    pub max_bytes: u64,
+    pub max_files: u32,
+    pub timeout: u64,
+    enabled: bool,
+    redact: Option<Redactor>,
+    active_len: u64,
+    rotated: u32,
+}
+
+impl Recorder {
+    /// Opens (creating if needed) the journal in vfs_dir, appending to what is
+    /// already there. Recording starts enabled.
+    pub fn new(vfs_dir: &str, max_bytes: u64) -> Result<Recorder, VfsClientError> {
+        let timeout = 5;
+        let dir = vfs_dir.trim_end_matches('/').to_string();
+        vfs::open_dir(&dir, true, Some(timeout))?;
+        let mut recorder = Recorder {
+            dir,
+            max_bytes,
+            max_files: DEFAULT_MAX_FILES,
+            timeout,
+            enabled: true,
+            redact: None,
+            active_len: 0,
+            rotated: 0,
+        };
+        recorder.active_len = vfs::open_file(recorder.path(0), true, Some(timeout))?
+            .metadata()?
+            .len();
+        while recorder.rotated < recorder.max_files && recorder.exists(recorder.rotated + 1)? {
+            recorder.rotated += 1;
+        }
+        Ok(recorder)
+    }
+
+    /// Set how many rotated files are kept.
+    pub fn max_files(mut self, max_files: u32) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    /// Pass every message through redact before it is written.
+    pub fn redact(mut self, redact: Redactor) -> Self {
+        self.redact = Some(redact);
+        self
+    }
+
+    /// Start or stop recording. [`Recorder::record()`] does nothing while stopped.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Append message and its blob, as [`Message::blob()`] gets it, to the journal.
+    pub fn record(&mut self, message: &Message) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.record_with_blob(message, message.blob())
+    }
+
+    /// Append message with blob to the journal.
+    pub fn record_with_blob(
+        &mut self,
+        message: &Message,
+        mut blob: Option<LazyLoadBlob>,
+    ) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut message = message.clone();
+        if let Some(redact) = self.redact {
+            redact(&mut message, &mut blob);
+        }
+        let frame = frame(&Record {
+            message,
+            blob: blob.map(|blob| (blob.mime, blob.bytes)),
+        })?;
+        if self.active_len > 0 && self.active_len + frame.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        File::new(self.path(0), self.timeout).append(&frame)?;
+        self.active_len += frame.len() as u64;
+        Ok(())
+    }
+
+    /// The paths of the journal files that exist, oldest first, to replay in order.
+    pub fn paths(&self) -> Vec<String> {
+        (0..=self.rotated)
+            .rev()
+            .map(|index| self.path(index))
+            .collect()
+    }
+
+    fn path(&self, index: u32) -> String {
+        log_path(&self.dir, JOURNAL_FILE, index)
+    }
+
+    fn exists(&self, index: u32) -> Result<bool, VfsClientError> {
+        match vfs::metadata(&self.path(index), Some(self.timeout)) {
+            Ok(_) => Ok(true),
+            Err(VfsClientError::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn rotate(&mut self) -> Result<(), VfsClientError> {
+        let (renames, kept) = rotation_renames(self.rotated, self.max_files);
+        if self.max_files == 0 {
+            vfs::remove_file(&self.path(0), Some(self.timeout))?;
+        }
+        for (from, to) in renames {
+            vfs::rename(&self.path(from), &self.path(to), Some(self.timeout))?;
+        }
+        vfs::create_file(self.path(0), Some(self.timeout))?;
+        self.rotated = kept;
+        self.active_len = 0;
+        Ok(())
+    }
+}
+
+/// The messages of a journal written by a [`Recorder`], in the order they were
+/// recorded, with their sources and blobs. A record cut short, as by a crash
+/// mid-write, ends the journal with an error.
+pub struct Replayer {
+    bytes: Vec<u8>,
+    offset: usize,
+}
+
+impl Replayer {
+    /// Read the journal file at path, e.g. one of [`Recorder::paths()`].
+    pub fn open(path: &str) -> Result<Replayer, VfsClientError> {
+        Ok(Replayer::from_bytes(
+            vfs::open_file(path, false, None)?.read()?,
+        ))
+    }
+
+    /// Replay a journal read some other way, e.g. from a file attached to a bug report.
+    pub fn from_bytes(bytes: Vec<u8>) -> Replayer {
+        Replayer { bytes, offset: 0 }
+    }
+
+    /// Queue every message that is left on host, for the code under test to receive
+    /// with [`crate::await_message()`]. Returns how many were queued.
+    #[cfg(any(test, feature = "mock"))]
+    pub fn feed(self, host: &crate::host::MockHost) -> anyhow::Result<usize> {
+        let mut fed = 0;
+        for record in self {
+            let (_, message, blob) = record?;
+            host.push_message(message, blob);
+            fed += 1;
+        }
+        Ok(fed)
+    }
+}
+
+impl Iterator for Replayer {
+    type Item = anyhow::Result<(Address, Message, Option<LazyLoadBlob>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+        let record = unframe(&self.bytes[self.offset..]);
+        // nothing after a bad record can be trusted to start at a record boundary
+        self.offset = match &record {
+            Ok((_, len)) => self.offset + len,
+            Err(_) => self.bytes.len(),
+        };
+        Some(record.map(|(record, _)| {
+            (
+                record.message.source().clone(),
+                record.message,
+                record
+                    .blob
+                    .map(|(mime, bytes)| LazyLoadBlob { mime, bytes }),
+            )
+        }))
+    }
+}
+
+/// record, length-prefixed.
+fn frame(record: &Record) -> anyhow::Result<Vec<u8>> {
+    let body = bincode::serialize(record)?;
+    let len = u32::try_from(body.len())
+        .map_err(|_| anyhow::anyhow!("journal record of {} bytes is too large", body.len()))?;
+    let mut frame = len.to_le_bytes().to_vec();
+    frame.extend(body);
+    Ok(frame)
+}
+
+/// The record at the start of bytes, and how many bytes its frame takes.
+fn unframe(bytes: &[u8]) -> anyhow::Result<(Record, usize)> {
+    let (len, rest) = bytes
+        .split_first_chunk::<4>()
+        .ok_or_else(|| anyhow::anyhow!("journal ends within a record length"))?;
+    let len = u32::from_le_bytes(*len) as usize;
+    let body = rest
+        .get(..len)
+        .ok_or_else(|| anyhow::anyhow!("journal ends within a record of {len} bytes"))?;
+    Ok((bincode::deserialize(body)?, 4 + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::vfs::{FileMetadata, FileType, VfsError, VfsResponse};
+
+    fn request(body: &str) -> Message {
+        Message::Request {
+            source: "alice.os@client:app:pub.os".parse().unwrap(),
+            expects_response: Some(5),
+            body: body.as_bytes().to_vec(),
+            metadata: Some("{}".to_string()),
+            capabilities: vec![],
+        }
+    }
+
+    fn ok() -> Reply {
+        Reply::json(&VfsResponse::Ok)
+    }
+
+    fn metadata(len: u64) -> Reply {
+        Reply::json(&VfsResponse::Metadata(FileMetadata {
+            file_type: FileType::File,
+            len,
+            created: None,
+            modified: None,
+        }))
+    }
+
+    fn not_found() -> Reply {
+        Reply::json(&VfsResponse::Err(VfsError::IOError(
+            "No such file or directory".to_string(),
+        )))
+    }
+
+    /// A recorder on a fresh journal, without rotated files, and the host serving it.
+    fn recorder(max_bytes: u64) -> (MockHost, Recorder) {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        for reply in [ok(), ok(), metadata(0), not_found()] {
+            host.reply(reply);
+        }
+        let recorder = {
+            let _installed = host.install();
+            Recorder::new("/app:sys/journal/", max_bytes).unwrap()
+        };
+        host.take_calls();
+        (host, recorder)
+    }
+
+    /// The vfs actions sent, as JSON, their paths, and the blobs of appends.
+    fn actions(host: &MockHost) -> Vec<(serde_json::Value, String, Option<Vec<u8>>)> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, blob, .. } => {
+                    let request: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    Some((
+                        request["action"].clone(),
+                        request["path"].as_str().unwrap().to_string(),
+                        blob.map(|blob| blob.bytes),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_framing() {
+        let record = Record {
+            message: request("hi"),
+            blob: Some((None, vec![1, 2, 3])),
+        };
+        let framed = frame(&record).unwrap();
+        let len = u32::from_le_bytes(framed[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, framed.len() - 4);
+        let (unframed, used) = unframe(&framed).unwrap();
+        assert_eq!(used, framed.len());
+        assert_eq!(unframed.message.body(), b"hi");
+
+        // a journal cut short yields what came before, then an error, then ends
+        let mut bytes = [framed.clone(), framed.clone()].concat();
+        bytes.truncate(framed.len() + 6);
+        let mut replayer = Replayer::from_bytes(bytes);
+        assert!(replayer.next().unwrap().is_ok());
+        assert!(replayer
+            .next()
+            .unwrap()
+            .unwrap_err()
+            .to_string()
+            .contains("ends within a record"));
+        assert!(replayer.next().is_none());
+        assert!(Replayer::from_bytes(vec![]).next().is_none());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let record_len = frame(&Record {
+            message: request("0123456789"),
+            blob: None,
+        })
+        .unwrap()
+        .len() as u64;
+        // room for two records per file
+        let (host, recorder) = recorder(record_len * 2);
+        let mut recorder = recorder.max_files(1);
+        let _installed = host.install();
+        for _ in 0..7 {
+            host.reply(ok());
+        }
+        for _ in 0..3 {
+            recorder
+                .record_with_blob(&request("0123456789"), None)
+                .unwrap();
+        }
+        let actions: Vec<(String, String)> = actions(&host)
+            .into_iter()
+            .map(|(action, path, _)| (action.to_string(), path))
+            .collect();
+        let active = "/app:sys/journal/journal".to_string();
+        let append = (r#""Append""#.to_string(), active.clone());
+        assert_eq!(
+            actions,
+            [
+                append.clone(),
+                append.clone(),
+                (
+                    r#"{"Rename":{"new_path":"/app:sys/journal/journal.1"}}"#.to_string(),
+                    active.clone()
+                ),
+                (r#""CreateFile""#.to_string(), active.clone()),
+                append,
+            ]
+        );
+        assert_eq!(
+            recorder.paths(),
+            ["/app:sys/journal/journal.1", "/app:sys/journal/journal"]
+        );
+
+        // nothing is written while disabled
+        recorder.set_enabled(false);
+        recorder.record(&request("ignored")).unwrap();
+        assert!(host.take_calls().is_empty());
+    }
+
+    fn redact_tokens(message: &mut Message, blob: &mut Option<LazyLoadBlob>) {
+        if let Message::Request { body, .. } = message {
+            if body.starts_with(b"token") {
+                *body = b"[redacted]".to_vec();
+            }
+        }
+        if let Some(blob) = blob {
+            blob.bytes.clear();
+        }
+    }
+
+    #[test]
+    fn test_record_then_replay() {
+        let (host, recorder) = recorder(1024 * 1024);
+        let mut recorder = recorder.redact(redact_tokens);
+        let received = [
+            (request("ping"), None),
+            (
+                request("token=hunter2"),
+                Some(LazyLoadBlob {
+                    mime: Some("text/plain".to_string()),
+                    bytes: b"secret".to_vec(),
+                }),
+            ),
+            (
+                Message::Response {
+                    source: "bob.os@server:app:pub.os".parse().unwrap(),
+                    body: b"pong".to_vec(),
+                    metadata: None,
+                    context: Some(b"ctx".to_vec()),
+                    capabilities: vec![],
+                },
+                None,
+            ),
+        ];
+        let journal: Vec<u8> = {
+            let _installed = host.install();
+            for (message, blob) in &received {
+                host.reply(ok());
+                recorder.record_with_blob(message, blob.clone()).unwrap();
+            }
+            actions(&host)
+                .into_iter()
+                .flat_map(|(_, _, blob)| blob.unwrap())
+                .collect()
+        };
+
+        // replayed into a fresh host, as a handler under test would see them
+        let replay = MockHost::new();
+        let _installed = replay.install();
+        assert_eq!(Replayer::from_bytes(journal).feed(&replay).unwrap(), 3);
+        let mut seen = vec![];
+        for _ in 0..3 {
+            let message = crate::await_message().unwrap();
+            seen.push((
+                message.source().to_string(),
+                String::from_utf8(message.body().to_vec()).unwrap(),
+                message.blob().map(|blob| (blob.mime, blob.bytes)),
+            ));
+        }
+        assert_eq!(
+            seen,
+            [
+                (
+                    "alice.os@client:app:pub.os".to_string(),
+                    "ping".to_string(),
+                    None
+                ),
+                (
+                    "alice.os@client:app:pub.os".to_string(),
+                    "[redacted]".to_string(),
+                    Some((Some("text/plain".to_string()), vec![]))
+                ),
+                (
+                    "bob.os@server:app:pub.os".to_string(),
+                    "pong".to_string(),
+                    None
+                ),
+            ]
+        );
+    }
+}
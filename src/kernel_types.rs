@@ -3,6 +3,7 @@ use crate::{Address, ProcessId};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
+use thiserror::Error;
 
 //
 // process-facing kernel types, used for process
@@ -13,7 +14,40 @@ use std::hash::{Hash, Hasher};
 pub type Context = Vec<u8>;
 pub type NodeId = String; // QNS domain name
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// The version of the types in this module, as `major.minor.patch`. Minor and
+/// patch releases only add fields and variants, which both older and newer
+/// peers tolerate: missing fields take their defaults, unknown fields are
+/// ignored and unknown unit variants become `Unknown`. Major releases break.
+pub const PROTOCOL_VERSION: &str = "1.0.0";
+
+/// From [`check_compat()`]: the peer's kernel types can't be read as ours.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("protocol version {peer} is incompatible with {PROTOCOL_VERSION}")]
+pub struct IncompatibleVersion {
+    pub peer: String,
+}
+
+/// Whether a peer speaking peer_version, e.g. as reported in its metadata, can
+/// exchange the types in this module with us.
+pub fn check_compat(peer_version: &str) -> Result<(), IncompatibleVersion> {
+    let major = |version: &str| -> Option<u64> {
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        // the rest must be numbers too
+        parts
+            .try_for_each(|part| part.parse::<u64>().map(|_| ()))
+            .ok()?;
+        Some(major)
+    };
+    match major(peer_version) {
+        Some(peer) if Some(peer) == major(PROTOCOL_VERSION) => Ok(()),
+        _ => Err(IncompatibleVersion {
+            peer: peer_version.to_string(),
+        }),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct LazyLoadBlob {
     pub mime: Option<String>, // MIME type
     pub bytes: Vec<u8>,
@@ -21,18 +55,25 @@ pub struct LazyLoadBlob {
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Request {
+    #[serde(default)]
     pub inherit: bool,
+    #[serde(default)]
     pub expects_response: Option<u64>, // number of seconds until timeout
     pub body: Vec<u8>,
+    #[serde(default)]
     pub metadata: Option<String>, // JSON-string
+    #[serde(default)]
     pub capabilities: Vec<Capability>,
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Response {
+    #[serde(default)]
     pub inherit: bool,
     pub body: Vec<u8>,
+    #[serde(default)]
     pub metadata: Option<String>, // JSON-string
+    #[serde(default)]
     pub capabilities: Vec<Capability>,
 }
 
@@ -103,21 +144,22 @@ impl std::fmt::Display for Capability {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SendError {
     pub kind: SendErrorKind,
     pub target: Address,
     pub message: Message,
+    #[serde(default)]
     pub lazy_load_blob: Option<LazyLoadBlob>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SendErrorKind {
     Offline,
     Timeout,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OnExit {
     None,
     Restart,
@@ -152,9 +194,12 @@ pub enum KernelCommand {
     InitializeProcess {
         id: ProcessId,
         wasm_bytes_handle: String,
+        #[serde(default)]
         wit_version: Option<u32>,
         on_exit: OnExit,
+        #[serde(default)]
         initial_capabilities: HashSet<Capability>,
+        #[serde(default)]
         public: bool,
     },
     /// Create an arbitrary capability and grant it to a process.
@@ -178,13 +223,23 @@ pub enum KernelCommand {
     Shutdown,
     /// Ask kernel to produce debugging information
     Debug(KernelPrint),
+    /// A variant this version of the crate doesn't know, from a newer peer. Only
+    /// variants without fields land here: others still fail to deserialize.
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum KernelPrint {
     ProcessMap,
     Process(ProcessId),
-    HasCap { on: ProcessId, cap: Capability },
+    HasCap {
+        on: ProcessId,
+        cap: Capability,
+    },
+    /// See [`KernelCommand::Unknown`].
+    #[serde(other)]
+    Unknown,
 }
 
 /// IPC body format for all KernelCommand responses
@@ -196,6 +251,9 @@ pub enum KernelResponse {
     RunProcessError,
     KilledProcess(ProcessId),
     Debug(KernelPrintResponse),
+    /// See [`KernelCommand::Unknown`].
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -203,6 +261,9 @@ pub enum KernelPrintResponse {
     ProcessMap(ProcessMap),
     Process(Option<PersistedProcess>),
     HasCap(Option<bool>),
+    /// See [`KernelCommand::Unknown`].
+    #[serde(other)]
+    Unknown,
 }
 
 pub type ProcessMap = HashMap<ProcessId, PersistedProcess>;
@@ -212,9 +273,12 @@ pub type ProcessMap = HashMap<ProcessId, PersistedProcess>;
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PersistedProcess {
     pub wasm_bytes_handle: String,
+    #[serde(default)]
     pub wit_version: Option<u32>,
     pub on_exit: OnExit,
+    #[serde(default)]
     pub capabilities: HashSet<Capability>,
+    #[serde(default)]
     pub public: bool,
 }
 
@@ -224,6 +288,9 @@ pub enum StateAction {
     SetState(ProcessId),
     DeleteState(ProcessId),
     Backup,
+    /// See [`KernelCommand::Unknown`].
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -233,17 +300,38 @@ pub enum StateResponse {
     DeleteState,
     Backup,
     Err(StateError),
+    /// See [`KernelCommand::Unknown`].
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum StateError {
-    RocksDBError { action: String, error: String },
-    StartupError { action: String },
-    BadBytes { action: String },
-    BadRequest { error: String },
-    BadJson { error: String },
-    NotFound { process_id: ProcessId },
-    IOError { error: String },
+    RocksDBError {
+        action: String,
+        error: String,
+    },
+    StartupError {
+        action: String,
+    },
+    BadBytes {
+        action: String,
+    },
+    BadRequest {
+        error: String,
+    },
+    BadJson {
+        error: String,
+    },
+    NotFound {
+        process_id: ProcessId,
+    },
+    IOError {
+        error: String,
+    },
+    /// See [`KernelCommand::Unknown`].
+    #[serde(other)]
+    Unknown,
 }
 
 #[allow(dead_code)]
@@ -257,6 +345,7 @@ impl StateError {
             StateError::BadJson { .. } => "NoJson",
             StateError::NotFound { .. } => "NotFound",
             StateError::IOError { .. } => "IOError",
+            StateError::Unknown => "Unknown",
         }
     }
 }
@@ -264,6 +353,10 @@ impl StateError {
 //
 // package types
 //
+// these are only ever read from and written to JSON files, so absent optional
+// fields are left out when writing. the types above can travel in bincode,
+// which can't skip fields, so they only get defaults for reading.
+//
 
 /// Represents the metadata associated with a hyperware package, which is an ERC721 compatible token.
 /// This is deserialized from the `metadata.json` file in a package.
@@ -276,10 +369,15 @@ impl StateError {
 /// - `properties`: A required field containing important information about the package.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Erc721Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub external_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub animation_url: Option<String>,
     pub properties: Erc721Properties,
 }
@@ -303,12 +401,19 @@ pub struct Erc721Properties {
     pub package_name: String,
     pub publisher: String,
     pub current_version: String,
+    #[serde(default)]
     pub mirrors: Vec<NodeId>,
+    #[serde(default)]
     pub code_hashes: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub screenshots: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub wit_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub dependencies: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub api_includes: Option<Vec<std::path::PathBuf>>,
 }
 
@@ -318,20 +423,30 @@ pub struct PackageManifestEntry {
     pub process_name: String,
     pub process_wasm_path: String,
     pub on_exit: OnExit,
+    #[serde(default)]
     pub request_networking: bool,
+    #[serde(default)]
     pub request_capabilities: Vec<serde_json::Value>,
+    #[serde(default)]
     pub grant_capabilities: Vec<serde_json::Value>,
+    #[serde(default)]
     pub public: bool,
 }
 
 /// the type that gets deserialized from a `scripts.json` object
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DotScriptsEntry {
+    #[serde(default)]
     pub root: bool,
+    #[serde(default)]
     pub public: bool,
+    #[serde(default)]
     pub request_networking: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub request_capabilities: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub grant_capabilities: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub wit_version: Option<u32>,
 }
 
@@ -365,137 +480,496 @@ impl std::fmt::Display for Message {
 //
 // conversions between wit types and kernel types (annoying!)
 //
+// each pair has From impls both ways, which destructure fully so that a field
+// added on either side fails to compile until it is converted. Address and
+// ProcessId are the wit types themselves, so need none. the free functions
+// are the same conversions under the names the runtime has always used.
+//
 
-pub fn de_wit_address(wit: wit::Address) -> Address {
-    Address {
-        node: wit.node,
-        process: wit.process,
+impl From<wit::LazyLoadBlob> for LazyLoadBlob {
+    fn from(wit: wit::LazyLoadBlob) -> Self {
+        let wit::LazyLoadBlob { mime, bytes } = wit;
+        LazyLoadBlob { mime, bytes }
     }
 }
 
-pub fn en_wit_address(address: Address) -> wit::Address {
-    wit::Address {
-        node: address.node,
-        process: address.process,
+impl From<LazyLoadBlob> for wit::LazyLoadBlob {
+    fn from(blob: LazyLoadBlob) -> Self {
+        let LazyLoadBlob { mime, bytes } = blob;
+        wit::LazyLoadBlob { mime, bytes }
     }
 }
 
-pub fn de_wit_request(wit: wit::Request) -> Request {
-    Request {
-        inherit: wit.inherit,
-        expects_response: wit.expects_response,
-        body: wit.body,
-        metadata: wit.metadata,
-        capabilities: wit
-            .capabilities
-            .into_iter()
-            .map(de_wit_capability)
-            .collect(),
+impl From<wit::Capability> for Capability {
+    fn from(wit: wit::Capability) -> Self {
+        let wit::Capability { issuer, params } = wit;
+        Capability { issuer, params }
     }
 }
 
-pub fn en_wit_request(request: Request) -> wit::Request {
-    wit::Request {
-        inherit: request.inherit,
-        expects_response: request.expects_response,
-        body: request.body,
-        metadata: request.metadata,
-        capabilities: request
-            .capabilities
-            .into_iter()
-            .map(en_wit_capability)
-            .collect(),
+impl From<Capability> for wit::Capability {
+    fn from(cap: Capability) -> Self {
+        let Capability { issuer, params } = cap;
+        wit::Capability { issuer, params }
     }
 }
 
-pub fn de_wit_response(wit: wit::Response) -> Response {
-    Response {
-        inherit: wit.inherit,
-        body: wit.body,
-        metadata: wit.metadata,
-        capabilities: wit
-            .capabilities
-            .into_iter()
-            .map(de_wit_capability)
-            .collect(),
+impl From<wit::Request> for Request {
+    fn from(wit: wit::Request) -> Self {
+        let wit::Request {
+            inherit,
+            expects_response,
+            body,
+            metadata,
+            capabilities,
+        } = wit;
+        Request {
+            inherit,
+            expects_response,
+            body,
+            metadata,
+            capabilities: de_wit_capabilities(capabilities),
+        }
     }
 }
 
-pub fn en_wit_response(response: Response) -> wit::Response {
-    wit::Response {
-        inherit: response.inherit,
-        body: response.body,
-        metadata: response.metadata,
-        capabilities: response
-            .capabilities
-            .into_iter()
-            .map(en_wit_capability)
-            .collect(),
+impl From<Request> for wit::Request {
+    fn from(request: Request) -> Self {
+        let Request {
+            inherit,
+            expects_response,
+            body,
+            metadata,
+            capabilities,
+        } = request;
+        wit::Request {
+            inherit,
+            expects_response,
+            body,
+            metadata,
+            capabilities: en_wit_capabilities(capabilities),
+        }
     }
 }
 
-pub fn de_wit_blob(wit: Option<wit::LazyLoadBlob>) -> Option<LazyLoadBlob> {
-    match wit {
-        None => None,
-        Some(wit) => Some(LazyLoadBlob {
-            mime: wit.mime,
-            bytes: wit.bytes,
-        }),
+impl From<wit::Response> for Response {
+    fn from(wit: wit::Response) -> Self {
+        let wit::Response {
+            inherit,
+            body,
+            metadata,
+            capabilities,
+        } = wit;
+        Response {
+            inherit,
+            body,
+            metadata,
+            capabilities: de_wit_capabilities(capabilities),
+        }
     }
 }
 
-pub fn en_wit_blob(load: Option<LazyLoadBlob>) -> Option<wit::LazyLoadBlob> {
-    match load {
-        None => None,
-        Some(load) => Some(wit::LazyLoadBlob {
-            mime: load.mime,
-            bytes: load.bytes,
-        }),
+impl From<Response> for wit::Response {
+    fn from(response: Response) -> Self {
+        let Response {
+            inherit,
+            body,
+            metadata,
+            capabilities,
+        } = response;
+        wit::Response {
+            inherit,
+            body,
+            metadata,
+            capabilities: en_wit_capabilities(capabilities),
+        }
     }
 }
 
-pub fn de_wit_capability(wit: wit::Capability) -> Capability {
-    Capability {
-        issuer: Address {
-            node: wit.issuer.node,
-            process: ProcessId {
-                process_name: wit.issuer.process.process_name,
-                package_name: wit.issuer.process.package_name,
-                publisher_node: wit.issuer.process.publisher_node,
-            },
-        },
-        params: wit.params,
+impl From<wit::Message> for Message {
+    fn from(wit: wit::Message) -> Self {
+        match wit {
+            wit::Message::Request(request) => Message::Request(request.into()),
+            wit::Message::Response((response, context)) => {
+                Message::Response((response.into(), context))
+            }
+        }
     }
 }
 
-pub fn en_wit_capability(cap: Capability) -> wit::Capability {
-    wit::Capability {
-        issuer: en_wit_address(cap.issuer),
-        params: cap.params,
+impl From<Message> for wit::Message {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Request(request) => wit::Message::Request(request.into()),
+            Message::Response((response, context)) => {
+                wit::Message::Response((response.into(), context))
+            }
+        }
     }
 }
 
-pub fn en_wit_message(message: Message) -> wit::Message {
-    match message {
-        Message::Request(request) => wit::Message::Request(en_wit_request(request)),
-        Message::Response((response, context)) => {
-            wit::Message::Response((en_wit_response(response), context))
+impl From<wit::SendErrorKind> for SendErrorKind {
+    fn from(wit: wit::SendErrorKind) -> Self {
+        match wit {
+            wit::SendErrorKind::Offline => SendErrorKind::Offline,
+            wit::SendErrorKind::Timeout => SendErrorKind::Timeout,
         }
     }
 }
 
-pub fn en_wit_send_error(error: SendError) -> wit::SendError {
-    wit::SendError {
-        kind: en_wit_send_error_kind(error.kind),
-        target: en_wit_address(error.target),
-        message: en_wit_message(error.message),
-        lazy_load_blob: en_wit_blob(error.lazy_load_blob),
+impl From<SendErrorKind> for wit::SendErrorKind {
+    fn from(kind: SendErrorKind) -> Self {
+        match kind {
+            SendErrorKind::Offline => wit::SendErrorKind::Offline,
+            SendErrorKind::Timeout => wit::SendErrorKind::Timeout,
+        }
+    }
+}
+
+impl From<wit::SendError> for SendError {
+    fn from(wit: wit::SendError) -> Self {
+        let wit::SendError {
+            kind,
+            target,
+            message,
+            lazy_load_blob,
+        } = wit;
+        SendError {
+            kind: kind.into(),
+            target,
+            message: message.into(),
+            lazy_load_blob: de_wit_blob(lazy_load_blob),
+        }
+    }
+}
+
+impl From<SendError> for wit::SendError {
+    fn from(error: SendError) -> Self {
+        let SendError {
+            kind,
+            target,
+            message,
+            lazy_load_blob,
+        } = error;
+        wit::SendError {
+            kind: kind.into(),
+            target,
+            message: message.into(),
+            lazy_load_blob: en_wit_blob(lazy_load_blob),
+        }
+    }
+}
+
+impl From<wit::OnExit> for OnExit {
+    fn from(wit: wit::OnExit) -> Self {
+        match wit {
+            wit::OnExit::None => OnExit::None,
+            wit::OnExit::Restart => OnExit::Restart,
+            wit::OnExit::Requests(requests) => OnExit::Requests(
+                requests
+                    .into_iter()
+                    .map(|(target, request, blob)| (target, request.into(), de_wit_blob(blob)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<OnExit> for wit::OnExit {
+    fn from(on_exit: OnExit) -> Self {
+        match on_exit {
+            OnExit::None => wit::OnExit::None,
+            OnExit::Restart => wit::OnExit::Restart,
+            OnExit::Requests(requests) => wit::OnExit::Requests(
+                requests
+                    .into_iter()
+                    .map(|(target, request, blob)| (target, request.into(), en_wit_blob(blob)))
+                    .collect(),
+            ),
+        }
     }
 }
 
+pub fn de_wit_address(wit: wit::Address) -> Address {
+    wit
+}
+
+pub fn en_wit_address(address: Address) -> wit::Address {
+    address
+}
+
+pub fn de_wit_request(wit: wit::Request) -> Request {
+    wit.into()
+}
+
+pub fn en_wit_request(request: Request) -> wit::Request {
+    request.into()
+}
+
+pub fn de_wit_response(wit: wit::Response) -> Response {
+    wit.into()
+}
+
+pub fn en_wit_response(response: Response) -> wit::Response {
+    response.into()
+}
+
+pub fn de_wit_blob(wit: Option<wit::LazyLoadBlob>) -> Option<LazyLoadBlob> {
+    wit.map(Into::into)
+}
+
+pub fn en_wit_blob(load: Option<LazyLoadBlob>) -> Option<wit::LazyLoadBlob> {
+    load.map(Into::into)
+}
+
+pub fn de_wit_capability(wit: wit::Capability) -> Capability {
+    wit.into()
+}
+
+pub fn en_wit_capability(cap: Capability) -> wit::Capability {
+    cap.into()
+}
+
+pub fn de_wit_capabilities(wit: Vec<wit::Capability>) -> Vec<Capability> {
+    wit.into_iter().map(Into::into).collect()
+}
+
+pub fn en_wit_capabilities(caps: Vec<Capability>) -> Vec<wit::Capability> {
+    caps.into_iter().map(Into::into).collect()
+}
+
+pub fn de_wit_message(wit: wit::Message) -> Message {
+    wit.into()
+}
+
+pub fn en_wit_message(message: Message) -> wit::Message {
+    message.into()
+}
+
+pub fn de_wit_send_error(wit: wit::SendError) -> SendError {
+    wit.into()
+}
+
+pub fn en_wit_send_error(error: SendError) -> wit::SendError {
+    error.into()
+}
+
+pub fn de_wit_send_error_kind(wit: wit::SendErrorKind) -> SendErrorKind {
+    wit.into()
+}
+
 pub fn en_wit_send_error_kind(kind: SendErrorKind) -> wit::SendErrorKind {
-    match kind {
-        SendErrorKind::Offline => wit::SendErrorKind::Offline,
-        SendErrorKind::Timeout => wit::SendErrorKind::Timeout,
+    kind.into()
+}
+
+pub fn de_wit_on_exit(wit: wit::OnExit) -> OnExit {
+    wit.into()
+}
+
+pub fn en_wit_on_exit(on_exit: OnExit) -> wit::OnExit {
+    on_exit.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISSUER: &str = "our.os@kv:distro:sys";
+
+    #[test]
+    fn test_older_shapes() {
+        // written before inherit, metadata and capabilities were sent
+        let request: Request = serde_json::from_str(r#"{"body": [1, 2]}"#).unwrap();
+        assert_eq!(request.body, [1, 2]);
+        assert!(!request.inherit && request.capabilities.is_empty());
+        assert_eq!(request.expects_response, None);
+
+        let persisted: PersistedProcess =
+            serde_json::from_str(r#"{"wasm_bytes_handle": "/a.wasm", "on_exit": "Restart"}"#)
+                .unwrap();
+        assert!(persisted.on_exit.is_restart());
+        assert!(persisted.capabilities.is_empty() && !persisted.public);
+
+        let command: KernelCommand = serde_json::from_str(
+            r#"{"InitializeProcess": {
+                "id": "chat:chat:template.os",
+                "wasm_bytes_handle": "/chat.wasm",
+                "on_exit": "None"
+            }}"#,
+        )
+        .unwrap();
+        let KernelCommand::InitializeProcess {
+            wit_version,
+            initial_capabilities,
+            public,
+            ..
+        } = command
+        else {
+            panic!("got {command:?}");
+        };
+        assert_eq!(wit_version, None);
+        assert!(initial_capabilities.is_empty() && !public);
+
+        let scripts: DotScriptsEntry = serde_json::from_str("{}").unwrap();
+        assert!(!scripts.root && scripts.request_capabilities.is_none());
+    }
+
+    #[test]
+    fn test_newer_shapes() {
+        // fields added since are ignored
+        let response: Response = serde_json::from_str(&format!(
+            r#"{{"inherit": true, "body": [], "metadata": null,
+                "capabilities": [{{"issuer": "{ISSUER}", "params": "\"read\""}}],
+                "priority": 3}}"#
+        ))
+        .unwrap();
+        assert_eq!(response.capabilities[0].issuer.node, "our.os");
+
+        // as are unit variants
+        for unknown in [r#""Restart""#, r#"{"Restart": null}"#] {
+            let command: KernelCommand = serde_json::from_str(unknown).unwrap();
+            assert!(matches!(command, KernelCommand::Unknown));
+        }
+        let response: KernelResponse = serde_json::from_str(r#""Paused""#).unwrap();
+        assert!(matches!(response, KernelResponse::Unknown));
+        let response: StateResponse = serde_json::from_str(r#""Restored""#).unwrap();
+        assert!(matches!(response, StateResponse::Unknown));
+        let error: StateError = serde_json::from_str(r#""DiskFull""#).unwrap();
+        assert_eq!(error.kind(), "Unknown");
+        // but variants with fields still fail
+        assert!(serde_json::from_str::<KernelCommand>(r#"{"Restart": {"id": 1}}"#).is_err());
+
+        // known variants are unaffected
+        let response: KernelResponse =
+            serde_json::from_str(r#"{"KilledProcess": "chat:chat:template.os"}"#).unwrap();
+        assert!(matches!(response, KernelResponse::KilledProcess(id) if id.process() == "chat"));
+    }
+
+    #[test]
+    fn test_package_types_skip_absent_fields() {
+        let properties: Erc721Properties = serde_json::from_str(
+            r#"{"package_name": "chat", "publisher": "template.os", "current_version": "0.1.0"}"#,
+        )
+        .unwrap();
+        let written = serde_json::to_value(&properties).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "package_name": "chat",
+                "publisher": "template.os",
+                "current_version": "0.1.0",
+                "mirrors": [],
+                "code_hashes": {},
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_compat() {
+        assert_eq!(check_compat(PROTOCOL_VERSION), Ok(()));
+        assert_eq!(check_compat("1.7.2"), Ok(()));
+        assert_eq!(check_compat("1"), Ok(()));
+        for peer in ["0.9.0", "2.0.0", "", "1.x", "v1.0.0"] {
+            assert_eq!(
+                check_compat(peer),
+                Err(IncompatibleVersion {
+                    peer: peer.to_string()
+                })
+            );
+        }
+    }
+
+    fn cap(params: &str) -> Capability {
+        Capability {
+            issuer: ISSUER.parse().unwrap(),
+            params: params.to_string(),
+        }
+    }
+
+    fn request() -> Request {
+        Request {
+            inherit: true,
+            expects_response: Some(5),
+            body: b"ping".to_vec(),
+            metadata: Some("{}".to_string()),
+            capabilities: vec![cap("\"read\""), cap("\"write\"")],
+        }
+    }
+
+    fn response() -> Response {
+        Response {
+            inherit: false,
+            body: b"pong".to_vec(),
+            metadata: None,
+            capabilities: vec![cap("{}")],
+        }
+    }
+
+    fn blob() -> LazyLoadBlob {
+        LazyLoadBlob {
+            mime: Some("text/plain".to_string()),
+            bytes: b"blob".to_vec(),
+        }
+    }
+
+    /// kernel -> wit -> kernel gives back kernel, and wit -> kernel -> wit gives
+    /// back wit. Most wit types aren't PartialEq, so those are compared by Debug.
+    fn assert_round_trip<K, W>(kernel: K)
+    where
+        K: Clone + std::fmt::Debug + PartialEq + Into<W>,
+        W: std::fmt::Debug + Into<K>,
+    {
+        let wit: W = kernel.clone().into();
+        let wit_debug = format!("{wit:?}");
+        let back: K = wit.into();
+        assert_eq!(back, kernel);
+        let again: W = back.into();
+        assert_eq!(format!("{again:?}"), wit_debug);
+    }
+
+    #[test]
+    fn test_wit_round_trips() {
+        assert_round_trip::<_, wit::LazyLoadBlob>(blob());
+        assert_round_trip::<_, wit::Capability>(cap("\"read\""));
+        assert_round_trip::<_, wit::Request>(request());
+        assert_round_trip::<_, wit::Request>(Request {
+            expects_response: None,
+            capabilities: vec![],
+            ..request()
+        });
+        assert_round_trip::<_, wit::Response>(response());
+        assert_round_trip::<_, wit::Message>(Message::Request(request()));
+        assert_round_trip::<_, wit::Message>(Message::Response((response(), None)));
+        assert_round_trip::<_, wit::Message>(Message::Response((
+            response(),
+            Some(b"context".to_vec()),
+        )));
+        assert_round_trip::<_, wit::SendErrorKind>(SendErrorKind::Offline);
+        assert_round_trip::<_, wit::SendErrorKind>(SendErrorKind::Timeout);
+        for lazy_load_blob in [None, Some(blob())] {
+            assert_round_trip::<_, wit::SendError>(SendError {
+                kind: SendErrorKind::Timeout,
+                target: "peer.os@chat:chat:template.os".parse().unwrap(),
+                message: Message::Request(request()),
+                lazy_load_blob,
+            });
+        }
+        assert_round_trip::<_, wit::OnExit>(OnExit::None);
+        assert_round_trip::<_, wit::OnExit>(OnExit::Restart);
+        assert_round_trip::<_, wit::OnExit>(OnExit::Requests(vec![
+            (ISSUER.parse().unwrap(), request(), Some(blob())),
+            (ISSUER.parse().unwrap(), request(), None),
+        ]));
+    }
+
+    #[test]
+    fn test_capability_collections() {
+        let caps = vec![cap("\"read\""), cap("{}")];
+        let wit = en_wit_capabilities(caps.clone());
+        assert_eq!(wit.len(), 2);
+        assert_eq!(wit[0].params, "\"read\"");
+        assert_eq!(de_wit_capabilities(wit), caps);
+        assert!(de_wit_capabilities(vec![]).is_empty());
+        assert_eq!(de_wit_blob(en_wit_blob(None)), None);
     }
 }
@@ -1,5 +1,6 @@
-use crate::{get_blob, Message, PackageId, Request};
+use crate::{get_blob, timer::Deadline, Message, PackageId, Request};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use thiserror::Error;
 
@@ -147,120 +148,157 @@ pub enum KvCapabilityKind {
     Write,
 }
 
+/// How a [`Kv`] serializes typed keys and values. Raw methods like
+/// [`Kv::get_raw()`] bypass the codec.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvCodec {
+    #[default]
+    Json,
+    Bincode,
+}
+
+impl KvCodec {
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            KvCodec::Json => serde_json::to_vec(value)?,
+            KvCodec::Bincode => bincode::serialize(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> anyhow::Result<T> {
+        match self {
+            KvCodec::Json => serde_json::from_slice(bytes).map_err(anyhow::Error::from),
+            KvCodec::Bincode => bincode::deserialize(bytes).map_err(anyhow::Error::from),
+        }
+        .map_err(|e| anyhow::anyhow!("Failed to deserialize value: {}", e))
+    }
+}
+
 /// Kv helper struct for a db.
 /// Opening or creating a kv will give you a `Result<Kv>`.
 /// You can call it's impl functions to interact with it.
+///
+/// Errors returned by `kv:distro:sys` are [`KvError`]s and can be recovered with
+/// [`anyhow::Error::downcast_ref()`], e.g. to tell [`KvError::NoDb`] from
+/// [`KvError::NoReadCap`]. Use [`Kv::try_get()`] to treat a missing key as `None`.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Kv<K, V> {
     pub package_id: PackageId,
     pub db: String,
     pub timeout: u64,
+    #[serde(default)]
+    pub codec: KvCodec,
+    /// Bounds every request instead of timeout, if set.
+    #[serde(skip)]
+    pub deadline: Option<Deadline>,
     _marker: PhantomData<(K, V)>,
 }
 
-impl<K, V> Kv<K, V>
-where
-    K: Serialize + DeserializeOwned,
-    V: Serialize + DeserializeOwned,
-{
-    /// Get a value.
-    pub fn get(&self, key: &K) -> anyhow::Result<V> {
-        let key = serde_json::to_vec(key)?;
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
+impl<K, V> Kv<K, V> {
+    /// Serialize keys and values with codec instead of JSON.
+    /// Every user of a db must agree on its codec.
+    pub fn with_codec(mut self, codec: KvCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Give every request up to what is left of deadline, instead of
+    /// [`Kv::timeout`] each, e.g. to bound a run of operations as a whole.
+    /// Requests made once it has passed fail with
+    /// [`crate::BuildError::DeadlineExceeded`].
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Send action to `kv:distro:sys`, with blob as the request blob if given.
+    /// A [`KvResponse::Err`] is returned as an error.
+    fn send(&self, action: KvAction, blob: Option<Vec<u8>>) -> anyhow::Result<KvResponse> {
+        let mut request = Request::new()
+            .target(crate::SystemProcess::Kv.address(crate::our_node()))
             .body(serde_json::to_vec(&KvRequest {
                 package_id: self.package_id.clone(),
                 db: self.db.clone(),
-                action: KvAction::Get(key),
-            })?)
-            .send_and_await_response(self.timeout)?;
+                action,
+            })?);
+        if let Some(blob) = blob {
+            request = request.blob_bytes(blob);
+        }
+        let res = match &self.deadline {
+            Some(deadline) => request.send_and_await_with_deadline(deadline)?,
+            None => request.send_and_await_response(self.timeout)?,
+        };
 
         match res {
             Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Get { .. } => {
-                        let bytes = match get_blob() {
-                            Some(bytes) => bytes.bytes,
-                            None => return Err(anyhow::anyhow!("kv: no blob")),
-                        };
-                        let value = serde_json::from_slice::<V>(&bytes)
-                            .map_err(|e| anyhow::anyhow!("Failed to deserialize value: {}", e))?;
-                        Ok(value)
-                    }
+                match serde_json::from_slice::<KvResponse>(&body)? {
                     KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
+                    response => Ok(response),
                 }
             }
             _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
         }
     }
 
+    /// Get the bytes stored at key, or `None` if there are none.
+    fn get_bytes(&self, key: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.send(KvAction::Get(key), None) {
+            Ok(KvResponse::Get { .. }) => match get_blob() {
+                Some(bytes) => Ok(Some(bytes.bytes)),
+                None => Err(anyhow::anyhow!("kv: no blob")),
+            },
+            Ok(response) => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
+            Err(e) if is_key_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn expect_ok(response: KvResponse) -> anyhow::Result<()> {
+        match response {
+            KvResponse::Ok => Ok(()),
+            _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
+        }
+    }
+}
+
+impl<K, V> Kv<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Get a value.
+    pub fn get(&self, key: &K) -> anyhow::Result<V> {
+        self.get_as(key)
+    }
+
     /// Get a value as a different type T
     pub fn get_as<T>(&self, key: &K) -> anyhow::Result<T>
     where
         T: DeserializeOwned,
     {
-        let key = serde_json::to_vec(key)?;
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Get(key),
-            })?)
-            .send_and_await_response(self.timeout)?;
+        self.try_get_as(key)?
+            .ok_or_else(|| KvError::KeyNotFound.into())
+    }
 
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Get { .. } => {
-                        let bytes = match get_blob() {
-                            Some(bytes) => bytes.bytes,
-                            None => return Err(anyhow::anyhow!("kv: no blob")),
-                        };
-                        let value = serde_json::from_slice::<T>(&bytes)
-                            .map_err(|e| anyhow::anyhow!("Failed to deserialize value: {}", e))?;
-                        Ok(value)
-                    }
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
+    /// Get a value, or `None` if key is not in the db.
+    pub fn try_get(&self, key: &K) -> anyhow::Result<Option<V>> {
+        self.try_get_as(key)
+    }
+
+    /// Get a value as a different type T, or `None` if key is not in the db.
+    pub fn try_get_as<T>(&self, key: &K) -> anyhow::Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        match self.get_bytes(self.codec.encode(key)?)? {
+            Some(bytes) => Ok(Some(self.codec.decode(&bytes)?)),
+            None => Ok(None),
         }
     }
 
     /// Set a value, optionally in a transaction.
     pub fn set(&self, key: &K, value: &V, tx_id: Option<u64>) -> anyhow::Result<()> {
-        let key = serde_json::to_vec(key)?;
-        let value = serde_json::to_vec(value)?;
-
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Set { key, tx_id },
-            })?)
-            .blob_bytes(value)
-            .send_and_await_response(self.timeout)?;
-
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
-        }
+        self.set_as(key, value, tx_id)
     }
 
     /// Set a value as a different type T
@@ -268,57 +306,14 @@ where
     where
         T: Serialize,
     {
-        let key = serde_json::to_vec(key)?;
-        let value = serde_json::to_vec(value)?;
-
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Set { key, tx_id },
-            })?)
-            .blob_bytes(value)
-            .send_and_await_response(self.timeout)?;
-
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
-        }
+        let key = self.codec.encode(key)?;
+        let value = self.codec.encode(value)?;
+        Self::expect_ok(self.send(KvAction::Set { key, tx_id }, Some(value))?)
     }
 
     /// Delete a value, optionally in a transaction.
     pub fn delete(&self, key: &K, tx_id: Option<u64>) -> anyhow::Result<()> {
-        let key = serde_json::to_vec(key)?;
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Delete { key, tx_id },
-            })?)
-            .send_and_await_response(self.timeout)?;
-
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
-        }
+        self.delete_as(key, tx_id)
     }
 
     /// Delete a value with a different key type
@@ -326,170 +321,735 @@ where
     where
         T: Serialize,
     {
-        let key = serde_json::to_vec(key)?;
-
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Delete { key, tx_id },
-            })?)
-            .send_and_await_response(self.timeout)?;
-
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
-        }
+        let key = self.codec.encode(key)?;
+        Self::expect_ok(self.send(KvAction::Delete { key, tx_id }, None)?)
     }
 
     /// Begin a transaction.
     pub fn begin_tx(&self) -> anyhow::Result<u64> {
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::BeginTx,
-            })?)
-            .send_and_await_response(self.timeout)?;
-
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::BeginTx { tx_id } => Ok(tx_id),
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
+        match self.send(KvAction::BeginTx, None)? {
+            KvResponse::BeginTx { tx_id } => Ok(tx_id),
+            response => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
         }
     }
 
     /// Commit a transaction.
     pub fn commit_tx(&self, tx_id: u64) -> anyhow::Result<()> {
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Commit { tx_id },
-            })?)
-            .send_and_await_response(self.timeout)?;
-
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
+        Self::expect_ok(self.send(KvAction::Commit { tx_id }, None)?)
+    }
 
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err(error) => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
+    /// Start collecting writes to commit together with [`Batch::commit()`].
+    pub fn batch(&self) -> Batch<'_, K, V> {
+        Batch {
+            kv: self,
+            ops: Vec::new(),
+            max_ops: DEFAULT_MAX_BATCH_OPS,
+            allow_split: false,
+            error: None,
         }
     }
+
+    /// Run f in a transaction, committing its writes if it returns `Ok` and
+    /// abandoning them if it returns `Err`.
+    ///
+    /// Writes are sent as they are made, but only become visible to others on commit.
+    /// `kv:distro:sys` does not read through transactions, so [`Txn::get()`] answers
+    /// from the writes made so far in this transaction before falling back to the db.
+    pub fn transaction<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut Txn<'_, K, V>) -> anyhow::Result<T>,
+    {
+        let mut txn = Txn {
+            kv: self,
+            tx_id: self.begin_tx()?,
+            written: HashMap::new(),
+        };
+        let result = f(&mut txn)?;
+        self.commit_tx(txn.tx_id)?;
+        Ok(result)
+    }
 }
 
 impl Kv<Vec<u8>, Vec<u8>> {
     /// Get raw bytes directly
     pub fn get_raw(&self, key: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Get(key.to_vec()),
-            })?)
-            .send_and_await_response(self.timeout)?;
+        self.try_get_raw(key)?
+            .ok_or_else(|| KvError::KeyNotFound.into())
+    }
 
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-                match response {
-                    KvResponse::Get { .. } => {
-                        let bytes = match get_blob() {
-                            Some(bytes) => bytes.bytes,
-                            None => return Err(anyhow::anyhow!("kv: no blob")),
-                        };
-                        Ok(bytes)
-                    }
-                    KvResponse::Err { 0: error } => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
-            }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
-        }
+    /// Get raw bytes directly, or `None` if key is not in the db.
+    pub fn try_get_raw(&self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        self.get_bytes(key.to_vec())
     }
 
     /// Set raw bytes directly
     pub fn set_raw(&self, key: &[u8], value: &[u8], tx_id: Option<u64>) -> anyhow::Result<()> {
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Set {
-                    key: key.to_vec(),
-                    tx_id,
+        let action = KvAction::Set {
+            key: key.to_vec(),
+            tx_id,
+        };
+        Self::expect_ok(self.send(action, Some(value.to_vec()))?)
+    }
+
+    /// Delete raw bytes directly
+    pub fn delete_raw(&self, key: &[u8], tx_id: Option<u64>) -> anyhow::Result<()> {
+        let action = KvAction::Delete {
+            key: key.to_vec(),
+            tx_id,
+        };
+        Self::expect_ok(self.send(action, None)?)
+    }
+}
+
+/// Default cap on the number of operations in a [`Batch`].
+pub const DEFAULT_MAX_BATCH_OPS: usize = 1000;
+
+/// A single write collected by a [`Batch`], with key and value already encoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl BatchOp {
+    /// The action (and request blob) carrying this op in transaction tx_id.
+    pub fn to_action(&self, tx_id: u64) -> (KvAction, Option<Vec<u8>>) {
+        match self {
+            BatchOp::Set { key, value } => (
+                KvAction::Set {
+                    key: key.clone(),
+                    tx_id: Some(tx_id),
                 },
-            })?)
-            .blob_bytes(value.to_vec())
-            .send_and_await_response(self.timeout)?;
+                Some(value.clone()),
+            ),
+            BatchOp::Delete { key } => (
+                KvAction::Delete {
+                    key: key.clone(),
+                    tx_id: Some(tx_id),
+                },
+                None,
+            ),
+        }
+    }
+}
 
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
+/// Writes collected locally and committed together in one transaction.
+/// Created with [`Kv::batch()`].
+///
+/// A batch holds at most `max_ops` operations. Larger batches fail to commit
+/// unless [`Batch::allow_split()`] is set, in which case they are committed as
+/// several transactions of up to `max_ops` each, and are no longer atomic as a whole.
+pub struct Batch<'a, K, V> {
+    kv: &'a Kv<K, V>,
+    ops: Vec<BatchOp>,
+    max_ops: usize,
+    allow_split: bool,
+    error: Option<anyhow::Error>,
+}
 
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err { 0: error } => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-                }
+impl<K, V> Batch<'_, K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn set(&mut self, key: &K, value: &V) -> &mut Self {
+        let op = self
+            .kv
+            .codec
+            .encode(key)
+            .and_then(|key| Ok((key, self.kv.codec.encode(value)?)));
+        match op {
+            Ok((key, value)) => self.ops.push(BatchOp::Set { key, value }),
+            Err(e) => self.error = self.error.take().or(Some(e)),
+        }
+        self
+    }
+
+    pub fn delete(&mut self, key: &K) -> &mut Self {
+        match self.kv.codec.encode(key) {
+            Ok(key) => self.ops.push(BatchOp::Delete { key }),
+            Err(e) => self.error = self.error.take().or(Some(e)),
+        }
+        self
+    }
+
+    /// Set raw bytes, bypassing the codec.
+    pub fn set_raw(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    /// Delete raw bytes, bypassing the codec.
+    pub fn delete_raw(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.to_vec() });
+        self
+    }
+
+    /// Set the cap on operations per transaction. Defaults to [`DEFAULT_MAX_BATCH_OPS`].
+    pub fn max_ops(&mut self, max_ops: usize) -> &mut Self {
+        self.max_ops = max_ops.max(1);
+        self
+    }
+
+    /// Allow a batch over `max_ops` to be committed as several transactions.
+    pub fn allow_split(&mut self, allow_split: bool) -> &mut Self {
+        self.allow_split = allow_split;
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Send every collected operation. Nothing is sent if any key or value
+    /// failed to encode, or if the batch is too big and may not be split.
+    pub fn commit(self) -> anyhow::Result<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        for chunk in split_batch(&self.ops, self.max_ops, self.allow_split)? {
+            let tx_id = self.kv.begin_tx()?;
+            for op in chunk {
+                let (action, blob) = op.to_action(tx_id);
+                Kv::<K, V>::expect_ok(self.kv.send(action, blob)?)?;
             }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
+            self.kv.commit_tx(tx_id)?;
         }
+        Ok(())
     }
+}
 
-    /// Delete raw bytes directly
-    pub fn delete_raw(&self, key: &[u8], tx_id: Option<u64>) -> anyhow::Result<()> {
-        let res = Request::new()
-            .target(("our", "kv", "distro", "sys"))
-            .body(serde_json::to_vec(&KvRequest {
-                package_id: self.package_id.clone(),
-                db: self.db.clone(),
-                action: KvAction::Delete {
-                    key: key.to_vec(),
-                    tx_id,
-                },
-            })?)
-            .send_and_await_response(self.timeout)?;
+/// The transactions to commit ops in.
+fn split_batch(
+    ops: &[BatchOp],
+    max_ops: usize,
+    allow_split: bool,
+) -> anyhow::Result<Vec<&[BatchOp]>> {
+    if ops.len() > max_ops && !allow_split {
+        return Err(anyhow::anyhow!(
+            "kv: batch of {} operations exceeds the limit of {}; allow_split(true) to commit it non-atomically",
+            ops.len(),
+            max_ops
+        ));
+    }
+    Ok(ops.chunks(max_ops.max(1)).collect())
+}
 
-        match res {
-            Ok(Message::Response { body, .. }) => {
-                let response = serde_json::from_slice::<KvResponse>(&body)?;
+/// A transaction in progress, handed to the closure passed to [`Kv::transaction()`].
+pub struct Txn<'a, K, V> {
+    kv: &'a Kv<K, V>,
+    tx_id: u64,
+    /// Keys written in this transaction, mapped to their new value or `None` if deleted.
+    written: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<K, V> Txn<'_, K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn tx_id(&self) -> u64 {
+        self.tx_id
+    }
 
-                match response {
-                    KvResponse::Ok => Ok(()),
-                    KvResponse::Err { 0: error } => Err(error.into()),
-                    _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
+    /// Get a value as this transaction would see it.
+    pub fn get(&self, key: &K) -> anyhow::Result<Option<V>> {
+        let key = self.kv.codec.encode(key)?;
+        let bytes = match self.written.get(&key) {
+            Some(written) => written.clone(),
+            None => self.kv.get_bytes(key)?,
+        };
+        bytes.map(|bytes| self.kv.codec.decode(&bytes)).transpose()
+    }
+
+    pub fn set(&mut self, key: &K, value: &V) -> anyhow::Result<()> {
+        let key = self.kv.codec.encode(key)?;
+        let value = self.kv.codec.encode(value)?;
+        self.send(BatchOp::Set { key, value })
+    }
+
+    pub fn delete(&mut self, key: &K) -> anyhow::Result<()> {
+        let key = self.kv.codec.encode(key)?;
+        self.send(BatchOp::Delete { key })
+    }
+
+    fn send(&mut self, op: BatchOp) -> anyhow::Result<()> {
+        let (action, blob) = op.to_action(self.tx_id);
+        Kv::<K, V>::expect_ok(self.kv.send(action, blob)?)?;
+        match op {
+            BatchOp::Set { key, value } => self.written.insert(key, Some(value)),
+            BatchOp::Delete { key } => self.written.insert(key, None),
+        };
+        Ok(())
+    }
+}
+
+fn is_key_not_found(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<KvError>(), Some(KvError::KeyNotFound))
+}
+
+/// The fields an [`Indexed`] collection can be searched by, each a name and the
+/// JSON pointer to it in a record, e.g. `("owner", "/owner/name")`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexSpec {
+    fields: Vec<(String, String)>,
+}
+
+impl IndexSpec {
+    pub fn new() -> Self {
+        IndexSpec::default()
+    }
+
+    /// Index records by the value at pointer, as field.
+    pub fn field(mut self, name: &str, pointer: &str) -> Self {
+        self.fields.push((name.to_string(), pointer.to_string()));
+        self
+    }
+}
+
+/// Records of type T kept as JSON in a kv db, under `{name}:{id}`, with an index
+/// entry `idx:{name}:{field}:{value}:{id}` for every field in its [`IndexSpec`],
+/// laid out so that a prefix scan of the db finds records by those fields.
+/// `kv:distro:sys` has no scan yet, so nothing here queries them.
+///
+/// Strings, numbers and booleans are indexed; fields that are missing, `null`,
+/// arrays or objects are not. Numbers are indexed as `f64`s, in an encoding that
+/// sorts as they do, so that a range of keys is a range of values. A record and its index entries
+/// are written in one [`Batch`], so they change together. The previous record is
+/// read first to remove its stale entries, so writes to one id must not race.
+///
+/// ```no_run
+/// use hyperware_process_lib::kv::{self, IndexSpec, Indexed};
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Item {
+///     owner: String,
+///     price: f64,
+/// }
+///
+/// let kv = kv::open_raw(hyperware_process_lib::our().package_id(), "shop", None)?;
+/// let items = Indexed::<Item>::new(
+///     kv,
+///     "item",
+///     IndexSpec::new().field("owner", "/owner").field("price", "/price"),
+/// );
+/// items.put("1", &Item { owner: "alice".into(), price: 9.5 })?;
+/// let item = items.get("1")?;
+/// # anyhow::Ok(())
+/// ```
+pub struct Indexed<T> {
+    kv: Kv<Vec<u8>, Vec<u8>>,
+    name: String,
+    spec: IndexSpec,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Indexed<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn new(kv: Kv<Vec<u8>, Vec<u8>>, name: &str, spec: IndexSpec) -> Self {
+        Indexed {
+            kv,
+            name: name.to_string(),
+            spec,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<T>> {
+        self.get_json(id)?
+            .map(|record| Ok(serde_json::from_value(record)?))
+            .transpose()
+    }
+
+    /// Write record as id, updating its index entries.
+    pub fn put(&self, id: &str, record: &T) -> anyhow::Result<()> {
+        let old = self.get_json(id)?;
+        let ops = self.put_ops(id, old.as_ref(), &serde_json::to_value(record)?)?;
+        self.commit(ops)
+    }
+
+    /// Delete the record id and its index entries. Returns whether there was one.
+    pub fn delete(&self, id: &str) -> anyhow::Result<bool> {
+        let Some(old) = self.get_json(id)? else {
+            return Ok(false);
+        };
+        self.commit(self.delete_ops(id, &old))?;
+        Ok(true)
+    }
+
+    fn get_json(&self, id: &str) -> anyhow::Result<Option<serde_json::Value>> {
+        self.kv
+            .try_get_raw(&self.record_key(id))?
+            .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .transpose()
+    }
+
+    fn commit(&self, ops: Vec<BatchOp>) -> anyhow::Result<()> {
+        let mut batch = self.kv.batch();
+        for op in &ops {
+            match op {
+                BatchOp::Set { key, value } => batch.set_raw(key, value),
+                BatchOp::Delete { key } => batch.delete_raw(key),
+            };
+        }
+        batch.commit()
+    }
+
+    fn record_key(&self, id: &str) -> Vec<u8> {
+        format!("{}:{id}", escape_key_part(&self.name)).into_bytes()
+    }
+
+    /// The index keys of the fields of record, which is stored as id.
+    fn index_keys(&self, id: &str, record: &serde_json::Value) -> Vec<Vec<u8>> {
+        self.spec
+            .fields
+            .iter()
+            .filter_map(|(field, pointer)| {
+                let value = index_value(record.pointer(pointer)?)?;
+                Some(format!("{}{value}:{id}", self.field_prefix(field)).into_bytes())
+            })
+            .collect()
+    }
+
+    /// The writes replacing old, if id has a record, with new.
+    fn put_ops(
+        &self,
+        id: &str,
+        old: Option<&serde_json::Value>,
+        new: &serde_json::Value,
+    ) -> anyhow::Result<Vec<BatchOp>> {
+        let new_keys = self.index_keys(id, new);
+        let mut ops: Vec<BatchOp> = old
+            .map(|old| self.index_keys(id, old))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|key| !new_keys.contains(key))
+            .map(|key| BatchOp::Delete { key })
+            .collect();
+        ops.push(BatchOp::Set {
+            key: self.record_key(id),
+            value: serde_json::to_vec(new)?,
+        });
+        ops.extend(
+            new_keys
+                .into_iter()
+                .map(|key| BatchOp::Set { key, value: vec![] }),
+        );
+        Ok(ops)
+    }
+
+    fn delete_ops(&self, id: &str, old: &serde_json::Value) -> Vec<BatchOp> {
+        let mut ops: Vec<BatchOp> = self
+            .index_keys(id, old)
+            .into_iter()
+            .map(|key| BatchOp::Delete { key })
+            .collect();
+        ops.push(BatchOp::Delete {
+            key: self.record_key(id),
+        });
+        ops
+    }
+
+    fn field_prefix(&self, field: &str) -> String {
+        format!(
+            "idx:{}:{}:",
+            escape_key_part(&self.name),
+            escape_key_part(field)
+        )
+    }
+}
+
+/// How value appears in index keys: tagged by type, with strings escaped so they
+/// hold no `:`, and numbers rewritten so that they sort as they compare.
+fn index_value(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Bool(b) => Some(format!("b{}", u8::from(*b))),
+        serde_json::Value::Number(n) => {
+            // -0.0 is 0.0, and the sign bit flipped (or all bits, for negatives)
+            // makes the bits of a float sort as its value
+            let n = n.as_f64()?;
+            let bits = if n == 0.0 { 0 } else { n.to_bits() };
+            let ordered = if bits >> 63 == 1 {
+                !bits
+            } else {
+                bits | 1 << 63
+            };
+            Some(format!("n{ordered:016x}"))
+        }
+        serde_json::Value::String(s) => Some(format!("s{}", escape_key_part(s))),
+        _ => None,
+    }
+}
+
+fn escape_key_part(part: &str) -> String {
+    part.replace('%', "%25").replace(':', "%3A")
+}
+
+/// The first bytes of a file written by [`backup()`].
+pub const BACKUP_MAGIC: &[u8] = b"HWKVBAK1";
+
+/// How many bytes of entries [`backup()`] collects before appending them to the
+/// file, and [`restore()`] reads at a time.
+pub const BACKUP_CHUNK_BYTES: usize = 64 * 1024;
+
+/// How many entries [`restore()`] writes per transaction, recording its progress
+/// after each.
+pub const RESTORE_BATCH_OPS: usize = 500;
+
+/// Append an entry to out as a [`backup()`] file frames it: the key and then
+/// the value, each as a big-endian `u32` length followed by the bytes.
+pub fn encode_backup_entry(out: &mut Vec<u8>, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+    for part in [key, value] {
+        let len = u32::try_from(part.len())
+            .map_err(|_| anyhow::anyhow!("kv: {}-byte entry is too big to back up", part.len()))?;
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(part);
+    }
+    Ok(())
+}
+
+/// The entry framed at the start of bytes, and how many bytes it takes up, or
+/// `None` if bytes stops before the entry does.
+pub fn decode_backup_entry(bytes: &[u8]) -> Option<(Vec<u8>, Vec<u8>, usize)> {
+    let mut at = 0;
+    let mut parts = [vec![], vec![]];
+    for part in &mut parts {
+        let len = u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().unwrap()) as usize;
+        at += 4;
+        *part = bytes.get(at..at + len)?.to_vec();
+        at += len;
+    }
+    let [key, value] = parts;
+    Some((key, value, at))
+}
+
+/// Write the entries of db at keys, as raw bytes, to the vfs file at dest_path,
+/// replacing it, for [`restore()`] to read back. Keys db has no entry for are
+/// skipped. Entries are read one at a time and appended
+/// [`BACKUP_CHUNK_BYTES`] at a time, so the db need not fit in memory.
+/// Returns how many were written.
+///
+/// The entries are read one after the other, not as a snapshot.
+pub fn backup<K, V, I>(db: &Kv<K, V>, keys: I, dest_path: &str) -> anyhow::Result<u64>
+where
+    K: Serialize,
+    I: IntoIterator<Item = K>,
+{
+    let mut file = crate::vfs::create_file(dest_path, Some(db.timeout))?;
+    let entries = keys.into_iter().filter_map(|key| {
+        let key = match db.codec.encode(&key) {
+            Ok(key) => key,
+            Err(e) => return Some(Err(e)),
+        };
+        db.get_bytes(key.clone())
+            .map(|value| value.map(|value| (key, value)))
+            .transpose()
+    });
+    write_backup(entries, |chunk| Ok(file.append(chunk)?))
+}
+
+fn write_backup<E, A>(entries: E, mut append: A) -> anyhow::Result<u64>
+where
+    E: Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>>,
+    A: FnMut(&[u8]) -> anyhow::Result<()>,
+{
+    let mut chunk = BACKUP_MAGIC.to_vec();
+    let mut count = 0;
+    for entry in entries {
+        let (key, value) = entry?;
+        encode_backup_entry(&mut chunk, &key, &value)?;
+        count += 1;
+        if chunk.len() >= BACKUP_CHUNK_BYTES {
+            append(&chunk)?;
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        append(&chunk)?;
+    }
+    Ok(count)
+}
+
+/// How far a [`restore()`] got, kept next to the backup file while it runs.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RestoreProgress {
+    /// Where in the backup file the next entry to restore starts.
+    pub offset: u64,
+    pub restored: u64,
+    /// The length of the backup file, to tell it apart from a different one
+    /// since written at the same path.
+    pub file_len: u64,
+}
+
+/// Set every entry in the vfs file at src_path, as written by [`backup()`], in
+/// db, [`RESTORE_BATCH_OPS`] per transaction. Entries db has that the backup
+/// doesn't are left alone. Returns how many entries were restored in all.
+///
+/// Progress is recorded at `{src_path}.progress` after each transaction, so a
+/// restore that fails or is cut short picks up where it left off when run
+/// again. The record is removed once the restore completes.
+pub fn restore<K, V>(db: &Kv<K, V>, src_path: &str) -> anyhow::Result<u64>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    let file = crate::vfs::open_file(src_path, false, Some(db.timeout))?;
+    let mut io = KernelRestore {
+        db,
+        file_len: file.metadata()?.len,
+        file,
+        progress_path: format!("{src_path}.progress"),
+    };
+    run_restore(&mut io)
+}
+
+/// What a restore needs from the runtime, so that it can be exercised without one.
+trait RestoreIo {
+    fn file_len(&self) -> u64;
+    /// The len bytes of the backup file from offset, which it must hold.
+    fn read(&mut self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>>;
+    fn apply(&mut self, ops: &[BatchOp]) -> anyhow::Result<()>;
+    fn load_progress(&mut self) -> anyhow::Result<Option<RestoreProgress>>;
+    fn save_progress(&mut self, progress: &RestoreProgress) -> anyhow::Result<()>;
+    fn clear_progress(&mut self) -> anyhow::Result<()>;
+}
+
+struct KernelRestore<'a, K, V> {
+    db: &'a Kv<K, V>,
+    file: crate::vfs::File,
+    file_len: u64,
+    progress_path: String,
+}
+
+impl<K, V> RestoreIo for KernelRestore<'_, K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn file_len(&self) -> u64 {
+        self.file_len
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+        let len = len.min(self.file_len.saturating_sub(offset) as usize);
+        self.file.seek(crate::vfs::SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; len];
+        let read = self.file.read_at(&mut buffer)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    fn apply(&mut self, ops: &[BatchOp]) -> anyhow::Result<()> {
+        let mut batch = self.db.batch();
+        batch.max_ops(ops.len());
+        for op in ops {
+            if let BatchOp::Set { key, value } = op {
+                batch.set_raw(key, value);
+            }
+        }
+        batch.commit()
+    }
+
+    fn load_progress(&mut self) -> anyhow::Result<Option<RestoreProgress>> {
+        use crate::vfs::VfsClientError;
+        match crate::vfs::open_file(&self.progress_path, false, Some(self.db.timeout)) {
+            Ok(file) => Ok(Some(serde_json::from_slice(&file.read()?)?)),
+            Err(VfsClientError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_progress(&mut self, progress: &RestoreProgress) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(progress)?;
+        Ok(crate::vfs::write_atomic(
+            &self.progress_path,
+            &bytes,
+            Some(self.db.timeout),
+        )?)
+    }
+
+    fn clear_progress(&mut self) -> anyhow::Result<()> {
+        Ok(crate::vfs::remove_file(
+            &self.progress_path,
+            Some(self.db.timeout),
+        )?)
+    }
+}
+
+fn run_restore<I: RestoreIo>(io: &mut I) -> anyhow::Result<u64> {
+    let file_len = io.file_len();
+    if file_len < BACKUP_MAGIC.len() as u64 {
+        return Err(anyhow::anyhow!("kv: not a backup file"));
+    }
+    let magic = io.read(0, BACKUP_MAGIC.len())?;
+    if magic != BACKUP_MAGIC {
+        return Err(anyhow::anyhow!("kv: not a backup file"));
+    }
+    let mut progress = match io.load_progress()? {
+        Some(progress) if progress.file_len == file_len => progress,
+        _ => RestoreProgress {
+            offset: BACKUP_MAGIC.len() as u64,
+            restored: 0,
+            file_len,
+        },
+    };
+    // whether there is a progress record to remove once done
+    let mut recorded = progress.offset > BACKUP_MAGIC.len() as u64;
+    // bytes read past the last entry restored, and the entries decoded from them
+    let mut buffer: Vec<u8> = vec![];
+    let mut read_to = progress.offset;
+    let mut ops = vec![];
+    let mut batch_end = progress.offset;
+    loop {
+        match decode_backup_entry(&buffer) {
+            Some((key, value, len)) => {
+                buffer.drain(..len);
+                batch_end += len as u64;
+                ops.push(BatchOp::Set { key, value });
+            }
+            None if read_to < file_len => {
+                // asking for more than is left fails like a read past the end would
+                let len = (file_len - read_to).min(BACKUP_CHUNK_BYTES as u64) as usize;
+                let chunk = io.read(read_to, len)?;
+                if chunk.is_empty() {
+                    return Err(anyhow::anyhow!("kv: backup file ended early"));
                 }
+                read_to += chunk.len() as u64;
+                buffer.extend_from_slice(&chunk);
+                continue;
             }
-            _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
+            None if !buffer.is_empty() => {
+                return Err(anyhow::anyhow!(
+                    "kv: backup file ends in a partial entry at byte {batch_end}"
+                ));
+            }
+            None => {}
+        }
+        let done = read_to >= file_len && buffer.is_empty();
+        if ops.len() >= RESTORE_BATCH_OPS || (done && !ops.is_empty()) {
+            io.apply(&ops)?;
+            progress.offset = batch_end;
+            progress.restored += ops.len() as u64;
+            ops.clear();
+            if !done {
+                io.save_progress(&progress)?;
+                recorded = true;
+            }
+        }
+        if done {
+            break;
         }
     }
+    if recorded {
+        io.clear_progress()?;
+    }
+    Ok(progress.restored)
 }
 
 /// Helper function to open a raw bytes key-value store
@@ -507,59 +1067,537 @@ where
     K: Serialize + DeserializeOwned,
     V: Serialize + DeserializeOwned,
 {
-    let timeout = timeout.unwrap_or(5);
-
-    let res = Request::new()
-        .target(("our", "kv", "distro", "sys"))
-        .body(serde_json::to_vec(&KvRequest {
-            package_id: package_id.clone(),
-            db: db.to_string(),
-            action: KvAction::Open,
-        })?)
-        .send_and_await_response(timeout)?;
-
-    match res {
-        Ok(Message::Response { body, .. }) => {
-            let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-            match response {
-                KvResponse::Ok => Ok(Kv {
-                    package_id,
-                    db: db.to_string(),
-                    timeout,
-                    _marker: PhantomData,
-                }),
-                KvResponse::Err(error) => Err(error.into()),
-                _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
-            }
-        }
-        _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
-    }
+    let kv = Kv {
+        package_id,
+        db: db.to_string(),
+        timeout: timeout.unwrap_or(5),
+        codec: KvCodec::default(),
+        deadline: None,
+        _marker: PhantomData,
+    };
+    Kv::<K, V>::expect_ok(kv.send(KvAction::Open, None)?)?;
+    Ok(kv)
 }
 
 /// Removes and deletes a kv db.
 pub fn remove_db(package_id: PackageId, db: &str, timeout: Option<u64>) -> anyhow::Result<()> {
-    let timeout = timeout.unwrap_or(5);
-
-    let res = Request::new()
-        .target(("our", "kv", "distro", "sys"))
-        .body(serde_json::to_vec(&KvRequest {
-            package_id: package_id.clone(),
-            db: db.to_string(),
-            action: KvAction::RemoveDb,
-        })?)
-        .send_and_await_response(timeout)?;
-
-    match res {
-        Ok(Message::Response { body, .. }) => {
-            let response = serde_json::from_slice::<KvResponse>(&body)?;
-
-            match response {
-                KvResponse::Ok => Ok(()),
-                KvResponse::Err(error) => Err(error.into()),
-                _ => Err(anyhow::anyhow!("kv: unexpected response {:?}", response)),
+    let kv: Kv<(), ()> = Kv {
+        package_id,
+        db: db.to_string(),
+        timeout: timeout.unwrap_or(5),
+        codec: KvCodec::default(),
+        deadline: None,
+        _marker: PhantomData,
+    };
+    Kv::<(), ()>::expect_ok(kv.send(KvAction::RemoveDb, None)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_kv_request_wire_shape() {
+        let request = KvRequest {
+            package_id: PackageId::new("app", "sys"),
+            db: "users".to_string(),
+            action: KvAction::Set {
+                key: b"k".to_vec(),
+                tx_id: None,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "package_id": {"package_name": "app", "publisher_node": "sys"},
+                "db": "users",
+                "action": {"Set": {"key": [107], "tx_id": null}},
+            })
+        );
+
+        let actions = [
+            (KvAction::Open, json!("Open")),
+            (KvAction::RemoveDb, json!("RemoveDb")),
+            (KvAction::Get(vec![1, 2]), json!({"Get": [1, 2]})),
+            (
+                KvAction::Delete {
+                    key: vec![3],
+                    tx_id: Some(7),
+                },
+                json!({"Delete": {"key": [3], "tx_id": 7}}),
+            ),
+            (KvAction::BeginTx, json!("BeginTx")),
+            (
+                KvAction::Commit { tx_id: 7 },
+                json!({"Commit": {"tx_id": 7}}),
+            ),
+        ];
+        for (action, expected) in actions {
+            assert_eq!(serde_json::to_value(&action).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_kv_response_wire_shape() {
+        let parse = |value: serde_json::Value| serde_json::from_value::<KvResponse>(value).unwrap();
+        assert!(matches!(parse(json!("Ok")), KvResponse::Ok));
+        assert!(matches!(
+            parse(json!({"BeginTx": {"tx_id": 3}})),
+            KvResponse::BeginTx { tx_id: 3 }
+        ));
+        assert!(matches!(parse(json!({"Get": [1]})), KvResponse::Get(key) if key == [1]));
+        assert!(matches!(
+            parse(json!({"Err": "KeyNotFound"})),
+            KvResponse::Err(KvError::KeyNotFound)
+        ));
+        assert!(matches!(
+            parse(json!({"Err": "NoReadCap"})),
+            KvResponse::Err(KvError::NoReadCap)
+        ));
+        let KvResponse::Err(KvError::NoDb(package_id, db)) = parse(json!({"Err": {"NoDb": [
+            {"package_name": "app", "publisher_node": "sys"},
+            "users",
+        ]}})) else {
+            panic!("expected NoDb");
+        };
+        assert_eq!(package_id, PackageId::new("app", "sys"));
+        assert_eq!(db, "users");
+    }
+
+    #[test]
+    fn test_key_not_found_is_distinguished() {
+        assert!(is_key_not_found(&KvError::KeyNotFound.into()));
+        assert!(!is_key_not_found(&KvError::NoReadCap.into()));
+        assert!(!is_key_not_found(&anyhow::anyhow!("key not found")));
+    }
+
+    #[test]
+    fn test_batch_op_encoding() {
+        let set = BatchOp::Set {
+            key: b"a".to_vec(),
+            value: b"1".to_vec(),
+        };
+        let (action, blob) = set.to_action(9);
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            json!({"Set": {"key": [97], "tx_id": 9}})
+        );
+        assert_eq!(blob, Some(b"1".to_vec()));
+
+        let (action, blob) = BatchOp::Delete { key: b"a".to_vec() }.to_action(9);
+        assert_eq!(
+            serde_json::to_value(&action).unwrap(),
+            json!({"Delete": {"key": [97], "tx_id": 9}})
+        );
+        assert_eq!(blob, None);
+    }
+
+    #[test]
+    fn test_split_batch_gating() {
+        let ops: Vec<BatchOp> = (0..5u8).map(|i| BatchOp::Delete { key: vec![i] }).collect();
+        // within the cap: one transaction either way
+        assert_eq!(split_batch(&ops, 5, false).unwrap().len(), 1);
+        assert_eq!(split_batch(&ops, 5, true).unwrap().len(), 1);
+        // over the cap: refused unless splitting is allowed
+        assert!(split_batch(&ops, 2, false).is_err());
+        let chunks = split_batch(&ops, 2, true).unwrap();
+        assert_eq!(
+            chunks.iter().map(|c| c.len()).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        assert_eq!(chunks.concat(), ops);
+        assert!(split_batch(&[], 2, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_codecs() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Profile {
+            name: String,
+            age: u32,
+        }
+        let profile = Profile {
+            name: "ada".to_string(),
+            age: 36,
+        };
+        for codec in [KvCodec::Json, KvCodec::Bincode] {
+            let bytes = codec.encode(&profile).unwrap();
+            assert_eq!(codec.decode::<Profile>(&bytes).unwrap(), profile);
+        }
+        assert_eq!(
+            KvCodec::Json.encode(&profile).unwrap(),
+            br#"{"name":"ada","age":36}"#
+        );
+        assert!(KvCodec::Bincode.decode::<Profile>(b"{}").is_err());
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Item {
+        owner: String,
+        price: f64,
+    }
+
+    fn items() -> Indexed<Item> {
+        let kv = Kv {
+            package_id: PackageId::new("app", "sys"),
+            db: "shop".to_string(),
+            timeout: 5,
+            codec: KvCodec::default(),
+            deadline: None,
+            _marker: PhantomData,
+        };
+        let spec = IndexSpec::new()
+            .field("owner", "/owner")
+            .field("price", "/price");
+        Indexed::new(kv, "item", spec)
+    }
+
+    /// Put as [`Indexed::put()`] does, into db instead of kv.
+    fn put(db: &mut BTreeMap<Vec<u8>, Vec<u8>>, items: &Indexed<Item>, id: &str, item: Item) {
+        let old = db
+            .get(&items.record_key(id))
+            .map(|bytes| serde_json::from_slice(bytes).unwrap());
+        let new = serde_json::to_value(item).unwrap();
+        apply(db, items.put_ops(id, old.as_ref(), &new).unwrap());
+    }
+
+    fn apply(db: &mut BTreeMap<Vec<u8>, Vec<u8>>, ops: Vec<BatchOp>) {
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => db.insert(key, value),
+                BatchOp::Delete { key } => db.remove(&key),
+            };
+        }
+    }
+
+    /// The ids of the index keys in db, as in a scan with start_after, up to end.
+    fn ids(
+        db: &BTreeMap<Vec<u8>, Vec<u8>>,
+        items: &Indexed<Item>,
+        field: &str,
+        start_after: &[u8],
+        end: &[u8],
+    ) -> Vec<String> {
+        let prefix = items.field_prefix(field).into_bytes();
+        db.keys()
+            .filter(|key| key.starts_with(&prefix) && key.as_slice() > start_after)
+            .take_while(|key| key.as_slice() < end)
+            .map(|key| {
+                let rest = std::str::from_utf8(&key[prefix.len()..]).unwrap();
+                rest.split_once(':').unwrap().1.to_string()
+            })
+            .collect()
+    }
+
+    /// The start of the index keys of field with value.
+    fn value_key(items: &Indexed<Item>, field: &str, value: serde_json::Value) -> String {
+        format!(
+            "{}{}",
+            items.field_prefix(field),
+            index_value(&value).unwrap()
+        )
+    }
+
+    fn find_by(db: &BTreeMap<Vec<u8>, Vec<u8>>, items: &Indexed<Item>, owner: &str) -> Vec<String> {
+        let prefix = format!("{}:", value_key(items, "owner", json!(owner))).into_bytes();
+        let mut end = prefix.clone();
+        end.push(0xff);
+        ids(db, items, "owner", &prefix, &end)
+    }
+
+    fn item(owner: &str, price: f64) -> Item {
+        Item {
+            owner: owner.to_string(),
+            price,
+        }
+    }
+
+    #[test]
+    fn test_index_consistency_across_updates() {
+        let items = items();
+        let mut db = BTreeMap::new();
+        put(&mut db, &items, "1", item("alice", 5.0));
+        put(&mut db, &items, "2", item("bob", 7.0));
+        // an owner that could be mistaken for a prefix of another, or for a separator
+        put(&mut db, &items, "3", item("alice:x", 1.0));
+        assert_eq!(find_by(&db, &items, "alice"), ["1"]);
+        assert_eq!(find_by(&db, &items, "alice:x"), ["3"]);
+
+        // a new owner moves the record between index entries
+        put(&mut db, &items, "1", item("bob", 5.0));
+        assert!(find_by(&db, &items, "alice").is_empty());
+        assert_eq!(find_by(&db, &items, "bob"), ["1", "2"]);
+        // an unchanged index entry isn't deleted and rewritten
+        let ops = items
+            .put_ops(
+                "1",
+                Some(&serde_json::to_value(item("bob", 5.0)).unwrap()),
+                &serde_json::to_value(item("bob", 6.0)).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            ops.iter()
+                .filter(|op| matches!(op, BatchOp::Delete { .. }))
+                .count(),
+            1
+        );
+
+        let old = serde_json::to_value(item("bob", 5.0)).unwrap();
+        apply(&mut db, items.delete_ops("1", &old));
+        assert_eq!(find_by(&db, &items, "bob"), ["2"]);
+        // only the other records and their entries are left: two each
+        assert_eq!(db.len(), 2 * 3);
+        assert_eq!(index_value(&json!(["alice"])), None);
+    }
+
+    #[test]
+    fn test_numeric_ordering() {
+        let items = items();
+        let mut db = BTreeMap::new();
+        for (id, price) in [
+            ("a", 10.0),
+            ("b", 2.0),
+            ("c", -1.5),
+            ("d", 0.0),
+            ("e", 1e9),
+            ("f", -300.0),
+        ] {
+            put(&mut db, &items, id, item("alice", price));
+        }
+        // entries for a value continue with `:`, which sorts just before `;`
+        let range = |min: serde_json::Value, max: serde_json::Value| {
+            let start_after = value_key(&items, "price", min);
+            let end = format!("{};", value_key(&items, "price", max));
+            ids(&db, &items, "price", start_after.as_bytes(), end.as_bytes())
+        };
+        assert_eq!(
+            range(json!(f64::MIN), json!(f64::MAX)),
+            ["f", "c", "d", "b", "a", "e"]
+        );
+        // integers compare with floats
+        assert_eq!(range(json!(0), json!(10)), ["d", "b", "a"]);
+        assert_eq!(index_value(&json!(-0.0)), index_value(&json!(0)));
+        assert_eq!(index_value(&json!(2)), index_value(&json!(2.0)));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let kv = Kv::<String, String> {
+            package_id: PackageId::new("app", "sys"),
+            db: "db".to_string(),
+            timeout: 5,
+            codec: KvCodec::default(),
+            deadline: None,
+            _marker: PhantomData,
+        }
+        .with_deadline(Deadline::at_ms(0));
+        let error = kv.get(&"key".to_string()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(crate::BuildError::DeadlineExceeded)
+        ));
+        assert!(host.take_calls().is_empty());
+    }
+
+    #[test]
+    fn test_backup_framing() {
+        let mut bytes = vec![];
+        encode_backup_entry(&mut bytes, b"key", b"").unwrap();
+        assert_eq!(bytes, [0, 0, 0, 3, b'k', b'e', b'y', 0, 0, 0, 0]);
+        encode_backup_entry(&mut bytes, b"", b"v").unwrap();
+        let (key, value, len) = decode_backup_entry(&bytes).unwrap();
+        assert_eq!((key, value, len), (b"key".to_vec(), vec![], 11));
+        let (key, value, len) = decode_backup_entry(&bytes[11..]).unwrap();
+        assert_eq!((key, value, len), (vec![], b"v".to_vec(), 9));
+        for cut in 0..11 {
+            assert_eq!(decode_backup_entry(&bytes[..cut]), None, "{cut}");
+        }
+    }
+
+    #[test]
+    fn test_backup_reads_given_keys() {
+        use crate::host::{Call, MockHost, Reply};
+        use crate::vfs::VfsResponse;
+        let host = MockHost::new();
+        let _installed = host.install();
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let kv = Kv::<String, String> {
+            package_id: PackageId::new("app", "sys"),
+            db: "db".to_string(),
+            timeout: 5,
+            codec: KvCodec::default(),
+            deadline: None,
+            _marker: PhantomData,
+        };
+        host.reply(Reply::json(&VfsResponse::Ok));
+        host.reply(Reply::with_blob(
+            serde_json::to_vec(&KvResponse::Get(vec![])).unwrap(),
+            crate::LazyLoadBlob::new(None::<String>, b"\"1\"".to_vec()),
+        ));
+        host.reply(Reply::json(&KvResponse::Err(KvError::KeyNotFound)));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        let written = backup(
+            &kv,
+            ["a".to_string(), "gone".to_string()],
+            "/app:sys/kv.bak",
+        );
+        assert_eq!(written.unwrap(), 1);
+
+        let mut expected = BACKUP_MAGIC.to_vec();
+        encode_backup_entry(&mut expected, b"\"a\"", b"\"1\"").unwrap();
+        let appended = host.take_calls().into_iter().find_map(|call| match call {
+            Call::SendAndAwaitResponse {
+                blob: Some(blob), ..
+            } => Some(blob.bytes),
+            _ => None,
+        });
+        assert_eq!(appended, Some(expected));
+    }
+
+    /// A backup file and a db in memory, failing the apply numbered fail_at.
+    #[derive(Default)]
+    struct MockRestore {
+        file: Vec<u8>,
+        db: BTreeMap<Vec<u8>, Vec<u8>>,
+        progress: Option<RestoreProgress>,
+        applies: usize,
+        fail_at: Option<usize>,
+    }
+
+    impl RestoreIo for MockRestore {
+        fn file_len(&self) -> u64 {
+            self.file.len() as u64
+        }
+
+        fn read(&mut self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+            // like ReadExact, fail rather than come up short
+            let start = offset as usize;
+            match self.file.get(start..start + len) {
+                Some(bytes) => Ok(bytes.to_vec()),
+                None => Err(anyhow::anyhow!("failed to fill whole buffer")),
+            }
+        }
+
+        fn apply(&mut self, ops: &[BatchOp]) -> anyhow::Result<()> {
+            self.applies += 1;
+            if self.fail_at == Some(self.applies) {
+                return Err(anyhow::anyhow!("kv: timeout"));
             }
+            for op in ops {
+                if let BatchOp::Set { key, value } = op {
+                    self.db.insert(key.clone(), value.clone());
+                }
+            }
+            Ok(())
+        }
+
+        fn load_progress(&mut self) -> anyhow::Result<Option<RestoreProgress>> {
+            Ok(self.progress.clone())
+        }
+
+        fn save_progress(&mut self, progress: &RestoreProgress) -> anyhow::Result<()> {
+            self.progress = Some(progress.clone());
+            Ok(())
         }
-        _ => Err(anyhow::anyhow!("kv: unexpected message: {:?}", res)),
+
+        fn clear_progress(&mut self) -> anyhow::Result<()> {
+            self.progress = None;
+            Ok(())
+        }
+    }
+
+    fn backed_up(count: usize) -> (BTreeMap<Vec<u8>, Vec<u8>>, Vec<u8>) {
+        let entries: BTreeMap<Vec<u8>, Vec<u8>> = (0..count)
+            .map(|i| (format!("key{i:05}").into_bytes(), vec![i as u8; i % 300]))
+            .collect();
+        let mut file = vec![];
+        let written = write_backup(entries.clone().into_iter().map(Ok), |chunk| {
+            assert!(chunk.len() < BACKUP_CHUNK_BYTES + 400);
+            file.extend_from_slice(chunk);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(written, count as u64);
+        (entries, file)
+    }
+
+    #[test]
+    fn test_backup_restore_round_trip() {
+        let (entries, file) = backed_up(1234);
+        assert!(file.starts_with(BACKUP_MAGIC));
+        let mut io = MockRestore {
+            file,
+            ..Default::default()
+        };
+        io.db.insert(b"other".to_vec(), b"kept".to_vec());
+        assert_eq!(run_restore(&mut io).unwrap(), 1234);
+        assert_eq!(io.applies, 3);
+        assert_eq!(io.progress, None);
+        assert_eq!(io.db.remove(&b"other"[..]), Some(b"kept".to_vec()));
+        assert_eq!(io.db, entries);
+
+        let (_, file) = backed_up(0);
+        let mut io = MockRestore {
+            file,
+            ..Default::default()
+        };
+        assert_eq!(run_restore(&mut io).unwrap(), 0);
+        assert_eq!(io.applies, 0);
+    }
+
+    #[test]
+    fn test_restore_resumes() {
+        let (entries, file) = backed_up(1234);
+        let mut io = MockRestore {
+            file: file.clone(),
+            fail_at: Some(3),
+            ..Default::default()
+        };
+        assert!(run_restore(&mut io).is_err());
+        let progress = io.progress.clone().unwrap();
+        assert_eq!(progress.restored, 2 * RESTORE_BATCH_OPS as u64);
+        assert_eq!(io.db.len(), 2 * RESTORE_BATCH_OPS);
+
+        // the first two batches are not applied again
+        io.fail_at = None;
+        io.applies = 0;
+        assert_eq!(run_restore(&mut io).unwrap(), 1234);
+        assert_eq!(io.applies, 1);
+        assert_eq!(io.progress, None);
+        assert_eq!(io.db, entries);
+
+        // progress recorded against another file is ignored
+        let mut io = MockRestore {
+            file,
+            progress: Some(RestoreProgress {
+                offset: 100,
+                restored: 7,
+                file_len: 1,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(run_restore(&mut io).unwrap(), 1234);
+        assert_eq!(io.db, entries);
+
+        let mut io = MockRestore {
+            file: b"not a backup".to_vec(),
+            ..Default::default()
+        };
+        assert!(run_restore(&mut io).is_err());
+        let (_, mut file) = backed_up(3);
+        file.pop();
+        let mut io = MockRestore {
+            file,
+            ..Default::default()
+        };
+        assert!(run_restore(&mut io)
+            .unwrap_err()
+            .to_string()
+            .contains("partial entry"));
     }
 }
@@ -22,47 +22,141 @@ wit_bindgen::generate!({
     world: "lib",
 });
 
+/// Config files in the vfs, with defaults and reloading.
+pub mod config;
+/// Signing bodies as a process, and verifying such signatures, for trust across
+/// nodes that doesn't rest on intermediaries.
+pub mod crypto;
+/// Keep requests that failed to deliver, and retry them with backoff.
+pub mod deadletter;
+/// Route incoming messages to handlers by body variant. See [`handle!`].
+pub mod dispatch;
+/// Bincode and MessagePack alternatives to JSON for bodies and state.
+pub mod encoding;
+pub use encoding::{
+    set_typed_state_bincode, set_typed_state_msgpack, try_get_typed_state_bincode,
+    try_get_typed_state_msgpack,
+};
+/// Versioned bodies, for IPC enums that gain variants between package versions.
+pub mod envelope;
+/// One error type for the whole crate, with the [`error::ErrorKind`] of what
+/// went wrong.
+pub mod error;
+pub use error::{Error, ErrorKind};
 /// Interact with the eth provider module.
 pub mod eth;
+/// A single-threaded executor for awaiting many requests at once. See
+/// [`Request::send_async()`].
+#[cfg(any(test, feature = "async"))]
+pub mod executor;
+/// Checking the capabilities and source of incoming requests. See [`require_capability!`].
+pub mod guard;
+/// Answering and sending health checks between processes.
+pub mod health;
 /// Interact with the system homepage.
 ///
 /// Your process must have the [`Capability`] to message
 /// `homepage:homepage:sys` to use this module.
 pub mod homepage;
+/// Interceptors run on every request sent and message received.
+pub mod hooks;
+/// The host functions the crate calls, replaceable, e.g. for tests off-node.
+pub mod host;
+pub use host::{
+    clear_state, current_source, drop_capabilities, get_blob, get_blob_len, get_blob_mime,
+    get_blob_range, get_state, has_blob, our_capabilities, print_to_terminal, receive,
+    save_capabilities, send_and_await_response, send_request, send_response, set_state,
+};
 /// Interact with the HTTP server and client modules.
 /// Contains types from the `http` crate to use as well.
 ///
 /// Your process must have the [`Capability`] to message and receive messages from
 /// `http-server:distro:sys` and/or `http-client:distro:sys` to use this module.
 pub mod http;
+/// Interact with hypermap, the onchain namespace
+pub mod hypermap;
+/// Handle retried requests at most once.
+pub mod inbox;
+/// Record the messages a process receives, and replay them to reproduce bugs.
+pub mod journal;
 /// The types that the kernel itself uses -- warning -- these will
 /// be incompatible with WIT types in some cases, leading to annoying errors.
 /// Use only to interact with the kernel or runtime in certain ways.
 pub mod kernel_types;
-/// Interact with hypermap, the onchain namespace
-pub mod hypermap;
 /// Interact with the key_value module
 ///
 /// Your process must have the [`Capability`] to message and receive messages from
 /// `kv:distro:sys` to use this module.
 pub mod kv;
+/// Limits on the size of the messages sent, to fail clearly instead of being killed.
+pub mod limits;
+/// Structured logging as JSON lines, to the terminal and/or a vfs file.
+pub mod logger;
 #[cfg(feature = "logging")]
 pub mod logging;
+/// The loop at the heart of most processes. See [`run_process!`].
+pub mod main_loop;
+/// Counters, gauges and histograms, rendered in the Prometheus text format.
+pub mod metrics;
 /// Interact with the networking module
 /// For configuration, debugging, and creating signatures with networking key.
 ///
 /// Your process must have the [`Capability`] to message and receive messages from
 /// `net:distro:sys` to use this module.
 pub mod net;
+/// Requests delivered at least once, even across process restarts.
+pub mod outbox;
+/// Read the manifest and metadata of installed packages.
+pub mod package;
+/// Report panics to the terminal, another process, and/or persistent storage.
+pub mod panic_hook;
+pub use panic_hook::set_panic_hook;
+/// Spawn worker processes and distribute tasks to them.
+pub mod pool;
+/// Topic subscriptions by remote processes, and publishing updates to them.
+pub mod pubsub;
+/// Messages received but not yet handled, and turning requests away when too
+/// many are.
+pub mod queue;
+/// Processes of a package finding each other by role, through a coordinator.
+pub mod registry;
+/// Typed request-response calls between processes.
+pub mod rpc;
+/// Publishing the JSON Schemas of the bodies a process speaks, and fetching others'.
+#[cfg(any(test, feature = "schema"))]
+pub mod schema;
+/// Reading and writing user-level settings kept by the settings process, and
+/// following changes to them.
+pub mod settings;
+/// Shutting down cleanly when asked to, before being killed.
+pub mod shutdown;
 /// Interact with the sqlite module
 ///
 /// Your process must have the [`Capability] to message and receive messages from
 /// `sqlite:distro:sys` to use this module.
 pub mod sqlite;
+/// Restarting child processes when they exit, with backoff.
+pub mod supervise;
+/// Mirror a vfs directory to another node, sending what changed with [`transfer`].
+pub mod sync;
+/// Leveled printing to the terminal. See [`error!`], [`warn!`], [`info!`] and [`debug!`].
+pub mod terminal;
+pub use terminal::{log_error_chain, set_log_level};
+/// Build messages and stand in for the kernel in tests.
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 /// Interact with the timer runtime module.
 ///
 /// The `timer:distro:sys` module is public, so no special capabilities needed.
 pub mod timer;
+/// Opt-in trace context for Requests, carried in their metadata, and timing records.
+pub mod trace;
+/// Send files too large for one blob to other processes in chunks.
+pub mod transfer;
+/// Helpers for pacing and organizing outgoing work.
+pub mod util;
+/// A version handshake between processes of different package versions.
+pub mod version;
 /// Interact with the virtual filesystem
 ///
 /// Your process must have the [`Capability`] to message and receive messages from
@@ -74,16 +168,22 @@ pub mod scripting;
 
 mod types;
 pub use types::{
-    address::{Address, AddressParseError},
+    address::{is_valid_node_name, Address, AddressParseError},
+    alias::AliasTable,
     capability::Capability,
     lazy_load_blob::LazyLoadBlob,
-    message::{Message, _wit_message_to_message},
+    message::{_wit_message_to_message, BuildError, Data, Message},
     on_exit::OnExit,
     package_id::PackageId,
-    process_id::{ProcessId, ProcessIdParseError},
+    process_id::{IdSegment, ProcessId, ProcessIdParseError},
+    remote_error::{AwaitError, OnRemoteError, RemoteError, ERROR_KEY},
     request::Request,
     response::Response,
-    send_error::{SendError, SendErrorKind, _wit_send_error_to_send_error},
+    send_error::{_wit_send_error_to_send_error, SendError, SendErrorKind},
+    system_process::{
+        set_system_naming, system_naming, SystemNaming, SystemProcess, SYSTEM_PACKAGE,
+        SYSTEM_PUBLISHER,
+    },
 };
 
 /// Implement the wit-bindgen specific code that the kernel uses to hook into
@@ -95,6 +195,7 @@ macro_rules! call_init {
         impl Guest for Component {
             fn init(our: String) {
                 let our: Address = our.parse().unwrap();
+                $crate::set_our(our.clone());
                 $init_func(our);
             }
         }
@@ -102,6 +203,72 @@ macro_rules! call_init {
     };
 }
 
+/// Implement the wit-bindgen specific code that the kernel uses to hook into a
+/// process, and run [`main_loop::main_loop()`]: load the saved state, or make a
+/// fresh one with `init`, then call `handler` with every incoming message and
+/// save the state after each one it handles successfully. Handler errors are
+/// printed rather than crashing the process. Pass a [`main_loop::PersistState`]
+/// as a third argument to save the state less often.
+/// Requests to shut down are taken care of as described in [`shutdown::handle()`].
+///
+/// Example:
+/// ```ignore
+/// use hyperware_process_lib::{run_process, Address, Message, SendError};
+/// use serde::{Deserialize, Serialize};
+///
+/// wit_bindgen::generate!({
+///     path: "target/wit",
+///     world: "process-v1",
+/// });
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct State {
+///     pings: u64,
+/// }
+///
+/// fn init(_our: &Address) -> State {
+///     State { pings: 0 }
+/// }
+///
+/// fn handle(
+///     our: &Address,
+///     message: Result<Message, SendError>,
+///     state: &mut State,
+/// ) -> anyhow::Result<()> {
+///     let message = message?;
+///     if message.is_request() && message.source().node == our.node {
+///         state.pings += 1;
+///         hyperware_process_lib::Response::new()
+///             .body(serde_json::to_vec(&state.pings)?)
+///             .send()?;
+///     }
+///     Ok(())
+/// }
+///
+/// run_process!(init, handle);
+/// ```
+#[macro_export]
+macro_rules! run_process {
+    ($init:path, $handler:path) => {
+        $crate::run_process!(
+            $init,
+            $handler,
+            $crate::main_loop::PersistState::EveryMessage
+        );
+    };
+    ($init:path, $handler:path, $persist:expr) => {
+        struct Component;
+        impl Guest for Component {
+            fn init(our: String) {
+                let our: $crate::Address = our.parse().unwrap();
+                $crate::set_our(our.clone());
+                $crate::main_loop::main_loop(our, $init, $handler, $persist);
+            }
+        }
+        export!(Component);
+    };
+}
+
 /// Override the `println!` macro to print to the terminal.
 /// Uses the `print_to_terminal` function from the WIT interface on maximally-verbose
 /// mode, i.e., this print will always show up in the terminal. To control
@@ -109,10 +276,10 @@ macro_rules! call_init {
 #[macro_export]
 macro_rules! println {
     () => {
-        $crate::print_to_terminal(0, "\n");
+        $crate::print_to_terminal($crate::terminal::Verbosity::Always.as_u8(), "\n");
     };
     ($($arg:tt)*) => {{
-        $crate::print_to_terminal(0, &format!($($arg)*));
+        $crate::print_to_terminal($crate::terminal::Verbosity::Always.as_u8(), &format!($($arg)*));
     }};
 }
 
@@ -122,10 +289,10 @@ macro_rules! println {
 #[macro_export]
 macro_rules! kiprintln {
     () => {
-        $crate::print_to_terminal(0, "\n");
+        $crate::print_to_terminal($crate::terminal::Verbosity::Always.as_u8(), "\n");
     };
     ($($arg:tt)*) => {{
-        $crate::print_to_terminal(0, &format!($($arg)*));
+        $crate::print_to_terminal($crate::terminal::Verbosity::Always.as_u8(), &format!($($arg)*));
     }};
 }
 
@@ -139,11 +306,56 @@ macro_rules! kiprintln {
 macro_rules! process_println {
     () => {
         let our = $crate::our();
-        $crate::print_to_terminal(0, format!("{}: ", our.process()).as_str());
+        $crate::print_to_terminal($crate::terminal::Verbosity::Always.as_u8(), format!("{}: ", our.process()).as_str());
     };
     ($($arg:tt)*) => {{
         let our = $crate::our();
-        $crate::print_to_terminal(0, format!("{}: {}", our.process(), format!($($arg)*)).as_str());
+        $crate::print_to_terminal($crate::terminal::Verbosity::Always.as_u8(), format!("{}: {}", our.process(), format!($($arg)*)).as_str());
+    }};
+}
+
+/// Print to the terminal at [`terminal::Level::Error`], which always shows up.
+/// Lines are prefixed with their level; see [`terminal::set_log_level()`] to
+/// filter them before they reach the terminal.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        $crate::__log!($crate::terminal::Level::Error, $($arg)*);
+    }};
+}
+
+/// Print to the terminal at [`terminal::Level::Warn`]. See [`error!`].
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        $crate::__log!($crate::terminal::Level::Warn, $($arg)*);
+    }};
+}
+
+/// Print to the terminal at [`terminal::Level::Info`]. See [`error!`].
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        $crate::__log!($crate::terminal::Level::Info, $($arg)*);
+    }};
+}
+
+/// Print to the terminal at [`terminal::Level::Debug`]. See [`error!`].
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        $crate::__log!($crate::terminal::Level::Debug, $($arg)*);
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:expr, $($arg:tt)*) => {{
+        let level = $level;
+        if $crate::terminal::log_enabled(level) {
+            $crate::terminal::log(level, &format!($($arg)*));
+        }
     }};
 }
 
@@ -170,8 +382,23 @@ macro_rules! process_println {
 /// }
 /// ```
 pub fn await_message() -> Result<Message, SendError> {
+    if let Some((queued, blob)) = queue::pop() {
+        host::restore_blob(blob);
+        return queued;
+    }
+    receive_message()
+}
+
+/// The next message from the runtime, bypassing any in the [`queue`].
+#[allow(clippy::result_large_err)]
+pub(crate) fn receive_message() -> Result<Message, SendError> {
     match crate::receive() {
-        Ok((source, message)) => Ok(_wit_message_to_message(source, message)),
+        Ok((source, message)) => {
+            let mut message = _wit_message_to_message(source, message);
+            trace::on_receive(&mut message);
+            hooks::after_receive(&message);
+            Ok(message)
+        }
         Err((send_err, context)) => Err(_wit_send_error_to_send_error(send_err, context)),
     }
 }
@@ -259,6 +486,7 @@ where
 ///     field_two: HashSet::new(),
 /// });
 /// ```
+#[deprecated(note = "returns None on a deserialization error too; use try_get_typed_blob")]
 pub fn get_typed_blob<T, F, E>(deserializer: F) -> Option<T>
 where
     F: Fn(&[u8]) -> Result<T, E>,
@@ -295,6 +523,7 @@ where
 ///     field_two: HashSet::new(),
 /// });
 /// ```
+#[deprecated(note = "returns None on a deserialization error too; use try_get_typed_state")]
 pub fn get_typed_state<T, F, E>(deserializer: F) -> Option<T>
 where
     F: Fn(&[u8]) -> Result<T, E>,
@@ -309,6 +538,134 @@ where
     }
 }
 
+/// Whether the message this process is handling has a blob, which a [`Request`]
+/// or [`Response`] with `inherit` set would forward.
+pub fn has_inherited_blob() -> bool {
+    crate::get_blob().is_some()
+}
+
+/// Fetch the blob of the most recent message we've received and deserialize it
+/// with the provided function. Returns `Ok(None)` if that message had no blob,
+/// and an error, with the size and start of the blob, if it does not deserialize.
+///
+/// Example:
+/// ```no_run
+/// use hyperware_process_lib::try_get_typed_blob;
+/// use serde::{Serialize, Deserialize};
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Upload {
+///     name: String,
+///     bytes: Vec<u8>,
+/// }
+///
+/// let upload: Option<Upload> = try_get_typed_blob(|bytes| serde_json::from_slice(bytes))?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn try_get_typed_blob<T, F, E>(deserializer: F) -> anyhow::Result<Option<T>>
+where
+    F: Fn(&[u8]) -> Result<T, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let bytes = crate::get_blob().map(|blob| blob.bytes);
+    deserialize_typed("blob", bytes, deserializer)
+}
+
+/// Fetch the persisted state of this process, saved with [`set_state()`], and
+/// deserialize it with the provided function. Returns `Ok(None)` if this process
+/// has no saved state, and an error, with the size and start of the state, if it
+/// does not deserialize.
+pub fn try_get_typed_state<T, F, E>(deserializer: F) -> anyhow::Result<Option<T>>
+where
+    F: Fn(&[u8]) -> Result<T, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    deserialize_typed("state", crate::get_state(), deserializer)
+}
+
+/// How many bytes of a blob or state that fails to deserialize are shown in the error.
+const PREVIEW_BYTES: usize = 32;
+
+fn deserialize_typed<T, F, E>(
+    what: &str,
+    bytes: Option<Vec<u8>>,
+    deserializer: F,
+) -> anyhow::Result<Option<T>>
+where
+    F: Fn(&[u8]) -> Result<T, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    bytes
+        .map(|bytes| deserialize_bytes(what, &bytes, deserializer))
+        .transpose()
+}
+
+pub(crate) fn deserialize_bytes<T, F, E>(
+    what: &str,
+    bytes: &[u8],
+    deserializer: F,
+) -> anyhow::Result<T>
+where
+    F: Fn(&[u8]) -> Result<T, E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    deserializer(bytes).map_err(|e| {
+        let preview = String::from_utf8_lossy(&bytes[..bytes.len().min(PREVIEW_BYTES)]);
+        anyhow::Error::new(e).context(format!(
+            "failed to deserialize {}-byte {what} starting with {preview:?}",
+            bytes.len()
+        ))
+    })
+}
+
+static OUR: OurCell = OurCell(std::sync::OnceLock::new());
+
+/// Remember the [`Address`] of this process, as handed to `init`, for [`our()`].
+/// [`call_init!`] and [`run_process!`] call this before anything else; processes
+/// that implement `Guest` themselves must call it first thing in `init`.
+/// Only the first call has an effect. Also starts the uptime reported by
+/// [`health::responder()`].
+pub fn set_our(our: Address) {
+    health::mark_started();
+    OUR.set(our);
+}
+
+/// The [`Address`] of this process.
+///
+/// Panics if called before [`set_our()`].
+pub fn our() -> &'static Address {
+    OUR.get()
+}
+
+/// [`our()`], if [`set_our()`] has been called.
+pub(crate) fn try_our() -> Option<&'static Address> {
+    OUR.0.get()
+}
+
+/// The node this process is running on, i.e. `our().node`.
+pub fn our_node() -> &'static str {
+    &our().node
+}
+
+/// The [`ProcessId`] of this process, i.e. `our().process`.
+pub fn our_process() -> &'static ProcessId {
+    &our().process
+}
+
+struct OurCell(std::sync::OnceLock<Address>);
+
+impl OurCell {
+    fn set(&self, our: Address) {
+        let _ = self.0.set(our);
+    }
+
+    fn get(&self) -> &Address {
+        self.0.get().expect(
+            "our() called before set_our(): use call_init!/run_process!, or call set_our() at the start of init",
+        )
+    }
+}
+
 /// See if we have the [`Capability`] to message a certain process.
 /// Note if you have not saved the [`Capability`], you will not be able to message the other process.
 pub fn can_message(address: &Address) -> bool {
@@ -437,3 +794,45 @@ macro_rules! validate_spawn_args {
         ));
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_our_cell_first_set_wins() {
+        let cell = OurCell(std::sync::OnceLock::new());
+        cell.set("first.os@app:pkg:pub.os".parse().unwrap());
+        cell.set("second.os@app:pkg:pub.os".parse().unwrap());
+        assert_eq!(cell.get().node, "first.os");
+        assert_eq!(cell.get().process(), "app");
+    }
+
+    #[test]
+    fn test_deserialize_typed() {
+        let json = |bytes: &[u8]| serde_json::from_slice::<Vec<u32>>(bytes);
+        for what in ["blob", "state"] {
+            assert!(deserialize_typed(what, None, json).unwrap().is_none());
+            assert_eq!(
+                deserialize_typed(what, Some(b"[1,2]".to_vec()), json).unwrap(),
+                Some(vec![1, 2])
+            );
+
+            let corrupt = format!("[1,2,{}", "9".repeat(100)).into_bytes();
+            let error = deserialize_typed(what, Some(corrupt), json).unwrap_err();
+            let message = format!("{error:#}");
+            assert!(message.starts_with(&format!(
+                "failed to deserialize 105-byte {what} starting with \"[1,2,{}\": ",
+                "9".repeat(27)
+            )));
+            // the serde error is kept as the cause
+            assert!(error.downcast_ref::<serde_json::Error>().is_some());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "our() called before set_our()")]
+    fn test_our_cell_get_before_set_panics() {
+        OurCell(std::sync::OnceLock::new()).get();
+    }
+}
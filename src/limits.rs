@@ -0,0 +1,144 @@
+use crate::{BuildError, LazyLoadBlob};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+
+/// The largest IPC body sent by default, see [`set_max_body()`].
+pub const DEFAULT_MAX_BODY: u64 = 10 * 1024 * 1024;
+/// The largest blob sent by default, see [`set_max_blob()`].
+pub const DEFAULT_MAX_BLOB: u64 = 100 * 1024 * 1024;
+
+thread_local! {
+    static MAX_BODY: Cell<u64> = const { Cell::new(DEFAULT_MAX_BODY) };
+    static MAX_BLOB: Cell<u64> = const { Cell::new(DEFAULT_MAX_BLOB) };
+}
+
+/// Which part of a message was over its limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayloadKind {
+    Body,
+    Blob,
+}
+
+impl std::fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PayloadKind::Body => "body",
+            PayloadKind::Blob => "blob",
+        })
+    }
+}
+
+/// Refuse to send IPC bodies over bytes long from now on: [`crate::Request::send()`]
+/// and friends fail with [`BuildError::TooLarge`] instead of handing them to the
+/// runtime, which may kill a process that sends too much without saying why.
+/// `unchecked_send()` skips the check where a large body is intended.
+pub fn set_max_body(bytes: u64) {
+    MAX_BODY.set(bytes);
+}
+
+/// Refuse to send blobs over bytes long from now on, as [`set_max_body()`] does
+/// for bodies.
+pub fn set_max_blob(bytes: u64) {
+    MAX_BLOB.set(bytes);
+}
+
+pub fn max_body() -> u64 {
+    MAX_BODY.get()
+}
+
+pub fn max_blob() -> u64 {
+    MAX_BLOB.get()
+}
+
+/// Check the sizes of a message about to be sent against the limits.
+pub(crate) fn check(body: &[u8], blob: Option<&LazyLoadBlob>) -> Result<(), BuildError> {
+    let sizes = [
+        (PayloadKind::Body, Some(body.len()), max_body()),
+        (PayloadKind::Blob, blob.map(|b| b.bytes.len()), max_blob()),
+    ];
+    for (kind, size, limit) in sizes {
+        if let Some(size) = size.map(|size| size as u64).filter(|size| *size > limit) {
+            return Err(BuildError::TooLarge { kind, size, limit });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+    use crate::{Address, Request, Response};
+
+    fn sent(host: &MockHost) -> usize {
+        host.take_calls()
+            .into_iter()
+            .filter(|call| matches!(call, Call::SendRequest { .. } | Call::SendResponse { .. }))
+            .count()
+    }
+
+    #[test]
+    fn test_guard() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        set_max_body(8);
+        set_max_blob(16);
+        let target: Address = "tester.os@kv:distro:sys".parse().unwrap();
+
+        let error = Request::to(&target).body(vec![0; 9]).send().unwrap_err();
+        assert!(matches!(
+            error,
+            BuildError::TooLarge {
+                kind: PayloadKind::Body,
+                size: 9,
+                limit: 8
+            }
+        ));
+        assert_eq!(error.to_string(), "body of 9 bytes is over the limit of 8");
+        let error = Request::to(&target)
+            .body(vec![0; 8])
+            .blob_bytes(vec![0; 17])
+            .send_and_await_response(5)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            BuildError::TooLarge {
+                kind: PayloadKind::Blob,
+                ..
+            }
+        ));
+        let error = Response::new().body(vec![0; 9]).send().unwrap_err();
+        assert!(matches!(error, BuildError::TooLarge { .. }));
+        // nothing reached the host
+        assert!(host.take_calls().is_empty());
+
+        // at the limits is fine
+        Request::to(&target)
+            .body(vec![0; 8])
+            .blob_bytes(vec![0; 16])
+            .send()
+            .unwrap();
+        assert_eq!(sent(&host), 1);
+        set_max_body(DEFAULT_MAX_BODY);
+        set_max_blob(DEFAULT_MAX_BLOB);
+    }
+
+    #[test]
+    fn test_unchecked_send() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        set_max_body(8);
+        Request::to(("tester.os", "kv", "distro", "sys"))
+            .body(vec![0; 9])
+            .unchecked_send()
+            .unwrap();
+        Response::new().body(vec![0; 9]).unchecked_send().unwrap();
+        assert_eq!(sent(&host), 2);
+        // the other checks still apply
+        assert!(matches!(
+            Request::new().body(vec![0; 9]).unchecked_send(),
+            Err(BuildError::NoTarget)
+        ));
+        set_max_body(DEFAULT_MAX_BODY);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::terminal::{log_enabled, Level};
+use crate::vfs::{self, VfsClientError, VfsError};
+use crate::Message;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Default number of entries buffered before they are written to the log file.
+pub const DEFAULT_FLUSH_EVERY: usize = 100;
+/// Size past which the log file is rotated.
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 1024 * 1024;
+/// Number of rotated log files kept.
+pub const DEFAULT_MAX_FILES: u32 = 5;
+
+/// How stale a cached timestamp may be, see [`crate::timer::now_ms_cached()`].
+const TIMESTAMP_STALENESS_MS: u64 = 10;
+
+/// Where a [`Logger`] writes its entries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Print each entry with `print_to_terminal`, at the verbosity of its [`Level`].
+    Terminal,
+    /// Append entries to the vfs file at this path, rotating it as a [`vfs::Logger`] does.
+    File(String),
+    /// Both of the above.
+    Both(String),
+}
+
+/// Structured logger emitting one JSON object per entry:
+/// `{"ts":1700000000000,"level":"info","msg":"...","fields":{...}}`.
+///
+/// Entries are filtered by the same level as the leveled macros ([`crate::info!`] and
+/// friends), so [`crate::set_log_level()`] controls every sink. File writes are
+/// buffered and flushed every [`Logger::flush_every()`] entries, on [`Logger::flush()`],
+/// and when the `Logger` is dropped.
+///
+/// ```no_run
+/// use hyperware_process_lib::logger::{LogTarget, Logger};
+/// use hyperware_process_lib::terminal::Level;
+/// use serde_json::json;
+///
+/// let mut logger = Logger::new(LogTarget::Both("/my-app:publisher.os/logs/app.jsonl".into())).unwrap();
+/// logger.log(Level::Info, "started");
+/// logger
+///     .with_fields(&[("user", json!("alice")), ("attempt", json!(2))])
+///     .log(Level::Warn, "login failed");
+/// ```
+pub struct Logger {
+    terminal: bool,
+    file: Option<vfs::Logger>,
+    buffer: EntryBuffer,
+}
+
+impl Logger {
+    pub fn new(target: LogTarget) -> Result<Self, VfsClientError> {
+        let (terminal, path) = match target {
+            LogTarget::Terminal => (true, None),
+            LogTarget::File(path) => (false, Some(path)),
+            LogTarget::Both(path) => (true, Some(path)),
+        };
+        let file = match path {
+            Some(path) => {
+                let (dir, base_name) = path.rsplit_once('/').ok_or_else(|| {
+                    VfsClientError::Runtime(VfsError::ParseError {
+                        error: "log file path has no directory".to_string(),
+                        path: path.clone(),
+                    })
+                })?;
+                // entries are buffered here, so the file writer flushes only when told to
+                Some(
+                    vfs::Logger::new(dir, base_name, DEFAULT_MAX_FILE_BYTES, DEFAULT_MAX_FILES)?
+                        .flush_lines(usize::MAX)
+                        .flush_interval_ms(u64::MAX),
+                )
+            }
+            None => None,
+        };
+        Ok(Logger {
+            terminal,
+            file,
+            buffer: EntryBuffer::new(DEFAULT_FLUSH_EVERY),
+        })
+    }
+
+    /// Set how many entries are buffered before they are written to the log file.
+    pub fn flush_every(mut self, entries: usize) -> Self {
+        self.buffer.flush_every = entries.max(1);
+        self
+    }
+
+    /// Log an entry without fields.
+    pub fn log(&mut self, level: Level, msg: &str) {
+        self.log_fields(level, msg, Map::new());
+    }
+
+    /// Start an entry with fields, finishing it with [`Entry::log()`].
+    pub fn with_fields(&mut self, fields: &[(&str, Value)]) -> Entry<'_> {
+        Entry {
+            logger: self,
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+        }
+    }
+
+    /// Write out all buffered entries.
+    pub fn flush(&mut self) -> Result<(), VfsClientError> {
+        let lines = self.buffer.take();
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        for line in lines {
+            file.log(&line)?;
+        }
+        file.flush()
+    }
+
+    fn log_fields(&mut self, level: Level, msg: &str, fields: Map<String, Value>) {
+        if !log_enabled(level) {
+            return;
+        }
+        let ts = crate::timer::now_ms_cached(TIMESTAMP_STALENESS_MS).unwrap_or_default();
+        let line = entry_line(ts, level, msg, &fields);
+        if self.terminal {
+            crate::print_to_terminal(level.verbosity(), &line);
+        }
+        if self.file.is_some() && self.buffer.push(line) {
+            let _ = self.flush();
+        }
+    }
+}
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// An entry with fields, started by [`Logger::with_fields()`].
+pub struct Entry<'a> {
+    logger: &'a mut Logger,
+    fields: Map<String, Value>,
+}
+
+impl Entry<'_> {
+    /// Record the sizes of message as the fields `body_len` and, if it has a blob,
+    /// `blob_len`, e.g. to find what pushed a process over the [`crate::limits`].
+    pub fn sizes(mut self, message: &Message) -> Self {
+        self.fields
+            .insert("body_len".to_string(), message.body_len().into());
+        if let Some(blob_len) = message.blob_len() {
+            self.fields.insert("blob_len".to_string(), blob_len.into());
+        }
+        self
+    }
+
+    pub fn log(self, level: Level, msg: &str) {
+        self.logger.log_fields(level, msg, self.fields);
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    ts: u64,
+    level: Level,
+    msg: &'a str,
+    fields: &'a Map<String, Value>,
+}
+
+fn entry_line(ts: u64, level: Level, msg: &str, fields: &Map<String, Value>) -> String {
+    serde_json::to_string(&Record {
+        ts,
+        level,
+        msg,
+        fields,
+    })
+    .unwrap()
+}
+
+/// Lines waiting to be written to the log file.
+struct EntryBuffer {
+    lines: Vec<String>,
+    flush_every: usize,
+}
+
+impl EntryBuffer {
+    fn new(flush_every: usize) -> Self {
+        EntryBuffer {
+            lines: vec![],
+            flush_every,
+        }
+    }
+
+    /// Buffer a line. Returns whether the buffer is now full and should be flushed.
+    fn push(&mut self, line: String) -> bool {
+        self.lines.push(line);
+        self.lines.len() >= self.flush_every
+    }
+
+    fn take(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_entry_line_shape() {
+        let fields: Map<String, Value> = [
+            ("user".to_string(), json!("alice")),
+            ("attempt".to_string(), json!(2)),
+        ]
+        .into_iter()
+        .collect();
+        let line = entry_line(1_700_000_000_000, Level::Warn, "login \"failed\"", &fields);
+        assert!(!line.contains('\n'));
+        assert!(line.starts_with(r#"{"ts":1700000000000,"level":"warn","msg":"#));
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({
+                "ts": 1_700_000_000_000u64,
+                "level": "warn",
+                "msg": "login \"failed\"",
+                "fields": {"user": "alice", "attempt": 2},
+            })
+        );
+        assert_eq!(
+            entry_line(0, Level::Debug, "", &Map::new()),
+            r#"{"ts":0,"level":"debug","msg":"","fields":{}}"#
+        );
+    }
+
+    #[test]
+    fn test_sizes() {
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        let message = Message::Request {
+            source: "tester.os@tester:app:sys".parse().unwrap(),
+            expects_response: None,
+            body: vec![0; 42],
+            metadata: None,
+            capabilities: vec![],
+        };
+        let mut logger = Logger::new(LogTarget::Terminal).unwrap();
+        logger
+            .with_fields(&[("user", json!("alice"))])
+            .sizes(&message)
+            .log(Level::Error, "big request");
+        let Some(crate::host::Call::Print { message, .. }) = host.take_calls().pop() else {
+            panic!("nothing printed");
+        };
+        let line: Value = serde_json::from_str(&message).unwrap();
+        // there is no blob to measure
+        assert_eq!(line["fields"], json!({"user": "alice", "body_len": 42}));
+    }
+
+    #[test]
+    fn test_entry_buffer_flush_threshold() {
+        let mut buffer = EntryBuffer::new(3);
+        assert!(!buffer.push("a".to_string()));
+        assert!(!buffer.push("b".to_string()));
+        assert!(buffer.push("c".to_string()));
+        assert_eq!(buffer.take(), vec!["a", "b", "c"]);
+
+        // the count starts over after a flush
+        assert!(buffer.take().is_empty());
+        assert!(!buffer.push("d".to_string()));
+        assert_eq!(buffer.take(), vec!["d"]);
+
+        let mut unbuffered = EntryBuffer::new(1);
+        assert!(unbuffered.push("e".to_string()));
+    }
+}
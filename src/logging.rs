@@ -44,7 +44,10 @@ impl std::io::Write for RemoteWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let body = serde_json::json!({"Log": buf});
         let body = serde_json::to_vec(&body).unwrap();
-        Request::to(&self.target).body(body).send().unwrap();
+        Request::to(&self.target)
+            .body(body)
+            .send()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         Ok(buf.len())
     }
 
@@ -153,8 +156,8 @@ pub fn init_logging(
     max_log_file_size: Option<u64>,
 ) -> anyhow::Result<()> {
     let our = crate::our();
-    let log_dir_path = create_drive(our.package_id(), "log", None)?;
-    let log_file_path = format!("{log_dir_path}/{}.log", our.process());
+    let log_dir = create_drive(our.package_id(), "log", None)?;
+    let log_file_path = log_dir.file(&format!("{}.log", our.process()))?;
     let log_file = open_file(&log_file_path, true, None)?;
 
     let file_filter = EnvFilter::new(file_level.as_str());
@@ -0,0 +1,181 @@
+use crate::timer::wall_clock_ms;
+use crate::{await_message, set_state, try_get_typed_state, Address, Message, SendError};
+use serde::{de::DeserializeOwned, Serialize};
+use std::cell::Cell;
+
+/// When [`main_loop()`] saves the process state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PersistState {
+    /// After every message the handler returns `Ok` for.
+    #[default]
+    EveryMessage,
+    /// After a message the handler returns `Ok` for, if it called [`request_save()`].
+    OnDemand,
+    /// Never: the process manages its state itself, if at all.
+    Never,
+}
+
+thread_local! {
+    static SAVE_REQUESTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Ask [`main_loop()`] to save the state once the current handler call succeeds.
+/// Only has an effect with [`PersistState::OnDemand`].
+pub fn request_save() {
+    SAVE_REQUESTED.set(true);
+}
+
+/// The body of a process: load the state saved by a previous run (as JSON), or
+/// make a fresh one with init if there is none or it does not deserialize, then hand every incoming message, including the
+/// [`SendError`]s of messages this process sent, to handler.
+///
+/// Shutdown requests, and requests arriving once a shutdown has begun, are
+/// taken care of by [`crate::shutdown::handle()`] instead of handler, saving the
+/// state first unless persist is [`PersistState::Never`].
+///
+/// Errors returned by handler are printed with [`crate::log_error_chain()`] and
+/// the loop carries on. The state is saved as configured by persist, and never
+/// after a failed handler call, so a message that fails halfway does not persist
+/// half of its changes (though they remain in memory).
+///
+/// With [`crate::metrics::record_messages()`] on, every message handed to
+/// handler is counted and timed. Requests received while the handler awaits
+/// responses with [`crate::executor::run()`] are queued, or shed as set with
+/// [`crate::queue::set_backpressure()`].
+///
+/// Usually called through [`crate::run_process!`].
+pub fn main_loop<S>(
+    our: Address,
+    init: fn(&Address) -> S,
+    handler: fn(&Address, Result<Message, SendError>, &mut S) -> anyhow::Result<()>,
+    persist: PersistState,
+) -> !
+where
+    S: Serialize + DeserializeOwned,
+{
+    let saved = try_get_typed_state(|bytes| serde_json::from_slice(bytes)).unwrap_or_else(|e| {
+        crate::log_error_chain(&e.context("discarding saved state"));
+        None
+    });
+    let mut state = saved.unwrap_or_else(|| init(&our));
+    loop {
+        let message = await_message();
+        if let Ok(message) = &message {
+            let save = || {
+                if persist != PersistState::Never {
+                    save_state(&state);
+                }
+            };
+            match crate::shutdown::handle_with(message, save, wall_clock_ms) {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(e) => {
+                    crate::log_error_chain(&e.context("failed to answer about shutdown"));
+                    continue;
+                }
+            }
+        }
+        SAVE_REQUESTED.set(false);
+        let timer = crate::metrics::start_message(&message);
+        let result = handler(&our, message, &mut state);
+        if let Some(timer) = timer {
+            timer.finish();
+        }
+        if should_save(persist, result.is_ok(), SAVE_REQUESTED.get()) {
+            save_state(&state);
+        }
+        if let Err(e) = result {
+            crate::log_error_chain(&e);
+        }
+    }
+}
+
+fn save_state<S: Serialize>(state: &S) {
+    match serde_json::to_vec(state) {
+        Ok(bytes) => set_state(&bytes),
+        Err(e) => crate::error!("failed to serialize state: {e}"),
+    }
+}
+
+fn should_save(persist: PersistState, handled: bool, save_requested: bool) -> bool {
+    handled
+        && match persist {
+            PersistState::EveryMessage => true,
+            PersistState::OnDemand => save_requested,
+            PersistState::Never => false,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_save() {
+        assert!(should_save(PersistState::EveryMessage, true, false));
+        assert!(!should_save(PersistState::EveryMessage, false, true));
+        assert!(should_save(PersistState::OnDemand, true, true));
+        assert!(!should_save(PersistState::OnDemand, true, false));
+        assert!(!should_save(PersistState::OnDemand, false, true));
+        assert!(!should_save(PersistState::Never, true, true));
+    }
+
+    #[test]
+    fn test_request_save() {
+        SAVE_REQUESTED.set(false);
+        request_save();
+        assert!(SAVE_REQUESTED.get());
+    }
+
+    mod expansion {
+        use crate::{Address, Message, SendError};
+        use serde::{Deserialize, Serialize};
+
+        // stand-ins for what `wit_bindgen::generate!` puts in scope in a process
+        trait Guest {
+            fn init(our: String);
+        }
+        macro_rules! export {
+            ($component:ident) => {
+                #[allow(dead_code)]
+                const EXPORTED: fn(String) = <$component as Guest>::init;
+            };
+        }
+
+        #[derive(Serialize, Deserialize)]
+        struct State {
+            count: u64,
+        }
+
+        fn init(_our: &Address) -> State {
+            State { count: 0 }
+        }
+
+        fn handle(
+            _our: &Address,
+            message: Result<Message, SendError>,
+            state: &mut State,
+        ) -> anyhow::Result<()> {
+            message?;
+            state.count += 1;
+            Ok(())
+        }
+
+        mod on_demand {
+            use super::{handle, init, Guest};
+            crate::run_process!(init, handle, crate::main_loop::PersistState::OnDemand);
+
+            #[test]
+            fn test_run_process_with_persist_expands_to_guest() {
+                let _: fn(String) = <Component as Guest>::init;
+            }
+        }
+
+        crate::run_process!(init, handle);
+
+        #[test]
+        fn test_run_process_expands_to_guest() {
+            let _: fn(String) = <Component as Guest>::init;
+        }
+    }
+}
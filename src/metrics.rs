@@ -0,0 +1,532 @@
+use crate::http::server::{HttpResponse, Router, StatusCode};
+use crate::{LazyLoadBlob, Message, Response, SendError};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Instant;
+
+/// The body of a request asking a process for its metrics, answered by
+/// [`serve()`] with [`render_prometheus()`].
+pub const METRICS_BODY: &[u8] = b"__metrics";
+
+/// The `Content-Type` of the Prometheus text format.
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The upper bounds of the buckets of a [`histogram()`], suiting durations in
+/// milliseconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+/// Counted by [`crate::main_loop::main_loop()`] when [`record_messages()`] is
+/// on, by the package of the message's source.
+pub const MESSAGES_TOTAL: &str = "messages_total";
+/// Observed by [`crate::main_loop::main_loop()`] when [`record_messages()`] is
+/// on: how long the handler took with each message.
+pub const MESSAGE_HANDLE_MS: &str = "message_handle_ms";
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Counter(f64),
+    Gauge(f64),
+    Histogram {
+        bounds: Vec<f64>,
+        /// Per bucket, not cumulative.
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Counter(_) => "counter",
+            Value::Gauge(_) => "gauge",
+            Value::Histogram { .. } => "histogram",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Family {
+    help: Option<String>,
+    series: BTreeMap<Labels, Value>,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<BTreeMap<String, Family>> = const { RefCell::new(BTreeMap::new()) };
+    static RECORD_MESSAGES: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Update the series of name and labels, making it with new if there is none.
+/// An update of a name already in use by another kind of metric is dropped.
+fn update(name: &str, labels: &Labels, new: Value, f: impl FnOnce(&mut Value)) {
+    REGISTRY.with_borrow_mut(|registry| {
+        let family = registry.entry(name.to_string()).or_default();
+        let kind = new.kind();
+        if family.series.values().any(|value| value.kind() != kind) {
+            crate::debug!("metrics: {name} is not a {kind}, dropping update");
+            return;
+        }
+        f(family.series.entry(labels.clone()).or_insert(new));
+    })
+}
+
+fn read(name: &str, labels: &Labels) -> Option<Value> {
+    REGISTRY.with_borrow(|registry| registry.get(name)?.series.get(labels).cloned())
+}
+
+fn with_label(mut labels: Labels, name: &str, value: &str) -> Labels {
+    labels.retain(|(n, _)| n != name);
+    labels.push((name.to_string(), value.to_string()));
+    labels.sort();
+    labels
+}
+
+/// A value that only goes up, e.g. requests handled. See [`counter()`].
+#[derive(Clone, Debug)]
+pub struct Counter {
+    name: String,
+    labels: Labels,
+}
+
+impl Counter {
+    /// The same counter, for the series with label name set to value.
+    pub fn label(self, name: &str, value: &str) -> Self {
+        Counter {
+            labels: with_label(self.labels, name, value),
+            ..self
+        }
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1.0);
+    }
+
+    /// Add amount, unless it is negative.
+    pub fn inc_by(&self, amount: f64) {
+        if amount < 0.0 {
+            return;
+        }
+        update(&self.name, &self.labels, Value::Counter(0.0), |value| {
+            if let Value::Counter(total) = value {
+                *total += amount;
+            }
+        });
+    }
+
+    pub fn get(&self) -> f64 {
+        match read(&self.name, &self.labels) {
+            Some(Value::Counter(total)) => total,
+            _ => 0.0,
+        }
+    }
+}
+
+/// A value that goes up and down, e.g. the length of a queue. See [`gauge()`].
+#[derive(Clone, Debug)]
+pub struct Gauge {
+    name: String,
+    labels: Labels,
+}
+
+impl Gauge {
+    /// The same gauge, for the series with label name set to value.
+    pub fn label(self, name: &str, value: &str) -> Self {
+        Gauge {
+            labels: with_label(self.labels, name, value),
+            ..self
+        }
+    }
+
+    pub fn set(&self, value: f64) {
+        self.apply(|gauge| *gauge = value);
+    }
+
+    pub fn add(&self, amount: f64) {
+        self.apply(|gauge| *gauge += amount);
+    }
+
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1.0);
+    }
+
+    pub fn get(&self) -> f64 {
+        match read(&self.name, &self.labels) {
+            Some(Value::Gauge(value)) => value,
+            _ => 0.0,
+        }
+    }
+
+    fn apply(&self, f: impl FnOnce(&mut f64)) {
+        update(&self.name, &self.labels, Value::Gauge(0.0), |value| {
+            if let Value::Gauge(gauge) = value {
+                f(gauge);
+            }
+        });
+    }
+}
+
+/// Observations counted into fixed buckets, e.g. handling durations. See
+/// [`histogram()`].
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    name: String,
+    labels: Labels,
+    bounds: Vec<f64>,
+}
+
+impl Histogram {
+    /// The same histogram, for the series with label name set to value.
+    pub fn label(self, name: &str, value: &str) -> Self {
+        Histogram {
+            labels: with_label(self.labels, name, value),
+            ..self
+        }
+    }
+
+    /// Use bounds as the upper bounds of the buckets, instead of
+    /// [`DEFAULT_BUCKETS`]. Only has an effect before the first observation of
+    /// the series; a `+Inf` bucket is always added.
+    pub fn buckets(self, bounds: &[f64]) -> Self {
+        let mut bounds: Vec<f64> = bounds.iter().copied().filter(|b| b.is_finite()).collect();
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        Histogram { bounds, ..self }
+    }
+
+    pub fn observe(&self, x: f64) {
+        update(
+            &self.name,
+            &self.labels,
+            Value::Histogram {
+                counts: vec![0; self.bounds.len()],
+                bounds: self.bounds.clone(),
+                sum: 0.0,
+                count: 0,
+            },
+            |value| {
+                if let Value::Histogram {
+                    bounds,
+                    counts,
+                    sum,
+                    count,
+                } = value
+                {
+                    if let Some(bucket) = bounds.iter().position(|bound| x <= *bound) {
+                        counts[bucket] += 1;
+                    }
+                    *sum += x;
+                    *count += 1;
+                }
+            },
+        );
+    }
+
+    /// How many observations there have been.
+    pub fn count(&self) -> u64 {
+        match read(&self.name, &self.labels) {
+            Some(Value::Histogram { count, .. }) => count,
+            _ => 0,
+        }
+    }
+}
+
+/// The counter called name, without labels.
+pub fn counter(name: &str) -> Counter {
+    Counter {
+        name: name.to_string(),
+        labels: vec![],
+    }
+}
+
+/// The gauge called name, without labels.
+pub fn gauge(name: &str) -> Gauge {
+    Gauge {
+        name: name.to_string(),
+        labels: vec![],
+    }
+}
+
+/// The histogram called name, without labels, with [`DEFAULT_BUCKETS`].
+pub fn histogram(name: &str) -> Histogram {
+    Histogram {
+        name: name.to_string(),
+        labels: vec![],
+        bounds: DEFAULT_BUCKETS.to_vec(),
+    }
+}
+
+/// Give the metric called name a `# HELP` line when rendered.
+pub fn describe(name: &str, help: &str) {
+    REGISTRY.with_borrow_mut(|registry| {
+        registry.entry(name.to_string()).or_default().help = Some(help.to_string());
+    });
+}
+
+/// Forget every metric.
+pub fn reset() {
+    REGISTRY.with_borrow_mut(BTreeMap::clear);
+}
+
+/// Every metric in the Prometheus text format, by name, then by labels.
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+    REGISTRY.with_borrow(|registry| {
+        for (name, family) in registry {
+            let Some(first) = family.series.values().next() else {
+                continue;
+            };
+            if let Some(help) = &family.help {
+                let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+                let _ = writeln!(out, "# HELP {name} {help}");
+            }
+            let _ = writeln!(out, "# TYPE {name} {}", first.kind());
+            for (labels, value) in &family.series {
+                render_series(&mut out, name, labels, value);
+            }
+        }
+    });
+    out
+}
+
+fn render_series(out: &mut String, name: &str, labels: &Labels, value: &Value) {
+    match value {
+        Value::Counter(value) | Value::Gauge(value) => {
+            let _ = writeln!(out, "{name}{} {}", render_labels(labels), number(*value));
+        }
+        Value::Histogram {
+            bounds,
+            counts,
+            sum,
+            count,
+        } => {
+            let mut cumulative = 0;
+            for (bound, bucket) in bounds.iter().zip(counts) {
+                cumulative += bucket;
+                let labels = with_label(labels.clone(), "le", &number(*bound));
+                let _ = writeln!(out, "{name}_bucket{} {cumulative}", render_labels(&labels));
+            }
+            let labels_inf = with_label(labels.clone(), "le", "+Inf");
+            let _ = writeln!(out, "{name}_bucket{} {count}", render_labels(&labels_inf));
+            let labels = render_labels(labels);
+            let _ = writeln!(out, "{name}_sum{labels} {}", number(*sum));
+            let _ = writeln!(out, "{name}_count{labels} {count}");
+        }
+    }
+}
+
+fn render_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Answer message if it is a metrics request, returning `Some(())` if it was
+/// one, so that a handler can start with
+///
+/// ```no_run
+/// # fn handle(message: &hyperware_process_lib::Message) -> anyhow::Result<()> {
+/// if hyperware_process_lib::metrics::serve(message).is_some() {
+///     return Ok(());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A failure to answer is logged rather than returned.
+pub fn serve(message: &Message) -> Option<()> {
+    let Message::Request {
+        expects_response,
+        body,
+        ..
+    } = message
+    else {
+        return None;
+    };
+    if body != METRICS_BODY {
+        return None;
+    }
+    if expects_response.is_some() {
+        if let Err(e) = Response::new().body(render_prometheus()).send() {
+            crate::log_error_chain(
+                &anyhow::Error::from(e).context("failed to answer metrics request"),
+            );
+        }
+    }
+    Some(())
+}
+
+/// The metrics as the response to a scrape over HTTP.
+pub fn http_response() -> (HttpResponse, Option<LazyLoadBlob>) {
+    (
+        HttpResponse::new(StatusCode::OK).header("Content-Type", PROMETHEUS_CONTENT_TYPE),
+        Some(LazyLoadBlob::new(
+            Some(PROMETHEUS_CONTENT_TYPE),
+            render_prometheus(),
+        )),
+    )
+}
+
+/// Add a `GET /metrics` route answering with [`http_response()`] to router.
+/// The path is bound along with the others by [`Router::bind()`].
+pub fn route(router: Router) -> Router {
+    router.route("GET", "/metrics", |_, _| http_response())
+}
+
+/// Have [`crate::main_loop::main_loop()`], and so [`crate::run_process!`],
+/// count every message by the package of its source, in [`MESSAGES_TOTAL`]
+/// with label `source_package`, and observe how long its handler took, in
+/// [`MESSAGE_HANDLE_MS`]. Messages taken care of by the loop itself, such as
+/// shutdown requests, are not recorded. Off until turned on.
+pub fn record_messages(on: bool) {
+    RECORD_MESSAGES.set(on);
+}
+
+/// A message being handled, recorded when it is finished with.
+pub(crate) struct MessageTimer {
+    source_package: String,
+    started: Instant,
+}
+
+/// Start timing message, if [`record_messages()`] is on.
+pub(crate) fn start_message(message: &Result<Message, SendError>) -> Option<MessageTimer> {
+    if !RECORD_MESSAGES.get() {
+        return None;
+    }
+    let source = match message {
+        Ok(message) => message.source(),
+        // the message bounced back from its target
+        Err(e) => &e.target,
+    };
+    Some(MessageTimer {
+        source_package: source.package_id().to_string(),
+        started: Instant::now(),
+    })
+}
+
+impl MessageTimer {
+    pub(crate) fn finish(self) {
+        counter(MESSAGES_TOTAL)
+            .label("source_package", &self.source_package)
+            .inc();
+        histogram(MESSAGE_HANDLE_MS).observe(self.started.elapsed().as_secs_f64() * 1000.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters_and_gauges() {
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        reset();
+        assert_eq!(render_prometheus(), "");
+        describe("requests_total", "Requests handled.\nBy route.");
+        counter("requests_total").inc();
+        counter("requests_total").inc_by(2.0);
+        counter("requests_total").inc_by(-5.0);
+        counter("requests_total")
+            .label("route", "/api/\"items\"")
+            .inc();
+        gauge("queue_depth").set(7.0);
+        gauge("queue_depth").dec();
+        gauge("temperature").set(-0.5);
+        // a name belongs to the first kind of metric to use it
+        gauge("requests_total").set(100.0);
+        assert_eq!(counter("requests_total").get(), 3.0);
+        assert_eq!(
+            render_prometheus(),
+            "\
+# TYPE queue_depth gauge
+queue_depth 6
+# HELP requests_total Requests handled.\\nBy route.
+# TYPE requests_total counter
+requests_total 3
+requests_total{route=\"/api/\\\"items\\\"\"} 1
+# TYPE temperature gauge
+temperature -0.5
+"
+        );
+    }
+
+    #[test]
+    fn test_render_histogram() {
+        reset();
+        let latency = histogram("handle_ms").buckets(&[10.0, 1.0, 5.0, f64::INFINITY]);
+        for x in [0.5, 1.0, 3.0, 7.5, 12.0] {
+            latency.observe(x);
+        }
+        histogram("handle_ms")
+            .label("kind", "slow")
+            .buckets(&[100.0])
+            .observe(250.0);
+        assert_eq!(latency.count(), 5);
+        assert_eq!(
+            render_prometheus(),
+            "\
+# TYPE handle_ms histogram
+handle_ms_bucket{le=\"1\"} 2
+handle_ms_bucket{le=\"5\"} 3
+handle_ms_bucket{le=\"10\"} 4
+handle_ms_bucket{le=\"+Inf\"} 5
+handle_ms_sum 24
+handle_ms_count 5
+handle_ms_bucket{kind=\"slow\",le=\"100\"} 0
+handle_ms_bucket{kind=\"slow\",le=\"+Inf\"} 1
+handle_ms_sum{kind=\"slow\"} 250
+handle_ms_count{kind=\"slow\"} 1
+"
+        );
+    }
+
+    #[test]
+    fn test_record_messages() {
+        reset();
+        let message = crate::test_utils::MessageBuilder::request()
+            .from("other.os@chat:chat:template.os")
+            .body("hi")
+            .build();
+        assert!(start_message(&Ok(message.clone())).is_none());
+        record_messages(true);
+        start_message(&Ok(message)).unwrap().finish();
+        record_messages(false);
+        assert_eq!(
+            counter(MESSAGES_TOTAL)
+                .label("source_package", "chat:template.os")
+                .get(),
+            1.0
+        );
+        assert_eq!(histogram(MESSAGE_HANDLE_MS).count(), 1);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{get_blob, Address, NodeId, Request, SendError};
+use crate::{get_blob, Address, BuildError, NodeId, Request, SendError};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
@@ -108,6 +108,132 @@ pub enum NetResponse {
     Verified(bool),
 }
 
+/// Result of [`ping()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PingResult {
+    /// The node's networking process answered, after rtt_ms milliseconds.
+    Responded { rtt_ms: u64 },
+    /// The message was delivered, but no answer came within the timeout.
+    TimedOut,
+    /// The node could not be reached: it is offline, or unknown to our PKI.
+    /// Use [`is_known()`] to tell the two apart.
+    Offline,
+}
+
+/// Errors from [`sign()`] and [`verify()`].
+#[derive(Debug, thiserror::Error)]
+pub enum NetError {
+    /// The request was not sent, e.g. because a hook refused it.
+    #[error("failed to send: {0}")]
+    Build(#[from] BuildError),
+    #[error("failed to reach net:distro:sys: {0}")]
+    Send(Box<SendError>),
+    /// `net:distro:sys` answered a [`NetAction::Sign`] without a signature.
+    #[error("net:distro:sys answered sign without a signature")]
+    NoSignature,
+}
+
+impl From<SendError> for NetError {
+    fn from(error: SendError) -> Self {
+        NetError::Send(Box::new(error))
+    }
+}
+
+/// Check whether node is online by sending a minimal request to its
+/// `net:distro:sys` and waiting up to timeout seconds for an answer.
+/// Any answer, even a refusal, counts as [`PingResult::Responded`].
+pub fn ping(node: &str, timeout: u64) -> anyhow::Result<PingResult> {
+    let stopwatch = crate::timer::Stopwatch::start();
+    let result = Request::to(crate::SystemProcess::Net.address(node))
+        .body(rmp_serde::to_vec(&NetAction::GetDiagnostics)?)
+        .send_and_await_response(timeout)?;
+    Ok(ping_result(result.map(|_| ()), stopwatch.elapsed_ms()))
+}
+
+fn ping_result(result: Result<(), SendError>, elapsed_ms: u64) -> PingResult {
+    match result {
+        Ok(()) => PingResult::Responded { rtt_ms: elapsed_ms },
+        Err(e) if e.kind.is_timeout() => PingResult::TimedOut,
+        Err(_) => PingResult::Offline,
+    }
+}
+
+/// Whether node is in our networking process's copy of the PKI, i.e. whether
+/// messages to it can be routed at all.
+pub fn is_known(node: &str) -> anyhow::Result<bool> {
+    Ok(get_identity(node)?.is_some())
+}
+
+/// Look up node's [`Identity`] in our networking process's copy of the PKI.
+/// The name is normalized with [`normalize_name()`] first.
+/// Returns `None` if the node is unknown.
+pub fn get_identity(node: &str) -> anyhow::Result<Option<Identity>> {
+    match net_request(&NetAction::GetPeer(normalize_name(node)), 5)? {
+        NetResponse::Peer(peer) => Ok(peer),
+        response => Err(anyhow::anyhow!("net: unexpected response {:?}", response)),
+    }
+}
+
+/// A page of at most limit identities, in name order, starting after the name
+/// cursor (or from the start if `None`). Returns the page and the cursor for the
+/// next page, which is `None` on the last page.
+///
+/// `net:distro:sys` only lists the peers we have a connection with, so those are
+/// the identities covered; use [`get_identity()`] for any other node.
+pub fn get_all_identities(
+    cursor: Option<&str>,
+    limit: usize,
+) -> anyhow::Result<(Vec<Identity>, Option<String>)> {
+    match net_request(&NetAction::GetPeers, 5)? {
+        NetResponse::Peers(peers) => Ok(identity_page(peers, cursor, limit)),
+        response => Err(anyhow::anyhow!("net: unexpected response {:?}", response)),
+    }
+}
+
+fn identity_page(
+    mut identities: Vec<Identity>,
+    cursor: Option<&str>,
+    limit: usize,
+) -> (Vec<Identity>, Option<String>) {
+    identities.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut page: Vec<Identity> = identities
+        .into_iter()
+        .filter(|identity| cursor.is_none_or(|cursor| identity.name.as_str() > cursor))
+        .take(limit.saturating_add(1))
+        .collect();
+    let next = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|identity| identity.name.clone())
+    } else {
+        None
+    };
+    (page, next)
+}
+
+/// Normalize a node name for lookup: surrounding whitespace and trailing dots are
+/// removed and the name is lowercased, so `" Alice.OS. "` becomes `"alice.os"`.
+pub fn normalize_name(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Our own node's [`Identity`]: name, networking key, and routing.
+pub fn our_identity() -> anyhow::Result<Identity> {
+    let our = crate::our_node();
+    match net_request(&NetAction::GetPeer(our.to_string()), 5)? {
+        NetResponse::Peer(Some(identity)) => Ok(identity),
+        NetResponse::Peer(None) => Err(anyhow::anyhow!("net: our node {our} is not in the PKI")),
+        response => Err(anyhow::anyhow!("net: unexpected response {:?}", response)),
+    }
+}
+
+/// Send a local [`NetAction`] to `net:distro:sys` and parse the [`NetResponse`].
+fn net_request(action: &NetAction, timeout: u64) -> anyhow::Result<NetResponse> {
+    let response = Request::to(crate::SystemProcess::Net.address("our"))
+        .body(rmp_serde::to_vec(action)?)
+        .send_and_await_response(timeout)??;
+    Ok(rmp_serde::from_slice(response.body())?)
+}
+
 /// Request performed to `hns-indexer:hns-indexer:sys`, a userspace process
 /// installed by default.
 ///
@@ -166,16 +292,17 @@ impl HnsUpdate {
 ///
 /// This function uses a 30-second timeout to reach `net:distro:sys`. If more
 /// control over the timeout is needed, create a [`Request`] directly.
-pub fn sign<T>(message: T) -> Result<Vec<u8>, SendError>
+pub fn sign<T>(message: T) -> Result<Vec<u8>, NetError>
 where
     T: Into<Vec<u8>>,
 {
-    Request::to(("our", "net", "distro", "sys"))
+    Request::to(crate::SystemProcess::Net.address("our"))
         .body(rmp_serde::to_vec(&NetAction::Sign).unwrap())
         .blob_bytes(message.into())
-        .send_and_await_response(30)
-        .unwrap()
-        .map(|_resp| get_blob().unwrap().bytes)
+        .send_and_await_response(30)??;
+    get_blob()
+        .map(|blob| blob.bytes)
+        .ok_or(NetError::NoSignature)
 }
 
 /// Verify a signature on a message.
@@ -186,13 +313,13 @@ where
 ///
 /// This function uses a 30-second timeout to reach `net:distro:sys`. If more
 /// control over the timeout is needed, create a [`Request`] directly.
-pub fn verify<T, U, V>(from: T, message: U, signature: V) -> Result<bool, SendError>
+pub fn verify<T, U, V>(from: T, message: U, signature: V) -> Result<bool, NetError>
 where
     T: Into<Address>,
     U: Into<Vec<u8>>,
     V: Into<Vec<u8>>,
 {
-    Request::to(("our", "net", "distro", "sys"))
+    let resp = Request::to(crate::SystemProcess::Net.address("our"))
         .body(
             rmp_serde::to_vec(&NetAction::Verify {
                 from: from.into(),
@@ -201,16 +328,11 @@ where
             .unwrap(),
         )
         .blob_bytes(message.into())
-        .send_and_await_response(30)
-        .unwrap()
-        .map(|resp| {
-            let Ok(NetResponse::Verified(valid)) =
-                rmp_serde::from_slice::<NetResponse>(resp.body())
-            else {
-                return false;
-            };
-            valid
-        })
+        .send_and_await_response(30)??;
+    let Ok(NetResponse::Verified(valid)) = rmp_serde::from_slice::<NetResponse>(resp.body()) else {
+        return Ok(false);
+    };
+    Ok(valid)
 }
 
 /// Get a [`crate::hypermap::Hypermap`] entry name from its namehash.
@@ -231,7 +353,7 @@ where
             .unwrap(),
         )
         .send_and_await_response(timeout.unwrap_or(30))
-        .unwrap()
+        .ok()?
         .ok()?;
 
     let Ok(IndexerResponses::Name(maybe_name)) =
@@ -242,3 +364,133 @@ where
 
     maybe_name
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direct() -> Identity {
+        Identity {
+            name: "direct.os".to_string(),
+            networking_key: "0xabcd".to_string(),
+            routing: NodeRouting::Direct {
+                ip: "1.2.3.4".to_string(),
+                ports: BTreeMap::from([("ws".to_string(), 9000)]),
+            },
+        }
+    }
+
+    #[test]
+    fn test_identity_shape() {
+        assert_eq!(
+            serde_json::to_value(direct()).unwrap(),
+            serde_json::json!({
+                "name": "direct.os",
+                "networking_key": "0xabcd",
+                "routing": {"Direct": {"ip": "1.2.3.4", "ports": {"ws": 9000}}},
+            })
+        );
+        let routed: Identity = serde_json::from_value(serde_json::json!({
+            "name": "routed.os",
+            "networking_key": "0x01",
+            "routing": {"Routers": ["router-a.os", "router-b.os"]},
+        }))
+        .unwrap();
+        assert!(!routed.is_direct());
+        assert_eq!(routed.routers().unwrap().len(), 2);
+        assert_eq!(direct().get_protocol_port("ws"), Some(9000));
+        assert_eq!(direct().get_protocol_port("tcp"), None);
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("alice.os"), "alice.os");
+        assert_eq!(normalize_name(" Alice.OS. "), "alice.os");
+        assert_eq!(normalize_name("bob.hypr..."), "bob.hypr");
+        assert_eq!(normalize_name("sub.Name.os"), "sub.name.os");
+        assert_eq!(normalize_name("."), "");
+        assert_eq!(normalize_name(""), "");
+    }
+
+    #[test]
+    fn test_identity_page() {
+        let named = |name: &str| Identity {
+            name: name.to_string(),
+            ..direct()
+        };
+        let all = || vec![named("c.os"), named("a.os"), named("d.os"), named("b.os")];
+        let names = |page: &[Identity]| page.iter().map(|i| i.name.clone()).collect::<Vec<_>>();
+
+        let (page, next) = identity_page(all(), None, 2);
+        assert_eq!(names(&page), vec!["a.os", "b.os"]);
+        assert_eq!(next.as_deref(), Some("b.os"));
+
+        let (page, next) = identity_page(all(), next.as_deref(), 2);
+        assert_eq!(names(&page), vec!["c.os", "d.os"]);
+        assert_eq!(next, None);
+
+        let (page, next) = identity_page(all(), Some("d.os"), 2);
+        assert!(page.is_empty());
+        assert_eq!(next, None);
+
+        let (page, next) = identity_page(all(), None, 10);
+        assert_eq!(page.len(), 4);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn test_ping_result() {
+        let error = |kind| SendError {
+            kind,
+            target: "other.os@net:distro:sys".parse().unwrap(),
+            message: crate::Message::Request {
+                source: "our.os@app:app:sys".parse().unwrap(),
+                expects_response: Some(5),
+                body: vec![],
+                metadata: None,
+                capabilities: vec![],
+            },
+            lazy_load_blob: None,
+            context: None,
+        };
+        assert_eq!(
+            ping_result(Ok(()), 42),
+            PingResult::Responded { rtt_ms: 42 }
+        );
+        assert_eq!(
+            ping_result(Err(error(crate::SendErrorKind::Timeout)), 5000),
+            PingResult::TimedOut
+        );
+        assert_eq!(
+            ping_result(Err(error(crate::SendErrorKind::Offline)), 3),
+            PingResult::Offline
+        );
+    }
+
+    #[test]
+    fn test_net_ipc_round_trips_through_msgpack() {
+        let action = rmp_serde::to_vec(&NetAction::GetPeer("other.os".to_string())).unwrap();
+        assert!(matches!(
+            rmp_serde::from_slice::<NetAction>(&action).unwrap(),
+            NetAction::GetPeer(name) if name == "other.os"
+        ));
+
+        let response = rmp_serde::to_vec(&NetResponse::Peer(Some(direct()))).unwrap();
+        let NetResponse::Peer(Some(identity)) = rmp_serde::from_slice(&response).unwrap() else {
+            panic!("expected Peer");
+        };
+        assert_eq!(identity.name, "direct.os");
+        assert!(identity.is_direct());
+
+        let response = rmp_serde::to_vec(&NetResponse::Peer(None)).unwrap();
+        assert!(matches!(
+            rmp_serde::from_slice(&response).unwrap(),
+            NetResponse::Peer(None)
+        ));
+        let diagnostics = rmp_serde::to_vec(&NetResponse::Diagnostics("ok".into())).unwrap();
+        assert!(matches!(
+            rmp_serde::from_slice(&diagnostics).unwrap(),
+            NetResponse::Diagnostics(text) if text == "ok"
+        ));
+    }
+}
@@ -0,0 +1,550 @@
+use crate::{util::Uid, Address, Message, Request, SendError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Identifies a request pushed to an [`Outbox`]. Sent as the request context, so
+/// it comes back with the response or [`SendError`], and as its idempotency key.
+pub type CorrelationId = Uid;
+
+/// The largest blob an [`Outgoing`] may carry inline; bigger ones should be read
+/// from a vfs file with [`OutboxBlob::Vfs`].
+pub const MAX_INLINE_BLOB_BYTES: usize = 64 * 1024;
+
+/// The blob of an [`Outgoing`] request.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OutboxBlob {
+    /// Stored in the outbox, up to [`MAX_INLINE_BLOB_BYTES`].
+    Inline {
+        mime: Option<String>,
+        bytes: Vec<u8>,
+    },
+    /// Read from the vfs file at path on every attempt.
+    Vfs { mime: Option<String>, path: String },
+}
+
+/// How often, and for how long, an [`Outgoing`] request is tried.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts before giving up, counting the first.
+    pub max_attempts: u32,
+    /// How long each attempt waits for a response, in seconds.
+    pub timeout: u64,
+    /// How long after being pushed the request is dropped unanswered, in ms.
+    pub expires_after_ms: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            timeout: 30,
+            expires_after_ms: None,
+        }
+    }
+}
+
+/// A request to deliver at least once, as pushed to an [`Outbox`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Outgoing {
+    pub target: Address,
+    pub body: Vec<u8>,
+    pub metadata: Option<String>,
+    pub blob: Option<OutboxBlob>,
+    pub policy: RetryPolicy,
+}
+
+impl Outgoing {
+    pub fn new(target: Address, body: Vec<u8>) -> Self {
+        Outgoing {
+            target,
+            body,
+            metadata: None,
+            blob: None,
+            policy: RetryPolicy::default(),
+        }
+    }
+
+    pub fn metadata(mut self, metadata: &str) -> Self {
+        self.metadata = Some(metadata.to_string());
+        self
+    }
+
+    pub fn blob(mut self, blob: OutboxBlob) -> Self {
+        self.blob = Some(blob);
+        self
+    }
+
+    pub fn policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Pending {
+    outgoing: Outgoing,
+    /// Sent as the [`IDEMPOTENCY_KEY`](crate::inbox::IDEMPOTENCY_KEY) of every
+    /// attempt, so the receiver can tell retries apart from new requests.
+    idempotency_key: String,
+    attempts: u32,
+    pushed_at_ms: u64,
+}
+
+/// What became of pending requests in [`Outbox::recover()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    pub resent: Vec<CorrelationId>,
+    /// Dropped because they were out of attempts or past their expiry.
+    pub dropped: Vec<CorrelationId>,
+}
+
+/// What [`Outbox::handle_send_error()`] did about a failed attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    Resent(CorrelationId),
+    /// Out of attempts or past its expiry: the request is gone from the outbox.
+    GaveUp(CorrelationId),
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutboxContext {
+    outbox_id: CorrelationId,
+}
+
+/// The correlation id in the context of a response to, or [`SendError`] for, a
+/// request sent from an [`Outbox`].
+pub fn correlation_id(context: Option<&[u8]>) -> Option<CorrelationId> {
+    serde_json::from_slice::<OutboxContext>(context?)
+        .ok()
+        .map(|context| context.outbox_id)
+}
+
+/// What the outbox needs from the runtime, so that it can be exercised without one.
+trait Io {
+    fn load(&mut self, path: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn save(&mut self, path: &str, bytes: &[u8]) -> anyhow::Result<()>;
+    fn send(&mut self, id: CorrelationId, pending: &Pending) -> anyhow::Result<()>;
+    fn now_ms(&mut self) -> u64;
+}
+
+struct Kernel;
+
+impl Io for Kernel {
+    fn load(&mut self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        use crate::vfs::VfsClientError;
+        match crate::vfs::open_file(path, false, None) {
+            Ok(file) => Ok(Some(file.read()?)),
+            // anything but a missing file must not start an empty outbox over it
+            Err(VfsClientError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&mut self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        Ok(crate::vfs::write_atomic(path, bytes, None)?)
+    }
+
+    fn send(&mut self, id: CorrelationId, pending: &Pending) -> anyhow::Result<()> {
+        let outgoing = &pending.outgoing;
+        let metadata = crate::inbox::with_idempotency_key(
+            outgoing.metadata.as_deref(),
+            &pending.idempotency_key,
+        );
+        let request = Request::to(&outgoing.target)
+            .body(outgoing.body.clone())
+            .metadata(&metadata)
+            .context(serde_json::to_vec(&OutboxContext { outbox_id: id })?)
+            .expects_response(outgoing.policy.timeout);
+        let request = match &outgoing.blob {
+            Some(OutboxBlob::Inline { mime, bytes }) => request.blob(crate::LazyLoadBlob {
+                mime: mime.clone(),
+                bytes: bytes.clone(),
+            }),
+            Some(OutboxBlob::Vfs { mime, path }) => request.blob(crate::LazyLoadBlob {
+                mime: mime.clone(),
+                bytes: crate::vfs::open_file(path, false, None)?.read()?,
+            }),
+            None => request,
+        };
+        request.send()?;
+        Ok(())
+    }
+
+    fn now_ms(&mut self) -> u64 {
+        crate::timer::wall_clock_ms()
+    }
+}
+
+/// Requests that must get through even if the process restarts while they are
+/// awaiting responses, kept in a vfs file.
+///
+/// Each request pushed is written to the file before it is sent, and stays there
+/// until [`Outbox::mark_done()`]. Failed attempts are retried by
+/// [`Outbox::handle_send_error()`], and after a restart [`Outbox::recover()`] sends
+/// everything still pending again. Requests are thus delivered at least once,
+/// possibly more often: every attempt carries the same idempotency key, for the
+/// receiver to drop retries with an [`inbox::Deduper`](crate::inbox::Deduper).
+///
+/// ```no_run
+/// use hyperware_process_lib::outbox::{correlation_id, Outbox, Outgoing};
+/// use hyperware_process_lib::{await_message, Address};
+///
+/// let mut outbox = Outbox::open("/my-app:publisher.os/outbox.json").unwrap();
+/// outbox.recover().unwrap();
+/// let peer: Address = "friend.os@notify:my-app:publisher.os".parse().unwrap();
+/// outbox.push(Outgoing::new(peer, b"important".to_vec())).unwrap();
+/// loop {
+///     match await_message() {
+///         Ok(message) => {
+///             if let Some(id) = correlation_id(message.context()) {
+///                 outbox.mark_done(id).unwrap();
+///             }
+///         }
+///         Err(error) => {
+///             outbox.handle_send_error(&error).unwrap();
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Outbox {
+    path: String,
+    state: OutboxState,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+struct OutboxState {
+    pending: BTreeMap<CorrelationId, Pending>,
+}
+
+impl Outbox {
+    /// Load the outbox kept in the vfs file at path, or start an empty one if
+    /// there is no such file. Nothing is sent until [`Outbox::recover()`].
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        Self::open_with(&mut Kernel, path)
+    }
+
+    /// Save outgoing, then send it.
+    pub fn push(&mut self, outgoing: Outgoing) -> anyhow::Result<CorrelationId> {
+        self.push_with(&mut Kernel, outgoing)
+    }
+
+    /// Forget a request once it has been answered. Returns whether it was pending.
+    pub fn mark_done(&mut self, id: CorrelationId) -> anyhow::Result<bool> {
+        self.mark_done_with(&mut Kernel, id)
+    }
+
+    /// If error is for a request from this outbox, send it again, or drop it if
+    /// it is out of attempts or past its expiry.
+    pub fn handle_send_error(&mut self, error: &SendError) -> anyhow::Result<Option<Retry>> {
+        self.handle_send_error_with(&mut Kernel, error)
+    }
+
+    /// Send every pending request again, dropping those out of attempts or past
+    /// their expiry. Call once on startup.
+    pub fn recover(&mut self) -> anyhow::Result<Recovery> {
+        self.recover_with(&mut Kernel)
+    }
+
+    /// The requests awaiting responses, by correlation id.
+    pub fn pending(&self) -> impl Iterator<Item = (CorrelationId, &Outgoing)> {
+        self.state
+            .pending
+            .iter()
+            .map(|(id, pending)| (*id, &pending.outgoing))
+    }
+
+    /// Whether message answers a request from this outbox that is still pending.
+    pub fn is_pending_response(&self, message: &Message) -> bool {
+        correlation_id(message.context()).is_some_and(|id| self.state.pending.contains_key(&id))
+    }
+
+    fn open_with<I: Io>(io: &mut I, path: &str) -> anyhow::Result<Self> {
+        let state = match io.load(path)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => OutboxState::default(),
+        };
+        Ok(Outbox {
+            path: path.to_string(),
+            state,
+        })
+    }
+
+    fn save<I: Io>(&self, io: &mut I) -> anyhow::Result<()> {
+        io.save(&self.path, &serde_json::to_vec(&self.state)?)
+    }
+
+    fn push_with<I: Io>(
+        &mut self,
+        io: &mut I,
+        outgoing: Outgoing,
+    ) -> anyhow::Result<CorrelationId> {
+        if let Some(OutboxBlob::Inline { bytes, .. }) = &outgoing.blob {
+            if bytes.len() > MAX_INLINE_BLOB_BYTES {
+                return Err(anyhow::anyhow!(
+                    "{}-byte inline blob is over the {MAX_INLINE_BLOB_BYTES}-byte limit: keep it in a vfs file",
+                    bytes.len()
+                ));
+            }
+        }
+        let id = Uid::new();
+        self.state.pending.insert(
+            id,
+            Pending {
+                outgoing,
+                idempotency_key: id.to_string(),
+                // counted before sending, so a crash in between still counts it
+                attempts: 1,
+                pushed_at_ms: io.now_ms(),
+            },
+        );
+        self.save(io)?;
+        io.send(id, &self.state.pending[&id])?;
+        Ok(id)
+    }
+
+    fn mark_done_with<I: Io>(&mut self, io: &mut I, id: CorrelationId) -> anyhow::Result<bool> {
+        if self.state.pending.remove(&id).is_none() {
+            return Ok(false);
+        }
+        self.save(io)?;
+        Ok(true)
+    }
+
+    fn handle_send_error_with<I: Io>(
+        &mut self,
+        io: &mut I,
+        error: &SendError,
+    ) -> anyhow::Result<Option<Retry>> {
+        let Some(id) = correlation_id(error.context()) else {
+            return Ok(None);
+        };
+        if !self.state.pending.contains_key(&id) {
+            return Ok(None);
+        }
+        let now_ms = io.now_ms();
+        let retry = if self.retry(io, id, now_ms)? {
+            Retry::Resent(id)
+        } else {
+            Retry::GaveUp(id)
+        };
+        self.save(io)?;
+        Ok(Some(retry))
+    }
+
+    fn recover_with<I: Io>(&mut self, io: &mut I) -> anyhow::Result<Recovery> {
+        let now_ms = io.now_ms();
+        let mut recovery = Recovery::default();
+        let ids: Vec<_> = self.state.pending.keys().copied().collect();
+        for id in ids {
+            if self.retry(io, id, now_ms)? {
+                recovery.resent.push(id);
+            } else {
+                recovery.dropped.push(id);
+            }
+        }
+        self.save(io)?;
+        Ok(recovery)
+    }
+
+    /// Send request id again if it has attempts left and has not expired, or drop
+    /// it. Returns whether it was sent. The caller saves.
+    fn retry<I: Io>(&mut self, io: &mut I, id: CorrelationId, now_ms: u64) -> anyhow::Result<bool> {
+        let pending = self.state.pending.get_mut(&id).unwrap();
+        let policy = pending.outgoing.policy;
+        let expired = policy
+            .expires_after_ms
+            .is_some_and(|after| now_ms.saturating_sub(pending.pushed_at_ms) >= after);
+        if expired || pending.attempts >= policy.max_attempts {
+            self.state.pending.remove(&id);
+            return Ok(false);
+        }
+        pending.attempts += 1;
+        if let Err(e) = io.send(id, pending) {
+            crate::warn!("outbox failed to resend request {id}: {e:#}");
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SendErrorKind;
+
+    /// A vfs file, a clock and a record of sends.
+    #[derive(Default)]
+    struct MockIo {
+        file: Option<Vec<u8>>,
+        now_ms: u64,
+        sent: Vec<CorrelationId>,
+        keys: Vec<String>,
+    }
+
+    impl Io for MockIo {
+        fn load(&mut self, _: &str) -> anyhow::Result<Option<Vec<u8>>> {
+            Ok(self.file.clone())
+        }
+
+        fn save(&mut self, _: &str, bytes: &[u8]) -> anyhow::Result<()> {
+            self.file = Some(bytes.to_vec());
+            Ok(())
+        }
+
+        fn send(&mut self, id: CorrelationId, pending: &Pending) -> anyhow::Result<()> {
+            self.sent.push(id);
+            self.keys.push(pending.idempotency_key.clone());
+            Ok(())
+        }
+
+        fn now_ms(&mut self) -> u64 {
+            self.now_ms
+        }
+    }
+
+    fn outgoing(policy: RetryPolicy) -> Outgoing {
+        Outgoing::new(
+            "friend.os@notify:app:pub.os".parse().unwrap(),
+            b"hi".to_vec(),
+        )
+        .policy(policy)
+    }
+
+    fn send_error(id: CorrelationId) -> SendError {
+        let context = serde_json::to_vec(&OutboxContext { outbox_id: id }).unwrap();
+        SendError {
+            kind: SendErrorKind::Timeout,
+            target: "friend.os@notify:app:pub.os".parse().unwrap(),
+            message: Message::Request {
+                source: "our.os@app:app:pub.os".parse().unwrap(),
+                expects_response: Some(30),
+                body: b"hi".to_vec(),
+                metadata: None,
+                capabilities: vec![],
+            },
+            lazy_load_blob: None,
+            context: Some(context),
+        }
+    }
+
+    #[test]
+    fn test_recovery_after_restart() {
+        let mut io = MockIo::default();
+        let mut outbox = Outbox::open_with(&mut io, "/outbox.json").unwrap();
+        let first = outbox
+            .push_with(&mut io, outgoing(RetryPolicy::default()))
+            .unwrap();
+        let second = outbox
+            .push_with(&mut io, outgoing(RetryPolicy::default()))
+            .unwrap();
+        let third = outbox
+            .push_with(&mut io, outgoing(RetryPolicy::default()))
+            .unwrap();
+        assert_eq!(io.sent, [first, second, third]);
+        assert!(outbox.mark_done_with(&mut io, second).unwrap());
+        assert!(!outbox.mark_done_with(&mut io, second).unwrap());
+
+        // the process restarts: what was pending is loaded and sent again
+        drop(outbox);
+        io.sent.clear();
+        let keys = std::mem::take(&mut io.keys);
+        let mut outbox = Outbox::open_with(&mut io, "/outbox.json").unwrap();
+        assert!(io.sent.is_empty());
+        let recovery = outbox.recover_with(&mut io).unwrap();
+        assert_eq!(recovery.resent, [first, third]);
+        assert_eq!(io.sent, [first, third]);
+        assert_eq!(io.keys, [keys[0].clone(), keys[2].clone()]);
+        assert_eq!(keys[0], first.to_string());
+        assert_eq!(outbox.state.pending[&first].attempts, 2);
+        // ids are not reused
+        let fourth = outbox
+            .push_with(&mut io, outgoing(RetryPolicy::default()))
+            .unwrap();
+        assert!(fourth > third);
+
+        let response = Message::Response {
+            source: "friend.os@notify:app:pub.os".parse().unwrap(),
+            body: vec![],
+            metadata: None,
+            context: Some(serde_json::to_vec(&OutboxContext { outbox_id: third }).unwrap()),
+            capabilities: vec![],
+        };
+        assert!(outbox.is_pending_response(&response));
+        assert_eq!(correlation_id(response.context()), Some(third));
+        assert_eq!(correlation_id(Some(b"other context")), None);
+    }
+
+    #[test]
+    fn test_attempts_and_expiry() {
+        let mut io = MockIo::default();
+        let mut outbox = Outbox::open_with(&mut io, "/outbox.json").unwrap();
+        let twice = RetryPolicy {
+            max_attempts: 2,
+            ..RetryPolicy::default()
+        };
+        let expiring = RetryPolicy {
+            expires_after_ms: Some(1_000),
+            ..RetryPolicy::default()
+        };
+        let a = outbox.push_with(&mut io, outgoing(twice)).unwrap();
+        let b = outbox.push_with(&mut io, outgoing(expiring)).unwrap();
+
+        assert_eq!(
+            outbox
+                .handle_send_error_with(&mut io, &send_error(a))
+                .unwrap(),
+            Some(Retry::Resent(a))
+        );
+        assert_eq!(
+            outbox
+                .handle_send_error_with(&mut io, &send_error(a))
+                .unwrap(),
+            Some(Retry::GaveUp(a))
+        );
+        assert_eq!(
+            outbox
+                .handle_send_error_with(&mut io, &send_error(a))
+                .unwrap(),
+            None
+        );
+
+        io.now_ms = 999;
+        assert_eq!(
+            outbox
+                .handle_send_error_with(&mut io, &send_error(b))
+                .unwrap(),
+            Some(Retry::Resent(b))
+        );
+
+        // expired while the process was down
+        io.now_ms = 5_000;
+        let mut outbox = Outbox::open_with(&mut io, "/outbox.json").unwrap();
+        let recovery = outbox.recover_with(&mut io).unwrap();
+        assert_eq!(recovery.dropped, [b]);
+        assert!(recovery.resent.is_empty());
+        assert_eq!(outbox.pending().count(), 0);
+        let reloaded = Outbox::open_with(&mut io, "/outbox.json").unwrap();
+        assert_eq!(reloaded.pending().count(), 0);
+    }
+
+    #[test]
+    fn test_inline_blob_cap() {
+        let mut io = MockIo::default();
+        let mut outbox = Outbox::open_with(&mut io, "/outbox.json").unwrap();
+        let blob = |len| OutboxBlob::Inline {
+            mime: None,
+            bytes: vec![0; len],
+        };
+        let too_big = outgoing(RetryPolicy::default()).blob(blob(MAX_INLINE_BLOB_BYTES + 1));
+        assert!(outbox.push_with(&mut io, too_big).is_err());
+        assert!(io.sent.is_empty());
+        let fits = outgoing(RetryPolicy::default()).blob(blob(MAX_INLINE_BLOB_BYTES));
+        outbox.push_with(&mut io, fits).unwrap();
+        let on_disk = outgoing(RetryPolicy::default()).blob(OutboxBlob::Vfs {
+            mime: None,
+            path: "/app:pub.os/big.bin".into(),
+        });
+        outbox.push_with(&mut io, on_disk).unwrap();
+        assert_eq!(io.sent.len(), 2);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{kernel_types::OnExit, vfs, PackageId};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A capability as listed in `manifest.json`: either just the process it is of,
+/// or the process along with params.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ManifestCapability {
+    Process(String),
+    WithParams { process: String, params: Value },
+}
+
+impl ManifestCapability {
+    /// The process the capability is of, e.g. `vfs:distro:sys`.
+    pub fn process(&self) -> &str {
+        match self {
+            ManifestCapability::Process(process) => process,
+            ManifestCapability::WithParams { process, .. } => process,
+        }
+    }
+
+    pub fn params(&self) -> Option<&Value> {
+        match self {
+            ManifestCapability::Process(_) => None,
+            ManifestCapability::WithParams { params, .. } => Some(params),
+        }
+    }
+}
+
+/// An entry of the array in `manifest.json`: one process of the package.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub process_name: String,
+    pub process_wasm_path: String,
+    pub on_exit: OnExit,
+    #[serde(default)]
+    pub request_networking: bool,
+    #[serde(default)]
+    pub request_capabilities: Vec<ManifestCapability>,
+    #[serde(default)]
+    pub grant_capabilities: Vec<ManifestCapability>,
+    #[serde(default)]
+    pub public: bool,
+    /// Fields not listed above, kept as they are.
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+/// The `metadata.json` of a package, in the ERC721 metadata format also
+/// described by [`crate::kernel_types::Erc721Metadata`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackageMetadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub external_url: Option<String>,
+    pub properties: PackageProperties,
+    /// Fields not listed above, kept as they are.
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+impl PackageMetadata {
+    pub fn version(&self) -> &str {
+        &self.properties.current_version
+    }
+
+    pub fn website(&self) -> Option<&str> {
+        self.external_url.as_deref()
+    }
+}
+
+/// The `properties` of a [`PackageMetadata`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PackageProperties {
+    pub package_name: String,
+    pub publisher: String,
+    pub current_version: String,
+    /// Fields not listed above, e.g. `code_hashes` and `mirrors`, kept as they are.
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+/// The directory in the package's drive that its installed files are in.
+pub fn pkg_dir(package_id: &PackageId) -> String {
+    format!("/{package_id}/pkg")
+}
+
+/// The processes of an installed package, from its `manifest.json`. Needs read
+/// capability for the package's drive.
+pub fn read_manifest(package_id: &PackageId) -> anyhow::Result<Vec<ManifestEntry>> {
+    let path = format!("{}/manifest.json", pkg_dir(package_id));
+    vfs::read_json(&path, None).with_context(|| format!("failed to read manifest of {package_id}"))
+}
+
+/// The metadata of an installed package, from its `metadata.json`. Needs read
+/// capability for the package's drive.
+pub fn read_metadata(package_id: &PackageId) -> anyhow::Result<PackageMetadata> {
+    let path = format!("{}/metadata.json", pkg_dir(package_id));
+    vfs::read_json(&path, None).with_context(|| format!("failed to read metadata of {package_id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"[
+        {
+            "process_name": "chat",
+            "process_wasm_path": "/chat.wasm",
+            "on_exit": "Restart",
+            "request_networking": true,
+            "request_capabilities": [
+                "http-server:distro:sys",
+                {"process": "vfs:distro:sys", "params": {"root": true}},
+                {"process": "eth:distro:sys", "params": "read"}
+            ],
+            "grant_capabilities": ["http-server:distro:sys"],
+            "public": false,
+            "wit_version": 1
+        },
+        {
+            "process_name": "helper",
+            "process_wasm_path": "/helper.wasm",
+            "on_exit": "None",
+            "request_networking": false,
+            "request_capabilities": [],
+            "grant_capabilities": [],
+            "public": true
+        }
+    ]"#;
+
+    const METADATA: &str = r#"{
+        "name": "Chat",
+        "description": "A chat app.",
+        "image": "",
+        "external_url": "https://example.com/chat",
+        "animation_url": "",
+        "properties": {
+            "package_name": "chat",
+            "publisher": "template.os",
+            "current_version": "0.2.0",
+            "mirrors": [],
+            "code_hashes": {"0.2.0": "abcd"},
+            "wit_version": 1,
+            "dependencies": []
+        }
+    }"#;
+
+    #[test]
+    fn test_manifest() {
+        let manifest: Vec<ManifestEntry> = serde_json::from_str(MANIFEST).unwrap();
+        assert_eq!(manifest.len(), 2);
+        let chat = &manifest[0];
+        assert_eq!(chat.process_name, "chat");
+        assert!(chat.on_exit.is_restart());
+        assert!(chat.request_networking && !chat.public);
+        let processes: Vec<_> = chat
+            .request_capabilities
+            .iter()
+            .map(|c| c.process())
+            .collect();
+        assert_eq!(
+            processes,
+            ["http-server:distro:sys", "vfs:distro:sys", "eth:distro:sys"]
+        );
+        assert_eq!(chat.request_capabilities[0].params(), None);
+        assert_eq!(
+            chat.request_capabilities[1].params(),
+            Some(&serde_json::json!({"root": true}))
+        );
+        assert_eq!(
+            chat.request_capabilities[2].params(),
+            Some(&serde_json::json!("read"))
+        );
+        assert_eq!(chat.rest["wit_version"], 1);
+        assert!(manifest[1].rest.is_empty());
+
+        // unknown fields survive a round trip
+        let written = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(written[0]["wit_version"], 1);
+        assert_eq!(
+            written[0]["request_capabilities"][1],
+            serde_json::json!({"process": "vfs:distro:sys", "params": {"root": true}})
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata: PackageMetadata = serde_json::from_str(METADATA).unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("Chat"));
+        assert_eq!(metadata.version(), "0.2.0");
+        assert_eq!(metadata.website(), Some("https://example.com/chat"));
+        assert_eq!(metadata.rest["animation_url"], "");
+        assert_eq!(metadata.properties.rest["code_hashes"]["0.2.0"], "abcd");
+        let written = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(written["properties"]["wit_version"], 1);
+
+        let package_id = PackageId::new("chat", "template.os");
+        assert_eq!(pkg_dir(&package_id), "/chat:template.os/pkg");
+    }
+}
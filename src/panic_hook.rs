@@ -0,0 +1,211 @@
+use crate::{Address, Request};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fmt;
+use std::panic::Location;
+
+/// Default cap on the length of a [`PanicReport`] message, in bytes.
+pub const DEFAULT_MAX_REPORT_BYTES: usize = 4096;
+
+/// What [`set_panic_hook()`] reports when this process panics.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PanicReport {
+    /// The address of the process, as a string.
+    pub process: String,
+    /// The panic message, truncated to at most [`PanicHookOptions::max_report_bytes()`].
+    pub message: String,
+    /// `file:line:column` of the panic, if known.
+    pub location: Option<String>,
+}
+
+impl fmt::Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(
+                f,
+                "{} panicked at {location}: {}",
+                self.process, self.message
+            ),
+            None => write!(f, "{} panicked: {}", self.process, self.message),
+        }
+    }
+}
+
+/// Where [`set_panic_hook()`] sends and saves reports, beyond printing them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PanicHookOptions {
+    pub report_to: Option<Address>,
+    pub persist_to_state: bool,
+    pub crash_log: Option<String>,
+    pub max_report_bytes: usize,
+}
+
+impl Default for PanicHookOptions {
+    fn default() -> Self {
+        PanicHookOptions {
+            report_to: None,
+            persist_to_state: false,
+            crash_log: None,
+            max_report_bytes: DEFAULT_MAX_REPORT_BYTES,
+        }
+    }
+}
+
+impl PanicHookOptions {
+    /// Print reports to the terminal only.
+    pub fn new() -> Self {
+        PanicHookOptions::default()
+    }
+
+    /// Also send each report, serialized as JSON, in a Request to address,
+    /// without expecting a response.
+    pub fn report_to(mut self, address: Address) -> Self {
+        self.report_to = Some(address);
+        self
+    }
+
+    /// Also save each report, serialized as JSON, as the process state.
+    /// This **replaces** whatever state the process had.
+    pub fn persist_to_state(mut self, persist: bool) -> Self {
+        self.persist_to_state = persist;
+        self
+    }
+
+    /// Also append each report, as a JSON line, to the vfs file at path,
+    /// creating it if needed.
+    pub fn crash_log<T>(mut self, path: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.crash_log = Some(path.into());
+        self
+    }
+
+    pub fn max_report_bytes(mut self, max_report_bytes: usize) -> Self {
+        self.max_report_bytes = max_report_bytes;
+        self
+    }
+}
+
+/// Replace the panic hook with one that prints a [`PanicReport`] at verbosity 0
+/// and then sends and saves it as configured in options. Failures to send or save
+/// are ignored: nothing in the hook panics.
+///
+/// ```no_run
+/// use hyperware_process_lib::{panic_hook::PanicHookOptions, set_panic_hook, Address};
+///
+/// let supervisor: Address = "our.os@supervisor:my-app:publisher.os".parse().unwrap();
+/// set_panic_hook(
+///     PanicHookOptions::new()
+///         .report_to(supervisor)
+///         .crash_log("/my-app:publisher.os/crashes.jsonl"),
+/// );
+/// ```
+pub fn set_panic_hook(options: PanicHookOptions) {
+    let process = crate::our().to_string();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = panic_report(
+            &process,
+            info.payload(),
+            info.location(),
+            options.max_report_bytes,
+        );
+        crate::print_to_terminal(0, &report.to_string());
+        let Ok(json) = serde_json::to_vec(&report) else {
+            return;
+        };
+        if let Some(address) = &options.report_to {
+            let _ = Request::to(address).body(json.clone()).send();
+        }
+        if options.persist_to_state {
+            crate::set_state(&json);
+        }
+        if let Some(path) = &options.crash_log {
+            if let Ok(mut file) = crate::vfs::open_file(path, true, Some(5)) {
+                let mut line = json;
+                line.push(b'\n');
+                let _ = file.append(&line);
+            }
+        }
+    }));
+}
+
+fn panic_report(
+    process: &str,
+    payload: &(dyn Any + Send),
+    location: Option<&Location<'_>>,
+    max_bytes: usize,
+) -> PanicReport {
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "Box<dyn Any>"
+    };
+    PanicReport {
+        process: process.to_string(),
+        message: truncate(message, max_bytes).to_string(),
+        location: location.map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column())),
+    }
+}
+
+/// The longest prefix of text that is at most max_bytes long and ends on a char boundary.
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panic_report() {
+        let location = Location::caller();
+        let report = panic_report(
+            "our.os@app:pkg:pub.os",
+            &"index out of bounds",
+            Some(location),
+            DEFAULT_MAX_REPORT_BYTES,
+        );
+        let at = format!(
+            "src/panic_hook.rs:{}:{}",
+            location.line(),
+            location.column()
+        );
+        assert_eq!(report.location.as_deref(), Some(at.as_str()));
+        assert_eq!(
+            report.to_string(),
+            format!("our.os@app:pkg:pub.os panicked at {at}: index out of bounds")
+        );
+
+        let owned = panic_report("p", &"formatted 42".to_string(), None, 100);
+        assert_eq!(owned.message, "formatted 42");
+        assert_eq!(owned.to_string(), "p panicked: formatted 42");
+        assert_eq!(panic_report("p", &7u32, None, 100).message, "Box<dyn Any>");
+
+        let json = serde_json::to_value(&owned).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"process": "p", "message": "formatted 42", "location": null})
+        );
+    }
+
+    #[test]
+    fn test_report_size_cap() {
+        let long = "x".repeat(10_000);
+        assert_eq!(panic_report("p", &long, None, 64).message.len(), 64);
+        // never splits a multi-byte char
+        assert_eq!(truncate("aé", 2), "a");
+        assert_eq!(truncate("aé", 3), "aé");
+        assert_eq!(truncate("日本", 4), "日");
+        assert_eq!(truncate("short", 0), "");
+    }
+}
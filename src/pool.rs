@@ -0,0 +1,473 @@
+use crate::{util::Uid, Address, Capability, Message, OnExit, ProcessId, Request, SendError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Identifies a task submitted to a [`WorkerPool`]. Sent to the worker as the
+/// request context, so it comes back with the worker's response.
+pub type TaskId = Uid;
+
+/// Default time a worker has to answer a task, in seconds.
+pub const DEFAULT_TASK_TIMEOUT: u64 = 60;
+
+/// Which worker [`WorkerPool::submit()`] gives the next task to.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Distribution {
+    /// Each worker in turn.
+    #[default]
+    RoundRobin,
+    /// The worker with the fewest tasks in flight, the first in [`WorkerPool::workers()`] on a tie.
+    LeastOutstanding,
+}
+
+/// A worker's answer to a task, as returned by [`WorkerPool::handle()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskResult {
+    pub id: TaskId,
+    pub worker: ProcessId,
+    pub body: Vec<u8>,
+}
+
+impl TaskResult {
+    /// Deserialize the worker's response body from JSON.
+    pub fn result<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+/// Worker processes, spawned from one wasm file, that tasks are distributed to.
+///
+/// Each task is sent to a worker as a Request whose body is the task as JSON and
+/// whose context is its [`TaskId`], expecting a Response within the task timeout.
+/// Workers answer each task with a Response; [`WorkerPool::handle()`] matches
+/// it to the task. If a send to a worker fails (it is gone, or did not answer in
+/// time), hand the [`SendError`] to [`WorkerPool::handle_send_error()`]: the worker
+/// is replaced by a newly spawned one and its tasks in flight are submitted again.
+///
+/// A `WorkerPool` serializes with its workers and tasks in flight, so a process
+/// that keeps it in its state can pick up where it left off after a restart.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, pool::WorkerPool};
+///
+/// let mut pool = WorkerPool::spawn("/my-app:publisher.os/pkg/worker.wasm", 4, vec![]).unwrap();
+/// let task = pool.submit(&("resize", 640, 480)).unwrap();
+/// loop {
+///     match await_message() {
+///         Ok(message) => {
+///             if let Some(result) = pool.handle(&message) {
+///                 assert_eq!(result.id, task);
+///             }
+///         }
+///         Err(error) => {
+///             pool.handle_send_error(&error).unwrap();
+///         }
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WorkerPool {
+    wasm_path: String,
+    capabilities: Vec<Capability>,
+    workers: Vec<Worker>,
+    distribution: Distribution,
+    timeout: u64,
+    /// Round-robin cursor: the index of the next worker to get a task.
+    next_worker: usize,
+    /// How many workers have been spawned, for naming the next one.
+    spawned: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct Worker {
+    process: ProcessId,
+    /// Task bodies by id, kept so they can be sent again if the worker goes away.
+    in_flight: BTreeMap<TaskId, Vec<u8>>,
+}
+
+/// What the pool needs from the runtime, so that it can be exercised without one.
+trait Runtime {
+    fn spawn(
+        &mut self,
+        name: &str,
+        wasm_path: &str,
+        capabilities: &[Capability],
+    ) -> anyhow::Result<ProcessId>;
+    fn send(&mut self, worker: &ProcessId, id: TaskId, body: Vec<u8>, timeout: u64);
+}
+
+struct Kernel;
+
+impl Runtime for Kernel {
+    fn spawn(
+        &mut self,
+        name: &str,
+        wasm_path: &str,
+        capabilities: &[Capability],
+    ) -> anyhow::Result<ProcessId> {
+        crate::spawn(
+            Some(name),
+            wasm_path,
+            OnExit::None,
+            capabilities.to_vec(),
+            vec![],
+            false,
+        )
+        .map_err(|e| anyhow::anyhow!("failed to spawn worker {name}: {e:?}"))
+    }
+
+    fn send(&mut self, worker: &ProcessId, id: TaskId, body: Vec<u8>, timeout: u64) {
+        let _ = Request::to(Address::new(crate::our_node(), worker.clone()))
+            .body(body)
+            .context(serde_json::to_vec(&id).unwrap())
+            .expects_response(timeout)
+            .send();
+    }
+}
+
+impl WorkerPool {
+    /// Spawn n workers from the wasm file at wasm_path, each requesting capabilities.
+    pub fn spawn(wasm_path: &str, n: usize, capabilities: Vec<Capability>) -> anyhow::Result<Self> {
+        Self::spawn_with(&mut Kernel, wasm_path, n, capabilities)
+    }
+
+    pub fn distribution(mut self, distribution: Distribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Set how long a worker has to answer each task, in seconds.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send task, serialized as JSON, to a worker.
+    pub fn submit<T: Serialize>(&mut self, task: &T) -> anyhow::Result<TaskId> {
+        self.submit_with(&mut Kernel, serde_json::to_vec(task)?)
+    }
+
+    /// If message is a worker's Response to a task, take the task out of flight
+    /// and return the result.
+    pub fn handle(&mut self, message: &Message) -> Option<TaskResult> {
+        let Message::Response {
+            source,
+            body,
+            context,
+            ..
+        } = message
+        else {
+            return None;
+        };
+        let id: TaskId = serde_json::from_slice(context.as_deref()?).ok()?;
+        let worker = self.worker_mut(&source.process)?;
+        worker.in_flight.remove(&id)?;
+        Some(TaskResult {
+            id,
+            worker: source.process.clone(),
+            body: body.clone(),
+        })
+    }
+
+    /// If error is for a task sent to one of the workers, replace that worker with
+    /// a newly spawned one and submit its tasks in flight again. Returns whether
+    /// error was for this pool. Fails if the new worker can't be spawned, in which
+    /// case the failed worker's tasks are given to the others.
+    pub fn handle_send_error(&mut self, error: &SendError) -> anyhow::Result<bool> {
+        self.handle_send_error_with(&mut Kernel, error)
+    }
+
+    /// The workers, in the order they were spawned.
+    pub fn workers(&self) -> impl Iterator<Item = &ProcessId> {
+        self.workers.iter().map(|worker| &worker.process)
+    }
+
+    /// How many tasks are awaiting results.
+    pub fn outstanding(&self) -> usize {
+        self.workers
+            .iter()
+            .map(|worker| worker.in_flight.len())
+            .sum()
+    }
+
+    fn spawn_with<R: Runtime>(
+        runtime: &mut R,
+        wasm_path: &str,
+        n: usize,
+        capabilities: Vec<Capability>,
+    ) -> anyhow::Result<Self> {
+        let mut pool = WorkerPool {
+            wasm_path: wasm_path.to_string(),
+            capabilities,
+            workers: vec![],
+            distribution: Distribution::default(),
+            timeout: DEFAULT_TASK_TIMEOUT,
+            next_worker: 0,
+            spawned: 0,
+        };
+        for _ in 0..n.max(1) {
+            let process = pool.spawn_worker(runtime)?;
+            pool.workers.push(Worker {
+                process,
+                in_flight: BTreeMap::new(),
+            });
+        }
+        Ok(pool)
+    }
+
+    fn spawn_worker<R: Runtime>(&mut self, runtime: &mut R) -> anyhow::Result<ProcessId> {
+        let stem = self
+            .wasm_path
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches(".wasm");
+        let name = format!("{stem}-{}", self.spawned);
+        self.spawned += 1;
+        runtime.spawn(&name, &self.wasm_path, &self.capabilities)
+    }
+
+    fn submit_with<R: Runtime>(
+        &mut self,
+        runtime: &mut R,
+        body: Vec<u8>,
+    ) -> anyhow::Result<TaskId> {
+        let id = Uid::new();
+        self.dispatch(runtime, id, body);
+        Ok(id)
+    }
+
+    /// Send a task to the worker the distribution picks.
+    fn dispatch<R: Runtime>(&mut self, runtime: &mut R, id: TaskId, body: Vec<u8>) {
+        let index = self.pick();
+        let worker = &mut self.workers[index];
+        runtime.send(&worker.process, id, body.clone(), self.timeout);
+        worker.in_flight.insert(id, body);
+    }
+
+    fn pick(&mut self) -> usize {
+        match self.distribution {
+            Distribution::RoundRobin => {
+                let index = self.next_worker % self.workers.len();
+                self.next_worker = (index + 1) % self.workers.len();
+                index
+            }
+            Distribution::LeastOutstanding => self
+                .workers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, worker)| worker.in_flight.len())
+                .map(|(index, _)| index)
+                .unwrap(),
+        }
+    }
+
+    fn handle_send_error_with<R: Runtime>(
+        &mut self,
+        runtime: &mut R,
+        error: &SendError,
+    ) -> anyhow::Result<bool> {
+        let Some(index) = self
+            .workers
+            .iter()
+            .position(|worker| worker.process == error.target.process)
+        else {
+            return Ok(false);
+        };
+        let requeue = std::mem::take(&mut self.workers[index].in_flight);
+        match self.spawn_worker(runtime) {
+            Ok(process) => {
+                self.workers[index].process = process;
+                self.requeue(runtime, requeue);
+                Ok(true)
+            }
+            Err(e) if self.workers.len() > 1 => {
+                self.workers.remove(index);
+                self.requeue(runtime, requeue);
+                Err(e)
+            }
+            Err(e) => {
+                // the only worker: keep it and its tasks, so the next error retries
+                self.workers[index].in_flight = requeue;
+                Err(e)
+            }
+        }
+    }
+
+    fn requeue<R: Runtime>(&mut self, runtime: &mut R, tasks: BTreeMap<TaskId, Vec<u8>>) {
+        for (id, body) in tasks {
+            self.dispatch(runtime, id, body);
+        }
+    }
+
+    fn worker_mut(&mut self, process: &ProcessId) -> Option<&mut Worker> {
+        self.workers
+            .iter_mut()
+            .find(|worker| &worker.process == process)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SendErrorKind;
+
+    /// Spawns workers named as asked and records sends, optionally refusing to spawn.
+    #[derive(Default)]
+    struct MockRuntime {
+        sent: Vec<(String, TaskId)>,
+        fail_spawns: bool,
+    }
+
+    impl Runtime for MockRuntime {
+        fn spawn(&mut self, name: &str, _: &str, _: &[Capability]) -> anyhow::Result<ProcessId> {
+            if self.fail_spawns {
+                return Err(anyhow::anyhow!("no more processes"));
+            }
+            Ok(ProcessId::new(Some(name), "app", "pub.os"))
+        }
+
+        fn send(&mut self, worker: &ProcessId, id: TaskId, _: Vec<u8>, _: u64) {
+            self.sent.push((worker.process().to_string(), id));
+        }
+    }
+
+    fn pool(runtime: &mut MockRuntime, n: usize) -> WorkerPool {
+        WorkerPool::spawn_with(runtime, "/app:pub.os/pkg/worker.wasm", n, vec![]).unwrap()
+    }
+
+    fn response(worker: &ProcessId, id: TaskId, body: &[u8]) -> Message {
+        Message::Response {
+            source: Address::new("our.os", worker.clone()),
+            body: body.to_vec(),
+            metadata: None,
+            context: Some(serde_json::to_vec(&id).unwrap()),
+            capabilities: vec![],
+        }
+    }
+
+    fn send_error(worker: &ProcessId, id: TaskId) -> SendError {
+        SendError {
+            kind: SendErrorKind::Timeout,
+            target: Address::new("our.os", worker.clone()),
+            message: response(worker, id, b""),
+            lazy_load_blob: None,
+            context: Some(serde_json::to_vec(&id).unwrap()),
+        }
+    }
+
+    fn per_worker(runtime: &MockRuntime) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for (worker, _) in &runtime.sent {
+            *counts.entry(worker.as_str()).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_round_robin_is_fair() {
+        let mut runtime = MockRuntime::default();
+        let mut pool = pool(&mut runtime, 3);
+        let names: Vec<_> = pool.workers().map(|w| w.process().to_string()).collect();
+        assert_eq!(names, ["worker-0", "worker-1", "worker-2"]);
+        let ids: Vec<TaskId> = (0..9)
+            .map(|n| pool.submit_with(&mut runtime, vec![n]).unwrap())
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            per_worker(&runtime),
+            [("worker-0", 3), ("worker-1", 3), ("worker-2", 3)].into()
+        );
+        assert_eq!(pool.outstanding(), 9);
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let mut runtime = MockRuntime::default();
+        let mut pool = pool(&mut runtime, 3).distribution(Distribution::LeastOutstanding);
+        let ids: Vec<TaskId> = (0..3)
+            .map(|n| pool.submit_with(&mut runtime, vec![n]).unwrap())
+            .collect();
+        // worker-1 finishes its task, so it gets the next two
+        let worker_1 = pool.workers[1].process.clone();
+        let result = pool
+            .handle(&response(&worker_1, ids[1], b"\"done\""))
+            .unwrap();
+        assert_eq!(result.id, ids[1]);
+        assert_eq!(result.result::<String>().unwrap(), "done");
+        let third = pool.submit_with(&mut runtime, vec![3]).unwrap();
+        assert_eq!(
+            runtime.sent.last().unwrap(),
+            &("worker-1".to_string(), third)
+        );
+        let fourth = pool.submit_with(&mut runtime, vec![4]).unwrap();
+        assert_eq!(
+            runtime.sent.last().unwrap(),
+            &("worker-0".to_string(), fourth)
+        );
+
+        // results only count once, and only from the worker the task went to
+        assert!(pool.handle(&response(&worker_1, ids[1], b"")).is_none());
+        assert!(pool.handle(&response(&worker_1, ids[0], b"")).is_none());
+        assert_eq!(pool.outstanding(), 4);
+    }
+
+    #[test]
+    fn test_respawn_requeues_in_flight() {
+        let mut runtime = MockRuntime::default();
+        let mut pool = pool(&mut runtime, 2);
+        let ids: Vec<TaskId> = (0..4)
+            .map(|n| pool.submit_with(&mut runtime, vec![n]).unwrap())
+            .collect();
+        runtime.sent.clear();
+        let worker_0 = pool.workers[0].process.clone();
+        assert!(pool
+            .handle_send_error_with(&mut runtime, &send_error(&worker_0, ids[0]))
+            .unwrap());
+
+        // worker-0 is replaced and its tasks, 0 and 2, are sent again
+        let names: Vec<_> = pool.workers().map(|w| w.process().to_string()).collect();
+        assert_eq!(names, ["worker-2", "worker-1"]);
+        let mut resent: Vec<_> = runtime.sent.iter().map(|(_, id)| *id).collect();
+        resent.sort();
+        assert_eq!(resent, [ids[0], ids[2]]);
+        assert_eq!(pool.outstanding(), 4);
+
+        // the other failures for the replaced worker, and its late answers, are ignored
+        assert!(!pool
+            .handle_send_error_with(&mut runtime, &send_error(&worker_0, ids[2]))
+            .unwrap());
+        assert!(pool.handle(&response(&worker_0, ids[0], b"")).is_none());
+        assert_eq!(runtime.sent.len(), 2);
+
+        // survives a restart
+        let json = serde_json::to_string(&pool).unwrap();
+        let mut restored: WorkerPool = serde_json::from_str(&json).unwrap();
+        let (worker, id) = runtime.sent[0].clone();
+        let worker = ProcessId::new(Some(&worker), "app", "pub.os");
+        assert_eq!(restored.handle(&response(&worker, id, b"")).unwrap().id, id);
+        assert!(restored.submit_with(&mut runtime, vec![]).unwrap() > ids[3]);
+    }
+
+    #[test]
+    fn test_failed_respawn() {
+        let mut runtime = MockRuntime::default();
+        let mut pool = pool(&mut runtime, 2);
+        let id = pool.submit_with(&mut runtime, vec![0]).unwrap();
+        runtime.fail_spawns = true;
+        let worker_0 = pool.workers[0].process.clone();
+        assert!(pool
+            .handle_send_error_with(&mut runtime, &send_error(&worker_0, id))
+            .is_err());
+        // the remaining worker takes over
+        assert_eq!(pool.workers().count(), 1);
+        assert_eq!(runtime.sent.last().unwrap(), &("worker-1".to_string(), id));
+
+        // the last worker is kept, tasks and all
+        let worker_1 = pool.workers[0].process.clone();
+        assert!(pool
+            .handle_send_error_with(&mut runtime, &send_error(&worker_1, id))
+            .is_err());
+        assert_eq!(pool.workers().count(), 1);
+        assert_eq!(pool.outstanding(), 1);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::{Address, Message, Request, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Default number of consecutive failed sends after which a subscriber is dropped.
+pub const DEFAULT_MAX_FAILURES: u32 = 3;
+/// How long [`subscribe()`] and [`unsubscribe()`] wait for the publisher's ack, in seconds.
+pub const ACK_TIMEOUT: u64 = 5;
+
+/// The wire protocol, as JSON bodies.
+///
+/// - A subscriber sends `Subscribe` or `Unsubscribe` in a Request; the publisher
+///   answers with `Ack` if a response is expected.
+/// - A publisher sends `Update` in a Request, expecting an `Ack` if it waits for
+///   acks (see [`Subscribers::ack_timeout()`]); subscribers answer with [`ack()`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PubSubMessage {
+    Subscribe { topic: String },
+    Unsubscribe { topic: String },
+    Update { topic: String, payload: Value },
+    Ack,
+}
+
+/// The publisher's side: who is subscribed to which topics. Serializes, so it
+/// can be kept in the process state.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Subscribers {
+    /// Subscribers by topic, in the order they subscribed.
+    topics: BTreeMap<String, Vec<Address>>,
+    /// How many sends in a row failed, for subscribers whose last send failed.
+    failures: HashMap<Address, u32>,
+    max_failures: u32,
+    ack_timeout: Option<u64>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Self {
+        Subscribers::new()
+    }
+}
+
+/// The outcome of [`Subscribers::publish()`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PublishReport {
+    pub delivered: Vec<Address>,
+    pub failed: Vec<Address>,
+    /// Subscribers dropped because this was their last allowed failure.
+    /// These are also in `failed`.
+    pub pruned: Vec<Address>,
+}
+
+impl Subscribers {
+    /// No subscribers; updates are sent without waiting for acks.
+    pub fn new() -> Self {
+        Subscribers {
+            topics: BTreeMap::new(),
+            failures: HashMap::new(),
+            max_failures: DEFAULT_MAX_FAILURES,
+            ack_timeout: None,
+        }
+    }
+
+    /// Drop a subscriber, from all topics, after this many sends to it fail in a row.
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// Wait up to timeout seconds for each subscriber to [`ack()`] every update.
+    /// A subscriber that doesn't counts as a failed send. Without this, updates
+    /// are sent without expecting responses, so no send is ever counted as failed.
+    pub fn ack_timeout(mut self, timeout: u64) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// If message is a `Subscribe` request, add its source to the topic and ack it.
+    /// Returns whether it was one.
+    pub fn handle_subscribe(&mut self, message: &Message) -> bool {
+        match parse_request(message) {
+            Some(PubSubMessage::Subscribe { topic }) => {
+                self.add(&topic, message.source().clone());
+                reply_ack(message);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// If message is an `Unsubscribe` request, remove its source from the topic
+    /// and ack it. Returns whether it was one.
+    pub fn handle_unsubscribe(&mut self, message: &Message) -> bool {
+        match parse_request(message) {
+            Some(PubSubMessage::Unsubscribe { topic }) => {
+                self.remove(&topic, message.source());
+                reply_ack(message);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Send value to every subscriber of topic, dropping those that have now failed
+    /// [`Subscribers::max_failures()`] sends in a row.
+    pub fn publish<T: Serialize>(
+        &mut self,
+        topic: &str,
+        value: &T,
+    ) -> anyhow::Result<PublishReport> {
+        let ack_timeout = self.ack_timeout;
+        self.publish_with(topic, value, |address, body| {
+            let request = Request::to(address).body(body);
+            match ack_timeout {
+                Some(timeout) => matches!(
+                    request.send_and_await_response(timeout),
+                    Ok(Ok(response)) if parse_body(response.body()) == Some(PubSubMessage::Ack)
+                ),
+                None => request.send().is_ok(),
+            }
+        })
+    }
+
+    /// The subscribers of topic, in the order they subscribed.
+    pub fn subscribers(&self, topic: &str) -> &[Address] {
+        self.topics
+            .get(topic)
+            .map_or(&[], |subscribers| subscribers)
+    }
+
+    /// The topics with at least one subscriber.
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.keys().map(|topic| topic.as_str())
+    }
+
+    pub fn add(&mut self, topic: &str, subscriber: Address) {
+        let subscribers = self.topics.entry(topic.to_string()).or_default();
+        if !subscribers.contains(&subscriber) {
+            subscribers.push(subscriber);
+        }
+    }
+
+    /// Remove subscriber from topic. Returns whether it was subscribed.
+    pub fn remove(&mut self, topic: &str, subscriber: &Address) -> bool {
+        let Some(subscribers) = self.topics.get_mut(topic) else {
+            return false;
+        };
+        let before = subscribers.len();
+        subscribers.retain(|s| s != subscriber);
+        let removed = subscribers.len() < before;
+        if subscribers.is_empty() {
+            self.topics.remove(topic);
+        }
+        if !self.topics.values().any(|s| s.contains(subscriber)) {
+            self.failures.remove(subscriber);
+        }
+        removed
+    }
+
+    /// Remove subscriber from every topic.
+    pub fn remove_everywhere(&mut self, subscriber: &Address) {
+        self.topics.retain(|_, subscribers| {
+            subscribers.retain(|s| s != subscriber);
+            !subscribers.is_empty()
+        });
+        self.failures.remove(subscriber);
+    }
+
+    fn publish_with<T, F>(
+        &mut self,
+        topic: &str,
+        value: &T,
+        mut send: F,
+    ) -> anyhow::Result<PublishReport>
+    where
+        T: Serialize,
+        F: FnMut(&Address, Vec<u8>) -> bool,
+    {
+        let body = serde_json::to_vec(&PubSubMessage::Update {
+            topic: topic.to_string(),
+            payload: serde_json::to_value(value)?,
+        })?;
+        let mut report = PublishReport::default();
+        for subscriber in self.subscribers(topic).to_vec() {
+            if send(&subscriber, body.clone()) {
+                self.failures.remove(&subscriber);
+                report.delivered.push(subscriber);
+            } else {
+                if self.record_failure(&subscriber) {
+                    report.pruned.push(subscriber.clone());
+                }
+                report.failed.push(subscriber);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Count a failed send to subscriber, dropping it if that was one too many.
+    /// Returns whether it was dropped.
+    fn record_failure(&mut self, subscriber: &Address) -> bool {
+        let failures = self.failures.entry(subscriber.clone()).or_default();
+        *failures += 1;
+        if *failures >= self.max_failures {
+            self.remove_everywhere(subscriber);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Subscribe to topic on publisher, waiting for its ack.
+pub fn subscribe(publisher: &Address, topic: &str) -> anyhow::Result<()> {
+    send_and_await_ack(
+        publisher,
+        &PubSubMessage::Subscribe {
+            topic: topic.to_string(),
+        },
+    )
+}
+
+/// Unsubscribe from topic on publisher, waiting for its ack.
+pub fn unsubscribe(publisher: &Address, topic: &str) -> anyhow::Result<()> {
+    send_and_await_ack(
+        publisher,
+        &PubSubMessage::Unsubscribe {
+            topic: topic.to_string(),
+        },
+    )
+}
+
+/// If message is an `Update` request whose payload deserializes as T, its topic
+/// and payload. Answer it with [`ack()`] if the publisher waits for acks.
+pub fn parse_update<T: DeserializeOwned>(message: &Message) -> Option<(String, T)> {
+    match parse_request(message)? {
+        PubSubMessage::Update { topic, payload } => {
+            Some((topic, serde_json::from_value(payload).ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// Acknowledge an update, if its publisher expects a response.
+pub fn ack(message: &Message) {
+    reply_ack(message);
+}
+
+fn send_and_await_ack(publisher: &Address, message: &PubSubMessage) -> anyhow::Result<()> {
+    let response = Request::to(publisher)
+        .body(serde_json::to_vec(message)?)
+        .send_and_await_response(ACK_TIMEOUT)??;
+    match parse_body(response.body()) {
+        Some(PubSubMessage::Ack) => Ok(()),
+        _ => Err(anyhow::anyhow!("{publisher} did not ack")),
+    }
+}
+
+fn reply_ack(message: &Message) {
+    if let Message::Request {
+        expects_response: Some(_),
+        ..
+    } = message
+    {
+        let _ = Response::new()
+            .body(serde_json::to_vec(&PubSubMessage::Ack).unwrap())
+            .send();
+    }
+}
+
+fn parse_request(message: &Message) -> Option<PubSubMessage> {
+    match message {
+        Message::Request { body, .. } => parse_body(body),
+        Message::Response { .. } => None,
+    }
+}
+
+fn parse_body(body: &[u8]) -> Option<PubSubMessage> {
+    serde_json::from_slice(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(process: &str) -> Address {
+        format!("{process}.os@sub:app:pub.os").parse().unwrap()
+    }
+
+    fn request(source: &Address, message: &PubSubMessage) -> Message {
+        Message::Request {
+            source: source.clone(),
+            expects_response: None,
+            body: serde_json::to_vec(message).unwrap(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    #[test]
+    fn test_topic_filtering() {
+        let (alice, bob) = (address("alice"), address("bob"));
+        let mut subscribers = Subscribers::new();
+        let subscribe = |topic: &str| PubSubMessage::Subscribe {
+            topic: topic.to_string(),
+        };
+        assert!(subscribers.handle_subscribe(&request(&alice, &subscribe("prices"))));
+        assert!(subscribers.handle_subscribe(&request(&bob, &subscribe("prices"))));
+        assert!(subscribers.handle_subscribe(&request(&bob, &subscribe("news"))));
+        // subscribing twice is the same as once
+        assert!(subscribers.handle_subscribe(&request(&alice, &subscribe("prices"))));
+        assert!(!subscribers.handle_subscribe(&request(&alice, &PubSubMessage::Ack)));
+        assert!(!subscribers.handle_unsubscribe(&request(&alice, &subscribe("news"))));
+        assert_eq!(
+            subscribers.subscribers("prices"),
+            [alice.clone(), bob.clone()]
+        );
+
+        let mut sent = vec![];
+        let report = subscribers
+            .publish_with("news", &"headline", |to, body| {
+                sent.push((to.clone(), body));
+                true
+            })
+            .unwrap();
+        assert_eq!(report.delivered, vec![bob.clone()]);
+        assert_eq!(sent.len(), 1);
+        let update = request(&address("publisher"), &parse_body(&sent[0].1).unwrap());
+        assert_eq!(
+            parse_update::<String>(&update),
+            Some(("news".to_string(), "headline".to_string()))
+        );
+        assert_eq!(parse_update::<u64>(&update), None);
+        assert!(subscribers
+            .publish_with("weather", &1, |_, _| panic!("nobody subscribed"))
+            .unwrap()
+            .delivered
+            .is_empty());
+
+        let unsubscribe = PubSubMessage::Unsubscribe {
+            topic: "news".to_string(),
+        };
+        assert!(subscribers.handle_unsubscribe(&request(&bob, &unsubscribe)));
+        assert_eq!(subscribers.topics().collect::<Vec<_>>(), ["prices"]);
+    }
+
+    #[test]
+    fn test_pruning_after_consecutive_failures() {
+        let (alice, bob) = (address("alice"), address("bob"));
+        let mut subscribers = Subscribers::new().max_failures(3);
+        subscribers.add("prices", alice.clone());
+        subscribers.add("prices", bob.clone());
+        subscribers.add("news", bob.clone());
+
+        let publish = |subscribers: &mut Subscribers, bob_up: bool| {
+            subscribers
+                .publish_with("prices", &1, |to, _| to == &alice || bob_up)
+                .unwrap()
+        };
+        // a success in between starts the count over
+        assert_eq!(publish(&mut subscribers, false).failed, vec![bob.clone()]);
+        assert!(publish(&mut subscribers, false).pruned.is_empty());
+        assert!(publish(&mut subscribers, true).failed.is_empty());
+        publish(&mut subscribers, false);
+        publish(&mut subscribers, false);
+        let report = publish(&mut subscribers, false);
+        assert_eq!(report.pruned, vec![bob.clone()]);
+        assert_eq!(report.failed, vec![bob.clone()]);
+        assert_eq!(report.delivered, vec![alice.clone()]);
+
+        // pruned from every topic
+        assert_eq!(subscribers.subscribers("prices"), vec![alice.clone()]);
+        assert!(subscribers.subscribers("news").is_empty());
+        assert!(subscribers.failures.is_empty());
+
+        // and persists
+        let json = serde_json::to_string(&subscribers).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Subscribers>(&json).unwrap(),
+            subscribers
+        );
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_eq!(
+            serde_json::to_value(PubSubMessage::Update {
+                topic: "t".to_string(),
+                payload: serde_json::json!({"x": 1}),
+            })
+            .unwrap(),
+            serde_json::json!({"Update": {"topic": "t", "payload": {"x": 1}}})
+        );
+        assert_eq!(
+            serde_json::to_string(&PubSubMessage::Ack).unwrap(),
+            "\"Ack\""
+        );
+    }
+}
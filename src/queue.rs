@@ -0,0 +1,232 @@
+use crate::{LazyLoadBlob, Message, Response, SendError};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// The metadata key of a busy response, holding how many milliseconds the
+/// requester should wait before retrying. See [`retry_after_ms()`].
+pub const BUSY_KEY: &str = "__busy";
+
+/// Counted when [`Backpressure`] sheds a request, by the package of its source.
+pub const SHED_TOTAL: &str = "requests_shed_total";
+
+/// How stale the clock stamping queued messages may be, in milliseconds.
+const CLOCK_STALENESS_MS: u64 = 50;
+
+/// When to turn requests away rather than queue them. See [`set_backpressure()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backpressure {
+    /// How many messages may be queued before requests are shed.
+    pub max_depth: usize,
+    /// What busy responses tell requesters to wait before retrying.
+    pub retry_after_ms: u64,
+}
+
+impl Backpressure {
+    /// Shed requests once max_depth messages are queued, asking requesters to
+    /// retry after a second.
+    pub fn new(max_depth: usize) -> Self {
+        Backpressure {
+            max_depth,
+            retry_after_ms: 1000,
+        }
+    }
+
+    pub fn retry_after_ms(mut self, retry_after_ms: u64) -> Self {
+        self.retry_after_ms = retry_after_ms;
+        self
+    }
+
+    /// Whether received should be shed rather than queued behind depth others:
+    /// only a request expecting a response, never a response to one of ours or
+    /// a send error, and only once the queue is full.
+    pub fn should_shed(&self, received: &Result<Message, SendError>, depth: usize) -> bool {
+        matches!(
+            received,
+            Ok(Message::Request {
+                expects_response: Some(_),
+                ..
+            })
+        ) && depth >= self.max_depth
+    }
+}
+
+thread_local! {
+    /// Messages received but not yet handed out, each with when it was queued
+    /// and its blob.
+    #[allow(clippy::type_complexity)]
+    static PENDING: RefCell<
+        VecDeque<(u64, Result<Message, SendError>, Option<LazyLoadBlob>)>,
+    > = const { RefCell::new(VecDeque::new()) };
+    static BACKPRESSURE: RefCell<Option<Backpressure>> = const { RefCell::new(None) };
+}
+
+/// Shed requests that arrive while the queue is full, as described by
+/// backpressure, or stop shedding with `None`.
+///
+/// A shed request is answered at once with a busy response: a
+/// [`Response::error()`] with code `busy`, a body of
+/// `{"Err": "busy, retry after N ms"}`, and [`BUSY_KEY`] in its metadata. Each is counted in
+/// [`SHED_TOTAL`].
+pub fn set_backpressure(backpressure: Option<Backpressure>) {
+    BACKPRESSURE.set(backpressure);
+}
+
+/// How many messages have been received but not yet handed out by
+/// [`crate::await_message()`].
+///
+/// The runtime's own queue can not be seen from inside a process: these are
+/// the messages taken from it while the process waited for something else,
+/// as [`crate::executor::run()`] does for the responses it awaits.
+pub fn depth() -> usize {
+    PENDING.with_borrow(VecDeque::len)
+}
+
+/// How long ago the oldest queued message was queued, if any is.
+pub fn oldest_age_ms() -> Option<u64> {
+    let queued = PENDING.with_borrow(|pending| pending.front().map(|(queued, ..)| *queued))?;
+    Some(now_ms().saturating_sub(queued))
+}
+
+/// How long message, a busy response to a shed request, asks its requester to
+/// wait before retrying, or `None` if it is not a busy response.
+pub fn retry_after_ms(message: &Message) -> Option<u64> {
+    let metadata: serde_json::Value = serde_json::from_str(message.metadata()?).ok()?;
+    metadata.get(BUSY_KEY)?.as_u64()
+}
+
+/// Queue received, the message last received from the runtime, and its blob,
+/// for [`crate::await_message()`] to hand out later, unless [`Backpressure`]
+/// sheds it.
+#[cfg_attr(not(any(test, feature = "async")), allow(dead_code))]
+pub(crate) fn push(received: Result<Message, SendError>, blob: Option<LazyLoadBlob>) {
+    let backpressure = BACKPRESSURE.with_borrow(Clone::clone);
+    if let Some(backpressure) = backpressure {
+        if backpressure.should_shed(&received, depth()) {
+            if let Ok(request) = &received {
+                shed(request, backpressure.retry_after_ms);
+            }
+            return;
+        }
+    }
+    PENDING.with_borrow_mut(|pending| pending.push_back((now_ms(), received, blob)));
+}
+
+/// The oldest queued message and its blob, if any.
+#[allow(clippy::result_large_err, clippy::type_complexity)]
+pub(crate) fn pop() -> Option<(Result<Message, SendError>, Option<LazyLoadBlob>)> {
+    PENDING.with_borrow_mut(|pending| {
+        pending
+            .pop_front()
+            .map(|(_, received, blob)| (received, blob))
+    })
+}
+
+fn shed(request: &Message, retry_after_ms: u64) {
+    crate::metrics::counter(SHED_TOTAL)
+        .label("source_package", &request.source().package_id().to_string())
+        .inc();
+    let sent = Response::error("busy", format!("busy, retry after {retry_after_ms} ms"))
+        .metadata_field(BUSY_KEY, retry_after_ms)
+        .and_then(|response| Ok(response.send()?));
+    if let Err(e) = sent {
+        crate::log_error_chain(&e.context("failed to shed request"));
+    }
+}
+
+fn now_ms() -> u64 {
+    crate::timer::now_ms_cached(CLOCK_STALENESS_MS).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+    use crate::test_utils::MessageBuilder;
+
+    #[allow(clippy::result_large_err)]
+    fn request(expects_response: bool) -> Result<Message, SendError> {
+        let request = MessageBuilder::request()
+            .from("other.os@client:app:pub.os")
+            .body("work");
+        match expects_response {
+            true => Ok(request.expects_response(5).build()),
+            false => Ok(request.build()),
+        }
+    }
+
+    #[test]
+    fn test_shed_decision_boundaries() {
+        let backpressure = Backpressure::new(2);
+        assert!(!backpressure.should_shed(&request(true), 0));
+        assert!(!backpressure.should_shed(&request(true), 1));
+        assert!(backpressure.should_shed(&request(true), 2));
+        assert!(backpressure.should_shed(&request(true), 3));
+        // nobody would hear that we are busy
+        assert!(!backpressure.should_shed(&request(false), 3));
+        assert!(Backpressure::new(0).should_shed(&request(true), 0));
+    }
+
+    #[test]
+    fn test_responses_are_never_shed() {
+        let backpressure = Backpressure::new(0);
+        let response = MessageBuilder::response()
+            .from("other.os@server:app:pub.os")
+            .body("done")
+            .build();
+        assert!(!backpressure.should_shed(&Ok(response), usize::MAX));
+        let bounced = SendError {
+            kind: crate::SendErrorKind::Timeout,
+            target: "other.os@server:app:pub.os".parse().unwrap(),
+            message: request(true).unwrap(),
+            lazy_load_blob: None,
+            context: None,
+        };
+        assert!(!backpressure.should_shed(&Err(bounced), usize::MAX));
+    }
+
+    #[test]
+    fn test_queue_sheds_when_full() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        set_backpressure(Some(Backpressure::new(1).retry_after_ms(250)));
+        assert_eq!((depth(), oldest_age_ms()), (0, None));
+        push(request(true), None);
+        push(request(true), None);
+        push(
+            Ok(MessageBuilder::response()
+                .from("other.os@server:app:pub.os")
+                .body("done")
+                .build()),
+            None,
+        );
+        set_backpressure(None);
+        assert_eq!(depth(), 2);
+        assert!(oldest_age_ms().is_some());
+
+        let busy: Vec<Call> = host
+            .take_calls()
+            .into_iter()
+            .filter(|call| matches!(call, Call::SendResponse { .. }))
+            .collect();
+        assert_eq!(busy.len(), 1);
+        let Call::SendResponse { response, .. } = &busy[0] else {
+            unreachable!()
+        };
+        assert_eq!(response.body, br#"{"Err":"busy, retry after 250 ms"}"#);
+        let answered = MessageBuilder::response()
+            .from("our@client:app:pub.os")
+            .metadata(response.metadata.as_deref().unwrap())
+            .build();
+        assert_eq!(retry_after_ms(&answered), Some(250));
+        assert_eq!(
+            crate::metrics::counter(SHED_TOTAL)
+                .label("source_package", "app:pub.os")
+                .get(),
+            1.0
+        );
+
+        assert!(pop().unwrap().0.unwrap().is_request());
+        assert!(!pop().unwrap().0.unwrap().is_request());
+        assert!(pop().is_none());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::timer::wall_clock_ms;
+use crate::{Address, Message, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How long a registration lasts unless refreshed, by default.
+pub const DEFAULT_TTL_MS: u64 = 60 * 1000;
+/// How long [`register()`], [`lookup()`] and [`list()`] wait for the coordinator,
+/// in seconds.
+pub const REQUEST_TIMEOUT: u64 = 5;
+
+/// The wire protocol, as JSON bodies: processes send these in Requests to the
+/// coordinator, which answers each with a [`RegistryResponse`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RegistryRequest {
+    /// Register the source of the request as role, replacing whoever held it.
+    Register {
+        role: String,
+    },
+    Lookup {
+        role: String,
+    },
+    List,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RegistryResponse {
+    /// The registration lasts this long, unless refreshed by registering again.
+    Registered {
+        ttl_ms: u64,
+    },
+    Found(Option<Address>),
+    Listed(Vec<Registration>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Registration {
+    pub role: String,
+    pub address: Address,
+    /// When the registration lapses, in ms since the Unix epoch.
+    pub expires_at_ms: u64,
+}
+
+/// The coordinator's side: which process holds which role. A registration
+/// lapses [`Registry::ttl_ms()`] after it was last made, so processes that
+/// crashed drop out. Serializes, so it can be kept in the process state.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, registry::{self, Registry}};
+///
+/// let mut registry = Registry::new();
+/// loop {
+///     let Ok(message) = await_message() else { continue };
+///     if registry::serve(&mut registry, &message) {
+///         continue;
+///     }
+///     // other requests
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Registry {
+    registrations: BTreeMap<String, Registration>,
+    ttl_ms: u64,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl Registry {
+    /// No registrations, which last [`DEFAULT_TTL_MS`].
+    pub fn new() -> Self {
+        Registry {
+            registrations: BTreeMap::new(),
+            ttl_ms: DEFAULT_TTL_MS,
+        }
+    }
+
+    /// Let registrations lapse this long after they were last made.
+    pub fn ttl_ms(mut self, ttl_ms: u64) -> Self {
+        self.ttl_ms = ttl_ms;
+        self
+    }
+
+    /// Register address as role, replacing whoever held it.
+    pub fn register(&mut self, role: &str, address: Address) {
+        self.register_at(role, address, wall_clock_ms());
+    }
+
+    /// The process holding role, unless its registration lapsed.
+    pub fn lookup(&self, role: &str) -> Option<&Address> {
+        self.lookup_at(role, wall_clock_ms())
+    }
+
+    /// The registrations that haven't lapsed, by role.
+    pub fn list(&self) -> Vec<Registration> {
+        self.list_at(wall_clock_ms())
+    }
+
+    /// Forget the registrations that lapsed, e.g. before persisting the registry.
+    pub fn prune(&mut self) {
+        self.prune_at(wall_clock_ms());
+    }
+
+    fn register_at(&mut self, role: &str, address: Address, now_ms: u64) {
+        self.registrations.insert(
+            role.to_string(),
+            Registration {
+                role: role.to_string(),
+                address,
+                expires_at_ms: now_ms.saturating_add(self.ttl_ms),
+            },
+        );
+    }
+
+    fn lookup_at(&self, role: &str, now_ms: u64) -> Option<&Address> {
+        self.registrations
+            .get(role)
+            .filter(|registration| registration.expires_at_ms > now_ms)
+            .map(|registration| &registration.address)
+    }
+
+    fn list_at(&self, now_ms: u64) -> Vec<Registration> {
+        self.registrations
+            .values()
+            .filter(|registration| registration.expires_at_ms > now_ms)
+            .cloned()
+            .collect()
+    }
+
+    fn prune_at(&mut self, now_ms: u64) {
+        self.registrations
+            .retain(|_, registration| registration.expires_at_ms > now_ms);
+    }
+
+    fn serve_at(&mut self, message: &Message, now_ms: u64) -> bool {
+        let Message::Request { body, .. } = message else {
+            return false;
+        };
+        let Ok(request) = serde_json::from_slice::<RegistryRequest>(body) else {
+            return false;
+        };
+        let response = match request {
+            RegistryRequest::Register { role } => {
+                self.prune_at(now_ms);
+                self.register_at(&role, message.source().clone(), now_ms);
+                RegistryResponse::Registered {
+                    ttl_ms: self.ttl_ms,
+                }
+            }
+            RegistryRequest::Lookup { role } => {
+                RegistryResponse::Found(self.lookup_at(&role, now_ms).cloned())
+            }
+            RegistryRequest::List => RegistryResponse::Listed(self.list_at(now_ms)),
+        };
+        if let Message::Request {
+            expects_response: Some(_),
+            ..
+        } = message
+        {
+            let _ = Response::new()
+                .body(serde_json::to_vec(&response).unwrap())
+                .send();
+        }
+        true
+    }
+}
+
+/// If message is a [`RegistryRequest`], answer it from registry. Returns whether
+/// it was one.
+pub fn serve(registry: &mut Registry, message: &Message) -> bool {
+    registry.serve_at(message, wall_clock_ms())
+}
+
+/// Register our process as role with coordinator. Returns how long, in ms, the
+/// registration lasts: register again well before then to stay registered.
+pub fn register(coordinator: &Address, role: &str) -> anyhow::Result<u64> {
+    match call(
+        coordinator,
+        &RegistryRequest::Register {
+            role: role.to_string(),
+        },
+    )? {
+        RegistryResponse::Registered { ttl_ms } => Ok(ttl_ms),
+        response => Err(unexpected(coordinator, &response)),
+    }
+}
+
+/// The process registered as role with coordinator, if any.
+pub fn lookup(coordinator: &Address, role: &str) -> anyhow::Result<Option<Address>> {
+    match call(
+        coordinator,
+        &RegistryRequest::Lookup {
+            role: role.to_string(),
+        },
+    )? {
+        RegistryResponse::Found(address) => Ok(address),
+        response => Err(unexpected(coordinator, &response)),
+    }
+}
+
+/// Every registration with coordinator, by role.
+pub fn list(coordinator: &Address) -> anyhow::Result<Vec<Registration>> {
+    match call(coordinator, &RegistryRequest::List)? {
+        RegistryResponse::Listed(registrations) => Ok(registrations),
+        response => Err(unexpected(coordinator, &response)),
+    }
+}
+
+fn call(coordinator: &Address, request: &RegistryRequest) -> anyhow::Result<RegistryResponse> {
+    let response = Request::to(coordinator)
+        .body(serde_json::to_vec(request)?)
+        .send_and_await_response(REQUEST_TIMEOUT)??;
+    serde_json::from_slice(response.body())
+        .map_err(|e| anyhow::anyhow!("{coordinator} is not a registry: {e}"))
+}
+
+fn unexpected(coordinator: &Address, response: &RegistryResponse) -> anyhow::Error {
+    anyhow::anyhow!("unexpected response from registry {coordinator}: {response:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+
+    fn address(process: &str) -> Address {
+        format!("our.os@{process}:app:pub.os").parse().unwrap()
+    }
+
+    fn request(source: &Address, request: &RegistryRequest) -> Message {
+        Message::Request {
+            source: source.clone(),
+            expects_response: Some(5),
+            body: serde_json::to_vec(request).unwrap(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    fn register(role: &str) -> RegistryRequest {
+        RegistryRequest::Register {
+            role: role.to_string(),
+        }
+    }
+
+    fn lookup(role: &str) -> RegistryRequest {
+        RegistryRequest::Lookup {
+            role: role.to_string(),
+        }
+    }
+
+    fn responses(host: &MockHost) -> Vec<RegistryResponse> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendResponse { response, .. } => {
+                    Some(serde_json::from_slice(&response.body).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let (worker, ui) = (address("worker-1f3a"), address("ui"));
+        let mut registry = Registry::new().ttl_ms(1_000);
+
+        assert!(registry.serve_at(&request(&worker, &register("worker")), 0));
+        assert!(registry.serve_at(&request(&ui, &lookup("worker")), 999));
+        assert!(registry.serve_at(&request(&ui, &RegistryRequest::List), 999));
+        // lapsed, as the worker didn't refresh
+        assert!(registry.serve_at(&request(&ui, &lookup("worker")), 1_000));
+        assert!(registry.serve_at(&request(&ui, &RegistryRequest::List), 1_000));
+        assert_eq!(
+            responses(&host),
+            [
+                RegistryResponse::Registered { ttl_ms: 1_000 },
+                RegistryResponse::Found(Some(worker.clone())),
+                RegistryResponse::Listed(vec![Registration {
+                    role: "worker".to_string(),
+                    address: worker.clone(),
+                    expires_at_ms: 1_000,
+                }]),
+                RegistryResponse::Found(None),
+                RegistryResponse::Listed(vec![]),
+            ]
+        );
+
+        // other messages are left to the caller
+        let response = Message::Response {
+            source: ui.clone(),
+            body: serde_json::to_vec(&RegistryRequest::List).unwrap(),
+            metadata: None,
+            context: None,
+            capabilities: vec![],
+        };
+        assert!(!registry.serve_at(&response, 0));
+        let mut other = request(&ui, &RegistryRequest::List);
+        if let Message::Request { body, .. } = &mut other {
+            *body = b"\"Ping\"".to_vec();
+        }
+        assert!(!registry.serve_at(&other, 0));
+        registry.prune_at(1_000);
+        assert_eq!(registry, Registry::new().ttl_ms(1_000));
+    }
+
+    #[test]
+    fn test_lookup_after_reregistration() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let (first, second, ui) = (address("worker-1"), address("worker-2"), address("ui"));
+        let mut registry = Registry::new().ttl_ms(1_000);
+
+        registry.serve_at(&request(&first, &register("worker")), 0);
+        // refreshing extends the registration
+        registry.serve_at(&request(&first, &register("worker")), 800);
+        assert_eq!(registry.lookup_at("worker", 1_500), Some(&first));
+        // a respawned worker takes over the role
+        registry.serve_at(&request(&second, &register("worker")), 1_600);
+        registry.serve_at(&request(&ui, &lookup("worker")), 1_700);
+        assert_eq!(
+            responses(&host).pop(),
+            Some(RegistryResponse::Found(Some(second.clone())))
+        );
+
+        // and persists
+        let json = serde_json::to_string(&registry).unwrap();
+        let restored: Registry = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.lookup_at("worker", 2_000), Some(&second));
+    }
+
+    #[test]
+    fn test_client() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let coordinator = address("coordinator");
+
+        host.reply(Reply::json(&RegistryResponse::Registered {
+            ttl_ms: 60_000,
+        }));
+        assert_eq!(super::register(&coordinator, "ui").unwrap(), 60_000);
+        host.reply(Reply::json(&RegistryResponse::Found(Some(address("ui")))));
+        assert_eq!(
+            super::lookup(&coordinator, "ui").unwrap(),
+            Some(address("ui"))
+        );
+        host.reply(Reply::json(&RegistryResponse::Found(None)));
+        assert!(super::list(&coordinator).is_err());
+        let sent: Vec<RegistryRequest> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse {
+                    target, request, ..
+                } => {
+                    assert_eq!(target, coordinator);
+                    Some(serde_json::from_slice(&request.body).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, [register("ui"), lookup("ui"), RegistryRequest::List]);
+    }
+}
@@ -0,0 +1,378 @@
+use crate::{timer::Deadline, Address, BuildError, Message, Request, Response, SendError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// The body of an RPC request: `{"method": "...", "params": ...}`. params may be
+/// left out for methods that take `()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// The body of an RPC response is a `Result<Value, RpcError>` serialized as JSON:
+/// `{"Ok": <result>}` or `{"Err": <RpcError>}`.
+pub type RpcResponse = Result<Value, RpcError>;
+
+/// Errors a [`Service`] answers with.
+#[derive(Clone, Debug, Error, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RpcError {
+    #[error("unknown method {0}")]
+    UnknownMethod(String),
+    #[error("bad params for {method}: {error}")]
+    BadParams { method: String, error: String },
+    /// The handler returned an error, described by its chain.
+    #[error("{method} failed: {error}")]
+    Failed { method: String, error: String },
+}
+
+/// Errors from [`call()`].
+#[derive(Debug, Error)]
+pub enum CallError {
+    #[error("failed to encode params: {0}")]
+    Encode(serde_json::Error),
+    #[error("failed to reach service: {0}")]
+    Send(Box<SendError>),
+    /// The request was not sent, e.g. because its [`Deadline`] had passed.
+    #[error("failed to send: {0}")]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    Rpc(#[from] RpcError),
+    #[error("malformed response: {0}")]
+    BadResponse(String),
+}
+
+impl From<SendError> for CallError {
+    fn from(error: SendError) -> Self {
+        CallError::Send(Box::new(error))
+    }
+}
+
+type Handler<S> = Box<dyn Fn(&mut S, Value) -> RpcResponse>;
+
+/// The methods of a [`Service`], by name.
+pub struct Methods<S> {
+    handlers: HashMap<String, Handler<S>>,
+}
+
+impl<S> Methods<S> {
+    fn new() -> Self {
+        Methods {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Register handler as method name. handler gets the service and the params
+    /// deserialized as P, and its result is serialized as the response.
+    pub fn method<P, R, F>(&mut self, name: &str, handler: F) -> &mut Self
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(&mut S, P) -> anyhow::Result<R> + 'static,
+    {
+        let method = name.to_string();
+        let handle = move |service: &mut S, params: Value| {
+            let params = serde_json::from_value(params).map_err(|e| RpcError::BadParams {
+                method: method.clone(),
+                error: e.to_string(),
+            })?;
+            let failed = |error: String| RpcError::Failed {
+                method: method.clone(),
+                error,
+            };
+            let result = handler(service, params).map_err(|e| failed(format!("{e:#}")))?;
+            serde_json::to_value(result).map_err(|e| failed(e.to_string()))
+        };
+        self.handlers.insert(name.to_string(), Box::new(handle));
+        self
+    }
+
+    fn call(&self, service: &mut S, request: RpcRequest) -> RpcResponse {
+        match self.handlers.get(&request.method) {
+            Some(handler) => handler(service, request.params),
+            None => Err(RpcError::UnknownMethod(request.method)),
+        }
+    }
+}
+
+/// A set of methods other processes can [`call()`], served with [`serve()`].
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, rpc::{self, Methods, Service}};
+///
+/// struct Counter {
+///     count: u64,
+/// }
+///
+/// impl Service for Counter {
+///     fn methods(methods: &mut Methods<Self>) {
+///         methods
+///             .method("add", |counter: &mut Counter, n: u64| {
+///                 counter.count += n;
+///                 Ok(counter.count)
+///             })
+///             .method("get", |counter: &mut Counter, (): ()| Ok(counter.count));
+///     }
+/// }
+///
+/// let mut counter = Counter { count: 0 };
+/// let message = await_message().unwrap();
+/// if !rpc::serve(&mut counter, &message).unwrap() {
+///     // not an RPC request: handle it some other way
+/// }
+/// ```
+pub trait Service: Sized + 'static {
+    fn methods(methods: &mut Methods<Self>);
+}
+
+/// If message is an RPC request, run the method it names and, if the sender
+/// expects a response, answer with the result or an [`RpcError`]. Returns whether
+/// message was an RPC request. Fails only if the response could not be sent.
+pub fn serve<S: Service>(service: &mut S, message: &Message) -> anyhow::Result<bool> {
+    serve_with(service, message, |body| {
+        Response::new().body(body).send()?;
+        Ok(())
+    })
+}
+
+fn serve_with<S, F>(service: &mut S, message: &Message, respond: F) -> anyhow::Result<bool>
+where
+    S: Service,
+    F: FnOnce(Vec<u8>) -> anyhow::Result<()>,
+{
+    let Message::Request {
+        expects_response,
+        body,
+        ..
+    } = message
+    else {
+        return Ok(false);
+    };
+    let Ok(request) = serde_json::from_slice::<RpcRequest>(body) else {
+        return Ok(false);
+    };
+    let mut methods = Methods::new();
+    S::methods(&mut methods);
+    let response = methods.call(service, request);
+    if expects_response.is_some() {
+        respond(serde_json::to_vec(&response)?)?;
+    }
+    Ok(true)
+}
+
+/// Call method on the [`Service`] served by target with params, waiting up to
+/// timeout seconds for the result.
+pub fn call<P, R>(target: &Address, method: &str, params: &P, timeout: u64) -> Result<R, CallError>
+where
+    P: Serialize,
+    R: DeserializeOwned,
+{
+    let response = Request::to(target)
+        .body(request_body(method, params)?)
+        .send_and_await_response(timeout)??;
+    decode_response(&response)
+}
+
+/// Like [`call()`], waiting for the result only as long as deadline allows. Fails
+/// with [`BuildError::DeadlineExceeded`] if it has already passed.
+pub fn call_with_deadline<P, R>(
+    target: &Address,
+    method: &str,
+    params: &P,
+    deadline: &Deadline,
+) -> Result<R, CallError>
+where
+    P: Serialize,
+    R: DeserializeOwned,
+{
+    let response = Request::to(target)
+        .body(request_body(method, params)?)
+        .send_and_await_with_deadline(deadline)??;
+    decode_response(&response)
+}
+
+fn request_body<P: Serialize>(method: &str, params: &P) -> Result<Vec<u8>, CallError> {
+    let request = RpcRequest {
+        method: method.to_string(),
+        params: serde_json::to_value(params).map_err(CallError::Encode)?,
+    };
+    serde_json::to_vec(&request).map_err(CallError::Encode)
+}
+
+fn decode_response<R: DeserializeOwned>(message: &Message) -> Result<R, CallError> {
+    let response: RpcResponse = serde_json::from_slice(message.body())
+        .map_err(|e| CallError::BadResponse(e.to_string()))?;
+    serde_json::from_value(response?).map_err(|e| CallError::BadResponse(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Transfer {
+        from: String,
+        to: String,
+        amount: u64,
+    }
+
+    struct Bank {
+        balances: HashMap<String, u64>,
+    }
+
+    impl Service for Bank {
+        fn methods(methods: &mut Methods<Self>) {
+            methods
+                .method("balance", |bank: &mut Bank, account: String| {
+                    Ok(bank.balances.get(&account).copied().unwrap_or_default())
+                })
+                .method("transfer", |bank: &mut Bank, transfer: Transfer| {
+                    let from = bank.balances.entry(transfer.from).or_default();
+                    *from = from
+                        .checked_sub(transfer.amount)
+                        .ok_or_else(|| anyhow::anyhow!("insufficient funds"))?;
+                    *bank.balances.entry(transfer.to).or_default() += transfer.amount;
+                    Ok(())
+                });
+        }
+    }
+
+    fn bank() -> Bank {
+        Bank {
+            balances: [("alice".to_string(), 10)].into_iter().collect(),
+        }
+    }
+
+    fn request(body: Vec<u8>, expects_response: Option<u64>) -> Message {
+        Message::Request {
+            source: "our.os@client:app:pub.os".parse().unwrap(),
+            expects_response,
+            body,
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    fn response(body: Vec<u8>) -> Message {
+        Message::Response {
+            source: "our.os@bank:app:pub.os".parse().unwrap(),
+            body,
+            metadata: None,
+            context: None,
+            capabilities: vec![],
+        }
+    }
+
+    /// Send a call through the service as [`call()`] would, and decode the answer.
+    fn call_bank<P: Serialize, R: DeserializeOwned>(
+        bank: &mut Bank,
+        method: &str,
+        params: &P,
+    ) -> Result<R, CallError> {
+        let mut answer = None;
+        let message = request(request_body(method, params).unwrap(), Some(5));
+        assert!(serve_with(bank, &message, |body| {
+            answer = Some(body);
+            Ok(())
+        })
+        .unwrap());
+        decode_response(&response(answer.unwrap()))
+    }
+
+    #[test]
+    fn test_two_method_service() {
+        let mut bank = bank();
+        let balance: u64 = call_bank(&mut bank, "balance", &"alice").unwrap();
+        assert_eq!(balance, 10);
+        let transfer = Transfer {
+            from: "alice".into(),
+            to: "bob".into(),
+            amount: 4,
+        };
+        let () = call_bank(&mut bank, "transfer", &transfer).unwrap();
+        assert_eq!(
+            call_bank::<_, u64>(&mut bank, "balance", &"bob").unwrap(),
+            4
+        );
+        assert_eq!(
+            call_bank::<_, u64>(&mut bank, "balance", &"alice").unwrap(),
+            6
+        );
+
+        let overdraft = Transfer {
+            amount: 100,
+            ..transfer
+        };
+        match call_bank::<_, ()>(&mut bank, "transfer", &overdraft) {
+            Err(CallError::Rpc(RpcError::Failed { method, error })) => {
+                assert_eq!(method, "transfer");
+                assert_eq!(error, "insufficient funds");
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_errors_are_structured() {
+        let mut bank = bank();
+        assert!(matches!(
+            call_bank::<_, u64>(&mut bank, "withdraw", &5),
+            Err(CallError::Rpc(RpcError::UnknownMethod(method))) if method == "withdraw"
+        ));
+        assert!(matches!(
+            call_bank::<_, u64>(&mut bank, "balance", &5),
+            Err(CallError::Rpc(RpcError::BadParams { method, .. })) if method == "balance"
+        ));
+        // the result type is the client's to get wrong, too
+        assert!(matches!(
+            call_bank::<_, String>(&mut bank, "balance", &"alice"),
+            Err(CallError::BadResponse(_))
+        ));
+        assert!(matches!(
+            decode_response::<u64>(&response(b"nope".to_vec())),
+            Err(CallError::BadResponse(_))
+        ));
+
+        let wire = serde_json::to_value(RpcResponse::Err(RpcError::UnknownMethod("x".into())));
+        assert_eq!(
+            wire.unwrap(),
+            serde_json::json!({"Err": {"UnknownMethod": "x"}})
+        );
+    }
+
+    #[test]
+    fn test_serve_skips_other_messages() {
+        let mut bank = bank();
+        let mut responded = false;
+        let mut serve = |message: &Message| {
+            serve_with(&mut bank, message, |_| {
+                responded = true;
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert!(!serve(&request(b"{\"Get\":1}".to_vec(), Some(5))));
+        assert!(!serve(&response(
+            request_body("balance", &"alice").unwrap()
+        )));
+        // a call that expects no response is still run, but not answered
+        let transfer = Transfer {
+            from: "alice".into(),
+            to: "bob".into(),
+            amount: 1,
+        };
+        assert!(serve(&request(
+            request_body("transfer", &transfer).unwrap(),
+            None
+        )));
+        assert!(!responded);
+        assert_eq!(bank.balances["bob"], 1);
+
+        // params may be left out for methods that take none
+        let body = br#"{"method":"nope"}"#.to_vec();
+        assert!(serve_with(&mut bank, &request(body, None), |_| Ok(())).unwrap());
+    }
+}
@@ -0,0 +1,303 @@
+use crate::{Address, Message, Request, Response};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+/// The body of a request asking a process for its [`ProcessSchema`].
+pub const SCHEMA_BODY: &[u8] = b"__schema";
+
+/// How long [`fetch()`] waits for an answer, in seconds.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// A type that can describe its own JSON serialization as a JSON Schema.
+///
+/// Implemented here for the primitives and containers serde serializes, so that
+/// a body type's implementation is mostly made of [`object()`], [`enum_of()`]
+/// and the `json_schema()` of its fields:
+///
+/// ```
+/// use hyperware_process_lib::schema::{self, JsonSchema};
+/// use serde_json::Value;
+///
+/// // serialized as {"Add": {"n": 1}} or "Get"
+/// enum CounterRequest {
+///     Add { n: u64 },
+///     Get,
+/// }
+///
+/// impl JsonSchema for CounterRequest {
+///     fn json_schema() -> Value {
+///         schema::enum_of(&[
+///             ("Add", Some(schema::object(&[("n", u64::json_schema())]))),
+///             ("Get", None),
+///         ])
+///     }
+/// }
+/// ```
+pub trait JsonSchema {
+    fn json_schema() -> Value;
+}
+
+/// What a process speaks: the JSON Schemas of the bodies of the requests it
+/// takes and of the responses it answers them with.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ProcessSchema {
+    pub request: Value,
+    pub response: Value,
+}
+
+thread_local! {
+    static SERVED: RefCell<Option<ProcessSchema>> = const { RefCell::new(None) };
+}
+
+/// The [`ProcessSchema`] of a process taking Req and answering with Resp.
+pub fn describe<Req: JsonSchema, Resp: JsonSchema>() -> ProcessSchema {
+    ProcessSchema {
+        request: document(Req::json_schema()),
+        response: document(Resp::json_schema()),
+    }
+}
+
+/// Answer [`serve()`]d schema requests with schema from now on, typically
+/// `describe::<MyRequest, MyResponse>()` in `init`.
+pub fn set(schema: ProcessSchema) {
+    SERVED.set(Some(schema));
+}
+
+/// Answer message if it is a schema request, returning `Some(())` if it was
+/// one, so that a handler can start with
+///
+/// ```no_run
+/// # fn handle(message: &hyperware_process_lib::Message) -> anyhow::Result<()> {
+/// if hyperware_process_lib::schema::serve(message).is_some() {
+///     return Ok(());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Schema requests are left to the handler until a schema is [`set()`]. A
+/// failure to answer is logged rather than returned.
+pub fn serve(message: &Message) -> Option<()> {
+    let Message::Request {
+        expects_response,
+        body,
+        ..
+    } = message
+    else {
+        return None;
+    };
+    if body != SCHEMA_BODY {
+        return None;
+    }
+    let schema = SERVED.with_borrow(Clone::clone)?;
+    if expects_response.is_some() {
+        let answer = serde_json::to_vec(&schema)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| Ok(Response::new().body(body).send()?));
+        if let Err(e) = answer {
+            crate::log_error_chain(&e.context("failed to answer schema request"));
+        }
+    }
+    Some(())
+}
+
+/// Ask target for its [`ProcessSchema`], waiting up to [`DEFAULT_TIMEOUT_SECS`].
+pub fn fetch(target: &Address) -> anyhow::Result<ProcessSchema> {
+    let response = Request::to(target)
+        .body(SCHEMA_BODY)
+        .send_and_await_response(DEFAULT_TIMEOUT_SECS)??;
+    serde_json::from_slice(response.body())
+        .with_context(|| format!("{target} answered schema request with something else"))
+}
+
+/// The schema of a JSON object with properties, all of them required.
+pub fn object(properties: &[(&str, Value)]) -> Value {
+    let required: Vec<&str> = properties.iter().map(|(name, _)| *name).collect();
+    let properties: Map<String, Value> = properties
+        .iter()
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect();
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+/// The schema of an enum as serde serializes it by default: a unit variant as
+/// its name, any other as an object with its name as the only key. Each
+/// variant is given with the schema of its contents, or `None` if it has none.
+pub fn enum_of(variants: &[(&str, Option<Value>)]) -> Value {
+    let one_of: Vec<Value> = variants
+        .iter()
+        .map(|(name, contents)| match contents {
+            None => json!({"const": name}),
+            Some(contents) => object(&[(name, contents.clone())]),
+        })
+        .collect();
+    json!({ "oneOf": one_of })
+}
+
+fn document(mut schema: Value) -> Value {
+    if let Value::Object(map) = &mut schema {
+        map.insert(
+            "$schema".to_string(),
+            "https://json-schema.org/draft/2020-12/schema".into(),
+        );
+    }
+    schema
+}
+
+macro_rules! impl_schema {
+    ($schema:tt => $($t:ty),*) => {
+        $(impl JsonSchema for $t {
+            fn json_schema() -> Value {
+                json!($schema)
+            }
+        })*
+    };
+}
+
+impl_schema!({"type": "boolean"} => bool);
+impl_schema!({"type": "integer", "minimum": 0} => u8, u16, u32, u64, u128, usize);
+impl_schema!({"type": "integer"} => i8, i16, i32, i64, i128, isize);
+impl_schema!({"type": "number"} => f32, f64);
+impl_schema!({"type": "string"} => String, str, char, Address);
+impl_schema!({"type": "null"} => ());
+
+impl<T: JsonSchema + ?Sized> JsonSchema for &T {
+    fn json_schema() -> Value {
+        T::json_schema()
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn json_schema() -> Value {
+        json!({"anyOf": [T::json_schema(), {"type": "null"}]})
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn json_schema() -> Value {
+        json!({"type": "array", "items": T::json_schema()})
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for HashMap<String, T> {
+    fn json_schema() -> Value {
+        json!({"type": "object", "additionalProperties": T::json_schema()})
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for BTreeMap<String, T> {
+    fn json_schema() -> Value {
+        HashMap::<String, T>::json_schema()
+    }
+}
+
+impl<T: JsonSchema, E: JsonSchema> JsonSchema for Result<T, E> {
+    fn json_schema() -> Value {
+        enum_of(&[
+            ("Ok", Some(T::json_schema())),
+            ("Err", Some(E::json_schema())),
+        ])
+    }
+}
+
+impl JsonSchema for Value {
+    fn json_schema() -> Value {
+        json!({})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::test_utils::MessageBuilder;
+
+    #[allow(dead_code)] // only described
+    enum KvRequest {
+        Get { key: String },
+        Keys,
+    }
+
+    impl JsonSchema for KvRequest {
+        fn json_schema() -> Value {
+            enum_of(&[
+                ("Get", Some(object(&[("key", String::json_schema())]))),
+                ("Keys", None),
+            ])
+        }
+    }
+
+    type KvResponse = Result<Option<Vec<u8>>, String>;
+
+    #[test]
+    fn test_describe() {
+        let schema = describe::<KvRequest, KvResponse>();
+        assert_eq!(
+            schema.request["$schema"],
+            "https://json-schema.org/draft/2020-12/schema"
+        );
+        assert_eq!(schema.request["oneOf"][0]["required"], json!(["Get"]));
+        assert_eq!(
+            schema.request["oneOf"][0]["properties"]["Get"]["properties"]["key"],
+            json!({"type": "string"})
+        );
+        assert_eq!(schema.request["oneOf"][1], json!({"const": "Keys"}));
+        assert_eq!(
+            schema.response["oneOf"][0]["properties"]["Ok"]["anyOf"][0]["items"],
+            json!({"type": "integer", "minimum": 0})
+        );
+        assert_eq!(
+            schema.response["oneOf"][1]["properties"]["Err"],
+            json!({"type": "string"})
+        );
+    }
+
+    #[test]
+    fn test_serve_round_trips() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let request = MessageBuilder::request()
+            .body(SCHEMA_BODY)
+            .expects_response(5)
+            .build();
+        SERVED.set(None);
+        assert_eq!(serve(&request), None);
+
+        let schema = describe::<KvRequest, KvResponse>();
+        set(schema.clone());
+        let other = MessageBuilder::request()
+            .body_json(&json!({"Get": {"key": "a"}}))
+            .expects_response(5)
+            .build();
+        assert_eq!(serve(&other), None);
+        assert!(host.take_calls().is_empty());
+
+        assert_eq!(serve(&request), Some(()));
+        let calls = host.take_calls();
+        let [Call::SendResponse { response, .. }] = &calls[..] else {
+            panic!("got {calls:?}");
+        };
+        let served: ProcessSchema = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(served, schema);
+
+        host.reply(Reply::body(response.body.clone()));
+        let target: Address = "node.os@kv:app:pub.os".parse().unwrap();
+        assert_eq!(fetch(&target).unwrap(), schema);
+        let calls = host.take_calls();
+        let [Call::SendAndAwaitResponse { request, .. }] = &calls[..] else {
+            panic!("got {calls:?}");
+        };
+        assert_eq!(request.body, SCHEMA_BODY);
+
+        host.reply(Reply::body("nope"));
+        assert!(fetch(&target).is_err());
+    }
+}
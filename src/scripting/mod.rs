@@ -2,7 +2,7 @@
 /// A macro for writing a "script" process. Using this will create the initial
 /// entry point for your process, including the standard `init` function which
 /// is called by the system, and a set of calls that:
-/// 1. Parse the `our` string into an `Address` object.
+/// 1. Parse the `our` string into an `Address` object, and [`crate::set_our()`] it.
 /// 2. Wait for the first message to be sent to the process.
 /// 3. Convert the message body into a string.
 /// 4. Call the `init` function you provide with the [`crate::Address`] and the message body string.
@@ -13,8 +13,9 @@ macro_rules! script {
         struct Component;
         impl Guest for Component {
             fn init(our: String) {
-                use hyperware_process_lib::{await_message, println, Address, Message, Response};
+                use $crate::{await_message, println, Address, Message, Response};
                 let our: Address = our.parse().unwrap();
+                $crate::set_our(our.clone());
                 let Message::Request {
                     body,
                     expects_response,
@@ -72,8 +73,9 @@ macro_rules! widget {
     ($widget_label:expr, $create_widget_func:ident) => {
         struct Component;
         impl Guest for Component {
-            fn init(_our: String) {
-                use hyperware_process_lib::Request;
+            fn init(our: String) {
+                use $crate::Request;
+                $crate::set_our(our.parse().unwrap());
                 Request::to(("our", "homepage", "homepage", "sys"))
                     .body(
                         serde_json::json!({
@@ -91,3 +93,54 @@ macro_rules! widget {
         export!(Component);
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::host::{Call, MockHost, Reply};
+    use crate::test_utils::MessageBuilder;
+    use crate::vfs::{VfsError, VfsResponse};
+
+    trait Guest {
+        fn init(our: String);
+    }
+
+    macro_rules! export {
+        ($component:ident) => {};
+    }
+
+    fn stat(_our: crate::Address, path: String) -> String {
+        match crate::vfs::metadata(&path, None) {
+            Ok(_) => "found".to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    crate::script!(stat);
+
+    #[test]
+    fn test_script_sets_our() {
+        let host = MockHost::new();
+        let _installed = host.install();
+        let request = MessageBuilder::request()
+            .from("tester.os@terminal:terminal:sys")
+            .body("/app:sys/pkg/a.txt")
+            .build();
+        host.push_message(request, None);
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::NoReadCap)));
+
+        Component::init("tester.os@tester:app:sys".to_string());
+        assert_eq!(crate::our().to_string(), "tester.os@tester:app:sys");
+        let targets: Vec<_> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { target, .. } => Some(target),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            targets,
+            [crate::Address::new("tester.os", ("vfs", "distro", "sys"))]
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{Address, Message, Request};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+/// How long requests to the settings process wait for an answer, in seconds.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+/// Whose setting a key names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Our package's own: the key is stored as `{package_id}:{key}`.
+    #[default]
+    Package,
+    /// The user's, shared by every package: the key is stored as given.
+    Global,
+}
+
+/// The body of a request to the settings process, as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SettingsRequest {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: Value,
+    },
+    /// Send us a [`SettingsNotification`] whenever a key starting with prefix
+    /// changes.
+    Subscribe {
+        prefix: String,
+    },
+}
+
+/// The body of the settings process's answer to a [`SettingsRequest`], as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SettingsResponse {
+    /// Answers [`SettingsRequest::Get`]: `None` if the key is not set.
+    Value(Option<Value>),
+    /// Answers [`SettingsRequest::Set`] and [`SettingsRequest::Subscribe`].
+    Ok,
+    Err(String),
+}
+
+/// The body of a request from the settings process to a subscriber, as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SettingsNotification {
+    /// key, as stored, now has value: `null` if it was removed.
+    Changed { key: String, value: Value },
+}
+
+/// The settings process on our node.
+pub fn settings_address() -> Address {
+    Address::new("our", ("settings", "settings", "sys"))
+}
+
+/// key as stored under scope: prefixed with our package id for
+/// [`Scope::Package`]. Panics if called before [`crate::set_our()`].
+pub fn scoped_key(scope: Scope, key: &str) -> String {
+    match scope {
+        Scope::Package => format!("{}:{key}", crate::our().package_id()),
+        Scope::Global => key.to_string(),
+    }
+}
+
+/// Our package's setting key, if it is set.
+pub fn get<T: DeserializeOwned>(key: &str) -> anyhow::Result<Option<T>> {
+    get_in(Scope::Package, key)
+}
+
+/// The setting key under scope, if it is set.
+pub fn get_in<T: DeserializeOwned>(scope: Scope, key: &str) -> anyhow::Result<Option<T>> {
+    let key = scoped_key(scope, key);
+    match send(&SettingsRequest::Get { key: key.clone() })? {
+        SettingsResponse::Value(None) => Ok(None),
+        SettingsResponse::Value(Some(value)) => serde_json::from_value(value)
+            .map(Some)
+            .with_context(|| format!("setting {key} has an unexpected type")),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Set our package's setting key to value.
+pub fn set<T: Serialize>(key: &str, value: &T) -> anyhow::Result<()> {
+    set_in(Scope::Package, key, value)
+}
+
+/// Set the setting key under scope to value.
+pub fn set_in<T: Serialize>(scope: Scope, key: &str, value: &T) -> anyhow::Result<()> {
+    let request = SettingsRequest::Set {
+        key: scoped_key(scope, key),
+        value: serde_json::to_value(value)?,
+    };
+    match send(&request)? {
+        SettingsResponse::Ok => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Ask the settings process to notify us of changes to our package's settings
+/// whose keys start with prefix. Read the notifications with [`parse_change()`].
+pub fn subscribe(prefix: &str) -> anyhow::Result<()> {
+    subscribe_in(Scope::Package, prefix)
+}
+
+/// Like [`subscribe()`], for keys under scope.
+pub fn subscribe_in(scope: Scope, prefix: &str) -> anyhow::Result<()> {
+    let request = SettingsRequest::Subscribe {
+        prefix: scoped_key(scope, prefix),
+    };
+    match send(&request)? {
+        SettingsResponse::Ok => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// The key and new value of a setting, if message is a change notification
+/// from the settings process on our node. The key is as stored, i.e. with our
+/// package id in front for [`Scope::Package`] settings; see [`scoped_key()`].
+pub fn parse_change(message: &Message) -> Option<(String, Value)> {
+    if !message.is_request()
+        || !message.is_local()
+        || message.source().process != settings_address().process
+    {
+        return None;
+    }
+    let SettingsNotification::Changed { key, value } =
+        serde_json::from_slice(message.body()).ok()?;
+    Some((key, value))
+}
+
+fn send(request: &SettingsRequest) -> anyhow::Result<SettingsResponse> {
+    let response = Request::to(settings_address())
+        .body(serde_json::to_vec(request)?)
+        .send_and_await_response(DEFAULT_TIMEOUT_SECS)??;
+    serde_json::from_slice(response.body()).context("settings process answered with something else")
+}
+
+fn unexpected(response: SettingsResponse) -> anyhow::Error {
+    match response {
+        SettingsResponse::Err(e) => anyhow::anyhow!("settings process: {e}"),
+        other => anyhow::anyhow!("unexpected answer from settings process: {other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::test_utils::MessageBuilder;
+    use serde_json::json;
+
+    fn sent(host: &MockHost) -> Vec<SettingsRequest> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse {
+                    target, request, ..
+                } => {
+                    assert_eq!(target, settings_address());
+                    Some(serde_json::from_slice(&request.body).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keys_are_namespaced() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        host.reply(Reply::json(&SettingsResponse::Value(Some(json!("dark")))));
+        host.reply(Reply::json(&SettingsResponse::Value(None)));
+        host.reply(Reply::json(&SettingsResponse::Ok));
+        host.reply(Reply::json(&SettingsResponse::Ok));
+        host.reply(Reply::json(&SettingsResponse::Ok));
+
+        assert_eq!(get::<String>("theme").unwrap().as_deref(), Some("dark"));
+        assert_eq!(get_in::<String>(Scope::Global, "locale").unwrap(), None);
+        set("font_size", &14).unwrap();
+        set_in(Scope::Global, "locale", &"en").unwrap();
+        subscribe("").unwrap();
+        assert_eq!(
+            sent(&host),
+            [
+                SettingsRequest::Get {
+                    key: "app:sys:theme".to_string()
+                },
+                SettingsRequest::Get {
+                    key: "locale".to_string()
+                },
+                SettingsRequest::Set {
+                    key: "app:sys:font_size".to_string(),
+                    value: json!(14)
+                },
+                SettingsRequest::Set {
+                    key: "locale".to_string(),
+                    value: json!("en")
+                },
+                SettingsRequest::Subscribe {
+                    prefix: "app:sys:".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        host.reply(Reply::json(&SettingsResponse::Value(Some(json!("dark")))));
+        host.reply(Reply::json(&SettingsResponse::Err("read-only".to_string())));
+        host.reply(Reply::body("garbage"));
+
+        let error = get::<u64>("theme").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "setting app:sys:theme has an unexpected type"
+        );
+        let error = set("theme", &"light").unwrap_err();
+        assert_eq!(error.to_string(), "settings process: read-only");
+        assert!(subscribe("theme").is_err());
+    }
+
+    #[test]
+    fn test_parse_change() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let change = SettingsNotification::Changed {
+            key: "app:sys:theme".to_string(),
+            value: json!("light"),
+        };
+        let notification = MessageBuilder::request()
+            .from("tester.os@settings:settings:sys")
+            .body_json(&change)
+            .build();
+        assert_eq!(
+            parse_change(&notification),
+            Some(("app:sys:theme".to_string(), json!("light")))
+        );
+
+        // only the settings process on our node is believed
+        for source in [
+            "other.os@settings:settings:sys",
+            "tester.os@impostor:app:sys",
+        ] {
+            let forged = MessageBuilder::request()
+                .from(source)
+                .body_json(&change)
+                .build();
+            assert_eq!(parse_change(&forged), None, "{source}");
+        }
+        let response = MessageBuilder::response()
+            .from("tester.os@settings:settings:sys")
+            .body_json(&change)
+            .build();
+        assert_eq!(parse_change(&response), None);
+        let other = MessageBuilder::request()
+            .from("tester.os@settings:settings:sys")
+            .body_json(&SettingsResponse::Ok)
+            .build();
+        assert_eq!(parse_change(&other), None);
+    }
+}
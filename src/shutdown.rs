@@ -0,0 +1,308 @@
+use crate::timer::wall_clock_ms;
+use crate::{Message, Response};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// The body of a request asking a process to shut down, typically sent by its
+/// app's supervisor or an updater before killing it. Only requests from our own
+/// node are honored: from elsewhere it is an ordinary request.
+pub const SHUTDOWN_BODY: &[u8] = b"__shutdown";
+
+/// How long an [`install()`]ed handler gets, by default.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// The body of the responses sent by [`handle()`], as JSON.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ShutdownResponse {
+    /// Answers a shutdown request: the process has done all it will and can be
+    /// killed. error is the handler's error, if it failed, and timed_out whether
+    /// it ran past its timeout.
+    Ready {
+        error: Option<String>,
+        timed_out: bool,
+    },
+    /// Answers a request that arrived once a shutdown had begun, which was not
+    /// handled.
+    ShuttingDown,
+}
+
+/// The time left to an [`install()`]ed handler. A process can't be interrupted,
+/// so the timeout only holds if the handler bounds what it waits for by
+/// [`Budget::remaining_secs()`], e.g. as the timeout of the requests it awaits.
+pub struct Budget {
+    deadline_ms: u64,
+    now_ms: fn() -> u64,
+}
+
+impl Budget {
+    /// Seconds until the timeout, rounded up, and at least 1 so that it can be
+    /// used as a request timeout as is.
+    pub fn remaining_secs(&self) -> u64 {
+        self.remaining_ms().div_ceil(1000).max(1)
+    }
+
+    pub fn remaining_ms(&self) -> u64 {
+        self.deadline_ms.saturating_sub((self.now_ms)())
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining_ms() == 0
+    }
+}
+
+type Handler = Box<dyn FnOnce(&Budget) -> anyhow::Result<()>>;
+
+#[derive(Default)]
+struct State {
+    handler: Option<(Handler, u64)>,
+    /// Set once a shutdown begins, to the response sent for it once it is done.
+    shutting_down: Option<Option<ShutdownResponse>>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+/// Run handler when asked to shut down: persist state, flush buffered writes,
+/// tell subscribers, and so on. It gets [`DEFAULT_TIMEOUT_SECS`]. Replaces any
+/// handler installed before.
+pub fn install<F>(handler: F)
+where
+    F: FnOnce(&Budget) -> anyhow::Result<()> + 'static,
+{
+    install_with_timeout(handler, DEFAULT_TIMEOUT_SECS);
+}
+
+/// Like [`install()`], giving handler timeout_secs.
+pub fn install_with_timeout<F>(handler: F, timeout_secs: u64)
+where
+    F: FnOnce(&Budget) -> anyhow::Result<()> + 'static,
+{
+    STATE.with_borrow_mut(|state| state.handler = Some((Box::new(handler), timeout_secs)));
+}
+
+/// Whether message asks this process to shut down: a request from our node with
+/// body [`SHUTDOWN_BODY`].
+pub fn is_shutdown_request(message: &Message) -> bool {
+    match message {
+        Message::Request { source, body, .. } => {
+            body == SHUTDOWN_BODY && source.node == crate::our().node
+        }
+        Message::Response { .. } => false,
+    }
+}
+
+/// Whether a shutdown has begun. From then on new work should not be started,
+/// e.g. requests dropped or refused.
+pub fn is_shutting_down() -> bool {
+    STATE.with_borrow(|state| state.shutting_down.is_some())
+}
+
+/// Take care of message if it is about shutting down, returning whether it was.
+/// Call on every incoming message before handling it; [`crate::run_process!`]
+/// does so already.
+///
+/// - A shutdown request begins the shutdown: the [`install()`]ed handler runs,
+///   then the request is answered with [`ShutdownResponse::Ready`], even if the
+///   handler failed or timed out. Further shutdown requests get the same answer.
+/// - Any other request, once the shutdown has begun, is answered with
+///   [`ShutdownResponse::ShuttingDown`] if it expects a response.
+/// - Responses are never taken care of, so work in flight can finish.
+pub fn handle(message: &Message) -> anyhow::Result<bool> {
+    handle_with(message, || {}, wall_clock_ms)
+}
+
+/// [`handle()`], calling save once a shutdown has begun, before the handler.
+pub(crate) fn handle_with(
+    message: &Message,
+    save: impl FnOnce(),
+    now_ms: fn() -> u64,
+) -> anyhow::Result<bool> {
+    let Message::Request {
+        expects_response, ..
+    } = message
+    else {
+        return Ok(false);
+    };
+    if !is_shutdown_request(message) {
+        if !is_shutting_down() {
+            return Ok(false);
+        }
+        crate::debug!("shutting down, refusing request from {}", message.source());
+        if expects_response.is_some() {
+            respond(&ShutdownResponse::ShuttingDown)?;
+        }
+        return Ok(true);
+    }
+    let done = STATE.with_borrow(|state| state.shutting_down.clone());
+    let response = match done {
+        Some(Some(response)) => response,
+        // the handler is running and received this from within
+        Some(None) => return Ok(true),
+        None => {
+            crate::info!("shutting down at the request of {}", message.source());
+            let handler = STATE.with_borrow_mut(|state| {
+                state.shutting_down = Some(None);
+                state.handler.take()
+            });
+            save();
+            let response = run(handler, now_ms);
+            STATE.with_borrow_mut(|state| state.shutting_down = Some(Some(response.clone())));
+            response
+        }
+    };
+    respond(&response)?;
+    Ok(true)
+}
+
+fn run(handler: Option<(Handler, u64)>, now_ms: fn() -> u64) -> ShutdownResponse {
+    let Some((handler, timeout_secs)) = handler else {
+        return ShutdownResponse::Ready {
+            error: None,
+            timed_out: false,
+        };
+    };
+    let budget = Budget {
+        deadline_ms: now_ms().saturating_add(timeout_secs.saturating_mul(1000)),
+        now_ms,
+    };
+    let error = handler(&budget).err().map(|e| {
+        crate::log_error_chain(&e);
+        format!("{e:#}")
+    });
+    let timed_out = budget.expired();
+    if timed_out {
+        crate::warn!("shutdown handler ran past its {timeout_secs}s timeout");
+    }
+    ShutdownResponse::Ready { error, timed_out }
+}
+
+fn respond(response: &ShutdownResponse) -> anyhow::Result<()> {
+    Response::new().body(serde_json::to_vec(response)?).send()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    thread_local! {
+        static CLOCK_MS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn clock() -> u64 {
+        CLOCK_MS.get()
+    }
+
+    fn request(source: &str, body: &[u8], expects_response: Option<u64>) -> Message {
+        Message::Request {
+            source: source.parse().unwrap(),
+            expects_response,
+            body: body.to_vec(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    const UPDATER: &str = "tester.os@updater:app:sys";
+
+    fn responses(host: &MockHost) -> Vec<ShutdownResponse> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendResponse { response, .. } => {
+                    Some(serde_json::from_slice(&response.body).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_flag_and_ack() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let ran = Rc::new(Cell::new(0));
+        let counter = ran.clone();
+        install(move |budget| {
+            counter.set(counter.get() + 1);
+            assert_eq!(budget.remaining_secs(), DEFAULT_TIMEOUT_SECS);
+            Ok(())
+        });
+        let work = request("friend.os@chat:chat:template.os", b"work", Some(5));
+        let shutdown = request(UPDATER, SHUTDOWN_BODY, Some(5));
+
+        // a shutdown from another node is ordinary work
+        let remote = request("friend.os@updater:app:sys", SHUTDOWN_BODY, Some(5));
+        assert!(!is_shutdown_request(&remote));
+        assert!(!handle_with(&remote, || {}, clock).unwrap());
+        assert!(!handle_with(&work, || {}, clock).unwrap());
+        assert!(!is_shutting_down());
+
+        let saved = Cell::new(false);
+        assert!(handle_with(&shutdown, || saved.set(true), clock).unwrap());
+        assert!(is_shutting_down() && saved.get());
+        assert_eq!(ran.get(), 1);
+        let ready = ShutdownResponse::Ready {
+            error: None,
+            timed_out: false,
+        };
+        assert_eq!(responses(&host), std::slice::from_ref(&ready));
+
+        // new work is refused, and answered only if it wants an answer
+        assert!(handle_with(&work, || {}, clock).unwrap());
+        let no_answer = request("friend.os@chat:chat:template.os", b"work", None);
+        assert!(handle_with(&no_answer, || {}, clock).unwrap());
+        assert_eq!(responses(&host), [ShutdownResponse::ShuttingDown]);
+        // responses still get through
+        let response = Message::Response {
+            source: UPDATER.parse().unwrap(),
+            body: vec![],
+            metadata: None,
+            context: None,
+            capabilities: vec![],
+        };
+        assert!(!handle_with(&response, || {}, clock).unwrap());
+
+        // a repeated shutdown request gets the same ack without rerunning
+        assert!(handle_with(&shutdown, || panic!("saved twice"), clock).unwrap());
+        assert_eq!(ran.get(), 1);
+        assert_eq!(responses(&host), [ready]);
+    }
+
+    #[test]
+    fn test_ack_shape_with_failure_and_timeout() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        CLOCK_MS.set(1_000);
+        install_with_timeout(
+            |budget| {
+                assert_eq!(budget.remaining_ms(), 2_000);
+                CLOCK_MS.set(3_500);
+                assert!(budget.expired());
+                assert_eq!(budget.remaining_secs(), 1);
+                anyhow::bail!("subscribers unreachable")
+            },
+            2,
+        );
+        handle_with(&request(UPDATER, SHUTDOWN_BODY, Some(5)), || {}, clock).unwrap();
+        let [ready] = &responses(&host)[..] else {
+            panic!("not answered once");
+        };
+        assert_eq!(
+            serde_json::to_value(ready).unwrap(),
+            serde_json::json!({
+                "Ready": {"error": "subscribers unreachable", "timed_out": true}
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(ShutdownResponse::ShuttingDown).unwrap(),
+            "ShuttingDown"
+        );
+    }
+}
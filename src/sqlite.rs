@@ -84,6 +84,16 @@ pub enum SqliteAction {
     /// A successful commit will respond with [`SqliteResponse::Ok`]. Any error will be
     /// contained in the [`SqliteResponse::Err`] variant.
     Commit { tx_id: u64 },
+    /// Writes a consistent copy of the database to `path` on the vfs, replacing
+    /// any file there. Runtimes that predate this action respond with
+    /// [`SqliteError::MalformedRequest`]; [`backup()`] falls back to a dump then.
+    ///
+    /// Using this action requires the sender to have the read capability
+    /// for the database.
+    ///
+    /// A successful backup will respond with [`SqliteResponse::Ok`]. Any error will be
+    /// contained in the [`SqliteResponse::Err`] variant.
+    Backup { path: String },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -185,7 +195,7 @@ impl Sqlite {
         params: Vec<serde_json::Value>,
     ) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>> {
         let res = Request::new()
-            .target(("our", "sqlite", "distro", "sys"))
+            .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
             .body(serde_json::to_vec(&SqliteRequest {
                 package_id: self.package_id.clone(),
                 db: self.db.clone(),
@@ -226,7 +236,7 @@ impl Sqlite {
         tx_id: Option<u64>,
     ) -> anyhow::Result<()> {
         let res = Request::new()
-            .target(("our", "sqlite", "distro", "sys"))
+            .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
             .body(serde_json::to_vec(&SqliteRequest {
                 package_id: self.package_id.clone(),
                 db: self.db.clone(),
@@ -255,7 +265,7 @@ impl Sqlite {
     /// Begin a transaction.
     pub fn begin_tx(&self) -> anyhow::Result<u64> {
         let res = Request::new()
-            .target(("our", "sqlite", "distro", "sys"))
+            .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
             .body(serde_json::to_vec(&SqliteRequest {
                 package_id: self.package_id.clone(),
                 db: self.db.clone(),
@@ -283,7 +293,7 @@ impl Sqlite {
     /// Commit a transaction.
     pub fn commit_tx(&self, tx_id: u64) -> anyhow::Result<()> {
         let res = Request::new()
-            .target(("our", "sqlite", "distro", "sys"))
+            .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
             .body(serde_json::to_vec(&SqliteRequest {
                 package_id: self.package_id.clone(),
                 db: self.db.clone(),
@@ -309,12 +319,379 @@ impl Sqlite {
     }
 }
 
+impl Sqlite {
+    /// Begin a transaction. Statements executed through the returned [`Tx`] are
+    /// applied together by [`Tx::commit()`], or not at all.
+    pub fn begin(&self) -> anyhow::Result<Tx<'_>> {
+        Ok(Tx {
+            db: self,
+            tx_id: self.begin_tx()?,
+        })
+    }
+}
+
+/// A transaction on a [`Sqlite`] database, created with [`Sqlite::begin()`].
+///
+/// `sqlite:distro:sys` queues statements until commit and has no rollback action:
+/// dropping a `Tx` (or calling [`Tx::rollback()`]) simply never commits it.
+/// Because statements are queued, queries do not see the transaction's own writes.
+/// Transactions do not nest: transaction control statements such as `BEGIN` or
+/// `SAVEPOINT` are rejected by [`Tx::execute()`].
+#[derive(Debug)]
+pub struct Tx<'a> {
+    db: &'a Sqlite,
+    tx_id: u64,
+}
+
+impl Tx<'_> {
+    pub fn tx_id(&self) -> u64 {
+        self.tx_id
+    }
+
+    /// Execute a write statement as part of the transaction.
+    pub fn execute(&self, statement: String, params: Vec<serde_json::Value>) -> anyhow::Result<()> {
+        check_not_transaction_control(&statement)?;
+        self.db.write(statement, params, Some(self.tx_id))
+    }
+
+    /// Query the database. See [`Tx`] for what the query can see.
+    pub fn query(
+        &self,
+        query: String,
+        params: Vec<serde_json::Value>,
+    ) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>> {
+        self.db.read(query, params)
+    }
+
+    pub fn commit(self) -> anyhow::Result<()> {
+        self.db.commit_tx(self.tx_id)
+    }
+
+    /// Abandon the transaction.
+    pub fn rollback(self) {}
+}
+
+/// Reject statements that would start, end, or nest a transaction, since they
+/// would fight with the runtime's own transaction handling.
+fn check_not_transaction_control(statement: &str) -> anyhow::Result<()> {
+    let keyword = statement
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(';')
+        .to_ascii_uppercase();
+    match keyword.as_str() {
+        "BEGIN" | "COMMIT" | "END" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" => Err(anyhow::anyhow!(
+            "sqlite: {keyword} is not allowed inside a transaction; transactions do not nest"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A migration passed to [`migrate()`] that failed. Its transaction was not committed,
+/// so none of the migrations in that run were applied.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("sqlite migration {index} failed: {error}")]
+pub struct MigrationError {
+    /// Index of the failing statement in the migrations slice.
+    pub index: usize,
+    pub error: String,
+}
+
+/// Bring db up to date with migrations, an append-only list of write statements.
+///
+/// Applied versions (indices into migrations) are recorded in a `_migrations` table.
+/// Unapplied migrations run in order in a single transaction, and the versions
+/// that ran are returned. If one fails, nothing is committed and a
+/// [`MigrationError`] is returned.
+pub fn migrate(db: &Sqlite, migrations: &[&str]) -> anyhow::Result<Vec<usize>> {
+    migrate_with(db, migrations, now_secs())
+}
+
+/// The operations [`migrate()`] needs, so they can be mocked.
+trait MigrationTransport {
+    fn read(&self, query: &str) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>>;
+    fn write(
+        &self,
+        statement: &str,
+        params: Vec<serde_json::Value>,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<()>;
+    fn begin_tx(&self) -> anyhow::Result<u64>;
+    fn commit_tx(&self, tx_id: u64) -> anyhow::Result<()>;
+}
+
+impl MigrationTransport for Sqlite {
+    fn read(&self, query: &str) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>> {
+        Sqlite::read(self, query.to_string(), vec![])
+    }
+
+    fn write(
+        &self,
+        statement: &str,
+        params: Vec<serde_json::Value>,
+        tx_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        Sqlite::write(self, statement.to_string(), params, tx_id)
+    }
+
+    fn begin_tx(&self) -> anyhow::Result<u64> {
+        Sqlite::begin_tx(self)
+    }
+
+    fn commit_tx(&self, tx_id: u64) -> anyhow::Result<()> {
+        Sqlite::commit_tx(self, tx_id)
+    }
+}
+
+fn migrate_with<T: MigrationTransport>(
+    db: &T,
+    migrations: &[&str],
+    now: u64,
+) -> anyhow::Result<Vec<usize>> {
+    db.write(
+        "CREATE TABLE IF NOT EXISTS _migrations (version INTEGER PRIMARY KEY, applied_at INTEGER NOT NULL)",
+        vec![],
+        None,
+    )?;
+    let applied: Vec<u64> = db
+        .read("SELECT version FROM _migrations")?
+        .iter()
+        .filter_map(|row| row.get("version")?.as_u64())
+        .collect();
+    let pending = pending_migrations(&applied, migrations.len())?;
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+
+    let tx_id = db.begin_tx()?;
+    for &index in &pending {
+        let result = check_not_transaction_control(migrations[index])
+            .and_then(|_| db.write(migrations[index], vec![], Some(tx_id)));
+        if let Err(e) = result {
+            return Err(MigrationError {
+                index,
+                error: sqlite_error_text(&e),
+            }
+            .into());
+        }
+        db.write(
+            "INSERT INTO _migrations (version, applied_at) VALUES (?, ?)",
+            vec![index.into(), now.into()],
+            Some(tx_id),
+        )?;
+    }
+    // the runtime only runs queued statements on commit, so that is where they fail
+    if let Err(e) = db.commit_tx(tx_id) {
+        return Err(MigrationError {
+            index: pending[0],
+            error: sqlite_error_text(&e),
+        }
+        .into());
+    }
+    Ok(pending)
+}
+
+/// Indices of the migrations not yet applied, in order. Fails if the database has
+/// applied migrations that this list doesn't have, e.g. after a downgrade.
+fn pending_migrations(applied: &[u64], count: usize) -> anyhow::Result<Vec<usize>> {
+    if let Some(unknown) = applied.iter().find(|&&version| version >= count as u64) {
+        return Err(anyhow::anyhow!(
+            "sqlite: database has migration {unknown} applied, but only {count} migrations are known"
+        ));
+    }
+    Ok((0..count)
+        .filter(|&index| !applied.contains(&(index as u64)))
+        .collect())
+}
+
+/// The sqlite error message inside e, without our own framing.
+fn sqlite_error_text(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<SqliteError>() {
+        Some(SqliteError::RusqliteError(text)) => text.clone(),
+        _ => e.to_string(),
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// How many rows [`backup()`] reads per query when dumping a table.
+pub const DUMP_PAGE_ROWS: usize = 500;
+
+/// Back db up to the vfs file at dest_path, replacing it. Uses the runtime's
+/// [`SqliteAction::Backup`] if it has it, and otherwise writes a dump: the SQL
+/// statements that recreate db's tables, their rows, and then its indexes,
+/// triggers and views, wrapped in one transaction, as the `sqlite3` shell's
+/// `.dump` would.
+pub fn backup(db: &Sqlite, dest_path: &str) -> anyhow::Result<()> {
+    let res = Request::new()
+        .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
+        .body(serde_json::to_vec(&SqliteRequest {
+            package_id: db.package_id.clone(),
+            db: db.db.clone(),
+            action: SqliteAction::Backup {
+                path: dest_path.to_string(),
+            },
+        })?)
+        .send_and_await_response(db.timeout)?;
+
+    match res {
+        Ok(Message::Response { body, .. }) => {
+            let response = serde_json::from_slice::<SqliteResponse>(&body)?;
+
+            match response {
+                SqliteResponse::Ok => Ok(()),
+                SqliteResponse::Err(SqliteError::MalformedRequest) => {
+                    let mut file = crate::vfs::create_file(dest_path, Some(db.timeout))?;
+                    dump_with(db, DUMP_PAGE_ROWS, |chunk| {
+                        Ok(file.append(chunk.as_bytes())?)
+                    })
+                }
+                SqliteResponse::Err(error) => Err(error.into()),
+                _ => Err(anyhow::anyhow!(
+                    "sqlite: unexpected response {:?}",
+                    response
+                )),
+            }
+        }
+        _ => Err(anyhow::anyhow!("sqlite: unexpected message: {:?}", res)),
+    }
+}
+
+/// Write the dump of db described in [`backup()`] through append, a table
+/// page of page_rows rows at a time.
+fn dump_with<T, A>(db: &T, page_rows: usize, mut append: A) -> anyhow::Result<()>
+where
+    T: MigrationTransport,
+    A: FnMut(&str) -> anyhow::Result<()>,
+{
+    let schema = db.read(
+        "SELECT type, name, sql FROM sqlite_master \
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' ORDER BY rowid",
+    )?;
+    let field = |row: &HashMap<String, serde_json::Value>, name: &str| {
+        row.get(name)
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    append("BEGIN TRANSACTION;\n")?;
+    let tables: Vec<_> = schema
+        .iter()
+        .filter(|row| field(row, "type") == "table")
+        .collect();
+    for table in &tables {
+        append(&format!("{};\n", field(table, "sql")))?;
+    }
+    for table in &tables {
+        let name = field(table, "name");
+        let order = dump_order(db, &name, &field(table, "sql"))?;
+        let mut offset = 0;
+        loop {
+            let rows = db.read(&format!(
+                "SELECT * FROM {} ORDER BY {order} LIMIT {page_rows} OFFSET {offset}",
+                quote_identifier(&name)
+            ))?;
+            let mut chunk = String::new();
+            for row in &rows {
+                chunk.push_str(&insert_statement(&name, row));
+                chunk.push('\n');
+            }
+            if !chunk.is_empty() {
+                append(&chunk)?;
+            }
+            if rows.len() < page_rows {
+                break;
+            }
+            offset += rows.len();
+        }
+    }
+    for other in schema.iter().filter(|row| field(row, "type") != "table") {
+        append(&format!("{};\n", field(other, "sql")))?;
+    }
+    append("COMMIT;\n")
+}
+
+/// What to order the rows of table by, so that its pages neither skip nor
+/// repeat rows: its rowid, or for a `WITHOUT ROWID` table, its primary key.
+fn dump_order<T: MigrationTransport>(db: &T, table: &str, sql: &str) -> anyhow::Result<String> {
+    if !sql.to_ascii_uppercase().contains("WITHOUT ROWID") {
+        return Ok("rowid".to_string());
+    }
+    let key = db.read(&format!(
+        "SELECT name FROM pragma_table_info({}) WHERE pk > 0 ORDER BY pk",
+        sql_literal(&serde_json::Value::String(table.to_string()))
+    ))?;
+    let columns: Vec<String> = key
+        .iter()
+        .filter_map(|row| row.get("name")?.as_str().map(quote_identifier))
+        .collect();
+    if columns.is_empty() {
+        return Err(anyhow::anyhow!(
+            "sqlite: no primary key found for WITHOUT ROWID table {table}"
+        ));
+    }
+    Ok(columns.join(", "))
+}
+
+/// The statement inserting row, as returned by [`Sqlite::read()`], into table.
+fn insert_statement(table: &str, row: &HashMap<String, serde_json::Value>) -> String {
+    // sorted, so that dumps of the same rows are the same
+    let mut columns: Vec<_> = row.iter().collect();
+    columns.sort_by(|a, b| a.0.cmp(b.0));
+    let names: Vec<String> = columns
+        .iter()
+        .map(|(name, _)| quote_identifier(name))
+        .collect();
+    let values: Vec<String> = columns
+        .iter()
+        .map(|(_, value)| sql_literal(value))
+        .collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES ({});",
+        quote_identifier(table),
+        names.join(", "),
+        values.join(", ")
+    )
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// value as a SQL literal. Blobs come back from the runtime as arrays of bytes.
+fn sql_literal(value: &serde_json::Value) -> String {
+    use serde_json::Value;
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Bool(b) => (*b as u8).to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Array(bytes) => {
+            let hex: Option<String> = bytes
+                .iter()
+                .map(|byte| Some(format!("{:02X}", u8::try_from(byte.as_u64()?).ok()?)))
+                .collect();
+            match hex {
+                Some(hex) => format!("X'{hex}'"),
+                None => sql_literal(&Value::String(value.to_string())),
+            }
+        }
+        Value::Object(_) => sql_literal(&Value::String(value.to_string())),
+    }
+}
+
 /// Open or create sqlite database.
 pub fn open(package_id: PackageId, db: &str, timeout: Option<u64>) -> anyhow::Result<Sqlite> {
     let timeout = timeout.unwrap_or(5);
 
     let res = Request::new()
-        .target(("our", "sqlite", "distro", "sys"))
+        .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
         .body(serde_json::to_vec(&SqliteRequest {
             package_id: package_id.clone(),
             db: db.to_string(),
@@ -348,7 +725,7 @@ pub fn remove_db(package_id: PackageId, db: &str, timeout: Option<u64>) -> anyho
     let timeout = timeout.unwrap_or(5);
 
     let res = Request::new()
-        .target(("our", "sqlite", "distro", "sys"))
+        .target(crate::SystemProcess::Sqlite.address(crate::our_node()))
         .body(serde_json::to_vec(&SqliteRequest {
             package_id: package_id.clone(),
             db: db.to_string(),
@@ -372,3 +749,266 @@ pub fn remove_db(package_id: PackageId, db: &str, timeout: Option<u64>) -> anyho
         _ => Err(anyhow::anyhow!("sqlite: unexpected message: {:?}", res)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records writes and fails any statement containing `fail_on`.
+    #[derive(Default)]
+    struct MockDb {
+        applied: Vec<u64>,
+        fail_on: Option<&'static str>,
+        fail_commit: bool,
+        log: RefCell<Vec<String>>,
+    }
+
+    impl MigrationTransport for MockDb {
+        fn read(&self, query: &str) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>> {
+            self.log.borrow_mut().push(format!("read {query}"));
+            Ok(self
+                .applied
+                .iter()
+                .map(|&v| HashMap::from([("version".to_string(), v.into())]))
+                .collect())
+        }
+
+        fn write(
+            &self,
+            statement: &str,
+            params: Vec<serde_json::Value>,
+            tx_id: Option<u64>,
+        ) -> anyhow::Result<()> {
+            if self.fail_on.is_some_and(|fail| statement.contains(fail)) {
+                return Err(
+                    SqliteError::RusqliteError("near \"TABEL\": syntax error".into()).into(),
+                );
+            }
+            let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+            self.log
+                .borrow_mut()
+                .push(format!("write {tx_id:?} {statement} {}", params.join(",")));
+            Ok(())
+        }
+
+        fn begin_tx(&self) -> anyhow::Result<u64> {
+            self.log.borrow_mut().push("begin".to_string());
+            Ok(7)
+        }
+
+        fn commit_tx(&self, tx_id: u64) -> anyhow::Result<()> {
+            if self.fail_commit {
+                return Err(SqliteError::RusqliteError("UNIQUE constraint failed".into()).into());
+            }
+            self.log.borrow_mut().push(format!("commit {tx_id}"));
+            Ok(())
+        }
+    }
+
+    const MIGRATIONS: [&str; 3] = [
+        "CREATE TABLE users (id INTEGER PRIMARY KEY)",
+        "ALTER TABLE users ADD COLUMN name TEXT",
+        "CREATE INDEX users_name ON users (name)",
+    ];
+
+    #[test]
+    fn test_migrate_applies_pending_in_order() {
+        let db = MockDb {
+            applied: vec![0],
+            ..Default::default()
+        };
+        assert_eq!(migrate_with(&db, &MIGRATIONS, 100).unwrap(), vec![1, 2]);
+        let log = db.log.into_inner();
+        assert!(log[0].contains("CREATE TABLE IF NOT EXISTS _migrations"));
+        assert_eq!(log[1], "read SELECT version FROM _migrations");
+        assert_eq!(
+            log[2..],
+            [
+                "begin".to_string(),
+                format!("write Some(7) {} ", MIGRATIONS[1]),
+                "write Some(7) INSERT INTO _migrations (version, applied_at) VALUES (?, ?) 1,100"
+                    .to_string(),
+                format!("write Some(7) {} ", MIGRATIONS[2]),
+                "write Some(7) INSERT INTO _migrations (version, applied_at) VALUES (?, ?) 2,100"
+                    .to_string(),
+                "commit 7".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_up_to_date() {
+        let db = MockDb {
+            applied: vec![2, 0, 1],
+            ..Default::default()
+        };
+        assert!(migrate_with(&db, &MIGRATIONS, 0).unwrap().is_empty());
+        // no transaction is started
+        assert!(!db.log.borrow().iter().any(|line| line == "begin"));
+
+        let downgraded = MockDb {
+            applied: vec![0, 1, 2, 3],
+            ..Default::default()
+        };
+        assert!(migrate_with(&downgraded, &MIGRATIONS, 0).is_err());
+    }
+
+    #[test]
+    fn test_migrate_failure_reports_index() {
+        let db = MockDb {
+            fail_on: Some("ADD COLUMN"),
+            ..Default::default()
+        };
+        let error = migrate_with(&db, &MIGRATIONS, 0).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<MigrationError>(),
+            Some(&MigrationError {
+                index: 1,
+                error: "near \"TABEL\": syntax error".to_string()
+            })
+        );
+        // nothing was committed
+        assert!(!db
+            .log
+            .borrow()
+            .iter()
+            .any(|line| line.starts_with("commit")));
+
+        let db = MockDb {
+            fail_commit: true,
+            ..Default::default()
+        };
+        let error = migrate_with(&db, &MIGRATIONS, 0).unwrap_err();
+        let error = error.downcast_ref::<MigrationError>().unwrap();
+        assert_eq!(error.index, 0);
+        assert_eq!(error.error, "UNIQUE constraint failed");
+    }
+
+    #[test]
+    fn test_nested_transactions_rejected() {
+        for statement in [
+            "BEGIN",
+            "begin transaction",
+            "COMMIT;",
+            "END",
+            "  ROLLBACK TO sp",
+            "SAVEPOINT sp",
+            "release sp",
+        ] {
+            assert!(
+                check_not_transaction_control(statement).is_err(),
+                "{statement}"
+            );
+        }
+        for statement in ["INSERT INTO t VALUES (1)", "UPDATE begins SET x = 1", ""] {
+            assert!(check_not_transaction_control(statement).is_ok());
+        }
+
+        let db = MockDb::default();
+        let error = migrate_with(&db, &["CREATE TABLE a (x)", "BEGIN"], 0).unwrap_err();
+        assert_eq!(error.downcast_ref::<MigrationError>().unwrap().index, 1);
+    }
+
+    /// Two tables of a few rows each, one `WITHOUT ROWID`, handed out a page
+    /// of two rows at a time.
+    #[derive(Default)]
+    struct DumpDb {
+        queries: RefCell<Vec<String>>,
+    }
+
+    impl MigrationTransport for DumpDb {
+        fn read(&self, query: &str) -> anyhow::Result<Vec<HashMap<String, serde_json::Value>>> {
+            use serde_json::json;
+            self.queries.borrow_mut().push(query.to_string());
+            let row = |pairs: &[(&str, serde_json::Value)]| -> HashMap<String, serde_json::Value> {
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect()
+            };
+            Ok(if query.contains("sqlite_master") {
+                vec![
+                    row(&[
+                        ("type", json!("table")),
+                        ("name", json!("users")),
+                        ("sql", json!("CREATE TABLE users (id INTEGER PRIMARY KEY)")),
+                    ]),
+                    row(&[
+                        ("type", json!("table")),
+                        ("name", json!("tags")),
+                        (
+                            "sql",
+                            json!("CREATE TABLE tags (a, b, PRIMARY KEY (b, a)) without rowid"),
+                        ),
+                    ]),
+                ]
+            } else if query.contains("pragma_table_info('tags')") {
+                vec![row(&[("name", json!("b"))]), row(&[("name", json!("a"))])]
+            } else if query.contains("OFFSET 0") {
+                vec![row(&[("id", json!(1))]), row(&[("id", json!(2))])]
+            } else {
+                vec![row(&[("id", json!(3))])]
+            })
+        }
+
+        fn write(&self, _: &str, _: Vec<serde_json::Value>, _: Option<u64>) -> anyhow::Result<()> {
+            unreachable!("dumps only read")
+        }
+
+        fn begin_tx(&self) -> anyhow::Result<u64> {
+            unreachable!("dumps only read")
+        }
+
+        fn commit_tx(&self, _: u64) -> anyhow::Result<()> {
+            unreachable!("dumps only read")
+        }
+    }
+
+    #[test]
+    fn test_dump_pages_in_a_stable_order() {
+        let db = DumpDb::default();
+        let mut dump = String::new();
+        dump_with(&db, 2, |chunk| {
+            dump.push_str(chunk);
+            Ok(())
+        })
+        .unwrap();
+        let pages: Vec<String> = db
+            .queries
+            .into_inner()
+            .into_iter()
+            .filter(|query| query.starts_with("SELECT * FROM"))
+            .collect();
+        assert_eq!(
+            pages,
+            vec![
+                "SELECT * FROM \"users\" ORDER BY rowid LIMIT 2 OFFSET 0",
+                "SELECT * FROM \"users\" ORDER BY rowid LIMIT 2 OFFSET 2",
+                "SELECT * FROM \"tags\" ORDER BY \"b\", \"a\" LIMIT 2 OFFSET 0",
+                "SELECT * FROM \"tags\" ORDER BY \"b\", \"a\" LIMIT 2 OFFSET 2",
+            ]
+        );
+        assert_eq!(dump.matches("INSERT INTO \"users\"").count(), 3);
+        assert!(dump.ends_with("COMMIT;\n"));
+    }
+
+    #[test]
+    fn test_dump_renders_literals() {
+        use serde_json::json;
+        assert_eq!(sql_literal(&json!(null)), "NULL");
+        assert_eq!(sql_literal(&json!(true)), "1");
+        assert_eq!(sql_literal(&json!(-2.5)), "-2.5");
+        assert_eq!(sql_literal(&json!("it's")), "'it''s'");
+        assert_eq!(sql_literal(&json!([0, 171, 255])), "X'00ABFF'");
+        assert_eq!(sql_literal(&json!([1, 256])), "'[1,256]'");
+        let row = HashMap::from([
+            ("name".to_string(), json!("o'brien")),
+            ("id".to_string(), json!(3)),
+        ]);
+        assert_eq!(
+            insert_statement("my \"users\"", &row),
+            "INSERT INTO \"my \"\"users\"\"\" (\"id\", \"name\") VALUES (3, 'o''brien');"
+        );
+    }
+}
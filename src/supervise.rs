@@ -0,0 +1,644 @@
+use crate::timer::wall_clock_ms;
+use crate::{Address, Capability, Message, OnExit, ProcessId, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Whether a child is restarted when it exits.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Always,
+    /// Unless it announced a clean exit with [`report_done()`] first.
+    #[default]
+    OnFailure,
+    Never,
+}
+
+/// A child process to start and keep running.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChildSpec {
+    /// The process name, kept across restarts.
+    pub name: String,
+    pub wasm_path: String,
+    pub capabilities: Vec<Capability>,
+    pub public: bool,
+    pub policy: RestartPolicy,
+}
+
+impl ChildSpec {
+    pub fn new(name: &str, wasm_path: &str) -> Self {
+        ChildSpec {
+            name: name.to_string(),
+            wasm_path: wasm_path.to_string(),
+            capabilities: vec![],
+            public: false,
+            policy: RestartPolicy::default(),
+        }
+    }
+
+    pub fn capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn public(mut self, public: bool) -> Self {
+        self.public = public;
+        self
+    }
+
+    pub fn policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+/// How soon children are restarted, and when to stop trying.
+///
+/// The nth restart within `window_ms` waits `initial_delay_ms * multiplier^(n - 1)`,
+/// at most `max_delay_ms`, so a child that keeps crashing is restarted ever more
+/// slowly, while one that ran for a whole window starts over at `initial_delay_ms`.
+/// With an `initial_delay_ms` of 0, children are restarted at once, without a timer.
+/// The supervisor gives up on a child once it has been restarted `max_restarts`
+/// times within `window_ms`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SupervisorConfig {
+    pub initial_delay_ms: u64,
+    pub multiplier: u32,
+    pub max_delay_ms: u64,
+    pub max_restarts: u32,
+    pub window_ms: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            initial_delay_ms: 1000,
+            multiplier: 2,
+            max_delay_ms: 30 * 1000,
+            max_restarts: 5,
+            window_ms: 60 * 1000,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    /// How long to wait before a restart, given how many restarts of the same
+    /// child happened within the window before it.
+    pub fn delay_ms(&self, recent_restarts: u32) -> u64 {
+        let factor = (self.multiplier as u64).saturating_pow(recent_restarts);
+        self.initial_delay_ms
+            .saturating_mul(factor)
+            .min(self.max_delay_ms)
+    }
+}
+
+/// What [`Supervisor::handle()`] made of a message about a child.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SupervisionEvent {
+    /// The child exited and, per its policy, stays down.
+    Exited {
+        name: String,
+        clean: bool,
+    },
+    /// The child exited and will be restarted at this time, in ms since the Unix
+    /// epoch, when a timer fires.
+    RestartScheduled {
+        name: String,
+        at_ms: u64,
+    },
+    Restarted {
+        name: String,
+        process: ProcessId,
+    },
+    RestartFailed {
+        name: String,
+        error: String,
+    },
+    /// The child was restarted too often within the window: it stays down until
+    /// started again with [`Supervisor::start()`].
+    GaveUp {
+        name: String,
+        restarts: u32,
+    },
+}
+
+/// Sent to the supervisor by a child, as JSON Request bodies.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SupervisorRequest {
+    /// The child is about to exit on purpose, see [`report_done()`].
+    Done,
+    /// Sent on the child's behalf by the runtime when it exits, as its [`OnExit`].
+    Exited { name: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct RestartTimer {
+    supervise_restart: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum ChildState {
+    Running,
+    /// Running, and announced it is about to exit cleanly.
+    Done,
+    Waiting {
+        restart_at_ms: u64,
+    },
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct Child {
+    spec: ChildSpec,
+    process: ProcessId,
+    state: ChildState,
+    /// When the child was restarted, within the last window.
+    restarts_ms: Vec<u64>,
+}
+
+/// What the supervisor needs from the runtime, so that it can be exercised without one.
+trait Runtime {
+    fn spawn(&mut self, spec: &ChildSpec, on_exit: OnExit) -> anyhow::Result<ProcessId>;
+    fn set_timer(&mut self, delay_ms: u64, name: &str) -> anyhow::Result<()>;
+}
+
+struct Kernel;
+
+impl Runtime for Kernel {
+    fn spawn(&mut self, spec: &ChildSpec, on_exit: OnExit) -> anyhow::Result<ProcessId> {
+        crate::spawn(
+            Some(&spec.name),
+            &spec.wasm_path,
+            on_exit,
+            spec.capabilities.clone(),
+            vec![],
+            spec.public,
+        )
+        .map_err(|e| anyhow::anyhow!("failed to spawn {}: {e:?}", spec.name))
+    }
+
+    fn set_timer(&mut self, delay_ms: u64, name: &str) -> anyhow::Result<()> {
+        let context = serde_json::to_vec(&RestartTimer {
+            supervise_restart: name.to_string(),
+        })?;
+        Ok(crate::timer::set_timer(delay_ms, Some(context))?)
+    }
+}
+
+/// Child processes restarted when they exit, per their [`RestartPolicy`].
+///
+/// Each child is spawned with an [`OnExit`] that sends the supervisor a
+/// [`SupervisorRequest::Exited`] when it exits, for any reason. Restarts back
+/// off and are limited as [`SupervisorConfig`] says, waiting on a timer whose
+/// response [`Supervisor::handle()`] also consumes. A `Supervisor` serializes,
+/// so a process that keeps it in its state goes on supervising after a restart.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, supervise::{ChildSpec, Supervisor}};
+///
+/// let mut supervisor = Supervisor::new();
+/// supervisor
+///     .start(ChildSpec::new("indexer", "/my-app:publisher.os/pkg/indexer.wasm"))
+///     .unwrap();
+/// loop {
+///     let Ok(message) = await_message() else { continue };
+///     if let Some(event) = supervisor.handle(&message) {
+///         println!("{event:?}");
+///         continue;
+///     }
+///     // other messages
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Supervisor {
+    config: SupervisorConfig,
+    children: BTreeMap<String, Child>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Supervisor::default()
+    }
+
+    pub fn config(mut self, config: SupervisorConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Spawn the child spec describes and supervise it, replacing a child of the
+    /// same name, e.g. one given up on.
+    pub fn start(&mut self, spec: ChildSpec) -> anyhow::Result<ProcessId> {
+        self.start_with(&mut Kernel, spec)
+    }
+
+    /// Stop supervising the child named name: it is not restarted when it exits.
+    /// Returns whether there was one.
+    pub fn forget(&mut self, name: &str) -> bool {
+        self.children.remove(name).is_some()
+    }
+
+    /// If message is an exit notice from a child, or a restart timer firing,
+    /// act on it and say what happened.
+    pub fn handle(&mut self, message: &Message) -> Option<SupervisionEvent> {
+        if let Ok(Some(timer)) = crate::timer::context_as::<RestartTimer>(message) {
+            return self.restart_due_with(&mut Kernel, &timer.supervise_restart, wall_clock_ms());
+        }
+        self.handle_with(&mut Kernel, message, wall_clock_ms())
+    }
+
+    /// The supervised children by name, and the processes they run as.
+    pub fn children(&self) -> impl Iterator<Item = (&str, &ProcessId)> {
+        self.children
+            .iter()
+            .map(|(name, child)| (name.as_str(), &child.process))
+    }
+
+    fn start_with<R: Runtime>(
+        &mut self,
+        runtime: &mut R,
+        spec: ChildSpec,
+    ) -> anyhow::Result<ProcessId> {
+        let process = runtime.spawn(&spec, exit_notice(&spec.name)?)?;
+        self.children.insert(
+            spec.name.clone(),
+            Child {
+                spec,
+                process: process.clone(),
+                state: ChildState::Running,
+                restarts_ms: vec![],
+            },
+        );
+        Ok(process)
+    }
+
+    fn handle_with<R: Runtime>(
+        &mut self,
+        runtime: &mut R,
+        message: &Message,
+        now_ms: u64,
+    ) -> Option<SupervisionEvent> {
+        let Message::Request { source, body, .. } = message else {
+            return None;
+        };
+        let request = serde_json::from_slice::<SupervisorRequest>(body).ok()?;
+        let name = match &request {
+            SupervisorRequest::Exited { name } => name.clone(),
+            SupervisorRequest::Done => self
+                .children
+                .iter()
+                .find(|(_, child)| child.process == source.process)?
+                .0
+                .clone(),
+        };
+        let child = self.children.get_mut(&name)?;
+        // only the child itself, or the runtime on its behalf, speaks for it
+        if source.process != child.process || child.state == ChildState::Stopped {
+            return None;
+        }
+        if request == SupervisorRequest::Done {
+            child.state = ChildState::Done;
+            return None;
+        }
+        let clean = child.state == ChildState::Done;
+        let restart = match child.spec.policy {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !clean,
+            RestartPolicy::Never => false,
+        };
+        if !restart {
+            child.state = ChildState::Stopped;
+            return Some(SupervisionEvent::Exited { name, clean });
+        }
+        let window_start_ms = now_ms.saturating_sub(self.config.window_ms);
+        child.restarts_ms.retain(|at_ms| *at_ms > window_start_ms);
+        let restarts = child.restarts_ms.len() as u32;
+        if restarts >= self.config.max_restarts {
+            child.state = ChildState::Stopped;
+            return Some(SupervisionEvent::GaveUp { name, restarts });
+        }
+        let delay_ms = self.config.delay_ms(restarts);
+        let restart_at_ms = now_ms.saturating_add(delay_ms);
+        child.state = ChildState::Waiting { restart_at_ms };
+        if delay_ms == 0 {
+            return self.restart_due_with(runtime, &name, now_ms);
+        }
+        if let Err(e) = runtime.set_timer(delay_ms, &name) {
+            child.state = ChildState::Stopped;
+            return Some(SupervisionEvent::RestartFailed {
+                name,
+                error: e.to_string(),
+            });
+        }
+        Some(SupervisionEvent::RestartScheduled {
+            name,
+            at_ms: restart_at_ms,
+        })
+    }
+
+    fn restart_due_with<R: Runtime>(
+        &mut self,
+        runtime: &mut R,
+        name: &str,
+        now_ms: u64,
+    ) -> Option<SupervisionEvent> {
+        let child = self.children.get_mut(name)?;
+        // a timer from before the child was started again, or forgotten and re-added
+        let ChildState::Waiting { restart_at_ms } = child.state else {
+            return None;
+        };
+        if restart_at_ms > now_ms {
+            return None;
+        }
+        child.restarts_ms.push(now_ms);
+        let name = name.to_string();
+        let spawned = exit_notice(&name).and_then(|on_exit| runtime.spawn(&child.spec, on_exit));
+        Some(match spawned {
+            Ok(process) => {
+                child.process = process.clone();
+                child.state = ChildState::Running;
+                SupervisionEvent::Restarted { name, process }
+            }
+            Err(e) => {
+                child.state = ChildState::Stopped;
+                SupervisionEvent::RestartFailed {
+                    name,
+                    error: e.to_string(),
+                }
+            }
+        })
+    }
+}
+
+/// The [`OnExit`] a child is spawned with, telling us it exited.
+fn exit_notice(name: &str) -> anyhow::Result<OnExit> {
+    Ok(OnExit::Requests(vec![Request::to(crate::our()).body(
+        serde_json::to_vec(&SupervisorRequest::Exited {
+            name: name.to_string(),
+        })?,
+    )]))
+}
+
+/// Tell supervisor, from a child, that we are about to exit on purpose, so that
+/// under [`RestartPolicy::OnFailure`] we are not restarted.
+pub fn report_done(supervisor: &Address) -> anyhow::Result<()> {
+    Request::to(supervisor)
+        .body(serde_json::to_vec(&SupervisorRequest::Done)?)
+        .send()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spawns children named as asked and records timers, optionally refusing to
+    /// spawn or to set timers.
+    #[derive(Default)]
+    struct MockRuntime {
+        spawned: Vec<String>,
+        timers: Vec<(u64, String)>,
+        fail_spawns: bool,
+        fail_timers: bool,
+    }
+
+    impl Runtime for MockRuntime {
+        fn spawn(&mut self, spec: &ChildSpec, on_exit: OnExit) -> anyhow::Result<ProcessId> {
+            if self.fail_spawns {
+                return Err(anyhow::anyhow!("no more processes"));
+            }
+            assert!(on_exit.is_requests());
+            self.spawned.push(spec.name.clone());
+            Ok(ProcessId::new(Some(&spec.name), "app", "pub.os"))
+        }
+
+        fn set_timer(&mut self, delay_ms: u64, name: &str) -> anyhow::Result<()> {
+            if self.fail_timers {
+                return Err(anyhow::anyhow!("refused"));
+            }
+            self.timers.push((delay_ms, name.to_string()));
+            Ok(())
+        }
+    }
+
+    fn from_child(name: &str, request: &SupervisorRequest) -> Message {
+        Message::Request {
+            source: format!("our.os@{name}:app:pub.os").parse().unwrap(),
+            expects_response: None,
+            body: serde_json::to_vec(request).unwrap(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    fn exited(name: &str) -> Message {
+        from_child(
+            name,
+            &SupervisorRequest::Exited {
+                name: name.to_string(),
+            },
+        )
+    }
+
+    fn config() -> SupervisorConfig {
+        SupervisorConfig {
+            initial_delay_ms: 100,
+            multiplier: 2,
+            max_delay_ms: 1_000,
+            max_restarts: 5,
+            window_ms: 10_000,
+        }
+    }
+
+    fn start_worker(runtime: &mut MockRuntime, config: SupervisorConfig) -> Supervisor {
+        crate::set_our("our.os@super:app:pub.os".parse().unwrap());
+        let mut supervisor = Supervisor::new().config(config);
+        supervisor
+            .start_with(
+                runtime,
+                ChildSpec::new("worker", "/app:pub.os/pkg/worker.wasm"),
+            )
+            .unwrap();
+        supervisor
+    }
+
+    /// Crash the worker at now_ms and restart it once its timer is due, returning
+    /// the delay it waited, or the event if it was not scheduled.
+    fn crash(
+        supervisor: &mut Supervisor,
+        runtime: &mut MockRuntime,
+        now_ms: u64,
+    ) -> Result<u64, SupervisionEvent> {
+        match supervisor.handle_with(runtime, &exited("worker"), now_ms) {
+            Some(SupervisionEvent::RestartScheduled { at_ms, .. }) => {
+                assert_eq!(runtime.timers.pop().unwrap().0, at_ms - now_ms);
+                // early timers don't restart
+                if at_ms > now_ms {
+                    assert_eq!(
+                        supervisor.restart_due_with(runtime, "worker", at_ms - 1),
+                        None
+                    );
+                }
+                let event = supervisor.restart_due_with(runtime, "worker", at_ms);
+                assert!(matches!(event, Some(SupervisionEvent::Restarted { .. })));
+                Ok(at_ms - now_ms)
+            }
+            event => Err(event.unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_backoff_schedule() {
+        assert_eq!(
+            (0..6).map(|n| config().delay_ms(n)).collect::<Vec<_>>(),
+            [100, 200, 400, 800, 1_000, 1_000]
+        );
+        let mut runtime = MockRuntime::default();
+        let mut supervisor = start_worker(&mut runtime, config());
+        let delays: Vec<u64> = (0..4)
+            .map(|n| crash(&mut supervisor, &mut runtime, n * 1_000).unwrap())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800]);
+        assert_eq!(runtime.spawned, ["worker"; 5]);
+        // a child that stayed up for the whole window starts over
+        assert_eq!(crash(&mut supervisor, &mut runtime, 14_000), Ok(100));
+
+        // with no initial delay, restarts are immediate, with no timer
+        let mut supervisor = start_worker(
+            &mut runtime,
+            SupervisorConfig {
+                initial_delay_ms: 0,
+                ..config()
+            },
+        );
+        for now_ms in [0, 1, 2] {
+            assert!(matches!(
+                supervisor.handle_with(&mut runtime, &exited("worker"), now_ms),
+                Some(SupervisionEvent::Restarted { .. })
+            ));
+        }
+        assert!(runtime.timers.is_empty());
+    }
+
+    #[test]
+    fn test_circuit_breaker_window() {
+        let mut runtime = MockRuntime::default();
+        let config = SupervisorConfig {
+            max_restarts: 3,
+            ..config()
+        };
+        let mut supervisor = start_worker(&mut runtime, config);
+        // restarted at 100, 1_200 and 2_400
+        for now_ms in [0, 1_000, 2_000] {
+            crash(&mut supervisor, &mut runtime, now_ms).unwrap();
+        }
+        // three restarts within the 10s window: give up
+        assert_eq!(
+            crash(&mut supervisor, &mut runtime, 10_099),
+            Err(SupervisionEvent::GaveUp {
+                name: "worker".to_string(),
+                restarts: 3
+            })
+        );
+        // and ignore the child from then on
+        assert_eq!(
+            supervisor.handle_with(&mut runtime, &exited("worker"), 20_000),
+            None
+        );
+
+        // had it crashed once the first restart left the window, it would be retried
+        let mut supervisor = supervisor_with_restarts(&mut runtime, config, &[100, 1_200, 2_400]);
+        assert_eq!(crash(&mut supervisor, &mut runtime, 10_100), Ok(400));
+
+        // persisted state keeps the count
+        let mut supervisor = supervisor_with_restarts(&mut runtime, config, &[100, 1_200, 2_400]);
+        let json = serde_json::to_string(&supervisor).unwrap();
+        supervisor = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            crash(&mut supervisor, &mut runtime, 3_000),
+            Err(SupervisionEvent::GaveUp { restarts: 3, .. })
+        ));
+    }
+
+    fn supervisor_with_restarts(
+        runtime: &mut MockRuntime,
+        config: SupervisorConfig,
+        restarts_ms: &[u64],
+    ) -> Supervisor {
+        let mut supervisor = start_worker(runtime, config);
+        supervisor.children.get_mut("worker").unwrap().restarts_ms = restarts_ms.to_vec();
+        supervisor
+    }
+
+    #[test]
+    fn test_policies() {
+        let mut runtime = MockRuntime::default();
+        let mut supervisor = start_worker(&mut runtime, config());
+
+        // a clean exit is not restarted under OnFailure
+        assert_eq!(
+            supervisor.handle_with(
+                &mut runtime,
+                &from_child("worker", &SupervisorRequest::Done),
+                0
+            ),
+            None
+        );
+        assert_eq!(
+            supervisor.handle_with(&mut runtime, &exited("worker"), 0),
+            Some(SupervisionEvent::Exited {
+                name: "worker".to_string(),
+                clean: true
+            })
+        );
+
+        // others can't speak for a child
+        let spec = ChildSpec::new("worker", "/app:pub.os/pkg/worker.wasm");
+        supervisor
+            .start_with(&mut runtime, spec.clone().policy(RestartPolicy::Never))
+            .unwrap();
+        let mut impostor = exited("worker");
+        if let Message::Request { source, .. } = &mut impostor {
+            *source = "our.os@other:app:pub.os".parse().unwrap();
+        }
+        assert_eq!(supervisor.handle_with(&mut runtime, &impostor, 0), None);
+        assert_eq!(
+            supervisor.handle_with(&mut runtime, &exited("worker"), 0),
+            Some(SupervisionEvent::Exited {
+                name: "worker".to_string(),
+                clean: false
+            })
+        );
+
+        // Always restarts even clean exits, and a failed spawn is reported
+        supervisor
+            .start_with(&mut runtime, spec.policy(RestartPolicy::Always))
+            .unwrap();
+        supervisor.handle_with(
+            &mut runtime,
+            &from_child("worker", &SupervisorRequest::Done),
+            0,
+        );
+        assert!(crash(&mut supervisor, &mut runtime, 0).is_ok());
+        runtime.fail_spawns = true;
+        supervisor.handle_with(&mut runtime, &exited("worker"), 1_000);
+        assert!(matches!(
+            supervisor.restart_due_with(&mut runtime, "worker", 2_000),
+            Some(SupervisionEvent::RestartFailed { .. })
+        ));
+
+        // so is a restart timer that can't be set, rather than waiting forever
+        runtime.fail_spawns = false;
+        let mut supervisor = start_worker(&mut runtime, config());
+        runtime.fail_timers = true;
+        assert_eq!(
+            supervisor.handle_with(&mut runtime, &exited("worker"), 0),
+            Some(SupervisionEvent::RestartFailed {
+                name: "worker".to_string(),
+                error: "refused".to_string()
+            })
+        );
+        assert_eq!(
+            supervisor.handle_with(&mut runtime, &exited("worker"), 1_000),
+            None
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! Mirror a vfs directory to another node, usually another node of the same
+//! user, one changed file at a time.
+//!
+//! Each side describes its copy of the directory with a [`Manifest`]: the size,
+//! hash and modification time of every file in it. The remote answers a
+//! [`manifest_request()`] with [`serve_manifest()`]; [`plan()`] compares the
+//! local directory against that, and [`apply()`] sends what changed with the
+//! [`transfer`](crate::transfer) protocol.
+//!
+//! ```no_run
+//! use hyperware_process_lib::{sync, transfer};
+//!
+//! let peer: hyperware_process_lib::Address = "laptop.os@sync:sync:publisher.os".parse().unwrap();
+//! let response = sync::manifest_request(&peer).send_and_await_response(30).unwrap().unwrap();
+//! let sync::SyncResponse::Manifest(remote) = serde_json::from_slice(response.body()).unwrap() else {
+//!     panic!("peer could not list its files");
+//! };
+//! let plan = sync::plan("/sync:publisher.os/shared", &remote, sync::Conflict::NewerWins).unwrap();
+//! let senders = sync::apply(&plan, |path, _entry| {
+//!     transfer::send_file(peer.clone(), path, 256 * 1024)
+//! })
+//! .unwrap();
+//! ```
+use crate::transfer::Sender;
+use crate::vfs::{self, DirEntry, FileType, VfsClientError};
+use crate::{Address, Message, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// One file of a [`Manifest`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The file's path relative to the directory, without a leading `/`.
+    pub path: String,
+    pub size: u64,
+    /// SHA-256 of the file's contents.
+    pub hash: [u8; 32],
+    /// Last modification time in milliseconds since the UNIX epoch, if the
+    /// runtime reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+}
+
+/// Every file under a directory, at any depth, sorted by path.
+pub type Manifest = Vec<ManifestEntry>;
+
+/// What to do with a file that changed on both sides: that is, one whose remote
+/// copy differs from the local one and was modified after it.
+///
+/// Files whose modification times are not known are never in conflict: the
+/// local copy is uploaded.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Conflict {
+    /// Keep the remote copy, as it is the newer.
+    #[default]
+    NewerWins,
+    /// Fail the plan with [`SyncError::Conflict`].
+    Error,
+}
+
+/// What [`apply()`] and the remote need to do to make the remote directory a
+/// copy of the local one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// The local directory planned for.
+    pub dir: String,
+    /// Files missing or different on the remote.
+    pub upload: Vec<ManifestEntry>,
+    /// Paths on the remote with no local file, for the remote to remove.
+    pub delete: Vec<String>,
+    /// Paths the same on both sides, or newer on the remote under
+    /// [`Conflict::NewerWins`].
+    pub skip: Vec<String>,
+}
+
+impl SyncPlan {
+    /// Whether the remote is already a copy of the local directory.
+    pub fn is_empty(&self) -> bool {
+        self.upload.is_empty() && self.delete.is_empty()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("{path} was changed on both sides: remote copy modified at {remote_modified}, local at {local_modified}")]
+    Conflict {
+        path: String,
+        local_modified: u64,
+        remote_modified: u64,
+    },
+}
+
+/// What [`manifest_request()`] sends.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SyncRequest {
+    Manifest,
+}
+
+/// What [`serve_manifest()`] answers.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SyncResponse {
+    Manifest(Manifest),
+    Err(String),
+}
+
+/// The [`Manifest`] of the vfs directory dir, hashing every file under it.
+pub fn manifest(dir: &str) -> anyhow::Result<Manifest> {
+    let mut paths = vec![];
+    walk(
+        dir,
+        &mut |dir| vfs::open_dir(dir, false, None)?.read(),
+        &mut paths,
+    )?;
+    let mut manifest = paths
+        .into_iter()
+        .map(|path| {
+            let meta = vfs::metadata(&path, None)?;
+            Ok(ManifestEntry {
+                path: relative(dir, &path).to_string(),
+                size: meta.len,
+                hash: vfs::hash_file(&path, None)?,
+                modified: meta.modified,
+            })
+        })
+        .collect::<anyhow::Result<Manifest>>()?;
+    manifest.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(manifest)
+}
+
+/// Plan making the remote directory described by remote a copy of the local
+/// directory local_dir.
+pub fn plan(local_dir: &str, remote: &Manifest, conflict: Conflict) -> anyhow::Result<SyncPlan> {
+    let local = manifest(local_dir)?;
+    let mut plan = diff(&local, remote, conflict)?;
+    plan.dir = local_dir.trim_end_matches('/').to_string();
+    Ok(plan)
+}
+
+/// Start uploading every file plan uploads, calling transfer with the full vfs
+/// path of each and its entry, and return the transfers in order. The caller
+/// drives them as any other [`Sender`], usually one started by
+/// [`crate::transfer::send_file()`].
+///
+/// The remote writes received files where its [`crate::transfer::Receiver`]
+/// puts them, so files in subdirectories need a receiver per directory, or a
+/// transfer that tells the remote where each belongs. Removing the files of
+/// [`SyncPlan::delete`] is left to the remote.
+pub fn apply<F>(plan: &SyncPlan, mut transfer: F) -> anyhow::Result<Vec<Sender>>
+where
+    F: FnMut(&str, &ManifestEntry) -> anyhow::Result<Sender>,
+{
+    plan.upload
+        .iter()
+        .map(|entry| transfer(&format!("{}/{}", plan.dir, entry.path), entry))
+        .collect()
+}
+
+/// A request to target for the [`Manifest`] of the directory it serves with
+/// [`serve_manifest()`], answered with a JSON [`SyncResponse`].
+pub fn manifest_request(target: &Address) -> Request {
+    Request::to(target).body(serde_json::to_vec(&SyncRequest::Manifest).unwrap())
+}
+
+/// If message is a [`manifest_request()`], answer it with the [`Manifest`] of
+/// the vfs directory dir. Fails only if the response could not be sent; a
+/// directory that could not be read is answered with [`SyncResponse::Err`].
+pub fn serve_manifest(message: &Message, dir: &str) -> Option<anyhow::Result<()>> {
+    let Message::Request { body, .. } = message else {
+        return None;
+    };
+    let SyncRequest::Manifest = serde_json::from_slice(body).ok()?;
+    let response = match manifest(dir) {
+        Ok(manifest) => SyncResponse::Manifest(manifest),
+        Err(e) => SyncResponse::Err(e.to_string()),
+    };
+    Some(
+        Response::new()
+            .body(serde_json::to_vec(&response).unwrap())
+            .send()
+            .map_err(Into::into),
+    )
+}
+
+/// Compare the manifests of the two sides. Entries are matched by path.
+fn diff(local: &Manifest, remote: &Manifest, conflict: Conflict) -> Result<SyncPlan, SyncError> {
+    let mut remote: BTreeMap<&str, &ManifestEntry> = remote
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let mut plan = SyncPlan::default();
+    for entry in local {
+        let Some(theirs) = remote.remove(entry.path.as_str()) else {
+            plan.upload.push(entry.clone());
+            continue;
+        };
+        if theirs.size == entry.size && theirs.hash == entry.hash {
+            plan.skip.push(entry.path.clone());
+            continue;
+        }
+        match (entry.modified, theirs.modified) {
+            (Some(local_modified), Some(remote_modified)) if remote_modified > local_modified => {
+                match conflict {
+                    Conflict::NewerWins => plan.skip.push(entry.path.clone()),
+                    Conflict::Error => {
+                        return Err(SyncError::Conflict {
+                            path: entry.path.clone(),
+                            local_modified,
+                            remote_modified,
+                        })
+                    }
+                }
+            }
+            _ => plan.upload.push(entry.clone()),
+        }
+    }
+    plan.delete = remote.into_keys().map(str::to_string).collect();
+    Ok(plan)
+}
+
+/// Push the path of every file under dir onto files, depth first.
+fn walk<R>(dir: &str, read_dir: &mut R, files: &mut Vec<String>) -> Result<(), VfsClientError>
+where
+    R: FnMut(&str) -> Result<Vec<DirEntry>, VfsClientError>,
+{
+    for entry in read_dir(dir)? {
+        match entry.file_type {
+            FileType::File => files.push(entry.path),
+            FileType::Directory => walk(&entry.path, read_dir, files)?,
+            FileType::Symlink | FileType::Other => {}
+        }
+    }
+    Ok(())
+}
+
+/// path relative to dir, whether or not either has a leading `/`.
+fn relative<'a>(dir: &str, path: &'a str) -> &'a str {
+    let dir = dir.trim_matches('/');
+    let path = path.trim_start_matches('/');
+    path.strip_prefix(dir)
+        .map_or(path, |rest| rest.trim_start_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn entry(path: &str, contents: &str, modified: Option<u64>) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size: contents.len() as u64,
+            hash: Sha256::digest(contents.as_bytes()).into(),
+            modified,
+        }
+    }
+
+    fn paths(entries: &[ManifestEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_plan_uploads_deletes_and_skips() {
+        let local = vec![
+            entry("a.txt", "same", Some(10)),
+            entry("docs/b.md", "edited", Some(30)),
+            entry("docs/new.md", "new", Some(5)),
+        ];
+        let remote = vec![
+            entry("a.txt", "same", Some(99)),
+            entry("docs/b.md", "original", Some(20)),
+            entry("old/gone.txt", "gone", Some(1)),
+            entry("stale.txt", "stale", None),
+        ];
+        let plan = diff(&local, &remote, Conflict::Error).unwrap();
+        assert_eq!(paths(&plan.upload), ["docs/b.md", "docs/new.md"]);
+        assert_eq!(plan.delete, ["old/gone.txt", "stale.txt"]);
+        assert_eq!(plan.skip, ["a.txt"]);
+
+        // already in sync
+        let plan = diff(&local, &local, Conflict::Error).unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(plan.skip.len(), 3);
+
+        // an empty local directory empties the remote
+        let plan = diff(&vec![], &remote, Conflict::NewerWins).unwrap();
+        assert!(plan.upload.is_empty());
+        assert_eq!(plan.delete.len(), 4);
+    }
+
+    #[test]
+    fn test_plan_conflicts() {
+        let local = vec![
+            entry("notes.txt", "mine", Some(100)),
+            entry("undated.txt", "mine", None),
+        ];
+        let remote = vec![
+            entry("notes.txt", "theirs", Some(200)),
+            entry("undated.txt", "theirs", Some(200)),
+        ];
+        let plan = diff(&local, &remote, Conflict::NewerWins).unwrap();
+        assert_eq!(paths(&plan.upload), ["undated.txt"]);
+        assert_eq!(plan.skip, ["notes.txt"]);
+
+        let error = diff(&local, &remote, Conflict::Error).unwrap_err();
+        assert!(matches!(
+            error,
+            SyncError::Conflict { ref path, local_modified: 100, remote_modified: 200 }
+                if path == "notes.txt"
+        ));
+
+        // an older remote copy is overwritten under either policy
+        let remote = vec![entry("notes.txt", "theirs", Some(50))];
+        let plan = diff(&local[..1].to_vec(), &remote, Conflict::Error).unwrap();
+        assert_eq!(paths(&plan.upload), ["notes.txt"]);
+    }
+
+    #[test]
+    fn test_walk_relative_paths() {
+        let dir = |path: &str, file_type| DirEntry {
+            path: path.to_string(),
+            file_type,
+        };
+        let mut tree: BTreeMap<&str, Vec<DirEntry>> = BTreeMap::from([
+            (
+                "/app:pub.os/shared",
+                vec![
+                    dir("app:pub.os/shared/a.txt", FileType::File),
+                    dir("app:pub.os/shared/docs", FileType::Directory),
+                    dir("app:pub.os/shared/link", FileType::Symlink),
+                ],
+            ),
+            (
+                "app:pub.os/shared/docs",
+                vec![dir("app:pub.os/shared/docs/b.md", FileType::File)],
+            ),
+        ]);
+        let mut files = vec![];
+        walk(
+            "/app:pub.os/shared",
+            &mut |path| Ok(tree.remove(path).unwrap_or_default()),
+            &mut files,
+        )
+        .unwrap();
+        let relative: Vec<_> = files
+            .iter()
+            .map(|path| relative("/app:pub.os/shared/", path))
+            .collect();
+        assert_eq!(relative, ["a.txt", "docs/b.md"]);
+    }
+}
@@ -0,0 +1,682 @@
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt;
+
+/// Severity of a line printed with the [`crate::error!`], [`crate::warn!`],
+/// [`crate::info!`] and [`crate::debug!`] macros. Each level prints at its own
+/// `print_to_terminal` verbosity, so the terminal's verbosity setting hides the
+/// less severe ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    /// The `print_to_terminal` verbosity this level prints at: 0 for `Error`,
+    /// which always shows, up to 3 for `Debug`.
+    pub fn verbosity(&self) -> u8 {
+        match self {
+            Level::Error => Verbosity::Always,
+            Level::Warn => Verbosity::Verbose,
+            Level::Info => Verbosity::Debug,
+            Level::Debug => Verbosity::Trace,
+        }
+        .as_u8()
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        })
+    }
+}
+
+thread_local! {
+    static LOG_LEVEL: Cell<Level> = const { Cell::new(Level::Debug) };
+    static LOG_PROCESS_NAME: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Drop lines less severe than level before they reach the terminal, saving the
+/// host call. Defaults to [`Level::Debug`], i.e. everything is printed and left
+/// to the terminal's verbosity to filter.
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.set(level);
+}
+
+pub fn log_level() -> Level {
+    LOG_LEVEL.get()
+}
+
+/// Whether to prefix lines with the name of this process, as [`crate::process_println!`] does.
+pub fn set_log_process_name(enabled: bool) {
+    LOG_PROCESS_NAME.set(enabled);
+}
+
+/// Whether a line at level passes the filter set with [`set_log_level()`].
+pub fn log_enabled(level: Level) -> bool {
+    level <= log_level()
+}
+
+/// Print a line at level, prefixed with the level. This is what the leveled
+/// macros call; prefer those.
+pub fn log(level: Level, message: &str) {
+    let process = LOG_PROCESS_NAME
+        .get()
+        .then(|| crate::our().process().to_string());
+    log_to(
+        log_level(),
+        process.as_deref(),
+        level,
+        message,
+        crate::print_to_terminal,
+    );
+}
+
+/// Print an error and every error that caused it, one per line, at [`Level::Error`].
+pub fn log_error_chain(error: &anyhow::Error) {
+    log(Level::Error, &format_error_chain(error));
+}
+
+fn log_to<F>(filter: Level, process: Option<&str>, level: Level, message: &str, mut sink: F)
+where
+    F: FnMut(u8, &str),
+{
+    if level > filter {
+        return;
+    }
+    match process {
+        Some(process) => sink(
+            level.verbosity(),
+            &format!("{process}: [{level}] {message}"),
+        ),
+        None => sink(level.verbosity(), &format!("[{level}] {message}")),
+    }
+}
+
+/// A `print_to_terminal` verbosity. The terminal shows a line if its
+/// verbosity is at most the one the user selected, so [`Verbosity::Always`]
+/// lines always show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    Always,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+impl Verbosity {
+    /// The number `print_to_terminal` takes: 0 for `Always` up to 3 for `Trace`.
+    pub const fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<Verbosity> for u8 {
+    fn from(verbosity: Verbosity) -> u8 {
+        verbosity.as_u8()
+    }
+}
+
+/// How long [`print_if()`] trusts the verbosity it last asked the terminal for.
+pub const VERBOSITY_TTL_MS: u64 = 10_000;
+/// How long [`verbosity()`] waits for the terminal, in seconds.
+const TERMINAL_TIMEOUT: u64 = 1;
+
+/// What this module asks `terminal:terminal:sys`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TerminalRequest {
+    GetVerbosity,
+    /// Run a command line as if the user had typed it.
+    InjectCommand(String),
+}
+
+/// What `terminal:terminal:sys` answers a [`TerminalRequest`] with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TerminalResponse {
+    Verbosity(u8),
+    InjectCommand(Result<(), String>),
+}
+
+/// The verbosity the user selected in the terminal, asked of
+/// `terminal:terminal:sys`. Fails if the terminal does not answer, as
+/// terminals that predate the query don't.
+pub fn verbosity() -> anyhow::Result<u8> {
+    match terminal_request(TerminalRequest::GetVerbosity)? {
+        TerminalResponse::Verbosity(verbosity) => Ok(verbosity),
+        other => Err(anyhow::anyhow!("unexpected terminal response: {other:?}")),
+    }
+}
+
+/// Run cmd in the terminal as if the user had typed it, e.g. to have a script
+/// start another. Fails if the terminal refuses the command or does not
+/// support being driven this way.
+pub fn inject_command(cmd: &str) -> anyhow::Result<()> {
+    match terminal_request(TerminalRequest::InjectCommand(cmd.to_string()))? {
+        TerminalResponse::InjectCommand(result) => result.map_err(|e| anyhow::anyhow!(e)),
+        other => Err(anyhow::anyhow!("unexpected terminal response: {other:?}")),
+    }
+}
+
+fn terminal_request(request: TerminalRequest) -> anyhow::Result<TerminalResponse> {
+    let target = crate::SystemProcess::Terminal.address(crate::our_node());
+    let response = crate::Request::to(target)
+        .body(serde_json::to_vec(&request)?)
+        .send_and_await_response(TERMINAL_TIMEOUT)??;
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+/// The verbosity [`print_if()`] last got from [`verbosity()`], and when.
+struct VerbosityCache {
+    fetched: Option<(std::time::Instant, Option<u8>)>,
+}
+
+impl VerbosityCache {
+    /// The cached verbosity, or a fresh one from query if the cached one is
+    /// more than ttl_ms old. A failed query is cached as `None`, so a terminal
+    /// that does not answer is asked again only once the TTL has passed.
+    fn get<Q>(&mut self, now: std::time::Instant, ttl_ms: u64, query: Q) -> Option<u8>
+    where
+        Q: FnOnce() -> anyhow::Result<u8>,
+    {
+        if let Some((fetched, verbosity)) = self.fetched {
+            let age = now.saturating_duration_since(fetched);
+            if age.as_millis() <= ttl_ms as u128 {
+                return verbosity;
+            }
+        }
+        let verbosity = query().ok();
+        self.fetched = Some((now, verbosity));
+        verbosity
+    }
+}
+
+thread_local! {
+    static VERBOSITY: std::cell::RefCell<VerbosityCache> =
+        const { std::cell::RefCell::new(VerbosityCache { fetched: None }) };
+}
+
+/// Print message at level, unless the terminal is known to hide it, saving
+/// the host call. The terminal's verbosity is asked with [`verbosity()`] at
+/// most once per [`VERBOSITY_TTL_MS`]; if it can not be had, everything is
+/// printed and left to the terminal to filter.
+///
+/// Asking awaits a response, which replaces the blob of the message being
+/// handled as [`crate::Request::send_and_await_response()`] does: read the
+/// blob before printing.
+pub fn print_if(level: Verbosity, message: &str) {
+    let selected = VERBOSITY
+        .with_borrow_mut(|cache| cache.get(std::time::Instant::now(), VERBOSITY_TTL_MS, verbosity));
+    if selected.is_none_or(|selected| level.as_u8() <= selected) {
+        crate::print_to_terminal(level.as_u8(), message);
+    }
+}
+
+fn format_error_chain(error: &anyhow::Error) -> String {
+    let mut text = error.to_string();
+    for cause in error.chain().skip(1) {
+        text.push_str(&format!("\n  caused by: {cause}"));
+    }
+    text
+}
+
+/// Columns of text, rendered with each column padded to its widest cell.
+/// Widths are counted in terminal cells, so wide characters such as CJK take
+/// two; see [`display_width()`].
+///
+/// ```
+/// use hyperware_process_lib::terminal::Table;
+///
+/// let mut table = Table::new(["process", "size"]);
+/// table.push_row(["chat:chat:sys", "12"]);
+/// table.push_row(["kv:distro:sys", "4096"]);
+/// assert_eq!(
+///     table.render(),
+///     "process        size\n\
+///      -------------  ----\n\
+///      chat:chat:sys  12\n\
+///      kv:distro:sys  4096"
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    max_width: Option<usize>,
+}
+
+impl Table {
+    pub fn new<I, T>(headers: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: fmt::Display,
+    {
+        Table {
+            headers: headers.into_iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Truncate cells wider than width, ending them with `…`.
+    pub fn max_width(mut self, width: usize) -> Self {
+        self.max_width = Some(width.max(1));
+        self
+    }
+
+    /// Add a row. Rows with fewer cells than there are columns are padded with
+    /// empty cells; cells beyond the headers get headerless columns.
+    pub fn push_row<I, T>(&mut self, row: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: fmt::Display,
+    {
+        self.rows
+            .push(row.into_iter().map(|cell| cell.to_string()).collect());
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The header line, a line of dashes under it, then a line per row. Columns
+    /// are separated by two spaces, and lines carry no trailing spaces.
+    pub fn render(&self) -> String {
+        let truncate = |cell: &str| match self.max_width {
+            Some(max) => truncate_to_width(cell, max),
+            None => cell.to_string(),
+        };
+        let header: Vec<String> = self.headers.iter().map(|h| truncate(h)).collect();
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|cell| truncate(cell)).collect())
+            .collect();
+
+        let columns = rows.iter().map(Vec::len).fold(header.len(), usize::max);
+        let mut widths = vec![0; columns];
+        for line in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(line) {
+                *width = (*width).max(display_width(cell));
+            }
+        }
+        let dashes: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+
+        let mut lines = vec![];
+        if !header.is_empty() {
+            lines.push(render_line(&header, &widths));
+            lines.push(render_line(&dashes, &widths));
+        }
+        lines.extend(rows.iter().map(|row| render_line(row, &widths)));
+        lines.join("\n")
+    }
+
+    /// Print the rendered table in one [`crate::print_to_terminal()`] call at
+    /// verbosity 0, like [`crate::println!`].
+    pub fn print(&self) {
+        crate::print_to_terminal(Verbosity::Always.as_u8(), &self.render());
+    }
+}
+
+fn render_line(cells: &[String], widths: &[usize]) -> String {
+    let mut line = String::new();
+    for (i, width) in widths.iter().enumerate() {
+        let cell = cells.get(i).map_or("", String::as_str);
+        if i > 0 {
+            line.push_str("  ");
+        }
+        line.push_str(cell);
+        line.extend(std::iter::repeat_n(' ', width - display_width(cell)));
+    }
+    line.truncate(line.trim_end().len());
+    line
+}
+
+fn truncate_to_width(text: &str, max: usize) -> String {
+    if display_width(text) <= max {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    let mut width = 0;
+    for c in text.chars() {
+        // leave a cell for the ellipsis
+        if width + char_width(c) >= max {
+            break;
+        }
+        width += char_width(c);
+        truncated.push(c);
+    }
+    truncated.push('…');
+    truncated
+}
+
+/// How many terminal cells text takes up: 2 for each East Asian wide or
+/// fullwidth character and most emoji, 0 for combining marks and other
+/// zero-width characters, 1 for everything else. This covers the common
+/// ranges rather than the full Unicode tables.
+pub fn display_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F
+        | 0x1AB0..=0x1AFF
+        | 0x1DC0..=0x1DFF
+        | 0x200B..=0x200F
+        | 0x20D0..=0x20FF
+        | 0xFE00..=0xFE0F
+        | 0xFE20..=0xFE2F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// How often a [`Progress`] reprints, by default.
+pub const DEFAULT_PROGRESS_INTERVAL_MS: u64 = 1000;
+
+/// A progress bar for long-running work, reprinted at most once per interval
+/// however often it is updated, so that updating it per item doesn't flood the
+/// terminal.
+///
+/// ```
+/// use hyperware_process_lib::terminal::Progress;
+///
+/// let mut progress = Progress::new("indexing").width(10);
+/// // the first update prints, later ones only once the interval has passed
+/// assert!(progress.update(1, 4, 0).is_some());
+/// assert!(progress.update(2, 4, 10).is_none());
+/// assert_eq!(
+///     progress.update(3, 4, 1_000).unwrap(),
+///     "indexing [#######---] 75% (3/4)"
+/// );
+/// ```
+pub struct Progress {
+    label: String,
+    width: usize,
+    interval_ms: u64,
+    current: u64,
+    total: u64,
+    last_print_ms: Option<u64>,
+    done_printed: bool,
+}
+
+impl Progress {
+    pub fn new(label: &str) -> Self {
+        Progress {
+            label: label.to_string(),
+            width: 30,
+            interval_ms: DEFAULT_PROGRESS_INTERVAL_MS,
+            current: 0,
+            total: 0,
+            last_print_ms: None,
+            done_printed: false,
+        }
+    }
+
+    /// The width of the bar itself, in cells. Defaults to 30.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Reprint at most once per interval_ms. Defaults to
+    /// [`DEFAULT_PROGRESS_INTERVAL_MS`].
+    pub fn interval_ms(mut self, interval_ms: u64) -> Self {
+        self.interval_ms = interval_ms;
+        self
+    }
+
+    /// Record progress and print the bar if it is due, as [`Progress::update()`]
+    /// decides, with [`crate::print_to_terminal()`] at verbosity 0.
+    pub fn set(&mut self, current: u64, total: u64) {
+        let now_ms = crate::timer::wall_clock_ms();
+        if let Some(line) = self.update(current, total, now_ms) {
+            crate::print_to_terminal(Verbosity::Always.as_u8(), &line);
+        }
+    }
+
+    /// Record progress at now_ms, returning the line to print if it is due: on
+    /// the first update, once interval_ms has passed since the last printed
+    /// one, and on the first update that reaches total, so the finished bar
+    /// always shows.
+    pub fn update(&mut self, current: u64, total: u64, now_ms: u64) -> Option<String> {
+        self.current = current;
+        self.total = total;
+        let done = current >= total;
+        let due = match self.last_print_ms {
+            None => true,
+            Some(last) => now_ms.saturating_sub(last) >= self.interval_ms,
+        };
+        if !(due || done && !self.done_printed) {
+            return None;
+        }
+        self.last_print_ms = Some(now_ms);
+        self.done_printed |= done;
+        Some(self.render())
+    }
+
+    /// The bar for the last update, e.g. `label [###-------] 30% (3/10)`. Work
+    /// with a total of 0 counts as done.
+    pub fn render(&self) -> String {
+        let (current, total) = (self.current.min(self.total), self.total);
+        let (filled, percent) = match total {
+            0 => (self.width, 100),
+            total => (
+                (self.width as u128 * current as u128 / total as u128) as usize,
+                current as u128 * 100 / total as u128,
+            ),
+        };
+        format!(
+            "{} [{}{}] {}% ({}/{})",
+            self.label,
+            "#".repeat(filled),
+            "-".repeat(self.width - filled),
+            percent,
+            self.current,
+            self.total
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printed(filter: Level, process: Option<&str>, level: Level) -> Vec<(u8, String)> {
+        let mut calls = vec![];
+        log_to(filter, process, level, "hello", |verbosity, text| {
+            calls.push((verbosity, text.to_string()))
+        });
+        calls
+    }
+
+    #[test]
+    fn test_log_filtering() {
+        assert_eq!(
+            printed(Level::Debug, None, Level::Debug),
+            vec![(3, "[DEBUG] hello".to_string())]
+        );
+        assert_eq!(
+            printed(Level::Info, None, Level::Warn),
+            vec![(1, "[WARN] hello".to_string())]
+        );
+        // less severe than the filter: no host call at all
+        assert!(printed(Level::Info, None, Level::Debug).is_empty());
+        assert!(printed(Level::Error, None, Level::Warn).is_empty());
+        assert_eq!(printed(Level::Error, None, Level::Error).len(), 1);
+
+        assert_eq!(
+            printed(Level::Debug, Some("app:pkg:pub.os"), Level::Info),
+            vec![(2, "app:pkg:pub.os: [INFO] hello".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_log_level_setting() {
+        assert_eq!(log_level(), Level::Debug);
+        assert!(log_enabled(Level::Debug));
+        set_log_level(Level::Warn);
+        assert!(log_enabled(Level::Error));
+        assert!(log_enabled(Level::Warn));
+        assert!(!log_enabled(Level::Info));
+    }
+
+    #[test]
+    fn test_verbosity_cache_ttl() {
+        let start = std::time::Instant::now();
+        let ms = |ms| start + std::time::Duration::from_millis(ms);
+        let mut cache = VerbosityCache { fetched: None };
+        let mut queries = 0;
+        let mut query = |result: anyhow::Result<u8>| {
+            queries += 1;
+            result
+        };
+        assert_eq!(cache.get(ms(0), 100, || query(Ok(1))), Some(1));
+        // within the TTL the terminal is not asked again
+        assert_eq!(cache.get(ms(50), 100, || query(Ok(3))), Some(1));
+        assert_eq!(cache.get(ms(100), 100, || query(Ok(3))), Some(1));
+        assert_eq!(cache.get(ms(101), 100, || query(Ok(3))), Some(3));
+        // a failure is cached as unknown for a TTL too
+        assert_eq!(
+            cache.get(ms(300), 100, || query(Err(anyhow::anyhow!("timeout")))),
+            None
+        );
+        assert_eq!(cache.get(ms(350), 100, || query(Ok(2))), None);
+        assert_eq!(cache.get(ms(401), 100, || query(Ok(2))), Some(2));
+        // a clock reading from before the last fetch counts as fresh
+        assert_eq!(cache.get(ms(10), 100, || query(Ok(0))), Some(2));
+        assert_eq!(queries, 4);
+    }
+
+    #[test]
+    fn test_print_if() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        host.reply(crate::host::Reply::json(&TerminalResponse::Verbosity(1)));
+        print_if(Verbosity::Always, "shown");
+        print_if(Verbosity::Verbose, "also shown");
+        print_if(Verbosity::Debug, "hidden");
+        print_if(Verbosity::Trace, "hidden");
+        assert_eq!(
+            host.prints(),
+            [(0, "shown".to_string()), (1, "also shown".to_string())]
+        );
+        let asked = host
+            .calls()
+            .into_iter()
+            .filter(|call| matches!(call, crate::host::Call::SendAndAwaitResponse { .. }))
+            .count();
+        assert_eq!(asked, 1);
+    }
+
+    #[test]
+    fn test_format_error_chain() {
+        let error = anyhow::anyhow!("disk full")
+            .context("failed to write state")
+            .context("failed to handle request");
+        assert_eq!(
+            format_error_chain(&error),
+            "failed to handle request\n  caused by: failed to write state\n  caused by: disk full"
+        );
+        assert_eq!(format_error_chain(&anyhow::anyhow!("alone")), "alone");
+    }
+
+    #[test]
+    fn test_table_alignment_with_wide_characters() {
+        let mut table = Table::new(["name", "city", "n"]);
+        table.push_row(["アリス", "東京", "1"]);
+        table.push_row(["bob", "Zürich", "22"]);
+        table.push_row(["cafe\u{301}"]);
+        assert_eq!(display_width("アリス"), 6);
+        assert_eq!(display_width("cafe\u{301}"), 4);
+        assert_eq!(
+            table.render(),
+            [
+                "name    city    n",
+                "------  ------  --",
+                "アリス  東京    1",
+                "bob     Zürich  22",
+                "cafe\u{301}",
+            ]
+            .join("\n")
+        );
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn test_table_truncation() {
+        let mut table = Table::new(["key", "value"]).max_width(5);
+        table.push_row(["short", "a much longer value"]);
+        table.push_row(["漢字漢字", "x"]);
+        table.push_row(["k", "v", "extra"]);
+        assert_eq!(
+            table.render(),
+            [
+                "key    value",
+                "-----  -----  -----",
+                "short  a mu…",
+                "漢字…  x",
+                "k      v      extra",
+            ]
+            .join("\n")
+        );
+        assert_eq!(Table::new(Vec::<String>::new()).render(), "");
+    }
+
+    #[test]
+    fn test_progress_throttling() {
+        let mut progress = Progress::new("sync").width(4).interval_ms(100);
+        assert_eq!(
+            progress.update(0, 8, 1_000).unwrap(),
+            "sync [----] 0% (0/8)"
+        );
+        assert_eq!(progress.update(1, 8, 1_050), None);
+        assert_eq!(progress.update(3, 8, 1_099), None);
+        // the interval counts from the last print, not the last update
+        assert_eq!(
+            progress.update(4, 8, 1_100).unwrap(),
+            "sync [##--] 50% (4/8)"
+        );
+        assert_eq!(progress.update(5, 8, 1_150), None);
+        // finishing prints straight away, once
+        assert_eq!(
+            progress.update(8, 8, 1_160).unwrap(),
+            "sync [####] 100% (8/8)"
+        );
+        assert_eq!(progress.update(8, 8, 1_170), None);
+        assert!(progress.update(8, 8, 1_260).is_some());
+
+        let mut empty = Progress::new("nothing").width(2);
+        assert_eq!(empty.update(0, 0, 0).unwrap(), "nothing [##] 100% (0/0)");
+        // more than total doesn't overflow the bar
+        assert_eq!(empty.update(5, 2, 1), None);
+        assert_eq!(empty.render(), "nothing [##] 100% (5/2)");
+    }
+}
@@ -0,0 +1,334 @@
+use crate::host::{set_host, Host, HostGuard};
+use crate::hyperware::process::standard as wit;
+use crate::{Address, Capability, LazyLoadBlob, Message};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The source of built messages unless [`MessageBuilder::from()`] says otherwise.
+pub const DEFAULT_SOURCE: &str = "sender.os@sender:test:sys";
+
+enum Kind {
+    Request { expects_response: Option<u64> },
+    Response { context: Option<Vec<u8>> },
+}
+
+/// Builds the [`Message`]s a handler would get from [`crate::await_message()`], for
+/// calling it in tests.
+///
+/// ```
+/// use hyperware_process_lib::test_utils::MessageBuilder;
+///
+/// let message = MessageBuilder::request()
+///     .from("node.os@proc:pkg:pub.os")
+///     .body_json(&serde_json::json!({"Get": 1}))
+///     .expects_response(5)
+///     .build();
+/// assert!(message.is_request());
+/// ```
+pub struct MessageBuilder {
+    kind: Kind,
+    source: Address,
+    body: Vec<u8>,
+    metadata: Option<String>,
+    capabilities: Vec<Capability>,
+}
+
+impl MessageBuilder {
+    pub fn request() -> Self {
+        Self::new(Kind::Request {
+            expects_response: None,
+        })
+    }
+
+    pub fn response() -> Self {
+        Self::new(Kind::Response { context: None })
+    }
+
+    fn new(kind: Kind) -> Self {
+        MessageBuilder {
+            kind,
+            source: DEFAULT_SOURCE.parse().unwrap(),
+            body: vec![],
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    /// Set the source. Panics if source is not a valid [`Address`].
+    pub fn from(mut self, source: &str) -> Self {
+        self.source = source
+            .parse()
+            .unwrap_or_else(|e| panic!("bad source address {source}: {e}"));
+        self
+    }
+
+    pub fn source(mut self, source: Address) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn body<T: Into<Vec<u8>>>(mut self, body: T) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Set the body to body serialized as JSON. Panics if it doesn't serialize.
+    pub fn body_json<T: Serialize>(mut self, body: &T) -> Self {
+        self.body = serde_json::to_vec(body).expect("body serializes to JSON");
+        self
+    }
+
+    pub fn metadata(mut self, metadata: &str) -> Self {
+        self.metadata = Some(metadata.to_string());
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Make the request expect a response within timeout seconds. Panics if
+    /// building a response.
+    pub fn expects_response(mut self, timeout: u64) -> Self {
+        match &mut self.kind {
+            Kind::Request { expects_response } => *expects_response = Some(timeout),
+            Kind::Response { .. } => panic!("only requests expect responses"),
+        }
+        self
+    }
+
+    /// Set the context the response comes back with. Panics if building a request.
+    pub fn context<T: Into<Vec<u8>>>(mut self, context: T) -> Self {
+        match &mut self.kind {
+            Kind::Response { context: slot } => *slot = Some(context.into()),
+            Kind::Request { .. } => panic!("only responses have contexts"),
+        }
+        self
+    }
+
+    pub fn build(self) -> Message {
+        match self.kind {
+            Kind::Request { expects_response } => Message::Request {
+                source: self.source,
+                expects_response,
+                body: self.body,
+                metadata: self.metadata,
+                capabilities: self.capabilities,
+            },
+            Kind::Response { context } => Message::Response {
+                source: self.source,
+                body: self.body,
+                metadata: self.metadata,
+                context,
+                capabilities: self.capabilities,
+            },
+        }
+    }
+}
+
+/// A response sent through a [`FakeBlobStore`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SentResponse {
+    pub body: Vec<u8>,
+    pub metadata: Option<String>,
+    pub capabilities: Vec<Capability>,
+    /// The blob the response carried: its own, or if it inherited, the one set
+    /// with [`FakeBlobStore::set_blob()`].
+    pub blob: Option<LazyLoadBlob>,
+}
+
+#[derive(Default)]
+struct Store {
+    blob: Option<LazyLoadBlob>,
+    responses: Vec<SentResponse>,
+}
+
+/// Stands in for the kernel's blob of the message being handled, and collects
+/// the responses sent to it, for code that uses [`crate::get_blob()`] and
+/// [`crate::Response`]. Takes effect on the current thread once installed.
+///
+/// ```
+/// use hyperware_process_lib::test_utils::FakeBlobStore;
+/// use hyperware_process_lib::{get_blob, LazyLoadBlob, Response};
+///
+/// let store = FakeBlobStore::new();
+/// let _installed = store.install();
+/// store.set_blob(Some(LazyLoadBlob::new(None::<String>, b"picture".to_vec())));
+///
+/// // the handler under test
+/// let size = get_blob().unwrap().bytes.len();
+/// Response::new().body(size.to_string()).send().unwrap();
+///
+/// assert_eq!(store.responses()[0].body, b"7");
+/// ```
+#[derive(Clone, Default)]
+pub struct FakeBlobStore {
+    store: Rc<RefCell<Store>>,
+}
+
+impl FakeBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make this the host of the current thread until the guard is dropped.
+    pub fn install(&self) -> HostGuard {
+        set_host(Rc::new(self.clone()))
+    }
+
+    /// Set the blob [`crate::get_blob()`] returns.
+    pub fn set_blob(&self, blob: Option<LazyLoadBlob>) {
+        crate::host::clear_blob_cache();
+        self.store.borrow_mut().blob = blob;
+    }
+
+    /// The responses sent so far, oldest first.
+    pub fn responses(&self) -> Vec<SentResponse> {
+        self.store.borrow().responses.clone()
+    }
+
+    /// Like [`FakeBlobStore::responses()`], but forgets them.
+    pub fn take_responses(&self) -> Vec<SentResponse> {
+        std::mem::take(&mut self.store.borrow_mut().responses)
+    }
+}
+
+impl Host for FakeBlobStore {
+    fn get_blob(&self) -> Option<LazyLoadBlob> {
+        self.store.borrow().blob.clone()
+    }
+
+    fn has_blob(&self) -> bool {
+        self.store.borrow().blob.is_some()
+    }
+
+    fn send_response(&self, response: &wit::Response, blob: Option<&LazyLoadBlob>) {
+        let mut store = self.store.borrow_mut();
+        let blob = match blob {
+            Some(blob) => Some(blob.clone()),
+            None if response.inherit => store.blob.clone(),
+            None => None,
+        };
+        store.responses.push(SentResponse {
+            body: response.body.clone(),
+            metadata: response.metadata.clone(),
+            capabilities: response.capabilities.clone(),
+            blob,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_blob, Response};
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    enum ThumbnailRequest {
+        Resize { width: u32 },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    enum ThumbnailResponse {
+        Resized { from: String, bytes: usize },
+        NoImage,
+    }
+
+    /// A handler as a process would write it.
+    fn handle(message: &Message) -> anyhow::Result<()> {
+        let ThumbnailRequest::Resize { width } = serde_json::from_slice(message.body())?;
+        let response = match get_blob() {
+            Some(image) => ThumbnailResponse::Resized {
+                from: message.source().node().to_string(),
+                bytes: image.bytes.len().min(width as usize),
+            },
+            None => ThumbnailResponse::NoImage,
+        };
+        Response::new()
+            .body(serde_json::to_vec(&response)?)
+            .inherit(true)
+            .send()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_handler_with_synthetic_request() {
+        let store = FakeBlobStore::new();
+        let _installed = store.install();
+        let request = MessageBuilder::request()
+            .from("alice.os@gallery:gallery:pub.os")
+            .body_json(&ThumbnailRequest::Resize { width: 4 })
+            .expects_response(5)
+            .build();
+
+        handle(&request).unwrap();
+        store.set_blob(Some(LazyLoadBlob::new(Some("image/png"), b"a big image")));
+        handle(&request).unwrap();
+
+        let responses = store.take_responses();
+        let bodies: Vec<ThumbnailResponse> = responses
+            .iter()
+            .map(|response| serde_json::from_slice(&response.body).unwrap())
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                ThumbnailResponse::NoImage,
+                ThumbnailResponse::Resized {
+                    from: "alice.os".into(),
+                    bytes: 4
+                }
+            ]
+        );
+        assert_eq!(responses[0].blob, None);
+        assert_eq!(
+            responses[1].blob.as_ref().unwrap().mime.as_deref(),
+            Some("image/png")
+        );
+        assert!(store.responses().is_empty());
+    }
+
+    #[test]
+    fn test_message_builder() {
+        let response = MessageBuilder::response()
+            .from("bob.os@chat:chat:pub.os")
+            .body("hi")
+            .context(b"ctx".to_vec())
+            .metadata("m")
+            .build();
+        match response {
+            Message::Response {
+                source,
+                body,
+                metadata,
+                context,
+                ..
+            } => {
+                assert_eq!(source.to_string(), "bob.os@chat:chat:pub.os");
+                assert_eq!(body, b"hi");
+                assert_eq!(metadata.as_deref(), Some("m"));
+                assert_eq!(context.as_deref(), Some(&b"ctx"[..]));
+            }
+            _ => panic!("built a request"),
+        }
+        let request = MessageBuilder::request().build();
+        assert_eq!(request.source().to_string(), DEFAULT_SOURCE);
+        assert!(request.is_request());
+    }
+
+    #[test]
+    fn test_guard_restores_previous_host() {
+        let outer = FakeBlobStore::new();
+        outer.set_blob(Some(LazyLoadBlob::new(None::<String>, b"outer")));
+        let _outer = outer.install();
+        {
+            let inner = FakeBlobStore::new();
+            let _inner = inner.install();
+            assert_eq!(get_blob(), None);
+        }
+        assert_eq!(get_blob().unwrap().bytes, b"outer");
+    }
+}
@@ -1,5 +1,6 @@
-use crate::{Context, Message, Request, SendError};
-use serde::{Deserialize, Serialize};
+use crate::{BuildError, Context, Message, Request, SendError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// The [`Request::body()`] field for requests to `timer:distro:sys`, a runtime module
 /// that allows processes to set timers with a duration specified in milliseconds.
@@ -19,24 +20,623 @@ impl Into<Vec<u8>> for TimerAction {
 
 /// Set a timer using the runtime that will return a [`crate::Response`] after the specified duration.
 /// The duration should be a number of milliseconds.
-pub fn set_timer(duration: u64, context: Option<Context>) {
-    let mut request = Request::to(("our", "timer", "distro", "sys"))
+pub fn set_timer(duration: u64, context: Option<Context>) -> Result<(), BuildError> {
+    let mut request = Request::to(crate::SystemProcess::Timer.address("our"))
         .body(TimerAction::SetTimer(duration))
         .expects_response((duration / 1000) + 1);
 
     if let Some(context) = context {
         request = request.context(context);
     }
-    // safe to unwrap this call when we know we've set both target and body
-    request.send().unwrap();
+    request.send()
 }
 
 /// Set a timer using the runtime that will return a [`crate::Response`] after the specified duration,
 /// then wait for that timer to resolve. The duration should be a number of milliseconds.
-pub fn set_and_await_timer(duration: u64) -> Result<Message, SendError> {
-    Request::to(("our", "timer", "distro", "sys"))
+pub fn set_and_await_timer(duration: u64) -> Result<Result<Message, SendError>, BuildError> {
+    Request::to(crate::SystemProcess::Timer.address("our"))
         .body(TimerAction::SetTimer(duration))
         .send_and_await_response((duration / 1000) + 1)
-        // safe to unwrap this call when we know we've set both target and body
-        .unwrap()
+}
+
+/// Set a timer whose [`crate::Response`] carries ctx, serialized as JSON, as its context.
+/// Recover it with [`context_as()`] when the timer fires.
+pub fn set_timer_typed<T: Serialize>(duration: u64, ctx: &T) -> anyhow::Result<()> {
+    set_timer(duration, Some(serde_json::to_vec(ctx)?))?;
+    Ok(())
+}
+
+/// Block for ms milliseconds. Any other messages that arrive in the meantime
+/// are not lost: they are handled after this returns.
+pub fn sleep(ms: u64) -> anyhow::Result<()> {
+    set_and_await_timer(ms)??;
+    Ok(())
+}
+
+/// Whether message is a timer firing: a [`crate::Response`] from our own `timer:distro:sys`.
+pub fn is_timer_response(message: &Message) -> bool {
+    is_timer_response_from(message, &crate::our().node)
+}
+
+/// The context of a timer set with [`set_timer_typed()`], deserialized as T.
+/// Returns `None` if message is not a timer response or has no context.
+pub fn context_as<T: DeserializeOwned>(message: &Message) -> anyhow::Result<Option<T>> {
+    if !is_timer_response(message) {
+        return Ok(None);
+    }
+    parse_context(message)
+}
+
+fn is_timer_response_from(message: &Message, our_node: &str) -> bool {
+    !message.is_request()
+        && message.source().node == our_node
+        && message.is_process(crate::SystemProcess::Timer)
+}
+
+fn parse_context<T: DeserializeOwned>(message: &Message) -> anyhow::Result<Option<T>> {
+    match message.context() {
+        Some(context) => Ok(Some(serde_json::from_slice(context)?)),
+        None => Ok(None),
+    }
+}
+
+/// Recurring and one-shot timers identified by tag, which can be cancelled.
+///
+/// `timer:distro:sys` cannot cancel a timer once set, so every timer armed by a
+/// `Schedule` carries its tag and a generation in its context. Cancelling or
+/// replacing a tag bumps its generation, and [`Schedule::handle()`] ignores firings
+/// from older generations.
+///
+/// A `Schedule` is serializable so it can be kept in process state. Timers do not
+/// survive a restart, so call [`Schedule::rearm()`] after restoring one.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    entries: HashMap<String, ScheduleEntry>,
+    /// Never reset, even when a tag is cancelled, so no old firing can match a new entry.
+    generations: HashMap<String, u64>,
+}
+
+/// A tag that fired, returned by [`Schedule::handle()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fired {
+    pub tag: String,
+    /// Whether the tag was armed with [`Schedule::every()`], and so is armed again.
+    pub recurring: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ScheduleEntry {
+    interval_ms: Option<u64>,
+    /// When the entry is next due, in ms since the UNIX epoch.
+    due_ms: u64,
+}
+
+/// The context of a timer armed by a [`Schedule`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ScheduleContext {
+    schedule_tag: String,
+    generation: u64,
+}
+
+/// A timer to set: fire in duration ms with context.
+#[derive(Debug, PartialEq, Eq)]
+struct Arm {
+    duration: u64,
+    context: ScheduleContext,
+}
+
+impl Arm {
+    fn set(self) -> Result<(), BuildError> {
+        set_timer(
+            self.duration,
+            Some(serde_json::to_vec(&self.context).unwrap()),
+        )
+    }
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fire tag every interval_ms milliseconds, replacing any existing entry for tag.
+    pub fn every(&mut self, interval_ms: u64, tag: &str) -> Result<(), BuildError> {
+        let now = wall_clock_ms();
+        self.add(tag, Some(interval_ms), now + interval_ms, now)
+            .set()
+    }
+
+    /// Fire tag once at unix_ms (in ms since the UNIX epoch), or right away if that
+    /// has passed. Replaces any existing entry for tag.
+    pub fn once_at(&mut self, unix_ms: u64, tag: &str) -> Result<(), BuildError> {
+        self.add(tag, None, unix_ms, wall_clock_ms()).set()
+    }
+
+    /// Stop tag from firing. Returns whether it was scheduled.
+    pub fn cancel(&mut self, tag: &str) -> bool {
+        let removed = self.entries.remove(tag).is_some();
+        if removed {
+            self.bump(tag);
+        }
+        removed
+    }
+
+    /// The scheduled tags.
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|tag| tag.as_str())
+    }
+
+    /// Check whether message is one of this schedule's timers firing. If so, re-arm it
+    /// if recurring and return which tag fired. Returns `None` for other messages and
+    /// for firings of cancelled or replaced entries, and an error if re-arming fails.
+    pub fn handle(&mut self, message: &Message) -> Result<Option<Fired>, BuildError> {
+        let Some((fired, arm)) = self.fired(message) else {
+            return Ok(None);
+        };
+        if let Some(arm) = arm {
+            arm.set()?;
+        }
+        Ok(Some(fired))
+    }
+
+    /// Arm a timer for every entry, e.g. after restoring the schedule from state.
+    /// Entries that came due while the process was down fire right away.
+    pub fn rearm(&mut self) -> Result<(), BuildError> {
+        self.rearm_at(wall_clock_ms())
+            .into_iter()
+            .try_for_each(Arm::set)
+    }
+
+    fn fired(&mut self, message: &Message) -> Option<(Fired, Option<Arm>)> {
+        if !is_timer_response(message) {
+            return None;
+        }
+        let context = serde_json::from_slice(message.context()?).ok()?;
+        self.fire(&context, wall_clock_ms())
+    }
+
+    fn add(&mut self, tag: &str, interval_ms: Option<u64>, due_ms: u64, now: u64) -> Arm {
+        let generation = self.bump(tag);
+        self.entries.insert(
+            tag.to_string(),
+            ScheduleEntry {
+                interval_ms,
+                due_ms,
+            },
+        );
+        Arm {
+            duration: due_ms.saturating_sub(now),
+            context: ScheduleContext {
+                schedule_tag: tag.to_string(),
+                generation,
+            },
+        }
+    }
+
+    fn bump(&mut self, tag: &str) -> u64 {
+        let generation = self.generations.entry(tag.to_string()).or_default();
+        *generation += 1;
+        *generation
+    }
+
+    fn fire(&mut self, context: &ScheduleContext, now: u64) -> Option<(Fired, Option<Arm>)> {
+        let tag = &context.schedule_tag;
+        if self.generations.get(tag) != Some(&context.generation) {
+            return None;
+        }
+        let entry = self.entries.get_mut(tag)?;
+        let fired = Fired {
+            tag: tag.clone(),
+            recurring: entry.interval_ms.is_some(),
+        };
+        match entry.interval_ms {
+            Some(interval_ms) => {
+                entry.due_ms = now + interval_ms;
+                let arm = Arm {
+                    duration: interval_ms,
+                    context: context.clone(),
+                };
+                Some((fired, Some(arm)))
+            }
+            None => {
+                self.entries.remove(tag);
+                Some((fired, None))
+            }
+        }
+    }
+
+    fn rearm_at(&mut self, now: u64) -> Vec<Arm> {
+        let mut entries: Vec<(String, ScheduleEntry)> = self
+            .entries
+            .iter()
+            .map(|(tag, entry)| (tag.clone(), entry.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .map(|(tag, entry)| self.add(&tag, entry.interval_ms, entry.due_ms, now))
+            .collect()
+    }
+}
+
+/// The current time in milliseconds since the UNIX epoch, from the wall clock the
+/// runtime provides to processes.
+///
+/// This is the host machine's clock: it can jump (e.g. on NTP adjustments), differs
+/// between nodes, and its resolution is up to the host. Use it for timestamps and
+/// scheduling, not for ordering events across nodes.
+pub fn now_ms() -> anyhow::Result<u64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_millis() as u64)
+}
+
+thread_local! {
+    /// The last wall-clock reading of [`now_ms_cached()`], and when it was taken.
+    static CACHED_NOW: std::cell::Cell<Option<(std::time::Instant, u64)>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Like [`now_ms()`], but reuses the last reading if it is at most max_staleness_ms
+/// old, so the result may lag the wall clock by up to that much. Meant for tight
+/// loops that stamp many records and don't need millisecond precision.
+pub fn now_ms_cached(max_staleness_ms: u64) -> anyhow::Result<u64> {
+    let now = std::time::Instant::now();
+    if let Some(cached) = fresh_cached(CACHED_NOW.get(), now, max_staleness_ms) {
+        return Ok(cached);
+    }
+    let unix_ms = now_ms()?;
+    CACHED_NOW.set(Some((now, unix_ms)));
+    Ok(unix_ms)
+}
+
+fn fresh_cached(
+    cached: Option<(std::time::Instant, u64)>,
+    now: std::time::Instant,
+    max_staleness_ms: u64,
+) -> Option<u64> {
+    let (taken, unix_ms) = cached?;
+    let age = now.checked_duration_since(taken)?;
+    (age.as_millis() <= max_staleness_ms as u128).then_some(unix_ms)
+}
+
+/// Coarse duration measurement for logging, on the runtime's monotonic clock,
+/// which unlike [`now_ms()`] never goes backwards.
+///
+/// ```no_run
+/// use hyperware_process_lib::timer::Stopwatch;
+///
+/// let stopwatch = Stopwatch::start();
+/// // ... do some work ...
+/// println!("took {}ms", stopwatch.elapsed_ms());
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Stopwatch {
+    started: std::time::Instant,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch {
+            started: std::time::Instant::now(),
+        }
+    }
+
+    pub fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Return the elapsed time and start measuring again from now.
+    pub fn lap_ms(&mut self) -> u64 {
+        let elapsed = self.elapsed_ms();
+        *self = Stopwatch::start();
+        elapsed
+    }
+}
+
+/// An overall time limit for several requests awaited one after another, e.g.
+/// all the calls needed to answer one incoming request. Each is given what is
+/// left as its timeout by [`Request::send_and_await_with_deadline()`], and is not
+/// sent at all once nothing is left.
+///
+/// ```no_run
+/// use hyperware_process_lib::{timer::Deadline, Request};
+///
+/// let deadline = Deadline::in_ms(5_000);
+/// for step in ["fetch", "index", "store"] {
+///     Request::to(("our", "worker", "app", "sys.os"))
+///         .body(step)
+///         .send_and_await_with_deadline(&deadline)??;
+/// }
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    at_ms: u64,
+    now_ms: fn() -> u64,
+}
+
+impl Deadline {
+    /// The deadline total_ms from now.
+    pub fn in_ms(total_ms: u64) -> Self {
+        Deadline::in_ms_with(total_ms, wall_clock_ms)
+    }
+
+    /// A deadline at a time in milliseconds since the UNIX epoch, as
+    /// [`now_ms()`] reads it.
+    pub fn at_ms(at_ms: u64) -> Self {
+        Deadline {
+            at_ms,
+            now_ms: wall_clock_ms,
+        }
+    }
+
+    pub(crate) fn in_ms_with(total_ms: u64, now_ms: fn() -> u64) -> Self {
+        Deadline {
+            at_ms: now_ms().saturating_add(total_ms),
+            now_ms,
+        }
+    }
+
+    pub fn remaining_ms(&self) -> u64 {
+        self.at_ms.saturating_sub((self.now_ms)())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining_ms() == 0
+    }
+
+    /// The timeout in seconds to give a request so that it ends by the deadline,
+    /// or `None` if it has passed. Request timeouts are whole seconds, so this
+    /// is rounded up: an awaited response can arrive up to a second late.
+    pub fn timeout_secs(&self) -> Option<u64> {
+        match self.remaining_ms() {
+            0 => None,
+            remaining_ms => Some(remaining_ms.div_ceil(1000)),
+        }
+    }
+}
+
+/// [`now_ms()`], or 0 if the clock reads before the UNIX epoch, for stamping
+/// records where an error would only be ignored.
+pub(crate) fn wall_clock_ms() -> u64 {
+    now_ms().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Address;
+
+    fn response(source: &str, context: Option<Vec<u8>>) -> Message {
+        Message::Response {
+            source: source.parse::<Address>().unwrap(),
+            body: vec![],
+            metadata: None,
+            context,
+            capabilities: vec![],
+        }
+    }
+
+    #[test]
+    fn test_timer_action_body() {
+        let body: Vec<u8> = TimerAction::SetTimer(1500).into();
+        assert_eq!(body, br#"{"SetTimer":1500}"#);
+    }
+
+    #[test]
+    fn test_is_timer_response() {
+        assert!(is_timer_response_from(
+            &response("our.os@timer:distro:sys", None),
+            "our.os"
+        ));
+        // another node's timer, another process, or a request are not our timers firing
+        assert!(!is_timer_response_from(
+            &response("them.os@timer:distro:sys", None),
+            "our.os"
+        ));
+        assert!(!is_timer_response_from(
+            &response("our.os@timer:app:sys", None),
+            "our.os"
+        ));
+        let request = Message::Request {
+            source: "our.os@timer:distro:sys".parse().unwrap(),
+            expects_response: None,
+            body: vec![],
+            metadata: None,
+            capabilities: vec![],
+        };
+        assert!(!is_timer_response_from(&request, "our.os"));
+    }
+
+    #[test]
+    fn test_context_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Job {
+            Refresh { feed: String },
+            Cleanup,
+        }
+        let job = Job::Refresh {
+            feed: "prices".to_string(),
+        };
+        let message = response(
+            "our.os@timer:distro:sys",
+            Some(serde_json::to_vec(&job).unwrap()),
+        );
+        assert_eq!(parse_context::<Job>(&message).unwrap(), Some(job));
+        assert_eq!(
+            parse_context::<Job>(&response("our.os@timer:distro:sys", None)).unwrap(),
+            None
+        );
+        assert!(parse_context::<Job>(&response(
+            "our.os@timer:distro:sys",
+            Some(b"not json".to_vec())
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_schedule_cancellation_via_generation() {
+        let mut schedule = Schedule::new();
+        let first = schedule.add("poll", Some(1_000), 1_000, 0);
+        assert_eq!(first.duration, 1_000);
+        assert_eq!(first.context.generation, 1);
+
+        // a recurring firing re-arms with the same generation
+        let (fired, arm) = schedule.fire(&first.context, 1_000).unwrap();
+        assert_eq!(
+            fired,
+            Fired {
+                tag: "poll".to_string(),
+                recurring: true
+            }
+        );
+        let arm = arm.unwrap();
+        assert_eq!(
+            arm,
+            Arm {
+                duration: 1_000,
+                context: first.context.clone()
+            }
+        );
+
+        // after cancelling, the armed timer's firing is stale
+        assert!(schedule.cancel("poll"));
+        assert!(schedule.fire(&arm.context, 2_000).is_none());
+        assert!(!schedule.cancel("poll"));
+
+        // re-adding the tag never revives old generations
+        let second = schedule.add("poll", Some(500), 2_500, 2_000);
+        assert_eq!(second.context.generation, 3);
+        assert!(schedule.fire(&first.context, 2_500).is_none());
+        assert!(schedule.fire(&second.context, 2_500).is_some());
+
+        // one-shot entries are removed when they fire
+        let once = schedule.add("report", None, 5_000, 3_000);
+        assert_eq!(once.duration, 2_000);
+        let (fired, arm) = schedule.fire(&once.context, 5_000).unwrap();
+        assert!(!fired.recurring);
+        assert!(arm.is_none());
+        assert!(schedule.fire(&once.context, 5_001).is_none());
+        assert_eq!(schedule.tags().collect::<Vec<_>>(), vec!["poll"]);
+    }
+
+    #[test]
+    fn test_schedule_restore_and_rearm() {
+        let mut schedule = Schedule::new();
+        let poll = schedule.add("poll", Some(1_000), 11_000, 10_000);
+        let report = schedule.add("report", None, 12_000, 10_000);
+
+        let bytes = serde_json::to_vec(&schedule).unwrap();
+        let mut restored: Schedule = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(restored, schedule);
+
+        // restart at 11_500: poll is overdue, report is 500ms out
+        let arms = restored.rearm_at(11_500);
+        assert_eq!(arms.len(), 2);
+        assert_eq!(arms[0].context.schedule_tag, "poll");
+        assert_eq!(arms[0].duration, 0);
+        assert_eq!(arms[1].context.schedule_tag, "report");
+        assert_eq!(arms[1].duration, 500);
+
+        // timers armed before the restart are stale, the new ones fire
+        assert!(restored.fire(&poll.context, 11_500).is_none());
+        assert!(restored.fire(&report.context, 12_000).is_none());
+        assert!(restored.fire(&arms[0].context, 11_500).is_some());
+        assert!(restored.fire(&arms[1].context, 12_000).is_some());
+    }
+
+    #[test]
+    fn test_now_ms_cache() {
+        use std::time::{Duration, Instant};
+        let taken = Instant::now();
+        let cached = Some((taken, 1_700_000_000_000));
+        assert_eq!(fresh_cached(None, taken, 100), None);
+        assert_eq!(fresh_cached(cached, taken, 0), Some(1_700_000_000_000));
+        assert_eq!(
+            fresh_cached(cached, taken + Duration::from_millis(100), 100),
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(
+            fresh_cached(cached, taken + Duration::from_millis(101), 100),
+            None
+        );
+
+        let first = now_ms_cached(60_000).unwrap();
+        assert_eq!(now_ms_cached(60_000).unwrap(), first);
+        assert!(now_ms().unwrap() >= first);
+    }
+
+    #[test]
+    fn test_stopwatch() {
+        let mut stopwatch = Stopwatch::start();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(stopwatch.elapsed_ms() >= 5);
+        assert!(stopwatch.lap_ms() >= 5);
+        assert!(stopwatch.elapsed_ms() < 5);
+    }
+
+    thread_local! {
+        static CLOCK_MS: std::cell::Cell<u64> = const { std::cell::Cell::new(10_000) };
+    }
+
+    fn clock() -> u64 {
+        CLOCK_MS.get()
+    }
+
+    #[test]
+    fn test_refused_timers_are_errors() {
+        fn refuse(_: &mut crate::hooks::RequestSnapshot) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("refused"))
+        }
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        crate::hooks::on_send(refuse);
+
+        let refused = |result| matches!(result, Err(BuildError::Hook(_)));
+        assert!(refused(set_timer(10, None)));
+        assert!(refused(set_and_await_timer(10).map(|_| ())));
+        assert!(refused(Schedule::new().every(1_000, "tick")));
+        assert!(sleep(10).is_err());
+        assert!(host.take_calls().is_empty());
+        crate::hooks::clear();
+    }
+
+    #[test]
+    fn test_deadline_budget_across_calls() {
+        use crate::host::{Call, MockHost, Reply};
+        use crate::BuildError;
+
+        let host = MockHost::new();
+        let _installed = host.install();
+        let deadline = Deadline::in_ms_with(5_000, clock);
+        let call = |step: &str| {
+            Request::to(("our", "worker", "app", "sys"))
+                .body(step)
+                .send_and_await_with_deadline(&deadline)
+        };
+
+        for (step, took_ms) in [("fetch", 1_200), ("index", 3_000), ("store", 0)] {
+            host.reply(Reply::body("done"));
+            call(step).unwrap().unwrap();
+            CLOCK_MS.set(CLOCK_MS.get() + took_ms);
+        }
+        // each got what was left, rounded up to whole seconds
+        let timeouts: Vec<Option<u64>> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, .. } => Some(request.expects_response),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(timeouts, [Some(5), Some(4), Some(1)]);
+        assert_eq!(deadline.remaining_ms(), 800);
+
+        CLOCK_MS.set(CLOCK_MS.get() + 800);
+        assert!(deadline.is_expired());
+        assert!(matches!(call("late"), Err(BuildError::DeadlineExceeded)));
+        assert!(host.take_calls().is_empty());
+    }
 }
@@ -0,0 +1,408 @@
+use crate::timer::Stopwatch;
+use crate::{types::message::metadata_object, Address, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// The metadata key trace context travels under.
+pub const TRACE_KEY: &str = "__trace";
+/// The metadata key the sender's own metadata is kept under when it can't be
+/// merged with [`TRACE_KEY`], because it is not a JSON object.
+pub const USER_METADATA_KEY: &str = "__metadata";
+/// Records buffered past this many are dropped, oldest first.
+pub const MAX_BUFFERED_RECORDS: usize = 10_000;
+
+/// Identifies a chain of requests across processes (the trace) and one hop in
+/// it (the span).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Start a new trace.
+    pub fn new_root() -> Self {
+        TraceContext {
+            trace_id: format!("{:032x}", rand::random::<u128>()),
+            span_id: new_span_id(),
+            parent_span_id: None,
+        }
+    }
+
+    /// A new span in the same trace, whose parent is this one.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            trace_id: self.trace_id.clone(),
+            span_id: new_span_id(),
+            parent_span_id: Some(self.span_id.clone()),
+        }
+    }
+}
+
+fn new_span_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// A timing record, as buffered by [`record()`] and the send and receive hooks
+/// and written by [`flush_to()`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub trace_id: String,
+    pub span_id: String,
+    pub event: String,
+    /// Milliseconds since the current trace context was entered: since the
+    /// Request that carried it arrived, or since it was started here.
+    pub elapsed_ms: u64,
+}
+
+struct Tracer {
+    enabled: bool,
+    print: bool,
+    current: Option<(TraceContext, Stopwatch)>,
+    records: VecDeque<TraceRecord>,
+}
+
+thread_local! {
+    static TRACER: RefCell<Tracer> = const {
+        RefCell::new(Tracer {
+            enabled: false,
+            print: false,
+            current: None,
+            records: VecDeque::new(),
+        })
+    };
+}
+
+/// Start tracing: from now on every Request sent carries trace context in its
+/// metadata, trace context is taken out of the metadata of every message received
+/// with [`crate::await_message()`] (so handlers see the sender's metadata as it
+/// was), and sends and receives are recorded.
+pub fn enable() {
+    TRACER.with_borrow_mut(|tracer| tracer.enabled = true);
+}
+
+/// Stop tracing. Buffered records are kept until [`flush_to()`] or [`take_records()`].
+pub fn disable() {
+    TRACER.with_borrow_mut(|tracer| {
+        tracer.enabled = false;
+        tracer.current = None;
+    });
+}
+
+pub fn is_enabled() -> bool {
+    TRACER.with_borrow(|tracer| tracer.enabled)
+}
+
+/// Also print each record with [`crate::debug!`] as it is made.
+pub fn print_records(print: bool) {
+    TRACER.with_borrow_mut(|tracer| tracer.print = print);
+}
+
+/// The trace context of the Request being handled, or of the trace started by
+/// this process since it last received a Request, if any.
+pub fn current() -> Option<TraceContext> {
+    TRACER.with_borrow(|tracer| tracer.current.as_ref().map(|(context, _)| context.clone()))
+}
+
+/// Record event in the current trace, starting one if there is none.
+/// Does nothing unless tracing is [`enable()`]d.
+pub fn record(event: &str) {
+    TRACER.with_borrow_mut(|tracer| {
+        if tracer.enabled {
+            let context = tracer.current_or_root().clone();
+            tracer.push(&context, event);
+        }
+    });
+}
+
+/// Remove and return the buffered records.
+pub fn take_records() -> Vec<TraceRecord> {
+    TRACER.with_borrow_mut(|tracer| tracer.records.drain(..).collect())
+}
+
+/// Append the buffered records, as JSON lines, to the vfs file at path, creating
+/// it if needed. The records are only removed from the buffer once written.
+pub fn flush_to(path: &str) -> anyhow::Result<()> {
+    let (count, lines) = TRACER.with_borrow(|tracer| {
+        let mut lines = vec![];
+        for record in &tracer.records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        Ok::<_, serde_json::Error>((tracer.records.len(), lines))
+    })?;
+    if count == 0 {
+        return Ok(());
+    }
+    let mut file = crate::vfs::open_file(path, true, Some(5))?;
+    file.append(&lines)?;
+    TRACER.with_borrow_mut(|tracer| {
+        tracer.records.drain(..count.min(tracer.records.len()));
+    });
+    Ok(())
+}
+
+impl Tracer {
+    fn current_or_root(&mut self) -> &TraceContext {
+        &self
+            .current
+            .get_or_insert_with(|| (TraceContext::new_root(), Stopwatch::start()))
+            .0
+    }
+
+    fn push(&mut self, context: &TraceContext, event: &str) {
+        let elapsed_ms = self
+            .current
+            .as_ref()
+            .map(|(_, stopwatch)| stopwatch.elapsed_ms())
+            .unwrap_or_default();
+        let record = TraceRecord {
+            trace_id: context.trace_id.clone(),
+            span_id: context.span_id.clone(),
+            event: event.to_string(),
+            elapsed_ms,
+        };
+        if self.print {
+            crate::debug!(
+                "trace {} span {} +{}ms: {}",
+                record.trace_id,
+                record.span_id,
+                record.elapsed_ms,
+                record.event
+            );
+        }
+        if self.records.len() >= MAX_BUFFERED_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+/// Metadata carrying context alongside the sender's metadata. A JSON object
+/// gets a [`TRACE_KEY`] entry; anything else, including no metadata and objects
+/// that already use one of the reserved keys, is kept verbatim under
+/// [`USER_METADATA_KEY`]. [`extract_trace()`] undoes this.
+pub fn merge_trace(metadata: Option<&str>, context: &TraceContext) -> String {
+    let mut object = match metadata.map(|m| metadata_object(Some(m))) {
+        Some(Ok(object))
+            if !object.contains_key(TRACE_KEY) && !object.contains_key(USER_METADATA_KEY) =>
+        {
+            object
+        }
+        _ => {
+            let mut object = Map::new();
+            object.insert(
+                USER_METADATA_KEY.to_string(),
+                metadata.map_or(Value::Null, |m| Value::String(m.to_string())),
+            );
+            object
+        }
+    };
+    object.insert(
+        TRACE_KEY.to_string(),
+        serde_json::to_value(context).unwrap(),
+    );
+    Value::Object(object).to_string()
+}
+
+/// Split metadata made by [`merge_trace()`] into the trace context and the
+/// sender's metadata. Metadata without trace context is returned unchanged.
+/// Object metadata comes back as the same JSON, though not necessarily with the
+/// same formatting or key order.
+pub fn extract_trace(metadata: Option<&str>) -> (Option<TraceContext>, Option<String>) {
+    let Some(text) = metadata else {
+        return (None, None);
+    };
+    let Ok(mut object) = metadata_object(Some(text)) else {
+        return (None, Some(text.to_string()));
+    };
+    let Some(context) = object
+        .get(TRACE_KEY)
+        .and_then(|trace| TraceContext::deserialize(trace).ok())
+    else {
+        return (None, Some(text.to_string()));
+    };
+    object.remove(TRACE_KEY);
+    let user = match object.remove(USER_METADATA_KEY) {
+        Some(Value::String(user)) if object.is_empty() => Some(user),
+        Some(Value::Null) if object.is_empty() => None,
+        wrapped => {
+            if let Some(wrapped) = wrapped {
+                object.insert(USER_METADATA_KEY.to_string(), wrapped);
+            }
+            Some(Value::Object(object).to_string())
+        }
+    };
+    (Some(context), user)
+}
+
+/// The metadata to send a Request to target with: metadata as given unless
+/// tracing is enabled, in which case a child span of the current trace is merged in.
+pub(crate) fn on_send(target: &Address, metadata: Option<String>) -> Option<String> {
+    TRACER.with_borrow_mut(|tracer| {
+        if !tracer.enabled {
+            return metadata;
+        }
+        let span = tracer.current_or_root().child();
+        tracer.push(&span, &format!("send to {target}"));
+        Some(merge_trace(metadata.as_deref(), &span))
+    })
+}
+
+/// Take the trace context out of a received message's metadata. A Request makes
+/// its context (or none, if it carries none) the current one; a Response is
+/// recorded in the current trace.
+pub(crate) fn on_receive(message: &mut Message) {
+    TRACER.with_borrow_mut(|tracer| {
+        if !tracer.enabled {
+            return;
+        }
+        let (source, is_request, metadata) = match message {
+            Message::Request {
+                source, metadata, ..
+            } => (source.to_string(), true, metadata),
+            Message::Response {
+                source, metadata, ..
+            } => (source.to_string(), false, metadata),
+        };
+        let (context, user) = extract_trace(metadata.as_deref());
+        *metadata = user;
+        if is_request {
+            tracer.current = context.map(|context| (context, Stopwatch::start()));
+            if let Some((context, _)) = tracer.current.clone() {
+                tracer.push(&context, &format!("receive from {source}"));
+            }
+        } else if let Some((context, _)) = tracer.current.clone() {
+            tracer.push(&context, &format!("response from {source}"));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> TraceContext {
+        TraceContext {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_string(),
+            span_id: "b7ad6b7169203331".to_string(),
+            parent_span_id: None,
+        }
+    }
+
+    #[test]
+    fn test_merge_extract_round_trips() {
+        for metadata in [
+            None,
+            Some(""),
+            Some("plain text"),
+            Some("42"),
+            Some("[1,2]"),
+            Some("{not json"),
+            Some(r#"{"__trace":"mine"}"#),
+            Some(r#"{"__metadata":"mine"}"#),
+        ] {
+            let merged = merge_trace(metadata, &context());
+            assert_eq!(
+                extract_trace(Some(&merged)),
+                (Some(context()), metadata.map(str::to_string)),
+                "{metadata:?}"
+            );
+        }
+
+        // objects are merged with, so receivers that don't trace still see their keys
+        let merged = merge_trace(Some(r#"{"user":"alice","n":1}"#), &context());
+        let value: Value = serde_json::from_str(&merged).unwrap();
+        assert_eq!(value["user"], "alice");
+        assert_eq!(value[TRACE_KEY]["span_id"], "b7ad6b7169203331");
+        let (extracted, user) = extract_trace(Some(&merged));
+        assert_eq!(extracted, Some(context()));
+        assert_eq!(
+            serde_json::from_str::<Value>(&user.unwrap()).unwrap(),
+            serde_json::json!({"user": "alice", "n": 1})
+        );
+        assert_eq!(
+            extract_trace(Some(&merge_trace(Some("{}"), &context())))
+                .1
+                .as_deref(),
+            Some("{}")
+        );
+    }
+
+    #[test]
+    fn test_extract_without_trace_is_unchanged() {
+        assert_eq!(extract_trace(None), (None, None));
+        for metadata in ["text", r#"{"a": 1}"#, r#"{"__trace": 5}"#] {
+            assert_eq!(
+                extract_trace(Some(metadata)),
+                (None, Some(metadata.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_child_span() {
+        let child = context().child();
+        assert_eq!(child.trace_id, context().trace_id);
+        assert_ne!(child.span_id, context().span_id);
+        assert_eq!(child.parent_span_id.as_deref(), Some("b7ad6b7169203331"));
+        assert_eq!(TraceContext::new_root().trace_id.len(), 32);
+    }
+
+    #[test]
+    fn test_send_and_receive_hooks() {
+        let target: Address = "our.os@server:app:pub.os".parse().unwrap();
+        // nothing happens until tracing is enabled
+        assert_eq!(on_send(&target, Some("m".into())), Some("m".to_string()));
+        record("ignored");
+        assert!(take_records().is_empty());
+
+        enable();
+        let mut incoming = Message::Request {
+            source: "our.os@client:app:pub.os".parse().unwrap(),
+            expects_response: None,
+            body: vec![],
+            metadata: Some(merge_trace(Some("m"), &context())),
+            capabilities: vec![],
+        };
+        on_receive(&mut incoming);
+        assert_eq!(incoming.metadata(), Some("m"));
+        assert_eq!(current(), Some(context()));
+
+        let sent = on_send(&target, None);
+        let (span, user) = extract_trace(sent.as_deref());
+        let span = span.unwrap();
+        assert_eq!(user, None);
+        assert_eq!(span.trace_id, context().trace_id);
+        assert_eq!(span.parent_span_id.as_deref(), Some("b7ad6b7169203331"));
+        record("done");
+
+        let records = take_records();
+        let events: Vec<_> = records.iter().map(|r| r.event.as_str()).collect();
+        assert_eq!(
+            events,
+            [
+                "receive from our.os@client:app:pub.os",
+                "send to our.os@server:app:pub.os",
+                "done"
+            ]
+        );
+        assert_eq!(records[1].span_id, span.span_id);
+        assert!(records.iter().all(|r| r.trace_id == context().trace_id));
+
+        // a request without context ends the trace, and the next send starts one
+        let mut untraced = incoming.clone();
+        if let Message::Request { metadata, .. } = &mut untraced {
+            *metadata = None;
+        }
+        on_receive(&mut untraced);
+        assert_eq!(current(), None);
+        let (span, _) = extract_trace(on_send(&target, None).as_deref());
+        assert_ne!(span.unwrap().trace_id, context().trace_id);
+        disable();
+    }
+}
@@ -0,0 +1,1136 @@
+//! Move files larger than a single blob between processes, usually on different
+//! nodes, in numbered chunks.
+//!
+//! # Protocol
+//!
+//! Every message is a Request from the sender with a JSON [`TransferRequest`]
+//! body, answered by the receiver with a JSON [`TransferResponse`]:
+//!
+//! 1. `Offer` announces the file. The receiver answers `Accepted` with the chunks
+//!    it already has, which is empty for a new transfer and lists what arrived
+//!    before an interruption for a resumed one.
+//! 2. `Chunk` carries chunk `seq` (bytes `seq * chunk_size ..`) in its blob; the
+//!    receiver writes it and answers `Ack`. The sender keeps up to a window of
+//!    chunks unacknowledged and sends a chunk again if its send fails.
+//! 3. `Manifest` gives the total size and SHA-256 hash once every chunk is acked.
+//!    The receiver answers `Missing` if it lacks chunks, which the sender sends
+//!    again before a new `Manifest`, and otherwise checks the hash of what it
+//!    wrote and answers `Complete` or `HashMismatch`.
+//!
+//! A `Rejected` answer to anything ends the transfer. [`Sender`] and [`Receiver`]
+//! both serialize, so either side can keep its progress in its state and
+//! carry on after restarting.
+use crate::vfs::{self, SeekFrom};
+use crate::{get_blob, Address, Message, Request, Response, SendError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Identifies a transfer in every message of it. Derived from the sender's
+/// address, the file's path and its hash, so it is the same when a transfer is
+/// started again, but not when another sender or file has the same contents.
+pub type TransferId = String;
+
+/// Default number of chunks a [`Sender`] keeps unacknowledged.
+pub const DEFAULT_WINDOW: usize = 8;
+/// Default time the receiver has to answer each message, in seconds.
+pub const DEFAULT_TIMEOUT: u64 = 30;
+/// How many failed sends in a row, without any progress in between, end a transfer.
+pub const MAX_RETRIES: u32 = 5;
+
+/// What the sender sends. See the [module documentation](self).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransferRequest {
+    Offer {
+        id: TransferId,
+        name: String,
+        size: u64,
+        chunk_size: u64,
+    },
+    /// The chunk's bytes are the blob.
+    Chunk { id: TransferId, seq: u64 },
+    Manifest {
+        id: TransferId,
+        size: u64,
+        hash: [u8; 32],
+    },
+}
+
+/// What the receiver answers. See the [module documentation](self).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransferResponse {
+    Accepted { have: Vec<u64> },
+    Ack { seq: u64 },
+    Missing { seqs: Vec<u64> },
+    Complete,
+    HashMismatch { actual: [u8; 32] },
+    Rejected { reason: String },
+}
+
+/// Where a transfer stands, as reported by [`Sender::handle()`] and [`Receiver::handle()`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Status {
+    InProgress,
+    Complete,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Progress {
+    pub id: TransferId,
+    /// Bytes sent and acknowledged, or received.
+    pub bytes: u64,
+    pub size: u64,
+    pub status: Status,
+}
+
+fn transfer_id(source: &Address, path: &str, hash: &[u8; 32]) -> TransferId {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::new()
+        .chain_update(source.to_string())
+        .chain_update([0])
+        .chain_update(path)
+        .chain_update([0])
+        .chain_update(hash)
+        .finalize();
+    digest[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn chunk_count(size: u64, chunk_size: u64) -> u64 {
+    size.div_ceil(chunk_size)
+}
+
+/// The length of chunk seq of a file of size bytes.
+fn chunk_len(size: u64, chunk_size: u64, seq: u64) -> u64 {
+    chunk_size.min(size.saturating_sub(seq * chunk_size))
+}
+
+/// Which message a response or [`SendError`] is about, sent as the request context.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum Sent {
+    Offer,
+    Chunk(u64),
+    Manifest,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Context {
+    id: TransferId,
+    sent: Sent,
+}
+
+/// What the sender needs from the runtime, so that it can be exercised without one.
+trait SenderIo {
+    fn read_chunk(&mut self, path: &str, offset: u64, len: u64) -> anyhow::Result<Vec<u8>>;
+    fn send(
+        &mut self,
+        sender: &Sender,
+        body: TransferRequest,
+        sent: Sent,
+        blob: Option<Vec<u8>>,
+    ) -> anyhow::Result<()>;
+}
+
+struct SenderKernel;
+
+impl SenderIo for SenderKernel {
+    fn read_chunk(&mut self, path: &str, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+        let mut file = vfs::open_file(path, false, None)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = vec![0; len as usize];
+        let read = file.read_at(&mut buffer)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    fn send(
+        &mut self,
+        sender: &Sender,
+        body: TransferRequest,
+        sent: Sent,
+        blob: Option<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        let context = Context {
+            id: sender.id.clone(),
+            sent,
+        };
+        let mut request = Request::to(&sender.target)
+            .body(serde_json::to_vec(&body).unwrap())
+            .context(serde_json::to_vec(&context).unwrap())
+            .expects_response(sender.timeout);
+        if let Some(bytes) = blob {
+            request = request.blob_bytes(bytes);
+        }
+        Ok(request.send()?)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum Stage {
+    Offering,
+    Sending,
+    Finishing,
+    Done(Status),
+}
+
+/// The sending side of a transfer, started by [`send_file()`]. Feed it every
+/// message the process receives with [`Sender::handle()`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Sender {
+    id: TransferId,
+    target: Address,
+    path: String,
+    size: u64,
+    chunk_size: u64,
+    hash: [u8; 32],
+    window: usize,
+    timeout: u64,
+    stage: Stage,
+    /// Chunks the receiver has.
+    acked: BTreeSet<u64>,
+    in_flight: BTreeSet<u64>,
+    /// Chunks to send again, before any new ones.
+    resend: BTreeSet<u64>,
+    /// The next chunk never sent.
+    next_seq: u64,
+    failures: u32,
+}
+
+/// Start sending the file at vfs_path to target in chunks of chunk_size bytes,
+/// with the default window and timeout. This sends the offer; hand every
+/// message the process receives to [`Sender::handle()`] to send the rest.
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, transfer};
+///
+/// let peer = "friend.os@files:file-share:publisher.os".parse().unwrap();
+/// let mut sender = transfer::send_file(peer, "/file-share:publisher.os/big.bin", 256 * 1024).unwrap();
+/// loop {
+///     let message = await_message();
+///     if let Some(progress) = sender.handle(&message) {
+///         println!("{}/{} bytes", progress.bytes, progress.size);
+///         if progress.status != transfer::Status::InProgress {
+///             break;
+///         }
+///     }
+/// }
+/// ```
+pub fn send_file(target: Address, vfs_path: &str, chunk_size: u64) -> anyhow::Result<Sender> {
+    let mut file = vfs::open_file(vfs_path, false, None)?;
+    let size = file.metadata()?.len;
+    let hash = file.hash()?;
+    let mut sender = Sender::new(crate::our(), target, vfs_path, size, chunk_size, hash)?;
+    sender.offer(&mut SenderKernel);
+    if let Status::Failed(reason) = sender.progress().status {
+        return Err(anyhow::anyhow!("transfer: offer failed: {reason}"));
+    }
+    Ok(sender)
+}
+
+impl Sender {
+    fn new(
+        source: &Address,
+        target: Address,
+        path: &str,
+        size: u64,
+        chunk_size: u64,
+        hash: [u8; 32],
+    ) -> anyhow::Result<Self> {
+        if chunk_size == 0 {
+            return Err(anyhow::anyhow!("chunk size must be positive"));
+        }
+        Ok(Sender {
+            id: transfer_id(source, path, &hash),
+            target,
+            path: path.to_string(),
+            size,
+            chunk_size,
+            hash,
+            window: DEFAULT_WINDOW,
+            timeout: DEFAULT_TIMEOUT,
+            stage: Stage::Offering,
+            acked: BTreeSet::new(),
+            in_flight: BTreeSet::new(),
+            resend: BTreeSet::new(),
+            next_seq: 0,
+            failures: 0,
+        })
+    }
+
+    /// Set how many chunks may be unacknowledged at once. Takes effect as acks arrive.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Set how long the receiver has to answer each message, in seconds.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            id: self.id.clone(),
+            bytes: self
+                .acked
+                .iter()
+                .map(|seq| chunk_len(self.size, self.chunk_size, *seq))
+                .sum(),
+            size: self.size,
+            status: match &self.stage {
+                Stage::Done(status) => status.clone(),
+                _ => Status::InProgress,
+            },
+        }
+    }
+
+    /// If message is the receiver's answer to, or the failure of, one of this
+    /// transfer's messages, act on it and return the progress. Otherwise `None`.
+    pub fn handle(&mut self, message: &Result<Message, SendError>) -> Option<Progress> {
+        self.handle_with(&mut SenderKernel, message)
+    }
+
+    /// Send the offer again, as after restarting with a saved `Sender`: the
+    /// receiver answers with the chunks it has, and the rest are sent. If the
+    /// offer can't be sent, the transfer fails.
+    pub fn resume(&mut self) {
+        if !matches!(self.stage, Stage::Done(_)) {
+            self.offer(&mut SenderKernel);
+        }
+    }
+
+    fn offer<I: SenderIo>(&mut self, io: &mut I) {
+        self.stage = Stage::Offering;
+        self.in_flight.clear();
+        let name = self.path.rsplit('/').next().unwrap_or_default().to_string();
+        let offer = TransferRequest::Offer {
+            id: self.id.clone(),
+            name,
+            size: self.size,
+            chunk_size: self.chunk_size,
+        };
+        self.send(io, offer, Sent::Offer, None);
+    }
+
+    /// Send body, or if it is refused, end the transfer. Returns whether it was sent.
+    fn send<I: SenderIo>(
+        &mut self,
+        io: &mut I,
+        body: TransferRequest,
+        sent: Sent,
+        blob: Option<Vec<u8>>,
+    ) -> bool {
+        match io.send(self, body, sent, blob) {
+            Ok(()) => true,
+            Err(e) => {
+                self.stage = Stage::Done(Status::Failed(format!("{e:#}")));
+                false
+            }
+        }
+    }
+
+    fn handle_with<I: SenderIo>(
+        &mut self,
+        io: &mut I,
+        message: &Result<Message, SendError>,
+    ) -> Option<Progress> {
+        if matches!(self.stage, Stage::Done(_)) {
+            return None;
+        }
+        let (context, response) = match message {
+            Ok(Message::Response {
+                source,
+                body,
+                context,
+                ..
+            }) if source == &self.target => (
+                context.as_deref()?,
+                Some(serde_json::from_slice::<TransferResponse>(body).ok()?),
+            ),
+            Err(error) if error.target == self.target => (error.context.as_deref()?, None),
+            _ => return None,
+        };
+        let context: Context = serde_json::from_slice(context).ok()?;
+        if context.id != self.id {
+            return None;
+        }
+        match response {
+            Some(response) => self.on_response(io, context.sent, response),
+            None => self.on_failure(io, context.sent),
+        }
+        Some(self.progress())
+    }
+
+    fn on_response<I: SenderIo>(&mut self, io: &mut I, sent: Sent, response: TransferResponse) {
+        self.failures = 0;
+        match (sent, response) {
+            (Sent::Offer, TransferResponse::Accepted { have }) => {
+                self.acked = have.into_iter().collect();
+                self.resend.clear();
+                self.next_seq = 0;
+                self.stage = Stage::Sending;
+            }
+            (Sent::Chunk(seq), TransferResponse::Ack { .. }) => {
+                self.in_flight.remove(&seq);
+                self.acked.insert(seq);
+            }
+            (Sent::Manifest, TransferResponse::Missing { seqs }) => {
+                for seq in seqs {
+                    self.acked.remove(&seq);
+                    self.resend.insert(seq);
+                }
+                self.stage = Stage::Sending;
+            }
+            (Sent::Manifest, TransferResponse::Complete) => {
+                self.stage = Stage::Done(Status::Complete);
+            }
+            (Sent::Manifest, TransferResponse::HashMismatch { .. }) => {
+                self.stage = Stage::Done(Status::Failed("hash mismatch".to_string()));
+            }
+            (_, TransferResponse::Rejected { reason }) => {
+                self.stage = Stage::Done(Status::Failed(reason));
+            }
+            // answers to messages from before a resume
+            _ => {}
+        }
+        self.pump(io);
+    }
+
+    fn on_failure<I: SenderIo>(&mut self, io: &mut I, sent: Sent) {
+        self.failures += 1;
+        if self.failures > MAX_RETRIES {
+            self.stage = Stage::Done(Status::Failed(format!(
+                "{} sends to {} failed in a row",
+                self.failures, self.target
+            )));
+            return;
+        }
+        match sent {
+            Sent::Offer => self.offer(io),
+            Sent::Chunk(seq) => {
+                if self.in_flight.remove(&seq) {
+                    self.resend.insert(seq);
+                }
+                self.pump(io);
+            }
+            Sent::Manifest if self.stage == Stage::Finishing => {
+                self.stage = Stage::Sending;
+                self.pump(io);
+            }
+            Sent::Manifest => {}
+        }
+    }
+
+    /// Fill the window with chunks, or send the manifest once they are all acked.
+    fn pump<I: SenderIo>(&mut self, io: &mut I) {
+        if self.stage != Stage::Sending {
+            return;
+        }
+        let chunks = chunk_count(self.size, self.chunk_size);
+        while self.in_flight.len() < self.window {
+            let seq = match self.resend.pop_first() {
+                Some(seq) => seq,
+                None => {
+                    while self.next_seq < chunks && self.acked.contains(&self.next_seq) {
+                        self.next_seq += 1;
+                    }
+                    if self.next_seq == chunks {
+                        break;
+                    }
+                    self.next_seq += 1;
+                    self.next_seq - 1
+                }
+            };
+            let offset = seq * self.chunk_size;
+            let len = chunk_len(self.size, self.chunk_size, seq);
+            match io.read_chunk(&self.path, offset, len) {
+                Ok(bytes) => {
+                    let chunk = TransferRequest::Chunk {
+                        id: self.id.clone(),
+                        seq,
+                    };
+                    if !self.send(io, chunk, Sent::Chunk(seq), Some(bytes)) {
+                        return;
+                    }
+                    self.in_flight.insert(seq);
+                }
+                Err(e) => {
+                    self.stage = Stage::Done(Status::Failed(format!("{e:#}")));
+                    return;
+                }
+            }
+        }
+        if self.in_flight.is_empty() && self.acked.len() as u64 >= chunks {
+            self.stage = Stage::Finishing;
+            let manifest = TransferRequest::Manifest {
+                id: self.id.clone(),
+                size: self.size,
+                hash: self.hash,
+            };
+            self.send(io, manifest, Sent::Manifest, None);
+        }
+    }
+}
+
+/// An incoming transfer, as tracked by a [`Receiver`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Incoming {
+    path: String,
+    size: u64,
+    chunk_size: u64,
+    received: BTreeSet<u64>,
+    status: Status,
+}
+
+impl Incoming {
+    fn missing(&self) -> Vec<u64> {
+        (0..chunk_count(self.size, self.chunk_size))
+            .filter(|seq| !self.received.contains(seq))
+            .collect()
+    }
+
+    fn progress(&self, id: &str) -> Progress {
+        Progress {
+            id: id.to_string(),
+            bytes: self
+                .received
+                .iter()
+                .map(|seq| chunk_len(self.size, self.chunk_size, *seq))
+                .sum(),
+            size: self.size,
+            status: self.status.clone(),
+        }
+    }
+}
+
+/// What the receiver needs from the runtime, so that it can be exercised without one.
+trait ReceiverIo {
+    fn create(&mut self, path: &str) -> anyhow::Result<()>;
+    fn write_at(&mut self, path: &str, offset: u64, bytes: &[u8]) -> anyhow::Result<()>;
+    fn hash(&mut self, path: &str) -> anyhow::Result<[u8; 32]>;
+}
+
+struct ReceiverKernel;
+
+impl ReceiverIo for ReceiverKernel {
+    fn create(&mut self, path: &str) -> anyhow::Result<()> {
+        vfs::open_file(path, true, None)?.set_len(0)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut file = vfs::open_file(path, false, None)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)?;
+        Ok(())
+    }
+
+    fn hash(&mut self, path: &str) -> anyhow::Result<[u8; 32]> {
+        Ok(vfs::hash_file(path, None)?)
+    }
+}
+
+/// The receiving side of transfers: writes the files offered to it into a vfs
+/// directory. Feed it every message the process receives with [`Receiver::handle()`].
+///
+/// ```no_run
+/// use hyperware_process_lib::{await_message, transfer::Receiver};
+///
+/// let mut receiver = Receiver::new("/file-share:publisher.os/incoming");
+/// loop {
+///     let Ok(message) = await_message() else { continue };
+///     if let Some(Ok(progress)) = receiver.handle(&message) {
+///         println!("{}: {}/{} bytes", progress.id, progress.bytes, progress.size);
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Receiver {
+    dir: String,
+    transfers: BTreeMap<TransferId, Incoming>,
+}
+
+impl Receiver {
+    /// Write received files into the vfs directory dir, which must exist.
+    pub fn new(dir: &str) -> Self {
+        Receiver {
+            dir: dir.trim_end_matches('/').to_string(),
+            transfers: BTreeMap::new(),
+        }
+    }
+
+    /// If message is a transfer request, act on it, answer it and return the
+    /// progress of its transfer. Fails if the file can not be written to, or
+    /// the answer can not be sent.
+    pub fn handle(&mut self, message: &Message) -> Option<anyhow::Result<Progress>> {
+        let blob = get_blob().map(|blob| blob.bytes);
+        let result = self.handle_with(&mut ReceiverKernel, message, blob)?;
+        if let Ok((Some(response), _)) = &result {
+            if let Message::Request {
+                expects_response: Some(_),
+                ..
+            } = message
+            {
+                let sent = Response::new()
+                    .body(serde_json::to_vec(response).unwrap())
+                    .send();
+                if let Err(e) = sent {
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        Some(result.map(|(_, progress)| progress))
+    }
+
+    /// The progress of every transfer, finished or not.
+    pub fn transfers(&self) -> impl Iterator<Item = Progress> + '_ {
+        self.transfers
+            .iter()
+            .map(|(id, incoming)| incoming.progress(id))
+    }
+
+    /// The vfs path a transfer is written to.
+    pub fn path(&self, id: &str) -> Option<&str> {
+        self.transfers
+            .get(id)
+            .map(|incoming| incoming.path.as_str())
+    }
+
+    /// Forget a transfer, finished or not. The file is left in place.
+    pub fn forget(&mut self, id: &str) -> bool {
+        self.transfers.remove(id).is_some()
+    }
+
+    /// What to answer the transfer request in message with, if anything, and
+    /// the progress of its transfer.
+    fn handle_with<I: ReceiverIo>(
+        &mut self,
+        io: &mut I,
+        message: &Message,
+        blob: Option<Vec<u8>>,
+    ) -> Option<anyhow::Result<(Option<TransferResponse>, Progress)>> {
+        let Message::Request { body, .. } = message else {
+            return None;
+        };
+        let request: TransferRequest = serde_json::from_slice(body).ok()?;
+        Some(self.apply(io, &request, blob))
+    }
+
+    fn apply<I: ReceiverIo>(
+        &mut self,
+        io: &mut I,
+        request: &TransferRequest,
+        blob: Option<Vec<u8>>,
+    ) -> anyhow::Result<(Option<TransferResponse>, Progress)> {
+        let rejected = |id: &str, reason: &str| {
+            let progress = Progress {
+                id: id.to_string(),
+                bytes: 0,
+                size: 0,
+                status: Status::Failed(reason.to_string()),
+            };
+            let response = TransferResponse::Rejected {
+                reason: reason.to_string(),
+            };
+            Ok((Some(response), progress))
+        };
+        match request {
+            TransferRequest::Offer {
+                id,
+                name,
+                size,
+                chunk_size,
+            } => {
+                if let Some(incoming) = self.transfers.get(id) {
+                    if incoming.size == *size && incoming.chunk_size == *chunk_size {
+                        let have = incoming.received.iter().copied().collect();
+                        let response = TransferResponse::Accepted { have };
+                        return Ok((Some(response), incoming.progress(id)));
+                    }
+                }
+                if *chunk_size == 0 {
+                    return rejected(id, "chunk size must be positive");
+                }
+                if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                    return rejected(id, "bad file name");
+                }
+                let path = format!("{}/{name}", self.dir);
+                io.create(&path)?;
+                let incoming = Incoming {
+                    path,
+                    size: *size,
+                    chunk_size: *chunk_size,
+                    received: BTreeSet::new(),
+                    status: Status::InProgress,
+                };
+                let progress = incoming.progress(id);
+                self.transfers.insert(id.clone(), incoming);
+                Ok((Some(TransferResponse::Accepted { have: vec![] }), progress))
+            }
+            TransferRequest::Chunk { id, seq } => {
+                let Some(incoming) = self.transfers.get_mut(id) else {
+                    return rejected(id, "unknown transfer");
+                };
+                let expected = chunk_len(incoming.size, incoming.chunk_size, *seq);
+                let bytes = blob.unwrap_or_default();
+                if *seq >= chunk_count(incoming.size, incoming.chunk_size)
+                    || bytes.len() as u64 != expected
+                {
+                    // answer nothing: the sender's send fails and it tries again
+                    return Ok((None, incoming.progress(id)));
+                }
+                if !incoming.received.contains(seq) {
+                    io.write_at(&incoming.path, seq * incoming.chunk_size, &bytes)?;
+                    incoming.received.insert(*seq);
+                }
+                let response = TransferResponse::Ack { seq: *seq };
+                Ok((Some(response), incoming.progress(id)))
+            }
+            TransferRequest::Manifest { id, size, hash } => {
+                let Some(incoming) = self.transfers.get_mut(id) else {
+                    return rejected(id, "unknown transfer");
+                };
+                if *size != incoming.size {
+                    return rejected(id, "size does not match the offer");
+                }
+                let missing = incoming.missing();
+                if !missing.is_empty() {
+                    let response = TransferResponse::Missing { seqs: missing };
+                    return Ok((Some(response), incoming.progress(id)));
+                }
+                let actual = io.hash(&incoming.path)?;
+                let response = if &actual == hash {
+                    incoming.status = Status::Complete;
+                    TransferResponse::Complete
+                } else {
+                    // start over: every chunk is suspect
+                    incoming.received.clear();
+                    incoming.status = Status::Failed("hash mismatch".to_string());
+                    TransferResponse::HashMismatch { actual }
+                };
+                Ok((Some(response), incoming.progress(id)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    const CHUNK: u64 = 4;
+
+    fn file() -> Vec<u8> {
+        b"0123456789abcdefghij-_".to_vec()
+    }
+
+    fn sha256(bytes: &[u8]) -> [u8; 32] {
+        Sha256::digest(bytes).into()
+    }
+
+    fn peer() -> Address {
+        "peer.os@files:app:pub.os".parse().unwrap()
+    }
+
+    /// Reads chunks of file() and records what is sent, refusing to send the
+    /// chunk numbered refuse.
+    #[derive(Default)]
+    struct MockSender {
+        sent: Vec<(TransferRequest, Option<Vec<u8>>)>,
+        refuse: Option<u64>,
+    }
+
+    impl SenderIo for MockSender {
+        fn read_chunk(&mut self, _: &str, offset: u64, len: u64) -> anyhow::Result<Vec<u8>> {
+            Ok(file()[offset as usize..(offset + len) as usize].to_vec())
+        }
+
+        fn send(
+            &mut self,
+            _: &Sender,
+            body: TransferRequest,
+            sent: Sent,
+            blob: Option<Vec<u8>>,
+        ) -> anyhow::Result<()> {
+            if self.refuse.is_some_and(|seq| sent == Sent::Chunk(seq)) {
+                return Err(crate::BuildError::Hook("refused".to_string()).into());
+            }
+            self.sent.push((body, blob));
+            Ok(())
+        }
+    }
+
+    /// Files in memory.
+    #[derive(Default)]
+    struct MockFiles {
+        files: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl ReceiverIo for MockFiles {
+        fn create(&mut self, path: &str) -> anyhow::Result<()> {
+            self.files.insert(path.to_string(), vec![]);
+            Ok(())
+        }
+
+        fn write_at(&mut self, path: &str, offset: u64, bytes: &[u8]) -> anyhow::Result<()> {
+            let file = self.files.get_mut(path).unwrap();
+            let end = offset as usize + bytes.len();
+            if file.len() < end {
+                file.resize(end, 0);
+            }
+            file[offset as usize..end].copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn hash(&mut self, path: &str) -> anyhow::Result<[u8; 32]> {
+            Ok(sha256(&self.files[path]))
+        }
+    }
+
+    fn sender() -> Sender {
+        let size = file().len() as u64;
+        let source = "sender.os@files:app:pub.os".parse().unwrap();
+        let path = "/app:pub.os/big.bin";
+        Sender::new(&source, peer(), path, size, CHUNK, sha256(&file())).unwrap()
+    }
+
+    // the same shape as await_message()
+    #[allow(clippy::result_large_err)]
+    fn response(
+        sender: &Sender,
+        sent: Sent,
+        response: &TransferResponse,
+    ) -> Result<Message, SendError> {
+        Ok(Message::Response {
+            source: peer(),
+            body: serde_json::to_vec(response).unwrap(),
+            metadata: None,
+            context: Some(
+                serde_json::to_vec(&Context {
+                    id: sender.id.clone(),
+                    sent,
+                })
+                .unwrap(),
+            ),
+            capabilities: vec![],
+        })
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn failure(sender: &Sender, sent: Sent) -> Result<Message, SendError> {
+        let context = Context {
+            id: sender.id.clone(),
+            sent,
+        };
+        Err(SendError {
+            kind: crate::SendErrorKind::Timeout,
+            target: peer(),
+            message: request(&TransferRequest::Offer {
+                id: sender.id.clone(),
+                name: String::new(),
+                size: 0,
+                chunk_size: 1,
+            }),
+            lazy_load_blob: None,
+            context: Some(serde_json::to_vec(&context).unwrap()),
+        })
+    }
+
+    fn request(body: &TransferRequest) -> Message {
+        Message::Request {
+            source: "sender.os@files:app:pub.os".parse().unwrap(),
+            expects_response: Some(30),
+            body: serde_json::to_vec(body).unwrap(),
+            metadata: None,
+            capabilities: vec![],
+        }
+    }
+
+    /// Hand every message the sender sent to the receiver, returning its answers.
+    fn deliver(
+        receiver: &mut Receiver,
+        files: &mut MockFiles,
+        sent: Vec<(TransferRequest, Option<Vec<u8>>)>,
+    ) -> Vec<(TransferRequest, TransferResponse)> {
+        sent.into_iter()
+            .map(|(body, blob)| {
+                let result = receiver.handle_with(files, &request(&body), blob).unwrap();
+                (body, result.unwrap().0.unwrap())
+            })
+            .collect()
+    }
+
+    fn sent_for(request: &TransferRequest) -> Sent {
+        match request {
+            TransferRequest::Offer { .. } => Sent::Offer,
+            TransferRequest::Chunk { seq, .. } => Sent::Chunk(*seq),
+            TransferRequest::Manifest { .. } => Sent::Manifest,
+        }
+    }
+
+    /// Run sender and receiver against each other until neither has anything to say.
+    fn run(
+        sender: &mut Sender,
+        io: &mut MockSender,
+        receiver: &mut Receiver,
+        files: &mut MockFiles,
+    ) {
+        while !io.sent.is_empty() {
+            let sent = std::mem::take(&mut io.sent);
+            for (request, answer) in deliver(receiver, files, sent) {
+                sender.handle_with(io, &response(sender, sent_for(&request), &answer));
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_with_window() {
+        let (mut io, mut files) = (MockSender::default(), MockFiles::default());
+        let mut sender = sender().window(2);
+        let mut receiver = Receiver::new("/app:pub.os/in/");
+        sender.offer(&mut io);
+        let answers = deliver(&mut receiver, &mut files, std::mem::take(&mut io.sent));
+        sender.handle_with(&mut io, &response(&sender, Sent::Offer, &answers[0].1));
+        // only a window's worth of chunks goes out before acks come back
+        assert_eq!(io.sent.len(), 2);
+
+        run(&mut sender, &mut io, &mut receiver, &mut files);
+        assert_eq!(sender.progress().status, Status::Complete);
+        assert_eq!(sender.progress().bytes, file().len() as u64);
+        assert_eq!(files.files["/app:pub.os/in/big.bin"], file());
+        let progress: Vec<_> = receiver.transfers().collect();
+        assert_eq!(progress[0].status, Status::Complete);
+        assert_eq!(receiver.path(sender.id()), Some("/app:pub.os/in/big.bin"));
+    }
+
+    #[test]
+    fn test_out_of_order_chunks_and_gaps() {
+        let mut files = MockFiles::default();
+        let mut receiver = Receiver::new("/in");
+        let id = sender().id;
+        let size = file().len() as u64;
+        let chunk = |seq: u64| {
+            let start = (seq * CHUNK) as usize;
+            let end = (start + CHUNK as usize).min(file().len());
+            (
+                TransferRequest::Chunk {
+                    id: id.clone(),
+                    seq,
+                },
+                Some(file()[start..end].to_vec()),
+            )
+        };
+        let offer = TransferRequest::Offer {
+            id: id.clone(),
+            name: "big.bin".into(),
+            size,
+            chunk_size: CHUNK,
+        };
+        let manifest = (
+            TransferRequest::Manifest {
+                id: id.clone(),
+                size,
+                hash: sha256(&file()),
+            },
+            None,
+        );
+        let answers = deliver(
+            &mut receiver,
+            &mut files,
+            vec![
+                (offer, None),
+                chunk(5),
+                chunk(2),
+                chunk(0),
+                chunk(2),
+                manifest.clone(),
+            ],
+        );
+        assert_eq!(answers[1].1, TransferResponse::Ack { seq: 5 });
+        // the duplicate is acked again, and the gaps are reported at the end
+        assert_eq!(answers[4].1, TransferResponse::Ack { seq: 2 });
+        assert_eq!(
+            answers[5].1,
+            TransferResponse::Missing {
+                seqs: vec![1, 3, 4]
+            }
+        );
+
+        let answers = deliver(
+            &mut receiver,
+            &mut files,
+            vec![chunk(4), chunk(1), chunk(3), manifest],
+        );
+        assert_eq!(answers[3].1, TransferResponse::Complete);
+        assert_eq!(files.files["/in/big.bin"], file());
+
+        // a chunk of the wrong length is not answered, so the sender sends it again
+        let result = receiver
+            .handle_with(&mut files, &request(&chunk(0).0), Some(vec![1]))
+            .unwrap();
+        assert_eq!(result.unwrap().0, None);
+    }
+
+    #[test]
+    fn test_retransmits() {
+        let (mut io, mut files) = (MockSender::default(), MockFiles::default());
+        let mut sender = sender().window(3);
+        let mut receiver = Receiver::new("/in");
+        sender.offer(&mut io);
+        let answers = deliver(&mut receiver, &mut files, std::mem::take(&mut io.sent));
+        sender.handle_with(&mut io, &response(&sender, Sent::Offer, &answers[0].1));
+        let first: Vec<_> = std::mem::take(&mut io.sent);
+        assert_eq!(first.len(), 3);
+
+        // chunk 1 times out: it is sent again, before any new chunk
+        sender.handle_with(&mut io, &failure(&sender, Sent::Chunk(1)));
+        assert!(matches!(
+            io.sent[0].0,
+            TransferRequest::Chunk { seq: 1, .. }
+        ));
+        // chunks 0 and 2 arrive; the retransmitted 1 is dropped on the way
+        io.sent.clear();
+        let delivered = deliver(
+            &mut receiver,
+            &mut files,
+            vec![first[0].clone(), first[2].clone()],
+        );
+        for (request, answer) in delivered {
+            sender.handle_with(&mut io, &response(&sender, sent_for(&request), &answer));
+        }
+        // pretend a stale ack for 1 arrives too, so that only the receiver's Missing
+        // answer to the manifest brings it back
+        sender.in_flight.remove(&1);
+        sender.acked.insert(1);
+        run(&mut sender, &mut io, &mut receiver, &mut files);
+        assert_eq!(sender.progress().status, Status::Complete);
+        assert_eq!(files.files["/in/big.bin"], file());
+
+        // and a receiver that never answers ends the transfer
+        let mut sender = self::sender();
+        let mut io = MockSender::default();
+        for _ in 0..=MAX_RETRIES {
+            sender.handle_with(&mut io, &failure(&sender, Sent::Offer));
+        }
+        assert!(matches!(sender.progress().status, Status::Failed(_)));
+        assert!(sender
+            .handle_with(&mut io, &failure(&sender, Sent::Offer))
+            .is_none());
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let (mut io, mut files) = (MockSender::default(), MockFiles::default());
+        let mut sender = sender();
+        sender.hash = sha256(b"something else");
+        let mut receiver = Receiver::new("/in");
+        sender.offer(&mut io);
+        run(&mut sender, &mut io, &mut receiver, &mut files);
+        assert_eq!(
+            sender.progress().status,
+            Status::Failed("hash mismatch".to_string())
+        );
+        let progress: Vec<_> = receiver.transfers().collect();
+        assert_eq!(
+            progress[0].status,
+            Status::Failed("hash mismatch".to_string())
+        );
+        assert_eq!(progress[0].bytes, 0);
+    }
+
+    #[test]
+    fn test_refused_send_fails_transfer() {
+        let (mut io, mut files) = (MockSender::default(), MockFiles::default());
+        let mut sender = sender().window(3);
+        let mut receiver = Receiver::new("/in");
+        sender.offer(&mut io);
+        let answers = deliver(&mut receiver, &mut files, std::mem::take(&mut io.sent));
+        io.refuse = Some(1);
+        sender.handle_with(&mut io, &response(&sender, Sent::Offer, &answers[0].1));
+        assert!(matches!(sender.progress().status, Status::Failed(_)));
+        // the refused chunk is not waited on
+        assert_eq!(sender.in_flight, BTreeSet::from([0]));
+        assert_eq!(io.sent.len(), 1);
+    }
+
+    #[test]
+    fn test_transfer_ids() {
+        let hash = sha256(&file());
+        let alice: Address = "alice.os@files:app:pub.os".parse().unwrap();
+        let bob: Address = "bob.os@files:app:pub.os".parse().unwrap();
+        let id = transfer_id(&alice, "/app:pub.os/a.bin", &hash);
+        assert_eq!(id.len(), 32);
+        assert_eq!(id, transfer_id(&alice, "/app:pub.os/a.bin", &hash));
+        assert_ne!(id, transfer_id(&bob, "/app:pub.os/a.bin", &hash));
+        assert_ne!(id, transfer_id(&alice, "/app:pub.os/b.bin", &hash));
+    }
+
+    #[test]
+    fn test_resume_from_saved_state() {
+        let (mut io, mut files) = (MockSender::default(), MockFiles::default());
+        let mut sender = sender().window(2);
+        let mut receiver = Receiver::new("/in");
+        sender.offer(&mut io);
+        let answers = deliver(&mut receiver, &mut files, std::mem::take(&mut io.sent));
+        sender.handle_with(&mut io, &response(&sender, Sent::Offer, &answers[0].1));
+        deliver(&mut receiver, &mut files, std::mem::take(&mut io.sent));
+
+        // both sides restart from their saved state; the acks in flight are lost
+        let mut receiver: Receiver =
+            serde_json::from_str(&serde_json::to_string(&receiver).unwrap()).unwrap();
+        let mut sender: Sender =
+            serde_json::from_str(&serde_json::to_string(&sender).unwrap()).unwrap();
+        sender.offer(&mut io);
+        let answers = deliver(&mut receiver, &mut files, std::mem::take(&mut io.sent));
+        assert_eq!(
+            answers[0].1,
+            TransferResponse::Accepted { have: vec![0, 1] }
+        );
+        sender.handle_with(&mut io, &response(&sender, Sent::Offer, &answers[0].1));
+        let resent: Vec<_> = io
+            .sent
+            .iter()
+            .map(|(request, _)| sent_for(request))
+            .collect();
+        assert_eq!(resent, [Sent::Chunk(2), Sent::Chunk(3)]);
+
+        run(&mut sender, &mut io, &mut receiver, &mut files);
+        assert_eq!(sender.progress().status, Status::Complete);
+        assert_eq!(files.files["/in/big.bin"], file());
+    }
+
+    #[test]
+    fn test_rejections() {
+        let mut files = MockFiles::default();
+        let mut receiver = Receiver::new("/in");
+        let unknown = TransferRequest::Chunk {
+            id: "nope".into(),
+            seq: 0,
+        };
+        let bad_name = TransferRequest::Offer {
+            id: "x".into(),
+            name: "../../etc".into(),
+            size: 1,
+            chunk_size: 1,
+        };
+        for request in [unknown, bad_name] {
+            let answers = deliver(&mut receiver, &mut files, vec![(request, None)]);
+            assert!(matches!(answers[0].1, TransferResponse::Rejected { .. }));
+        }
+        assert!(files.files.is_empty());
+        // not a transfer message
+        let other = Message::Request {
+            source: peer(),
+            expects_response: None,
+            body: b"{}".to_vec(),
+            metadata: None,
+            capabilities: vec![],
+        };
+        assert!(receiver.handle_with(&mut files, &other, None).is_none());
+    }
+}
@@ -1,4 +1,5 @@
-pub use crate::{Address, ProcessId, Request};
+use crate::types::process_id::{check_segment, IdSegment};
+pub use crate::{Address, ProcessId, ProcessIdParseError, Request};
 use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 
@@ -47,17 +48,20 @@ impl Address {
     pub fn send_request(&self) -> Request {
         Request::to(self)
     }
-}
 
-impl std::str::FromStr for Address {
-    type Err = AddressParseError;
-    /// Attempt to parse an `Address` from a string. The formatting structure for
-    /// an Address is `node@process_name:package_name:publisher_node`.
-    ///
-    /// The string being parsed must contain exactly one `@` and three `:` characters.
-    /// The `@` character separates the node ID from the rest of the address, and the
-    /// `:` characters separate the process name, package name, and publisher node ID.
-    fn from_str(input: &str) -> Result<Self, AddressParseError> {
+    /// Check each segment against the rules [`Address::from_str()`] enforces, e.g.
+    /// for an `Address` made with [`Address::new()`], which doesn't.
+    pub fn validate(&self) -> Result<(), AddressParseError> {
+        if self.node.is_empty() {
+            return Err(AddressParseError::MissingNodeId);
+        }
+        check_segment(IdSegment::Node, &self.node)?;
+        Ok(self.process.validate()?)
+    }
+
+    /// Parse an `Address` checking only its structure: one `@`, three `:` and no
+    /// empty segments, not which characters they contain.
+    pub fn parse_lenient(input: &str) -> Result<Self, AddressParseError> {
         // split string on '@' and ensure there is exactly one '@'
         let parts: Vec<&str> = input.split('@').collect();
         if parts.len() < 2 {
@@ -69,38 +73,40 @@ impl std::str::FromStr for Address {
         if node.is_empty() {
             return Err(AddressParseError::MissingNodeId);
         }
-
-        // split the rest on ':' and ensure there are exactly three ':'
-        let segments: Vec<&str> = parts[1].split(':').collect();
-        if segments.len() < 3 {
-            return Err(AddressParseError::MissingField);
-        } else if segments.len() > 3 {
-            return Err(AddressParseError::TooManyColons);
-        }
-        let process_name = segments[0].to_string();
-        if process_name.is_empty() {
-            return Err(AddressParseError::MissingField);
-        }
-        let package_name = segments[1].to_string();
-        if package_name.is_empty() {
-            return Err(AddressParseError::MissingField);
-        }
-        let publisher_node = segments[2].to_string();
-        if publisher_node.is_empty() {
-            return Err(AddressParseError::MissingField);
-        }
-
         Ok(Address {
             node,
-            process: ProcessId {
-                process_name,
-                package_name,
-                publisher_node,
-            },
+            process: ProcessId::parse_lenient(parts[1])?,
         })
     }
 }
 
+/// Whether name is a valid node name, as in the node of an [`Address`]: lowercase
+/// ASCII letters, digits, hyphens and dots, with no dot at either end or two in a
+/// row, e.g. `alice.os`. Doesn't check that the name is registered.
+pub fn is_valid_node_name(name: &str) -> bool {
+    check_segment(IdSegment::Node, name).is_ok()
+}
+
+impl std::str::FromStr for Address {
+    type Err = AddressParseError;
+    /// Attempt to parse an `Address` from a string. The formatting structure for
+    /// an Address is `node@process_name:package_name:publisher_node`.
+    ///
+    /// The string being parsed must contain exactly one `@` and three `:` characters.
+    /// The `@` character separates the node ID from the rest of the address, and the
+    /// `:` characters separate the process name, package name, and publisher node ID.
+    ///
+    /// The node ID and publisher node ID must be valid node names (see
+    /// [`is_valid_node_name()`]), and the process and package names may contain
+    /// only lowercase letters, numbers and hyphens. See [`Address::parse_lenient()`]
+    /// to skip the character checks.
+    fn from_str(input: &str) -> Result<Self, AddressParseError> {
+        let address = Address::parse_lenient(input)?;
+        address.validate()?;
+        Ok(address)
+    }
+}
+
 impl Serialize for Address {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -173,17 +179,77 @@ impl std::fmt::Display for Address {
 }
 
 /// Error type for parsing an `Address` from a string.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AddressParseError {
     TooManyAts,
     TooManyColons,
     MissingNodeId,
     MissingField,
+    /// position counts characters from the start of the segment.
+    InvalidCharacter {
+        segment: IdSegment,
+        character: char,
+        position: usize,
+    },
+    /// Only from [`Address::from_str_with_aliases()`].
+    UnknownAlias(String),
+    /// Only from [`Address::from_str_with_aliases()`].
+    AmbiguousAlias {
+        alias: String,
+        candidates: Vec<ProcessId>,
+    },
+}
+
+impl From<ProcessIdParseError> for AddressParseError {
+    fn from(error: ProcessIdParseError) -> Self {
+        match error {
+            ProcessIdParseError::TooManyColons => AddressParseError::TooManyColons,
+            ProcessIdParseError::MissingField => AddressParseError::MissingField,
+            ProcessIdParseError::InvalidCharacter {
+                segment,
+                character,
+                position,
+            } => AddressParseError::InvalidCharacter {
+                segment,
+                character,
+                position,
+            },
+            ProcessIdParseError::UnknownAlias(alias) => AddressParseError::UnknownAlias(alias),
+            ProcessIdParseError::AmbiguousAlias { alias, candidates } => {
+                AddressParseError::AmbiguousAlias { alias, candidates }
+            }
+        }
+    }
 }
 
 impl std::fmt::Display for AddressParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self}")
+        match self {
+            AddressParseError::InvalidCharacter {
+                segment,
+                character,
+                position,
+            } => ProcessIdParseError::InvalidCharacter {
+                segment: *segment,
+                character: *character,
+                position: *position,
+            }
+            .fmt(f),
+            AddressParseError::TooManyAts => write!(f, "Too many '@' chars in ProcessId string"),
+            AddressParseError::TooManyColons => write!(f, "Too many colons in ProcessId string"),
+            AddressParseError::MissingNodeId => write!(f, "Node ID missing"),
+            AddressParseError::MissingField => write!(f, "Missing field in ProcessId string"),
+            AddressParseError::UnknownAlias(alias) => {
+                ProcessIdParseError::UnknownAlias(alias.clone()).fmt(f)
+            }
+            AddressParseError::AmbiguousAlias { alias, candidates } => {
+                ProcessIdParseError::AmbiguousAlias {
+                    alias: alias.clone(),
+                    candidates: candidates.clone(),
+                }
+                .fmt(f)
+            }
+        }
     }
 }
 
@@ -194,6 +260,9 @@ impl std::error::Error for AddressParseError {
             AddressParseError::TooManyColons => "Too many colons in ProcessId string",
             AddressParseError::MissingNodeId => "Node ID missing",
             AddressParseError::MissingField => "Missing field in ProcessId string",
+            AddressParseError::InvalidCharacter { .. } => "Invalid character",
+            AddressParseError::UnknownAlias(_) => "Unknown process alias",
+            AddressParseError::AmbiguousAlias { .. } => "Ambiguous process alias",
         }
     }
 }
@@ -205,12 +274,119 @@ mod tests {
 
     #[test]
     fn test_valid_address() {
-        let input = "node123@process1:packageA:publisherB";
+        let input = "node123@process1:package-a:publisher.os";
         let address: Address = input.parse().unwrap();
         assert_eq!(address.node(), "node123");
         assert_eq!(address.process(), "process1");
+        assert_eq!(address.package(), "package-a");
+        assert_eq!(address.publisher(), "publisher.os");
+    }
+
+    #[test]
+    fn test_lenient() {
+        let input = "node123@process1:packageA:publisherB";
+        assert!(Address::from_str(input).is_err());
+        let address = Address::parse_lenient(input).unwrap();
         assert_eq!(address.package(), "packageA");
-        assert_eq!(address.publisher(), "publisherB");
+        assert_eq!(address.to_string(), input);
+        assert!(matches!(
+            Address::parse_lenient("node@a:b"),
+            Err(AddressParseError::MissingField)
+        ));
+    }
+
+    fn invalid(segment: IdSegment, character: char, position: usize) -> AddressParseError {
+        AddressParseError::InvalidCharacter {
+            segment,
+            character,
+            position,
+        }
+    }
+
+    #[test]
+    fn test_character_rules() {
+        use IdSegment::*;
+        let cases = [
+            ("alice.os@chat:chat:template.os", Ok(())),
+            ("a-1.b-2.os@p-1:pkg-2:x.y.z", Ok(())),
+            ("123@0:0:0", Ok(())),
+            ("Alice.os@chat:chat:template.os", Err(invalid(Node, 'A', 0))),
+            (
+                "alice.os@Chat:chat:template.os",
+                Err(invalid(ProcessName, 'C', 0)),
+            ),
+            (
+                "alice.os@chat:chät:template.os",
+                Err(invalid(PackageName, 'ä', 2)),
+            ),
+            // an en dash and an em dash, which look like hyphens
+            (
+                "alice.os@chat\u{2013}app:chat:template.os",
+                Err(invalid(ProcessName, '\u{2013}', 4)),
+            ),
+            (
+                "alice.os@chat:chat:template\u{2014}os",
+                Err(invalid(PublisherNode, '\u{2014}', 8)),
+            ),
+            ("alice os@chat:chat:template.os", Err(invalid(Node, ' ', 5))),
+            (
+                "alice.os@chat.app:chat:template.os",
+                Err(invalid(ProcessName, '.', 4)),
+            ),
+            (
+                "alice.os@chat:chat_app:template.os",
+                Err(invalid(PackageName, '_', 4)),
+            ),
+            (".os@chat:chat:template.os", Err(invalid(Node, '.', 0))),
+            ("alice.@chat:chat:template.os", Err(invalid(Node, '.', 5))),
+            (
+                "alice..os@chat:chat:template.os",
+                Err(invalid(Node, '.', 6)),
+            ),
+            (
+                "alice.os@chat:chat:template.os.",
+                Err(invalid(PublisherNode, '.', 11)),
+            ),
+            ("alice.os@chat:chat:", Err(AddressParseError::MissingField)),
+            (
+                "@chat:chat:template.os",
+                Err(AddressParseError::MissingNodeId),
+            ),
+            (
+                "a@b@chat:chat:template.os",
+                Err(AddressParseError::TooManyAts),
+            ),
+            (
+                "alice.os@chat:chat:template.os:x",
+                Err(AddressParseError::TooManyColons),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Address::from_str(input).map(|_| ()), expected, "{input}");
+            if let Ok(address) = Address::parse_lenient(input) {
+                assert_eq!(address.validate(), expected, "{input}");
+            }
+            if let Err(AddressParseError::InvalidCharacter { segment: Node, .. }) = expected {
+                assert!(
+                    !is_valid_node_name(input.split('@').next().unwrap()),
+                    "{input}"
+                );
+            }
+        }
+        assert!(is_valid_node_name("alice.os"));
+        assert!(!is_valid_node_name(""));
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            invalid(IdSegment::ProcessName, '\u{2013}', 4).to_string(),
+            "Invalid character '–' (U+2013) at position 4 of process name"
+        );
+        assert_eq!(
+            AddressParseError::MissingNodeId.to_string(),
+            "Node ID missing"
+        );
     }
 
     #[test]
@@ -260,7 +436,7 @@ mod tests {
 
     #[test]
     fn test_display() {
-        let input = "node123@process1:packageA:publisherB";
+        let input = "node123@process1:package-a:publisher.os";
         let address: Address = input.parse().unwrap();
         assert_eq!(format!("{}", address), input);
     }
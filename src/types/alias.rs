@@ -0,0 +1,240 @@
+use crate::types::process_id::{check_segment, IdSegment};
+use crate::{Address, AddressParseError, ProcessId, ProcessIdParseError, SystemProcess};
+use std::collections::BTreeMap;
+
+/// Short names for processes, for [`ProcessId::from_str_with_aliases()`] and
+/// [`Address::from_str_with_aliases()`] to expand, e.g. `vfs` for
+/// `vfs:distro:sys`.
+///
+/// [`AliasTable::default()`] names every [`SystemProcess`], by its process name
+/// and with underscores for hyphens (`http_server`), under the current
+/// [`crate::system_naming()`]. An alias registered for more than one process is
+/// ambiguous, and fails to expand rather than pick one.
+///
+/// ```
+/// use hyperware_process_lib::{AliasTable, Address, ProcessId};
+///
+/// let mut aliases = AliasTable::default();
+/// aliases.register_alias("chat", "chat:chat:template.os".parse().unwrap()).unwrap();
+/// let address = Address::from_str_with_aliases("our@chat", &aliases).unwrap();
+/// assert_eq!(address.to_string(), "our@chat:chat:template.os");
+/// assert_eq!(
+///     ProcessId::from_str_with_aliases("vfs", &aliases).unwrap().to_string(),
+///     "vfs:distro:sys"
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AliasTable {
+    aliases: BTreeMap<String, Vec<ProcessId>>,
+}
+
+impl Default for AliasTable {
+    fn default() -> Self {
+        let mut table = AliasTable::new();
+        for process in SystemProcess::ALL {
+            let name = process.name();
+            // system process names are valid aliases
+            let _ = table.register_alias(name, process.process_id());
+            if name.contains('-') {
+                let _ = table.register_alias(&name.replace('-', "_"), process.process_id());
+            }
+        }
+        table
+    }
+}
+
+impl AliasTable {
+    /// A table without any aliases, not even of the runtime processes.
+    pub fn new() -> Self {
+        AliasTable {
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    /// Let name stand for process. Registering a name again for another
+    /// process makes it ambiguous, not replaced. Fails unless name is a valid
+    /// process name, underscores also allowed, so that it can not be mistaken
+    /// for a full triple or an address.
+    pub fn register_alias(
+        &mut self,
+        name: &str,
+        process: ProcessId,
+    ) -> Result<(), ProcessIdParseError> {
+        // one character for another, so positions in errors still line up
+        check_segment(IdSegment::ProcessName, &name.replace('_', "-"))?;
+        let processes = self.aliases.entry(name.to_string()).or_default();
+        if !processes.contains(&process) {
+            processes.push(process);
+        }
+        Ok(())
+    }
+
+    /// The process name stands for.
+    pub fn resolve(&self, name: &str) -> Result<ProcessId, ProcessIdParseError> {
+        match self.aliases.get(name).map(Vec::as_slice) {
+            Some([process]) => Ok(process.clone()),
+            Some(candidates) if !candidates.is_empty() => {
+                Err(ProcessIdParseError::AmbiguousAlias {
+                    alias: name.to_string(),
+                    candidates: candidates.to_vec(),
+                })
+            }
+            _ => Err(ProcessIdParseError::UnknownAlias(name.to_string())),
+        }
+    }
+
+    /// Every alias and the processes it stands for, in alphabetical order.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &[ProcessId])> {
+        self.aliases
+            .iter()
+            .map(|(name, processes)| (name.as_str(), processes.as_slice()))
+    }
+}
+
+impl ProcessId {
+    /// Parse input as [`ProcessId::from_str()`] does if it is a full
+    /// `process:package:publisher` triple, and otherwise as an alias in aliases.
+    pub fn from_str_with_aliases(
+        input: &str,
+        aliases: &AliasTable,
+    ) -> Result<Self, ProcessIdParseError> {
+        if input.contains(':') {
+            return input.parse();
+        }
+        aliases.resolve(input)
+    }
+}
+
+impl Address {
+    /// Parse input as [`Address::from_str()`] does, except that the process
+    /// after the `@` may be an alias in aliases, e.g. `our@vfs`.
+    pub fn from_str_with_aliases(
+        input: &str,
+        aliases: &AliasTable,
+    ) -> Result<Self, AddressParseError> {
+        let Some((node, process)) = input.split_once('@') else {
+            return Err(AddressParseError::MissingNodeId);
+        };
+        if process.contains('@') {
+            return Err(AddressParseError::TooManyAts);
+        }
+        if node.is_empty() {
+            return Err(AddressParseError::MissingNodeId);
+        }
+        check_segment(IdSegment::Node, node)?;
+        Ok(Address::new(
+            node,
+            ProcessId::from_str_with_aliases(process, aliases)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expansion() {
+        let aliases = AliasTable::default();
+        for (alias, full) in [
+            ("vfs", "vfs:distro:sys"),
+            ("http-server", "http-server:distro:sys"),
+            ("http_server", "http-server:distro:sys"),
+            ("http_client", "http-client:distro:sys"),
+            ("terminal", "terminal:terminal:sys"),
+        ] {
+            assert_eq!(
+                ProcessId::from_str_with_aliases(alias, &aliases).unwrap(),
+                full.parse::<ProcessId>().unwrap(),
+                "{alias}"
+            );
+        }
+        assert_eq!(
+            Address::from_str_with_aliases("our@vfs", &aliases).unwrap(),
+            "our@vfs:distro:sys".parse::<Address>().unwrap()
+        );
+
+        let mut aliases = aliases;
+        aliases
+            .register_alias("chat", "chat:chat:template.os".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            Address::from_str_with_aliases("alice.os@chat", &aliases)
+                .unwrap()
+                .to_string(),
+            "alice.os@chat:chat:template.os"
+        );
+        assert!(aliases
+            .register_alias("a:b", "vfs:distro:sys".parse().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_unknown_and_ambiguous() {
+        let mut aliases = AliasTable::default();
+        assert_eq!(
+            ProcessId::from_str_with_aliases("nope", &aliases),
+            Err(ProcessIdParseError::UnknownAlias("nope".to_string()))
+        );
+        assert_eq!(
+            Address::from_str_with_aliases("our@nope", &aliases),
+            Err(AddressParseError::UnknownAlias("nope".to_string()))
+        );
+        assert_eq!(
+            ProcessIdParseError::UnknownAlias("nope".to_string()).to_string(),
+            "Unknown process alias \"nope\""
+        );
+
+        // registering the same process again is harmless, another is not
+        let ours: ProcessId = "kv:my-app:pub.os".parse().unwrap();
+        aliases
+            .register_alias("vfs", SystemProcess::Vfs.process_id())
+            .unwrap();
+        assert!(ProcessId::from_str_with_aliases("vfs", &aliases).is_ok());
+        aliases.register_alias("kv", ours.clone()).unwrap();
+        let error = ProcessId::from_str_with_aliases("kv", &aliases).unwrap_err();
+        assert_eq!(
+            error,
+            ProcessIdParseError::AmbiguousAlias {
+                alias: "kv".to_string(),
+                candidates: vec![SystemProcess::Kv.process_id(), ours],
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Ambiguous process alias \"kv\": could be kv:distro:sys or kv:my-app:pub.os"
+        );
+        assert!(AliasTable::new().resolve("vfs").is_err());
+    }
+
+    #[test]
+    fn test_full_triples_bypass_table() {
+        let mut aliases = AliasTable::new();
+        aliases
+            .register_alias("vfs", "vfs:my-app:pub.os".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            ProcessId::from_str_with_aliases("vfs:distro:sys", &aliases).unwrap(),
+            "vfs:distro:sys"
+        );
+        assert_eq!(
+            ProcessId::from_str_with_aliases("vfs:distro", &aliases),
+            Err(ProcessIdParseError::MissingField)
+        );
+        for input in [
+            "our@vfs:distro:sys",
+            "our@Vfs:distro:sys",
+            "@vfs",
+            "a@b@vfs",
+        ] {
+            assert_eq!(
+                Address::from_str_with_aliases(input, &aliases),
+                input.parse::<Address>(),
+                "{input}"
+            );
+        }
+        // and plain parsing knows nothing of aliases
+        assert!("our@vfs".parse::<Address>().is_err());
+        assert!("vfs".parse::<ProcessId>().is_err());
+    }
+}
@@ -27,12 +27,54 @@ pub enum Message {
     },
 }
 
-#[derive(Debug, Error, Serialize, Deserialize)]
+/// Where the bytes of a [`Message`] arrived, as told by [`Message::data()`]. A
+/// body or blob that is empty doesn't count.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Data<'a> {
+    None,
+    Body(&'a [u8]),
+    Blob(LazyLoadBlob),
+    Both { body: &'a [u8], blob: LazyLoadBlob },
+}
+
+impl Data<'_> {
+    /// The bytes of the blob if there is one, else those of the body, else none.
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Data::None => &[],
+            Data::Body(body) => body,
+            Data::Blob(blob) | Data::Both { blob, .. } => &blob.bytes,
+        }
+    }
+
+    pub fn has_body(&self) -> bool {
+        matches!(self, Data::Body(_) | Data::Both { .. })
+    }
+
+    pub fn has_blob(&self) -> bool {
+        matches!(self, Data::Blob(_) | Data::Both { .. })
+    }
+}
+
+#[derive(Clone, Debug, Error, Serialize, Deserialize)]
 pub enum BuildError {
     #[error("no body set for message")]
     NoBody,
     #[error("no target set for message")]
     NoTarget,
+    /// A [`crate::hooks::SendHook`] refused to let the message be sent.
+    #[error("send aborted by hook: {0}")]
+    Hook(String),
+    /// The [`crate::timer::Deadline`] passed before the request was sent.
+    #[error("deadline exceeded before sending")]
+    DeadlineExceeded,
+    /// The body or blob is over its limit, see [`crate::limits`].
+    #[error("{kind} of {size} bytes is over the limit of {limit}")]
+    TooLarge {
+        kind: crate::limits::PayloadKind,
+        size: u64,
+        limit: u64,
+    },
 }
 
 impl Message {
@@ -43,13 +85,38 @@ impl Message {
             Message::Response { source, .. } => source,
         }
     }
-    /// Get the IPC body of a `Message`.
+    /// Get the body of a `Message`.
     pub fn body(&self) -> &[u8] {
         match self {
             Message::Request { body, .. } => body,
             Message::Response { body, .. } => body,
         }
     }
+    /// The length of the body of a `Message`, in bytes.
+    pub fn body_len(&self) -> usize {
+        self.body().len()
+    }
+    /// Deserialize the body of a `Message` from bincode.
+    pub fn body_as_bincode<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        crate::encoding::Encoding::Bincode.decode("body", self.body())
+    }
+    /// Deserialize the body of a `Message` from MessagePack.
+    pub fn body_as_msgpack<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        crate::encoding::Encoding::MsgPack.decode("body", self.body())
+    }
+    /// Deserialize a body set with [`crate::Request::encoded_body()`] in whichever
+    /// encoding its tag names, or a plain JSON body.
+    pub fn body_as_encoded<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        crate::encoding::decode_encoded("body", self.body())
+    }
+    /// Deserialize a body wrapped in a versioned envelope by
+    /// [`crate::envelope::wrap()`], telling a variant from a newer protocol version
+    /// apart from a malformed body.
+    pub fn body_as_versioned<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<crate::envelope::Versioned<T>, crate::envelope::EnvelopeError> {
+        crate::envelope::unwrap(self.body())
+    }
     /// Get the metadata of a `Message`.
     pub fn metadata(&self) -> Option<&str> {
         match self {
@@ -57,6 +124,47 @@ impl Message {
             Message::Response { metadata, .. } => metadata.as_ref().map(|s| s.as_str()),
         }
     }
+    /// Parse the metadata of a `Message` as JSON, `None` if it has no metadata.
+    pub fn metadata_json(&self) -> anyhow::Result<Option<serde_json::Value>> {
+        let Some(metadata) = self.metadata() else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_str(metadata).map_err(|e| {
+            anyhow::anyhow!("metadata is not JSON: {e}")
+        })?))
+    }
+    /// Deserialize the entry key of the JSON object metadata of a `Message`, as set
+    /// with [`crate::Request::metadata_field()`]. `None` if there is no metadata
+    /// or no such entry; an error if the metadata is not a JSON object.
+    pub fn metadata_field_as<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Option<T>> {
+        let Some(value) = metadata_object(self.metadata())?.remove(key) else {
+            return Ok(None);
+        };
+        serde_json::from_value(value)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("metadata field {key}: {e}"))
+    }
+    /// The address a relay says it forwarded a `Message` on behalf of, set with
+    /// [`crate::Request::via()`]. Any sender can claim one: authorize with
+    /// [`Message::effective_source()`] instead.
+    pub fn forwarded_from(&self) -> anyhow::Result<Option<Address>> {
+        crate::guard::forwarded_from(self.metadata())
+    }
+    /// Who a `Message` is from, for authorization: the address it was forwarded
+    /// on behalf of if its source is one of trusted_relays, and its source
+    /// otherwise. See [`crate::guard::effective_source()`].
+    pub fn effective_source(&self, trusted_relays: &[Address]) -> std::borrow::Cow<'_, Address> {
+        crate::guard::effective_source(self, trusted_relays)
+    }
+    /// Check that a `Message` was signed by its source with
+    /// [`crate::Request::signed()`], and not altered or replayed since. See
+    /// [`crate::crypto::verify()`].
+    pub fn verify_signature(&self) -> anyhow::Result<crate::crypto::VerifiedSource> {
+        crate::crypto::verify(self)
+    }
     /// Get the context of a `Message`. Always `None` for requests.
     pub fn context(&self) -> Option<&[u8]> {
         match self {
@@ -70,6 +178,39 @@ impl Message {
     pub fn blob(&self) -> Option<LazyLoadBlob> {
         crate::get_blob()
     }
+    /// The length of the blob of a `Message`, in bytes, if it has one. Like
+    /// [`Message::blob()`], this reads the blob of the message last received,
+    /// though through [`crate::get_blob_len()`], so without copying it each time.
+    pub fn blob_len(&self) -> Option<usize> {
+        crate::get_blob_len().map(|len| len as usize)
+    }
+    /// The blob of a `Message`, as [`Message::blob()`] gets it, or an error naming
+    /// the source for handlers that can't do without one.
+    pub fn require_blob(&self) -> anyhow::Result<LazyLoadBlob> {
+        self.blob().ok_or_else(|| {
+            let kind = if self.is_request() {
+                "request"
+            } else {
+                "response"
+            };
+            anyhow::anyhow!(
+                "{kind} from {} has no blob: the sender likely forgot `.blob(...)`",
+                self.source()
+            )
+        })
+    }
+    /// Whether a `Message` brought bytes in its body, its blob, or both. Like
+    /// [`Message::blob()`], this fetches the blob of the message last received.
+    pub fn data(&self) -> Data<'_> {
+        let body = Some(self.body()).filter(|body| !body.is_empty());
+        let blob = self.blob().filter(|blob| !blob.bytes.is_empty());
+        match (body, blob) {
+            (None, None) => Data::None,
+            (Some(body), None) => Data::Body(body),
+            (None, Some(blob)) => Data::Blob(blob),
+            (Some(body), Some(blob)) => Data::Both { body, blob },
+        }
+    }
     /// Get the capabilities of a `Message`.
     pub fn capabilities(&self) -> &Vec<Capability> {
         match self {
@@ -103,6 +244,32 @@ impl Message {
     }
 }
 
+/// metadata as a JSON object: empty if there is none, and an error if it is
+/// anything but an object.
+pub(crate) fn metadata_object(
+    metadata: Option<&str>,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let Some(metadata) = metadata else {
+        return Ok(serde_json::Map::new());
+    };
+    match serde_json::from_str(metadata) {
+        Ok(serde_json::Value::Object(object)) => Ok(object),
+        _ => Err(anyhow::anyhow!("metadata is not a JSON object: {metadata}")),
+    }
+}
+
+/// metadata with key set to value, merged into its JSON object; see
+/// [`metadata_object()`].
+pub(crate) fn with_metadata_field(
+    metadata: Option<&str>,
+    key: &str,
+    value: impl Serialize,
+) -> anyhow::Result<String> {
+    let mut object = metadata_object(metadata)?;
+    object.insert(key.to_string(), serde_json::to_value(value)?);
+    Ok(serde_json::Value::Object(object).to_string())
+}
+
 pub fn _wit_message_to_message(
     source: Address,
     message: crate::hyperware::process::standard::Message,
@@ -126,3 +293,98 @@ pub fn _wit_message_to_message(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(metadata: Option<&str>) -> Message {
+        Message::Request {
+            source: "tester.os@tester:app:sys".parse().unwrap(),
+            expects_response: None,
+            body: vec![],
+            metadata: metadata.map(str::to_string),
+            capabilities: vec![],
+        }
+    }
+
+    #[test]
+    fn test_metadata_fields() {
+        let merged = crate::Request::new()
+            .metadata(r#"{"app":{"v":1}}"#)
+            .metadata_field("__trace", "abc")
+            .unwrap()
+            .metadata_field("retries", 2)
+            .unwrap()
+            .metadata_field("retries", 3)
+            .unwrap()
+            .metadata;
+        let message = request(merged.as_deref());
+        assert_eq!(
+            message.metadata_json().unwrap(),
+            Some(serde_json::json!({"app": {"v": 1}, "__trace": "abc", "retries": 3}))
+        );
+        assert_eq!(message.metadata_field_as::<u8>("retries").unwrap(), Some(3));
+        assert_eq!(message.metadata_field_as::<u8>("missing").unwrap(), None);
+        assert!(message.metadata_field_as::<u8>("__trace").is_err());
+
+        // no metadata starts a new object
+        let fresh = crate::Request::new().metadata_field("k", true).unwrap();
+        assert_eq!(fresh.metadata.as_deref(), Some(r#"{"k":true}"#));
+        let none = request(None);
+        assert_eq!(none.metadata_json().unwrap(), None);
+        assert_eq!(none.metadata_field_as::<bool>("k").unwrap(), None);
+    }
+
+    #[test]
+    fn test_non_object_metadata() {
+        for metadata in ["plain text", "[1,2]", "\"string\""] {
+            let error = crate::Request::new()
+                .metadata(metadata)
+                .metadata_field("k", 1)
+                .unwrap_err();
+            assert!(error.to_string().contains("not a JSON object"), "{error}");
+            assert!(request(Some(metadata))
+                .metadata_field_as::<u8>("k")
+                .is_err());
+        }
+        assert!(request(Some("plain text")).metadata_json().is_err());
+        assert_eq!(
+            request(Some("[1,2]")).metadata_json().unwrap(),
+            Some(serde_json::json!([1, 2]))
+        );
+    }
+
+    #[test]
+    fn test_require_blob_and_data() {
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        let error = request(None).require_blob().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "request from tester.os@tester:app:sys has no blob: the sender likely forgot `.blob(...)`"
+        );
+        assert_eq!(request(None).data(), Data::None);
+        assert_eq!(request(None).data().bytes(), b"");
+
+        let mut with_body = request(None);
+        if let Message::Request { body, .. } = &mut with_body {
+            *body = b"body".to_vec();
+        }
+        assert_eq!(with_body.data(), Data::Body(b"body"));
+        assert_eq!(with_body.data().bytes(), b"body");
+
+        // an empty blob is no blob to speak of, but is still a blob
+        host.set_blob(Some(LazyLoadBlob::new(None::<String>, vec![])));
+        assert_eq!(with_body.data(), Data::Body(b"body"));
+        assert!(with_body.require_blob().is_ok());
+
+        let blob = LazyLoadBlob::new(Some("text/plain"), b"blob".to_vec());
+        host.set_blob(Some(blob.clone()));
+        let data = with_body.data();
+        assert!(data.has_body() && data.has_blob());
+        assert_eq!(data.bytes(), b"blob");
+        assert_eq!(request(None).data(), Data::Blob(blob.clone()));
+        assert_eq!(request(None).require_blob().unwrap(), blob);
+    }
+}
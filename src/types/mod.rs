@@ -1,10 +1,13 @@
 pub mod address;
+pub mod alias;
 pub mod capability;
 pub mod lazy_load_blob;
 pub mod message;
 pub mod on_exit;
 pub mod package_id;
 pub mod process_id;
+pub mod remote_error;
 pub mod request;
 pub mod response;
 pub mod send_error;
+pub mod system_process;
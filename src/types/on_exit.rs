@@ -16,16 +16,14 @@ impl OnExit {
             crate::hyperware::process::standard::OnExit::Requests(reqs) => {
                 let mut requests: Vec<Request> = Vec::with_capacity(reqs.len());
                 for req in reqs {
-                    requests.push(Request {
-                        target: Some(req.0),
-                        inherit: req.1.inherit,
-                        timeout: req.1.expects_response,
-                        body: Some(req.1.body),
-                        metadata: req.1.metadata,
-                        blob: req.2,
-                        context: None,
-                        capabilities: req.1.capabilities,
-                    });
+                    let mut request = Request::to(req.0);
+                    request.inherit = req.1.inherit;
+                    request.timeout = req.1.expects_response;
+                    request.body = Some(req.1.body);
+                    request.metadata = req.1.metadata;
+                    request.blob = req.2;
+                    request.capabilities = req.1.capabilities;
+                    requests.push(request);
                 }
                 OnExit::Requests(requests)
             }
@@ -92,13 +90,14 @@ impl OnExit {
                     Option<LazyLoadBlob>,
                 )> = Vec::with_capacity(reqs.len());
                 for req in reqs {
+                    let metadata = req.metadata_to_send();
                     kernel_reqs.push((
                         req.target.ok_or(BuildError::NoTarget)?,
                         crate::hyperware::process::standard::Request {
                             inherit: req.inherit,
                             expects_response: None,
                             body: req.body.ok_or(BuildError::NoBody)?,
-                            metadata: req.metadata,
+                            metadata,
                             capabilities: req.capabilities,
                         },
                         req.blob,
@@ -1,3 +1,4 @@
+use crate::types::process_id::{check_segment, IdSegment};
 pub use crate::PackageId;
 use crate::ProcessIdParseError;
 use serde::{Deserialize, Serialize};
@@ -23,6 +24,28 @@ impl PackageId {
     pub fn publisher(&self) -> &str {
         &self.publisher_node
     }
+    /// Check both segments against the rules [`PackageId::from_str()`] enforces.
+    pub fn validate(&self) -> Result<(), ProcessIdParseError> {
+        check_segment(IdSegment::PackageName, &self.package_name)?;
+        check_segment(IdSegment::PublisherNode, &self.publisher_node)
+    }
+    /// Parse a `PackageId` checking only that it has two non-empty segments, not
+    /// which characters they contain.
+    pub fn parse_lenient(input: &str) -> Result<Self, ProcessIdParseError> {
+        let segments: Vec<&str> = input.split(':').collect();
+        if segments.len() < 2 {
+            return Err(ProcessIdParseError::MissingField);
+        } else if segments.len() > 2 {
+            return Err(ProcessIdParseError::TooManyColons);
+        }
+        if segments.iter().any(|segment| segment.is_empty()) {
+            return Err(ProcessIdParseError::MissingField);
+        }
+        Ok(PackageId {
+            package_name: segments[0].to_string(),
+            publisher_node: segments[1].to_string(),
+        })
+    }
 }
 
 impl Serialize for PackageId {
@@ -131,17 +154,11 @@ impl std::str::FromStr for PackageId {
     type Err = ProcessIdParseError;
     /// Attempts to parse a `PackageId` from a string. The string must match the pattern
     /// of two segments containing only lowercase letters, numbers and hyphens, separated by a colon.
+    /// The second, a node name, may also contain dots.
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let re = regex::Regex::new(r"^[a-z0-9-]+:[a-z0-9-.]+$").unwrap();
-        if !re.is_match(input) {
-            return Err(ProcessIdParseError::InvalidCharacter);
-        }
-
-        let segments: Vec<&str> = input.split(':').collect();
-        Ok(PackageId {
-            package_name: segments[0].to_string(),
-            publisher_node: segments[1].to_string(),
-        })
+        let package_id = PackageId::parse_lenient(input)?;
+        package_id.validate()?;
+        Ok(package_id)
     }
 }
 
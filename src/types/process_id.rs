@@ -32,24 +32,86 @@ impl ProcessId {
     pub fn publisher(&self) -> &str {
         &self.publisher_node
     }
+    /// Check each segment against the rules [`ProcessId::from_str()`] enforces,
+    /// e.g. for a `ProcessId` made with [`ProcessId::new()`], which doesn't.
+    pub fn validate(&self) -> Result<(), ProcessIdParseError> {
+        check_segment(IdSegment::ProcessName, &self.process_name)?;
+        check_segment(IdSegment::PackageName, &self.package_name)?;
+        check_segment(IdSegment::PublisherNode, &self.publisher_node)
+    }
+    /// Parse a `ProcessId` checking only that it has three non-empty segments,
+    /// not which characters they contain.
+    pub fn parse_lenient(input: &str) -> Result<Self, ProcessIdParseError> {
+        let segments: Vec<&str> = input.split(':').collect();
+        if segments.len() < 3 {
+            return Err(ProcessIdParseError::MissingField);
+        } else if segments.len() > 3 {
+            return Err(ProcessIdParseError::TooManyColons);
+        }
+        if segments.iter().any(|segment| segment.is_empty()) {
+            return Err(ProcessIdParseError::MissingField);
+        }
+        Ok(ProcessId {
+            process_name: segments[0].to_string(),
+            package_name: segments[1].to_string(),
+            publisher_node: segments[2].to_string(),
+        })
+    }
+}
+
+/// A part of a [`ProcessId`], [`crate::PackageId`] or [`crate::Address`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdSegment {
+    Node,
+    ProcessName,
+    PackageName,
+    PublisherNode,
+}
+
+impl std::fmt::Display for IdSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            IdSegment::Node => "node",
+            IdSegment::ProcessName => "process name",
+            IdSegment::PackageName => "package name",
+            IdSegment::PublisherNode => "publisher node",
+        })
+    }
+}
+
+/// Process and package names may contain lowercase ASCII letters, digits and
+/// hyphens. Node names may also contain dots, though not at either end or two
+/// in a row.
+pub(crate) fn check_segment(segment: IdSegment, value: &str) -> Result<(), ProcessIdParseError> {
+    if value.is_empty() {
+        return Err(ProcessIdParseError::MissingField);
+    }
+    let is_node = matches!(segment, IdSegment::Node | IdSegment::PublisherNode);
+    let chars: Vec<char> = value.chars().collect();
+    let bad = chars.iter().enumerate().find(|&(i, &c)| {
+        let dot_ok = is_node && i != 0 && i != chars.len() - 1 && chars[i - 1] != '.';
+        !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || (c == '.' && dot_ok))
+    });
+    match bad {
+        Some((position, &character)) => Err(ProcessIdParseError::InvalidCharacter {
+            segment,
+            character,
+            position,
+        }),
+        None => Ok(()),
+    }
 }
 
 impl std::str::FromStr for ProcessId {
     type Err = ProcessIdParseError;
     /// Attempts to parse a `ProcessId` from a string. The string must match the pattern
     /// of three segments containing only lowercase letters, numbers and hyphens, separated by colons.
+    /// The last, a node name, may also contain dots. See [`ProcessId::parse_lenient()`]
+    /// to skip the character checks.
     fn from_str(input: &str) -> Result<Self, ProcessIdParseError> {
-        let re = regex::Regex::new(r"^[a-z0-9-]+:[a-z0-9-]+:[a-z0-9-.]+$").unwrap();
-        if !re.is_match(input) {
-            return Err(ProcessIdParseError::InvalidCharacter);
-        }
-
-        let segments: Vec<&str> = input.split(':').collect();
-        Ok(ProcessId {
-            process_name: segments[0].to_string(),
-            package_name: segments[1].to_string(),
-            publisher_node: segments[2].to_string(),
-        })
+        let process_id = ProcessId::parse_lenient(input)?;
+        process_id.validate()?;
+        Ok(process_id)
     }
 }
 
@@ -118,24 +180,52 @@ impl PartialEq<ProcessId> for &str {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProcessIdParseError {
     TooManyColons,
     MissingField,
-    InvalidCharacter,
+    /// position counts characters from the start of the segment.
+    InvalidCharacter {
+        segment: IdSegment,
+        character: char,
+        position: usize,
+    },
+    /// Only from [`ProcessId::from_str_with_aliases()`].
+    UnknownAlias(String),
+    /// Only from [`ProcessId::from_str_with_aliases()`].
+    AmbiguousAlias {
+        alias: String,
+        candidates: Vec<ProcessId>,
+    },
 }
 
 impl std::fmt::Display for ProcessIdParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                ProcessIdParseError::TooManyColons => "Too many colons",
-                ProcessIdParseError::MissingField => "Missing field",
-                ProcessIdParseError::InvalidCharacter => "Invalid character",
+        match self {
+            ProcessIdParseError::TooManyColons => write!(f, "Too many colons"),
+            ProcessIdParseError::MissingField => write!(f, "Missing field"),
+            ProcessIdParseError::InvalidCharacter {
+                segment,
+                character,
+                position,
+            } => write!(
+                f,
+                // the code point tells apart lookalikes such as en dashes
+                "Invalid character {character:?} (U+{:04X}) at position {position} of {segment}",
+                *character as u32
+            ),
+            ProcessIdParseError::UnknownAlias(alias) => {
+                write!(f, "Unknown process alias {alias:?}")
             }
-        )
+            ProcessIdParseError::AmbiguousAlias { alias, candidates } => {
+                let candidates: Vec<String> = candidates.iter().map(|c| c.to_string()).collect();
+                write!(
+                    f,
+                    "Ambiguous process alias {alias:?}: could be {}",
+                    candidates.join(" or ")
+                )
+            }
+        }
     }
 }
 
@@ -144,7 +234,74 @@ impl std::error::Error for ProcessIdParseError {
         match self {
             ProcessIdParseError::TooManyColons => "Too many colons",
             ProcessIdParseError::MissingField => "Missing field",
-            ProcessIdParseError::InvalidCharacter => "Invalid character",
+            ProcessIdParseError::InvalidCharacter { .. } => "Invalid character",
+            ProcessIdParseError::UnknownAlias(_) => "Unknown process alias",
+            ProcessIdParseError::AmbiguousAlias { .. } => "Ambiguous process alias",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PackageId;
+
+    fn invalid(segment: IdSegment, character: char, position: usize) -> ProcessIdParseError {
+        ProcessIdParseError::InvalidCharacter {
+            segment,
+            character,
+            position,
+        }
+    }
+
+    #[test]
+    fn test_character_rules() {
+        use IdSegment::*;
+        let cases = [
+            ("chat:chat:template.os", Ok(())),
+            ("1:a-b:c", Ok(())),
+            ("chat:chat:Template.os", Err(invalid(PublisherNode, 'T', 0))),
+            // a non-breaking hyphen
+            (
+                "chat:chat\u{2011}app:template.os",
+                Err(invalid(PackageName, '\u{2011}', 4)),
+            ),
+            (
+                "chat:chat:template..os",
+                Err(invalid(PublisherNode, '.', 9)),
+            ),
+            ("chat::template.os", Err(ProcessIdParseError::MissingField)),
+            ("chat:template.os", Err(ProcessIdParseError::MissingField)),
+            ("a:b:c:d", Err(ProcessIdParseError::TooManyColons)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<ProcessId>().map(|_| ()), expected, "{input}");
+        }
+
+        let cases = [
+            ("chat:template.os", Ok(())),
+            ("chat:template.os.", Err(invalid(PublisherNode, '.', 11))),
+            ("Chat:template.os", Err(invalid(PackageName, 'C', 0))),
+            ("chat", Err(ProcessIdParseError::MissingField)),
+            (
+                "chat:chat:template.os",
+                Err(ProcessIdParseError::TooManyColons),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input.parse::<PackageId>().map(|_| ()), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_lenient_and_validate() {
+        let lenient = ProcessId::parse_lenient("Chat:chat:template.os").unwrap();
+        assert_eq!(lenient.process(), "Chat");
+        assert!(lenient.validate().is_err());
+        assert!(ProcessId::new(None, "chat", "template.os")
+            .validate()
+            .is_ok());
+        assert!(PackageId::parse_lenient("Chat:Template").is_ok());
+        assert!(PackageId::new("chat", "").validate().is_err());
+    }
+}
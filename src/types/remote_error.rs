@@ -0,0 +1,90 @@
+use crate::{Address, BuildError, Message, SendErrorKind};
+use thiserror::Error;
+
+/// The metadata key marking a response as a handler failure, holding its code.
+/// Set by [`crate::Response::error()`].
+pub const ERROR_KEY: &str = "__error";
+
+/// A request was delivered, but the process handling it failed, and answered
+/// with [`crate::Response::error()`], or with a `{"Err": "..."}` body as
+/// [`crate::handle!`] does, which has no code.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("{source_addr} failed{}: {message}", code.as_ref().map(|code| format!(" ({code})")).unwrap_or_default())]
+pub struct RemoteError {
+    /// Who answered with the error.
+    pub source_addr: Address,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl RemoteError {
+    /// The error response is, if it is marked as one by
+    /// [`crate::Response::error()`].
+    pub fn from_response(response: &Message) -> Option<RemoteError> {
+        let code: String = response.metadata_field_as(ERROR_KEY).ok()??;
+        let message = match serde_json::from_slice::<Result<(), String>>(response.body()) {
+            Ok(Err(message)) => message,
+            _ => String::from_utf8_lossy(response.body()).into_owned(),
+        };
+        Some(RemoteError {
+            source_addr: response.source().clone(),
+            code: Some(code),
+            message,
+        })
+    }
+
+    /// The error response is if its body is `{"Err": "..."}`, as
+    /// [`crate::handle!`] answers with, though unmarked.
+    pub(crate) fn from_err_body(response: &Message) -> Option<RemoteError> {
+        match serde_json::from_slice::<Result<serde::de::IgnoredAny, String>>(response.body()) {
+            Ok(Err(message)) => Some(RemoteError {
+                source_addr: response.source().clone(),
+                code: None,
+                message,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// What [`crate::Request::send_and_await_typed()`] does with a [`RemoteError`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnRemoteError {
+    /// Fail with [`AwaitError::Remote`], so the inner result is always `Ok`.
+    Fail,
+    /// Return it as the inner `Err`, leaving the outer one for failures to get
+    /// an answer at all.
+    Return,
+}
+
+/// Errors from [`crate::Request::send_and_await_typed()`], by which hop failed.
+#[derive(Debug, Error)]
+pub enum AwaitError {
+    /// The request was not sent.
+    #[error("failed to send: {0}")]
+    Build(#[from] BuildError),
+    /// The runtime could not deliver the request.
+    #[error("failed to reach {target}: {kind:?}")]
+    Transport {
+        target: Address,
+        kind: SendErrorKind,
+    },
+    /// The request was delivered, or not, but no response came in time.
+    #[error("no response from {target} within {waited_ms} ms")]
+    Timeout { target: Address, waited_ms: u64 },
+    /// The target answered that handling the request failed.
+    #[error(transparent)]
+    Remote(RemoteError),
+    #[error("response from {target} is not a {type_name}: {error}")]
+    Deserialize {
+        target: Address,
+        type_name: &'static str,
+        error: String,
+    },
+    /// See [`crate::Request::expect_blob_response()`].
+    #[error("response from {target} parsed as a {type_name}, and no blob was attached, though one was expected")]
+    MissingBlob {
+        target: Address,
+        type_name: &'static str,
+    },
+}
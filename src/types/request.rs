@@ -1,10 +1,18 @@
 use crate::{
-    our_capabilities, Address, Capability, LazyLoadBlob, Message, SendError,
-    _wit_message_to_message, _wit_send_error_to_send_error, types::message::BuildError,
+    _wit_message_to_message, _wit_send_error_to_send_error, our_capabilities,
+    timer::Deadline,
+    trace::USER_METADATA_KEY,
+    types::message::{metadata_object, BuildError},
+    types::remote_error::{AwaitError, OnRemoteError, RemoteError},
+    Address, Capability, LazyLoadBlob, Message, SendError,
 };
 
 /// `Request` builder. Use [`Request::new()`] or [`Request::to()`] to start a request,
 /// then build it, then call [`Request::send()`] on it to fire.
+///
+/// Methods from before the body and blob were so called, such as `ipc()` and
+/// `payload_bytes()`, still work but are deprecated; see the table above
+/// [`Request::ipc()`] for their new names.
 #[derive(Clone, Debug)]
 pub struct Request {
     pub target: Option<Address>,
@@ -15,6 +23,8 @@ pub struct Request {
     pub blob: Option<LazyLoadBlob>,
     pub context: Option<Vec<u8>>,
     pub capabilities: Vec<Capability>,
+    expects_blob: bool,
+    reserved: serde_json::Map<String, serde_json::Value>,
 }
 
 #[allow(dead_code)]
@@ -32,6 +42,8 @@ impl Request {
             blob: None,
             context: None,
             capabilities: vec![],
+            expects_blob: false,
+            reserved: serde_json::Map::new(),
         }
     }
     /// Start building a new `Request` with the `target` [`Address`]. In order
@@ -50,6 +62,8 @@ impl Request {
             blob: None,
             context: None,
             capabilities: vec![],
+            expects_blob: false,
+            reserved: serde_json::Map::new(),
         }
     }
     /// Set the `target` [`Address`] that this `Request` will go to.
@@ -83,6 +97,15 @@ impl Request {
         self.inherit = inherit;
         self
     }
+    /// Forward the blob of the message this process is handling, i.e. `inherit(true)`.
+    /// See [`Request::inherit()`] for the other effects of inheriting, and
+    /// [`crate::has_inherited_blob()`] to check that there is a blob to forward.
+    ///
+    /// A blob set on this request with [`Request::blob()`] or its variants is sent
+    /// instead of the inherited one.
+    pub fn inherit_blob(self) -> Self {
+        self.inherit(true)
+    }
     /// Set whether this [`crate::Request`] expects a [`crate::Response`], and provide
     /// a timeout value (in seconds) within which that response must be received.
     /// The sender will receive an error message with this request stored within
@@ -91,14 +114,25 @@ impl Request {
         self.timeout = Some(timeout);
         self
     }
-    /// Set the IPC body (Inter-Process Communication) value for this message. This field
-    /// is mandatory. An IPC body is simply a vector of bytes. Process developers are
+    /// Note that the response to this request should carry a blob, so that
+    /// [`Request::send_and_await_typed()`] says so in its error when the response
+    /// body parses but no blob came with it. Nothing else checks this.
+    pub fn expect_blob_response(mut self) -> Self {
+        self.expects_blob = true;
+        self
+    }
+    /// Whether [`Request::expect_blob_response()`] was called on this request.
+    pub fn expects_blob(&self) -> bool {
+        self.expects_blob
+    }
+    /// Set the body for this message. This field
+    /// is mandatory. A body is simply a vector of bytes. Process developers are
     /// responsible for architecting the serialization/derserialization strategy
     /// for these bytes, but the simplest and most common strategy is just to use
     /// a JSON spec that gets stored in bytes as a UTF-8 string.
     ///
     /// If the serialization strategy is complex, it's best to define it as an impl
-    /// of [`TryInto`] on your IPC body type, then use [`Request::try_body()`] instead of this.
+    /// of [`TryInto`] on your body type, then use [`Request::try_body()`] instead of this.
     pub fn body<T>(mut self, body: T) -> Self
     where
         T: Into<Vec<u8>>,
@@ -106,10 +140,10 @@ impl Request {
         self.body = Some(body.into());
         self
     }
-    /// Set the IPC body (Inter-Process Communication) value for this message, using a
+    /// Set the body for this message, using a
     /// type that's got an implementation of [`TryInto`] for `Vec<u8>`. It's best
-    /// to define an IPC body type within your app, then implement [`TryFrom`]/[`TryInto`]
-    /// for all IPC body serialization/deserialization.
+    /// to define a body type within your app, then implement [`TryFrom`]/[`TryInto`]
+    /// for all body serialization/deserialization.
     pub fn try_body<T, E>(mut self, body: T) -> Result<Self, E>
     where
         T: TryInto<Vec<u8>, Error = E>,
@@ -118,9 +152,37 @@ impl Request {
         self.body = Some(body.try_into()?);
         Ok(self)
     }
+    /// Set the body to value serialized with bincode. Like JSON, the receiver
+    /// must know to expect it; see [`Request::encoded_body()`] otherwise.
+    pub fn body_bincode<T>(self, value: &T) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        Ok(self.body(crate::encoding::Encoding::Bincode.encode(value)?))
+    }
+    /// Set the body to value serialized with MessagePack (with field names).
+    pub fn body_msgpack<T>(self, value: &T) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        Ok(self.body(crate::encoding::Encoding::MsgPack.encode(value)?))
+    }
+    /// Set the body to value serialized with encoding and prefixed with a
+    /// one-byte tag, so the receiver can read it with [`crate::Message::body_as_encoded()`]
+    /// whichever encoding was chosen.
+    pub fn encoded_body<T>(
+        self,
+        encoding: crate::encoding::Encoding,
+        value: &T,
+    ) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        Ok(self.body(crate::encoding::encoded(encoding, value)?))
+    }
     /// Set the metadata field for this request. Metadata is simply a [`String`].
     /// Metadata should usually be used for middleware and other message-passing
-    /// situations that require the original IPC body and [`LazyLoadBlob`] to be preserved.
+    /// situations that require the original body and [`LazyLoadBlob`] to be preserved.
     /// As such, metadata should not always be expected to reach the final destination
     /// of this request unless the full chain of behavior is known / controlled by
     /// the developer.
@@ -128,18 +190,107 @@ impl Request {
         self.metadata = Some(metadata.to_string());
         self
     }
+    /// Set key in the metadata of this request to value, as JSON: the metadata is
+    /// made a JSON object if there is none, and the entry merged into it if there
+    /// is. Fails if the metadata is already set to something other than a JSON
+    /// object. Read it with [`crate::Message::metadata_field_as()`].
+    ///
+    /// Keys starting with `__` are used by this library, e.g. [`crate::trace::TRACE_KEY`].
+    pub fn metadata_field<T>(mut self, key: &str, value: T) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        self.metadata = Some(crate::types::message::with_metadata_field(
+            self.metadata.as_deref(),
+            key,
+            value,
+        )?);
+        Ok(self)
+    }
+    /// Mark this request as forwarded on behalf of original_source, for a relay
+    /// passing requests on to their final recipient, which reads it with
+    /// [`crate::Message::effective_source()`]. A relay forwarding a request that
+    /// was itself forwarded should pass on its effective source, so that the
+    /// origin survives any number of hops.
+    pub fn via(mut self, original_source: &Address) -> Self {
+        self.reserved.insert(
+            crate::guard::VIA_KEY.to_string(),
+            serde_json::Value::String(original_source.to_string()),
+        );
+        self
+    }
+    /// Sign the body of this request as coming from this process, with
+    /// [`crate::crypto::sign_body()`], so that the recipient can check with
+    /// [`crate::Message::verify_signature()`] that it wasn't altered on the way.
+    /// Set the body first; any later change to it invalidates the signature.
+    /// Fails if there is no body yet or `net:distro:sys` won't sign.
+    pub fn signed(mut self) -> anyhow::Result<Self> {
+        let body = self.body.as_deref().ok_or(BuildError::NoBody)?;
+        let signature = crate::crypto::sign_body(body)?;
+        self.reserved.insert(
+            crate::crypto::SIGNATURE_KEY.to_string(),
+            serde_json::to_value(signature)?,
+        );
+        Ok(self)
+    }
+    /// Announce our version, and the oldest version of the target we support,
+    /// along with this request, for the target to pass to
+    /// [`crate::version::handle()`], saving [`crate::version::announce()`]'s
+    /// separate message. Unlike that, does not set them as ours: call
+    /// [`crate::version::set_version()`] for that. Fails if either is not a
+    /// valid version.
+    pub fn with_version(
+        mut self,
+        version: &str,
+        min_supported: &str,
+    ) -> Result<Self, crate::version::VersionError> {
+        version.parse::<crate::version::Version>()?;
+        min_supported.parse::<crate::version::Version>()?;
+        let announcement = crate::version::Announcement {
+            version: version.to_string(),
+            min_supported: min_supported.to_string(),
+        };
+        self.reserved.insert(
+            crate::version::VERSION_KEY.to_string(),
+            serde_json::to_value(announcement).unwrap(),
+        );
+        Ok(self)
+    }
+    /// The metadata this request is sent with: its metadata, with what
+    /// [`Request::via()`], [`Request::signed()`] and [`Request::with_version()`]
+    /// set merged in, however the metadata was set before or after them.
+    /// Metadata that is not a JSON object is kept apart from them as
+    /// [`crate::guard::with_via()`] does.
+    pub(crate) fn metadata_to_send(&self) -> Option<String> {
+        if self.reserved.is_empty() {
+            return self.metadata.clone();
+        }
+        let mut object = match metadata_object(self.metadata.as_deref()) {
+            Ok(object) if !object.contains_key(USER_METADATA_KEY) => object,
+            _ => {
+                let mut object = serde_json::Map::new();
+                object.insert(
+                    USER_METADATA_KEY.to_string(),
+                    serde_json::Value::String(self.metadata.clone().unwrap_or_default()),
+                );
+                object
+            }
+        };
+        object.extend(self.reserved.clone());
+        Some(serde_json::Value::Object(object).to_string())
+    }
     /// Set the blob of this request. A [`LazyLoadBlob`] holds bytes and an optional
     /// MIME type.
     ///
-    /// The purpose of having a blob field distinct from the IPC body field is to enable
+    /// The purpose of having a blob field distinct from the body field is to enable
     /// performance optimizations in all sorts of situations. [`LazyLoadBlob`]s are only brought
     /// across the runtime<>Wasm boundary if the process calls `get_blob()`, and this
     /// saves lots of work in data-intensive pipelines.
     ///
-    /// [`LazyLoadBlob`]s also provide a place for less-structured data, such that an IPC body type
+    /// [`LazyLoadBlob`]s also provide a place for less-structured data, such that a body type
     /// can be quickly locked in and upgraded within an app-protocol without breaking
     /// changes, while still allowing freedom to adjust the contents and shape of a
-    /// blob. IPC body formats should be rigorously defined.
+    /// blob. Body formats should be rigorously defined.
     pub fn blob(mut self, blob: LazyLoadBlob) -> Self {
         self.blob = Some(blob);
         self
@@ -238,7 +389,7 @@ impl Request {
     pub fn attach_messaging(mut self) -> Self {
         let our = crate::our();
         self.capabilities.extend(vec![Capability {
-            issuer: our,
+            issuer: our.clone(),
             params: "\"messaging\"".to_string(),
         }]);
         self
@@ -263,21 +414,36 @@ impl Request {
         self
     }
     /// Attempt to send the `Request`. This will only fail if the `target` or `body`
-    /// fields have not been set.
+    /// fields have not been set, the body or blob is over its [`crate::limits`], or a
+    /// [`crate::hooks::SendHook`] aborts it.
     pub fn send(self) -> Result<(), BuildError> {
+        if let Some(body) = &self.body {
+            crate::limits::check(body, self.blob.as_ref())?;
+        }
+        self.unchecked_send()
+    }
+    /// Like [`Request::send()`], but send a body or blob over its [`crate::limits`] too.
+    pub fn unchecked_send(self) -> Result<(), BuildError> {
+        let mut metadata = self.metadata_to_send();
         let Some(target) = self.target else {
             return Err(BuildError::NoTarget);
         };
         let Some(body) = self.body else {
             return Err(BuildError::NoBody);
         };
+        if overrides_inherited_blob(self.inherit, &self.blob) {
+            crate::debug!(
+                "request to {target} inherits but also sets a blob: sending the explicit blob"
+            );
+        }
+        crate::hooks::before_send(&target, body.len(), &mut metadata)?;
         crate::send_request(
             &target,
             &crate::hyperware::process::standard::Request {
                 inherit: self.inherit,
                 expects_response: self.timeout,
                 body,
-                metadata: self.metadata,
+                metadata: crate::trace::on_send(&target, metadata),
                 capabilities: self.capabilities,
             },
             self.context.as_ref(),
@@ -286,32 +452,195 @@ impl Request {
         Ok(())
     }
     /// Attempt to send the `Request`, then await its [`crate::Response`] or [`SendError`] (timeout, offline node).
-    /// This will only fail if the `target` or `body` fields have not been set.
+    /// This will only fail if the `target` or `body` fields have not been set, the body
+    /// or blob is over its [`crate::limits`], or a [`crate::hooks::SendHook`] aborts it.
     pub fn send_and_await_response(
         self,
         timeout: u64,
     ) -> Result<Result<Message, SendError>, BuildError> {
+        let mut metadata = self.metadata_to_send();
         let Some(target) = self.target else {
             return Err(BuildError::NoTarget);
         };
         let Some(body) = self.body else {
             return Err(BuildError::NoBody);
         };
+        crate::limits::check(&body, self.blob.as_ref())?;
+        if overrides_inherited_blob(self.inherit, &self.blob) {
+            crate::debug!(
+                "request to {target} inherits but also sets a blob: sending the explicit blob"
+            );
+        }
+        crate::hooks::before_send(&target, body.len(), &mut metadata)?;
         match crate::send_and_await_response(
             &target,
             &crate::hyperware::process::standard::Request {
                 inherit: self.inherit,
                 expects_response: Some(timeout),
                 body,
-                metadata: self.metadata,
+                metadata: crate::trace::on_send(&target, metadata),
                 capabilities: self.capabilities,
             },
             self.blob.as_ref(),
         ) {
-            Ok((source, message)) => Ok(Ok(_wit_message_to_message(source, message))),
+            Ok((source, message)) => {
+                let mut message = _wit_message_to_message(source, message);
+                crate::trace::on_receive(&mut message);
+                crate::hooks::after_receive(&message);
+                Ok(Ok(message))
+            }
             Err(send_err) => Ok(Err(_wit_send_error_to_send_error(send_err, self.context))),
         }
     }
+    /// Send the `Request`, expecting a response within timeout seconds, and
+    /// return a future of that response, to be awaited under
+    /// [`crate::executor::run()`] alongside other requests in flight. The
+    /// request's context is replaced with one that tells its response apart.
+    /// Fails as [`Request::send()`] does.
+    #[cfg(any(test, feature = "async"))]
+    pub fn send_async(self, timeout: u64) -> Result<crate::executor::ResponseFuture, BuildError> {
+        let id = crate::util::Uid::new();
+        self.expects_response(timeout)
+            .context(crate::executor::context(id))
+            .send()?;
+        Ok(crate::executor::ResponseFuture::new(id))
+    }
+    /// Like [`Request::send_and_await_response()`], then deserialize the response
+    /// body from JSON as T. Read the blob with [`crate::get_blob()`] as usual.
+    ///
+    /// Failures are told apart by which hop failed, see [`AwaitError`]: the
+    /// request was not sent, the runtime could not deliver it, no response came
+    /// in time, or the response did not parse as T, or came without a blob
+    /// when [`Request::expect_blob_response()`] said it would have one.
+    ///
+    /// A response marked by [`crate::Response::error()`], or a `{"Err": "..."}`
+    /// body, as [`crate::handle!`] answers with, that does not parse as T, is a
+    /// [`RemoteError`]: the target got the request and failed to handle it.
+    /// on_remote_error says whether it fails the call or is returned as the
+    /// inner `Err`.
+    #[allow(clippy::result_large_err)]
+    pub fn send_and_await_typed<T>(
+        self,
+        timeout: u64,
+        on_remote_error: OnRemoteError,
+    ) -> Result<Result<T, RemoteError>, AwaitError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let Some(target) = self.target.clone() else {
+            return Err(BuildError::NoTarget.into());
+        };
+        let expects_blob = self.expects_blob;
+        let response = match self.send_and_await_response(timeout)? {
+            Ok(response) => response,
+            Err(e) if e.kind.is_timeout() => {
+                return Err(AwaitError::Timeout {
+                    target,
+                    waited_ms: timeout.saturating_mul(1000),
+                })
+            }
+            Err(e) => {
+                return Err(AwaitError::Transport {
+                    target,
+                    kind: e.kind,
+                })
+            }
+        };
+        let type_name = std::any::type_name::<T>();
+        let parsed = match RemoteError::from_response(&response) {
+            Some(remote) => Err(remote),
+            None => match serde_json::from_slice(response.body()) {
+                Ok(value) => Ok(value),
+                Err(e) => Err(RemoteError::from_err_body(&response).ok_or_else(|| {
+                    AwaitError::Deserialize {
+                        target: target.clone(),
+                        type_name,
+                        error: e.to_string(),
+                    }
+                })?),
+            },
+        };
+        match parsed {
+            Ok(_) if expects_blob && !crate::has_blob() => {
+                Err(AwaitError::MissingBlob { target, type_name })
+            }
+            Ok(value) => Ok(Ok(value)),
+            Err(remote) => match on_remote_error {
+                OnRemoteError::Fail => Err(AwaitError::Remote(remote)),
+                OnRemoteError::Return => Ok(Err(remote)),
+            },
+        }
+    }
+    /// Like [`Request::send_and_await_response()`], with the time left to deadline
+    /// as the timeout, as [`Deadline::timeout_secs()`] rounds it. Fails with
+    /// [`BuildError::DeadlineExceeded`], without sending, if none is left.
+    pub fn send_and_await_with_deadline(
+        self,
+        deadline: &Deadline,
+    ) -> Result<Result<Message, SendError>, BuildError> {
+        let timeout = deadline
+            .timeout_secs()
+            .ok_or(BuildError::DeadlineExceeded)?;
+        self.send_and_await_response(timeout)
+    }
+}
+
+/// The names the builder methods had when the body was called the `ipc` and the
+/// blob the `payload`, kept so that code written against them still builds, with
+/// a deprecation warning. Each delegates to its new name:
+///
+/// | old | new |
+/// |-----|-----|
+/// | `ipc` | [`Request::body()`] |
+/// | `try_ipc` | [`Request::try_body()`] |
+/// | `payload` | [`Request::blob()`] |
+/// | `payload_mime` | [`Request::blob_mime()`] |
+/// | `payload_bytes` | [`Request::blob_bytes()`] |
+/// | `try_payload_bytes` | [`Request::try_blob_bytes()`] |
+/// | `inherit_payload` | [`Request::inherit_blob()`] |
+impl Request {
+    #[deprecated(note = "renamed to `body`")]
+    pub fn ipc<T>(self, body: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.body(body)
+    }
+    #[deprecated(note = "renamed to `try_body`")]
+    pub fn try_ipc<T, E>(self, body: T) -> Result<Self, E>
+    where
+        T: TryInto<Vec<u8>, Error = E>,
+        E: std::error::Error,
+    {
+        self.try_body(body)
+    }
+    #[deprecated(note = "renamed to `blob`")]
+    pub fn payload(self, blob: LazyLoadBlob) -> Self {
+        self.blob(blob)
+    }
+    #[deprecated(note = "renamed to `blob_mime`")]
+    pub fn payload_mime(self, mime: &str) -> Self {
+        self.blob_mime(mime)
+    }
+    #[deprecated(note = "renamed to `blob_bytes`")]
+    pub fn payload_bytes<T>(self, bytes: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.blob_bytes(bytes)
+    }
+    #[deprecated(note = "renamed to `try_blob_bytes`")]
+    pub fn try_payload_bytes<T, E>(self, bytes: T) -> Result<Self, E>
+    where
+        T: TryInto<Vec<u8>, Error = E>,
+        E: std::error::Error,
+    {
+        self.try_blob_bytes(bytes)
+    }
+    #[deprecated(note = "renamed to `inherit_blob`")]
+    pub fn inherit_payload(self) -> Self {
+        self.inherit_blob()
+    }
 }
 
 impl Default for Request {
@@ -319,3 +648,224 @@ impl Default for Request {
         Request::new()
     }
 }
+
+/// Whether an explicitly set blob takes the place of the inherited one.
+fn overrides_inherited_blob(inherit: bool, blob: &Option<LazyLoadBlob>) -> bool {
+    inherit && blob.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explicit_blob_overrides_inherited() {
+        let request = Request::new().inherit_blob();
+        assert!(request.inherit);
+        assert!(!overrides_inherited_blob(request.inherit, &request.blob));
+
+        let request = request.blob_bytes(b"explicit".to_vec());
+        assert!(overrides_inherited_blob(request.inherit, &request.blob));
+        assert_eq!(request.blob.as_ref().unwrap().bytes, b"explicit");
+
+        // without inherit, a blob is just a blob
+        let request = Request::new().blob_bytes(vec![1]);
+        assert!(!overrides_inherited_blob(request.inherit, &request.blob));
+    }
+
+    #[test]
+    fn test_send_and_await_typed_diagnostics() {
+        use crate::host::{MockHost, Reply};
+
+        let host = MockHost::new();
+        let _installed = host.install();
+        let target: Address = "node.os@thumbs:app:pub.os".parse().unwrap();
+        let request = || Request::to(&target).body("get");
+        let fail = OnRemoteError::Fail;
+
+        host.reply(Reply::json(&7));
+        let value = request().send_and_await_typed::<u32>(5, fail).unwrap();
+        assert_eq!(value, Ok(7));
+
+        host.reply(Reply::body("not json"));
+        let error = request().send_and_await_typed::<u32>(5, fail).unwrap_err();
+        assert!(matches!(error, AwaitError::Deserialize { .. }));
+        assert!(
+            error
+                .to_string()
+                .starts_with("response from node.os@thumbs:app:pub.os is not a u32: "),
+            "{error}"
+        );
+
+        host.reply(Reply::json(&7));
+        let error = request()
+            .expect_blob_response()
+            .send_and_await_typed::<u32>(5, fail)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "response from node.os@thumbs:app:pub.os parsed as a u32, and no blob was attached, though one was expected"
+        );
+
+        host.reply(Reply::with_blob(
+            b"7".to_vec(),
+            LazyLoadBlob::new(Some("image/png"), b"png".to_vec()),
+        ));
+        let value = request()
+            .expect_blob_response()
+            .send_and_await_typed::<u32>(5, fail)
+            .unwrap();
+        assert_eq!(value, Ok(7));
+        assert_eq!(crate::get_blob().unwrap().bytes, b"png");
+    }
+
+    #[test]
+    fn test_send_and_await_typed_classifies_failures() {
+        use crate::host::{MockHost, Reply};
+
+        let host = MockHost::new();
+        let _installed = host.install();
+        let target: Address = "node.os@thumbs:app:pub.os".parse().unwrap();
+        let request = || Request::to(&target).body("get");
+
+        // the runtime could not deliver it
+        host.reply(Reply::Error(crate::SendErrorKind::Offline));
+        let error = request()
+            .send_and_await_typed::<u32>(5, OnRemoteError::Fail)
+            .unwrap_err();
+        assert!(
+            matches!(
+                &error,
+                AwaitError::Transport { target: to, kind: crate::SendErrorKind::Offline } if *to == target
+            ),
+            "{error:?}"
+        );
+        assert_eq!(crate::Error::from(error).kind(), crate::ErrorKind::Send);
+
+        // nothing came back in time
+        host.reply(Reply::Error(crate::SendErrorKind::Timeout));
+        let error = request()
+            .send_and_await_typed::<u32>(5, OnRemoteError::Return)
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                AwaitError::Timeout {
+                    waited_ms: 5000,
+                    ..
+                }
+            ),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            "no response from node.os@thumbs:app:pub.os within 5000 ms"
+        );
+
+        // the target got it and failed to handle it
+        let remote = RemoteError {
+            source_addr: target.clone(),
+            code: Some("not_found".to_string()),
+            message: "no such thumbnail".to_string(),
+        };
+        let reply = || {
+            Reply::json(&Err::<(), &str>("no such thumbnail"))
+                .metadata(r#"{"__error":"not_found"}"#)
+        };
+        host.reply(reply());
+        let error = request()
+            .send_and_await_typed::<u32>(5, OnRemoteError::Fail)
+            .unwrap_err();
+        assert!(
+            matches!(&error, AwaitError::Remote(e) if *e == remote),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            "node.os@thumbs:app:pub.os failed (not_found): no such thumbnail"
+        );
+        host.reply(reply());
+        let returned = request()
+            .send_and_await_typed::<u32>(5, OnRemoteError::Return)
+            .unwrap();
+        assert_eq!(returned, Err(remote));
+
+        // marked, it is an error even if T would parse it
+        host.reply(reply());
+        let returned = request()
+            .send_and_await_typed::<Result<(), String>>(5, OnRemoteError::Return)
+            .unwrap();
+        assert!(returned.is_err());
+
+        // as handle! answers, unmarked: an error only if T does not parse it
+        host.reply(Reply::json(&Err::<(), String>("empty key".to_string())));
+        let returned = request()
+            .send_and_await_typed::<u32>(5, OnRemoteError::Return)
+            .unwrap();
+        assert_eq!(returned.unwrap_err().code, None);
+        host.reply(Reply::json(&Err::<(), String>("empty key".to_string())));
+        let returned = request()
+            .send_and_await_typed::<Result<(), String>>(5, OnRemoteError::Return)
+            .unwrap();
+        assert_eq!(returned, Ok(Err("empty key".to_string())));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_old_builder_names_delegate() {
+        let old = Request::new()
+            .ipc("body")
+            .payload_bytes("bytes")
+            .payload_mime("text/plain")
+            .inherit_payload();
+        let new = Request::new()
+            .body("body")
+            .blob_bytes("bytes")
+            .blob_mime("text/plain")
+            .inherit_blob();
+        assert_eq!(old.body, new.body);
+        assert_eq!(old.blob, new.blob);
+        assert_eq!(old.inherit, new.inherit);
+
+        let blob = LazyLoadBlob::new(Some("a/b"), b"x".to_vec());
+        let old = Request::new()
+            .try_ipc(vec![1u8])
+            .unwrap()
+            .payload(blob.clone())
+            .try_payload_bytes(vec![2u8])
+            .unwrap();
+        assert_eq!(old.body, Some(vec![1]));
+        assert_eq!(old.blob, Some(LazyLoadBlob::new(Some("a/b"), vec![2])));
+    }
+
+    #[test]
+    fn test_metadata_keeps_via_and_version() {
+        let origin: Address = "client.os@app:pkg:dev".parse().unwrap();
+        let request = Request::new()
+            .metadata("replaced")
+            .via(&origin)
+            .with_version("1.2.0", "1.0.0")
+            .unwrap();
+
+        // metadata set afterwards, plain or JSON, replaces only itself
+        let plain = request.clone().metadata("plain").metadata_to_send();
+        let object = metadata_object(plain.as_deref()).unwrap();
+        assert_eq!(object[USER_METADATA_KEY], "plain");
+        assert_eq!(object[crate::guard::VIA_KEY], origin.to_string());
+        assert_eq!(object[crate::version::VERSION_KEY]["version"], "1.2.0");
+
+        let json = request.metadata(r#"{"b":2}"#).metadata_to_send();
+        let object = metadata_object(json.as_deref()).unwrap();
+        assert_eq!(object["b"], 2);
+        assert_eq!(
+            crate::guard::forwarded_from(json.as_deref()).unwrap(),
+            Some(origin)
+        );
+        assert!(object.contains_key(crate::version::VERSION_KEY));
+        assert!(!object.contains_key(USER_METADATA_KEY));
+
+        // without them, metadata is sent as set
+        let request = Request::new().metadata("a").metadata("b");
+        assert_eq!(request.metadata_to_send().as_deref(), Some("b"));
+    }
+}
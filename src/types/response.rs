@@ -2,12 +2,17 @@ use crate::{our_capabilities, types::message::BuildError, Address, Capability, L
 
 /// `Response` builder. Use [`Response::new()`] to start a `Response`, then build it,
 /// then call [`Response::send()`] on it to fire.
+///
+/// Methods from before the body and blob were so called, such as `ipc()` and
+/// `payload_bytes()`, still work but are deprecated; see the table above
+/// [`Response::ipc()`] for their new names.
 pub struct Response {
     inherit: bool,
     body: Option<Vec<u8>>,
     metadata: Option<String>,
     blob: Option<LazyLoadBlob>,
     capabilities: Vec<Capability>,
+    withheld: Option<crate::guard::Denied>,
 }
 
 impl Response {
@@ -20,8 +25,20 @@ impl Response {
             metadata: None,
             blob: None,
             capabilities: vec![],
+            withheld: None,
         }
     }
+    /// Start building a `Response` saying that handling the request failed, with
+    /// a body of `{"Err": message}`, as [`crate::handle!`] answers with, and code
+    /// under [`crate::ERROR_KEY`] in its metadata, so that
+    /// [`crate::Request::send_and_await_typed()`] on the other end returns a
+    /// [`crate::RemoteError`] rather than failing to parse it. Codes are up to
+    /// the protocol; set more metadata with [`Response::metadata_field()`].
+    pub fn error(code: &str, message: impl std::fmt::Display) -> Self {
+        let body = serde_json::to_vec(&Err::<(), String>(message.to_string())).unwrap();
+        let metadata = serde_json::json!({ crate::ERROR_KEY: code }).to_string();
+        Response::new().body(body).metadata(&metadata)
+    }
     /// Set whether this `Response` will "inherit" the blob of the [`crate::Request`]
     /// that this process most recently received. Unlike with [`crate::Request`]s, the
     /// inherit field of a `Response` only deals with blob attachment, since
@@ -33,14 +50,14 @@ impl Response {
         self.inherit = inherit;
         self
     }
-    /// Set the IPC body (Inter-Process Communication) value for this message. This field
-    /// is mandatory. An IPC body is simply a vector of bytes. Process developers are
+    /// Set the body for this message. This field
+    /// is mandatory. A body is simply a vector of bytes. Process developers are
     /// responsible for architecting the serialization/derserialization strategy
     /// for these bytes, but the simplest and most common strategy is just to use
     /// a JSON spec that gets stored in bytes as a UTF-8 string.
     ///
     /// If the serialization strategy is complex, it's best to define it as an impl
-    /// of [`TryInto`] on your IPC body type, then use [`Response::try_body()`] instead of this.
+    /// of [`TryInto`] on your body type, then use [`Response::try_body()`] instead of this.
     pub fn body<T>(mut self, body: T) -> Self
     where
         T: Into<Vec<u8>>,
@@ -48,10 +65,10 @@ impl Response {
         self.body = Some(body.into());
         self
     }
-    /// Set the IPC body (Inter-Process Communication) value for this message, using a
+    /// Set the body for this message, using a
     /// type that's got an implementation of [`TryInto`] for `Vec<u8>`. It's best
-    /// to define an IPC body type within your app, then implement [`TryFrom`]/[`TryInto`] for
-    /// all IPC body serialization/deserialization.
+    /// to define a body type within your app, then implement [`TryFrom`]/[`TryInto`] for
+    /// all body serialization/deserialization.
     pub fn try_body<T, E>(mut self, body: T) -> Result<Self, E>
     where
         T: TryInto<Vec<u8>, Error = E>,
@@ -60,9 +77,37 @@ impl Response {
         self.body = Some(body.try_into()?);
         Ok(self)
     }
+    /// Set the body to value serialized with bincode. Like JSON, the receiver
+    /// must know to expect it; see [`Response::encoded_body()`] otherwise.
+    pub fn body_bincode<T>(self, value: &T) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        Ok(self.body(crate::encoding::Encoding::Bincode.encode(value)?))
+    }
+    /// Set the body to value serialized with MessagePack (with field names).
+    pub fn body_msgpack<T>(self, value: &T) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        Ok(self.body(crate::encoding::Encoding::MsgPack.encode(value)?))
+    }
+    /// Set the body to value serialized with encoding and prefixed with a
+    /// one-byte tag, so the receiver can read it with [`crate::Message::body_as_encoded()`]
+    /// whichever encoding was chosen.
+    pub fn encoded_body<T>(
+        self,
+        encoding: crate::encoding::Encoding,
+        value: &T,
+    ) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        Ok(self.body(crate::encoding::encoded(encoding, value)?))
+    }
     /// Set the metadata field for this response. Metadata is simply a [`String`].
     /// Metadata should usually be used for middleware and other message-passing
-    /// situations that require the original IPC body and blob to be preserved.
+    /// situations that require the original body and blob to be preserved.
     /// As such, metadata should not always be expected to reach the final destination
     /// of this response unless the full chain of behavior is known / controlled by
     /// the developer.
@@ -70,18 +115,31 @@ impl Response {
         self.metadata = Some(metadata.to_string());
         self
     }
+    /// Set key in the metadata of this response to value, as JSON, as
+    /// [`crate::Request::metadata_field()`] does.
+    pub fn metadata_field<T>(mut self, key: &str, value: T) -> anyhow::Result<Self>
+    where
+        T: serde::Serialize,
+    {
+        self.metadata = Some(crate::types::message::with_metadata_field(
+            self.metadata.as_deref(),
+            key,
+            value,
+        )?);
+        Ok(self)
+    }
     /// Set the blob of this response. A [`LazyLoadBlob`] holds bytes and an optional
     /// MIME type.
     ///
-    /// The purpose of having a blob field distinct from the IPC body field is to enable
+    /// The purpose of having a blob field distinct from the body field is to enable
     /// performance optimizations in all sorts of situations. [`LazyLoadBlob`]s are only brought
     /// across the runtime<>Wasm boundary if the process calls `get_blob()`, and this
     /// saves lots of work in data-intensive pipelines.
     ///
-    /// [`LazyLoadBlob`]s also provide a place for less-structured data, such that an IPC body type
+    /// [`LazyLoadBlob`]s also provide a place for less-structured data, such that a body type
     /// can be quickly locked in and upgraded within an app-protocol without breaking
     /// changes, while still allowing freedom to adjust the contents and shape of a
-    /// blob. IPC body formats should be rigorously defined.
+    /// blob. Body formats should be rigorously defined.
     pub fn blob(mut self, blob: LazyLoadBlob) -> Self {
         self.blob = Some(blob);
         self
@@ -161,10 +219,45 @@ impl Response {
         );
         self
     }
-    /// Attempt to send the `Response`. This will only fail if the IPC body field of
-    /// the `Response` has not yet been set using `body()` or `try_body()`.
+    /// Send this `Response` only if predicate holds for who it goes to, the
+    /// [`crate::current_source()`]. Otherwise, or if there is no request being
+    /// answered, a [`crate::guard::Denied::Withheld`] error is sent in its place,
+    /// as [`crate::guard::require()`] sends, with none of this `Response`'s
+    /// metadata, blob or capabilities. The decision is made here, so the rest of
+    /// the `Response` may be built before or after.
+    ///
+    /// See [`crate::guard::only_local()`] and [`crate::guard::only_package()`].
+    pub fn only_if(mut self, predicate: impl FnOnce(&Address) -> bool) -> Self {
+        let source = crate::current_source();
+        if !source.as_ref().is_some_and(predicate) {
+            self.withheld = Some(crate::guard::Denied::Withheld { sender: source });
+        }
+        self
+    }
+    /// Attempt to send the `Response`. This will only fail if the body field of
+    /// the `Response` has not yet been set using `body()` or `try_body()`, or the
+    /// body or blob is over its [`crate::limits`].
     pub fn send(self) -> Result<(), BuildError> {
-        if let Some(body) = self.body {
+        if let Some(body) = &self.body {
+            crate::limits::check(body, self.blob.as_ref())?;
+        }
+        self.unchecked_send()
+    }
+    /// Like [`Response::send()`], but send a body or blob over its [`crate::limits`] too.
+    pub fn unchecked_send(self) -> Result<(), BuildError> {
+        if let (Some(_), Some(denied)) = (&self.body, &self.withheld) {
+            crate::debug!("{denied}");
+            crate::send_response(
+                &crate::hyperware::process::standard::Response {
+                    inherit: false,
+                    body: crate::guard::denied_body(denied),
+                    metadata: None,
+                    capabilities: vec![],
+                },
+                None,
+            );
+            Ok(())
+        } else if let Some(body) = self.body {
             crate::send_response(
                 &crate::hyperware::process::standard::Response {
                     inherit: self.inherit,
@@ -181,8 +274,110 @@ impl Response {
     }
 }
 
+/// The names the builder methods had when the body was called the `ipc` and the
+/// blob the `payload`, kept so that code written against them still builds, with
+/// a deprecation warning. Each delegates to its new name:
+///
+/// | old | new |
+/// |-----|-----|
+/// | `ipc` | [`Response::body()`] |
+/// | `try_ipc` | [`Response::try_body()`] |
+/// | `payload` | [`Response::blob()`] |
+/// | `payload_mime` | [`Response::blob_mime()`] |
+/// | `payload_bytes` | [`Response::blob_bytes()`] |
+/// | `try_payload_bytes` | [`Response::try_blob_bytes()`] |
+impl Response {
+    #[deprecated(note = "renamed to `body`")]
+    pub fn ipc<T>(self, body: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.body(body)
+    }
+    #[deprecated(note = "renamed to `try_body`")]
+    pub fn try_ipc<T, E>(self, body: T) -> Result<Self, E>
+    where
+        T: TryInto<Vec<u8>, Error = E>,
+        E: std::error::Error,
+    {
+        self.try_body(body)
+    }
+    #[deprecated(note = "renamed to `blob`")]
+    pub fn payload(self, blob: LazyLoadBlob) -> Self {
+        self.blob(blob)
+    }
+    #[deprecated(note = "renamed to `blob_mime`")]
+    pub fn payload_mime(self, mime: &str) -> Self {
+        self.blob_mime(mime)
+    }
+    #[deprecated(note = "renamed to `blob_bytes`")]
+    pub fn payload_bytes<T>(self, bytes: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.blob_bytes(bytes)
+    }
+    #[deprecated(note = "renamed to `try_blob_bytes`")]
+    pub fn try_payload_bytes<T, E>(self, bytes: T) -> Result<Self, E>
+    where
+        T: TryInto<Vec<u8>, Error = E>,
+        E: std::error::Error,
+    {
+        self.try_blob_bytes(bytes)
+    }
+}
+
 impl Default for Response {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_old_builder_names_delegate() {
+        let old = Response::new()
+            .ipc("body")
+            .payload_bytes("bytes")
+            .payload_mime("text/plain");
+        let new = Response::new()
+            .body("body")
+            .blob_bytes("bytes")
+            .blob_mime("text/plain");
+        assert_eq!(old.body, new.body);
+        assert_eq!(old.blob, new.blob);
+
+        let old = Response::new()
+            .try_ipc(vec![1u8])
+            .unwrap()
+            .payload(LazyLoadBlob::new(Some("a/b"), b"x".to_vec()))
+            .try_payload_bytes(vec![2u8])
+            .unwrap();
+        assert_eq!(old.body, Some(vec![1]));
+        assert_eq!(old.blob, Some(LazyLoadBlob::new(Some("a/b"), vec![2])));
+    }
+
+    #[test]
+    fn test_error_is_recognized() {
+        let response = Response::error("not_found", "no such key")
+            .metadata_field("attempt", 2)
+            .unwrap();
+        assert_eq!(
+            response.body.as_deref(),
+            Some(&br#"{"Err":"no such key"}"#[..])
+        );
+        let answered = crate::test_utils::MessageBuilder::response()
+            .from("node.os@kv:app:pub.os")
+            .body(response.body.unwrap())
+            .metadata(response.metadata.as_deref().unwrap())
+            .build();
+        let remote = crate::RemoteError::from_response(&answered).unwrap();
+        assert_eq!(remote.code.as_deref(), Some("not_found"));
+        assert_eq!(remote.message, "no such key");
+        assert_eq!(remote.source_addr.to_string(), "node.os@kv:app:pub.os");
+    }
+}
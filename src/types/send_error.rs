@@ -1,4 +1,4 @@
-use crate::{Address, LazyLoadBlob, Message, _wit_message_to_message};
+use crate::{_wit_message_to_message, Address, LazyLoadBlob, Message};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
@@ -32,6 +32,12 @@ impl std::fmt::Display for SendError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.kind {
             SendErrorKind::Offline => write!(f, "Offline"),
+            SendErrorKind::Timeout if crate::types::system_process::is_missing(self) => write!(
+                f,
+                "Timeout: target runtime process {} not found \u{2014} are you on an old runtime? \
+                 See set_system_naming()",
+                self.target.process
+            ),
             SendErrorKind::Timeout => write!(f, "Timeout"),
         }
     }
@@ -65,7 +71,7 @@ pub fn _wit_send_error_to_send_error(
     send_err: crate::hyperware::process::standard::SendError,
     context: Option<Vec<u8>>,
 ) -> SendError {
-    SendError {
+    let error = SendError {
         kind: match send_err.kind {
             crate::hyperware::process::standard::SendErrorKind::Offline => SendErrorKind::Offline,
             crate::hyperware::process::standard::SendErrorKind::Timeout => SendErrorKind::Timeout,
@@ -74,5 +80,7 @@ pub fn _wit_send_error_to_send_error(
         message: _wit_message_to_message(send_err.target, send_err.message),
         lazy_load_blob: send_err.lazy_load_blob,
         context,
-    }
+    };
+    crate::types::system_process::warn_if_missing(&error);
+    error
 }
@@ -0,0 +1,323 @@
+use crate::{Address, ProcessId, SendError, SendErrorKind};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+
+/// The package of most of the runtime's own processes.
+pub const SYSTEM_PACKAGE: &str = "distro";
+
+/// The publisher of the runtime's own processes. A fork that publishes them
+/// under another name changes this.
+pub const SYSTEM_PUBLISHER: &str = "sys";
+
+/// How the runtime names its processes. Select it with [`set_system_naming()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SystemNaming {
+    /// `vfs:distro:sys`, with [`SYSTEM_PACKAGE`] and [`SYSTEM_PUBLISHER`].
+    #[default]
+    Current,
+    /// `vfs:sys:uqbar`, as older runtimes name them.
+    Legacy,
+}
+
+thread_local! {
+    static NAMING: Cell<SystemNaming> = const { Cell::new(SystemNaming::Current) };
+    static WARNED_MISSING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Address the runtime's processes as naming does from now on, e.g.
+/// [`SystemNaming::Legacy`] for a node on an older runtime. This covers every
+/// request this library makes to them.
+pub fn set_system_naming(naming: SystemNaming) {
+    NAMING.set(naming);
+}
+
+pub fn system_naming() -> SystemNaming {
+    NAMING.get()
+}
+
+/// The processes the runtime provides, for addressing them without spelling out
+/// their ids. A [`ProcessId`] holds `String`s, so it can't be a `const`; this can,
+/// and builds one when needed.
+///
+/// ```
+/// use hyperware_process_lib::SystemProcess;
+///
+/// const STORE: SystemProcess = SystemProcess::Kv;
+/// assert_eq!(STORE.process_id().to_string(), "kv:distro:sys");
+/// assert_eq!(STORE.address("our").to_string(), "our@kv:distro:sys");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SystemProcess {
+    Vfs,
+    HttpServer,
+    HttpClient,
+    Kv,
+    Sqlite,
+    Timer,
+    Eth,
+    Kernel,
+    Net,
+    Terminal,
+}
+
+impl SystemProcess {
+    pub const ALL: [SystemProcess; 10] = [
+        SystemProcess::Vfs,
+        SystemProcess::HttpServer,
+        SystemProcess::HttpClient,
+        SystemProcess::Kv,
+        SystemProcess::Sqlite,
+        SystemProcess::Timer,
+        SystemProcess::Eth,
+        SystemProcess::Kernel,
+        SystemProcess::Net,
+        SystemProcess::Terminal,
+    ];
+
+    /// The process name, e.g. `"http-server"`.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            SystemProcess::Vfs => "vfs",
+            SystemProcess::HttpServer => "http-server",
+            SystemProcess::HttpClient => "http-client",
+            SystemProcess::Kv => "kv",
+            SystemProcess::Sqlite => "sqlite",
+            SystemProcess::Timer => "timer",
+            SystemProcess::Eth => "eth",
+            SystemProcess::Kernel => "kernel",
+            SystemProcess::Net => "net",
+            SystemProcess::Terminal => "terminal",
+        }
+    }
+
+    /// The package name under the current [`system_naming()`].
+    pub fn package(&self) -> &'static str {
+        self.package_in(system_naming())
+    }
+
+    /// The publisher under the current [`system_naming()`].
+    pub fn publisher(&self) -> &'static str {
+        self.publisher_in(system_naming())
+    }
+
+    /// The package name under naming: [`SYSTEM_PACKAGE`] (or `sys`, for
+    /// [`SystemNaming::Legacy`]), except for the terminal, which is its own package.
+    pub const fn package_in(&self, naming: SystemNaming) -> &'static str {
+        match (self, naming) {
+            (SystemProcess::Terminal, _) => "terminal",
+            (_, SystemNaming::Current) => SYSTEM_PACKAGE,
+            (_, SystemNaming::Legacy) => "sys",
+        }
+    }
+
+    pub const fn publisher_in(&self, naming: SystemNaming) -> &'static str {
+        match naming {
+            SystemNaming::Current => SYSTEM_PUBLISHER,
+            SystemNaming::Legacy => "uqbar",
+        }
+    }
+
+    /// Which system process id names, under either naming.
+    pub fn from_process_id(id: &ProcessId) -> Option<SystemProcess> {
+        SystemProcess::ALL.into_iter().find(|process| {
+            [SystemNaming::Current, SystemNaming::Legacy]
+                .into_iter()
+                .any(|naming| {
+                    id.process_name == process.name()
+                        && id.package_name == process.package_in(naming)
+                        && id.publisher_node == process.publisher_in(naming)
+                })
+        })
+    }
+
+    pub fn process_id(&self) -> ProcessId {
+        ProcessId::new(Some(self.name()), self.package(), self.publisher())
+    }
+
+    /// The process on node, e.g. [`crate::our_node()`], or `"our"`, which the
+    /// runtime reads as our node. It is named as [`system_naming()`] says.
+    pub fn address(&self, node: &str) -> Address {
+        Address::new(node, self.process_id())
+    }
+}
+
+impl std::fmt::Display for SystemProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.name(), self.package(), self.publisher())
+    }
+}
+
+impl From<SystemProcess> for ProcessId {
+    fn from(process: SystemProcess) -> Self {
+        process.process_id()
+    }
+}
+
+impl PartialEq<SystemProcess> for ProcessId {
+    fn eq(&self, other: &SystemProcess) -> bool {
+        self.process_name == other.name()
+            && self.package_name == other.package()
+            && self.publisher_node == other.publisher()
+    }
+}
+
+/// Whether error is a request to one of our runtime processes timing out. This
+/// is what a request to a process that doesn't exist looks like, e.g. when
+/// addressed with the wrong [`SystemNaming`] for the runtime.
+pub(crate) fn is_missing(error: &SendError) -> bool {
+    let local = error.target.node == "our"
+        || crate::try_our().is_some_and(|our| our.node == error.target.node);
+    matches!(error.kind, SendErrorKind::Timeout)
+        && local
+        && SystemProcess::from_process_id(&error.target.process).is_some()
+}
+
+/// Warn about the first error that [`is_missing()`], since the callers of the
+/// requests that fail this way often keep only the [`SendErrorKind`].
+pub(crate) fn warn_if_missing(error: &SendError) {
+    if is_missing(error) && !WARNED_MISSING.replace(true) {
+        crate::warn!("{error}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::vfs::{self, FileMetadata, FileType, VfsResponse};
+    use crate::Request;
+
+    fn targets(host: &MockHost) -> Vec<String> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendRequest { target, .. } | Call::SendAndAwaitResponse { target, .. } => {
+                    Some(target.to_string())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn warnings(host: &MockHost) -> Vec<String> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::Print { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_canonical_strings() {
+        let ids: Vec<String> = SystemProcess::ALL
+            .iter()
+            .map(|process| process.process_id().to_string())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "vfs:distro:sys",
+                "http-server:distro:sys",
+                "http-client:distro:sys",
+                "kv:distro:sys",
+                "sqlite:distro:sys",
+                "timer:distro:sys",
+                "eth:distro:sys",
+                "kernel:distro:sys",
+                "net:distro:sys",
+                "terminal:terminal:sys",
+            ]
+        );
+        for process in SystemProcess::ALL {
+            assert_eq!(process.to_string(), process.process_id().to_string());
+            assert_eq!(process.process_id().validate(), Ok(()));
+            assert_eq!(process.process_id(), process);
+        }
+        assert_eq!(
+            SystemProcess::HttpClient.address("node.os"),
+            "node.os@http-client:distro:sys".parse::<Address>().unwrap()
+        );
+        let timer: ProcessId = "timer:distro:sys".parse().unwrap();
+        assert!(timer == SystemProcess::Timer && timer != SystemProcess::Net);
+    }
+
+    #[test]
+    fn test_both_namings_with_mocked_host() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let metadata = || {
+            Reply::json(&VfsResponse::Metadata(FileMetadata {
+                file_type: FileType::File,
+                len: 1,
+                created: None,
+                modified: None,
+            }))
+        };
+
+        host.reply(metadata());
+        vfs::metadata("/app:sys/file", None).unwrap();
+        crate::timer::set_timer(10, None).unwrap();
+        assert_eq!(
+            targets(&host),
+            ["tester.os@vfs:distro:sys", "our@timer:distro:sys"]
+        );
+
+        set_system_naming(SystemNaming::Legacy);
+        host.reply(metadata());
+        vfs::metadata("/app:sys/file", None).unwrap();
+        crate::timer::set_timer(10, None).unwrap();
+        assert_eq!(
+            targets(&host),
+            ["tester.os@vfs:sys:uqbar", "our@timer:sys:uqbar"]
+        );
+        // responses from the legacy processes are recognized too
+        let legacy: ProcessId = "timer:sys:uqbar".parse().unwrap();
+        assert!(legacy == SystemProcess::Timer);
+        assert_eq!(
+            SystemProcess::from_process_id(&legacy),
+            Some(SystemProcess::Timer)
+        );
+        assert_eq!(
+            SystemProcess::Terminal.to_string(),
+            "terminal:terminal:uqbar"
+        );
+    }
+
+    #[test]
+    fn test_timeout_against_system_process() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let timeout = |target: Address| {
+            host.reply(Reply::Error(SendErrorKind::Timeout));
+            Request::to(target)
+                .body("hi")
+                .send_and_await_response(1)
+                .unwrap()
+                .unwrap_err()
+                .to_string()
+        };
+
+        let error = timeout(SystemProcess::Vfs.address("our"));
+        assert_eq!(
+            error,
+            "Timeout: target runtime process vfs:distro:sys not found \u{2014} are you on an old \
+             runtime? See set_system_naming()"
+        );
+        // warned about once, as callers like vfs only keep the kind
+        assert_eq!(warnings(&host), [format!("[WARN] {error}")]);
+        // under the legacy name, from our node, it is known as well
+        assert!(timeout("tester.os@vfs:sys:uqbar".parse().unwrap()).contains("old runtime"));
+        assert!(warnings(&host).is_empty());
+
+        // other processes, and other nodes' runtimes, time out plainly
+        assert_eq!(
+            timeout("tester.os@chat:chat:sys".parse().unwrap()),
+            "Timeout"
+        );
+        assert_eq!(timeout(SystemProcess::Net.address("them.os")), "Timeout");
+    }
+}
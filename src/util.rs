@@ -0,0 +1,584 @@
+use crate::types::message::BuildError;
+use crate::{Address, Capability, LazyLoadBlob, Request};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use thiserror::Error;
+
+/// How stale the clock reading a [`RateLimiter`] refills from may be, see
+/// [`crate::timer::now_ms_cached()`].
+const CLOCK_STALENESS_MS: u64 = 10;
+
+/// A token bucket for pacing outgoing requests: holds at most capacity tokens,
+/// refills at refill_per_sec, and each request takes one. Requests that find the
+/// bucket empty can be queued with [`RateLimiter::acquire_or_defer()`] and sent
+/// later, in order, by [`RateLimiter::pump()`].
+///
+/// A `RateLimiter` serializes with its token count and queue, so it can be kept
+/// in the process state to hold to the budget across restarts.
+///
+/// ```no_run
+/// use hyperware_process_lib::{util::RateLimiter, Address, Request};
+///
+/// // 60 requests per minute, in bursts of up to 10
+/// let mut limiter = RateLimiter::new(10, 1.0);
+/// let api: Address = "our@api:my-app:publisher.os".parse().unwrap();
+/// limiter
+///     .acquire_or_defer(Request::to(api).body(b"...".to_vec()))
+///     .unwrap();
+/// // and whenever a recurring timer fires:
+/// limiter.pump();
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RateLimiter {
+    capacity: u32,
+    refill_per_sec: f64,
+    tokens: f64,
+    /// When tokens were last refilled, in ms since the UNIX epoch.
+    refilled_at_ms: Option<u64>,
+    queue: VecDeque<QueuedRequest>,
+}
+
+/// What [`RateLimiter::acquire_or_defer()`] did with a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deferred {
+    /// A token was available and the request was sent.
+    Sent,
+    /// The request was queued, behind position others.
+    Queued { position: usize },
+}
+
+impl RateLimiter {
+    /// A limiter with a full bucket of capacity tokens.
+    pub fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        RateLimiter {
+            capacity,
+            refill_per_sec: refill_per_sec.max(0.0),
+            tokens: capacity as f64,
+            refilled_at_ms: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Take a token if one is available.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(now())
+    }
+
+    /// Send request if a token is available, otherwise queue it for [`RateLimiter::pump()`].
+    /// Requests are also queued while others are waiting, so they go out in order.
+    /// Fails, without using a token, if request has no target or body.
+    pub fn acquire_or_defer(&mut self, request: Request) -> Result<Deferred, BuildError> {
+        self.acquire_or_defer_with(now(), request, |request| request.send())
+    }
+
+    /// Send as many queued requests, oldest first, as there are tokens for.
+    /// Returns how many were sent. Meant to be called when a recurring timer fires.
+    pub fn pump(&mut self) -> usize {
+        self.pump_with(now(), |request| {
+            // queued requests have a target and body, so this can't fail
+            let _ = request.send();
+        })
+    }
+
+    /// How many requests are waiting to be sent.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// How long until the next token is available, in ms: 0 if one is now, and
+    /// `None` if the limiter never refills.
+    pub fn next_token_in_ms(&mut self) -> Option<u64> {
+        self.next_token_in_ms_at(now())
+    }
+
+    /// The number of whole tokens available.
+    pub fn available(&mut self) -> u32 {
+        self.refill(now());
+        self.tokens as u32
+    }
+
+    fn refill(&mut self, now_ms: u64) {
+        if let Some(refilled_at_ms) = self.refilled_at_ms {
+            // a clock that goes backwards refills nothing
+            let elapsed_ms = now_ms.saturating_sub(refilled_at_ms);
+            self.tokens = (self.tokens + elapsed_ms as f64 * self.refill_per_sec / 1000.0)
+                .min(self.capacity as f64);
+        }
+        self.refilled_at_ms = Some(self.refilled_at_ms.map_or(now_ms, |at| at.max(now_ms)));
+    }
+
+    fn try_acquire_at(&mut self, now_ms: u64) -> bool {
+        self.refill(now_ms);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn acquire_or_defer_with<F>(
+        &mut self,
+        now_ms: u64,
+        request: Request,
+        send: F,
+    ) -> Result<Deferred, BuildError>
+    where
+        F: FnOnce(Request) -> Result<(), BuildError>,
+    {
+        let queued = QueuedRequest::try_from(request)?;
+        if self.queue.is_empty() && self.try_acquire_at(now_ms) {
+            send(queued.into())?;
+            return Ok(Deferred::Sent);
+        }
+        self.queue.push_back(queued);
+        Ok(Deferred::Queued {
+            position: self.queue.len() - 1,
+        })
+    }
+
+    fn pump_with<F: FnMut(Request)>(&mut self, now_ms: u64, mut send: F) -> usize {
+        let mut sent = 0;
+        while !self.queue.is_empty() && self.try_acquire_at(now_ms) {
+            send(self.queue.pop_front().unwrap().into());
+            sent += 1;
+        }
+        sent
+    }
+
+    fn next_token_in_ms_at(&mut self, now_ms: u64) -> Option<u64> {
+        self.refill(now_ms);
+        if self.tokens >= 1.0 {
+            Some(0)
+        } else if self.refill_per_sec > 0.0 && self.capacity > 0 {
+            // less a rounding error, so that a token due in 1ms is not reported as 2ms off
+            let missing_ms = (1.0 - self.tokens) * 1000.0 / self.refill_per_sec;
+            Some((missing_ms - 1e-9).ceil() as u64)
+        } else {
+            None
+        }
+    }
+}
+
+/// The cached clock, or for the rare failure to read it, a time that refills nothing.
+fn now() -> u64 {
+    crate::timer::now_ms_cached(CLOCK_STALENESS_MS).unwrap_or_default()
+}
+
+/// A [`Request`] with its target and body checked, in a form that serializes.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct QueuedRequest {
+    target: Address,
+    inherit: bool,
+    timeout: Option<u64>,
+    body: Vec<u8>,
+    metadata: Option<String>,
+    blob: Option<(Option<String>, Vec<u8>)>,
+    context: Option<Vec<u8>>,
+    capabilities: Vec<Capability>,
+}
+
+impl TryFrom<Request> for QueuedRequest {
+    type Error = BuildError;
+
+    fn try_from(request: Request) -> Result<Self, BuildError> {
+        let metadata = request.metadata_to_send();
+        Ok(QueuedRequest {
+            target: request.target.ok_or(BuildError::NoTarget)?,
+            inherit: request.inherit,
+            timeout: request.timeout,
+            body: request.body.ok_or(BuildError::NoBody)?,
+            metadata,
+            blob: request.blob.map(|blob| (blob.mime, blob.bytes)),
+            context: request.context,
+            capabilities: request.capabilities,
+        })
+    }
+}
+
+impl From<QueuedRequest> for Request {
+    fn from(queued: QueuedRequest) -> Self {
+        let mut request = Request::to(queued.target);
+        request.inherit = queued.inherit;
+        request.timeout = queued.timeout;
+        request.body = Some(queued.body);
+        request.metadata = queued.metadata;
+        request.blob = queued
+            .blob
+            .map(|(mime, bytes)| LazyLoadBlob { mime, bytes });
+        request.context = queued.context;
+        request.capabilities = queued.capabilities;
+        request
+    }
+}
+
+/// The Crockford base32 alphabet [`Uid`]s are written in.
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const MASK_48: u128 = (1 << 48) - 1;
+
+/// A sortable unique identifier, for correlating messages and naming records.
+///
+/// Its 16 bytes are a 48-bit millisecond timestamp, from
+/// [`crate::timer::now_ms_cached()`], then a 48-bit prefix drawn at random once
+/// per process, then a 32-bit counter. The `Uid`s a process makes are strictly
+/// increasing, even within a millisecond or if the clock goes backwards, and
+/// those of different processes order by time to within the clock's precision.
+///
+/// It is written as 26 characters of Crockford base32, which sort as the `Uid`s
+/// do, and serializes as that string.
+///
+/// ```
+/// use hyperware_process_lib::util::Uid;
+///
+/// let first = Uid::new();
+/// let second = Uid::new();
+/// assert!(first < second);
+/// assert!(first.to_string() < second.to_string());
+/// assert_eq!(first.to_string().parse::<Uid>().unwrap(), first);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uid(u128);
+
+/// Why a string is not a [`Uid`].
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum UidParseError {
+    #[error("a uid is 26 characters, not {0}")]
+    Length(usize),
+    #[error("{0:?} is not a Crockford base32 digit")]
+    Character(char),
+    /// 26 characters hold 130 bits: the first may only be 0-7.
+    #[error("uid is over 128 bits")]
+    Overflow,
+}
+
+struct UidGenerator {
+    prefix: u64,
+    last_ms: u64,
+    counter: u32,
+}
+
+thread_local! {
+    static UID_GENERATOR: RefCell<Option<UidGenerator>> = const { RefCell::new(None) };
+}
+
+impl Uid {
+    /// A `Uid` greater than any this process made before.
+    pub fn new() -> Self {
+        let now_ms = crate::timer::now_ms_cached(CLOCK_STALENESS_MS).unwrap_or_default();
+        UID_GENERATOR.with_borrow_mut(|generator| {
+            generator
+                .get_or_insert_with(|| UidGenerator::new(rand::random()))
+                .next(now_ms)
+        })
+    }
+
+    /// The `Uid` with these parts, of which only the low 48 bits of
+    /// timestamp_ms and prefix are kept.
+    pub fn from_parts(timestamp_ms: u64, prefix: u64, counter: u32) -> Self {
+        Uid(((timestamp_ms as u128 & MASK_48) << 80)
+            | ((prefix as u128 & MASK_48) << 32)
+            | counter as u128)
+    }
+
+    /// The time the `Uid` was made, in milliseconds since the Unix epoch.
+    pub fn timestamp_ms(&self) -> u64 {
+        (self.0 >> 80) as u64
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Uid(u128::from_be_bytes(bytes))
+    }
+}
+
+impl Default for Uid {
+    fn default() -> Self {
+        Uid::new()
+    }
+}
+
+impl UidGenerator {
+    fn new(prefix: u64) -> Self {
+        UidGenerator {
+            prefix: prefix & MASK_48 as u64,
+            last_ms: 0,
+            counter: 0,
+        }
+    }
+
+    /// The next `Uid` at now_ms, or if the clock hasn't moved past the last one,
+    /// at the last time with the counter bumped, carrying into the time on overflow.
+    fn next(&mut self, now_ms: u64) -> Uid {
+        if now_ms > self.last_ms {
+            self.last_ms = now_ms;
+            self.counter = 0;
+        } else if let Some(counter) = self.counter.checked_add(1) {
+            self.counter = counter;
+        } else {
+            self.last_ms += 1;
+            self.counter = 0;
+        }
+        Uid::from_parts(self.last_ms, self.prefix, self.counter)
+    }
+}
+
+impl std::fmt::Display for Uid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut encoded = [0u8; 26];
+        for (i, digit) in encoded.iter_mut().enumerate() {
+            let shift = 5 * (25 - i);
+            *digit = CROCKFORD[((self.0 >> shift) & 31) as usize];
+        }
+        // only ASCII digits were written
+        f.write_str(std::str::from_utf8(&encoded).unwrap())
+    }
+}
+
+impl std::str::FromStr for Uid {
+    type Err = UidParseError;
+
+    /// Parse a `Uid` as [`Display`](std::fmt::Display) writes it. Lowercase is
+    /// accepted, as are `I` and `L` for `1` and `O` for `0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let length = s.chars().count();
+        if length != 26 {
+            return Err(UidParseError::Length(length));
+        }
+        let mut value: u128 = 0;
+        for (i, c) in s.chars().enumerate() {
+            let digit = match c.to_ascii_uppercase() {
+                'O' => 0,
+                'I' | 'L' => 1,
+                upper => CROCKFORD
+                    .iter()
+                    .position(|&d| d as char == upper)
+                    .ok_or(UidParseError::Character(c))? as u128,
+            };
+            if i == 0 && digit > 7 {
+                return Err(UidParseError::Overflow);
+            }
+            value = (value << 5) | digit;
+        }
+        Ok(Uid(value))
+    }
+}
+
+impl Serialize for Uid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Uid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(n: u8) -> Request {
+        Request::to("our.os@api:app:pub.os".parse::<Address>().unwrap()).body(vec![n])
+    }
+
+    #[test]
+    fn test_burst_capacity() {
+        let mut limiter = RateLimiter::new(3, 1.0);
+        assert!(limiter.try_acquire_at(1_000));
+        assert!(limiter.try_acquire_at(1_000));
+        assert!(limiter.try_acquire_at(1_000));
+        assert!(!limiter.try_acquire_at(1_000));
+
+        // an idle limiter refills only up to capacity
+        assert!(!limiter.try_acquire_at(1_999));
+        limiter.refill(1_000_000);
+        assert_eq!(limiter.tokens, 3.0);
+
+        let mut empty = RateLimiter::new(0, 10.0);
+        assert!(!empty.try_acquire_at(1_000));
+        assert!(!empty.try_acquire_at(100_000));
+        assert_eq!(empty.next_token_in_ms_at(100_000), None);
+    }
+
+    #[test]
+    fn test_refill_math() {
+        let mut limiter = RateLimiter::new(10, 2.0);
+        for _ in 0..10 {
+            assert!(limiter.try_acquire_at(0));
+        }
+        // 2 per second: one token every 500ms, with fractions carried over
+        assert_eq!(limiter.next_token_in_ms_at(0), Some(500));
+        assert!(!limiter.try_acquire_at(499));
+        assert_eq!(limiter.next_token_in_ms_at(499), Some(1));
+        assert!(limiter.try_acquire_at(500));
+        assert!(!limiter.try_acquire_at(700));
+        assert!(limiter.try_acquire_at(1_000));
+        limiter.refill(3_000);
+        assert!((limiter.tokens - 4.0).abs() < 1e-9);
+
+        // the clock going backwards refills nothing and doesn't rewind the limiter
+        limiter.refill(2_000);
+        assert!((limiter.tokens - 4.0).abs() < 1e-9);
+        limiter.refill(3_500);
+        assert!((limiter.tokens - 5.0).abs() < 1e-9);
+
+        let mut slow = RateLimiter::new(1, 1.0 / 60.0);
+        assert!(slow.try_acquire_at(0));
+        assert_eq!(slow.next_token_in_ms_at(0), Some(60_000));
+        assert!(!slow.try_acquire_at(59_999));
+        assert!(slow.try_acquire_at(60_000));
+    }
+
+    #[test]
+    fn test_deferral_queue_ordering() {
+        let mut limiter = RateLimiter::new(1, 1.0);
+        let mut sent = vec![];
+        let mut send = |request: Request| {
+            sent.push(request.body.unwrap()[0]);
+            Ok(())
+        };
+        assert_eq!(
+            limiter
+                .acquire_or_defer_with(0, request(0), &mut send)
+                .unwrap(),
+            Deferred::Sent
+        );
+        for n in 1..=3 {
+            assert_eq!(
+                limiter
+                    .acquire_or_defer_with(0, request(n), &mut send)
+                    .unwrap(),
+                Deferred::Queued {
+                    position: n as usize - 1
+                }
+            );
+        }
+        // once a token is back, a new request still waits behind the queue
+        assert_eq!(
+            limiter
+                .acquire_or_defer_with(1_000, request(4), &mut send)
+                .unwrap(),
+            Deferred::Queued { position: 3 }
+        );
+        assert_eq!(limiter.queued(), 4);
+
+        let mut pumped = vec![];
+        let mut pump = |request: Request| pumped.push(request.body.unwrap()[0]);
+        assert_eq!(limiter.pump_with(1_000, &mut pump), 1);
+        assert_eq!(limiter.pump_with(1_500, &mut pump), 0);
+        assert_eq!(limiter.pump_with(10_000, &mut pump), 1);
+        assert_eq!(limiter.pump_with(13_000, &mut pump), 1);
+        assert_eq!(limiter.pump_with(20_000, &mut pump), 1);
+        assert_eq!(sent, vec![0]);
+        assert_eq!(pumped, vec![1, 2, 3, 4]);
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[test]
+    fn test_incomplete_requests_are_rejected() {
+        let mut limiter = RateLimiter::new(1, 1.0);
+        let no_body = Request::to("our.os@api:app:pub.os".parse::<Address>().unwrap());
+        assert!(matches!(
+            limiter.acquire_or_defer_with(0, no_body, |_| Ok(())),
+            Err(BuildError::NoBody)
+        ));
+        assert!(matches!(
+            limiter.acquire_or_defer_with(0, Request::new().body(vec![]), |_| Ok(())),
+            Err(BuildError::NoTarget)
+        ));
+        // and use no token
+        assert!(limiter.try_acquire_at(0));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let mut limiter = RateLimiter::new(1, 0.5);
+        limiter
+            .acquire_or_defer_with(0, request(0), |_| Ok(()))
+            .unwrap();
+        let with_blob = request(1)
+            .blob_bytes(vec![9, 9])
+            .metadata("m")
+            .context(vec![7]);
+        limiter
+            .acquire_or_defer_with(0, with_blob, |_| Ok(()))
+            .unwrap();
+
+        let json = serde_json::to_string(&limiter).unwrap();
+        let mut restored: RateLimiter = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, limiter);
+        let mut pumped = vec![];
+        assert_eq!(restored.pump_with(2_000, |request| pumped.push(request)), 1);
+        assert_eq!(pumped[0].blob.as_ref().unwrap().bytes, vec![9, 9]);
+        assert_eq!(pumped[0].metadata.as_deref(), Some("m"));
+        assert_eq!(pumped[0].context, Some(vec![7]));
+    }
+
+    #[test]
+    fn test_uid_monotonic_within_a_millisecond() {
+        let mut generator = UidGenerator::new(0xabcdef);
+        let mut uids = vec![generator.next(1_000)];
+        for _ in 0..1_000 {
+            uids.push(generator.next(1_000));
+        }
+        // the clock going backwards doesn't go back on earlier uids
+        uids.push(generator.next(999));
+        uids.push(generator.next(1_001));
+        assert!(uids.windows(2).all(|pair| pair[0] < pair[1]));
+        let strings: Vec<String> = uids.iter().map(Uid::to_string).collect();
+        assert!(strings.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(uids[0], Uid::from_parts(1_000, 0xabcdef, 0));
+        assert_eq!(uids[1_001], Uid::from_parts(1_000, 0xabcdef, 1_001));
+        assert_eq!(uids[1_002], Uid::from_parts(1_001, 0xabcdef, 0));
+
+        // a full counter carries into the time
+        generator.counter = u32::MAX;
+        let carried = generator.next(1_001);
+        assert_eq!(carried, Uid::from_parts(1_002, 0xabcdef, 0));
+        assert_eq!(carried.timestamp_ms(), 1_002);
+
+        let (first, second) = (Uid::new(), Uid::new());
+        assert!(first < second);
+    }
+
+    #[test]
+    fn test_uid_encoding_round_trips() {
+        for uid in [
+            Uid(0),
+            Uid(u128::MAX),
+            Uid::from_parts(1_700_000_000_000, 0x1234_5678_9abc, 42),
+            Uid::new(),
+        ] {
+            let encoded = uid.to_string();
+            assert_eq!(encoded.len(), 26);
+            assert_eq!(encoded.parse::<Uid>().unwrap(), uid);
+            assert_eq!(encoded.to_lowercase().parse::<Uid>().unwrap(), uid);
+            assert_eq!(Uid::from_bytes(uid.to_bytes()), uid);
+            let json = serde_json::to_string(&uid).unwrap();
+            assert_eq!(json, format!("\"{encoded}\""));
+            assert_eq!(serde_json::from_str::<Uid>(&json).unwrap(), uid);
+        }
+        assert_eq!(Uid(0).to_string(), "00000000000000000000000000");
+        assert_eq!(Uid(u128::MAX).to_string(), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        assert_eq!("0000000000000000000000000O".parse::<Uid>(), Ok(Uid(0)));
+        assert_eq!("000000000000000000000000Il".parse::<Uid>(), Ok(Uid(33)));
+
+        assert_eq!("0123".parse::<Uid>(), Err(UidParseError::Length(4)));
+        assert_eq!(
+            "0000000000000000000000000U".parse::<Uid>(),
+            Err(UidParseError::Character('U'))
+        );
+        assert_eq!(
+            "80000000000000000000000000".parse::<Uid>(),
+            Err(UidParseError::Overflow)
+        );
+        assert!(serde_json::from_str::<Uid>("5").is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! A handshake for processes of different package versions to find out whether
+//! they can work together.
+//!
+//! Each side tells the other its version and the oldest version of the peer it
+//! still supports, either in a message of its own with [`announce()`], or
+//! piggybacked on the metadata of its first real request with
+//! [`crate::Request::with_version()`]. The other side passes what it receives to
+//! [`handle()`], which compares the two and returns a [`VersionDecision`] for the
+//! process to act on: refuse work, or leave out features the peer lacks.
+//!
+//! Versions are [semver](https://semver.org) `major.minor.patch`, with an
+//! optional pre-release (`-beta.1`) and build metadata (`+abc123`), which is
+//! ignored.
+use crate::{trace::USER_METADATA_KEY, types::message::metadata_object, Address, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use thiserror::Error;
+
+/// The metadata key [`crate::Request::with_version()`] puts the [`Announcement`]
+/// under.
+pub const VERSION_KEY: &str = "__version";
+
+/// A semver version. Ordered by semver precedence: a pre-release comes before
+/// its release, and build metadata is dropped when parsing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// The dot-separated pre-release identifiers, empty for a release.
+    pub pre: Vec<String>,
+}
+
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+pub enum VersionError {
+    #[error("invalid version {version:?}: {reason}")]
+    Invalid { version: String, reason: String },
+}
+
+impl std::str::FromStr for Version {
+    type Err = VersionError;
+
+    fn from_str(input: &str) -> Result<Self, VersionError> {
+        let invalid = |reason: &str| VersionError::Invalid {
+            version: input.to_string(),
+            reason: reason.to_string(),
+        };
+        let version = input.strip_prefix('v').unwrap_or(input);
+        let version = version
+            .split_once('+')
+            .map_or(version, |(version, _)| version);
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (version, None),
+        };
+        let numbers = core
+            .split('.')
+            .map(|part| {
+                if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                    Err(invalid("expected major.minor.patch numbers"))
+                } else if part.len() > 1 && part.starts_with('0') {
+                    Err(invalid("numbers may not have leading zeros"))
+                } else {
+                    part.parse::<u64>().map_err(|_| invalid("number too large"))
+                }
+            })
+            .collect::<Result<Vec<u64>, _>>()?;
+        let [major, minor, patch] = numbers[..] else {
+            return Err(invalid("expected major.minor.patch numbers"));
+        };
+        let pre = match pre {
+            None => vec![],
+            Some(pre) => pre
+                .split('.')
+                .map(|identifier| {
+                    let valid = !identifier.is_empty()
+                        && identifier
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+                    let numeric = identifier.bytes().all(|b| b.is_ascii_digit());
+                    if !valid {
+                        Err(invalid("empty or invalid pre-release identifier"))
+                    } else if numeric && identifier.len() > 1 && identifier.starts_with('0') {
+                        Err(invalid("numbers may not have leading zeros"))
+                    } else {
+                        Ok(identifier.to_string())
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Version {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => {
+                    let pairs = self.pre.iter().zip(&other.pre);
+                    pairs
+                        .map(|(a, b)| compare_identifiers(a, b))
+                        .find(|ordering| ordering.is_ne())
+                        .unwrap_or_else(|| self.pre.len().cmp(&other.pre.len()))
+                }
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Numeric identifiers compare as numbers and before alphanumeric ones, which
+/// compare in ASCII order.
+fn compare_identifiers(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+/// What each side tells the other.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Announcement {
+    pub version: String,
+    pub min_supported: String,
+}
+
+/// The body of an [`announce()`] request.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum VersionRequest {
+    Announce(Announcement),
+}
+
+/// What [`handle()`] makes of a peer's [`Announcement`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VersionDecision {
+    /// Each side supports the other. The peer may still be older or newer.
+    Compatible { peer: Address, version: Version },
+    /// The peer is older than we support.
+    TheyMustUpgrade {
+        peer: Address,
+        version: Version,
+        min_supported: Version,
+    },
+    /// We are older than the peer supports.
+    WeMustUpgrade {
+        peer: Address,
+        version: Version,
+        min_supported: Version,
+    },
+}
+
+impl VersionDecision {
+    pub fn is_compatible(&self) -> bool {
+        matches!(self, VersionDecision::Compatible { .. })
+    }
+}
+
+thread_local! {
+    /// What [`set_version()`] was last called with.
+    static OURS: RefCell<Option<(Version, Version)>> = const { RefCell::new(None) };
+}
+
+/// Declare this process's version and the oldest peer version it supports,
+/// for [`handle()`] to compare against. [`announce()`] does so too.
+pub fn set_version(version: &str, min_supported: &str) -> Result<(), VersionError> {
+    let ours = (version.parse()?, min_supported.parse()?);
+    OURS.set(Some(ours));
+    Ok(())
+}
+
+/// Print a line saying this process started at version, e.g. first thing in `init`.
+pub fn banner(version: &str) {
+    crate::println!("{} {version} started", crate::our());
+}
+
+/// Tell target our version and the oldest version of it we support, in a
+/// request of its own, which expects no response. Fails if either is not a
+/// valid version, or if the request could not be sent.
+pub fn announce(target: &Address, version: &str, min_supported: &str) -> anyhow::Result<()> {
+    set_version(version, min_supported)?;
+    let body = VersionRequest::Announce(Announcement {
+        version: version.to_string(),
+        min_supported: min_supported.to_string(),
+    });
+    crate::Request::to(target)
+        .body(serde_json::to_vec(&body)?)
+        .send()?;
+    Ok(())
+}
+
+/// metadata with announcement set as its [`VERSION_KEY`], kept apart from
+/// non-object metadata as [`crate::guard::with_via()`] does.
+pub fn with_announcement(metadata: Option<&str>, announcement: &Announcement) -> String {
+    let mut object = match metadata_object(metadata) {
+        Ok(object) if !object.contains_key(USER_METADATA_KEY) => object,
+        _ => {
+            let mut object = serde_json::Map::new();
+            object.insert(
+                USER_METADATA_KEY.to_string(),
+                Value::String(metadata.unwrap_or_default().to_string()),
+            );
+            object
+        }
+    };
+    object.insert(
+        VERSION_KEY.to_string(),
+        serde_json::to_value(announcement).unwrap(),
+    );
+    Value::Object(object).to_string()
+}
+
+/// If message announces its source's version, in its body or in its metadata,
+/// compare it with ours. `None` if it does not, if the version it announces is
+/// not valid, or if ours was never set.
+///
+/// A message announced with [`announce()`] is meant only for this; one that
+/// piggybacks its announcement is to be handled as usual besides.
+pub fn handle(message: &Message) -> Option<VersionDecision> {
+    let ours = OURS.with_borrow(Clone::clone)?;
+    let announcement = announcement(message)?;
+    Some(decide(
+        message.source().clone(),
+        &ours,
+        announcement.version.parse().ok()?,
+        announcement.min_supported.parse().ok()?,
+    ))
+}
+
+fn announcement(message: &Message) -> Option<Announcement> {
+    if let Message::Request { body, .. } = message {
+        if let Ok(VersionRequest::Announce(announcement)) = serde_json::from_slice(body) {
+            return Some(announcement);
+        }
+    }
+    let mut object = metadata_object(Some(message.metadata()?)).ok()?;
+    serde_json::from_value(object.remove(VERSION_KEY)?).ok()
+}
+
+/// If both sides are too old for each other, the older one must upgrade, or
+/// we must if they are the same.
+fn decide(
+    peer: Address,
+    (ours, our_min): &(Version, Version),
+    version: Version,
+    min_supported: Version,
+) -> VersionDecision {
+    let they_too_old = &version < our_min;
+    let we_too_old = ours < &min_supported;
+    if they_too_old && !(we_too_old && ours <= &version) {
+        VersionDecision::TheyMustUpgrade {
+            peer,
+            version,
+            min_supported: our_min.clone(),
+        }
+    } else if we_too_old {
+        VersionDecision::WeMustUpgrade {
+            peer,
+            version,
+            min_supported,
+        }
+    } else {
+        VersionDecision::Compatible { peer, version }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost};
+    use crate::test_utils::MessageBuilder;
+
+    fn v(version: &str) -> Version {
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn test_semver_ordering() {
+        let ordered = [
+            "0.9.99",
+            "1.0.0-0.3.7",
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.2.0",
+            "1.10.0",
+            "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        // build metadata and a leading v are ignored
+        assert_eq!(v("v1.2.3+build.5"), v("1.2.3"));
+        assert_eq!(v("1.2.3-rc.1+x").to_string(), "1.2.3-rc.1");
+
+        for invalid in [
+            "",
+            "1",
+            "1.2",
+            "1.2.3.4",
+            "01.2.3",
+            "1.2.x",
+            "1.2.3-",
+            "1.2.3-a..b",
+            "1.2.3-01",
+            "-1.2.3",
+            "1.2.3-ü",
+        ] {
+            assert!(invalid.parse::<Version>().is_err(), "{invalid:?}");
+        }
+        assert!(v("1.2.3-0a") > v("1.2.3-9"));
+    }
+
+    #[test]
+    fn test_decisions() {
+        let peer: Address = "peer.os@app:app:pub.os".parse().unwrap();
+        let ours = (v("2.1.0"), v("2.0.0"));
+        let decide = |version, min| decide(peer.clone(), &ours, v(version), v(min));
+        assert!(decide("2.0.0", "1.0.0").is_compatible());
+        assert!(decide("3.4.0", "2.1.0").is_compatible());
+        // a pre-release of the minimum is older than it
+        assert!(matches!(
+            decide("2.0.0-rc.1", "1.0.0"),
+            VersionDecision::TheyMustUpgrade { .. }
+        ));
+        assert!(matches!(
+            decide("3.0.0", "2.1.1"),
+            VersionDecision::WeMustUpgrade { .. }
+        ));
+        // both too old for each other: the older must upgrade
+        assert!(matches!(
+            decide("1.0.0", "3.0.0"),
+            VersionDecision::TheyMustUpgrade { .. }
+        ));
+        assert!(matches!(
+            decide("2.1.0", "3.0.0"),
+            VersionDecision::WeMustUpgrade { .. }
+        ));
+    }
+
+    #[test]
+    fn test_piggyback_and_announce() {
+        crate::set_our("our.os@app:app:pub.os".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        crate::Request::to(("peer.os", "app", "app", "pub.os"))
+            .body("real work")
+            .metadata("not json")
+            .with_version("1.4.0", "1.2.0")
+            .unwrap()
+            .send()
+            .unwrap();
+        // building the request leaves our version to set_version()
+        assert!(OURS.with_borrow(Option::is_none));
+        let Some(Call::SendRequest { request, .. }) = host.take_calls().pop() else {
+            panic!("nothing sent");
+        };
+        let metadata = request.metadata.unwrap();
+
+        // the peer, at an older version, receives it
+        set_version("1.1.0", "1.0.0").unwrap();
+        let received = MessageBuilder::request()
+            .from("our.os@app:app:pub.os")
+            .body("real work")
+            .metadata(&metadata)
+            .build();
+        let Some(VersionDecision::WeMustUpgrade {
+            version,
+            min_supported,
+            ..
+        }) = handle(&received)
+        else {
+            panic!("expected WeMustUpgrade");
+        };
+        assert_eq!((version, min_supported), (v("1.4.0"), v("1.2.0")));
+        // user metadata survives
+        assert_eq!(
+            metadata_object(Some(&metadata)).unwrap()[USER_METADATA_KEY],
+            "not json"
+        );
+
+        announce(&"peer.os@app:app:pub.os".parse().unwrap(), "1.3.0", "1.0.0").unwrap();
+        let Some(Call::SendRequest { request, .. }) = host.take_calls().pop() else {
+            panic!("nothing sent");
+        };
+        set_version("1.4.0", "1.2.0").unwrap();
+        let received = MessageBuilder::request()
+            .from("our.os@app:app:pub.os")
+            .body(request.body)
+            .build();
+        assert!(handle(&received).unwrap().is_compatible());
+        assert_eq!(handle(&MessageBuilder::request().body("hi").build()), None);
+    }
+}
@@ -0,0 +1,422 @@
+use super::{
+    bad_response, classified, remove_path, rename, vfs_request, VfsAction, VfsClientError,
+    VfsError, VfsResponse,
+};
+use crate::{BuildError, Message, Request, SendError};
+use serde::{Deserialize, Serialize};
+
+/// One action of a [`VfsAction::Batch`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BatchAction {
+    /// Create the directory at path and any missing parents.
+    CreateDir {
+        path: String,
+    },
+    /// Write the `len` bytes of the request blob starting at `offset` as the
+    /// file at path, replacing it.
+    WriteFile {
+        path: String,
+        offset: u64,
+        len: u64,
+    },
+    /// Remove the file or empty directory at path.
+    Remove {
+        path: String,
+    },
+    Rename {
+        path: String,
+        new_path: String,
+    },
+}
+
+impl BatchAction {
+    pub fn path(&self) -> &str {
+        match self {
+            BatchAction::CreateDir { path }
+            | BatchAction::WriteFile { path, .. }
+            | BatchAction::Remove { path }
+            | BatchAction::Rename { path, .. } => path,
+        }
+    }
+
+    /// The [`VfsAction`] doing the same on its own, to classify its errors by.
+    fn vfs_action(&self) -> VfsAction {
+        match self {
+            BatchAction::CreateDir { .. } => VfsAction::CreateDirAll,
+            BatchAction::WriteFile { .. } => VfsAction::Write,
+            BatchAction::Remove { .. } => VfsAction::RemoveFile,
+            BatchAction::Rename { new_path, .. } => VfsAction::Rename {
+                new_path: new_path.clone(),
+            },
+        }
+    }
+}
+
+/// What [`Batch::commit()`] did: a result per action, in the order they were
+/// added.
+#[derive(Debug)]
+pub struct BatchResult {
+    pub results: Vec<Result<(), VfsClientError>>,
+    /// Whether the runtime does not support [`VfsAction::Batch`], so the
+    /// actions were sent one request at a time instead.
+    pub sequential: bool,
+}
+
+impl BatchResult {
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(Result::is_ok)
+    }
+
+    /// The failed actions' positions in the batch, with their errors.
+    pub fn errors(&self) -> impl Iterator<Item = (usize, &VfsClientError)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, result)| result.as_ref().err().map(|e| (i, e)))
+    }
+}
+
+/// Vfs actions collected locally and sent as one [`VfsAction::Batch`] request,
+/// the bytes of every file written concatenated into its blob. Created with
+/// [`batch()`].
+///
+/// A runtime that does not support batches answers
+/// [`VfsError::MalformedRequest`]; the actions are then sent one by one, in
+/// order, as [`BatchResult::sequential`] tells. Either way each action
+/// succeeds or fails on its own, so a failed one does not stop the rest.
+///
+/// ```no_run
+/// use hyperware_process_lib::vfs;
+///
+/// let mut batch = vfs::batch();
+/// batch
+///     .create_dir("/app:pub.os/data/site")
+///     .write_file("/app:pub.os/data/site/index.html", b"<h1>hi</h1>")
+///     .write_file("/app:pub.os/data/site/style.css", b"h1 { color: red }");
+/// let result = batch.commit().unwrap();
+/// for (i, e) in result.errors() {
+///     println!("action {i} failed: {e}");
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Batch {
+    actions: Vec<BatchAction>,
+    blob: Vec<u8>,
+    timeout: Option<u64>,
+}
+
+/// Start a [`Batch`].
+pub fn batch() -> Batch {
+    Batch::default()
+}
+
+/// How a [`Batch`] reaches the runtime, so that it can be exercised without one.
+trait BatchIo {
+    fn send_batch(
+        &mut self,
+        request: Request,
+        timeout: u64,
+    ) -> Result<Result<Message, SendError>, BuildError>;
+    /// Carry out a single action, with the bytes of a write.
+    fn run(
+        &mut self,
+        action: &BatchAction,
+        bytes: &[u8],
+        timeout: u64,
+    ) -> Result<(), VfsClientError>;
+}
+
+struct Vfs;
+
+impl BatchIo for Vfs {
+    fn send_batch(
+        &mut self,
+        request: Request,
+        timeout: u64,
+    ) -> Result<Result<Message, SendError>, BuildError> {
+        request.send_and_await_response(timeout)
+    }
+
+    fn run(
+        &mut self,
+        action: &BatchAction,
+        bytes: &[u8],
+        timeout: u64,
+    ) -> Result<(), VfsClientError> {
+        let path = action.path();
+        let request = match action {
+            BatchAction::CreateDir { .. } => vfs_request(path, VfsAction::CreateDirAll),
+            BatchAction::WriteFile { .. } => vfs_request(path, VfsAction::Write).blob_bytes(bytes),
+            BatchAction::Remove { path } => return remove_path(path, Some(timeout)),
+            BatchAction::Rename { path, new_path } => return rename(path, new_path, Some(timeout)),
+        };
+        let sent = request.send_and_await_response(timeout);
+        match classified(path, &action.vfs_action(), sent)? {
+            VfsResponse::Ok => Ok(()),
+            response => Err(bad_response(&response)),
+        }
+    }
+}
+
+const DEFAULT_TIMEOUT: u64 = 5;
+
+impl Batch {
+    /// Create the directory at path and any missing parents.
+    pub fn create_dir(&mut self, path: &str) -> &mut Self {
+        self.actions.push(BatchAction::CreateDir {
+            path: path.to_string(),
+        });
+        self
+    }
+
+    /// Write bytes as the file at path, creating or truncating it.
+    pub fn write_file(&mut self, path: &str, bytes: &[u8]) -> &mut Self {
+        self.actions.push(BatchAction::WriteFile {
+            path: path.to_string(),
+            offset: self.blob.len() as u64,
+            len: bytes.len() as u64,
+        });
+        self.blob.extend_from_slice(bytes);
+        self
+    }
+
+    /// Remove the file or empty directory at path.
+    pub fn remove(&mut self, path: &str) -> &mut Self {
+        self.actions.push(BatchAction::Remove {
+            path: path.to_string(),
+        });
+        self
+    }
+
+    /// Rename (move) the file or directory at path to new_path, replacing any
+    /// file there.
+    pub fn rename(&mut self, path: &str, new_path: &str) -> &mut Self {
+        self.actions.push(BatchAction::Rename {
+            path: path.to_string(),
+            new_path: new_path.to_string(),
+        });
+        self
+    }
+
+    /// Set how long the runtime has to answer, in seconds: the whole batch, or
+    /// each action if they are sent one by one. Defaults to 5.
+    pub fn timeout(&mut self, timeout: u64) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn actions(&self) -> &[BatchAction] {
+        &self.actions
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Send every collected action. Fails only if the batch as a whole could
+    /// not be sent or answered; the result of each action is in the
+    /// [`BatchResult`].
+    pub fn commit(self) -> Result<BatchResult, VfsClientError> {
+        self.commit_with(&mut Vfs)
+    }
+
+    fn commit_with<I: BatchIo>(self, io: &mut I) -> Result<BatchResult, VfsClientError> {
+        let Some(first) = self.actions.first() else {
+            return Ok(BatchResult {
+                results: vec![],
+                sequential: false,
+            });
+        };
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        // the runtime checks the capabilities of each action's own path
+        let path = first.path().to_string();
+        let action = VfsAction::Batch {
+            actions: self.actions.clone(),
+        };
+        let mut request = vfs_request(&path, action.clone());
+        if !self.blob.is_empty() {
+            request = request.blob_bytes(self.blob.clone());
+        }
+        match classified(&path, &action, io.send_batch(request, timeout)) {
+            Ok(VfsResponse::Batch(results)) if results.len() == self.actions.len() => {
+                Ok(BatchResult {
+                    results: results
+                        .into_iter()
+                        .zip(&self.actions)
+                        .map(|(result, action)| {
+                            result.map_err(|e| e.classify(action.path(), &action.vfs_action()))
+                        })
+                        .collect(),
+                    sequential: false,
+                })
+            }
+            Err(VfsClientError::Runtime(VfsError::MalformedRequest)) => {
+                Ok(self.run_sequentially(io, timeout))
+            }
+            Ok(response) => Err(bad_response(&response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn run_sequentially<I: BatchIo>(&self, io: &mut I, timeout: u64) -> BatchResult {
+        let results = self
+            .actions
+            .iter()
+            .map(|action| io.run(action, self.bytes_of(action), timeout))
+            .collect();
+        BatchResult {
+            results,
+            sequential: true,
+        }
+    }
+
+    /// The bytes a [`BatchAction::WriteFile`] writes, from the blob.
+    fn bytes_of(&self, action: &BatchAction) -> &[u8] {
+        match action {
+            BatchAction::WriteFile { offset, len, .. } => {
+                &self.blob[*offset as usize..(offset + len) as usize]
+            }
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers the batch request with response, and records what it was sent.
+    struct MockVfs {
+        response: VfsResponse,
+        /// The blob of each batch request sent.
+        sent: Vec<Option<Vec<u8>>>,
+        ran: Vec<(BatchAction, Vec<u8>)>,
+    }
+
+    impl MockVfs {
+        fn answering(response: VfsResponse) -> Self {
+            MockVfs {
+                response,
+                sent: vec![],
+                ran: vec![],
+            }
+        }
+    }
+
+    impl BatchIo for MockVfs {
+        fn send_batch(
+            &mut self,
+            request: Request,
+            _: u64,
+        ) -> Result<Result<Message, SendError>, BuildError> {
+            self.sent.push(request.blob.map(|blob| blob.bytes));
+            Ok(Ok(crate::test_utils::MessageBuilder::response()
+                .body_json(&self.response)
+                .build()))
+        }
+
+        fn run(
+            &mut self,
+            action: &BatchAction,
+            bytes: &[u8],
+            _: u64,
+        ) -> Result<(), VfsClientError> {
+            self.ran.push((action.clone(), bytes.to_vec()));
+            match action {
+                BatchAction::Remove { path } => {
+                    Err(VfsClientError::NotFound { path: path.clone() })
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn site() -> Batch {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let mut batch = batch();
+        batch
+            .create_dir("/app:pub.os/site")
+            .write_file("/app:pub.os/site/a.html", b"<p>a</p>")
+            .write_file("/app:pub.os/site/empty", b"")
+            .rename("/app:pub.os/site/a.html", "/app:pub.os/site/index.html")
+            .write_file("/app:pub.os/site/b.css", b"p {}")
+            .remove("/app:pub.os/old");
+        batch
+    }
+
+    #[test]
+    fn test_blob_offsets() {
+        let batch = site();
+        assert_eq!(batch.len(), 6);
+        let writes: Vec<_> = batch
+            .actions()
+            .iter()
+            .filter_map(|action| match action {
+                BatchAction::WriteFile { offset, len, .. } => Some((*offset, *len)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(writes, [(0, 8), (8, 0), (8, 4)]);
+        assert_eq!(batch.blob, b"<p>a</p>p {}");
+        assert_eq!(batch.bytes_of(&batch.actions()[4]), b"p {}");
+        assert_eq!(batch.bytes_of(&batch.actions()[2]), b"");
+
+        let results = (0..6)
+            .map(|i| match i {
+                5 => Err(VfsError::IOError("No such file".into())),
+                _ => Ok(()),
+            })
+            .collect();
+        let mut vfs = MockVfs::answering(VfsResponse::Batch(results));
+        let result = site().commit_with(&mut vfs).unwrap();
+        assert!(!result.sequential);
+        assert_eq!(vfs.sent, [Some(b"<p>a</p>p {}".to_vec())]);
+        assert!(vfs.ran.is_empty());
+        assert_eq!(result.errors().map(|(i, _)| i).collect::<Vec<_>>(), [5]);
+        assert!(matches!(
+            &result.results[5],
+            Err(VfsClientError::NotFound { path }) if path == "/app:pub.os/old"
+        ));
+    }
+
+    #[test]
+    fn test_sequential_fallback() {
+        let mut vfs = MockVfs::answering(VfsResponse::Err(VfsError::MalformedRequest));
+        let result = site().commit_with(&mut vfs).unwrap();
+        assert!(result.sequential);
+        assert!(!result.is_ok());
+        assert_eq!(result.results.len(), 6);
+        assert!(matches!(
+            result.results[5],
+            Err(VfsClientError::NotFound { .. })
+        ));
+        // every action ran in order, a failure not stopping the rest
+        let ran: Vec<_> = vfs
+            .ran
+            .iter()
+            .map(|(action, bytes)| (action.path(), bytes.as_slice()))
+            .collect();
+        assert_eq!(
+            ran,
+            [
+                ("/app:pub.os/site", &b""[..]),
+                ("/app:pub.os/site/a.html", b"<p>a</p>"),
+                ("/app:pub.os/site/empty", b""),
+                ("/app:pub.os/site/a.html", b""),
+                ("/app:pub.os/site/b.css", b"p {}"),
+                ("/app:pub.os/old", b""),
+            ]
+        );
+
+        // a result per action is required of a runtime that does batch
+        let mut vfs = MockVfs::answering(VfsResponse::Batch(vec![Ok(())]));
+        assert!(site().commit_with(&mut vfs).is_err());
+        let empty = batch().commit_with(&mut vfs).unwrap();
+        assert!(empty.results.is_empty());
+        assert_eq!(vfs.sent.len(), 1);
+    }
+}
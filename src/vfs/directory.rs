@@ -1,7 +1,9 @@
-use super::{parse_response, vfs_request, DirEntry, FileType, VfsAction, VfsError, VfsResponse};
+use super::{
+    bad_response, call, DirEntry, FileType, VfsAction, VfsClientError, VfsError, VfsResponse,
+};
 
 /// VFS (Virtual File System) helper struct for a directory.
-/// Opening or creating a directory will give you a `Result<Directory>`.
+/// Opening or creating a directory will give you a `Result<Directory, VfsClientError>`.
 /// You can call it's impl functions to interact with it.
 pub struct Directory {
     pub path: String,
@@ -11,47 +13,33 @@ pub struct Directory {
 impl Directory {
     /// Iterates through children of `Directory`, returning a vector of DirEntries.
     /// DirEntries contain the path and file type of each child.
-    pub fn read(&self) -> Result<Vec<DirEntry>, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::ReadDir)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn read(&self) -> Result<Vec<DirEntry>, VfsClientError> {
+        match call(&self.path, VfsAction::ReadDir, self.timeout)? {
             VfsResponse::ReadDir(entries) => Ok(entries),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 }
 
 /// Opens or creates a `Directory` at path.
 /// If trying to create an existing `Directory`, will just give you the path.
-pub fn open_dir(path: &str, create: bool, timeout: Option<u64>) -> Result<Directory, VfsError> {
+pub fn open_dir(
+    path: impl AsRef<str>,
+    create: bool,
+    timeout: Option<u64>,
+) -> Result<Directory, VfsClientError> {
+    let path = path.as_ref();
     let timeout = timeout.unwrap_or(5);
     if !create {
-        let message = vfs_request(path, VfsAction::Metadata)
-            .send_and_await_response(timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-        match parse_response(message.body())? {
+        match call(path, VfsAction::Metadata, timeout)? {
             VfsResponse::Metadata(m) => {
                 if m.file_type != FileType::Directory {
-                    return Err(VfsError::IOError(
+                    return Err(VfsClientError::Runtime(VfsError::IOError(
                         "entry at path is not a directory".to_string(),
-                    ));
+                    )));
                 }
             }
-            VfsResponse::Err(e) => return Err(e),
-            _ => {
-                return Err(VfsError::ParseError {
-                    error: "unexpected response".to_string(),
-                    path: path.to_string(),
-                })
-            }
+            response => return Err(bad_response(&response)),
         }
 
         return Ok(Directory {
@@ -60,39 +48,31 @@ pub fn open_dir(path: &str, create: bool, timeout: Option<u64>) -> Result<Direct
         });
     }
 
-    let message = vfs_request(path, VfsAction::CreateDirAll)
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-    match parse_response(message.body())? {
+    match call(path, VfsAction::CreateDirAll, timeout)? {
         VfsResponse::Ok => Ok(Directory {
             path: path.to_string(),
             timeout,
         }),
-        VfsResponse::Err(e) => Err(e),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
-            path: path.to_string(),
-        }),
+        response => Err(bad_response(&response)),
     }
 }
 
 /// Removes a dir at path, errors if path not found or path is not a `Directory`.
-pub fn remove_dir(path: &str, timeout: Option<u64>) -> Result<(), VfsError> {
+pub fn remove_dir(path: &str, timeout: Option<u64>) -> Result<(), VfsClientError> {
     let timeout = timeout.unwrap_or(5);
 
-    let message = vfs_request(path, VfsAction::RemoveDir)
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
+    match call(path, VfsAction::RemoveDir, timeout)? {
+        VfsResponse::Ok => Ok(()),
+        response => Err(bad_response(&response)),
+    }
+}
+
+/// Removes a dir at path and everything in it, errors if path not found or path is not a `Directory`.
+pub fn remove_dir_all(path: &str, timeout: Option<u64>) -> Result<(), VfsClientError> {
+    let timeout = timeout.unwrap_or(5);
 
-    match parse_response(message.body())? {
+    match call(path, VfsAction::RemoveDirAll, timeout)? {
         VfsResponse::Ok => Ok(()),
-        VfsResponse::Err(e) => Err(e),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
-            path: path.to_string(),
-        }),
+        response => Err(bad_response(&response)),
     }
 }
@@ -0,0 +1,325 @@
+use super::{create_drive, open_dir, remove_dir_all, remove_file, FileType, VfsError};
+use crate::PackageId;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// The drive holding the files of the installed package, read-only. See [`pkg_dir()`].
+pub const PKG_DRIVE: &str = "pkg";
+/// The drive for a process's own files. See [`data_dir()`].
+pub const DATA_DRIVE: &str = "data";
+/// The drive for scratch files. See [`tmp_dir()`].
+pub const TMP_DRIVE: &str = "tmp";
+
+thread_local! {
+    /// The drives of our package created by [`our_drive()`], by name.
+    static OUR_DRIVES: RefCell<HashMap<String, DrivePath>> = RefCell::new(HashMap::new());
+}
+
+/// The path of a drive, `/package_id/drive`, as [`super::create_drive()`]
+/// returns it, or of a directory within one. Build the paths of what it holds
+/// with [`DrivePath::file()`], [`DrivePath::dir()`] and [`DrivePath::join()`],
+/// which normalize separators and refuse to climb out with `..`.
+///
+/// It derefs to `str`, so it can be passed wherever a path is.
+///
+/// ```
+/// use hyperware_process_lib::{vfs::DrivePath, PackageId};
+///
+/// let drive = DrivePath::new(&PackageId::new("app", "pub.os"), "data").unwrap();
+/// assert_eq!(drive.file("notes.txt").unwrap(), "/app:pub.os/data/notes.txt");
+/// assert_eq!(drive.join("a//b/").unwrap().as_str(), "/app:pub.os/data/a/b");
+/// assert!(drive.join("../other").is_err());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DrivePath(String);
+
+impl DrivePath {
+    /// The path of drive in package_id's storage, without creating it.
+    pub fn new(package_id: &PackageId, drive: &str) -> Result<Self, VfsError> {
+        DrivePath(format!("/{package_id}")).join(drive)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The path of the file name in this directory.
+    pub fn file(&self, name: &str) -> Result<String, VfsError> {
+        self.join(name).map(String::from)
+    }
+
+    /// The path of the directory name in this directory.
+    pub fn dir(&self, name: &str) -> Result<DrivePath, VfsError> {
+        self.join(name)
+    }
+
+    /// Append path, which may have several segments, to this one. Repeated, leading
+    /// and trailing `/`s and `.` segments are dropped; a `..` segment is an error,
+    /// so that a name taken from a request can't escape the drive.
+    pub fn join(&self, path: &str) -> Result<DrivePath, VfsError> {
+        let mut joined = self.0.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => {
+                    return Err(VfsError::ParseError {
+                        error: "path may not contain `..`".to_string(),
+                        path: path.to_string(),
+                    })
+                }
+                segment => {
+                    joined.push('/');
+                    joined.push_str(segment);
+                }
+            }
+        }
+        Ok(DrivePath(joined))
+    }
+}
+
+impl std::fmt::Display for DrivePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::ops::Deref for DrivePath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for DrivePath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<DrivePath> for String {
+    fn from(path: DrivePath) -> Self {
+        path.0
+    }
+}
+
+impl PartialEq<str> for DrivePath {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for DrivePath {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// The drive called drive of our package, as set by [`crate::set_our()`],
+/// creating it the first time it is asked for in this process. Later calls
+/// reuse the path without asking vfs again.
+pub fn our_drive(drive: &str) -> anyhow::Result<DrivePath> {
+    if let Some(path) = OUR_DRIVES.with_borrow(|drives| drives.get(drive).cloned()) {
+        return Ok(path);
+    }
+    let path = create_drive(crate::our().package_id(), drive, None)?;
+    OUR_DRIVES.with_borrow_mut(|drives| drives.insert(drive.to_string(), path.clone()));
+    Ok(path)
+}
+
+/// The files of our package as installed, such as a UI to serve. The drive is
+/// made by installing the package and is read-only, so it is not created.
+pub fn pkg_dir() -> DrivePath {
+    // a valid drive name, so this can't fail
+    DrivePath::new(&crate::our().package_id(), PKG_DRIVE).unwrap()
+}
+
+/// The drive for our own files, such as state and config too large or too
+/// structured for [`crate::set_state()`], created on first use.
+pub fn data_dir() -> anyhow::Result<DrivePath> {
+    our_drive(DATA_DRIVE)
+}
+
+/// The drive for scratch files, created on first use. Nothing removes them
+/// but [`clear_tmp()`].
+pub fn tmp_dir() -> anyhow::Result<DrivePath> {
+    our_drive(TMP_DRIVE)
+}
+
+/// Remove everything in [`tmp_dir()`], directories and all, leaving the drive
+/// itself.
+pub fn clear_tmp() -> anyhow::Result<()> {
+    let tmp = tmp_dir()?;
+    for entry in open_dir(&tmp, false, None)?.read()? {
+        match entry.file_type {
+            FileType::Directory => remove_dir_all(&entry.path, None)?,
+            _ => remove_file(&entry.path, None)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::vfs::{create_drive, open_file, VfsResponse};
+
+    fn drive() -> DrivePath {
+        DrivePath::new(&PackageId::new("app", "pub.os"), "data").unwrap()
+    }
+
+    #[test]
+    fn test_normalization() {
+        let drive = drive();
+        assert_eq!(drive, "/app:pub.os/data");
+        assert_eq!(
+            DrivePath::new(&PackageId::new("app", "pub.os"), "/data/").unwrap(),
+            drive
+        );
+        assert_eq!(drive.join("a//b").unwrap(), "/app:pub.os/data/a/b");
+        assert_eq!(drive.join("/a/./b/").unwrap(), "/app:pub.os/data/a/b");
+        assert_eq!(drive.join("").unwrap(), drive);
+        assert_eq!(
+            drive.dir("logs/").unwrap().file("today.log").unwrap(),
+            "/app:pub.os/data/logs/today.log"
+        );
+        // dots within a name are fine, a `..` segment is not
+        assert_eq!(drive.file("..hidden").unwrap(), "/app:pub.os/data/..hidden");
+        for escape in ["..", "../other", "a/../../b", "a//.."] {
+            assert!(
+                matches!(drive.join(escape), Err(VfsError::ParseError { .. })),
+                "{escape}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_create_drive_already_exists() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let package_id = PackageId::new("app", "pub.os");
+
+        host.reply(Reply::json(&VfsResponse::Ok));
+        assert_eq!(
+            create_drive(package_id.clone(), "data", None).unwrap(),
+            drive()
+        );
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::IOError(
+            "drive already exists".to_string(),
+        ))));
+        let drive = create_drive(package_id.clone(), "data", None).unwrap();
+        assert_eq!(drive, "/app:pub.os/data");
+        // other errors still fail
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::NoWriteCap)));
+        assert!(create_drive(package_id, "data", None).is_err());
+
+        // and the path opens files directly
+        host.reply(Reply::json(&VfsResponse::Ok));
+        let file = open_file(drive.file("notes.txt").unwrap(), true, None).unwrap();
+        assert_eq!(file.path, "/app:pub.os/data/notes.txt");
+        let paths: Vec<String> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, .. } => {
+                    let request: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    Some(request["path"].as_str().unwrap().to_string())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(paths.len(), 4);
+        assert_eq!(paths[3], "/app:pub.os/data/notes.txt");
+    }
+
+    fn vfs_calls(host: &MockHost) -> Vec<(String, String)> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, .. } => {
+                    let request: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    let action = match &request["action"] {
+                        serde_json::Value::String(action) => action.clone(),
+                        action => action.as_object().unwrap().keys().next().unwrap().clone(),
+                    };
+                    Some((action, request["path"].as_str().unwrap().to_string()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_our_drive_is_created_once() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+
+        host.reply(Reply::json(&VfsResponse::Ok));
+        assert_eq!(data_dir().unwrap(), "/app:sys/data");
+        assert_eq!(our_drive("data").unwrap(), "/app:sys/data");
+        assert_eq!(
+            vfs_calls(&host),
+            [("CreateDrive".to_string(), "/app:sys/data".to_string())]
+        );
+
+        // a failure is not cached
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::NoWriteCap)));
+        assert!(our_drive("cache").is_err());
+        host.reply(Reply::json(&VfsResponse::Ok));
+        assert_eq!(our_drive("cache").unwrap(), "/app:sys/cache");
+        assert_eq!(vfs_calls(&host).len(), 2);
+
+        // the package drive is never created
+        assert_eq!(pkg_dir(), "/app:sys/pkg");
+        assert!(vfs_calls(&host).is_empty());
+    }
+
+    #[test]
+    fn test_clear_tmp_removes_everything_within() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+
+        host.reply(Reply::json(&VfsResponse::Ok));
+        host.reply(Reply::json(&VfsResponse::Metadata(
+            crate::vfs::FileMetadata {
+                file_type: FileType::Directory,
+                len: 0,
+                created: None,
+                modified: None,
+            },
+        )));
+        host.reply(Reply::json(&VfsResponse::ReadDir(vec![
+            crate::vfs::DirEntry {
+                path: "app:sys/tmp/upload.part".to_string(),
+                file_type: FileType::File,
+            },
+            crate::vfs::DirEntry {
+                path: "app:sys/tmp/unpacked".to_string(),
+                file_type: FileType::Directory,
+            },
+        ])));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        clear_tmp().unwrap();
+        let calls = vfs_calls(&host);
+        let calls: Vec<(&str, &str)> = calls
+            .iter()
+            .map(|(action, path)| (action.as_str(), path.as_str()))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                ("CreateDrive", "/app:sys/tmp"),
+                ("Metadata", "/app:sys/tmp"),
+                ("ReadDir", "/app:sys/tmp"),
+                ("RemoveFile", "app:sys/tmp/upload.part"),
+                ("RemoveDirAll", "app:sys/tmp/unpacked"),
+            ]
+        );
+    }
+}
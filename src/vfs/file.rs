@@ -1,14 +1,23 @@
 use super::{
-    parse_response, vfs_request, FileMetadata, SeekFrom, VfsAction, VfsError, VfsResponse,
+    bad_response, call, classified, hash_contents, is_already_exists, rename, vfs_request,
+    DrivePath, FileMetadata, SeekFrom, VfsAction, VfsClientError, VfsError, VfsResponse,
 };
-use crate::{get_blob, PackageId};
+use crate::{get_blob, timer::Deadline, BuildError, Message, PackageId, Request, SendError};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Digest;
+
+/// Size of the reads used when hashing a file client-side.
+pub(crate) const HASH_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// VFS (Virtual File System) helper struct for a file.
-/// Opening or creating a `File` will give you a `Result<File, VfsError>`.
-/// You can call its impl functions to interact with it.
+/// Opening or creating a `File` will give you a `Result<File, VfsClientError>`.
+/// You can call its impl functions to interact with it, which fail with a
+/// [`VfsClientError`] too.
 pub struct File {
     pub path: String,
     pub timeout: u64,
+    /// Bounds every request instead of timeout, if set.
+    pub deadline: Option<Deadline>,
 }
 
 impl File {
@@ -17,368 +26,798 @@ impl File {
         Self {
             path: path.into(),
             timeout,
+            deadline: None,
+        }
+    }
+
+    /// Give every request up to what is left of deadline, instead of
+    /// [`File::timeout`] each, e.g. to bound a run of reads and writes as a
+    /// whole. Requests made once it has passed fail with
+    /// [`VfsClientError::Timeout`].
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Send request and await the response, within the deadline if there is one.
+    fn send(&self, request: Request) -> Result<Result<Message, SendError>, BuildError> {
+        match &self.deadline {
+            Some(deadline) => request.send_and_await_with_deadline(deadline),
+            None => request.send_and_await_response(self.timeout),
+        }
+    }
+
+    /// Send action, with blob if any, and await the response, as [`File::send()`]
+    /// does, classifying any error.
+    fn call(&self, action: VfsAction, blob: Option<&[u8]>) -> Result<VfsResponse, VfsClientError> {
+        let mut request = vfs_request(&self.path, action.clone());
+        if let Some(blob) = blob {
+            request = request.blob_bytes(blob);
         }
+        classified(&self.path, &action, self.send(request))
+    }
+
+    /// The error for a response that should have had a blob but did not.
+    fn no_blob(&self) -> VfsClientError {
+        VfsClientError::Runtime(VfsError::ParseError {
+            error: "no blob".to_string(),
+            path: self.path.clone(),
+        })
     }
 
     /// Reads the entire file, from start position.
     /// Returns a vector of bytes.
-    pub fn read(&self) -> Result<Vec<u8>, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::Read)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn read(&self) -> Result<Vec<u8>, VfsClientError> {
+        match self.call(VfsAction::Read, None)? {
             VfsResponse::Read => {
                 let data = match get_blob() {
                     Some(bytes) => bytes.bytes,
-                    None => {
-                        return Err(VfsError::ParseError {
-                            error: "no blob".to_string(),
-                            path: self.path.clone(),
-                        })
-                    }
+                    None => return Err(self.no_blob()),
                 };
                 Ok(data)
             }
-            VfsResponse::Err(e) => Err(e.into()),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
+        }
+    }
+
+    /// Like [`File::read()`], but as a future to await under
+    /// [`crate::executor::run()`], so that reads of several files can be in
+    /// flight at once. Ignores [`File::deadline`].
+    #[cfg(any(test, feature = "async"))]
+    pub async fn read_async(&self) -> Result<Vec<u8>, VfsClientError> {
+        let action = VfsAction::Read;
+        let response = vfs_request(&self.path, action.clone())
+            .send_async(self.timeout)
+            .map_err(|e| super::not_sent(&self.path, &action, e))?;
+        let (message, blob) = match response.await {
+            Ok((message, blob)) => (Ok(Ok(message)), blob),
+            Err(e) => (Ok(Err(e)), None),
+        };
+
+        match classified(&self.path, &action, message)? {
+            VfsResponse::Read => blob.map(|blob| blob.bytes).ok_or_else(|| self.no_blob()),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Reads the entire file, from start position, into buffer.
     /// Returns the amount of bytes read.
-    pub fn read_into(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::Read)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn read_into(&self, buffer: &mut [u8]) -> Result<usize, VfsClientError> {
+        match self.call(VfsAction::Read, None)? {
             VfsResponse::Read => {
                 let data = get_blob().unwrap_or_default().bytes;
                 let len = std::cmp::min(data.len(), buffer.len());
                 buffer[..len].copy_from_slice(&data[..len]);
                 Ok(len)
             }
-            VfsResponse::Err(e) => Err(e.into()),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Read into buffer from current cursor position
     /// Returns the amount of bytes read.
-    pub fn read_at(&self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+    pub fn read_at(&self, buffer: &mut [u8]) -> Result<usize, VfsClientError> {
         let length = buffer.len() as u64;
 
-        let message = vfs_request(&self.path, VfsAction::ReadExact { length })
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+        match self.call(VfsAction::ReadExact { length }, None)? {
             VfsResponse::Read => {
                 let data = get_blob().unwrap_or_default().bytes;
                 let len = std::cmp::min(data.len(), buffer.len());
                 buffer[..len].copy_from_slice(&data[..len]);
                 Ok(len)
             }
-            VfsResponse::Err(e) => Err(e.into()),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Reads until end of file from current cursor position
     /// Returns a vector of bytes.
-    pub fn read_to_end(&self) -> Result<Vec<u8>, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::ReadToEnd)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn read_to_end(&self) -> Result<Vec<u8>, VfsClientError> {
+        match self.call(VfsAction::ReadToEnd, None)? {
             VfsResponse::Read => Ok(get_blob().unwrap_or_default().bytes),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Reads until end of file from current cursor position, converts to String.
     /// Throws error if bytes aren't valid utf-8.
     /// Returns a vector of bytes.
-    pub fn read_to_string(&self) -> Result<String, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::ReadToString)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn read_to_string(&self) -> Result<String, VfsClientError> {
+        match self.call(VfsAction::ReadToString, None)? {
             VfsResponse::ReadToString(s) => Ok(s),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Write entire slice as the new file.
     /// Truncates anything that existed at path before.
-    pub fn write(&self, buffer: &[u8]) -> Result<(), VfsError> {
-        let message = vfs_request(&self.path, VfsAction::Write)
-            .blob_bytes(buffer)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn write(&self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        match self.call(VfsAction::Write, Some(buffer))? {
             VfsResponse::Ok => Ok(()),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
-    /// Write buffer to file at current position, overwriting any existing data.
-    pub fn write_all(&mut self, buffer: &[u8]) -> Result<(), VfsError> {
-        let message = vfs_request(&self.path, VfsAction::WriteAll)
-            .blob_bytes(buffer)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    /// Write buffer to file at current position, overwriting exactly buffer.len()
+    /// bytes from there and extending the file if they run past its end. The
+    /// cursor ends up after the written bytes.
+    pub fn write_all(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        match self.call(VfsAction::WriteAll, Some(buffer))? {
             VfsResponse::Ok => Ok(()),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
+        }
+    }
+
+    /// Seek to offset from the start of the file, then [`File::write_all()`]
+    /// bytes there. If the seek succeeded but the write timed out, both are tried
+    /// once more: the write may or may not have happened, and rewriting the same
+    /// bytes at the same offset is harmless either way.
+    pub fn write_all_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), VfsClientError> {
+        self.seek(SeekFrom::Start(offset))?;
+        match self.write_all(bytes) {
+            Err(VfsClientError::Timeout { .. }) => {
+                crate::debug!("write to {} at {offset} timed out, retrying", self.path);
+                self.seek(SeekFrom::Start(offset))?;
+                self.write_all(bytes)
+            }
+            result => result,
+        }
+    }
+
+    /// Cut the file off at offset, dropping everything from there on. Unlike
+    /// [`File::set_len()`], never grows the file: if it is no longer than offset,
+    /// it is left as is.
+    pub fn truncate_from(&mut self, offset: u64) -> Result<(), VfsClientError> {
+        if self.metadata()?.len <= offset {
+            return Ok(());
         }
+        self.set_len(offset)
     }
 
     /// Write buffer to the end position of file.
-    pub fn append(&mut self, buffer: &[u8]) -> Result<(), VfsError> {
-        let message = vfs_request(&self.path, VfsAction::Append)
-            .blob_bytes(buffer)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn append(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        match self.call(VfsAction::Append, Some(buffer))? {
             VfsResponse::Ok => Ok(()),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Seek file to position.
     /// Returns the new position.
-    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::Seek(pos))
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsClientError> {
+        match self.call(VfsAction::Seek(pos), None)? {
             VfsResponse::SeekFrom {
                 new_offset: new_pos,
             } => Ok(new_pos),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Copies a file to path, returns a new File.
-    pub fn copy(&mut self, path: &str) -> Result<File, VfsError> {
-        let message = vfs_request(
-            &self.path,
-            VfsAction::CopyFile {
-                new_path: path.to_string(),
-            },
-        )
-        .send_and_await_response(self.timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn copy(&mut self, path: &str) -> Result<File, VfsClientError> {
+        let action = VfsAction::CopyFile {
+            new_path: path.to_string(),
+        };
+        match self.call(action, None)? {
             VfsResponse::Ok => Ok(File {
                 path: path.to_string(),
                 timeout: self.timeout,
+                deadline: self.deadline,
             }),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Set file length, if given size > underlying file, fills it with 0s.
-    pub fn set_len(&mut self, size: u64) -> Result<(), VfsError> {
-        let message = vfs_request(&self.path, VfsAction::SetLen(size))
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn set_len(&mut self, size: u64) -> Result<(), VfsClientError> {
+        match self.call(VfsAction::SetLen(size), None)? {
             VfsResponse::Ok => Ok(()),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
     /// Metadata of a path, returns file type and length.
-    pub fn metadata(&self) -> Result<FileMetadata, VfsError> {
-        let message = vfs_request(&self.path, VfsAction::Metadata)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
-
-        match parse_response(message.body())? {
+    pub fn metadata(&self) -> Result<FileMetadata, VfsClientError> {
+        match self.call(VfsAction::Metadata, None)? {
             VfsResponse::Metadata(metadata) => Ok(metadata),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 
-    /// Syncs path file buffers to disk.
-    pub fn sync_all(&self) -> Result<(), VfsError> {
-        let message = vfs_request(&self.path, VfsAction::SyncAll)
-            .send_and_await_response(self.timeout)
-            .unwrap()
-            .map_err(|e| VfsError::SendError(e.kind))?;
+    /// Serialize `value` to JSON and atomically replace the file contents with it.
+    /// See [`write_atomic()`] for the guarantees this provides.
+    pub fn save_json<T: Serialize>(&self, value: &T) -> Result<(), VfsClientError> {
+        let bytes = serde_json::to_vec(value).map_err(|e| VfsClientError::Json {
+            error: e.to_string(),
+            path: self.path.clone(),
+        })?;
+        write_atomic(&self.path, &bytes, Some(self.timeout))
+    }
+
+    /// Computes the SHA-256 hash of the entire file.
+    ///
+    /// Asks the runtime to hash the file itself. If the runtime does not support
+    /// [`VfsAction::Hash`], falls back to reading the file in chunks from the start
+    /// and hashing it here, so the whole file is never held in memory at once.
+    /// Either way, the cursor is left at an unspecified position afterwards.
+    pub fn hash(&mut self) -> Result<[u8; 32], VfsClientError> {
+        match self.call(VfsAction::Hash, None) {
+            Ok(VfsResponse::Hash(hash)) => Ok(hash),
+            Err(VfsClientError::Runtime(VfsError::MalformedRequest)) => hash_contents(self),
+            Ok(response) => Err(bad_response(&response)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the entire file, unless its SHA-256 hash is known_hash: `None` means
+    /// the contents are unchanged. The hash comes from [`File::hash()`], so the
+    /// contents are only transferred when they changed, or to hash them client-side
+    /// on a runtime that can't.
+    pub fn read_if_changed(
+        &mut self,
+        known_hash: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, VfsClientError> {
+        if &self.hash()? == known_hash {
+            return Ok(None);
+        }
+        self.read().map(Some)
+    }
 
-        match parse_response(message.body())? {
+    /// Syncs path file buffers to disk.
+    pub fn sync_all(&self) -> Result<(), VfsClientError> {
+        match self.call(VfsAction::SyncAll, None)? {
             VfsResponse::Ok => Ok(()),
-            VfsResponse::Err(e) => Err(e),
-            _ => Err(VfsError::ParseError {
-                error: "unexpected response".to_string(),
-                path: self.path.clone(),
-            }),
+            response => Err(bad_response(&response)),
         }
     }
 }
 
 impl Drop for File {
     fn drop(&mut self) {
-        vfs_request(&self.path, VfsAction::CloseFile)
-            .send()
-            .unwrap();
+        // best effort: a send hook may refuse it, and drop must not panic
+        let _ = vfs_request(&self.path, VfsAction::CloseFile).send();
     }
 }
 
 /// Creates a drive with path "/package_id/drive", gives you read and write caps.
 /// Will only work on the same package_id as you're calling it from, unless you
-/// have root capabilities.
+/// have root capabilities. A drive that already exists is just returned, so this
+/// can be called on every start.
 pub fn create_drive(
     package_id: PackageId,
     drive: &str,
     timeout: Option<u64>,
-) -> Result<String, VfsError> {
+) -> Result<DrivePath, VfsClientError> {
     let timeout = timeout.unwrap_or(5);
-    let path = format!("/{}/{}", package_id, drive);
-
-    let message = vfs_request(&path, VfsAction::CreateDrive)
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-    match parse_response(message.body())? {
-        VfsResponse::Ok => Ok(path),
-        VfsResponse::Err(e) => Err(e),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
-            path,
-        }),
+    let path = DrivePath::new(&package_id, drive).map_err(VfsClientError::Runtime)?;
+
+    match call(path.as_str(), VfsAction::CreateDrive, timeout) {
+        Ok(VfsResponse::Ok) => Ok(path),
+        Err(VfsClientError::Runtime(VfsError::IOError(e))) if is_already_exists(&e) => Ok(path),
+        Ok(response) => Err(bad_response(&response)),
+        Err(e) => Err(e),
     }
 }
 
 /// Opens a file at path, if no file at path, creates one if boolean create is true.
-pub fn open_file(path: &str, create: bool, timeout: Option<u64>) -> Result<File, VfsError> {
+pub fn open_file(
+    path: impl AsRef<str>,
+    create: bool,
+    timeout: Option<u64>,
+) -> Result<File, VfsClientError> {
+    let path = path.as_ref();
     let timeout = timeout.unwrap_or(5);
 
-    let message = vfs_request(path, VfsAction::OpenFile { create })
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-    match parse_response(message.body())? {
+    match call(path, VfsAction::OpenFile { create }, timeout)? {
         VfsResponse::Ok => Ok(File {
             path: path.to_string(),
             timeout,
+            deadline: None,
         }),
-        VfsResponse::Err(e) => Err(e),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
-            path: path.to_string(),
-        }),
+        response => Err(bad_response(&response)),
     }
 }
 
 /// Creates a file at path, if file found at path, truncates it to 0.
-pub fn create_file(path: &str, timeout: Option<u64>) -> Result<File, VfsError> {
+pub fn create_file(path: impl AsRef<str>, timeout: Option<u64>) -> Result<File, VfsClientError> {
+    let path = path.as_ref();
     let timeout = timeout.unwrap_or(5);
 
-    let message = vfs_request(path, VfsAction::CreateFile)
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-    match parse_response(message.body())? {
+    match call(path, VfsAction::CreateFile, timeout)? {
         VfsResponse::Ok => Ok(File {
             path: path.to_string(),
             timeout,
+            deadline: None,
         }),
-        VfsResponse::Err(e) => Err(e),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
-            path: path.to_string(),
-        }),
+        response => Err(bad_response(&response)),
     }
 }
 
 /// Removes a file at path, errors if path not found or path is not a file.
-pub fn remove_file(path: &str, timeout: Option<u64>) -> Result<(), VfsError> {
+pub fn remove_file(path: &str, timeout: Option<u64>) -> Result<(), VfsClientError> {
     let timeout = timeout.unwrap_or(5);
 
-    let message = vfs_request(path, VfsAction::RemoveFile)
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-    match parse_response(message.body())? {
+    match call(path, VfsAction::RemoveFile, timeout)? {
         VfsResponse::Ok => Ok(()),
-        VfsResponse::Err(e) => Err(e.into()),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
+        response => Err(bad_response(&response)),
+    }
+}
+
+/// Atomically replaces the file at path with the given bytes.
+///
+/// The bytes are first written to a hidden sibling temp file (`.{name}.tmp.{nonce}`),
+/// which is synced to disk and then renamed over `path`. If any step fails, the temp
+/// file is removed and whatever was at `path` before is left untouched. The nonce is
+/// random per call, so concurrent writers to the same path do not share a temp file.
+pub fn write_atomic(path: &str, bytes: &[u8], timeout: Option<u64>) -> Result<(), VfsClientError> {
+    let timeout = timeout.unwrap_or(5);
+    let temp_path = atomic_temp_path(path, rand::random());
+
+    let result = create_file(&temp_path, Some(timeout)).and_then(|temp_file| {
+        temp_file.write(bytes)?;
+        temp_file.sync_all()?;
+        rename(&temp_path, path, Some(timeout))
+    });
+    if result.is_err() {
+        let _ = remove_file(&temp_path, Some(timeout));
+    }
+    result
+}
+
+/// Reads the file at path and deserializes its contents from JSON.
+pub fn read_json<T: DeserializeOwned>(
+    path: &str,
+    timeout: Option<u64>,
+) -> Result<T, VfsClientError> {
+    let file = File::new(path, timeout.unwrap_or(5));
+    let bytes = file.read()?;
+    serde_json::from_slice(&bytes).map_err(|e| VfsClientError::Json {
+        error: e.to_string(),
+        path: path.to_string(),
+    })
+}
+
+/// Computes the SHA-256 hash of the file at path. See [`File::hash()`].
+pub fn hash_file(path: &str, timeout: Option<u64>) -> Result<[u8; 32], VfsClientError> {
+    open_file(path, false, timeout)?.hash()
+}
+
+/// Checks whether the SHA-256 hash of the file at path matches `expected`.
+pub fn verify(
+    path: &str,
+    expected: &[u8; 32],
+    timeout: Option<u64>,
+) -> Result<bool, VfsClientError> {
+    Ok(&hash_file(path, timeout)? == expected)
+}
+
+/// Reads the file at path, failing with [`VfsClientError::HashMismatch`]
+/// unless the SHA-256 hash of its contents is `expected`, e.g. to check a
+/// download.
+pub fn read_verified(
+    path: &str,
+    expected: &[u8; 32],
+    timeout: Option<u64>,
+) -> Result<Vec<u8>, VfsClientError> {
+    let bytes = File::new(path, timeout.unwrap_or(5)).read()?;
+    let mut unread = &bytes[..];
+    let actual: [u8; 32] = hash_chunks::<sha2::Sha256, _>(|buffer| {
+        let len = unread.len().min(buffer.len());
+        buffer[..len].copy_from_slice(&unread[..len]);
+        unread = &unread[len..];
+        Ok(len)
+    })?
+    .into();
+    if &actual != expected {
+        return Err(VfsClientError::HashMismatch {
             path: path.to_string(),
-        }),
+            expected: *expected,
+            actual,
+        });
+    }
+    Ok(bytes)
+}
+
+/// Feed chunks from `read` into a hasher until it reads zero bytes.
+/// Generic over the digest so other algorithms can reuse the chunked reads.
+pub(crate) fn hash_chunks<D, F>(mut read: F) -> Result<sha2::digest::Output<D>, VfsClientError>
+where
+    D: Digest,
+    F: FnMut(&mut [u8]) -> Result<usize, VfsClientError>,
+{
+    let mut hasher = D::new();
+    let mut buffer = vec![0; HASH_CHUNK_SIZE];
+    loop {
+        let len = read(&mut buffer)?;
+        if len == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..len]);
+    }
+}
+
+/// The sibling temp path used by [`write_atomic()`] for a given destination path.
+fn atomic_temp_path(path: &str, nonce: u64) -> String {
+    let name = super::path::file_name(path).unwrap_or_default();
+    match super::path::parent(path) {
+        Some(dir) => format!("{}/.{name}.tmp.{nonce:016x}", dir.trim_end_matches('/')),
+        None => format!(".{name}.tmp.{nonce:016x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::vfs::{FileType, VfsRequest};
+    use crate::SendErrorKind;
+    use serde_json::{json, Value};
+    use sha2::Sha256;
+
+    const PATH: &str = "/app:sys/drive/log";
+
+    /// The actions of the vfs requests sent so far, as JSON, with the bytes each
+    /// carried.
+    fn actions(host: &MockHost) -> Vec<(Value, Option<Vec<u8>>)> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, blob, .. } => {
+                    let request: VfsRequest = serde_json::from_slice(&request.body).unwrap();
+                    assert_eq!(request.path, PATH);
+                    Some((
+                        serde_json::to_value(request.action).unwrap(),
+                        blob.map(|blob| blob.bytes),
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn seek_to(offset: u64) -> (Value, Option<Vec<u8>>) {
+        (json!({"Seek": {"Start": offset}}), None)
+    }
+
+    fn write(bytes: &[u8]) -> (Value, Option<Vec<u8>>) {
+        (json!("WriteAll"), Some(bytes.to_vec()))
+    }
+
+    #[test]
+    fn test_write_all_at() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut file = File::new(PATH, 5);
+
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 10 }));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        file.write_all_at(10, b"abc").unwrap();
+        assert_eq!(actions(&host), [seek_to(10), write(b"abc")]);
+
+        // a timed out write is retried once, from the same offset
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 4 }));
+        host.reply(Reply::Error(SendErrorKind::Timeout));
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 4 }));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        file.write_all_at(4, b"de").unwrap();
+        assert_eq!(
+            actions(&host),
+            [seek_to(4), write(b"de"), seek_to(4), write(b"de")]
+        );
+
+        // but not twice
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 4 }));
+        host.reply(Reply::Error(SendErrorKind::Timeout));
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 4 }));
+        host.reply(Reply::Error(SendErrorKind::Timeout));
+        assert!(matches!(
+            file.write_all_at(4, b"de"),
+            Err(VfsClientError::Timeout { .. })
+        ));
+        assert_eq!(actions(&host).len(), 4);
+
+        // nothing is written if the seek fails, nor retried if the write fails
+        host.reply(Reply::Error(SendErrorKind::Timeout));
+        assert!(file.write_all_at(4, b"de").is_err());
+        assert_eq!(actions(&host), [seek_to(4)]);
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 4 }));
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::NoWriteCap)));
+        assert!(matches!(
+            file.write_all_at(4, b"de"),
+            Err(VfsClientError::NoCapability { .. })
+        ));
+        assert_eq!(actions(&host), [seek_to(4), write(b"de")]);
+    }
+
+    #[test]
+    fn test_truncate_from() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut file = File::new(PATH, 5);
+        let metadata = |len| {
+            Reply::json(&VfsResponse::Metadata(FileMetadata {
+                file_type: FileType::File,
+                len,
+                created: None,
+                modified: None,
+            }))
+        };
+
+        host.reply(metadata(100));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        file.truncate_from(40).unwrap();
+        assert_eq!(
+            actions(&host),
+            [(json!("Metadata"), None), (json!({"SetLen": 40}), None)]
+        );
+
+        // a file no longer than offset is left alone
+        host.reply(metadata(40));
+        file.truncate_from(40).unwrap();
+        assert_eq!(actions(&host), [(json!("Metadata"), None)]);
+    }
+
+    #[test]
+    fn test_hash_fallback_reads_exactly_what_is_left() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut file = File::new(PATH, 5);
+
+        // a runtime without Hash, on a file shorter than a chunk: asking for a
+        // whole chunk would fail, as read_exact does past the end
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::MalformedRequest)));
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 0 }));
+        host.reply(Reply::json(&VfsResponse::Metadata(FileMetadata {
+            file_type: FileType::File,
+            len: 5,
+            created: None,
+            modified: None,
+        })));
+        host.reply(Reply::with_blob(
+            serde_json::to_vec(&VfsResponse::Read).unwrap(),
+            crate::LazyLoadBlob::new(None::<String>, b"hello".to_vec()),
+        ));
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::IOError(
+            "failed to fill whole buffer".to_string(),
+        ))));
+        let hash = file.hash().unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(b"hello")));
+        assert_eq!(
+            actions(&host),
+            [
+                (json!("Hash"), None),
+                seek_to(0),
+                (json!("Metadata"), None),
+                (json!({"ReadExact": {"length": 5}}), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_atomic_temp_path() {
+        assert_eq!(
+            atomic_temp_path("/pkg:pub/drive/config.json", 0xabc),
+            "/pkg:pub/drive/.config.json.tmp.0000000000000abc"
+        );
+        assert_eq!(
+            atomic_temp_path("config.json", 1),
+            ".config.json.tmp.0000000000000001"
+        );
+        assert_ne!(
+            atomic_temp_path("/pkg:pub/drive/a", 1),
+            atomic_temp_path("/pkg:pub/drive/a", 2)
+        );
+    }
+
+    #[test]
+    fn test_write_atomic_removes_temp_file_on_failed_rename() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+
+        for _ in 0..3 {
+            host.reply(Reply::json(&VfsResponse::Ok));
+        }
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::NoWriteCap)));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        assert!(matches!(
+            write_atomic(PATH, b"new", None),
+            Err(VfsClientError::NoCapability { .. })
+        ));
+
+        let requests: Vec<(String, Value)> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, .. } => {
+                    let request: VfsRequest = serde_json::from_slice(&request.body).unwrap();
+                    Some((request.path, serde_json::to_value(request.action).unwrap()))
+                }
+                _ => None,
+            })
+            .collect();
+        let temp_path = requests[0].0.clone();
+        assert!(
+            temp_path.starts_with("/app:sys/drive/.log.tmp."),
+            "{temp_path}"
+        );
+        assert_eq!(
+            requests,
+            [
+                (temp_path.clone(), json!("CreateFile")),
+                (temp_path.clone(), json!("Write")),
+                (temp_path.clone(), json!("SyncAll")),
+                (temp_path.clone(), json!({"Rename": {"new_path": PATH}})),
+                (temp_path, json!("RemoveFile")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_chunks_sha256() {
+        fn hash(data: &[u8], read_size: usize) -> String {
+            let mut data = data;
+            let digest = hash_chunks::<Sha256, _>(|buffer| {
+                let len = data.len().min(buffer.len()).min(read_size);
+                buffer[..len].copy_from_slice(&data[..len]);
+                data = &data[len..];
+                Ok(len)
+            })
+            .unwrap();
+            digest.iter().map(|b| format!("{b:02x}")).collect()
+        }
+        assert_eq!(
+            hash(b"", 1),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hash(b"abc", 1),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let expected = "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1";
+        assert_eq!(hash(long, 7), expected);
+        assert_eq!(hash(long, usize::MAX), expected);
+    }
+
+    #[test]
+    fn test_deadline() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut file = File::new(PATH, 5).with_deadline(Deadline::in_ms(60_000));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        file.write_all(b"abc").unwrap();
+        let calls = host.take_calls();
+        let [Call::SendAndAwaitResponse { request, .. }] = &calls[..] else {
+            panic!("got {calls:?}");
+        };
+        assert_eq!(request.expects_response, Some(60));
+
+        file.deadline = Some(Deadline::at_ms(0));
+        assert!(matches!(
+            file.write_all(b"abc"),
+            Err(VfsClientError::Timeout { .. })
+        ));
+        assert!(host.take_calls().is_empty());
+    }
+
+    fn read_reply(bytes: &[u8]) -> Reply {
+        Reply::with_blob(
+            serde_json::to_vec(&VfsResponse::Read).unwrap(),
+            crate::LazyLoadBlob {
+                mime: None,
+                bytes: bytes.to_vec(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_read_if_changed() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut file = File::new(PATH, 5);
+        let known: [u8; 32] = Sha256::digest(b"abc").into();
+
+        // unchanged: only the hash is transferred
+        host.reply(Reply::json(&VfsResponse::Hash(known)));
+        assert_eq!(file.read_if_changed(&known).unwrap(), None);
+        assert_eq!(actions(&host), [(json!("Hash"), None)]);
+
+        host.reply(Reply::json(&VfsResponse::Hash([0; 32])));
+        host.reply(read_reply(b"abcd"));
+        assert_eq!(file.read_if_changed(&known).unwrap().unwrap(), b"abcd");
+        assert_eq!(
+            actions(&host),
+            [(json!("Hash"), None), (json!("Read"), None)]
+        );
+    }
+
+    #[test]
+    fn test_read_verified() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let expected: [u8; 32] = Sha256::digest(b"abc").into();
+
+        host.reply(read_reply(b"abc"));
+        assert_eq!(read_verified(PATH, &expected, None).unwrap(), b"abc");
+        host.reply(read_reply(b"abd"));
+        let error = read_verified(PATH, &expected, None).unwrap_err();
+        let actual: [u8; 32] = Sha256::digest(b"abd").into();
+        assert!(matches!(
+            &error,
+            VfsClientError::HashMismatch { path, expected: e, actual: a }
+                if path == PATH && e == &expected && a == &actual
+        ));
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "vfs: hash of {PATH} is {}, expected \
+                 ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                crate::vfs::hex(&actual)
+            )
+        );
+    }
+
+    #[test]
+    fn test_read_async_overlaps() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let a = File::new("/app:sys/drive/a", 5);
+        let b = File::new("/app:sys/drive/b", 5);
+
+        let reads = crate::executor::run(async {
+            let both = futures::future::join(a.read_async(), b.read_async());
+            let mut both = std::pin::pin!(both);
+            // polled once, both requests go out before either is answered
+            let _ = futures::poll!(both.as_mut());
+            let contexts: Vec<Vec<u8>> = host
+                .take_calls()
+                .into_iter()
+                .filter_map(|call| match call {
+                    Call::SendRequest { context, .. } => context,
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(contexts.len(), 2);
+            for (context, bytes) in contexts.into_iter().zip([&b"aaa"[..], b"bb"]).rev() {
+                let response = crate::test_utils::MessageBuilder::response()
+                    .from("tester.os@vfs:distro:sys")
+                    .body_json(&VfsResponse::Read)
+                    .context(context)
+                    .build();
+                let blob = crate::LazyLoadBlob::new(None::<String>, bytes.to_vec());
+                host.push_message(response, Some(blob));
+            }
+            both.await
+        });
+        assert_eq!(reads.0.unwrap(), b"aaa");
+        assert_eq!(reads.1.unwrap(), b"bb");
     }
 }
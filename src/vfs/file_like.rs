@@ -0,0 +1,403 @@
+use super::file::{hash_chunks, HASH_CHUNK_SIZE};
+use super::{File, FileMetadata, FileType, SeekFrom, VfsClientError, VfsError};
+use sha2::Sha256;
+
+/// The read, write and seek surface of [`File`], for code that works on a file
+/// whether it lives in the vfs or, as a [`MemFile`], in memory.
+///
+/// [`File`] implements each method with the inherent method of the same name,
+/// which the method docs of [`File`] describe.
+pub trait FileLike {
+    /// Reads the entire file, from start position.
+    fn read(&mut self) -> Result<Vec<u8>, VfsClientError>;
+
+    /// Read into buffer from current cursor position.
+    /// Returns the amount of bytes read.
+    fn read_at(&mut self, buffer: &mut [u8]) -> Result<usize, VfsClientError>;
+
+    /// Reads until end of file from current cursor position.
+    fn read_to_end(&mut self) -> Result<Vec<u8>, VfsClientError>;
+
+    /// Write entire slice as the new file.
+    fn write(&mut self, buffer: &[u8]) -> Result<(), VfsClientError>;
+
+    /// Write buffer at current position, overwriting exactly buffer.len() bytes
+    /// from there and moving the cursor after them.
+    fn write_all(&mut self, buffer: &[u8]) -> Result<(), VfsClientError>;
+
+    /// Write buffer to the end position of file.
+    fn append(&mut self, buffer: &[u8]) -> Result<(), VfsClientError>;
+
+    /// Seek file to position.
+    /// Returns the new position.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsClientError>;
+
+    /// Set file length, if given size > underlying file, fills it with 0s.
+    fn set_len(&mut self, size: u64) -> Result<(), VfsClientError>;
+
+    fn metadata(&self) -> Result<FileMetadata, VfsClientError>;
+
+    /// Computes the SHA-256 hash of the entire file, with [`hash_contents()`]
+    /// unless the implementation has a better way.
+    fn hash(&mut self) -> Result<[u8; 32], VfsClientError> {
+        hash_contents(self)
+    }
+}
+
+impl FileLike for File {
+    fn read(&mut self) -> Result<Vec<u8>, VfsClientError> {
+        File::read(self)
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8]) -> Result<usize, VfsClientError> {
+        File::read_at(self, buffer)
+    }
+
+    fn read_to_end(&mut self) -> Result<Vec<u8>, VfsClientError> {
+        File::read_to_end(self)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        File::write(self, buffer)
+    }
+
+    fn write_all(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        File::write_all(self, buffer)
+    }
+
+    fn append(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        File::append(self, buffer)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsClientError> {
+        File::seek(self, pos)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), VfsClientError> {
+        File::set_len(self, size)
+    }
+
+    fn metadata(&self) -> Result<FileMetadata, VfsClientError> {
+        File::metadata(self)
+    }
+
+    /// Asks the runtime, see [`File::hash()`].
+    fn hash(&mut self) -> Result<[u8; 32], VfsClientError> {
+        File::hash(self)
+    }
+}
+
+/// A [`FileLike`] held in memory, e.g. to build an export before deciding where
+/// to put it, or to test file handling without a node. Its cursor starts at 0.
+///
+/// ```
+/// use hyperware_process_lib::vfs::{FileLike, MemFile, SeekFrom};
+///
+/// let mut file = MemFile::new(b"hello world".to_vec());
+/// file.seek(SeekFrom::Start(6)).unwrap();
+/// file.write_all(b"there").unwrap();
+/// assert_eq!(file.into_inner(), b"hello there");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemFile {
+    bytes: Vec<u8>,
+    cursor: u64,
+}
+
+impl MemFile {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        MemFile { bytes, cursor: 0 }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// The cursor, clamped to the end of the file.
+    fn position(&self) -> usize {
+        usize::try_from(self.cursor).map_or(self.bytes.len(), |cursor| cursor.min(self.bytes.len()))
+    }
+}
+
+impl From<Vec<u8>> for MemFile {
+    fn from(bytes: Vec<u8>) -> Self {
+        MemFile::new(bytes)
+    }
+}
+
+impl FileLike for MemFile {
+    fn read(&mut self) -> Result<Vec<u8>, VfsClientError> {
+        Ok(self.bytes.clone())
+    }
+
+    fn read_at(&mut self, buffer: &mut [u8]) -> Result<usize, VfsClientError> {
+        let start = self.position();
+        let len = buffer.len().min(self.bytes.len() - start);
+        buffer[..len].copy_from_slice(&self.bytes[start..start + len]);
+        self.cursor += len as u64;
+        Ok(len)
+    }
+
+    fn read_to_end(&mut self) -> Result<Vec<u8>, VfsClientError> {
+        let rest = self.bytes[self.position()..].to_vec();
+        self.cursor = self.cursor.max(self.bytes.len() as u64);
+        Ok(rest)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        self.bytes = buffer.to_vec();
+        Ok(())
+    }
+
+    fn write_all(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        let start =
+            usize::try_from(self.cursor).map_err(|_| io_error("cursor past addressable memory"))?;
+        let end = start + buffer.len();
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        self.bytes[start..end].copy_from_slice(buffer);
+        self.cursor = end as u64;
+        Ok(())
+    }
+
+    fn append(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+        self.bytes.extend_from_slice(buffer);
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsClientError> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.cursor = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.bytes.len() as u64, offset),
+            SeekFrom::Current(offset) => (self.cursor, offset),
+        };
+        self.cursor = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io_error("invalid seek to a negative or overflowing position"))?;
+        Ok(self.cursor)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<(), VfsClientError> {
+        let size = usize::try_from(size).map_err(|_| io_error("length past addressable memory"))?;
+        self.bytes.resize(size, 0);
+        Ok(())
+    }
+
+    fn metadata(&self) -> Result<FileMetadata, VfsClientError> {
+        Ok(FileMetadata {
+            file_type: FileType::File,
+            len: self.bytes.len() as u64,
+            created: None,
+            modified: None,
+        })
+    }
+}
+
+fn io_error(error: &str) -> VfsClientError {
+    VfsClientError::Runtime(VfsError::IOError(error.to_string()))
+}
+
+/// Computes the SHA-256 hash of the entire file by reading it in chunks from the
+/// start, so the whole file is never held in memory at once. The cursor is left
+/// at the end of the file.
+pub fn hash_contents<F: FileLike + ?Sized>(file: &mut F) -> Result<[u8; 32], VfsClientError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut remaining = file.metadata()?.len;
+    hash_chunks::<Sha256, _>(|buffer| read_chunk(file, buffer, &mut remaining)).map(Into::into)
+}
+
+/// Copies everything from the cursor of from to its end into to at its cursor,
+/// in chunks, leaving both cursors after the copied bytes.
+/// Returns the amount of bytes copied.
+pub fn copy_contents<R, W>(from: &mut R, to: &mut W) -> Result<u64, VfsClientError>
+where
+    R: FileLike + ?Sized,
+    W: FileLike + ?Sized,
+{
+    let position = from.seek(SeekFrom::Current(0))?;
+    let mut remaining = from.metadata()?.len.saturating_sub(position);
+    let mut buffer = vec![0; HASH_CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let len = read_chunk(from, &mut buffer, &mut remaining)?;
+        if len == 0 {
+            return Ok(copied);
+        }
+        to.write_all(&buffer[..len])?;
+        copied += len as u64;
+    }
+}
+
+/// Read the next chunk of the remaining bytes of file into buffer, never asking
+/// for more than remaining: [`File::read_at()`] reads exactly as many bytes as
+/// asked, and fails rather than come up short at the end of the file.
+pub(crate) fn read_chunk<F: FileLike + ?Sized>(
+    file: &mut F,
+    buffer: &mut [u8],
+    remaining: &mut u64,
+) -> Result<usize, VfsClientError> {
+    let len = buffer
+        .len()
+        .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+    if len == 0 {
+        return Ok(0);
+    }
+    let read = file.read_at(&mut buffer[..len])?;
+    *remaining -= read as u64;
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    fn hex(hash: [u8; 32]) -> String {
+        hash.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Reads like [`File::read_at()`] does: exactly as many bytes as asked, and
+    /// an error, as from the runtime's `read_exact`, if the file ends first.
+    struct ExactFile(MemFile);
+
+    impl FileLike for ExactFile {
+        fn read(&mut self) -> Result<Vec<u8>, VfsClientError> {
+            self.0.read()
+        }
+
+        fn read_at(&mut self, buffer: &mut [u8]) -> Result<usize, VfsClientError> {
+            let remaining = self.0.bytes.len().saturating_sub(self.0.position());
+            if buffer.len() > remaining {
+                return Err(io_error("failed to fill whole buffer"));
+            }
+            self.0.read_at(buffer)
+        }
+
+        fn read_to_end(&mut self) -> Result<Vec<u8>, VfsClientError> {
+            self.0.read_to_end()
+        }
+
+        fn write(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+            self.0.write(buffer)
+        }
+
+        fn write_all(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+            self.0.write_all(buffer)
+        }
+
+        fn append(&mut self, buffer: &[u8]) -> Result<(), VfsClientError> {
+            self.0.append(buffer)
+        }
+
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64, VfsClientError> {
+            self.0.seek(pos)
+        }
+
+        fn set_len(&mut self, size: u64) -> Result<(), VfsClientError> {
+            self.0.set_len(size)
+        }
+
+        fn metadata(&self) -> Result<FileMetadata, VfsClientError> {
+            self.0.metadata()
+        }
+    }
+
+    #[test]
+    fn test_mem_file_cursor() {
+        let mut file = MemFile::new(b"0123456789".to_vec());
+        let mut buffer = [0; 4];
+        assert_eq!(file.read_at(&mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"0123");
+        assert_eq!(file.seek(SeekFrom::Current(2)).unwrap(), 6);
+        assert_eq!(file.read_to_end().unwrap(), b"6789");
+        assert_eq!(file.read_at(&mut buffer).unwrap(), 0);
+
+        // writing past the end fills the gap with 0s, like a sparse file
+        assert_eq!(file.seek(SeekFrom::End(2)).unwrap(), 12);
+        file.write_all(b"ab").unwrap();
+        assert_eq!(file.bytes(), b"0123456789\0\0ab");
+        assert!(file.seek(SeekFrom::Current(-15)).is_err());
+
+        file.set_len(3).unwrap();
+        file.append(b"!").unwrap();
+        assert_eq!(file.read().unwrap(), b"012!");
+        assert_eq!(file.metadata().unwrap().len, 4);
+        // the cursor is past the end now, so reads find nothing
+        assert_eq!(file.read_to_end().unwrap(), b"");
+    }
+
+    #[test]
+    fn test_hash_contents() {
+        let mut file = MemFile::new(b"abc".to_vec());
+        file.seek(SeekFrom::End(0)).unwrap();
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        // hashes the whole file wherever the cursor was
+        assert_eq!(hex(file.hash().unwrap()), expected);
+        assert_eq!(file.hash().unwrap(), hash_contents(&mut file).unwrap());
+
+        let big = vec![7; HASH_CHUNK_SIZE * 2 + 5];
+        let mut chunks = MemFile::new(big.clone());
+        assert_eq!(
+            chunks.hash().unwrap(),
+            <[u8; 32]>::from(Sha256::digest(&big))
+        );
+    }
+
+    #[test]
+    fn test_copy_contents() {
+        let mut from = MemFile::new(vec![1; HASH_CHUNK_SIZE + 10]);
+        from.seek(SeekFrom::Start(10)).unwrap();
+        let mut to = MemFile::new(b"header:".to_vec());
+        to.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(
+            copy_contents(&mut from, &mut to).unwrap(),
+            HASH_CHUNK_SIZE as u64
+        );
+        assert_eq!(to.metadata().unwrap().len, 7 + HASH_CHUNK_SIZE as u64);
+        assert_eq!(&to.bytes()[..8], b"header:\x01");
+        // both cursors moved past the copy
+        assert_eq!(copy_contents(&mut from, &mut to).unwrap(), 0);
+
+        // and trait objects work too
+        let from: &mut dyn FileLike = &mut MemFile::new(b"abc".to_vec());
+        let mut to = MemFile::default();
+        copy_contents(from, &mut to).unwrap();
+        assert_eq!(to.into_inner(), b"abc");
+    }
+
+    #[test]
+    fn test_chunks_stop_at_end_of_exact_reads() {
+        let bytes: Vec<u8> = (0..HASH_CHUNK_SIZE * 2 + 5).map(|i| i as u8).collect();
+        let mut file = ExactFile(MemFile::new(bytes.clone()));
+        // reading a whole chunk past the end fails, as it does in the vfs
+        file.seek(SeekFrom::End(-5)).unwrap();
+        assert!(file.read_at(&mut vec![0; HASH_CHUNK_SIZE]).is_err());
+
+        assert_eq!(
+            hash_contents(&mut file).unwrap(),
+            <[u8; 32]>::from(Sha256::digest(&bytes))
+        );
+        file.seek(SeekFrom::Start(3)).unwrap();
+        let mut to = MemFile::default();
+        assert_eq!(
+            copy_contents(&mut file, &mut to).unwrap(),
+            bytes.len() as u64 - 3
+        );
+        assert_eq!(to.bytes(), &bytes[3..]);
+        // empty files and cursors at the end read nothing
+        assert_eq!(copy_contents(&mut file, &mut to).unwrap(), 0);
+        let mut empty = ExactFile(MemFile::default());
+        assert_eq!(
+            hex(hash_contents(&mut empty).unwrap()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use super::{open_file, SeekFrom, VfsClientError};
+use crate::timer::{Fired, Schedule};
+use crate::BuildError;
+use serde::{Deserialize, Serialize};
+
+/// Reads the lines another process appends to a file as they arrive, like
+/// `tail -f`: each [`Follower::poll()`] reads only what was added since the last.
+///
+/// A file that shrank, or went missing, is taken to have been rotated: reading
+/// starts over from its beginning, and [`Follower::rotated()`] says so.
+///
+/// A `Follower` is serializable so it can be kept in process state, to pick up
+/// where it left off after a restart.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Follower {
+    pub path: String,
+    /// How far into the file has been read, including any partial line.
+    offset: u64,
+    /// The start of a line whose newline has not been written yet.
+    partial: Vec<u8>,
+    rotated: bool,
+    timeout: u64,
+}
+
+/// What a [`Follower`] needs from the vfs, so that it can be exercised without one.
+trait FollowIo {
+    /// The length of the file at path, or `None` if there is none.
+    fn len(&mut self, path: &str, timeout: u64) -> anyhow::Result<Option<u64>>;
+    /// The bytes of the file at path from offset to its end.
+    fn read_from(&mut self, path: &str, offset: u64, timeout: u64) -> anyhow::Result<Vec<u8>>;
+}
+
+struct Vfs;
+
+impl FollowIo for Vfs {
+    fn len(&mut self, path: &str, timeout: u64) -> anyhow::Result<Option<u64>> {
+        match super::metadata(path, Some(timeout)) {
+            Ok(meta) => Ok(Some(meta.len)),
+            Err(VfsClientError::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_from(&mut self, path: &str, offset: u64, timeout: u64) -> anyhow::Result<Vec<u8>> {
+        let mut file = open_file(path, false, Some(timeout))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file.read_to_end()?)
+    }
+}
+
+impl Follower {
+    /// Follow the file at path from its beginning.
+    pub fn new(path: &str) -> Self {
+        Follower {
+            path: path.to_string(),
+            offset: 0,
+            partial: vec![],
+            rotated: false,
+            timeout: 5,
+        }
+    }
+
+    /// Set the timeout of each vfs request, in seconds. Defaults to 5.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How far into the file has been read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether the last [`Follower::poll()`] found the file rotated.
+    pub fn rotated(&self) -> bool {
+        self.rotated
+    }
+
+    /// The lines completed since the last poll, without their newlines. A
+    /// line whose newline has not been written yet is held back until it is,
+    /// unless the file is rotated first, in which case it is returned as is.
+    pub fn poll(&mut self) -> anyhow::Result<Vec<String>> {
+        self.poll_with(&mut Vfs)
+    }
+
+    /// Poll the file every interval_ms, on schedule, under the tag
+    /// [`Follower::tag()`]: pass the firings [`Schedule::handle()`] returns to
+    /// [`Follower::handle()`].
+    pub fn attach(&self, schedule: &mut Schedule, interval_ms: u64) -> Result<(), BuildError> {
+        schedule.every(interval_ms, &self.tag())
+    }
+
+    /// The [`Schedule`] tag [`Follower::attach()`] polls this file under.
+    pub fn tag(&self) -> String {
+        format!("vfs-follow:{}", self.path)
+    }
+
+    /// [`Follower::poll()`] if fired is this follower's timer, returning `None`
+    /// if it is some other timer.
+    pub fn handle(&mut self, fired: &Fired) -> Option<anyhow::Result<Vec<String>>> {
+        (fired.tag == self.tag()).then(|| self.poll())
+    }
+
+    fn poll_with<I: FollowIo>(&mut self, io: &mut I) -> anyhow::Result<Vec<String>> {
+        let len = io.len(&self.path, self.timeout)?;
+        self.rotated = len.map_or(self.offset > 0, |len| len < self.offset);
+        let mut lines = vec![];
+        if self.rotated {
+            if !self.partial.is_empty() {
+                lines.push(to_line(std::mem::take(&mut self.partial)));
+            }
+            self.offset = 0;
+        }
+        match len {
+            Some(len) if len > self.offset => {}
+            _ => return Ok(lines),
+        }
+        let bytes = io.read_from(&self.path, self.offset, self.timeout)?;
+        self.offset += bytes.len() as u64;
+        self.partial.extend_from_slice(&bytes);
+        if let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') {
+            let rest = self.partial.split_off(end + 1);
+            let complete = std::mem::replace(&mut self.partial, rest);
+            lines.extend(
+                complete[..end]
+                    .split(|&b| b == b'\n')
+                    .map(|line| to_line(line.to_vec())),
+            );
+        }
+        Ok(lines)
+    }
+}
+
+fn to_line(mut bytes: Vec<u8>) -> String {
+    if bytes.last() == Some(&b'\r') {
+        bytes.pop();
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in memory, counting the bytes read from it.
+    #[derive(Default)]
+    struct MockFile {
+        bytes: Option<Vec<u8>>,
+        read: usize,
+    }
+
+    impl MockFile {
+        fn append(&mut self, bytes: &str) {
+            self.bytes
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(bytes.as_bytes());
+        }
+    }
+
+    impl FollowIo for MockFile {
+        fn len(&mut self, _path: &str, _timeout: u64) -> anyhow::Result<Option<u64>> {
+            Ok(self.bytes.as_ref().map(|bytes| bytes.len() as u64))
+        }
+
+        fn read_from(
+            &mut self,
+            _path: &str,
+            offset: u64,
+            _timeout: u64,
+        ) -> anyhow::Result<Vec<u8>> {
+            let bytes = self.bytes.as_ref().unwrap()[offset as usize..].to_vec();
+            self.read += bytes.len();
+            Ok(bytes)
+        }
+    }
+
+    #[test]
+    fn test_reads_only_new_bytes() {
+        let mut file = MockFile::default();
+        let mut follower = Follower::new("/app:pub.os/app.log");
+        assert!(follower.poll_with(&mut file).unwrap().is_empty());
+        assert!(!follower.rotated());
+
+        file.append("one\ntwo\r\n");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["one", "two"]);
+        assert_eq!(follower.offset(), 9);
+        assert!(follower.poll_with(&mut file).unwrap().is_empty());
+        file.append("three\n");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["three"]);
+        assert_eq!(follower.offset(), 15);
+        assert_eq!(file.read, 15);
+        assert!(!follower.rotated());
+    }
+
+    #[test]
+    fn test_partial_lines_buffered() {
+        let mut file = MockFile::default();
+        let mut follower = Follower::new("/app:pub.os/app.log");
+        file.append("hel");
+        assert!(follower.poll_with(&mut file).unwrap().is_empty());
+        assert_eq!(follower.offset(), 3);
+        file.append("lo\nwor");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["hello"]);
+        file.append("ld\n\n");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["world", ""]);
+        assert_eq!(file.read, 13);
+
+        // survives being kept in state
+        file.append("half");
+        follower.poll_with(&mut file).unwrap();
+        let mut follower: Follower =
+            serde_json::from_slice(&serde_json::to_vec(&follower).unwrap()).unwrap();
+        file.append(" done\n");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["half done"]);
+    }
+
+    #[test]
+    fn test_rotation_detected() {
+        let mut file = MockFile::default();
+        let mut follower = Follower::new("/app:pub.os/app.log");
+        file.append("old line\nold partial");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["old line"]);
+
+        // rotated away: nothing to read until it is recreated
+        file.bytes = None;
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["old partial"]);
+        assert!(follower.rotated());
+        assert_eq!(follower.offset(), 0);
+        assert!(follower.poll_with(&mut file).unwrap().is_empty());
+        assert!(!follower.rotated());
+
+        file.append("new\n");
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["new"]);
+        assert!(!follower.rotated());
+
+        // truncated in place and written past by less than was read
+        file.bytes = Some(b"a\nb".to_vec());
+        assert_eq!(follower.poll_with(&mut file).unwrap(), ["a"]);
+        assert!(follower.rotated());
+        assert_eq!(follower.offset(), 3);
+    }
+
+    #[test]
+    fn test_attach() {
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        let mut schedule = Schedule::new();
+        let follower = Follower::new("/app:pub.os/app.log");
+        follower.attach(&mut schedule, 1000).unwrap();
+        assert_eq!(
+            schedule.tags().collect::<Vec<_>>(),
+            ["vfs-follow:/app:pub.os/app.log"]
+        );
+        let mut other = Follower::new("/app:pub.os/other.log");
+        let fired = Fired {
+            tag: follower.tag(),
+            recurring: true,
+        };
+        assert!(other.handle(&fired).is_none());
+    }
+}
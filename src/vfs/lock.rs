@@ -0,0 +1,396 @@
+use super::{
+    bad_response, call, create_file, is_already_exists, metadata, remove_dir_all, File, VfsAction,
+    VfsClientError, VfsError, VfsResponse,
+};
+use crate::timer::wall_clock_ms;
+use serde::{Deserialize, Serialize};
+
+const MIN_BACKOFF_MS: u64 = 10;
+const MAX_BACKOFF_MS: u64 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    Exclusive,
+}
+
+/// Who holds a lock, as recorded inside the lock.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockHolder {
+    /// The `ProcessId` of the holder.
+    pub holder: String,
+    /// When the lock was acquired, in milliseconds since the UNIX epoch.
+    pub acquired_ms: u64,
+}
+
+/// A held lock. The lock is released when this is dropped.
+#[derive(Debug)]
+pub struct LockGuard {
+    pub path: String,
+    lock_path: String,
+    timeout: u64,
+    released: bool,
+}
+
+impl LockGuard {
+    /// Release the lock now, surfacing any error rather than ignoring it on drop.
+    pub fn release(mut self) -> Result<(), VfsClientError> {
+        self.released = true;
+        remove_dir_all(&self.lock_path, Some(self.timeout))
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = remove_dir_all(&self.lock_path, Some(self.timeout));
+        }
+    }
+}
+
+/// Take a lock on path, polling with backoff for up to timeout_ms milliseconds
+/// if another process holds it.
+///
+/// The lock is **advisory only**: it does not stop anyone from reading or writing
+/// path, it only excludes other callers of [`lock()`] on the same path. It lives
+/// next to path as a `.{name}.lock` directory, since creating a directory is the
+/// one vfs action that fails atomically if it already exists, and records the
+/// [`LockHolder`] inside it. If a holder crashes without releasing, the lock stays
+/// held; see [`steal_if_older_than()`].
+pub fn lock(path: &str, kind: LockKind, timeout_ms: u64) -> Result<LockGuard, VfsClientError> {
+    let LockKind::Exclusive = kind;
+    let timeout = 5;
+    let lock_path = lock_path(path);
+    let deadline = wall_clock_ms() + timeout_ms;
+    let mut backoff = MIN_BACKOFF_MS;
+
+    loop {
+        match call(&lock_path, VfsAction::CreateDir, timeout) {
+            Ok(VfsResponse::Ok) => break,
+            // the lock directory already exists: someone else holds the lock
+            Err(VfsClientError::Runtime(VfsError::IOError(e))) if is_already_exists(&e) => {}
+            Ok(response) => return Err(bad_response(&response)),
+            Err(e) => return Err(e),
+        }
+
+        let now = wall_clock_ms();
+        if now >= deadline {
+            return Err(VfsClientError::LockTimeout {
+                path: path.to_string(),
+            });
+        }
+        // a timer that fails to fire only shortens the wait
+        let _ = crate::timer::set_and_await_timer(backoff.min(deadline - now))?;
+        backoff = next_backoff(backoff);
+    }
+
+    let guard = LockGuard {
+        path: path.to_string(),
+        lock_path,
+        timeout,
+        released: false,
+    };
+    let holder = LockHolder {
+        holder: crate::our().process.to_string(),
+        acquired_ms: wall_clock_ms(),
+    };
+    let body = serde_json::to_vec(&holder).map_err(|e| VfsClientError::Json {
+        error: e.to_string(),
+        path: holder_path(&guard.lock_path),
+    })?;
+    create_file(holder_path(&guard.lock_path), Some(timeout))?.write(&body)?;
+    Ok(guard)
+}
+
+/// Returns who holds the lock on path, or `None` if it is not locked.
+/// A lock whose holder has not been recorded yet, i.e. whose holder file is
+/// missing or empty, is reported with an empty holder, acquired when the lock
+/// was last modified, or just now if the runtime doesn't say, so that it is
+/// never taken for stale on a guess. Failing to read or parse a recorded
+/// holder is an error.
+pub fn lock_holder(path: &str, timeout: Option<u64>) -> Result<Option<LockHolder>, VfsClientError> {
+    let lock_path = lock_path(path);
+    let lock_meta = match metadata(&lock_path, timeout) {
+        Ok(meta) => meta,
+        Err(VfsClientError::NotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let unrecorded = LockHolder {
+        holder: String::new(),
+        acquired_ms: lock_meta.modified().unwrap_or_else(wall_clock_ms),
+    };
+    let holder_path = holder_path(&lock_path);
+    let bytes = match File::new(&holder_path, timeout.unwrap_or(5)).read() {
+        Ok(bytes) if bytes.is_empty() => return Ok(Some(unrecorded)),
+        Ok(bytes) => bytes,
+        Err(VfsClientError::NotFound { .. }) => return Ok(Some(unrecorded)),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| VfsClientError::Json {
+            error: e.to_string(),
+            path: holder_path,
+        })
+}
+
+/// Escape hatch for holders that crashed without releasing: if the lock on path
+/// was acquired more than max_age_ms milliseconds ago, remove it.
+/// Returns whether a lock was removed.
+///
+/// Nothing stops a live holder from being robbed, so pick max_age_ms well above
+/// the longest time the lock is legitimately held.
+pub fn steal_if_older_than(
+    path: &str,
+    max_age_ms: u64,
+    timeout: Option<u64>,
+) -> Result<bool, VfsClientError> {
+    let Some(holder) = lock_holder(path, timeout)? else {
+        return Ok(false);
+    };
+    if !is_stale(&holder, wall_clock_ms(), max_age_ms) {
+        return Ok(false);
+    }
+    remove_dir_all(&lock_path(path), timeout)?;
+    Ok(true)
+}
+
+fn is_stale(holder: &LockHolder, now_ms: u64, max_age_ms: u64) -> bool {
+    now_ms.saturating_sub(holder.acquired_ms) > max_age_ms
+}
+
+fn next_backoff(backoff: u64) -> u64 {
+    (backoff * 2).min(MAX_BACKOFF_MS)
+}
+
+fn lock_path(path: &str) -> String {
+    let name = super::path::file_name(path).unwrap_or_default();
+    match super::path::parent(path) {
+        Some(dir) => format!("{}/.{name}.lock", dir.trim_end_matches('/')),
+        None => format!(".{name}.lock"),
+    }
+}
+
+fn holder_path(lock_path: &str) -> String {
+    format!("{lock_path}/holder.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_path() {
+        assert_eq!(
+            lock_path("/app:sys/pkg/index.json"),
+            "/app:sys/pkg/.index.json.lock"
+        );
+        assert_eq!(lock_path("/app:sys/pkg/dir/"), "/app:sys/pkg/.dir.lock");
+        assert_eq!(
+            holder_path(&lock_path("/app:sys/pkg/a")),
+            "/app:sys/pkg/.a.lock/holder.json"
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = MIN_BACKOFF_MS;
+        let mut waits = vec![];
+        for _ in 0..8 {
+            waits.push(backoff);
+            backoff = next_backoff(backoff);
+        }
+        assert_eq!(waits, vec![10, 20, 40, 80, 160, 320, 500, 500]);
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let holder = LockHolder {
+            holder: "worker:app:sys".to_string(),
+            acquired_ms: 1_000,
+        };
+        assert!(!is_stale(&holder, 1_500, 1_000));
+        assert!(!is_stale(&holder, 2_000, 1_000));
+        assert!(is_stale(&holder, 2_001, 1_000));
+        // clock went backwards: never stale
+        assert!(!is_stale(&holder, 500, 0));
+    }
+
+    #[test]
+    fn test_lock_fails_fast_unless_already_held() {
+        use crate::host::{Call, MockHost, Reply};
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::IOError(
+            "Permission denied (os error 13)".to_string(),
+        ))));
+        let err = lock("/app:sys/pkg/a", LockKind::Exclusive, 60_000).unwrap_err();
+        assert!(
+            matches!(&err, VfsClientError::Runtime(VfsError::IOError(e)) if e.starts_with("Permission denied")),
+            "{err:?}"
+        );
+        // no retries, and so no timers
+        let calls = host.take_calls();
+        assert_eq!(
+            calls
+                .iter()
+                .filter(|call| matches!(call, Call::SendAndAwaitResponse { .. }))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_lock_holder_falls_back_only_when_unrecorded() {
+        use crate::host::{MockHost, Reply};
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let lock_dir = || Reply::body(r#"{"Metadata":{"file_type":"Directory","len":0}}"#);
+
+        // a runtime that doesn't say when the lock was made: taken as just now
+        host.reply(lock_dir());
+        host.reply(not_found());
+        let before = wall_clock_ms();
+        let holder = lock_holder("/app:sys/pkg/a", None).unwrap().unwrap();
+        assert_eq!(holder.holder, "");
+        assert!(holder.acquired_ms >= before, "{holder:?}");
+
+        host.reply(modified_lock_dir(1_000));
+        host.reply(Reply::with_blob(
+            serde_json::to_vec(&VfsResponse::Read).unwrap(),
+            crate::LazyLoadBlob::new(None::<String>, vec![]),
+        ));
+        assert_eq!(
+            lock_holder("/app:sys/pkg/a", None).unwrap(),
+            Some(LockHolder {
+                holder: String::new(),
+                acquired_ms: 1_000,
+            })
+        );
+
+        host.reply(lock_dir());
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::NoReadCap)));
+        assert!(matches!(
+            lock_holder("/app:sys/pkg/a", None),
+            Err(VfsClientError::NoCapability { .. })
+        ));
+
+        host.reply(lock_dir());
+        host.reply(Reply::with_blob(
+            serde_json::to_vec(&VfsResponse::Read).unwrap(),
+            crate::LazyLoadBlob::new(None::<String>, b"{\"holder\":".to_vec()),
+        ));
+        assert!(matches!(
+            lock_holder("/app:sys/pkg/a", None),
+            Err(VfsClientError::Json { .. })
+        ));
+    }
+
+    fn not_found() -> crate::host::Reply {
+        crate::host::Reply::json(&VfsResponse::Err(VfsError::IOError(
+            "No such file or directory (os error 2)".to_string(),
+        )))
+    }
+
+    fn modified_lock_dir(modified: u64) -> crate::host::Reply {
+        crate::host::Reply::body(format!(
+            r#"{{"Metadata":{{"file_type":"Directory","len":0,"modified":{modified}}}}}"#
+        ))
+    }
+
+    /// The vfs actions sent, leaving out timers.
+    fn actions(host: &crate::host::MockHost) -> Vec<serde_json::Value> {
+        host.take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                crate::host::Call::SendAndAwaitResponse {
+                    target, request, ..
+                } if target.process.process() == "vfs" => {
+                    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                    Some(body["action"].clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lock_contention() {
+        use crate::host::{MockHost, Reply};
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let held = || {
+            Reply::json(&VfsResponse::Err(VfsError::IOError(
+                "File exists (os error 17)".to_string(),
+            )))
+        };
+
+        // held at first, then released: retried after a wait, then taken
+        host.reply(held());
+        host.reply(Reply::body("timer"));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        let guard = lock("/app:sys/pkg/a", LockKind::Exclusive, 60_000).unwrap();
+        guard.release().unwrap();
+        assert_eq!(
+            actions(&host),
+            [
+                "CreateDir",
+                "CreateDir",
+                "CreateFile",
+                "Write",
+                "RemoveDirAll"
+            ]
+        );
+
+        // held throughout
+        host.reply(held());
+        let err = lock("/app:sys/pkg/a", LockKind::Exclusive, 0).unwrap_err();
+        assert!(matches!(err, VfsClientError::LockTimeout { .. }), "{err:?}");
+        assert_eq!(actions(&host), ["CreateDir"]);
+    }
+
+    #[test]
+    fn test_steal_stale_lock() {
+        use crate::host::{MockHost, Reply};
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+
+        // recorded long ago: stolen
+        host.reply(modified_lock_dir(1_000));
+        host.reply(Reply::with_blob(
+            serde_json::to_vec(&VfsResponse::Read).unwrap(),
+            crate::LazyLoadBlob::new(
+                None::<String>,
+                br#"{"holder":"worker:app:sys","acquired_ms":1000}"#.to_vec(),
+            ),
+        ));
+        host.reply(Reply::json(&VfsResponse::Ok));
+        assert!(steal_if_older_than("/app:sys/pkg/a", 60_000, None).unwrap());
+        assert_eq!(actions(&host), ["Metadata", "Read", "RemoveDirAll"]);
+
+        // unrecorded, on a runtime that doesn't say when it was made: left alone
+        host.reply(Reply::body(
+            r#"{"Metadata":{"file_type":"Directory","len":0}}"#,
+        ));
+        host.reply(not_found());
+        assert!(!steal_if_older_than("/app:sys/pkg/a", 60_000, None).unwrap());
+        assert_eq!(actions(&host), ["Metadata", "Read"]);
+
+        // unrecorded, but made long ago: stolen
+        host.reply(modified_lock_dir(1_000));
+        host.reply(not_found());
+        host.reply(Reply::json(&VfsResponse::Ok));
+        assert!(steal_if_older_than("/app:sys/pkg/a", 60_000, None).unwrap());
+        assert_eq!(actions(&host), ["Metadata", "Read", "RemoveDirAll"]);
+
+        // not locked
+        host.reply(not_found());
+        assert!(!steal_if_older_than("/app:sys/pkg/a", 60_000, None).unwrap());
+    }
+}
@@ -0,0 +1,411 @@
+use super::{
+    create_file, metadata, open_dir, open_file, remove_file, rename, File, SeekFrom, VfsClientError,
+};
+use crate::timer::wall_clock_ms;
+
+/// Size of the reads used by [`Logger::tail()`] when scanning back from the end of a file.
+const TAIL_CHUNK_SIZE: u64 = 4096;
+
+/// Line-oriented log writer over vfs with size-based rotation.
+///
+/// Lines are buffered and appended to `{dir}/{base_name}` every `flush_lines` lines
+/// or `flush_interval_ms` milliseconds, whichever comes first (the interval is only
+/// checked when a line is logged), and when the `Logger` is dropped.
+/// Once the active file would grow past `max_bytes`, it is rotated to
+/// `{base_name}.1`, the previous `.1` to `.2`, and so on, keeping at most
+/// `max_files` rotated files.
+pub struct Logger {
+    pub dir: String,
+    pub base_name: String,
+    pub max_bytes: u64,
+    pub max_files: u32,
+    pub flush_lines: usize,
+    pub flush_interval_ms: u64,
+    pub timeout: u64,
+    buffer: Vec<String>,
+    active_len: u64,
+    rotated: u32,
+    last_flush_ms: u64,
+}
+
+impl Logger {
+    /// Opens (creating if needed) the log in dir, picking up any existing
+    /// active and rotated files.
+    pub fn new(
+        dir: &str,
+        base_name: &str,
+        max_bytes: u64,
+        max_files: u32,
+    ) -> Result<Logger, VfsClientError> {
+        let timeout = 5;
+        let dir = dir.trim_end_matches('/').to_string();
+        open_dir(&dir, true, Some(timeout))?;
+
+        let mut logger = Logger {
+            dir,
+            base_name: base_name.to_string(),
+            max_bytes,
+            max_files,
+            flush_lines: 100,
+            flush_interval_ms: 1000,
+            timeout,
+            buffer: Vec::new(),
+            active_len: 0,
+            rotated: 0,
+            last_flush_ms: wall_clock_ms(),
+        };
+        logger.active_len = open_file(logger.path(0), true, Some(timeout))?
+            .metadata()?
+            .len();
+        while logger.rotated < max_files && logger.exists(logger.rotated + 1)? {
+            logger.rotated += 1;
+        }
+        Ok(logger)
+    }
+
+    /// Set how many buffered lines trigger a flush.
+    pub fn flush_lines(mut self, lines: usize) -> Self {
+        self.flush_lines = lines;
+        self
+    }
+
+    /// Set how long lines may sit in the buffer before a flush.
+    pub fn flush_interval_ms(mut self, interval_ms: u64) -> Self {
+        self.flush_interval_ms = interval_ms;
+        self
+    }
+
+    /// Buffer a line, flushing if the buffer is full or old enough.
+    /// A trailing newline is added, so line should not contain one.
+    pub fn log(&mut self, line: &str) -> Result<(), VfsClientError> {
+        self.buffer.push(format!("{line}\n"));
+        if self.buffer.len() >= self.flush_lines
+            || wall_clock_ms().saturating_sub(self.last_flush_ms) >= self.flush_interval_ms
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write out all buffered lines, rotating as needed.
+    pub fn flush(&mut self) -> Result<(), VfsClientError> {
+        self.last_flush_ms = wall_clock_ms();
+        let lines = std::mem::take(&mut self.buffer);
+        for write in plan_writes(self.active_len, self.max_bytes, &lines) {
+            if write.rotate_first {
+                self.rotate()?;
+            }
+            File::new(self.path(0), self.timeout).append(write.bytes.as_bytes())?;
+            self.active_len += write.bytes.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Returns the last n lines logged, newest last, reading back through rotated
+    /// files as needed. Lines still in the buffer are included.
+    pub fn tail(&self, n: usize) -> Result<Vec<String>, VfsClientError> {
+        let mut lines: Vec<String> = self
+            .buffer
+            .iter()
+            .rev()
+            .take(n)
+            .map(|line| line.trim_end_matches('\n').to_string())
+            .collect();
+        lines.reverse();
+
+        for index in 0..=self.rotated {
+            if lines.len() >= n {
+                break;
+            }
+            let mut file = File::new(self.path(index), self.timeout);
+            let len = file.metadata()?.len();
+            let mut older = tail_from_end(len, n - lines.len(), |offset, buffer| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_at(buffer)
+            })?;
+            older.append(&mut lines);
+            lines = older;
+        }
+        Ok(lines)
+    }
+
+    /// Path of the active file (index 0) or of rotated file `{base_name}.{index}`.
+    fn path(&self, index: u32) -> String {
+        log_path(&self.dir, &self.base_name, index)
+    }
+
+    fn exists(&self, index: u32) -> Result<bool, VfsClientError> {
+        match metadata(&self.path(index), Some(self.timeout)) {
+            Ok(_) => Ok(true),
+            Err(VfsClientError::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn rotate(&mut self) -> Result<(), VfsClientError> {
+        let (renames, kept) = rotation_renames(self.rotated, self.max_files);
+        if self.max_files == 0 {
+            remove_file(&self.path(0), Some(self.timeout))?;
+        }
+        for (from, to) in renames {
+            rename(&self.path(from), &self.path(to), Some(self.timeout))?;
+        }
+        create_file(self.path(0), Some(self.timeout))?;
+        self.rotated = kept;
+        self.active_len = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+pub(crate) fn log_path(dir: &str, base_name: &str, index: u32) -> String {
+    if index == 0 {
+        format!("{dir}/{base_name}")
+    } else {
+        format!("{dir}/{base_name}.{index}")
+    }
+}
+
+/// Renames (by index, 0 being the active file) that shift every file one step
+/// older, oldest first so nothing is overwritten before it has moved. The oldest
+/// file falls off by being overwritten once there are `max_files` rotated files.
+/// Also returns how many rotated files there are afterwards.
+pub(crate) fn rotation_renames(rotated: u32, max_files: u32) -> (Vec<(u32, u32)>, u32) {
+    if max_files == 0 {
+        return (vec![], 0);
+    }
+    let kept = (rotated + 1).min(max_files);
+    let renames = (0..kept).rev().map(|index| (index, index + 1)).collect();
+    (renames, kept)
+}
+
+/// A single append to the active file, optionally preceded by a rotation.
+#[derive(Debug, PartialEq)]
+struct PlannedWrite {
+    rotate_first: bool,
+    bytes: String,
+}
+
+/// Group lines into appends so that no file grows past max_bytes, except when a
+/// single line is larger than max_bytes by itself.
+fn plan_writes(mut active_len: u64, max_bytes: u64, lines: &[String]) -> Vec<PlannedWrite> {
+    let mut writes: Vec<PlannedWrite> = vec![];
+    let mut current = PlannedWrite {
+        rotate_first: false,
+        bytes: String::new(),
+    };
+    for line in lines {
+        let len = line.len() as u64;
+        if active_len > 0 && active_len + len > max_bytes {
+            if !current.bytes.is_empty() {
+                writes.push(current);
+            }
+            current = PlannedWrite {
+                rotate_first: true,
+                bytes: String::new(),
+            };
+            active_len = 0;
+        }
+        current.bytes.push_str(line);
+        active_len += len;
+    }
+    if !current.bytes.is_empty() {
+        writes.push(current);
+    }
+    writes
+}
+
+/// Read the last n lines of a file of length len, reading backwards in chunks
+/// via `read_at(offset, buffer)` until enough newlines have been seen.
+fn tail_from_end<F>(len: u64, n: usize, mut read_at: F) -> Result<Vec<String>, VfsClientError>
+where
+    F: FnMut(u64, &mut [u8]) -> Result<usize, VfsClientError>,
+{
+    if n == 0 {
+        return Ok(vec![]);
+    }
+    let mut end = len;
+    let mut tail: Vec<u8> = vec![];
+    // a trailing newline terminates the last line rather than starting a new one
+    while end > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= n {
+        let start = end.saturating_sub(TAIL_CHUNK_SIZE);
+        let mut chunk = vec![0; (end - start) as usize];
+        let read = read_at(start, &mut chunk)?;
+        chunk.truncate(read);
+        chunk.append(&mut tail);
+        tail = chunk;
+        end = start;
+    }
+    let text = String::from_utf8_lossy(&tail);
+    let mut lines: Vec<String> = text
+        .strip_suffix('\n')
+        .unwrap_or(&text)
+        .split('\n')
+        .map(str::to_string)
+        .collect();
+    if tail.is_empty() {
+        lines.clear();
+    }
+    // the first line may have been cut off: drop it unless we read the whole file
+    if end > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    let skip = lines.len().saturating_sub(n);
+    Ok(lines.split_off(skip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::vfs::{FileMetadata, FileType, VfsRequest, VfsResponse};
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| format!("{l}\n")).collect()
+    }
+
+    #[test]
+    fn test_plan_writes_rotation_boundaries() {
+        // "aaaa\n" is 5 bytes: two fit exactly in 10
+        let writes = plan_writes(0, 10, &lines(&["aaaa", "bbbb", "cccc"]));
+        assert_eq!(
+            writes,
+            vec![
+                PlannedWrite {
+                    rotate_first: false,
+                    bytes: "aaaa\nbbbb\n".to_string()
+                },
+                PlannedWrite {
+                    rotate_first: true,
+                    bytes: "cccc\n".to_string()
+                },
+            ]
+        );
+
+        // already full: rotate before writing anything
+        let writes = plan_writes(10, 10, &lines(&["aaaa"]));
+        assert_eq!(writes.len(), 1);
+        assert!(writes[0].rotate_first);
+
+        // oversized line goes into an empty file whole, then rotates away
+        let writes = plan_writes(0, 4, &lines(&["toolong", "b"]));
+        assert_eq!(
+            writes,
+            vec![
+                PlannedWrite {
+                    rotate_first: false,
+                    bytes: "toolong\n".to_string()
+                },
+                PlannedWrite {
+                    rotate_first: true,
+                    bytes: "b\n".to_string()
+                },
+            ]
+        );
+
+        assert!(plan_writes(3, 10, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_rotation_renames() {
+        assert_eq!(rotation_renames(0, 3), (vec![(0, 1)], 1));
+        assert_eq!(rotation_renames(1, 3), (vec![(1, 2), (0, 1)], 2));
+        // at capacity: .3 is overwritten by .2
+        assert_eq!(rotation_renames(3, 3), (vec![(2, 3), (1, 2), (0, 1)], 3));
+        assert_eq!(rotation_renames(0, 0), (vec![], 0));
+        assert_eq!(log_path("/a:b/logs", "app.log", 0), "/a:b/logs/app.log");
+        assert_eq!(log_path("/a:b/logs", "app.log", 2), "/a:b/logs/app.log.2");
+    }
+
+    fn tail(contents: &str, n: usize) -> Vec<String> {
+        let bytes = contents.as_bytes();
+        tail_from_end(bytes.len() as u64, n, |offset, buffer| {
+            let offset = offset as usize;
+            let len = buffer.len().min(bytes.len() - offset);
+            buffer[..len].copy_from_slice(&bytes[offset..offset + len]);
+            Ok(len)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tail_from_end() {
+        assert_eq!(tail("a\nb\nc\n", 2), vec!["b", "c"]);
+        assert_eq!(tail("a\nb\nc", 2), vec!["b", "c"]);
+        assert_eq!(tail("a\nb\nc\n", 10), vec!["a", "b", "c"]);
+        assert_eq!(tail("a\nb\n", 0), Vec::<String>::new());
+        assert_eq!(tail("", 3), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_tail_from_end_across_chunks() {
+        let contents: String = (0..2000).map(|i| format!("line {i}\n")).collect();
+        assert!(contents.len() as u64 > 3 * TAIL_CHUNK_SIZE);
+        let expected: Vec<String> = (1000..2000).map(|i| format!("line {i}")).collect();
+        assert_eq!(tail(&contents, 1000), expected);
+        assert_eq!(tail(&contents, 5000).len(), 2000);
+    }
+
+    #[test]
+    fn test_tail_across_rotated_file() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let logger = Logger {
+            dir: "/app:sys/logs".to_string(),
+            base_name: "app.log".to_string(),
+            max_bytes: 6,
+            max_files: 3,
+            flush_lines: 100,
+            flush_interval_ms: u64::MAX,
+            timeout: 5,
+            buffer: lines(&["f"]),
+            active_len: 4,
+            rotated: 1,
+            last_flush_ms: 0,
+        };
+        // each file is read back from its end: its length, then a seek and a read
+        for contents in ["d\ne\n", "a\nb\nc\n"] {
+            let len = contents.len() as u64;
+            host.reply(Reply::json(&VfsResponse::Metadata(FileMetadata {
+                file_type: FileType::File,
+                len,
+                created: None,
+                modified: None,
+            })));
+            host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 0 }));
+            host.reply(Reply::with_blob(
+                serde_json::to_vec(&VfsResponse::Read).unwrap(),
+                crate::LazyLoadBlob::new(None::<String>, contents.as_bytes().to_vec()),
+            ));
+        }
+
+        assert_eq!(logger.tail(4).unwrap(), vec!["c", "d", "e", "f"]);
+        let paths: Vec<String> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, .. } => {
+                    let request: VfsRequest = serde_json::from_slice(&request.body).unwrap();
+                    Some(request.path)
+                }
+                _ => None,
+            })
+            .collect();
+        let (live, rotated) = ("/app:sys/logs/app.log", "/app:sys/logs/app.log.1");
+        assert_eq!(paths, [live, live, live, rotated, rotated, rotated]);
+
+        // lines are taken from the buffer and the live file first
+        assert_eq!(logger.tail(1).unwrap(), vec!["f"]);
+        assert!(host.take_calls().is_empty());
+
+        // dropped, it flushes the buffered line
+        host.reply(Reply::json(&VfsResponse::Ok));
+        drop(logger);
+    }
+}
@@ -2,11 +2,30 @@ use crate::Request;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub mod batch;
 pub mod directory;
+pub mod drive;
 pub mod file;
+pub mod file_like;
+pub mod follow;
+pub mod lock;
+pub mod logger;
+pub mod ndjson;
+pub mod path;
+#[cfg(feature = "zip")]
+pub mod zip;
 
+pub use batch::*;
 pub use directory::*;
+pub use drive::*;
 pub use file::*;
+pub use file_like::*;
+pub use follow::*;
+pub use lock::*;
+pub use logger::*;
+pub use ndjson::*;
+#[cfg(feature = "zip")]
+pub use zip::*;
 
 /// IPC body format for requests sent to vfs runtime module.
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,13 +36,15 @@ pub struct VfsRequest {
     pub action: VfsAction,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum VfsAction {
     CreateDrive,
     CreateDir,
     CreateDirAll,
     CreateFile,
-    OpenFile { create: bool },
+    OpenFile {
+        create: bool,
+    },
     CloseFile,
     Write,
     WriteAll,
@@ -32,22 +53,32 @@ pub enum VfsAction {
     Read,
     ReadDir,
     ReadToEnd,
-    ReadExact { length: u64 },
+    ReadExact {
+        length: u64,
+    },
     ReadToString,
     Seek(SeekFrom),
     RemoveFile,
     RemoveDir,
     RemoveDirAll,
-    Rename { new_path: String },
+    Rename {
+        new_path: String,
+    },
     Metadata,
     AddZip,
-    CopyFile { new_path: String },
+    CopyFile {
+        new_path: String,
+    },
     Len,
     SetLen(u64),
     Hash,
+    /// Several actions in one request, each on its own path; see [`Batch`].
+    Batch {
+        actions: Vec<BatchAction>,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum SeekFrom {
     Start(u64),
     End(i64),
@@ -66,6 +97,59 @@ pub enum FileType {
 pub struct FileMetadata {
     pub file_type: FileType,
     pub len: u64,
+    /// Creation time in milliseconds since the UNIX epoch, if the runtime reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// Last modification time in milliseconds since the UNIX epoch, if the runtime reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+}
+
+impl FileMetadata {
+    /// Length of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    /// Creation time in milliseconds since the UNIX epoch.
+    /// Older runtimes do not report this, in which case this is `None`.
+    pub fn created(&self) -> Option<u64> {
+        self.created
+    }
+
+    /// Last modification time in milliseconds since the UNIX epoch.
+    /// Older runtimes do not report this, in which case this is `None`.
+    pub fn modified(&self) -> Option<u64> {
+        self.modified
+    }
+
+    /// Length of the file formatted for display, e.g. `"1.5 KiB"`.
+    pub fn human_len(&self) -> String {
+        const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+        if self.len < 1024 {
+            return format!("{} B", self.len);
+        }
+        let mut size = self.len as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        format!("{size:.1} {}", UNITS[unit])
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -79,12 +163,16 @@ pub enum VfsResponse {
     Ok,
     Err(VfsError),
     Read,
-    SeekFrom { new_offset: u64 },
+    SeekFrom {
+        new_offset: u64,
+    },
     ReadDir(Vec<DirEntry>),
     ReadToString(String),
     Metadata(FileMetadata),
     Len(u64),
     Hash([u8; 32]),
+    /// The result of each action of a [`VfsAction::Batch`], in order.
+    Batch(Vec<Result<(), VfsError>>),
 }
 
 #[derive(Clone, Debug, Error, Serialize, Deserialize)]
@@ -110,52 +198,540 @@ pub enum VfsError {
     SendError(crate::SendErrorKind),
 }
 
+/// A [`VfsError`] classified into the cases callers usually need to tell apart,
+/// along with the path (and for timeouts, the action) of the failed request,
+/// or a failure of this library's own. The vfs helpers fail with one, and
+/// others can be built with [`VfsError::classify()`].
+#[derive(Clone, Debug, Error, Serialize, Deserialize)]
+pub enum VfsClientError {
+    #[error("vfs: nothing found at {path}")]
+    NotFound { path: String },
+    #[error("vfs: no capability for {path}")]
+    NoCapability { path: String },
+    #[error("vfs: {action} on {path} timed out")]
+    Timeout { path: String, action: String },
+    #[error("vfs: unexpected response: {got}")]
+    BadResponse { got: String },
+    #[error("vfs: request requires a blob")]
+    NoBlob,
+    #[error("vfs: {0}")]
+    Runtime(#[source] VfsError),
+    #[error("vfs: failed to (de)serialize JSON at {path}: {error}")]
+    Json { error: String, path: String },
+    #[error("vfs: directory not empty: {path}")]
+    NotEmpty { path: String },
+    #[error("vfs: timed out waiting for lock on {path}")]
+    LockTimeout { path: String },
+    #[error("vfs: deadline exceeded before sending")]
+    DeadlineExceeded,
+    #[error("vfs: {size} bytes to write are over the blob limit of {limit}, see crate::limits")]
+    TooLarge { size: u64, limit: u64 },
+    #[error("vfs: hash of {path} is {}, expected {}", hex(actual), hex(expected))]
+    HashMismatch {
+        path: String,
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    #[error("vfs: failed to send: {0}")]
+    Build(crate::BuildError),
+}
+
+/// A request that was never sent, as the [`VfsClientError`] the helpers fail
+/// with: a passed deadline is [`VfsClientError::DeadlineExceeded`], and a blob
+/// over its limit [`VfsClientError::TooLarge`].
+impl From<crate::BuildError> for VfsClientError {
+    fn from(error: crate::BuildError) -> Self {
+        match error {
+            crate::BuildError::DeadlineExceeded => VfsClientError::DeadlineExceeded,
+            crate::BuildError::TooLarge {
+                kind: crate::limits::PayloadKind::Blob,
+                size,
+                limit,
+            } => VfsClientError::TooLarge { size, limit },
+            error => VfsClientError::Build(error),
+        }
+    }
+}
+
+impl VfsError {
+    /// Classify this error from a request with the given path and action.
+    pub fn classify(self, path: &str, action: &VfsAction) -> VfsClientError {
+        match self {
+            VfsError::NoReadCap | VfsError::NoWriteCap => VfsClientError::NoCapability {
+                path: path.to_string(),
+            },
+            VfsError::NoBlob => VfsClientError::NoBlob,
+            VfsError::SendError(crate::SendErrorKind::Timeout) => timeout(path, action),
+            VfsError::IOError(ref e) if is_not_found(e) => VfsClientError::NotFound {
+                path: path.to_string(),
+            },
+            e => VfsClientError::Runtime(e),
+        }
+    }
+}
+
+/// A [`VfsClientError::Timeout`] of action on path.
+fn timeout(path: &str, action: &VfsAction) -> VfsClientError {
+    VfsClientError::Timeout {
+        path: path.to_string(),
+        // just the variant name, not its fields
+        action: format!("{:?}", action)
+            .split([' ', '('])
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// Send action on path and await the response, for the helpers failing with a
+/// [`VfsClientError`].
+pub(crate) fn call(
+    path: &str,
+    action: VfsAction,
+    timeout: u64,
+) -> Result<VfsResponse, VfsClientError> {
+    let sent = vfs_request(path, action.clone()).send_and_await_response(timeout);
+    classified(path, &action, sent)
+}
+
+/// The response to a request for action on path, or the error, classified,
+/// that it was not sent or answered, or answered with. A request whose
+/// deadline passed before it was sent timed out.
+pub(crate) fn classified(
+    path: &str,
+    action: &VfsAction,
+    sent: Result<Result<crate::Message, crate::SendError>, crate::BuildError>,
+) -> Result<VfsResponse, VfsClientError> {
+    let classify = |e: VfsError| e.classify(path, action);
+    let message = sent
+        .map_err(|e| not_sent(path, action, e))?
+        .map_err(|e| classify(VfsError::SendError(e.kind)))?;
+    match parse_response(message.body()).map_err(classify)? {
+        VfsResponse::Err(e) => Err(classify(e)),
+        response => Ok(response),
+    }
+}
+
+/// The error for a request for action on path that was never sent.
+pub(crate) fn not_sent(path: &str, action: &VfsAction, error: crate::BuildError) -> VfsClientError {
+    match error {
+        crate::BuildError::DeadlineExceeded => timeout(path, action),
+        error => error.into(),
+    }
+}
+
+/// A [`VfsClientError::BadResponse`] carrying response.
+pub(crate) fn bad_response(response: &VfsResponse) -> VfsClientError {
+    VfsClientError::BadResponse {
+        got: format!("{response:?}"),
+    }
+}
+
+pub(crate) fn is_not_found(io_error: &str) -> bool {
+    let io_error = io_error.to_lowercase();
+    io_error.contains("no such file") || io_error.contains("not found")
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn is_already_exists(io_error: &str) -> bool {
+    let io_error = io_error.to_lowercase();
+    io_error.contains("already exists") || io_error.contains("file exists")
+}
+
 pub fn vfs_request<T>(path: T, action: VfsAction) -> Request
 where
     T: Into<String>,
 {
-    Request::new().target(("our", "vfs", "distro", "sys")).body(
-        serde_json::to_vec(&VfsRequest {
-            path: path.into(),
-            action,
-        })
-        .expect("failed to serialize VfsRequest"),
-    )
+    Request::new()
+        .target(crate::SystemProcess::Vfs.address(crate::our_node()))
+        .body(
+            serde_json::to_vec(&VfsRequest {
+                path: path.into(),
+                action,
+            })
+            .expect("failed to serialize VfsRequest"),
+        )
 }
 
 /// Metadata of a path, returns file type and length.
-pub fn metadata(path: &str, timeout: Option<u64>) -> Result<FileMetadata, VfsError> {
-    let timeout = timeout.unwrap_or(5);
-
-    let message = vfs_request(path, VfsAction::Metadata)
-        .send_and_await_response(timeout)
-        .unwrap()
-        .map_err(|e| VfsError::SendError(e.kind))?;
-
-    match parse_response(message.body())? {
+pub fn metadata(path: &str, timeout: Option<u64>) -> Result<FileMetadata, VfsClientError> {
+    match call(path, VfsAction::Metadata, timeout.unwrap_or(5))? {
         VfsResponse::Metadata(metadata) => Ok(metadata),
-        VfsResponse::Err(e) => Err(e),
-        _ => Err(VfsError::ParseError {
-            error: "unexpected response".to_string(),
-            path: path.to_string(),
-        }),
+        response => Err(bad_response(&response)),
     }
 }
 
 /// Removes a path, if it's either a directory or a file.
-pub fn remove_path(path: &str, timeout: Option<u64>) -> Result<(), VfsError> {
+pub fn remove_path(path: &str, timeout: Option<u64>) -> Result<(), VfsClientError> {
     let meta = metadata(path, timeout)?;
 
     match meta.file_type {
         FileType::Directory => remove_dir(path, timeout),
         FileType::File => remove_file(path, timeout),
-        _ => Err(VfsError::ParseError {
+        _ => Err(VfsClientError::Runtime(VfsError::ParseError {
             error: "path is not a file or directory".to_string(),
             path: path.to_string(),
-        }),
+        })),
+    }
+}
+
+/// Renames (moves) a file or directory at path to new_path.
+/// If a file already exists at new_path, it will be replaced.
+pub fn rename(path: &str, new_path: &str, timeout: Option<u64>) -> Result<(), VfsClientError> {
+    let action = VfsAction::Rename {
+        new_path: new_path.to_string(),
+    };
+    match call(path, action, timeout.unwrap_or(5))? {
+        VfsResponse::Ok => Ok(()),
+        response => Err(bad_response(&response)),
+    }
+}
+
+/// Lists the names of the drives of package_id that this process holds a vfs capability for.
+/// Drives are only visible through their capabilities, so drives created by other
+/// processes in the package will not appear unless their caps were shared with us.
+/// Fails if one of our vfs capabilities has params that are not JSON.
+pub fn list_drives(package_id: &crate::PackageId) -> Result<Vec<String>, VfsClientError> {
+    drives_from_capabilities(package_id, &crate::our_capabilities())
+}
+
+/// Removes the drive at "/package_id/drive" and everything in it.
+/// If the drive is not empty, `force` must be set, otherwise returns [`VfsClientError::NotEmpty`].
+pub fn remove_drive(
+    package_id: &crate::PackageId,
+    drive: &str,
+    force: bool,
+    timeout: Option<u64>,
+) -> Result<(), VfsClientError> {
+    let timeout = timeout.unwrap_or(5);
+    let path = format!("/{}/{}", package_id, drive);
+
+    if !force {
+        let entries = open_dir(&path, false, Some(timeout))?.read()?;
+        check_removable(&path, &entries)?;
+    }
+
+    remove_dir_all(&path, Some(timeout))
+}
+
+/// Returns the total size in bytes of all files under path, which may be a drive,
+/// a directory, or a single file. Directories are walked recursively.
+pub fn drive_size(path: &str, timeout: Option<u64>) -> Result<u64, VfsClientError> {
+    let timeout = timeout.unwrap_or(5);
+    let meta = metadata(path, Some(timeout))?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    sum_sizes(
+        path,
+        &mut |dir| open_dir(dir, false, Some(timeout))?.read(),
+        &mut |file| metadata(file, Some(timeout)).map(|m| m.len()),
+    )
+}
+
+fn drives_from_capabilities(
+    package_id: &crate::PackageId,
+    capabilities: &[crate::Capability],
+) -> Result<Vec<String>, VfsClientError> {
+    let prefix = format!("/{}/", package_id);
+    let mut drives = vec![];
+    for cap in capabilities {
+        if cap.issuer().process != crate::SystemProcess::Vfs {
+            continue;
+        }
+        let params = cap.params_json().map_err(|e| {
+            VfsClientError::Runtime(VfsError::ParseError {
+                error: format!("capability params are not JSON: {e}"),
+                path: prefix.clone(),
+            })
+        })?;
+        let drive = params
+            .get("drive")
+            .and_then(|drive| drive.as_str())
+            .and_then(|drive| drive.strip_prefix(&prefix));
+        if let Some(drive) = drive.filter(|d| !d.is_empty() && !d.contains('/')) {
+            drives.push(drive.to_string());
+        }
+    }
+    drives.sort();
+    drives.dedup();
+    Ok(drives)
+}
+
+fn check_removable(path: &str, entries: &[DirEntry]) -> Result<(), VfsClientError> {
+    if entries.is_empty() {
+        Ok(())
+    } else {
+        Err(VfsClientError::NotEmpty {
+            path: path.to_string(),
+        })
     }
 }
 
+fn sum_sizes<R, L>(path: &str, read_dir: &mut R, len: &mut L) -> Result<u64, VfsClientError>
+where
+    R: FnMut(&str) -> Result<Vec<DirEntry>, VfsClientError>,
+    L: FnMut(&str) -> Result<u64, VfsClientError>,
+{
+    let mut total = 0;
+    for entry in read_dir(path)? {
+        total += match entry.file_type {
+            FileType::File => len(&entry.path)?,
+            FileType::Directory => sum_sizes(&entry.path, read_dir, len)?,
+            FileType::Symlink | FileType::Other => 0,
+        };
+    }
+    Ok(total)
+}
+
 pub fn parse_response(body: &[u8]) -> Result<VfsResponse, VfsError> {
     serde_json::from_slice::<VfsResponse>(body).map_err(|_| VfsError::MalformedRequest)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_response_without_timestamps() {
+        let body = br#"{"Metadata":{"file_type":"File","len":2048}}"#;
+        let VfsResponse::Metadata(metadata) = parse_response(body).unwrap() else {
+            panic!("expected metadata response");
+        };
+        assert!(metadata.is_file());
+        assert!(!metadata.is_dir());
+        assert_eq!(metadata.len(), 2048);
+        assert_eq!(metadata.created(), None);
+        assert_eq!(metadata.modified(), None);
+        assert_eq!(metadata.human_len(), "2.0 KiB");
+    }
+
+    #[test]
+    fn test_metadata_response_with_timestamps() {
+        let body = br#"{"Metadata":{"file_type":"Directory","len":0,"created":1700000000000,"modified":1700000001000}}"#;
+        let VfsResponse::Metadata(metadata) = parse_response(body).unwrap() else {
+            panic!("expected metadata response");
+        };
+        assert!(metadata.is_dir());
+        assert!(metadata.is_empty());
+        assert_eq!(metadata.created(), Some(1700000000000));
+        assert_eq!(metadata.modified(), Some(1700000001000));
+        assert_eq!(metadata.human_len(), "0 B");
+
+        let round_trip: FileMetadata =
+            serde_json::from_str(&serde_json::to_string(&metadata).unwrap()).unwrap();
+        assert_eq!(round_trip.modified(), metadata.modified());
+    }
+
+    #[test]
+    fn test_vfs_request_targets_our_node() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let request = vfs_request("/app:sys/pkg/a.txt", VfsAction::Read);
+        assert_eq!(
+            request.target,
+            Some(crate::Address::new("tester.os", ("vfs", "distro", "sys")))
+        );
+        assert_eq!(crate::our_node(), "tester.os");
+        assert_eq!(crate::our_process().to_string(), "tester:app:sys");
+    }
+
+    #[test]
+    fn test_drives_from_capabilities() {
+        let package_id: crate::PackageId = "app:sys".parse().unwrap();
+        let vfs = crate::Address::new("our", ("vfs", "distro", "sys"));
+        let other = crate::Address::new("our", ("kv", "distro", "sys"));
+        let caps = vec![
+            crate::Capability::new(vfs.clone(), r#"{"kind":"read","drive":"/app:sys/pkg"}"#),
+            crate::Capability::new(vfs.clone(), r#"{"kind":"write","drive":"/app:sys/pkg"}"#),
+            crate::Capability::new(vfs.clone(), r#"{"kind":"read","drive":"/app:sys/data"}"#),
+            crate::Capability::new(vfs.clone(), r#"{"kind":"read","drive":"/other:sys/x"}"#),
+            crate::Capability::new(other, r#"{"kind":"read","drive":"/app:sys/kv"}"#),
+            crate::Capability::new(vfs, r#"{"root":true}"#),
+        ];
+        assert_eq!(
+            drives_from_capabilities(&package_id, &caps).unwrap(),
+            vec!["data".to_string(), "pkg".to_string()]
+        );
+
+        let vfs = crate::Address::new("our", ("vfs", "distro", "sys"));
+        let other = crate::Address::new("our", ("kv", "distro", "sys"));
+        let malformed = vec![crate::Capability::new(vfs, "not json")];
+        assert!(matches!(
+            drives_from_capabilities(&package_id, &malformed),
+            Err(VfsClientError::Runtime(VfsError::ParseError { path, .. })) if path == "/app:sys/"
+        ));
+        // only vfs capabilities are read
+        let foreign = vec![crate::Capability::new(other, "not json")];
+        assert!(drives_from_capabilities(&package_id, &foreign)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_check_removable() {
+        assert!(check_removable("/app:sys/pkg", &[]).is_ok());
+        let entries = vec![DirEntry {
+            path: "/app:sys/pkg/a".to_string(),
+            file_type: FileType::File,
+        }];
+        assert!(matches!(
+            check_removable("/app:sys/pkg", &entries),
+            Err(VfsClientError::NotEmpty { path }) if path == "/app:sys/pkg"
+        ));
+    }
+
+    #[test]
+    fn test_sum_sizes_recurses() {
+        let entry = |path: &str, file_type| DirEntry {
+            path: path.to_string(),
+            file_type,
+        };
+        let mut read_dir = |dir: &str| -> Result<Vec<DirEntry>, VfsClientError> {
+            Ok(match dir {
+                "/d" => vec![
+                    entry("/d/a", FileType::File),
+                    entry("/d/sub", FileType::Directory),
+                    entry("/d/link", FileType::Symlink),
+                ],
+                "/d/sub" => vec![
+                    entry("/d/sub/b", FileType::File),
+                    entry("/d/sub/empty", FileType::Directory),
+                ],
+                _ => vec![],
+            })
+        };
+        let mut len = |file: &str| -> Result<u64, VfsClientError> {
+            Ok(match file {
+                "/d/a" => 10,
+                "/d/sub/b" => 32,
+                _ => panic!("unexpected file {file}"),
+            })
+        };
+        assert_eq!(sum_sizes("/d", &mut read_dir, &mut len).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_classify() {
+        let path = "/app:sys/pkg/a.txt";
+        let classify = |e: VfsError| e.classify(path, &VfsAction::ReadExact { length: 4 });
+
+        assert!(matches!(
+            classify(VfsError::NoReadCap),
+            VfsClientError::NoCapability { path: p } if p == path
+        ));
+        assert!(matches!(
+            classify(VfsError::NoWriteCap),
+            VfsClientError::NoCapability { .. }
+        ));
+        assert!(matches!(classify(VfsError::NoBlob), VfsClientError::NoBlob));
+        assert!(matches!(
+            classify(VfsError::SendError(crate::SendErrorKind::Timeout)),
+            VfsClientError::Timeout { path: p, action } if p == path && action == "ReadExact"
+        ));
+        assert!(matches!(
+            classify(VfsError::IOError(
+                "No such file or directory (os error 2)".to_string()
+            )),
+            VfsClientError::NotFound { path: p } if p == path
+        ));
+        for e in [
+            VfsError::AddCapFailed,
+            VfsError::MalformedRequest,
+            VfsError::UnzipError,
+            VfsError::IOError("permission denied".to_string()),
+            VfsError::SendError(crate::SendErrorKind::Offline),
+        ] {
+            assert!(matches!(classify(e), VfsClientError::Runtime(_)));
+        }
+
+        let err: anyhow::Error = classify(VfsError::NoBlob).into();
+        assert!(matches!(
+            err.downcast_ref::<VfsClientError>(),
+            Some(VfsClientError::NoBlob)
+        ));
+    }
+
+    #[test]
+    fn test_from_build_error() {
+        use crate::{limits::PayloadKind, BuildError};
+        let too_large = |kind| BuildError::TooLarge {
+            kind,
+            size: 20,
+            limit: 16,
+        };
+        assert!(matches!(
+            VfsClientError::from(too_large(PayloadKind::Blob)),
+            VfsClientError::TooLarge {
+                size: 20,
+                limit: 16
+            }
+        ));
+        assert!(matches!(
+            VfsClientError::from(too_large(PayloadKind::Body)),
+            VfsClientError::Build(BuildError::TooLarge { .. })
+        ));
+        assert!(matches!(
+            VfsClientError::from(BuildError::DeadlineExceeded),
+            VfsClientError::DeadlineExceeded
+        ));
+    }
+
+    #[test]
+    fn test_refused_send_is_an_error() {
+        fn refuse(_: &mut crate::hooks::RequestSnapshot) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("refused"))
+        }
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = crate::host::MockHost::new();
+        let _installed = host.install();
+        crate::hooks::on_send(refuse);
+
+        let refused =
+            |e: VfsClientError| matches!(e, VfsClientError::Build(crate::BuildError::Hook(_)));
+        assert!(refused(metadata("/app:sys/pkg/a.txt", None).unwrap_err()));
+        assert!(refused(open_dir("/app:sys/pkg", true, None).err().unwrap()));
+        assert!(refused(
+            File::new("/app:sys/pkg/a.txt", 5).read().unwrap_err()
+        ));
+        assert!(matches!(
+            crate::http::server::HttpServer::new(5).unbind_http_path("/"),
+            Err(crate::http::server::HttpServerError::Build(
+                crate::BuildError::Hook(_)
+            ))
+        ));
+        assert!(matches!(
+            crate::net::sign(b"message".to_vec()),
+            Err(crate::net::NetError::Build(crate::BuildError::Hook(_)))
+        ));
+        assert!(host.take_calls().is_empty());
+        crate::hooks::clear();
+    }
+
+    #[test]
+    fn test_helper_errors_downcast() {
+        use crate::host::{MockHost, Reply};
+
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let path = "/app:sys/pkg/a.txt";
+        host.reply(Reply::json(&VfsResponse::Err(VfsError::IOError(
+            "No such file or directory (os error 2)".to_string(),
+        ))));
+        host.reply(Reply::json(&VfsResponse::SeekFrom { new_offset: 3 }));
+
+        let err = anyhow::Error::from(File::new(path, 5).read().unwrap_err());
+        assert!(matches!(
+            err.downcast_ref::<VfsClientError>(),
+            Some(VfsClientError::NotFound { path: p }) if p == path
+        ));
+        let err = anyhow::Error::from(File::new(path, 5).read().unwrap_err());
+        assert!(matches!(
+            err.downcast_ref::<VfsClientError>(),
+            Some(VfsClientError::BadResponse { got }) if got.contains("SeekFrom")
+        ));
+    }
+}
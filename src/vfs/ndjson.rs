@@ -0,0 +1,344 @@
+use super::{open_file, write_atomic, File, SeekFrom, VfsClientError};
+use crate::timer::wall_clock_ms;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+/// Size of the reads [`NdjsonReader`] buffers lines from.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads into the buffer from the offset, returning how many bytes were read.
+type ReadAt = dyn FnMut(u64, &mut [u8]) -> Result<usize, VfsClientError>;
+
+/// A line of an NDJSON file that failed to parse, kept by an [`NdjsonReader`]
+/// that skips corrupt lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorruptLine {
+    /// 1-based, counting blank lines.
+    pub line: usize,
+    /// The line as read, lossily decoded and without its newline.
+    pub text: String,
+    pub error: String,
+}
+
+/// Reads the records of a newline-delimited JSON file one at a time, buffering
+/// the file in chunks rather than reading it whole, as a `BufReader` would.
+///
+/// Blank lines are ignored. A line that fails to parse is an error naming its
+/// line number, unless [`NdjsonReader::skip_corrupt()`] is set, in which case it
+/// is passed over and kept for [`NdjsonReader::corrupt()`]. A last line without
+/// a newline, e.g. from an interrupted append, is read like any other.
+pub struct NdjsonReader<T> {
+    pub path: String,
+    read_at: Box<ReadAt>,
+    offset: u64,
+    buffer: Vec<u8>,
+    eof: bool,
+    line: usize,
+    skip_corrupt: bool,
+    corrupt: Vec<CorruptLine>,
+    _record: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> NdjsonReader<T> {
+    /// Open the file at path for reading from the start.
+    pub fn open(path: &str, timeout: Option<u64>) -> Result<Self, VfsClientError> {
+        let mut file = open_file(path, false, timeout)?;
+        Ok(Self::from_read_at(path, move |offset, buffer| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_at(buffer)
+        }))
+    }
+
+    /// Read through `read_at(offset, buffer)`, which returns 0 at the end.
+    fn from_read_at<F>(path: &str, read_at: F) -> Self
+    where
+        F: FnMut(u64, &mut [u8]) -> Result<usize, VfsClientError> + 'static,
+    {
+        NdjsonReader {
+            path: path.to_string(),
+            read_at: Box::new(read_at),
+            offset: 0,
+            buffer: vec![],
+            eof: false,
+            line: 0,
+            skip_corrupt: false,
+            corrupt: vec![],
+            _record: PhantomData,
+        }
+    }
+
+    /// Set whether lines that fail to parse are skipped and collected, rather
+    /// than returned as errors.
+    pub fn skip_corrupt(mut self, skip: bool) -> Self {
+        self.skip_corrupt = skip;
+        self
+    }
+
+    /// The lines skipped so far for failing to parse.
+    pub fn corrupt(&self) -> &[CorruptLine] {
+        &self.corrupt
+    }
+
+    /// The number of the last line read, 1-based.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The next non-blank line and its number, without its newline.
+    fn next_line(&mut self) -> Result<Option<(usize, Vec<u8>)>, VfsClientError> {
+        loop {
+            let line = match self.buffer.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+                    line.pop();
+                    line
+                }
+                None if self.eof => {
+                    if self.buffer.is_empty() {
+                        return Ok(None);
+                    }
+                    std::mem::take(&mut self.buffer)
+                }
+                None => {
+                    self.fill()?;
+                    continue;
+                }
+            };
+            self.line += 1;
+            if !line.trim_ascii().is_empty() {
+                return Ok(Some((self.line, line)));
+            }
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), VfsClientError> {
+        let mut chunk = vec![0; READ_CHUNK_SIZE];
+        let read = (self.read_at)(self.offset, &mut chunk)?;
+        if read == 0 {
+            self.eof = true;
+        }
+        self.offset += read as u64;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+
+    /// The next record and its raw line, for [`ndjson_compact()`] to keep as is.
+    fn next_record(&mut self) -> Option<anyhow::Result<(T, Vec<u8>)>> {
+        loop {
+            let (line, bytes) = match self.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match serde_json::from_slice(&bytes) {
+                Ok(record) => return Some(Ok((record, bytes))),
+                Err(e) if self.skip_corrupt => self.corrupt.push(CorruptLine {
+                    line,
+                    text: String::from_utf8_lossy(&bytes).into_owned(),
+                    error: e.to_string(),
+                }),
+                Err(e) => {
+                    return Some(Err(anyhow::anyhow!(
+                        "{}: line {line}: failed to parse record: {e}",
+                        self.path
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for NdjsonReader<T> {
+    type Item = anyhow::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record()
+            .map(|record| record.map(|(record, _)| record))
+    }
+}
+
+/// Appends records to a newline-delimited JSON file, one per line.
+///
+/// Records are buffered and appended every `flush_lines` records or
+/// `flush_interval_ms` milliseconds, whichever comes first (the interval is
+/// only checked when a record is appended), and when the writer is dropped.
+pub struct NdjsonWriter<T> {
+    pub path: String,
+    pub flush_lines: usize,
+    pub flush_interval_ms: u64,
+    pub timeout: u64,
+    buffer: Vec<u8>,
+    buffered: usize,
+    last_flush_ms: u64,
+    _record: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize> NdjsonWriter<T> {
+    /// Open the file at path for appending, creating it if needed.
+    pub fn open(path: &str, timeout: Option<u64>) -> Result<Self, VfsClientError> {
+        let timeout = timeout.unwrap_or(5);
+        open_file(path, true, Some(timeout))?;
+        Ok(NdjsonWriter {
+            path: path.to_string(),
+            flush_lines: 100,
+            flush_interval_ms: 1000,
+            timeout,
+            buffer: vec![],
+            buffered: 0,
+            last_flush_ms: wall_clock_ms(),
+            _record: PhantomData,
+        })
+    }
+
+    /// Set how many buffered records trigger a flush.
+    pub fn flush_lines(mut self, lines: usize) -> Self {
+        self.flush_lines = lines;
+        self
+    }
+
+    /// Set how long records may sit in the buffer before a flush.
+    pub fn flush_interval_ms(mut self, interval_ms: u64) -> Self {
+        self.flush_interval_ms = interval_ms;
+        self
+    }
+
+    /// Buffer record as a line, flushing if the buffer is full or old enough.
+    pub fn append(&mut self, record: &T) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.buffer, record)?;
+        self.buffer.push(b'\n');
+        self.buffered += 1;
+        if self.buffered >= self.flush_lines
+            || wall_clock_ms().saturating_sub(self.last_flush_ms) >= self.flush_interval_ms
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Append all buffered records to the file.
+    pub fn flush(&mut self) -> Result<(), VfsClientError> {
+        self.last_flush_ms = wall_clock_ms();
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        File::new(&self.path, self.timeout).append(&self.buffer)?;
+        self.buffer.clear();
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+impl<T> Drop for NdjsonWriter<T> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            let _ = File::new(&self.path, self.timeout).append(&self.buffer);
+        }
+    }
+}
+
+/// Rewrite the NDJSON file at path keeping only the records retain returns
+/// true for, replacing it atomically with [`write_atomic()`], so that readers
+/// see either the old file or the new one. Kept lines are copied as they were,
+/// so fields `T` does not know of survive. Returns how many records were
+/// dropped.
+///
+/// The kept records are held in memory until the rewrite. A corrupt line fails
+/// the compaction rather than being silently dropped; records appended while
+/// compacting are lost.
+pub fn ndjson_compact<T: DeserializeOwned>(
+    path: &str,
+    retain: impl Fn(&T) -> bool,
+) -> anyhow::Result<usize> {
+    let timeout = Some(5);
+    let (kept, dropped) = compact(NdjsonReader::open(path, timeout)?, retain)?;
+    write_atomic(path, &kept, timeout)?;
+    Ok(dropped)
+}
+
+/// The lines of the records to keep, and how many were dropped.
+fn compact<T: DeserializeOwned>(
+    mut reader: NdjsonReader<T>,
+    retain: impl Fn(&T) -> bool,
+) -> anyhow::Result<(Vec<u8>, usize)> {
+    let mut kept = vec![];
+    let mut dropped = 0;
+    while let Some(record) = reader.next_record() {
+        let (record, line) = record?;
+        if retain(&record) {
+            kept.extend_from_slice(&line);
+            kept.push(b'\n');
+        } else {
+            dropped += 1;
+        }
+    }
+    Ok((kept, dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Event {
+        id: u32,
+    }
+
+    /// A reader over bytes, handed out a few at a time so that lines span reads.
+    fn reader(bytes: &[u8]) -> NdjsonReader<Event> {
+        let bytes = bytes.to_vec();
+        NdjsonReader::from_read_at("events.ndjson", move |offset, buffer| {
+            let start = (offset as usize).min(bytes.len());
+            let end = (start + 3).min(bytes.len()).min(start + buffer.len());
+            buffer[..end - start].copy_from_slice(&bytes[start..end]);
+            Ok(end - start)
+        })
+    }
+
+    #[test]
+    fn test_skip_corrupt_lines() {
+        let input = b"{\"id\":1}\nnot json\n\n{\"id\":2}\n{\"id\":";
+        let mut events = reader(input).skip_corrupt(true);
+        let read: Vec<Event> = events.by_ref().map(Result::unwrap).collect();
+        assert_eq!(read, vec![Event { id: 1 }, Event { id: 2 }]);
+        let corrupt: Vec<(usize, &str)> = events
+            .corrupt()
+            .iter()
+            .map(|line| (line.line, line.text.as_str()))
+            .collect();
+        assert_eq!(corrupt, vec![(2, "not json"), (5, "{\"id\":")]);
+        assert_eq!(events.line(), 5);
+    }
+
+    #[test]
+    fn test_errors_report_line_numbers() {
+        let input = b"{\"id\":1}\n\n  \n{\"id\":2}\n{\"id\":\"three\"}\n{\"id\":4}";
+        let mut events = reader(input);
+        assert_eq!(events.next().unwrap().unwrap(), Event { id: 1 });
+        assert_eq!(events.next().unwrap().unwrap(), Event { id: 2 });
+        assert_eq!(events.line(), 4);
+        let error = events.next().unwrap().unwrap_err().to_string();
+        assert!(
+            error.starts_with("events.ndjson: line 5: failed to parse record"),
+            "{error}"
+        );
+        // and reading carries on after it
+        assert_eq!(events.next().unwrap().unwrap(), Event { id: 4 });
+        assert!(events.next().is_none());
+        assert!(events.corrupt().is_empty());
+    }
+
+    #[test]
+    fn test_compact_keeps_retained_lines_verbatim() {
+        let input = b"{\"id\":1,\"extra\":true}\n{\"id\":2}\n\n{\"id\":3}\n{\"id\":4}";
+        let (kept, dropped) = compact(reader(input), |event: &Event| event.id % 2 == 1).unwrap();
+        assert_eq!(dropped, 2);
+        assert_eq!(kept, b"{\"id\":1,\"extra\":true}\n{\"id\":3}\n");
+
+        let (kept, dropped) = compact(reader(b""), |_: &Event| true).unwrap();
+        assert_eq!((kept, dropped), (vec![], 0));
+
+        // corrupt lines fail the compaction rather than vanish
+        assert!(compact(reader(b"{\"id\":1}\nnope\n"), |_: &Event| true).is_err());
+    }
+}
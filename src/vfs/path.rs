@@ -0,0 +1,254 @@
+//! Functions on vfs paths as strings, which do not ask vfs anything.
+//!
+//! A vfs path is `/{package}:{publisher}/{drive}/rest`: the first two segments
+//! name the drive, and [`normalize()`] and [`join()`] refuse to climb above
+//! it. A path without the leading `/` is relative, and may not climb above
+//! where it starts.
+
+use super::VfsError;
+use crate::PackageId;
+
+/// How many segments of an absolute path name its drive.
+const DRIVE_DEPTH: usize = 2;
+
+/// Collapse repeated `/`s, drop `.` segments and a trailing `/`, and resolve
+/// `..` segments. Fails if a `..` would climb above the drive of an absolute
+/// path, or above the start of a relative one.
+///
+/// ```
+/// use hyperware_process_lib::vfs::path::normalize;
+///
+/// assert_eq!(normalize("/app:pub.os/data//a/./b/../c/").unwrap(), "/app:pub.os/data/a/c");
+/// assert!(normalize("/app:pub.os/data/../other").is_err());
+/// ```
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    let absolute = path.starts_with('/');
+    let floor = if absolute { DRIVE_DEPTH } else { 0 };
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if segments.len() > floor => {
+                segments.pop();
+            }
+            ".." => {
+                return Err(VfsError::ParseError {
+                    error: match absolute {
+                        true => "`..` climbs out of the drive".to_string(),
+                        false => "`..` climbs above the start of the path".to_string(),
+                    },
+                    path: path.to_string(),
+                })
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let joined = segments.join("/");
+    Ok(match absolute {
+        true => format!("/{joined}"),
+        false => joined,
+    })
+}
+
+/// The path without its last segment, or `None` if it has none to drop. The
+/// parent of `/a` is `/`. Trailing `/`s are ignored, and nothing is normalized.
+pub fn parent(path: &str) -> Option<&str> {
+    let (parent, _) = path.trim_end_matches('/').rsplit_once('/')?;
+    match parent.trim_end_matches('/') {
+        "" if path.starts_with('/') => Some("/"),
+        "" => None,
+        parent => Some(parent),
+    }
+}
+
+/// The last segment of the path, or `None` if it is empty, `.` or `..`.
+/// Trailing `/`s are ignored.
+pub fn file_name(path: &str) -> Option<&str> {
+    let path = path.trim_end_matches('/');
+    let name = path.rsplit_once('/').map_or(path, |(_, name)| name);
+    match name {
+        "" | "." | ".." => None,
+        name => Some(name),
+    }
+}
+
+/// What follows the last `.` of [`file_name()`], or `None` if it has no `.`
+/// but a leading one, as with `.gitignore`. `a.tar.gz` has extension `gz`.
+pub fn extension(path: &str) -> Option<&str> {
+    let name = file_name(path)?;
+    match name.rsplit_once('.')? {
+        ("", _) => None,
+        (_, extension) => Some(extension),
+    }
+}
+
+/// The path of rel within base, [`normalize()`]d. rel is taken as relative
+/// even if it starts with `/`, so the result is always within base's drive,
+/// but unlike [`super::DrivePath::join()`] it may climb out of base with `..`.
+pub fn join(base: &str, rel: &str) -> Result<String, VfsError> {
+    match base {
+        "" => normalize(rel.trim_start_matches('/')),
+        base => normalize(&format!("{base}/{rel}")),
+    }
+}
+
+/// The package, drive and rest of an absolute path, with the rest, possibly
+/// empty, not starting with `/`. `None` if the path does not start with `/`, a
+/// valid [`PackageId`] and a drive.
+///
+/// ```
+/// use hyperware_process_lib::{vfs::path::split_drive, PackageId};
+///
+/// let (package_id, drive, rest) = split_drive("/app:pub.os/data/notes/today.md").unwrap();
+/// assert_eq!(package_id, PackageId::new("app", "pub.os"));
+/// assert_eq!((drive, rest), ("data", "notes/today.md"));
+/// ```
+pub fn split_drive(path: &str) -> Option<(PackageId, &str, &str)> {
+    let mut parts = path.strip_prefix('/')?.splitn(3, '/');
+    let package_id = parts.next()?.parse().ok()?;
+    let drive = parts.next().filter(|drive| !drive.is_empty())?;
+    Some((package_id, drive, parts.next().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("/app:pub.os/data/a/b", Some("/app:pub.os/data/a/b")),
+            ("/app:pub.os/data/a/b/", Some("/app:pub.os/data/a/b")),
+            ("//app:pub.os//data///a", Some("/app:pub.os/data/a")),
+            ("/app:pub.os/data/./a/.", Some("/app:pub.os/data/a")),
+            ("/app:pub.os/data/a/../b", Some("/app:pub.os/data/b")),
+            ("/app:pub.os/data/a/b/../../c", Some("/app:pub.os/data/c")),
+            ("/app:pub.os/data/a/..", Some("/app:pub.os/data")),
+            ("/app:pub.os/data", Some("/app:pub.os/data")),
+            ("/app:pub.os", Some("/app:pub.os")),
+            ("/", Some("/")),
+            ("//", Some("/")),
+            ("/app:pub.os/data/..", None),
+            ("/app:pub.os/data/../other", None),
+            ("/app:pub.os/data/a/../../other", None),
+            ("/app:pub.os/..", None),
+            ("/..", None),
+            ("a/b", Some("a/b")),
+            ("a//b/", Some("a/b")),
+            ("./a", Some("a")),
+            ("a/../b", Some("b")),
+            ("a/..", Some("")),
+            ("", Some("")),
+            (".", Some("")),
+            ("..", None),
+            ("a/../..", None),
+            ("../a", None),
+            // names with dots are just names
+            ("/app:pub.os/data/...", Some("/app:pub.os/data/...")),
+            ("a/.hidden/..b", Some("a/.hidden/..b")),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize(path).ok().as_deref(), *expected, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn test_parent() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("/app:pub.os/data/a/b.txt", Some("/app:pub.os/data/a")),
+            ("/app:pub.os/data/a/", Some("/app:pub.os/data")),
+            ("/app:pub.os/data//a", Some("/app:pub.os/data")),
+            ("/app:pub.os/data", Some("/app:pub.os")),
+            ("/app:pub.os", Some("/")),
+            ("/", None),
+            ("", None),
+            ("a/b", Some("a")),
+            ("a", None),
+            ("a/", None),
+            // not normalized
+            ("a/b/..", Some("a/b")),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(parent(path), *expected, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn test_file_name_and_extension() {
+        let cases: &[(&str, Option<&str>, Option<&str>)] = &[
+            ("/app:pub.os/data/notes.txt", Some("notes.txt"), Some("txt")),
+            ("/app:pub.os/data/dir/", Some("dir"), None),
+            ("/app:pub.os/data/a.tar.gz", Some("a.tar.gz"), Some("gz")),
+            ("/app:pub.os/data/.gitignore", Some(".gitignore"), None),
+            (
+                "/app:pub.os/data/.config.json",
+                Some(".config.json"),
+                Some("json"),
+            ),
+            ("/app:pub.os/data/trailing.", Some("trailing."), Some("")),
+            ("/app:pub.os/data/README", Some("README"), None),
+            ("/app:pub.os.d/data/README", Some("README"), None),
+            ("notes.md", Some("notes.md"), Some("md")),
+            ("a/..", None, None),
+            ("a/.", None, None),
+            ("/", None, None),
+            ("", None, None),
+        ];
+        for (path, name, ext) in cases {
+            assert_eq!(file_name(path), *name, "{path:?}");
+            assert_eq!(extension(path), *ext, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn test_join() {
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("/app:pub.os/data", "a/b", Some("/app:pub.os/data/a/b")),
+            ("/app:pub.os/data/", "/a", Some("/app:pub.os/data/a")),
+            ("/app:pub.os/data", "", Some("/app:pub.os/data")),
+            ("/app:pub.os/data/a", "../b", Some("/app:pub.os/data/b")),
+            ("/app:pub.os/data", "../other", None),
+            ("/app:pub.os/data", "a/../../other", None),
+            ("/app:pub.os/data", "/../other", None),
+            ("dir", "a", Some("dir/a")),
+            ("dir/sub", "../a", Some("dir/a")),
+            ("dir", "../../a", None),
+            ("", "a/b/", Some("a/b")),
+            ("", "/a", Some("a")),
+        ];
+        for (base, rel, expected) in cases {
+            assert_eq!(
+                join(base, rel).ok().as_deref(),
+                *expected,
+                "{base:?} + {rel:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_drive() {
+        // the drive and rest of each path that splits, all in app:pub.os
+        let cases: &[(&str, Option<(&str, &str)>)] = &[
+            ("/app:pub.os/data/a/b.txt", Some(("data", "a/b.txt"))),
+            ("/app:pub.os/data/a/", Some(("data", "a/"))),
+            ("/app:pub.os/data/", Some(("data", ""))),
+            ("/app:pub.os/data", Some(("data", ""))),
+            ("/app:pub.os/", None),
+            ("/app:pub.os", None),
+            ("app:pub.os/data", None),
+            ("//app:pub.os/data", None),
+            ("/app/data", None),
+            ("/App:pub.os/data", None),
+            ("/", None),
+            ("", None),
+        ];
+        let app = PackageId::new("app", "pub.os");
+        for (path, expected) in cases {
+            let split = split_drive(path).map(|(package_id, drive, rest)| {
+                assert_eq!(package_id, app, "{path:?}");
+                (drive, rest)
+            });
+            assert_eq!(split, *expected, "{path:?}");
+        }
+    }
+}
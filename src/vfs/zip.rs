@@ -0,0 +1,982 @@
+use super::file_like::read_chunk;
+use super::{
+    create_file, open_dir, open_file, path, DirEntry, FileLike, FileType, SeekFrom, VfsClientError,
+    VfsError,
+};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use std::collections::BTreeSet;
+
+/// Default limit on the uncompressed size of a single entry in [`extract_zip()`].
+pub const DEFAULT_MAX_ZIP_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Size of the reads and writes entries are extracted and archived in.
+const ZIP_CHUNK_SIZE: usize = 1024 * 1024;
+
+const LOCAL_HEADER_SIG: u32 = 0x04034b50;
+const LOCAL_HEADER_LEN: u64 = 30;
+/// Offset of the CRC within a local header.
+const LOCAL_CRC_OFFSET: u64 = 14;
+const CENTRAL_HEADER_SIG: u32 = 0x02014b50;
+const CENTRAL_HEADER_LEN: usize = 46;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x06054b50;
+const END_OF_CENTRAL_DIR_LEN: usize = 22;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+/// 1980-01-01 00:00, the earliest time a zip entry can carry.
+const DOS_DATE: u16 = 0x0021;
+/// Bit 0: the entry is encrypted.
+const ENCRYPTED_FLAG: u16 = 0x0001;
+/// Bit 11: entry names are UTF-8.
+const UTF8_FLAG: u16 = 0x0800;
+
+/// An entry listed in the central directory of a zip archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    /// Path of the entry within the archive, always relative and free of `..`.
+    /// Directory entries end in `/`.
+    pub name: String,
+    /// Uncompressed size in bytes.
+    pub size: u64,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// Extracts the zip archive at archive_path into dest_dir, creating it (and any
+/// directories within the archive) if needed. The archive is read, and each
+/// entry inflated and written, a chunk at a time.
+/// Returns the paths of every file written.
+///
+/// Entries larger than [`DEFAULT_MAX_ZIP_ENTRY_SIZE`] are rejected; use
+/// [`extract_zip_with_limit()`] to choose a different limit.
+pub fn extract_zip(
+    archive_path: &str,
+    dest_dir: &str,
+    timeout: Option<u64>,
+) -> Result<Vec<String>, VfsClientError> {
+    extract_zip_with_limit(archive_path, dest_dir, DEFAULT_MAX_ZIP_ENTRY_SIZE, timeout)
+}
+
+/// Extracts the zip archive at archive_path into dest_dir, rejecting the archive
+/// before anything is written if any entry claims to be larger than
+/// max_entry_size bytes or would be written outside of dest_dir.
+/// Returns the paths of every file written.
+///
+/// Each entry is checked against the size and CRC its central directory record
+/// claims as it is written, so one that turns out larger, or corrupt, fails the
+/// extraction part way, with the entries before it already written.
+pub fn extract_zip_with_limit(
+    archive_path: &str,
+    dest_dir: &str,
+    max_entry_size: u64,
+    timeout: Option<u64>,
+) -> Result<Vec<String>, VfsClientError> {
+    let mut archive = open_file(archive_path, false, timeout)?;
+    extract(
+        &mut archive,
+        archive_path,
+        dest_dir,
+        max_entry_size,
+        timeout,
+    )
+}
+
+/// Like [`extract_zip_with_limit()`], reading the archive from any [`FileLike`],
+/// e.g. a [`super::MemFile`] received in a blob.
+pub fn extract_zip_from<F: FileLike + ?Sized>(
+    archive: &mut F,
+    dest_dir: &str,
+    max_entry_size: u64,
+    timeout: Option<u64>,
+) -> Result<Vec<String>, VfsClientError> {
+    extract(archive, "zip archive", dest_dir, max_entry_size, timeout)
+}
+
+/// Extracts archive, naming it archive_path in errors.
+fn extract<F: FileLike + ?Sized>(
+    archive: &mut F,
+    archive_path: &str,
+    dest_dir: &str,
+    max_entry_size: u64,
+    timeout: Option<u64>,
+) -> Result<Vec<String>, VfsClientError> {
+    let timeout = timeout.unwrap_or(5);
+    let central_dir = read_central_dir(archive, archive_path)?;
+    let entries = central_dir.records.iter().map(|record| &record.entry);
+
+    if let Some(entry) = entries.clone().find(|e| e.size > max_entry_size) {
+        let error = format!(
+            "zip entry {} is {} bytes, over the limit of {} bytes",
+            entry.name, entry.size, max_entry_size
+        );
+        return Err(parse_error(error, archive_path));
+    }
+
+    let dest_dir = path::normalize(dest_dir).map_err(VfsClientError::Runtime)?;
+    let join = |name: &str| path::join(&dest_dir, name).map_err(VfsClientError::Runtime);
+    open_dir(&dest_dir, true, Some(timeout))?;
+    for dir in zip_dirs(entries) {
+        open_dir(join(&dir)?, true, Some(timeout))?;
+    }
+
+    let mut written = vec![];
+    for record in central_dir.records.iter().filter(|r| !r.entry.is_dir()) {
+        let path = join(&record.entry.name)?;
+        let mut file = create_file(&path, Some(timeout))?;
+        extract_entry(archive, archive_path, &central_dir, record, &mut file)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Lists the entries of the zip archive in archive from its central directory,
+/// without reading their data. Fails if any entry name is absolute or contains
+/// `..`, or if an entry is one [`extract_zip()`] can not extract.
+pub fn read_zip_entries<F: FileLike + ?Sized>(
+    archive: &mut F,
+) -> Result<Vec<ZipEntry>, VfsClientError> {
+    let central_dir = read_central_dir(archive, "zip archive")?;
+    Ok(central_dir.records.into_iter().map(|r| r.entry).collect())
+}
+
+/// Walks src_dir and writes every file in it to a new zip archive at archive_path.
+/// Entries are stored uncompressed, with names relative to src_dir, and copied
+/// into the archive a chunk at a time.
+pub fn create_zip(
+    src_dir: &str,
+    archive_path: &str,
+    timeout: Option<u64>,
+) -> Result<(), VfsClientError> {
+    let mut archive = open_file(archive_path, true, timeout)?;
+    create(src_dir, &mut archive, archive_path, timeout)
+}
+
+/// Like [`create_zip()`], writing the archive to any [`FileLike`], replacing
+/// what it held, e.g. to a [`super::MemFile`] to send it in a blob.
+pub fn create_zip_into<F: FileLike + ?Sized>(
+    src_dir: &str,
+    archive: &mut F,
+    timeout: Option<u64>,
+) -> Result<(), VfsClientError> {
+    create(src_dir, archive, "zip archive", timeout)
+}
+
+/// Archives src_dir into archive, naming it archive_path in errors.
+fn create<F: FileLike + ?Sized>(
+    src_dir: &str,
+    archive: &mut F,
+    archive_path: &str,
+    timeout: Option<u64>,
+) -> Result<(), VfsClientError> {
+    let timeout = timeout.unwrap_or(5);
+    let src_dir = path::normalize(src_dir).map_err(VfsClientError::Runtime)?;
+    let mut writer = ZipWriter::new(archive, archive_path)?;
+    add_dir_to_zip(&mut writer, &src_dir, &src_dir, timeout)?;
+    writer.finish()
+}
+
+fn add_dir_to_zip<F: FileLike + ?Sized>(
+    writer: &mut ZipWriter<F>,
+    root: &str,
+    dir: &str,
+    timeout: u64,
+) -> Result<(), VfsClientError> {
+    let mut entries: Vec<DirEntry> = open_dir(dir, false, Some(timeout))?.read()?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    for entry in entries {
+        // the runtime reports entry paths without the leading slash
+        let path = format!("/{}", entry.path.trim_start_matches('/'));
+        let name = path
+            .strip_prefix(root)
+            .map(|name| name.trim_start_matches('/'))
+            .ok_or_else(|| parse_error(format!("directory entry not under {}", root), &path))?;
+        match entry.file_type {
+            FileType::File => {
+                let mut file = open_file(&path, false, Some(timeout))?;
+                writer.add_file(name, &mut file)?;
+            }
+            FileType::Directory => {
+                writer.add_dir(name)?;
+                add_dir_to_zip(writer, root, &path, timeout)?;
+            }
+            FileType::Symlink | FileType::Other => {}
+        }
+    }
+    Ok(())
+}
+
+fn parse_error(error: String, path: &str) -> VfsClientError {
+    VfsClientError::Runtime(VfsError::ParseError {
+        error,
+        path: path.to_string(),
+    })
+}
+
+/// Every directory that must exist to extract entries, parents first.
+fn zip_dirs<'a>(entries: impl IntoIterator<Item = &'a ZipEntry>) -> BTreeSet<String> {
+    let mut dirs = BTreeSet::new();
+    for entry in entries {
+        let name = entry.name.trim_end_matches('/');
+        let mut dir = match entry.is_dir() {
+            true => Some(name),
+            false => path::parent(name),
+        };
+        while let Some(name) = dir {
+            dirs.insert(name.to_string());
+            dir = path::parent(name);
+        }
+    }
+    dirs
+}
+
+/// The central directory of an archive: its entries, and where it starts,
+/// which is where the data of the entries must end.
+struct CentralDir {
+    records: Vec<Record>,
+    offset: u64,
+}
+
+/// A [`ZipEntry`] along with what it takes to extract it.
+struct Record {
+    entry: ZipEntry,
+    method: u16,
+    crc: u32,
+    compressed: u64,
+    /// Offset of the local header of the entry in the archive.
+    offset: u64,
+}
+
+/// Reads the central directory of archive, and only that: the end of central
+/// directory record is looked for in the last bytes of the archive, and points
+/// at the rest.
+fn read_central_dir<F: FileLike + ?Sized>(
+    archive: &mut F,
+    archive_path: &str,
+) -> Result<CentralDir, VfsClientError> {
+    let parse = |error| parse_error(error, archive_path);
+    let len = archive.metadata()?.len;
+    // the record is followed by a comment of at most u16::MAX bytes
+    let tail_len = len.min((END_OF_CENTRAL_DIR_LEN + u16::MAX as usize) as u64);
+    let tail_start = len - tail_len;
+    let tail = read_range(archive, archive_path, tail_start, tail_len)?;
+    let eocd = find_end_of_central_dir(&tail).ok_or_else(|| parse("not a zip archive".into()))?;
+
+    let count = read_u16(&tail, eocd + 10).map_err(parse)?;
+    let central_len = read_u32(&tail, eocd + 12).map_err(parse)? as u64;
+    let offset = read_u32(&tail, eocd + 16).map_err(parse)? as u64;
+    // the central directory lies before the record that points at it
+    if offset + central_len > tail_start + eocd as u64 {
+        return Err(parse("central directory out of bounds".into()));
+    }
+    let bytes = read_range(archive, archive_path, offset, central_len)?;
+    let records = parse_central_dir(&bytes, count, offset).map_err(parse)?;
+    Ok(CentralDir { records, offset })
+}
+
+fn parse_central_dir(bytes: &[u8], count: u16, end: u64) -> Result<Vec<Record>, String> {
+    let mut records = Vec::with_capacity(count as usize);
+    let mut names = BTreeSet::new();
+    let mut offset = 0usize;
+    for _ in 0..count {
+        if read_u32(bytes, offset)? != CENTRAL_HEADER_SIG {
+            return Err("corrupt central directory".to_string());
+        }
+        let field = |at: usize| offset.checked_add(at).ok_or("truncated central directory");
+        let flags = read_u16(bytes, field(8)?)?;
+        let method = read_u16(bytes, field(10)?)?;
+        let crc = read_u32(bytes, field(16)?)?;
+        let compressed = read_u32(bytes, field(20)?)?;
+        let size = read_u32(bytes, field(24)?)?;
+        let name_len = read_u16(bytes, field(28)?)? as usize;
+        let extra_len = read_u16(bytes, field(30)?)? as usize;
+        let comment_len = read_u16(bytes, field(32)?)? as usize;
+        let local_offset = read_u32(bytes, field(42)?)?;
+
+        let name_start = field(CENTRAL_HEADER_LEN)?;
+        let name = name_start
+            .checked_add(name_len)
+            .and_then(|name_end| bytes.get(name_start..name_end))
+            .ok_or("truncated central directory")?;
+        // checked as it will be written, not as a lossy rendering of it
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| "zip entry name is not UTF-8".to_string())?;
+        if !is_safe_entry_name(&name) {
+            return Err(format!("zip entry {} escapes the destination", name));
+        }
+        if [compressed, size, local_offset].contains(&u32::MAX) {
+            return Err("zip64 archives are not supported".to_string());
+        }
+        if flags & ENCRYPTED_FLAG != 0 {
+            return Err(format!("zip entry {} is encrypted", name));
+        }
+        if method != STORED && method != DEFLATED {
+            return Err(format!(
+                "zip entry {} uses unsupported compression method {}",
+                name, method
+            ));
+        }
+        if local_offset as u64 >= end {
+            return Err(format!("zip entry {} starts past its data", name));
+        }
+        if !names.insert(name.clone()) {
+            return Err(format!("zip entry {} appears twice", name));
+        }
+
+        records.push(Record {
+            entry: ZipEntry {
+                name,
+                size: size as u64,
+            },
+            method,
+            crc,
+            compressed: compressed as u64,
+            offset: local_offset as u64,
+        });
+        offset = name_start
+            .checked_add(name_len + extra_len + comment_len)
+            .ok_or("truncated central directory")?;
+    }
+    Ok(records)
+}
+
+/// Copies the data of the entry of record from archive to the end of file,
+/// inflating it if need be, a chunk at a time.
+fn extract_entry<F, W>(
+    archive: &mut F,
+    archive_path: &str,
+    central_dir: &CentralDir,
+    record: &Record,
+    file: &mut W,
+) -> Result<(), VfsClientError>
+where
+    F: FileLike + ?Sized,
+    W: FileLike + ?Sized,
+{
+    let mut writer = EntryWriter {
+        file,
+        archive_path,
+        record,
+        crc: Crc32::new(),
+        len: 0,
+    };
+    let header = read_range(archive, archive_path, record.offset, LOCAL_HEADER_LEN)?;
+    let local_name_len = read_u16(&header, 26).map_err(|e| writer.error(e))? as u64;
+    let local_extra_len = read_u16(&header, 28).map_err(|e| writer.error(e))? as u64;
+    if read_u32(&header, 0) != Ok(LOCAL_HEADER_SIG) {
+        return Err(writer.error("no local header where the central directory says"));
+    }
+    let local_name = read_range(
+        archive,
+        archive_path,
+        record.offset + LOCAL_HEADER_LEN,
+        local_name_len,
+    )?;
+    if local_name != record.entry.name.as_bytes() {
+        return Err(writer.error("local header names a different entry"));
+    }
+    let start = record.offset + LOCAL_HEADER_LEN + local_name_len + local_extra_len;
+    if start + record.compressed > central_dir.offset {
+        return Err(writer.error("data runs into the central directory"));
+    }
+
+    archive.seek(SeekFrom::Start(start))?;
+    let mut remaining = record.compressed;
+    let mut buffer = vec![0; ZIP_CHUNK_SIZE.min(remaining as usize)];
+    let mut inflater = match record.method {
+        DEFLATED => Some((
+            InflateState::new_boxed(DataFormat::Raw),
+            vec![0; ZIP_CHUNK_SIZE],
+        )),
+        _ => None,
+    };
+    let mut ended = inflater.is_none();
+    loop {
+        let len = read_chunk(archive, &mut buffer, &mut remaining)?;
+        if len == 0 {
+            break;
+        }
+        match &mut inflater {
+            None => writer.write(&buffer[..len])?,
+            Some(_) if ended => return Err(writer.error("data after the deflate stream")),
+            Some((state, out)) => ended = inflate_into(state, &buffer[..len], out, &mut writer)?,
+        }
+    }
+    if remaining != 0 {
+        return Err(writer.error("truncated data"));
+    }
+    if !ended {
+        return Err(writer.error("deflate stream ends early"));
+    }
+    writer.finish()
+}
+
+/// Inflates the next chunk of a deflate stream into writer.
+/// Returns whether the stream ended.
+fn inflate_into<W: FileLike + ?Sized>(
+    state: &mut InflateState,
+    mut input: &[u8],
+    out: &mut [u8],
+    writer: &mut EntryWriter<W>,
+) -> Result<bool, VfsClientError> {
+    loop {
+        let result = inflate(state, input, out, MZFlush::None);
+        input = &input[result.bytes_consumed..];
+        writer.write(&out[..result.bytes_written])?;
+        match result.status {
+            Ok(MZStatus::StreamEnd) => return Ok(true),
+            Ok(_) if input.is_empty() && result.bytes_written < out.len() => return Ok(false),
+            Ok(_) => {}
+            // all inflated, nothing to go on with until the next chunk
+            Err(MZError::Buf) if input.is_empty() => return Ok(false),
+            Err(e) => return Err(writer.error(format!("corrupt deflate stream: {:?}", e))),
+        }
+    }
+}
+
+/// Where the data of an entry goes as it is extracted, checked against the size
+/// and CRC its central directory record claims.
+struct EntryWriter<'a, W: FileLike + ?Sized> {
+    file: &'a mut W,
+    archive_path: &'a str,
+    record: &'a Record,
+    crc: Crc32,
+    len: u64,
+}
+
+impl<W: FileLike + ?Sized> EntryWriter<'_, W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), VfsClientError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.len += bytes.len() as u64;
+        if self.len > self.record.entry.size {
+            let claimed = self.record.entry.size;
+            return Err(self.error(format!("more than the {claimed} bytes it claims")));
+        }
+        self.crc.update(bytes);
+        self.file.append(bytes)
+    }
+
+    fn finish(self) -> Result<(), VfsClientError> {
+        if self.len != self.record.entry.size {
+            let (len, claimed) = (self.len, self.record.entry.size);
+            Err(self.error(format!("{len} bytes, not the {claimed} it claims")))
+        } else if self.crc.finish() != self.record.crc {
+            Err(self.error("CRC mismatch"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn error(&self, error: impl std::fmt::Display) -> VfsClientError {
+        let error = format!("zip entry {}: {}", self.record.entry.name, error);
+        parse_error(error, self.archive_path)
+    }
+}
+
+/// Reads exactly len bytes of archive from offset.
+fn read_range<F: FileLike + ?Sized>(
+    archive: &mut F,
+    archive_path: &str,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>, VfsClientError> {
+    let too_large = |_| parse_error("zip archive past addressable memory".into(), archive_path);
+    let mut bytes = vec![0; usize::try_from(len).map_err(too_large)?];
+    let mut remaining = len;
+    let mut filled = 0;
+    archive.seek(SeekFrom::Start(offset))?;
+    while filled < bytes.len() {
+        match read_chunk(archive, &mut bytes[filled..], &mut remaining)? {
+            0 => return Err(parse_error("truncated zip archive".into(), archive_path)),
+            read => filled += read,
+        }
+    }
+    Ok(bytes)
+}
+
+fn find_end_of_central_dir(bytes: &[u8]) -> Option<usize> {
+    let last = bytes.len().checked_sub(END_OF_CENTRAL_DIR_LEN)?;
+    (0..=last)
+        .rev()
+        .find(|&i| read_u32(bytes, i) == Ok(END_OF_CENTRAL_DIR_SIG))
+}
+
+fn is_safe_entry_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.contains('\\')
+        && !name.contains(':')
+        && name
+            .trim_end_matches('/')
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    offset
+        .checked_add(2)
+        .and_then(|end| bytes.get(offset..end))
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "truncated zip archive".to_string())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    offset
+        .checked_add(4)
+        .and_then(|end| bytes.get(offset..end))
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "truncated zip archive".to_string())
+}
+
+/// The most entries an archive without zip64 extensions can list.
+const MAX_ZIP_ENTRIES: usize = u16::MAX as usize;
+/// The largest entry, offset or central directory an archive without zip64
+/// extensions can describe: its fields are 32 bits.
+const MAX_ZIP32_SIZE: u64 = u32::MAX as u64;
+
+/// Writes a zip archive with uncompressed (stored) entries to the end of a
+/// [`FileLike`], copying each file in chunks and going back to fill in its CRC
+/// once it is known. There is no zip64 support, so adding an entry past the
+/// format's limits of 65,535 entries and 4 GiB fails rather than writing a
+/// corrupt archive.
+struct ZipWriter<'a, F: FileLike + ?Sized> {
+    archive: &'a mut F,
+    archive_path: &'a str,
+    /// Length of the archive so far, without the central directory.
+    len: u64,
+    central_dir: Vec<u8>,
+    count: usize,
+}
+
+impl<'a, F: FileLike + ?Sized> ZipWriter<'a, F> {
+    /// Starts an archive in archive, replacing what it held.
+    fn new(archive: &'a mut F, archive_path: &'a str) -> Result<Self, VfsClientError> {
+        archive.write(&[])?;
+        Ok(ZipWriter {
+            archive,
+            archive_path,
+            len: 0,
+            central_dir: Vec::new(),
+            count: 0,
+        })
+    }
+
+    fn add_dir(&mut self, name: &str) -> Result<(), VfsClientError> {
+        let name = format!("{}/", name.trim_end_matches('/'));
+        self.check_room(&name, 0)?;
+        let crc = Crc32::new().finish();
+        let header = local_header(&name, STORED, crc, 0, 0);
+        self.archive.append(&header)?;
+        self.push_entry(&name, crc, 0, header.len() as u64);
+        Ok(())
+    }
+
+    fn add_file<S: FileLike + ?Sized>(
+        &mut self,
+        name: &str,
+        src: &mut S,
+    ) -> Result<(), VfsClientError> {
+        let size = src.metadata()?.len;
+        self.check_room(name, size)?;
+        // check_room keeps size within a u32
+        let header = local_header(name, STORED, 0, size as u32, size as u32);
+        self.archive.append(&header)?;
+
+        src.seek(SeekFrom::Start(0))?;
+        let mut crc = Crc32::new();
+        let mut remaining = size;
+        let mut buffer = vec![0; ZIP_CHUNK_SIZE.min(size as usize)];
+        loop {
+            let len = read_chunk(src, &mut buffer, &mut remaining)?;
+            if len == 0 {
+                break;
+            }
+            crc.update(&buffer[..len]);
+            self.archive.append(&buffer[..len])?;
+        }
+        if remaining != 0 {
+            let error = format!("zip entry {name}: file shrank while being archived");
+            return Err(parse_error(error, self.archive_path));
+        }
+
+        let crc = crc.finish();
+        self.archive
+            .seek(SeekFrom::Start(self.len + LOCAL_CRC_OFFSET))?;
+        self.archive.write_all(&crc.to_le_bytes())?;
+        self.push_entry(name, crc, size, header.len() as u64 + size);
+        Ok(())
+    }
+
+    /// Fails if an entry name with size bytes of data would not fit.
+    fn check_room(&self, name: &str, size: u64) -> Result<(), VfsClientError> {
+        let archive_len = self.len + self.central_dir.len() as u64;
+        match zip32_overflow(self.count, archive_len, name.len(), size) {
+            Some(error) => Err(parse_error(
+                format!("zip entry {name}: {error}"),
+                self.archive_path,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Records an entry of record_len bytes, just written, in the central directory.
+    fn push_entry(&mut self, name: &str, crc: u32, size: u64, record_len: u64) {
+        // check_room keeps the offset and size within a u32
+        let (offset, size) = (self.len as u32, size as u32);
+        self.central_dir
+            .extend(central_header(name, STORED, crc, size, size, offset));
+        self.len += record_len;
+        self.count += 1;
+    }
+
+    fn finish(self) -> Result<(), VfsClientError> {
+        let mut end = self.central_dir;
+        // check_room keeps these within their fields
+        let (count, central_len) = (self.count as u16, end.len() as u32);
+        end.extend(end_of_central_dir(count, central_len, self.len as u32));
+        self.archive.append(&end)
+    }
+}
+
+/// The local header of an entry.
+fn local_header(name: &str, method: u16, crc: u32, compressed: u32, size: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(LOCAL_HEADER_LEN as usize + name.len());
+    header.extend(LOCAL_HEADER_SIG.to_le_bytes());
+    header.extend(20u16.to_le_bytes()); // version needed
+    header.extend(UTF8_FLAG.to_le_bytes());
+    header.extend(method.to_le_bytes());
+    header.extend(0u16.to_le_bytes()); // time
+    header.extend(DOS_DATE.to_le_bytes());
+    header.extend(crc.to_le_bytes());
+    header.extend(compressed.to_le_bytes());
+    header.extend(size.to_le_bytes());
+    // zip32_overflow keeps names within a u16
+    header.extend((name.len() as u16).to_le_bytes());
+    header.extend(0u16.to_le_bytes()); // extra
+    header.extend(name.as_bytes());
+    header
+}
+
+/// The central directory record of an entry whose local header is at offset.
+fn central_header(
+    name: &str,
+    method: u16,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    offset: u32,
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(CENTRAL_HEADER_LEN + name.len());
+    header.extend(CENTRAL_HEADER_SIG.to_le_bytes());
+    header.extend(20u16.to_le_bytes()); // version made by
+                                        // from version needed to the extra field length, the fields are the local ones
+    let local = local_header(name, method, crc, compressed, size);
+    header.extend(&local[4..LOCAL_HEADER_LEN as usize]);
+    header.extend(0u16.to_le_bytes()); // comment
+    header.extend(0u16.to_le_bytes()); // disk
+    header.extend(0u16.to_le_bytes()); // internal attributes
+    header.extend(0u32.to_le_bytes()); // external attributes
+    header.extend(offset.to_le_bytes());
+    header.extend(name.as_bytes());
+    header
+}
+
+/// The record that ends an archive of count entries, whose central directory
+/// is central_len bytes from offset.
+fn end_of_central_dir(count: u16, central_len: u32, offset: u32) -> Vec<u8> {
+    let mut end = Vec::with_capacity(END_OF_CENTRAL_DIR_LEN);
+    end.extend(END_OF_CENTRAL_DIR_SIG.to_le_bytes());
+    end.extend(0u16.to_le_bytes()); // this disk
+    end.extend(0u16.to_le_bytes()); // central directory disk
+    end.extend(count.to_le_bytes());
+    end.extend(count.to_le_bytes());
+    end.extend(central_len.to_le_bytes());
+    end.extend(offset.to_le_bytes());
+    end.extend(0u16.to_le_bytes()); // comment
+    end
+}
+
+/// Why an archive of count entries and archive_len bytes, local headers and
+/// central directory together, can not take one more entry, if it can not.
+fn zip32_overflow(
+    count: usize,
+    archive_len: u64,
+    name_len: usize,
+    data_len: u64,
+) -> Option<String> {
+    // what the entry adds: its data, and its name and fixed fields twice
+    let entry_len = data_len.saturating_add(2 * (name_len as u64 + CENTRAL_HEADER_LEN as u64));
+    if count >= MAX_ZIP_ENTRIES {
+        Some(format!(
+            "archive already has {MAX_ZIP_ENTRIES} entries, the most without zip64"
+        ))
+    } else if name_len > u16::MAX as usize {
+        Some(format!(
+            "name is {name_len} bytes, over the limit of {}",
+            u16::MAX
+        ))
+    } else if archive_len.saturating_add(entry_len) > MAX_ZIP32_SIZE {
+        Some("archive would reach 4 GiB, the most without zip64".to_string())
+    } else {
+        None
+    }
+}
+
+/// The CRC-32 zip archives check entries with, computed a chunk at a time.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                self.0 = (self.0 >> 1) ^ (0xEDB88320 & (self.0 & 1).wrapping_neg());
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{Call, MockHost, Reply};
+    use crate::vfs::{MemFile, VfsRequest, VfsResponse};
+
+    /// An archive of entries, each a directory if its name ends in `/`.
+    fn archive(entries: &[(&str, &[u8])]) -> MemFile {
+        let mut archive = MemFile::default();
+        let mut writer = ZipWriter::new(&mut archive, "zip archive").unwrap();
+        for (name, data) in entries {
+            match name.strip_suffix('/') {
+                Some(dir) => writer.add_dir(dir).unwrap(),
+                None => writer
+                    .add_file(name, &mut MemFile::new(data.to_vec()))
+                    .unwrap(),
+            }
+        }
+        writer.finish().unwrap();
+        archive
+    }
+
+    /// An archive of one entry, data deflated, claiming size and crc for it.
+    fn deflated(name: &str, data: &[u8], size: u32, crc: u32) -> MemFile {
+        let compressed = miniz_oxide::deflate::compress_to_vec(data, 6);
+        let compressed_len = compressed.len() as u32;
+        let mut bytes = local_header(name, DEFLATED, crc, compressed_len, size);
+        bytes.extend(compressed);
+        let central = central_header(name, DEFLATED, crc, compressed_len, size, 0);
+        let offset = bytes.len() as u32;
+        bytes.extend(&central);
+        bytes.extend(end_of_central_dir(1, central.len() as u32, offset));
+        MemFile::new(bytes)
+    }
+
+    /// Every file entry of archive, extracted in memory.
+    fn extract_all(archive: &mut MemFile) -> Result<Vec<(String, Vec<u8>)>, VfsClientError> {
+        let central_dir = read_central_dir(archive, "zip archive")?;
+        let mut files = vec![];
+        for record in central_dir.records.iter().filter(|r| !r.entry.is_dir()) {
+            let mut file = MemFile::default();
+            extract_entry(archive, "zip archive", &central_dir, record, &mut file)?;
+            files.push((record.entry.name.clone(), file.into_inner()));
+        }
+        Ok(files)
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_zip_round_trip() {
+        let mut archive = archive(&[
+            ("assets/", b""),
+            ("assets/app.js", b"console.log(1)"),
+            ("index.html", b"<html></html>"),
+        ]);
+
+        let entries = read_zip_entries(&mut archive).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ZipEntry {
+                    name: "assets/".to_string(),
+                    size: 0
+                },
+                ZipEntry {
+                    name: "assets/app.js".to_string(),
+                    size: 14
+                },
+                ZipEntry {
+                    name: "index.html".to_string(),
+                    size: 13
+                },
+            ]
+        );
+        assert!(entries[0].is_dir());
+        assert_eq!(
+            zip_dirs(&entries).into_iter().collect::<Vec<_>>(),
+            vec!["assets".to_string()]
+        );
+        assert_eq!(
+            extract_all(&mut archive).unwrap(),
+            vec![
+                ("assets/app.js".to_string(), b"console.log(1)".to_vec()),
+                ("index.html".to_string(), b"<html></html>".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zip_dirs_includes_parents() {
+        let entries = vec![ZipEntry {
+            name: "a/b/c.txt".to_string(),
+            size: 1,
+        }];
+        assert_eq!(
+            zip_dirs(&entries).into_iter().collect::<Vec<_>>(),
+            vec!["a".to_string(), "a/b".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_zip_rejects_traversal() {
+        for name in ["../evil", "a/../../evil", "/etc/passwd", "a\\..\\b", "C:/x"] {
+            let err = read_zip_entries(&mut archive(&[(name, b"x")])).unwrap_err();
+            assert!(err.to_string().contains("escapes"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_zip_writer_refuses_past_zip32_limits() {
+        let mut archive = MemFile::default();
+        let mut writer = ZipWriter::new(&mut archive, "zip archive").unwrap();
+        for i in 0..MAX_ZIP_ENTRIES {
+            writer
+                .add_file(&i.to_string(), &mut MemFile::default())
+                .unwrap();
+        }
+        let err = writer.add_dir("one-too-many").unwrap_err();
+        assert!(err.to_string().contains("65535 entries"), "{err}");
+        writer.finish().unwrap();
+        assert_eq!(
+            read_zip_entries(&mut archive).unwrap().len(),
+            MAX_ZIP_ENTRIES
+        );
+
+        // sizes, which no test can afford to reach for real
+        assert_eq!(zip32_overflow(0, 0, 8, 1 << 20), None);
+        assert!(zip32_overflow(0, 0, 8, u32::MAX as u64).is_some());
+        assert!(zip32_overflow(0, 0, 8, u64::MAX).is_some());
+        assert!(zip32_overflow(10, MAX_ZIP32_SIZE - 100, 8, 0).is_some());
+        assert!(zip32_overflow(0, 0, u16::MAX as usize + 1, 0).is_some());
+    }
+
+    #[test]
+    fn test_zip_rejects_garbage() {
+        let read = |bytes: Vec<u8>| read_zip_entries(&mut MemFile::new(bytes));
+        assert!(read(vec![]).is_err());
+        assert!(read(b"definitely not a zip archive".to_vec()).is_err());
+        // offsets and lengths pointing past the end, or wrapping around
+        assert!(read(end_of_central_dir(1, 46, u32::MAX - 10)).is_err());
+        assert!(read(end_of_central_dir(1, u32::MAX, 0)).is_err());
+        let central = central_header("a", STORED, 0, 0, 0, u32::MAX - 1);
+        let mut bytes = central.clone();
+        bytes.extend(end_of_central_dir(1, central.len() as u32, 0));
+        assert!(read(bytes).is_err());
+        let mut bytes = central.clone();
+        bytes.extend(end_of_central_dir(2, central.len() as u32, 0));
+        assert!(read(bytes).is_err());
+    }
+
+    #[test]
+    fn test_extract_deflated_entry() {
+        let data = b"hello hello hello hello hello".repeat(100);
+        let (size, crc) = (data.len() as u32, crc32(&data));
+        assert_eq!(
+            extract_all(&mut deflated("a.txt", &data, size, crc)).unwrap(),
+            vec![("a.txt".to_string(), data.clone())]
+        );
+
+        // the sizes and CRC it claims are checked against the data itself
+        let err = extract_all(&mut deflated("a.txt", &data, size - 1, crc)).unwrap_err();
+        assert!(err.to_string().contains("more than"), "{err}");
+        let err = extract_all(&mut deflated("a.txt", &data, size + 1, crc)).unwrap_err();
+        assert!(err.to_string().contains("not the"), "{err}");
+        let err = extract_all(&mut deflated("a.txt", &data, size, crc ^ 1)).unwrap_err();
+        assert!(err.to_string().contains("CRC mismatch"), "{err}");
+    }
+
+    #[test]
+    fn test_extract_zip_from_mem_file() {
+        crate::set_our("tester.os@tester:app:sys".parse().unwrap());
+        let host = MockHost::new();
+        let _installed = host.install();
+        let mut archive = archive(&[("assets/app.js", b"console.log(1)")]);
+
+        // too big: rejected before anything is sent
+        let err = extract_zip_from(&mut archive, "/app:sys/out", 4, None).unwrap_err();
+        assert!(
+            matches!(&err, VfsClientError::Runtime(VfsError::ParseError { path, .. }) if path == "zip archive"),
+            "{err:?}"
+        );
+        assert!(host.take_calls().is_empty());
+
+        for _ in 0..4 {
+            host.reply(Reply::json(&VfsResponse::Ok));
+        }
+        let written = extract_zip_from(&mut archive, "/app:sys/out/", 1024, None).unwrap();
+        assert_eq!(written, ["/app:sys/out/assets/app.js"]);
+        let requests: Vec<(String, serde_json::Value, Option<Vec<u8>>)> = host
+            .take_calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                Call::SendAndAwaitResponse { request, blob, .. } => {
+                    let request: VfsRequest = serde_json::from_slice(&request.body).unwrap();
+                    let action = serde_json::to_value(request.action).unwrap();
+                    Some((request.path, action, blob.map(|blob| blob.bytes)))
+                }
+                _ => None,
+            })
+            .collect();
+        let file = "/app:sys/out/assets/app.js".to_string();
+        assert_eq!(
+            requests,
+            [
+                ("/app:sys/out".to_string(), "CreateDirAll".into(), None),
+                (
+                    "/app:sys/out/assets".to_string(),
+                    "CreateDirAll".into(),
+                    None
+                ),
+                (file.clone(), "CreateFile".into(), None),
+                (file, "Append".into(), Some(b"console.log(1)".to_vec())),
+            ]
+        );
+    }
+}